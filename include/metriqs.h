@@ -0,0 +1,48 @@
+/*
+ * C API for embedding a metriqs agent, built with the `capi` feature (see
+ * `src/capi.rs`). Functions that can fail return 0 on success, or -1 (or
+ * NULL) with the reason available from `metriqs_last_error`.
+ */
+
+#ifndef METRIQS_H
+#define METRIQS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct MetriqsAgent metriqs_agent;
+typedef struct MetriqsMetrics metriqs_metrics;
+
+/* Valid until the next failing call on the same thread. */
+const char *metriqs_last_error(void);
+
+metriqs_agent *metriqs_agent_new(void);
+metriqs_agent *metriqs_agent_from_config(const char *path);
+int metriqs_agent_listen_statsd_udp(metriqs_agent *agent, const char *address);
+int metriqs_agent_listen_statsd_tcp(metriqs_agent *agent, const char *address);
+int metriqs_agent_start(metriqs_agent *agent);
+int metriqs_agent_flush(metriqs_agent *agent);
+/* Frees the agent, even if shutting down didn't finish in time. */
+int metriqs_agent_shutdown(metriqs_agent *agent, double timeout_seconds);
+
+metriqs_metrics *metriqs_metrics_new(metriqs_agent *agent);
+void metriqs_metrics_free(metriqs_metrics *metrics);
+
+/*
+ * `dimensions` is `dimensions_len` key and value pairs, ie. twice as many
+ * strings, and may be NULL if there are none.
+ */
+int metriqs_metrics_count(const metriqs_metrics *metrics, const char *name, int64_t value, const char *const *dimensions, size_t dimensions_len);
+int metriqs_metrics_gauge(const metriqs_metrics *metrics, const char *name, double value, const char *const *dimensions, size_t dimensions_len);
+int metriqs_metrics_histogram(const metriqs_metrics *metrics, const char *name, double value, const char *const *dimensions, size_t dimensions_len);
+int metriqs_metrics_timing(const metriqs_metrics *metrics, const char *name, double milliseconds, const char *const *dimensions, size_t dimensions_len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
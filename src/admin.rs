@@ -0,0 +1,186 @@
+//! An HTTP API for operators to see what the agent holds and to act on it:
+//!
+//! - `GET /series`: the latest point of every stored series as a JSON
+//!   array, in the shape the JSON senders write, eg.
+//!   `{"time":1500000000000,"name":"api.requests","dimensions":{"host":"a"},"type":"count","value":3}`.
+//! - `GET /vars`: the latest value of every stored series as one JSON
+//!   object keyed by name and then by dimensions as `key=value` pairs
+//!   joined with `,` (`""` for none), eg.
+//!   `{"api.requests":{"host=a":3,"host=b":1},"queue":{"":2.5}}`, for
+//!   tooling and smoke tests that would rather not parse Prometheus text.
+//! - `GET /stats`: `Db::stats` as a JSON object, with when the last
+//!   aggregation was published (`last_aggregation`, milliseconds since the
+//!   epoch) and the aggregation interval in seconds.
+//! - `POST /flush`: aggregate and publish everything collected so far.
+//! - `POST /reset`: drop every stored series (see `Db::reset`).
+//! - `POST /promote`: start publishing aggregations if the Db is a
+//!   mirror's standby (see `DbOptions::standby`).
+//! - `GET /ui`: with the `ui` feature, a page listing stored series with
+//!   sparklines (see `ui`).
+//!
+//! Nothing is authenticated, so it should only be bound to a trusted
+//! address such as localhost.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::db::{AggregatedKey, Db};
+#[cfg(feature = "ui")]
+use super::ui;
+use super::util::Stop;
+use super::util::http::{self, Response};
+use super::util::json;
+
+pub struct AdminServer {
+    db: Arc<Db>,
+}
+
+impl AdminServer {
+    pub fn new(db: Arc<Db>) -> AdminServer {
+        AdminServer { db }
+    }
+
+    /// Serve the API on `listener` until `stop` is stopped.
+    pub fn serve(self, listener: &TcpListener, stop: &Stop) -> io::Result<()> {
+        let server = Arc::new(self);
+        http::serve(listener, stop, move |method, target| server.handle(method, target))
+    }
+
+    fn handle(&self, method: &str, target: &str) -> Response {
+        let path = target.split('?').next().unwrap_or(target);
+        match (method, path) {
+            ("GET", "/series") => Response::json(200, self.series()),
+            ("GET", "/vars") => Response::json(200, self.vars()),
+            ("GET", "/stats") => Response::json(200, self.stats()),
+            #[cfg(feature = "ui")]
+            ("GET", "/ui") => Response::html(200, ui::page(&self.db, target)),
+            ("POST", "/flush") => {
+                self.db.flush();
+                Response::text(200, "Flushed\n")
+            },
+            ("POST", "/reset") => match self.db.reset() {
+                Ok(()) => Response::text(200, "Reset\n"),
+                Err(err) => Response::text(500, format!("Error resetting: {}\n", err)),
+            },
+            ("POST", "/promote") => {
+                self.db.promote();
+                Response::text(200, "Promoted\n")
+            },
+            (_, "/series") | (_, "/vars") | (_, "/stats") | (_, "/flush") | (_, "/reset") | (_, "/promote") => Response::text(405, "Method Not Allowed\n"),
+            _ => Response::text(404, "Not Found\n"),
+        }
+    }
+
+    fn series(&self) -> String {
+        let series = self.db.latest().into_iter()
+            .map(|(key, (time, value))| {
+                let (kind, id) = match key {
+                    AggregatedKey::Count(ref id) => ("count", id),
+                    AggregatedKey::Gauge(ref id) => ("gauge", id),
+                };
+                let dimensions = id.dimensions().iter()
+                    .map(|(key, value)| format!("{}:{}", json::string(key), json::string(value)))
+                    .collect::<Vec<String>>();
+                format!(
+                    r#"{{"time":{},"name":{},"dimensions":{{{}}},"type":"{}","value":{}}}"#,
+                    millis(time),
+                    json::string(id.name()),
+                    dimensions.join(","),
+                    kind,
+                    json::number(value),
+                )
+            })
+            .collect::<Vec<String>>();
+        format!("[{}]", series.join(","))
+    }
+
+    fn vars(&self) -> String {
+        let mut names: BTreeMap<String, BTreeMap<String, f64>> = BTreeMap::new();
+        for (key, (_, value)) in self.db.latest() {
+            let id = key.id();
+            let dimensions = id.dimensions().iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<String>>();
+            names.entry(id.name().to_string()).or_default().insert(dimensions.join(","), value);
+        }
+        let names = names.iter()
+            .map(|(name, series)| {
+                let series = series.iter()
+                    .map(|(dimensions, value)| format!("{}:{}", json::string(dimensions), json::number(*value)))
+                    .collect::<Vec<String>>();
+                format!("{}:{{{}}}", json::string(name), series.join(","))
+            })
+            .collect::<Vec<String>>();
+        format!("{{{}}}", names.join(","))
+    }
+
+    fn stats(&self) -> String {
+        let stats = self.db.stats();
+        format!(
+            r#"{{"series":{},"points":{},"backlog":{},"accumulating":{},"subscribers":{},"estimated_bytes":{},"collected":{},"aggregations":{},"last_aggregation":{},"aggregation_interval":{}}}"#,
+            stats.series,
+            stats.points,
+            stats.backlog,
+            stats.accumulating,
+            stats.subscribers,
+            stats.estimated_bytes,
+            stats.collected,
+            stats.aggregations,
+            millis(self.db.last_aggregation()),
+            json::number(self.db.aggregation_interval().as_secs_f64()),
+        )
+    }
+}
+
+fn millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use super::super::db::DbOptions;
+    use super::super::metric::{CollectedMetric, MetricId};
+
+    #[test]
+    fn it_lists_flushes_and_resets_series() {
+        let db = Arc::new(Db::new(DbOptions::default()));
+        let server = AdminServer::new(db.clone());
+        let time = UNIX_EPOCH + Duration::from_secs(1);
+        db.collect(vec![CollectedMetric::Gauge(time, MetricId::from("queue").with_dimension("host", "a"), 2.5)]);
+
+        assert_eq!(server.handle("POST", "/flush").status, 200);
+        assert_eq!(
+            server.handle("GET", "/series"),
+            Response::json(200, r#"[{"time":1000,"name":"queue","dimensions":{"host":"a"},"type":"gauge","value":2.5}]"#),
+        );
+        assert!(server.handle("GET", "/stats").body.starts_with(r#"{"series":1,"points":1,"backlog":0,"accumulating":0,"subscribers":0,"#));
+
+        assert_eq!(server.handle("GET", "/reset").status, 405);
+        assert_eq!(server.handle("POST", "/reset").status, 200);
+        assert_eq!(server.handle("GET", "/series").body, "[]");
+        assert_eq!(server.handle("GET", "/metrics").status, 404);
+    }
+
+    #[test]
+    fn it_nests_latest_values_by_name_and_dimensions() {
+        let db = Arc::new(Db::new(DbOptions::default()));
+        let server = AdminServer::new(db.clone());
+        let time = UNIX_EPOCH + Duration::from_secs(1);
+        db.collect(vec![
+            CollectedMetric::Gauge(time, MetricId::from("queue").with_dimension("host", "a"), 2.5),
+            CollectedMetric::Gauge(time, MetricId::from("queue").with_dimension("host", "b"), 1.0),
+            CollectedMetric::Gauge(time, MetricId::from("workers"), 4.0),
+        ]);
+        db.flush();
+
+        assert_eq!(server.handle("GET", "/vars"), Response::json(200, r#"{"queue":{"host=a":2.5,"host=b":1},"workers":{"":4}}"#));
+        assert_eq!(server.handle("POST", "/vars").status, 405);
+    }
+}
@@ -0,0 +1,352 @@
+//! Runs a whole agent: a Db, its aggregation, eviction, snapshot, and
+//! enrichment loops, and the listeners and exporters registered with it or
+//! described by a config, so that embedding metriqs doesn't mean managing
+//! every blocking loop and thread by hand.
+//!
+//! ```ignore
+//! let mut agent = Agent::new(DbOptions::default())
+//!     .listener(|collector, stop| if let Err(err) = StatsdUdpListener::new(collector).listen_until("0.0.0.0:8125", &stop) {
+//!         error!("Error listening for StatsD: {}", err)
+//!     })
+//!     .exporter(None, |subscription, _| JsonLinesSender::stdout(subscription).send().unwrap());
+//! agent.start()?;
+//! // ...
+//! agent.shutdown();
+//! ```
+
+use std::mem;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::admin::AdminServer;
+use super::config::{Config, Workers};
+use super::db::{AggregatedMetric, Db, DbOptions, SubscriptionFilter, SubscriptionToken};
+use super::health::{HealthOptions, HealthServer, Probe};
+use super::supervisor::Supervisor;
+use super::recv::Collector;
+use super::send::GrpcServer;
+use super::util::{lock, Stop};
+#[cfg(unix)]
+use super::util::signal;
+
+/// How often stored points older than the retention are evicted.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Listeners and exporters are called again if they panic (see
+/// `supervisor`).
+type Listen = Box<dyn FnMut(Collector, Stop) + Send>;
+type Export = Box<dyn FnMut(Receiver<Arc<Vec<AggregatedMetric>>>, Stop) + Send>;
+type DbLoop = fn(&Db);
+
+pub struct Agent {
+    db: Arc<Db>,
+    config: Config,
+    listeners: Vec<Listen>,
+    exporters: Vec<(Option<SubscriptionFilter>, Export)>,
+    health: Option<(String, HealthOptions)>,
+    admin: Option<String>,
+    grpc: Option<String>,
+    /// Set once started.
+    workers: Option<Workers>,
+    stop: Stop,
+    /// Exporters' subscriptions, replaced when one is restarted.
+    subscriptions: Vec<Arc<Mutex<SubscriptionToken>>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Agent {
+    pub fn new(options: DbOptions) -> Agent {
+        Agent {
+            db: Arc::new(Db::new(options)),
+            config: Config::default(),
+            listeners: vec![],
+            exporters: vec![],
+            health: None,
+            admin: None,
+            grpc: None,
+            workers: None,
+            stop: Stop::new(),
+            subscriptions: vec![],
+            threads: vec![],
+        }
+    }
+
+    /// An agent with the config's Db that also starts its listeners and
+    /// exporters.
+    pub fn from_config(config: Config) -> Result<Agent, String> {
+        let mut agent = Agent::new(config.db_options()?);
+        agent.health = config.health()?;
+        agent.admin = config.admin()?;
+        agent.grpc = config.grpc()?;
+        agent.config = config;
+        Ok(agent)
+    }
+
+    /// Run `listen` on its own thread once started, with a collector of the
+    /// Db and a `Stop` that's stopped on shutdown. It's called again if it
+    /// panics, until shutdown.
+    pub fn listener<F>(mut self, listen: F) -> Agent
+        where F: FnMut(Collector, Stop) + Send + 'static
+    {
+        self.listeners.push(Box::new(listen));
+        self
+    }
+
+    /// Run `export` on its own thread once started, with a subscription to
+    /// the Db's aggregations, optionally filtered. The subscription is
+    /// dropped on shutdown, after the final aggregation, and `export`
+    /// should return once it's drained. If it panics it's called again with
+    /// a new subscription, until shutdown.
+    pub fn exporter<F>(mut self, filter: Option<SubscriptionFilter>, export: F) -> Agent
+        where F: FnMut(Receiver<Arc<Vec<AggregatedMetric>>>, Stop) + Send + 'static
+    {
+        self.exporters.push((filter, Box::new(export)));
+        self
+    }
+
+    /// Serve `/healthz` and `/readyz` on `address` once started (see
+    /// `health`), checking the Db's aggregation loop and every listener
+    /// and exporter.
+    pub fn health<S: Into<String>>(mut self, address: S, options: HealthOptions) -> Agent {
+        self.health = Some((address.into(), options));
+        self
+    }
+
+    /// Serve the admin API on `address` once started (see `admin`).
+    pub fn admin<S: Into<String>>(mut self, address: S) -> Agent {
+        self.admin = Some(address.into());
+        self
+    }
+
+    /// Stream aggregations to gRPC subscribers on `address` once started
+    /// (see `send::grpc`).
+    pub fn grpc<S: Into<String>>(mut self, address: S) -> Agent {
+        self.grpc = Some(address.into());
+        self
+    }
+
+    pub fn db(&self) -> &Arc<Db> {
+        &self.db
+    }
+
+    /// Stopped on shutdown, which stops the listeners given to `listener`.
+    /// Stopping it first, eg. from another thread, makes `run` shut down.
+    pub fn stopper(&self) -> &Stop {
+        &self.stop
+    }
+
+    /// The listeners and exporters started from the config, once started.
+    pub fn workers(&self) -> Option<&Workers> {
+        self.workers.as_ref()
+    }
+
+    /// Start the exporters, then the listeners, then the Db's loops, each
+    /// on its own thread. Exporters subscribe first so that they see the
+    /// first aggregation. Fails without starting any of the config's
+    /// listeners or exporters if one can't be created or the health checks',
+    /// admin API's, or gRPC API's address can't be bound; starting again
+    /// does nothing.
+    pub fn start(&mut self) -> Result<(), String> {
+        if self.workers.is_some() {
+            return Ok(())
+        }
+        let health_listener = match self.health {
+            Some((ref address, _)) => Some(TcpListener::bind(address.as_str()).map_err(|err| format!("health: {}", err))?),
+            None => None,
+        };
+        let admin_listener = match self.admin {
+            Some(ref address) => Some(TcpListener::bind(address.as_str()).map_err(|err| format!("admin: {}", err))?),
+            None => None,
+        };
+        let grpc_listener = match self.grpc {
+            Some(ref address) => Some(TcpListener::bind(address.as_str()).map_err(|err| format!("grpc: {}", err))?),
+            None => None,
+        };
+        // Before anything's collected, so that every metric gets them.
+        self.db.enrich();
+        let workers = self.config.start(&self.db)?;
+        let health = workers.health().clone();
+        self.workers = Some(workers);
+
+        // Listeners, exporters, and the Db's loops are restarted if they
+        // panic, until shutdown.
+        let supervisor = Supervisor::new(self.db.collector());
+        for (index, (filter, mut export)) in self.exporters.drain(..).enumerate() {
+            let (token, subscription) = self.db.subscribe(filter.clone());
+            let token = Arc::new(Mutex::new(token));
+            self.subscriptions.push(token.clone());
+            let (db, stop, supervisor) = (self.db.clone(), self.stop.clone(), supervisor.clone());
+            let probe = Probe::new(format!("agent.exporter[{}]", index));
+            health.add(&probe);
+            let mut subscription = Some(subscription);
+            self.threads.push(thread::spawn(move || probe.run(|| supervisor.run(probe.name(), &stop, || {
+                // A panic dropped the last subscription.
+                let subscription = subscription.take().unwrap_or_else(|| {
+                    let (replacement, subscription) = db.subscribe(filter.clone());
+                    db.unsubscribe(mem::replace(&mut *lock(&token), replacement));
+                    subscription
+                });
+                export(subscription, stop.clone())
+            }))));
+        }
+        for (index, mut listen) in self.listeners.drain(..).enumerate() {
+            let (db, stop, supervisor) = (self.db.clone(), self.stop.clone(), supervisor.clone());
+            let probe = Probe::new(format!("agent.listener[{}]", index));
+            health.add(&probe);
+            thread::spawn(move || probe.run(|| supervisor.run(probe.name(), &stop, || listen(db.collector(), stop.clone()))));
+        }
+        if let (Some(listener), Some((_, options))) = (health_listener, self.health.take()) {
+            let (server, stop) = (HealthServer::new(self.db.clone(), health, options), self.stop.clone());
+            self.threads.push(thread::spawn(move || if let Err(err) = server.serve(&listener, &stop) {
+                error!("Error serving health checks: {}", err)
+            }));
+        }
+        if let Some(listener) = admin_listener {
+            let (server, stop) = (AdminServer::new(self.db.clone()), self.stop.clone());
+            self.threads.push(thread::spawn(move || if let Err(err) = server.serve(&listener, &stop) {
+                error!("Error serving the admin API: {}", err)
+            }));
+        }
+        if let Some(listener) = grpc_listener {
+            let (server, stop) = (GrpcServer::new(self.db.clone()), self.stop.clone());
+            self.threads.push(thread::spawn(move || if let Err(err) = server.serve(&listener, &stop) {
+                error!("Error serving the gRPC API: {}", err)
+            }));
+        }
+
+        let loops: [(&str, DbLoop); 4] = [
+            ("aggregation", Db::sync_aggregate),
+            ("eviction", |db| db.sync_evict(EVICTION_INTERVAL)),
+            ("snapshot", Db::sync_snapshot),
+            ("enrichment", Db::sync_enrich),
+        ];
+        for &(name, run) in &loops {
+            let (db, stop, supervisor) = (self.db.clone(), self.stop.clone(), supervisor.clone());
+            self.threads.push(thread::spawn(move || supervisor.run(name, &stop, || run(&db))));
+        }
+        Ok(())
+    }
+
+    /// Switch to `config` (see `Workers::reload`). Before starting this
+    /// only replaces the config and the Db's stages.
+    pub fn reload(&mut self, config: Config) -> Result<Vec<String>, String> {
+        match self.workers {
+            Some(ref mut workers) => workers.reload(config, &self.db),
+            None => {
+                self.db.reconfigure(config.db_options()?);
+                self.config = config;
+                Ok(vec![])
+            },
+        }
+    }
+
+    /// Aggregate and publish everything collected so far right away.
+    pub fn flush(&self) {
+        self.db.flush()
+    }
+
+    /// Start, then block until the process gets SIGTERM or SIGINT or the
+    /// agent is otherwise stopped (see `stopper`), and shut down. Returns
+    /// whether shutting down finished within `timeout`.
+    #[cfg(unix)]
+    pub fn run(mut self, timeout: Duration) -> Result<bool, String> {
+        self.start()?;
+        signal::stop_on_termination(&self.stop).map_err(|err| format!("couldn't handle signals: {}", err))?;
+        self.stop.wait();
+        Ok(self.shutdown_timeout(timeout))
+    }
+
+    /// Like `shutdown` but gives up waiting after `timeout`, eg. so that
+    /// an exporter stuck on an unresponsive backend doesn't keep the
+    /// process from exiting; returns whether it finished in time. If it
+    /// didn't, shutting down carries on in the background.
+    pub fn shutdown_timeout(self, timeout: Duration) -> bool {
+        let (finished, done) = channel();
+        thread::spawn(move || {
+            self.shutdown();
+            let _ = finished.send(());
+        });
+        done.recv_timeout(timeout).is_ok()
+    }
+
+    /// Stop the listeners, run a final aggregation (see `Db::shutdown`),
+    /// and wait for the exporters to drain it and for the Db's loops to
+    /// return. Listeners aren't waited for since they may be blocked on
+    /// clients.
+    pub fn shutdown(self) {
+        self.stop.stop();
+        if let Some(workers) = self.workers {
+            workers.stop(&self.db);
+        }
+        self.db.shutdown();
+        for token in self.subscriptions {
+            self.db.unsubscribe(*lock(&token));
+        }
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::time::{Instant, UNIX_EPOCH};
+
+    use super::super::metric::{CollectedMetric, MetricId};
+
+    #[test]
+    fn it_drains_exporters_on_shutdown() {
+        let (send, recv) = channel();
+        let mut agent = Agent::new(DbOptions::default())
+            .listener(|collector, stop| {
+                collector.push(vec![CollectedMetric::Gauge(UNIX_EPOCH, MetricId::from("queue"), 1.0)]);
+                while stop.sleep(Duration::from_secs(1)) {}
+            })
+            .exporter(None, move |subscription, _| for metrics in subscription {
+                send.send(metrics).unwrap()
+            });
+        agent.start().unwrap();
+        while agent.db().stats().backlog == 0 && agent.db().stats().series == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        agent.shutdown();
+
+        let exported = recv.iter().flat_map(|metrics| (*metrics).clone()).collect::<Vec<AggregatedMetric>>();
+        assert_eq!(exported, vec![AggregatedMetric::Gauge(UNIX_EPOCH, MetricId::from("queue"), 1.0)]);
+    }
+
+    #[test]
+    fn it_gives_up_on_stuck_exporters() {
+        let mut agent = Agent::new(DbOptions::default())
+            .exporter(None, |_subscription, _| thread::sleep(Duration::from_secs(5)));
+        agent.start().unwrap();
+        assert!(!agent.shutdown_timeout(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn it_restarts_panicking_listeners() {
+        let mut runs = 0;
+        let mut agent = Agent::new(DbOptions::default())
+            .listener(move |collector, stop| {
+                runs += 1;
+                if runs == 1 {
+                    panic!("bad packet")
+                }
+                collector.push(vec![CollectedMetric::Gauge(UNIX_EPOCH, MetricId::from("queue"), 1.0)]);
+                while stop.sleep(Duration::from_secs(1)) {}
+            });
+        agent.start().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while agent.db().stats().backlog < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(agent.db().stats().backlog, 2);
+        agent.shutdown();
+    }
+}
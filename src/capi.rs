@@ -0,0 +1,372 @@
+//! A C API for embedding an agent in services written in other languages,
+//! declared in `include/metriqs.h`. Build it as a shared library with:
+//!
+//! ```text
+//! cargo rustc --release --lib --features capi --crate-type cdylib
+//! ```
+//!
+//! An agent is created empty or from a config file, given StatsD listeners,
+//! and started; metrics are recorded through a `metriqs_metrics` handle
+//! (see `client`) and go through the agent's pipeline like received ones:
+//!
+//! ```c
+//! metriqs_agent *agent = metriqs_agent_from_config("/etc/metriqs.toml");
+//! metriqs_agent_listen_statsd_udp(agent, "127.0.0.1:8125");
+//! metriqs_agent_start(agent);
+//! metriqs_metrics *metrics = metriqs_metrics_new(agent);
+//! const char *dimensions[] = {"endpoint", "/users"};
+//! metriqs_metrics_count(metrics, "requests", 1, dimensions, 1);
+//! metriqs_metrics_free(metrics);
+//! metriqs_agent_shutdown(agent, 5.0);
+//! ```
+//!
+//! Functions that can fail return 0 on success, or -1 (or a null pointer)
+//! with the reason available from `metriqs_last_error`. Recording and
+//! creating agents and handles also fail this way rather than unwinding
+//! into the caller if they panic.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_double, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::time::Duration;
+
+use super::agent::Agent;
+use super::client::Metrics;
+use super::config::Config;
+use super::db::DbOptions;
+use super::recv::push::statsd::{StatsdTcpListener, StatsdUdpListener};
+use super::supervisor::panic_message;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Only `None` while a builder method is replacing the agent, since they
+/// take it by value.
+pub struct MetriqsAgent {
+    agent: Option<Agent>,
+}
+
+impl MetriqsAgent {
+    fn new(agent: Agent) -> *mut MetriqsAgent {
+        Box::into_raw(Box::new(MetriqsAgent { agent: Some(agent) }))
+    }
+
+    fn build<F>(&mut self, build: F)
+        where F: FnOnce(Agent) -> Agent
+    {
+        self.agent = self.agent.take().map(build)
+    }
+
+    fn agent(&self) -> &Agent {
+        self.agent.as_ref().expect("agent is being built")
+    }
+
+    fn agent_mut(&mut self) -> &mut Agent {
+        self.agent.as_mut().expect("agent is being built")
+    }
+}
+
+pub struct MetriqsMetrics {
+    metrics: Metrics,
+}
+
+/// The reason the last failing call on this thread failed, or null. Valid
+/// until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn metriqs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |error| error.as_ptr()))
+}
+
+fn fail<E: ToString>(error: E) -> c_int {
+    let error = CString::new(error.to_string().replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(error));
+    -1
+}
+
+/// Run `body`, or return `failed` if it panics, since unwinding across the
+/// FFI boundary aborts the embedding process.
+fn guard<T, F>(failed: T, body: F) -> T
+    where F: FnOnce() -> T
+{
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(result) => result,
+        Err(panic) => {
+            fail(format!("panicked: {}", panic_message(&*panic)));
+            failed
+        },
+    }
+}
+
+/// An agent with a default Db and no listeners or exporters.
+#[no_mangle]
+pub extern "C" fn metriqs_agent_new() -> *mut MetriqsAgent {
+    guard(ptr::null_mut(), || MetriqsAgent::new(Agent::new(DbOptions::default())))
+}
+
+/// An agent described by the config file at `path` (see `config`), or null
+/// if it can't be loaded.
+///
+/// # Safety
+///
+/// `path` must be a null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn metriqs_agent_from_config(path: *const c_char) -> *mut MetriqsAgent {
+    guard(ptr::null_mut(), || {
+        let agent = string(path).and_then(|path| Config::load(path).and_then(Agent::from_config));
+        match agent {
+            Ok(agent) => MetriqsAgent::new(agent),
+            Err(err) => {
+                fail(err);
+                ptr::null_mut()
+            },
+        }
+    })
+}
+
+/// Listen for StatsD over UDP on `address` once started.
+///
+/// # Safety
+///
+/// `agent` must come from `metriqs_agent_new` or `metriqs_agent_from_config`
+/// and `address` must be a null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn metriqs_agent_listen_statsd_udp(agent: *mut MetriqsAgent, address: *const c_char) -> c_int {
+    let (handle, address) = match handle(agent).and_then(|handle| Ok((handle, string(address)?.to_string()))) {
+        Ok(args) => args,
+        Err(err) => return fail(err),
+    };
+    handle.build(|agent| agent.listener(move |collector, stop| {
+        if let Err(err) = StatsdUdpListener::new(collector).listen_until(address.as_str(), &stop) {
+            error!("Error listening for StatsD on {}: {}", address, err)
+        }
+    }));
+    0
+}
+
+/// Listen for StatsD over TCP on `address` once started. The address is
+/// bound right away so that failing to bind it is reported here.
+///
+/// # Safety
+///
+/// Same as `metriqs_agent_listen_statsd_udp`.
+#[no_mangle]
+pub unsafe extern "C" fn metriqs_agent_listen_statsd_tcp(agent: *mut MetriqsAgent, address: *const c_char) -> c_int {
+    let (handle, address) = match handle(agent).and_then(|handle| Ok((handle, string(address)?))) {
+        Ok(args) => args,
+        Err(err) => return fail(err),
+    };
+    let mut listener = match StatsdTcpListener::new(handle.agent().db().collector(), address) {
+        Ok(listener) => listener,
+        Err(err) => return fail(format!("couldn't listen on {}: {}", address, err)),
+    };
+    handle.build(|agent| agent.listener(move |_, stop| if let Err(err) = listener.listen_until(&stop) {
+        error!("Error listening for StatsD: {}", err)
+    }));
+    0
+}
+
+/// Start the agent's listeners, exporters, and Db loops (see `Agent::start`).
+///
+/// # Safety
+///
+/// `agent` must come from `metriqs_agent_new` or `metriqs_agent_from_config`.
+#[no_mangle]
+pub unsafe extern "C" fn metriqs_agent_start(agent: *mut MetriqsAgent) -> c_int {
+    match handle(agent).and_then(|handle| handle.agent_mut().start()) {
+        Ok(()) => 0,
+        Err(err) => fail(err),
+    }
+}
+
+/// Aggregate and publish everything collected so far.
+///
+/// # Safety
+///
+/// Same as `metriqs_agent_start`.
+#[no_mangle]
+pub unsafe extern "C" fn metriqs_agent_flush(agent: *mut MetriqsAgent) -> c_int {
+    match handle(agent) {
+        Ok(handle) => {
+            handle.agent().flush();
+            0
+        },
+        Err(err) => fail(err),
+    }
+}
+
+/// Stop the agent's listeners, run a final aggregation, wait up to
+/// `timeout_seconds` for the exporters to drain it, and free the agent.
+/// Returns -1 if they didn't finish in time.
+///
+/// # Safety
+///
+/// Same as `metriqs_agent_start`; `agent` can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn metriqs_agent_shutdown(agent: *mut MetriqsAgent, timeout_seconds: c_double) -> c_int {
+    if agent.is_null() {
+        return fail("agent is null")
+    }
+    let timeout = Duration::try_from_secs_f64(timeout_seconds).unwrap_or_default();
+    if Box::from_raw(agent).agent.is_none_or(|agent| agent.shutdown_timeout(timeout)) {
+        0
+    } else {
+        fail(format!("gave up shutting down after {} seconds", timeout_seconds))
+    }
+}
+
+/// Record metrics into `agent`'s Db. The handle can be used from any
+/// thread and can outlive the agent, although metrics recorded after it's
+/// shut down aren't aggregated.
+///
+/// # Safety
+///
+/// Same as `metriqs_agent_start`.
+#[no_mangle]
+pub unsafe extern "C" fn metriqs_metrics_new(agent: *mut MetriqsAgent) -> *mut MetriqsMetrics {
+    guard(ptr::null_mut(), || match handle(agent) {
+        Ok(handle) => Box::into_raw(Box::new(MetriqsMetrics { metrics: Metrics::new(handle.agent().db().collector()) })),
+        Err(err) => {
+            fail(err);
+            ptr::null_mut()
+        },
+    })
+}
+
+/// # Safety
+///
+/// `metrics` must come from `metriqs_metrics_new` and can't be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn metriqs_metrics_free(metrics: *mut MetriqsMetrics) {
+    if !metrics.is_null() {
+        drop(Box::from_raw(metrics))
+    }
+}
+
+/// Record a count. `dimensions` is `dimensions_len` key and value pairs, ie.
+/// `2 * dimensions_len` strings, and may be null if there are none.
+///
+/// # Safety
+///
+/// `metrics` must come from `metriqs_metrics_new`, and `name` and every
+/// dimension must be null-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn metriqs_metrics_count(metrics: *const MetriqsMetrics, name: *const c_char, value: i64, dimensions: *const *const c_char, dimensions_len: usize) -> c_int {
+    record(metrics, name, dimensions, dimensions_len, |metrics, name, dimensions| metrics.count(name, value, dimensions))
+}
+
+/// Record a gauge; see `metriqs_metrics_count`.
+///
+/// # Safety
+///
+/// Same as `metriqs_metrics_count`.
+#[no_mangle]
+pub unsafe extern "C" fn metriqs_metrics_gauge(metrics: *const MetriqsMetrics, name: *const c_char, value: c_double, dimensions: *const *const c_char, dimensions_len: usize) -> c_int {
+    record(metrics, name, dimensions, dimensions_len, |metrics, name, dimensions| metrics.gauge(name, value, dimensions))
+}
+
+/// Record a histogram sample; see `metriqs_metrics_count`.
+///
+/// # Safety
+///
+/// Same as `metriqs_metrics_count`.
+#[no_mangle]
+pub unsafe extern "C" fn metriqs_metrics_histogram(metrics: *const MetriqsMetrics, name: *const c_char, value: c_double, dimensions: *const *const c_char, dimensions_len: usize) -> c_int {
+    record(metrics, name, dimensions, dimensions_len, |metrics, name, dimensions| metrics.histogram(name, value, dimensions))
+}
+
+/// Record a timing in milliseconds, a histogram sample like StatsD timers;
+/// see `metriqs_metrics_count`. Fails for ones too long to be a duration.
+///
+/// # Safety
+///
+/// Same as `metriqs_metrics_count`.
+#[no_mangle]
+pub unsafe extern "C" fn metriqs_metrics_timing(metrics: *const MetriqsMetrics, name: *const c_char, milliseconds: c_double, dimensions: *const *const c_char, dimensions_len: usize) -> c_int {
+    let duration = match Duration::try_from_secs_f64(milliseconds.max(0.0) / 1000.0) {
+        Ok(duration) => duration,
+        Err(_) => return fail(format!("invalid timing of {} milliseconds", milliseconds)),
+    };
+    record(metrics, name, dimensions, dimensions_len, |metrics, name, dimensions| metrics.timing(name, duration, dimensions))
+}
+
+unsafe fn handle<'a>(agent: *mut MetriqsAgent) -> Result<&'a mut MetriqsAgent, String> {
+    agent.as_mut().ok_or_else(|| "agent is null".to_string())
+}
+
+unsafe fn record<F>(metrics: *const MetriqsMetrics, name: *const c_char, dimensions: *const *const c_char, dimensions_len: usize, record: F) -> c_int
+    where F: FnOnce(&Metrics, &str, &[(&str, &str)])
+{
+    guard(-1, || record_unguarded(metrics, name, dimensions, dimensions_len, record))
+}
+
+unsafe fn record_unguarded<F>(metrics: *const MetriqsMetrics, name: *const c_char, dimensions: *const *const c_char, dimensions_len: usize, record: F) -> c_int
+    where F: FnOnce(&Metrics, &str, &[(&str, &str)])
+{
+    let metrics = match metrics.as_ref() {
+        Some(metrics) => &metrics.metrics,
+        None => return fail("metrics is null"),
+    };
+    let name = match string(name) {
+        Ok(name) => name,
+        Err(err) => return fail(err),
+    };
+    let strings: &[*const c_char] = if dimensions_len == 0 {
+        &[]
+    } else if dimensions.is_null() {
+        return fail("dimensions is null")
+    } else {
+        slice::from_raw_parts(dimensions, dimensions_len * 2)
+    };
+    let mut pairs = Vec::with_capacity(dimensions_len);
+    for pair in strings.chunks(2) {
+        match (string(pair[0]), string(pair[1])) {
+            (Ok(key), Ok(value)) => pairs.push((key, value)),
+            (Err(err), _) | (_, Err(err)) => return fail(err),
+        }
+    }
+    record(metrics, name, &pairs);
+    0
+}
+
+unsafe fn string<'a>(string: *const c_char) -> Result<&'a str, String> {
+    if string.is_null() {
+        return Err("string is null".to_string())
+    }
+    CStr::from_ptr(string).to_str().map_err(|_| "string isn't UTF-8".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::CString;
+
+    #[test]
+    fn it_records_through_an_agent() {
+        unsafe {
+            let agent = metriqs_agent_new();
+            let db = (*agent).agent().db().clone();
+            assert_eq!(metriqs_agent_start(agent), 0);
+
+            let metrics = metriqs_metrics_new(agent);
+            let name = CString::new("requests").unwrap();
+            let (key, value) = (CString::new("endpoint").unwrap(), CString::new("/users").unwrap());
+            let dimensions = [key.as_ptr(), value.as_ptr()];
+            assert_eq!(metriqs_metrics_count(metrics, name.as_ptr(), 2, dimensions.as_ptr(), 1), 0);
+            assert_eq!(metriqs_metrics_timing(metrics, name.as_ptr(), 1.5, ptr::null(), 0), 0);
+            assert_eq!(db.stats().backlog, 2);
+            assert_eq!(metriqs_metrics_timing(metrics, name.as_ptr(), f64::INFINITY, ptr::null(), 0), -1);
+            assert_eq!(CStr::from_ptr(metriqs_last_error()).to_str().unwrap(), "invalid timing of inf milliseconds");
+
+            assert_eq!(metriqs_metrics_gauge(metrics, ptr::null(), 1.0, ptr::null(), 0), -1);
+            assert_eq!(CStr::from_ptr(metriqs_last_error()).to_str().unwrap(), "string is null");
+            metriqs_metrics_free(metrics);
+            assert_eq!(metriqs_agent_shutdown(agent, 5.0), 0);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Recording metrics from the application embedding metriqs, straight into
+//! a Db's collector instead of formatting StatsD lines for a listener:
+//!
+//! ```ignore
+//! let metrics = Metrics::new(db.collector());
+//! metrics.incr("requests", &[("endpoint", "/users")]);
+//! metrics.gauge("queue.depth", 12.0, &[]);
+//! let users = metrics.time("db.query", &[("table", "users")], || load_users());
+//! ```
+//!
+//! The `counter!`, `gauge!`, `histogram!`, and `time!` macros are
+//! shorthands for the same calls, with dimensions as `key => value` pairs:
+//!
+//! ```ignore
+//! counter!(metrics, "requests", "endpoint" => "/users");
+//! gauge!(metrics, "queue.depth", 12.0);
+//! let users = time!(metrics, "db.query", "table" => "users"; load_users());
+//! ```
+//!
+//! To time a block rather than a closure, `timer` returns a guard that
+//! records when it's dropped, or earlier with `Timer::stop`:
+//!
+//! ```ignore
+//! let _timer = metrics.timer("request", &[("endpoint", "/users")]);
+//! ```
+//!
+//! Metrics go through the Db's pipeline like received ones. Timings are
+//! histograms in milliseconds, like StatsD timers. Recording at high call
+//! rates, a `buffered` collector pushes them in batches instead:
+//!
+//! ```ignore
+//! let metrics = Metrics::new(db.collector().buffered(BufferingOptions::default()));
+//! ```
+
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use string_cache::DefaultAtom as Atom;
+
+use super::metric::{CollectedMetric, MetricId};
+use super::recv::Collector;
+
+/// Clones record to the same collector, eg. one per thread.
+#[derive(Clone)]
+pub struct Metrics {
+    collector: Arc<Collector>,
+}
+
+impl Metrics {
+    pub fn new(collector: Collector) -> Metrics {
+        Metrics { collector: Arc::new(collector) }
+    }
+
+    pub fn incr(&self, name: &str, dimensions: &[(&str, &str)]) {
+        self.count(name, 1, dimensions)
+    }
+
+    pub fn count(&self, name: &str, value: i64, dimensions: &[(&str, &str)]) {
+        self.record(CollectedMetric::Count(SystemTime::now(), id(name, dimensions), value))
+    }
+
+    pub fn gauge(&self, name: &str, value: f64, dimensions: &[(&str, &str)]) {
+        self.record(CollectedMetric::Gauge(SystemTime::now(), id(name, dimensions), value))
+    }
+
+    pub fn histogram(&self, name: &str, value: f64, dimensions: &[(&str, &str)]) {
+        self.record(CollectedMetric::Histogram(SystemTime::now(), id(name, dimensions), value))
+    }
+
+    /// Count `member` towards the unique members seen this interval.
+    pub fn set(&self, name: &str, member: &str, dimensions: &[(&str, &str)]) {
+        self.record(CollectedMetric::Set(SystemTime::now(), id(name, dimensions), member.to_string()))
+    }
+
+    /// Record `elapsed` as a histogram sample in milliseconds.
+    pub fn timing(&self, name: &str, elapsed: Duration, dimensions: &[(&str, &str)]) {
+        self.histogram(name, elapsed.as_secs_f64() * 1000.0, dimensions)
+    }
+
+    /// Call `f` and record how long it took (see `timing`).
+    pub fn time<F, R>(&self, name: &str, dimensions: &[(&str, &str)], f: F) -> R
+        where F: FnOnce() -> R
+    {
+        let started = Instant::now();
+        let result = f();
+        self.timing(name, started.elapsed(), dimensions);
+        result
+    }
+
+    /// Start timing; the elapsed time is recorded once the returned timer
+    /// is stopped or dropped (see `timing`).
+    pub fn timer(&self, name: &str, dimensions: &[(&str, &str)]) -> Timer {
+        Timer {
+            metrics: self.clone(),
+            name: name.to_string(),
+            dimensions: dimensions.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect(),
+            started: Instant::now(),
+            recorded: false,
+        }
+    }
+
+    fn record(&self, metric: CollectedMetric) {
+        self.collector.push(vec![metric])
+    }
+}
+
+/// Times from when it's created by `Metrics::timer` until it's stopped or
+/// dropped, recording the elapsed time once.
+pub struct Timer {
+    metrics: Metrics,
+    name: String,
+    dimensions: Vec<(String, String)>,
+    started: Instant,
+    recorded: bool,
+}
+
+impl Timer {
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Record the elapsed time now rather than when dropped, and return it.
+    pub fn stop(mut self) -> Duration {
+        self.record()
+    }
+
+    fn record(&mut self) -> Duration {
+        let elapsed = self.started.elapsed();
+        if !self.recorded {
+            self.recorded = true;
+            let dimensions = self.dimensions.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect::<Vec<(&str, &str)>>();
+            self.metrics.timing(&self.name, elapsed, &dimensions);
+        }
+        elapsed
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.record();
+    }
+}
+
+fn id(name: &str, dimensions: &[(&str, &str)]) -> MetricId {
+    MetricId::new(name, dimensions.iter().map(|&(key, value)| (Atom::from(key), Atom::from(value))).collect())
+}
+
+/// Increment a count by one: `counter!(metrics, "requests", "host" => "a")`.
+#[macro_export]
+macro_rules! counter {
+    ($metrics:expr, $name:expr $(, $key:expr => $value:expr)* $(,)*) => {
+        $metrics.incr($name, &[$(($key, $value)),*])
+    };
+}
+
+/// Record a gauge: `gauge!(metrics, "queue.depth", 12.0, "host" => "a")`.
+#[macro_export]
+macro_rules! gauge {
+    ($metrics:expr, $name:expr, $gauge:expr $(, $key:expr => $value:expr)* $(,)*) => {
+        $metrics.gauge($name, $gauge, &[$(($key, $value)),*])
+    };
+}
+
+/// Record a histogram sample: `histogram!(metrics, "bytes", 512.0)`.
+#[macro_export]
+macro_rules! histogram {
+    ($metrics:expr, $name:expr, $sample:expr $(, $key:expr => $value:expr)* $(,)*) => {
+        $metrics.histogram($name, $sample, &[$(($key, $value)),*])
+    };
+}
+
+/// Evaluate an expression and record how long it took, evaluating to its
+/// result: `time!(metrics, "db.query", "table" => "users"; load_users())`.
+#[macro_export]
+macro_rules! time {
+    ($metrics:expr, $name:expr $(, $key:expr => $value:expr)*; $body:expr) => {
+        $metrics.time($name, &[$(($key, $value)),*], || $body)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::db::{AggregatedMetric, Db, DbOptions};
+
+    #[test]
+    fn it_records_through_the_macros() {
+        let db = Db::new(DbOptions::default());
+        let subscription = db.aggregation_subscribe();
+        let metrics = Metrics::new(db.collector());
+
+        counter!(metrics, "requests", "endpoint" => "/users");
+        counter!(metrics, "requests", "endpoint" => "/users",);
+        gauge!(metrics, "queue.depth", 12.0);
+        let answer = time!(metrics, "work", "kind" => "sum"; 40 + 2);
+        assert_eq!(answer, 42);
+        assert_eq!(db.stats().backlog, 4);
+
+        db.aggregate(None);
+        let aggregated = subscription.recv().unwrap();
+        let requests = MetricId::from("requests").with_dimension("endpoint", "/users");
+        assert!(aggregated.iter().any(|metric| match *metric {
+            AggregatedMetric::Count(_, ref id, 2) => *id == requests,
+            _ => false,
+        }));
+        assert!(aggregated.iter().any(|metric| match *metric {
+            AggregatedMetric::Gauge(_, ref id, value) => *id == MetricId::from("queue.depth") && value == 12.0,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn it_records_timers_once() {
+        let db = Db::new(DbOptions::default());
+        let metrics = Metrics::new(db.collector());
+
+        {
+            let _timer = metrics.timer("request", &[("endpoint", "/users")]);
+        }
+        let timer = metrics.timer("request", &[]);
+        let elapsed = timer.stop();
+        assert!(elapsed < Duration::from_secs(1));
+        assert_eq!(db.stats().backlog, 2);
+    }
+}
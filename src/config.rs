@@ -0,0 +1,1433 @@
+//! Declarative configuration of a whole agent in TOML: the Db, the stages
+//! collected metrics go through, the listeners and pollers that receive
+//! them, and the exporters that send aggregations on.
+//!
+//! ```toml
+//! [db]
+//! aggregation_interval = "10s"
+//! default_dimensions = { env = "prod" }
+//! name_filter = ["!debug.*"]
+//!
+//! [[mapping]]
+//! match = "api.*.*.latency"
+//! name = "api_latency"
+//! dimensions = { env = "$1", endpoint = "$2" }
+//!
+//! [[listener]]
+//! type = "statsd_udp"
+//! address = "0.0.0.0:8125"
+//!
+//! [[exporter]]
+//! type = "graphite"
+//! address = "carbon:2003"
+//! include = ["service.*"]
+//! ```
+//!
+//! `Config::start` runs the listeners and exporters, and `Workers::reload`
+//! later switches them (and the Db's stages) to another config, restarting
+//! only what changed.
+//!
+//! Durations are either a number of seconds or a string with a unit, eg.
+//! `"500ms"`, `"10s"`, `"5m"`, or `"1h"`. Unknown keys are errors so that
+//! typos don't silently fall back to defaults.
+//!
+//! Every table's keys are the fields of the options it builds, so see
+//! their docs for what each does and defaults to. Enums are written as
+//! their variants in snake case, eg. `histogram_mode = "sketch"`.
+//!
+//! - `[db]`: `DbOptions`.
+//! - `[cluster]`, `[mirror]`, and `[enrichment]`: `ClusterOptions`,
+//!   `MirrorOptions`, and `EnrichmentOptions`.
+//! - `[health]`, `[admin]`, and `[grpc]`: an `address` to serve health
+//!   checks (with `HealthOptions`), the admin API, or gRPC streams on;
+//!   only read by `agent::Agent`.
+//! - `[[mapping]]`, `[[relabel]]`, `[[scrub]]`, `[[sampling]]`, and
+//!   `[[derived]]`: a `NameMapping`, `RelabelRule`, `ScrubRule`,
+//!   `SamplingRule`, or `DerivedMetric` each, in order.
+//! - `[[listener]]`: a `type` and that listener's or poller's keys (see
+//!   `Listener`).
+//! - `[[exporter]]`: a `type` and that sender's keys (see `Exporter`).
+
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use string_cache::DefaultAtom as Atom;
+use toml::{Table, Value};
+
+use super::db::{AggregatedMetric, CardinalityOverflow, Db, DbOptions, DerivedMetric, GaugeAggregation, HistogramMode, Overflow, SetMode, SubscriptionBuffer, SubscriptionFilter, SubscriptionToken, WindowClock};
+use super::health::{Health, HealthOptions, Probe};
+use super::supervisor::Supervisor;
+use super::recv::{ClusterOptions, EnrichmentOptions, MetadataSource, MirrorOptions, NameFilter, NameMapping, NegativeCounts, RelabelAction, RelabelRule, SamplingRule, ScrubAction, ScrubRule};
+use super::recv::pull::{snmp, CgroupOptions, CgroupPoller, ExecFormat, ExecOptions, ExecPoller, SnmpDevice, SnmpKind, SnmpOid, SnmpOptions, SnmpPoller};
+use super::recv::push::statsd::{Capture, CaptureOptions, Linter, RepeatOptions, Repeater, StatsdTcpListener, StatsdTcpOptions, StatsdUdpListener, StatsdUdpOptions};
+use super::send::{self, BufferedSender, CloudWatchOptions, DeliveryOptions, ElasticsearchOptions, FileOptions, GraphiteOptions, NatsOptions, OtlpOptions, PostgresOptions, StatsdOptions, StatsdTransport, WavefrontOptions};
+use super::util::{Glob, Stop};
+
+const SECTIONS: [&str; 14] = ["db", "cluster", "mirror", "enrichment", "health", "admin", "grpc", "mapping", "relabel", "scrub", "sampling", "derived", "listener", "exporter"];
+
+/// A parsed and validated configuration. Nothing is bound or connected
+/// until it's started.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    root: Table,
+}
+
+impl Config {
+    pub fn parse(input: &str) -> Result<Config, String> {
+        let root = input.parse::<Table>().map_err(|err| err.to_string())?;
+        if let Some(key) = root.keys().find(|key| !SECTIONS.contains(&key.as_str())) {
+            return Err(format!("unknown section `{}`", key))
+        }
+
+        let config = Config { root };
+        config.db_options()?;
+        for section in config.listeners()? {
+            listener_spec(&section)?;
+        }
+        for section in config.exporters()? {
+            exporter_spec(&section, None)?;
+        }
+        config.health()?;
+        config.admin()?;
+        config.grpc()?;
+        Ok(config)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, String> {
+        let input = fs::read_to_string(path.as_ref())
+            .map_err(|err| format!("couldn't read {}: {}", path.as_ref().display(), err))?;
+        Config::parse(&input)
+    }
+
+    pub fn db_options(&self) -> Result<DbOptions, String> {
+        let empty = Table::new();
+        let db = Section::new("db".to_string(), match self.root.get("db") {
+            Some(Value::Table(table)) => table,
+            Some(_) => return Err("`db` must be a table".to_string()),
+            None => &empty,
+        });
+
+        let mut options = DbOptions {
+            aggregation_interval: db.duration("aggregation_interval")?,
+            align_aggregation: db.bool("align_aggregation")?.unwrap_or(false),
+            standby: db.bool("standby")?.unwrap_or(false),
+            streaming: db.bool("streaming")?,
+            shards: db.integer("shards")?.map(|shards| shards as usize),
+            rollup_threads: db.integer("rollup_threads")?.map(|threads| threads as usize),
+            pooled_buffers: db.integer("pooled_buffers")?.map(|max| max as usize),
+            retention: db.duration("retention")?,
+            max_points_per_series: db.integer("max_points_per_series")?.map(|max| max as usize),
+            max_series: db.integer("max_series")?.map(|max| max as usize),
+            report_idle_gauges: db.bool("report_idle_gauges")?,
+            forget_gauges_after: db.integer("forget_gauges_after")?.map(|after| after as u32),
+            forget_totals_after: db.integer("forget_totals_after")?.map(|after| after as u32),
+            stale_after: db.integer("stale_after")?.map(|after| after as u32),
+            stale_markers: db.bool("stale_markers")?,
+            heartbeat: db.string("heartbeat")?,
+            lateness: db.duration("lateness")?,
+            correction_dimension: db.string("correction_dimension")?,
+            retain_aggregates: db.bool("retain_aggregates")?,
+            snapshot_path: db.string("snapshot_path")?.map(PathBuf::from),
+            snapshot_interval: db.duration("snapshot_interval")?,
+            percentiles: db.floats("percentiles")?,
+            histogram_buckets: db.floats("histogram_buckets")?,
+            default_dimensions: db.pairs("default_dimensions")?.into_iter()
+                .map(|(key, value)| (Atom::from(key), Atom::from(value)))
+                .collect(),
+            name_filter: db.strings("name_filter")?.map(|patterns| NameFilter::new(&patterns)),
+            ..DbOptions::default()
+        };
+        options.gauge_aggregation = match db.string("gauge_aggregation")?.as_deref() {
+            None => None,
+            Some("last") => Some(GaugeAggregation::Last),
+            Some("min") => Some(GaugeAggregation::Min),
+            Some("max") => Some(GaugeAggregation::Max),
+            Some("mean") => Some(GaugeAggregation::Mean),
+            Some("sum") => Some(GaugeAggregation::Sum),
+            Some(_) => return Err(db.invalid("gauge_aggregation", "one of last, min, max, mean, or sum")),
+        };
+        options.histogram_mode = match db.string("histogram_mode")?.as_deref() {
+            None => None,
+            Some("summary") => Some(HistogramMode::Summary),
+            Some("sketch") => Some(HistogramMode::Sketch(db.float("sketch_accuracy")?.unwrap_or(0.01))),
+            Some(_) => return Err(db.invalid("histogram_mode", "summary or sketch")),
+        };
+        options.set_mode = match db.string("set_mode")?.as_deref() {
+            None => None,
+            Some("exact") => Some(SetMode::Exact),
+            Some("hyperloglog") => Some(SetMode::HyperLogLog(db.integer("hyperloglog_precision")?.unwrap_or(14) as u8)),
+            Some(_) => return Err(db.invalid("set_mode", "exact or hyperloglog")),
+        };
+        let overflow = match db.string("subscription_overflow")?.as_deref() {
+            None | Some("drop_oldest") => Overflow::DropOldest,
+            Some("coalesce") => Overflow::Coalesce,
+            Some("block") => Overflow::Block(db.duration("subscription_block_timeout")?.unwrap_or_else(|| Duration::from_secs(1))),
+            Some(_) => return Err(db.invalid("subscription_overflow", "one of drop_oldest, coalesce, or block")),
+        };
+        options.subscription_buffer = db.integer("subscription_buffer")?
+            .map(|capacity| SubscriptionBuffer { capacity: capacity as usize, overflow });
+        options.window_clock = match db.string("window_clock")?.as_deref() {
+            None => None,
+            Some("wall") => Some(WindowClock::Wall),
+            Some("monotonic") => Some(WindowClock::Monotonic),
+            Some(_) => return Err(db.invalid("window_clock", "wall or monotonic")),
+        };
+        options.negative_counts = match db.string("negative_counts")?.as_deref() {
+            None => None,
+            Some("accept") => Some(NegativeCounts::Accept),
+            Some("clamp") => Some(NegativeCounts::Clamp),
+            Some("drop") => Some(NegativeCounts::Drop),
+            Some(_) => return Err(db.invalid("negative_counts", "accept, clamp, or drop")),
+        };
+        options.cardinality_overflow = match db.string("cardinality_overflow")?.as_deref() {
+            None => None,
+            Some("drop") => Some(CardinalityOverflow::Drop),
+            Some("collapse") => Some(CardinalityOverflow::Collapse),
+            Some(_) => return Err(db.invalid("cardinality_overflow", "drop or collapse")),
+        };
+        db.finish()?;
+
+        for section in self.sections("mapping")? {
+            let dimensions = section.pairs("dimensions")?;
+            options.mappings.push(NameMapping::new(&section.required_string("match")?, &section.required_string("name")?, &dimensions));
+            section.finish()?;
+        }
+        for section in self.sections("relabel")? {
+            options.relabel.push(relabel_rule(&section)?);
+            section.finish()?;
+        }
+        for section in self.sections("scrub")? {
+            options.scrub.push(scrub_rule(&section)?);
+            section.finish()?;
+        }
+        for section in self.sections("sampling")? {
+            let rate = section.float("rate")?;
+            if rate.is_some_and(|rate| !(rate > 0.0 && rate <= 1.0)) {
+                return Err(section.invalid("rate", "greater than 0 and at most 1"))
+            }
+            options.sampling.push(SamplingRule {
+                names: Glob::new(section.required_string("names")?),
+                rate,
+                max_per_window: section.integer("max_per_window")?.map(|max| max as usize),
+                window: section.duration("window")?,
+            });
+            section.finish()?;
+        }
+        for section in self.sections("derived")? {
+            let name = section.required_string("name")?;
+            let derived = DerivedMetric::new(&name, &section.required_string("expression")?)
+                .map_err(|err| format!("{}: invalid expression: {}", section.name, err))?;
+            options.derived.push(derived);
+            section.finish()?;
+        }
+        if let Some(section) = self.table("cluster")? {
+            let (peers, local) = (section.strings("peers")?.unwrap_or_default(), section.required_string("local")?);
+            if !peers.contains(&local) {
+                return Err(section.invalid("local", "one of `peers`"))
+            }
+            options.cluster = Some(ClusterOptions {
+                peers,
+                local,
+                transport: transport(&section, "transport")?,
+                virtual_nodes: section.integer("virtual_nodes")?.map(|nodes| nodes as usize),
+                max_pending: section.integer("max_pending")?.map(|max| max as usize),
+            });
+            section.finish()?;
+        }
+        if let Some(section) = self.table("mirror")? {
+            options.mirror = Some(MirrorOptions {
+                address: section.required_string("address")?,
+                transport: transport(&section, "transport")?,
+                max_pending: section.integer("max_pending")?.map(|max| max as usize),
+            });
+            section.finish()?;
+        }
+        if let Some(section) = self.table("enrichment")? {
+            let sources = section.strings("sources")?.unwrap_or_default().iter()
+                .map(|source| match source.as_str() {
+                    "ec2" => Ok(MetadataSource::Ec2),
+                    "gce" => Ok(MetadataSource::Gce),
+                    "kubernetes" => Ok(MetadataSource::Kubernetes),
+                    _ => Err(section.invalid("sources", "ec2, gce, or kubernetes")),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            options.enrichment = Some(EnrichmentOptions {
+                sources,
+                refresh_interval: section.duration("refresh_interval")?,
+                timeout: section.duration("timeout")?,
+                ..EnrichmentOptions::default()
+            });
+            section.finish()?;
+        }
+        Ok(options)
+    }
+
+    /// Where to serve health checks (see `health`) and how, if anywhere.
+    pub fn health(&self) -> Result<Option<(String, HealthOptions)>, String> {
+        let section = match self.table("health")? {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        let address = section.required_string("address")?;
+        let options = HealthOptions {
+            max_backlog: section.integer("max_backlog")?.map(|max| max as usize),
+        };
+        section.finish()?;
+        Ok(Some((address, options)))
+    }
+
+    /// Where to serve the admin API (see `admin`), if anywhere.
+    pub fn admin(&self) -> Result<Option<String>, String> {
+        let section = match self.table("admin")? {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        let address = section.required_string("address")?;
+        section.finish()?;
+        Ok(Some(address))
+    }
+
+    /// Where to serve the gRPC API (see `send::grpc`), if anywhere.
+    pub fn grpc(&self) -> Result<Option<String>, String> {
+        let section = match self.table("grpc")? {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        let address = section.required_string("address")?;
+        section.finish()?;
+        Ok(Some(address))
+    }
+
+    /// Start every exporter and then every listener and poller, each on
+    /// its own thread. Exporters subscribe to `db` and listeners push to
+    /// collectors of it. Fails without starting anything if any can't be
+    /// created, eg. because an address doesn't resolve.
+    pub fn start(&self, db: &Db) -> Result<Workers, String> {
+        let mut workers = Workers {
+            config: Config::default(),
+            listeners: vec![],
+            exporters: vec![],
+            health: Health::new(),
+            supervisor: Supervisor::new(db.collector()),
+        };
+        workers.apply(self.clone(), db)?;
+        Ok(workers)
+    }
+
+    /// Start only the StatsD listeners, each on its own thread, linting
+    /// what they receive with `linter` instead of collecting it (see
+    /// `Linter`). Nothing is repeated or captured, and pollers and
+    /// exporters aren't started. The listeners return once `stop` is
+    /// stopped.
+    pub fn lint(&self, linter: &Linter, stop: &Stop) -> Result<Vec<JoinHandle<()>>, String> {
+        // Listeners push their own telemetry, which is never aggregated, so
+        // it's dropped rather than queued.
+        let db = Db::new(DbOptions { name_filter: Some(NameFilter::new(&["!*"])), retain_aggregates: Some(false), ..DbOptions::default() });
+        let mut runs: Vec<Run> = vec![];
+        for section in self.listeners()? {
+            let (linter, until, name) = (linter.clone(), stop.clone(), section.name.clone());
+            match listener_spec(&section)? {
+                Listener::StatsdUdp(address, options, ..) => {
+                    let listener = StatsdUdpListener::with_options(db.collector(), options).lint(linter);
+                    runs.push(Box::new(move || if let Err(err) = listener.listen_until(address.as_str(), &until) {
+                        error!("{}: {}", name, err)
+                    }))
+                },
+                Listener::StatsdTcp(address, options, ..) => {
+                    let mut listener = StatsdTcpListener::with_options(db.collector(), address.as_str(), options)
+                        .map_err(|err| format!("{}: {}", section.name, err))?
+                        .lint(linter);
+                    runs.push(Box::new(move || if let Err(err) = listener.listen_until(&until) {
+                        error!("{}: {}", name, err)
+                    }))
+                },
+                Listener::Cgroup(..) | Listener::Exec(..) | Listener::Snmp(..) => {},
+            }
+        }
+        Ok(runs.into_iter().map(thread::spawn).collect())
+    }
+
+    fn table(&self, key: &str) -> Result<Option<Section<'_>>, String> {
+        match self.root.get(key) {
+            Some(Value::Table(table)) => Ok(Some(Section::new(key.to_string(), table))),
+            Some(_) => Err(format!("`{}` must be a table", key)),
+            None => Ok(None),
+        }
+    }
+
+    fn sections(&self, key: &str) -> Result<Vec<Section<'_>>, String> {
+        match self.root.get(key) {
+            None => Ok(vec![]),
+            Some(Value::Array(values)) => values.iter()
+                .enumerate()
+                .map(|(index, value)| match *value {
+                    Value::Table(ref table) => Ok(Section::new(format!("{}[{}]", key, index), table)),
+                    _ => Err(format!("`{}` must be an array of tables", key)),
+                })
+                .collect(),
+            Some(_) => Err(format!("`{}` must be an array of tables", key)),
+        }
+    }
+
+    fn listeners(&self) -> Result<Vec<Section<'_>>, String> {
+        self.sections("listener")
+    }
+
+    fn exporters(&self) -> Result<Vec<Section<'_>>, String> {
+        self.sections("exporter")
+    }
+}
+
+/// How long stopped workers are given to finish before their replacements
+/// start, or listeners to drain on shutdown.
+const STOP_GRACE: Duration = Duration::from_secs(1);
+
+/// The listeners and exporters started from a config.
+pub struct Workers {
+    config: Config,
+    listeners: Vec<Worker>,
+    exporters: Vec<Worker>,
+    health: Health,
+    supervisor: Supervisor,
+}
+
+impl Workers {
+    /// Switch to `config` without restarting the agent. The Db's stages,
+    /// including `[cluster]` and `[mirror]`, and derived metrics are
+    /// replaced (see `Db::reconfigure`), listeners
+    /// and exporters whose sections didn't change keep running, and the
+    /// rest are stopped or started. If any new listener or exporter can't
+    /// be created, or a listener can't bind, nothing changes and the error
+    /// is returned.
+    ///
+    /// Returns which settings changed but only apply to a new agent, eg.
+    /// `db.aggregation_interval`, `enrichment`, or `health`, so that they
+    /// can be reported.
+    pub fn reload(&mut self, config: Config, db: &Db) -> Result<Vec<String>, String> {
+        let options = config.db_options()?;
+        let empty = Value::Table(Table::new());
+        let (old, new) = (self.config.root.get("db").unwrap_or(&empty), config.root.get("db").unwrap_or(&empty));
+        let mut restart = vec![];
+        if let (Value::Table(old), Value::Table(new)) = (old, new) {
+            for key in old.keys().chain(new.keys().filter(|key| !old.contains_key(*key))) {
+                if key != "name_filter" && old.get(key) != new.get(key) {
+                    restart.push(format!("db.{}", key));
+                }
+            }
+        }
+        // The enricher is only created with the Db.
+        for key in &["enrichment", "health", "admin", "grpc"] {
+            if self.config.root.get(*key) != config.root.get(*key) {
+                restart.push(key.to_string());
+            }
+        }
+
+        self.apply(config, db)?;
+        db.reconfigure(options);
+        Ok(restart)
+    }
+
+    pub fn listeners(&self) -> usize {
+        self.listeners.len()
+    }
+
+    pub fn exporters(&self) -> usize {
+        self.exporters.len()
+    }
+
+    /// The running listeners and exporters, named after their sections or
+    /// an exporter's `name`, for the health checks.
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    /// Stop the listeners, giving them a moment to record what they've
+    /// already received, flush the Db so that what they collected is
+    /// exported, and then stop the exporters, waiting for them to finish.
+    pub fn stop(self, db: &Db) {
+        let health = &self.health;
+        let stopped = self.listeners.into_iter().map(|worker| worker.stop(db, health)).collect::<Vec<JoinHandle<()>>>();
+        wait_for(&stopped, STOP_GRACE);
+        db.flush();
+        for worker in self.exporters {
+            let _ = worker.stop(db, &self.health).join();
+        }
+    }
+
+    fn apply(&mut self, config: Config, db: &Db) -> Result<(), String> {
+        let (exporters, listeners) = (config.exporters()?, config.listeners()?);
+        let (exporters, _) = plan(&self.exporters, exporters);
+        let (listeners, kept) = plan(&self.listeners, listeners);
+        // Listeners that are replaced hand their sockets over to ones on
+        // the same address, which couldn't bind while they're running.
+        let replaced = self.listeners.iter().zip(&kept)
+            .filter(|&(_, &kept)| !kept)
+            .filter_map(|(worker, _)| worker.bound.as_ref())
+            .collect::<Vec<&Bound>>();
+
+        let mut pending = vec![];
+        let prepared = (|| -> Result<(), String> {
+            for (section, _) in exporters.iter().filter(|&(_, kept)| kept.is_none()) {
+                pending.push(prepare_exporter(section, db)?);
+            }
+            for (section, _) in listeners.iter().filter(|&(_, kept)| kept.is_none()) {
+                pending.push(prepare_listener(section, db, &replaced)?);
+            }
+            Ok(())
+        })();
+        if let Err(err) = prepared {
+            for pending in pending {
+                pending.cancel(db);
+            }
+            return Err(err)
+        }
+        let exporters = exporters.into_iter().map(|(_, kept)| kept).collect::<Vec<Option<usize>>>();
+        let listeners = listeners.into_iter().map(|(_, kept)| kept).collect::<Vec<Option<usize>>>();
+
+        // Pending workers are in the order they were prepared: the
+        // exporters' and then the listeners'.
+        let mut pending = pending.into_iter();
+        let mut stopped = vec![];
+        let mut old_exporters = self.exporters.drain(..).map(Some).collect::<Vec<Option<Worker>>>();
+        let mut old_listeners = self.listeners.drain(..).map(Some).collect::<Vec<Option<Worker>>>();
+        let exporters = exporters.into_iter().map(|kept| kept.map(|index| old_exporters[index].take().unwrap())).collect::<Vec<Option<Worker>>>();
+        let listeners = listeners.into_iter().map(|kept| kept.map(|index| old_listeners[index].take().unwrap())).collect::<Vec<Option<Worker>>>();
+        for worker in old_exporters.into_iter().chain(old_listeners).flatten() {
+            stopped.push(worker.stop(db, &self.health));
+        }
+        wait_for(&stopped, STOP_GRACE);
+
+        let (health, supervisor) = (&self.health, &self.supervisor);
+        self.exporters = exporters.into_iter().map(|kept| kept.unwrap_or_else(|| pending.next().unwrap().spawn(health, supervisor))).collect();
+        self.listeners = listeners.into_iter().map(|kept| kept.unwrap_or_else(|| pending.next().unwrap().spawn(health, supervisor))).collect();
+        self.config = config;
+        Ok(())
+    }
+}
+
+/// Match each section to a running worker started from the same one, which
+/// keeps running, or to none. Also returns which workers are kept.
+fn plan<'a>(workers: &[Worker], sections: Vec<Section<'a>>) -> (Vec<(Section<'a>, Option<usize>)>, Vec<bool>) {
+    let mut kept = vec![false; workers.len()];
+    let plan = sections.into_iter()
+        .map(|section| {
+            let index = (0..workers.len()).find(|&index| !kept[index] && workers[index].section == *section.table);
+            if let Some(index) = index {
+                kept[index] = true;
+            }
+            (section, index)
+        })
+        .collect();
+    (plan, kept)
+}
+
+/// Wait up to `grace` for every thread to finish.
+fn wait_for(handles: &[JoinHandle<()>], grace: Duration) {
+    let deadline = Instant::now() + grace;
+    while handles.iter().any(|handle| !handle.is_finished()) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Called again if it panics (see `supervisor`).
+type Run = Box<dyn FnMut() + Send>;
+
+/// A listener's socket. It's bound when the listener is prepared, so that
+/// an address in use fails the reload rather than the listener.
+enum Bound {
+    Udp(UdpSocket),
+    Tcp(TcpListener),
+}
+
+impl Bound {
+    /// Bind `address`, or share the socket of a replaced listener that's
+    /// bound to it.
+    fn udp(address: &str, replaced: &[&Bound]) -> io::Result<UdpSocket> {
+        for bound in replaced {
+            if let Bound::Udp(ref socket) = **bound {
+                if resolves_to(address, socket.local_addr()) {
+                    return socket.try_clone()
+                }
+            }
+        }
+        UdpSocket::bind(address)
+    }
+
+    fn tcp(address: &str, replaced: &[&Bound]) -> io::Result<TcpListener> {
+        for bound in replaced {
+            if let Bound::Tcp(ref listener) = **bound {
+                if resolves_to(address, listener.local_addr()) {
+                    return listener.try_clone()
+                }
+            }
+        }
+        TcpListener::bind(address)
+    }
+}
+
+/// Whether `address` is the one a socket is bound to; addresses with port
+/// zero never are.
+fn resolves_to(address: &str, local: io::Result<SocketAddr>) -> bool {
+    match (address.to_socket_addrs(), local) {
+        (Ok(mut addrs), Ok(local)) => addrs.any(|addr| addr == local),
+        _ => false,
+    }
+}
+
+/// A listener or exporter that's been created but isn't running yet.
+struct Pending {
+    section: Table,
+    stop: Stop,
+    subscription: Option<SubscriptionToken>,
+    probe: Arc<Probe>,
+    bound: Option<Bound>,
+    run: Run,
+}
+
+impl Pending {
+    fn spawn(self, health: &Health, supervisor: &Supervisor) -> Worker {
+        health.add(&self.probe);
+        let (probe, stop, supervisor, run) = (self.probe.clone(), self.stop.clone(), supervisor.clone(), self.run);
+        Worker {
+            section: self.section,
+            stop: self.stop,
+            subscription: self.subscription,
+            probe: self.probe,
+            bound: self.bound,
+            handle: thread::spawn(move || probe.run(|| supervisor.run(probe.name(), &stop, run))),
+        }
+    }
+
+    fn cancel(self, db: &Db) {
+        if let Some(token) = self.subscription {
+            db.unsubscribe(token);
+        }
+    }
+}
+
+/// A running listener or exporter and the section it was started from.
+struct Worker {
+    section: Table,
+    stop: Stop,
+    subscription: Option<SubscriptionToken>,
+    probe: Arc<Probe>,
+    bound: Option<Bound>,
+    handle: JoinHandle<()>,
+}
+
+impl Worker {
+    /// Ask it to return: listeners and the Prometheus exposer are stopped,
+    /// and other exporters return once their subscription is dropped.
+    fn stop(self, db: &Db, health: &Health) -> JoinHandle<()> {
+        health.remove(&self.probe);
+        self.stop.stop();
+        if let Some(token) = self.subscription {
+            db.unsubscribe(token);
+        }
+        self.handle
+    }
+}
+
+fn prepare_listener(section: &Section, db: &Db, replaced: &[&Bound]) -> Result<Pending, String> {
+    let stop = Stop::new();
+    let until = stop.clone();
+    let collector = db.collector();
+    let bind_error = |err| format!("{}: {}", section.name, err);
+    let (run, bound): (Run, Option<Bound>) = match listener_spec(section)? {
+        Listener::StatsdUdp(address, options, repeat, capture) => {
+            let socket = Bound::udp(&address, replaced).map_err(bind_error)?;
+            let mut listener = StatsdUdpListener::with_options(collector, options);
+            if let Some(options) = repeat {
+                listener = listener.repeat(repeater(section, options)?);
+            }
+            if let Some(options) = capture {
+                listener = listener.capture(Capture::new(options).map_err(|err| format!("{}: capture: {}", section.name, err))?);
+            }
+            let bound = socket.try_clone().map_err(bind_error)?;
+            let name = section.name.clone();
+            (Box::new(move || if let Err(err) = listener.listen_on(&socket, &until) {
+                error!("{}: error receiving StatsD datagrams: {}", name, err)
+            }), Some(Bound::Udp(bound)))
+        },
+        Listener::StatsdTcp(address, options, repeat, capture) => {
+            let socket = Bound::tcp(&address, replaced).map_err(bind_error)?;
+            let mut listener = StatsdTcpListener::with_options(collector, address.as_str(), options)
+                .map_err(|err| format!("{}: {}", section.name, err))?;
+            if let Some(options) = repeat {
+                listener = listener.repeat(repeater(section, options)?);
+            }
+            if let Some(options) = capture {
+                listener = listener.capture(Capture::new(options).map_err(|err| format!("{}: capture: {}", section.name, err))?);
+            }
+            let bound = socket.try_clone().map_err(bind_error)?;
+            let name = section.name.clone();
+            (Box::new(move || if let Err(err) = listener.listen_on(&socket, &until) {
+                error!("{}: error accepting StatsD connections: {}", name, err)
+            }), Some(Bound::Tcp(bound)))
+        },
+        Listener::Cgroup(options, interval) => {
+            let mut poller = CgroupPoller::new(collector, options);
+            (Box::new(move || poller.run_until(interval, &until)), None)
+        },
+        Listener::Exec(options, interval) => {
+            let mut poller = ExecPoller::new(collector, options);
+            (Box::new(move || poller.run_until(interval, &until)), None)
+        },
+        Listener::Snmp(targets, community, mut options, interval) => {
+            for target in targets {
+                let address = target.to_socket_addrs().ok().and_then(|mut addresses| addresses.next())
+                    .ok_or_else(|| format!("{}: couldn't resolve {}", section.name, target))?;
+                options.devices.push(SnmpDevice { address, community: community.clone(), name: Some(target) });
+            }
+            let mut poller = SnmpPoller::new(collector, options).map_err(|err| format!("{}: {}", section.name, err))?;
+            (Box::new(move || poller.run_until(interval, &until)), None)
+        },
+    };
+    Ok(Pending { section: section.table.clone(), stop, subscription: None, probe: Probe::new(section.name.clone()), bound, run })
+}
+
+fn repeater(section: &Section, options: RepeatOptions) -> Result<Repeater, String> {
+    Repeater::new(options).map_err(|err| format!("{}: repeat: {}", section.name, err))
+}
+
+fn prepare_exporter(section: &Section, db: &Db) -> Result<Pending, String> {
+    let probe = Probe::new(section.string("name")?.unwrap_or_else(|| section.name.clone()));
+    let (include, spec) = exporter_spec(section, Some(probe.backlog().clone()))?;
+    let (token, subscription) = db.subscribe(if include.names.is_empty() { None } else { Some(include) });
+    let stop = Stop::new();
+    match exporter(spec, subscription, db, stop.clone()) {
+        Ok(run) => Ok(Pending { section: section.table.clone(), stop, subscription: Some(token), probe, bound: None, run }),
+        Err(err) => {
+            db.unsubscribe(token);
+            Err(format!("{}: {}", section.name, err))
+        },
+    }
+}
+
+/// What a `[[listener]]` starts, by its `type`. Pollers take an
+/// `interval`, defaulting to 10 seconds.
+enum Listener {
+    /// `statsd_udp`: an `address`, `StatsdUdpOptions`, and `repeat` and
+    /// `capture` keys.
+    StatsdUdp(String, StatsdUdpOptions, Option<RepeatOptions>, Option<CaptureOptions>),
+    /// `statsd_tcp`: an `address`, `StatsdTcpOptions`, and `repeat` and
+    /// `capture` keys.
+    StatsdTcp(String, StatsdTcpOptions, Option<RepeatOptions>, Option<CaptureOptions>),
+    /// `cgroup`: an optional `root`.
+    Cgroup(CgroupOptions, Duration),
+    /// `exec`: `ExecOptions`.
+    Exec(ExecOptions, Duration),
+    /// `snmp`: `targets` to poll, as addresses that are also their
+    /// `device` dimension, their `community` (defaults to `public`), a
+    /// `timeout`, and `oids`, an array of `SnmpOid` tables.
+    Snmp(Vec<String>, String, SnmpOptions, Duration),
+}
+
+fn listener_spec(section: &Section) -> Result<Listener, String> {
+    let kind = section.required_string("type")?;
+    let listener = match kind.as_str() {
+        "statsd_udp" => {
+            let tos = match section.integer("tos")? {
+                Some(tos) if tos > 0xff => return Err(section.invalid("tos", "between 0 and 255")),
+                tos => tos.map(|tos| tos as u8),
+            };
+            let options = StatsdUdpOptions {
+                receive_buffer: section.integer("receive_buffer")?.map(|bytes| bytes as usize),
+                tos,
+            };
+            Listener::StatsdUdp(section.required_string("address")?, options, repeat(section)?, capture(section)?)
+        },
+        "statsd_tcp" => {
+            let options = StatsdTcpOptions {
+                read_timeout: section.duration("read_timeout")?,
+                keepalive: section.duration("keepalive")?,
+                #[cfg(feature = "flate2")]
+                gzip_frames: section.bool("gzip_frames")?,
+            };
+            Listener::StatsdTcp(section.required_string("address")?, options, repeat(section)?, capture(section)?)
+        },
+        "cgroup" => {
+            let options = CgroupOptions { root: section.string("root")?.map(PathBuf::from), ..CgroupOptions::default() };
+            Listener::Cgroup(options, interval(section)?)
+        },
+        "exec" => {
+            let format = match section.string("format")?.as_deref() {
+                None | Some("statsd") => ExecFormat::Statsd,
+                Some("influx") => ExecFormat::Influx,
+                Some(_) => return Err(section.invalid("format", "statsd or influx")),
+            };
+            let options = ExecOptions {
+                command: section.required_string("command")?,
+                args: section.strings("args")?.unwrap_or_default(),
+                format,
+                timeout: section.duration("timeout")?,
+            };
+            Listener::Exec(options, interval(section)?)
+        },
+        "snmp" => {
+            let targets = section.strings("targets")?.filter(|targets| !targets.is_empty()).ok_or_else(|| section.invalid("targets", "given"))?;
+            let community = section.string("community")?.unwrap_or_else(|| "public".to_string());
+            let oids = section.tables("oids")?.iter().map(snmp_oid).collect::<Result<Vec<SnmpOid>, String>>()?;
+            if oids.is_empty() {
+                return Err(section.invalid("oids", "given"))
+            }
+            let options = SnmpOptions { devices: vec![], oids, timeout: section.duration("timeout")? };
+            Listener::Snmp(targets, community, options, interval(section)?)
+        },
+        _ => return Err(section.invalid("type", "one of statsd_udp, statsd_tcp, cgroup, exec, or snmp")),
+    };
+    section.finish()?;
+    Ok(listener)
+}
+
+fn snmp_oid(section: &Section) -> Result<SnmpOid, String> {
+    let oid = section.required_string("oid")?;
+    snmp::parse_oid(&oid).map_err(|err| format!("{}: {}", section.name, err))?;
+    let kind = match section.string("kind")?.as_deref() {
+        None | Some("gauge") => SnmpKind::Gauge,
+        Some("counter") => SnmpKind::Counter,
+        Some(_) => return Err(section.invalid("kind", "gauge or counter")),
+    };
+    let oid = SnmpOid { oid, name: section.required_string("name")?, kind, walk: section.bool("walk")?.unwrap_or(false) };
+    section.finish()?;
+    Ok(oid)
+}
+
+/// `repeat`, addresses to repeat payloads to verbatim, with
+/// `repeat_transport`, `max_pending_repeats`, and `parse = false` to only
+/// repeat them (see `RepeatOptions`).
+fn repeat(section: &Section) -> Result<Option<RepeatOptions>, String> {
+    let options = RepeatOptions {
+        addresses: section.strings("repeat")?.unwrap_or_default(),
+        transport: transport(section, "repeat_transport")?,
+        parse: section.bool("parse")?,
+        max_pending: section.integer("max_pending_repeats")?.map(|max| max as usize),
+    };
+    if options.addresses.is_empty() {
+        if options.parse == Some(false) {
+            return Err(section.invalid("parse", "true unless payloads are repeated"))
+        }
+        return Ok(None)
+    }
+    Ok(Some(options))
+}
+
+/// `capture`, a file to capture payloads to, with `max_pending_captures`
+/// and `max_capture_bytes` (see `CaptureOptions`).
+fn capture(section: &Section) -> Result<Option<CaptureOptions>, String> {
+    let max_pending = section.integer("max_pending_captures")?.map(|max| max as usize);
+    let max_bytes = section.integer("max_capture_bytes")?;
+    Ok(section.string("capture")?.map(|path| CaptureOptions { path: PathBuf::from(path), max_pending, max_bytes }))
+}
+
+fn transport(section: &Section, key: &'static str) -> Result<Option<StatsdTransport>, String> {
+    match section.string(key)?.as_deref() {
+        None => Ok(None),
+        Some("udp") => Ok(Some(StatsdTransport::Udp)),
+        Some("tcp") => Ok(Some(StatsdTransport::Tcp)),
+        Some(_) => Err(section.invalid(key, "udp or tcp")),
+    }
+}
+
+fn interval(section: &Section) -> Result<Duration, String> {
+    Ok(section.duration("interval")?.unwrap_or_else(|| Duration::from_secs(10)))
+}
+
+/// What an `[[exporter]]` starts, by its `type`. Each takes an `address`
+/// unless noted, its sender's options, an optional `name` for error
+/// messages, `include` name patterns to subscribe to only some metrics,
+/// and, if it buffers, the `DeliveryOptions` `max_batches`, `spool_path`,
+/// and `max_spool_bytes`.
+enum Exporter {
+    Graphite(String, GraphiteOptions),
+    Statsd(String, StatsdOptions),
+    Otlp(String, OtlpOptions),
+    Prometheus(String, send::ExposerOptions),
+    Wavefront(String, WavefrontOptions),
+    /// With `sigv4`, also `access_key_id`, `secret_access_key`, and
+    /// `session_token`.
+    CloudWatch(String, CloudWatchOptions),
+    Elasticsearch(String, ElasticsearchOptions),
+    Postgres(String, PostgresOptions),
+    Nats(String, NatsOptions),
+    /// A `path` instead of an address.
+    File(PathBuf, FileOptions),
+    /// No keys of its own.
+    Stdout,
+    /// `remote_write`.
+    #[cfg(feature = "snap")]
+    RemoteWrite(String, send::RemoteWriteOptions),
+    /// `hosts` and a `topic` instead of an address.
+    #[cfg(feature = "kafka")]
+    Kafka(Vec<String>, String, send::KafkaOptions),
+}
+
+/// `backlog` is kept up to date by the exporter if it has delivery options.
+fn exporter_spec(section: &Section, backlog: Option<Arc<AtomicUsize>>) -> Result<(SubscriptionFilter, Exporter), String> {
+    let kind = section.required_string("type")?;
+    section.string("name")?;
+    let include = SubscriptionFilter {
+        names: section.strings("include")?.unwrap_or_default().iter().map(Glob::new).collect(),
+        ..SubscriptionFilter::default()
+    };
+    let delivery = DeliveryOptions {
+        max_batches: section.integer("max_batches")?.map(|max| max as usize),
+        spool_path: section.string("spool_path")?.map(PathBuf::from),
+        max_spool_bytes: section.integer("max_spool_bytes")?,
+        backlog,
+    };
+
+    let exporter = match kind.as_str() {
+        "graphite" => Exporter::Graphite(section.required_string("address")?, GraphiteOptions {
+            template: section.string("template")?,
+            connect_timeout: section.duration("connect_timeout")?,
+            delivery,
+        }),
+        "statsd" => {
+            Exporter::Statsd(section.required_string("address")?, StatsdOptions {
+                transport: transport(section, "transport")?,
+                tags: section.bool("tags")?,
+                max_packet_size: section.integer("max_packet_size")?.map(|max| max as usize),
+                delivery,
+            })
+        },
+        "otlp" => Exporter::Otlp(section.required_string("address")?, OtlpOptions {
+            path: section.string("path")?,
+            resource_attributes: section.pairs("resource_attributes")?,
+            headers: section.pairs("headers")?,
+            timeout: section.duration("timeout")?,
+            delivery,
+        }),
+        "prometheus" => Exporter::Prometheus(section.required_string("address")?, send::ExposerOptions {
+            stale_after: section.integer("stale_after")?.map(|after| after as u32),
+            metadata: None,
+        }),
+        "wavefront" => Exporter::Wavefront(section.required_string("address")?, WavefrontOptions {
+            source_dimension: section.string("source_dimension")?,
+            default_source: section.string("default_source")?,
+            prefix: section.string("prefix")?,
+            connect_timeout: section.duration("connect_timeout")?,
+            delivery,
+        }),
+        "cloudwatch" => Exporter::CloudWatch(section.required_string("address")?, CloudWatchOptions {
+            namespace: section.string("namespace")?,
+            region: section.string("region")?,
+            timeout: section.duration("timeout")?,
+            #[cfg(feature = "sigv4")]
+            credentials: aws_credentials(section)?,
+            delivery,
+        }),
+        "elasticsearch" => {
+            let index_period = match section.string("index_period")?.as_deref() {
+                None => None,
+                Some("hourly") => Some(send::IndexPeriod::Hourly),
+                Some("daily") => Some(send::IndexPeriod::Daily),
+                Some("monthly") => Some(send::IndexPeriod::Monthly),
+                Some(_) => return Err(section.invalid("index_period", "one of hourly, daily, or monthly")),
+            };
+            Exporter::Elasticsearch(section.required_string("address")?, ElasticsearchOptions {
+                index_prefix: section.string("index_prefix")?,
+                index_period,
+                headers: section.pairs("headers")?,
+                timeout: section.duration("timeout")?,
+                max_documents_per_request: section.integer("max_documents_per_request")?.map(|max| max as usize),
+                max_retries: section.integer("max_retries")?.map(|max| max as u32),
+                retry_backoff: section.duration("retry_backoff")?,
+                delivery,
+            })
+        },
+        "postgres" => Exporter::Postgres(section.required_string("address")?, PostgresOptions {
+            user: section.string("user")?,
+            password: section.string("password")?,
+            database: section.string("database")?,
+            table: section.string("table")?,
+            create_table: section.bool("create_table")?,
+            hypertable: section.bool("hypertable")?,
+            timeout: section.duration("timeout")?,
+            delivery,
+        }),
+        "nats" => {
+            let format = match section.string("format")?.as_deref() {
+                None | Some("json") => None,
+                Some("protobuf") => Some(send::NatsFormat::Protobuf),
+                Some(_) => return Err(section.invalid("format", "json or protobuf")),
+            };
+            Exporter::Nats(section.required_string("address")?, NatsOptions {
+                subject: section.string("subject")?,
+                format,
+                token: section.string("token")?,
+                user: section.string("user")?,
+                password: section.string("password")?,
+                timeout: section.duration("timeout")?,
+                delivery,
+            })
+        },
+        "file" => Exporter::File(PathBuf::from(section.required_string("path")?), FileOptions {
+            max_bytes: section.integer("max_bytes")?,
+            max_age: section.duration("max_age")?,
+            #[cfg(feature = "flate2")]
+            gzip: section.bool("gzip")?,
+        }),
+        "stdout" => Exporter::Stdout,
+        #[cfg(feature = "snap")]
+        "remote_write" => Exporter::RemoteWrite(section.required_string("address")?, send::RemoteWriteOptions {
+            path: section.string("path")?,
+            headers: section.pairs("headers")?,
+            timeout: section.duration("timeout")?,
+            shards: section.integer("shards")?.map(|shards| shards as usize),
+            max_samples_per_request: section.integer("max_samples_per_request")?.map(|max| max as usize),
+            max_retries: section.integer("max_retries")?.map(|max| max as u32),
+            retry_backoff: section.duration("retry_backoff")?,
+            delivery,
+        }),
+        #[cfg(feature = "kafka")]
+        "kafka" => {
+            let hosts = section.strings("hosts")?.ok_or_else(|| section.invalid("hosts", "given"))?;
+            let format = match section.string("format")?.as_deref() {
+                None | Some("json") => None,
+                Some("protobuf") => Some(send::KafkaFormat::Protobuf),
+                Some(_) => return Err(section.invalid("format", "json or protobuf")),
+            };
+            Exporter::Kafka(hosts, section.required_string("topic")?, send::KafkaOptions {
+                format,
+                client_id: section.string("client_id")?,
+                ack_timeout: section.duration("ack_timeout")?,
+                delivery,
+            })
+        },
+        _ => return Err(section.invalid("type", "a supported exporter")),
+    };
+    section.finish()?;
+    Ok((include, exporter))
+}
+
+fn exporter(spec: Exporter, subscription: Receiver<Arc<Vec<AggregatedMetric>>>, db: &Db, stop: Stop) -> Result<Run, String> {
+    let error = |err: std::io::Error| err.to_string();
+    Ok(match spec {
+        Exporter::Graphite(address, options) => {
+            let mut sender = send::GraphiteSender::new(subscription, address.as_str(), options).map_err(error)?;
+            Box::new(move || sender.send())
+        },
+        Exporter::Statsd(address, options) => {
+            let mut sender = send::StatsdSender::new(subscription, address.as_str(), options).map_err(error)?;
+            Box::new(move || sender.send())
+        },
+        Exporter::Otlp(address, options) => {
+            let mut sender = send::OtlpSender::new(subscription, &address, options).map_err(error)?;
+            Box::new(move || sender.send())
+        },
+        Exporter::Prometheus(address, mut options) => {
+            options.metadata = Some(db.metadata().clone());
+            let mut exposer = send::Exposer::with_options(subscription, address.as_str(), options).map_err(error)?;
+            Box::new(move || if let Err(err) = exposer.listen_until(&stop) {
+                error!("Error serving Prometheus scrapes: {}", err)
+            })
+        },
+        Exporter::Wavefront(address, options) => {
+            let mut sender = send::WavefrontSender::new(subscription, address.as_str(), options).map_err(error)?;
+            Box::new(move || sender.send())
+        },
+        Exporter::CloudWatch(address, options) => {
+            let mut sender = send::CloudWatchSender::new(subscription, db.metadata().clone(), &address, options).map_err(error)?;
+            Box::new(move || sender.send())
+        },
+        Exporter::Elasticsearch(address, options) => {
+            let mut sender = send::ElasticsearchSender::new(subscription, &address, options).map_err(error)?;
+            Box::new(move || sender.send())
+        },
+        Exporter::Postgres(address, options) => {
+            let mut sender = send::PostgresSender::new(subscription, &address, options).map_err(error)?;
+            Box::new(move || sender.send())
+        },
+        Exporter::Nats(address, options) => {
+            let mut sender = send::NatsSender::new(subscription, &address, options).map_err(error)?;
+            Box::new(move || sender.send())
+        },
+        Exporter::File(path, options) => {
+            let mut sender = send::FileSender::new(subscription, path, options);
+            Box::new(move || sender.send())
+        },
+        Exporter::Stdout => {
+            let mut sender = send::JsonLinesSender::stdout(subscription);
+            Box::new(move || if let Err(err) = sender.send() {
+                error!("Error writing metrics to stdout: {}", err)
+            })
+        },
+        #[cfg(feature = "snap")]
+        Exporter::RemoteWrite(address, options) => {
+            let mut sender = send::RemoteWriteSender::new(subscription, &address, options).map_err(error)?;
+            Box::new(move || sender.send())
+        },
+        #[cfg(feature = "kafka")]
+        Exporter::Kafka(hosts, topic, options) => {
+            let mut sender = send::KafkaSender::new(subscription, hosts, &topic, options).map_err(error)?;
+            Box::new(move || sender.send())
+        },
+    })
+}
+
+#[cfg(feature = "sigv4")]
+fn aws_credentials(section: &Section) -> Result<Option<send::AwsCredentials>, String> {
+    let access_key_id = section.string("access_key_id")?;
+    let secret_access_key = section.string("secret_access_key")?;
+    let session_token = section.string("session_token")?;
+    match (access_key_id, secret_access_key) {
+        (Some(access_key_id), Some(secret_access_key)) => Ok(Some(send::AwsCredentials { access_key_id, secret_access_key, session_token })),
+        (None, None) => Ok(None),
+        _ => Err(format!("{}: `access_key_id` and `secret_access_key` must be given together", section.name)),
+    }
+}
+
+fn relabel_rule(section: &Section) -> Result<RelabelRule, String> {
+    let action = match section.required_string("action")?.as_str() {
+        "replace" => RelabelAction::Replace {
+            target: section.required_string("target")?,
+            replacement: section.string("replacement")?.unwrap_or_else(|| "$1".to_string()),
+        },
+        "keep" => RelabelAction::Keep,
+        "drop" => RelabelAction::Drop,
+        "drop_dimensions" => RelabelAction::DropDimensions,
+        "keep_dimensions" => RelabelAction::KeepDimensions,
+        "map_dimensions" => RelabelAction::MapDimensions {
+            replacement: section.string("replacement")?.unwrap_or_else(|| "$1".to_string()),
+        },
+        _ => return Err(section.invalid("action", "one of replace, keep, drop, drop_dimensions, keep_dimensions, or map_dimensions")),
+    };
+    let sources = section.strings("sources")?.unwrap_or_default();
+    let regex = section.string("regex")?.unwrap_or_else(|| "(.*)".to_string());
+    RelabelRule::new(&sources, &regex, action).map_err(|err| format!("{}: invalid regex: {}", section.name, err))
+}
+
+fn scrub_rule(section: &Section) -> Result<ScrubRule, String> {
+    let action = match section.required_string("action")?.as_str() {
+        "remove" => ScrubAction::Remove,
+        "hash" => ScrubAction::Hash,
+        "bucket" => ScrubAction::Bucket(section.integer("buckets")?.ok_or_else(|| section.invalid("buckets", "given for bucket"))?),
+        _ => return Err(section.invalid("action", "remove, hash, or bucket")),
+    };
+    Ok(ScrubRule {
+        names: Glob::new(section.string("names")?.unwrap_or_else(|| "*".to_string())),
+        dimensions: Glob::new(section.required_string("dimensions")?),
+        action,
+    })
+}
+
+/// Parse `"10s"`-style durations: a number followed by `ms`, `s`, `m`, or
+/// `h`.
+pub fn parse_duration(input: &str) -> Option<Duration> {
+    let split = input.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+    let (number, unit) = input.split_at(split);
+    let number = number.parse::<f64>().ok()?;
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 60.0 * 60.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// A table being read, remembering which keys were looked at so unknown
+/// ones can be reported.
+struct Section<'a> {
+    name: String,
+    table: &'a Table,
+    used: RefCell<Vec<&'static str>>,
+}
+
+impl<'a> Section<'a> {
+    fn new(name: String, table: &'a Table) -> Section<'a> {
+        Section {
+            name,
+            table,
+            used: RefCell::new(vec![]),
+        }
+    }
+
+    fn get(&self, key: &'static str) -> Option<&'a Value> {
+        self.used.borrow_mut().push(key);
+        self.table.get(key)
+    }
+
+    fn invalid(&self, key: &str, expected: &str) -> String {
+        format!("{}: `{}` must be {}", self.name, key, expected)
+    }
+
+    fn string(&self, key: &'static str) -> Result<Option<String>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(_) => Err(self.invalid(key, "a string")),
+        }
+    }
+
+    fn required_string(&self, key: &'static str) -> Result<String, String> {
+        self.string(key)?.ok_or_else(|| format!("{}: `{}` is required", self.name, key))
+    }
+
+    fn bool(&self, key: &'static str) -> Result<Option<bool>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(&Value::Boolean(value)) => Ok(Some(value)),
+            Some(_) => Err(self.invalid(key, "a boolean")),
+        }
+    }
+
+    fn integer(&self, key: &'static str) -> Result<Option<u64>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(&Value::Integer(value)) if value >= 0 => Ok(Some(value as u64)),
+            Some(_) => Err(self.invalid(key, "a non-negative integer")),
+        }
+    }
+
+    fn float(&self, key: &'static str) -> Result<Option<f64>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(&Value::Float(value)) => Ok(Some(value)),
+            Some(&Value::Integer(value)) => Ok(Some(value as f64)),
+            Some(_) => Err(self.invalid(key, "a number")),
+        }
+    }
+
+    fn duration(&self, key: &'static str) -> Result<Option<Duration>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(&Value::Integer(seconds)) if seconds >= 0 => Ok(Some(Duration::from_secs(seconds as u64))),
+            Some(Value::String(value)) => parse_duration(value).map(Some).ok_or_else(|| self.invalid(key, "a duration")),
+            Some(_) => Err(self.invalid(key, "a duration")),
+        }
+    }
+
+    fn strings(&self, key: &'static str) -> Result<Option<Vec<String>>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Array(values)) => values.iter()
+                .map(|value| value.as_str().map(|value| value.to_string()).ok_or_else(|| self.invalid(key, "an array of strings")))
+                .collect::<Result<Vec<String>, String>>()
+                .map(Some),
+            Some(_) => Err(self.invalid(key, "an array of strings")),
+        }
+    }
+
+    fn floats(&self, key: &'static str) -> Result<Option<Vec<f64>>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Array(values)) => values.iter()
+                .map(|value| match *value {
+                    Value::Float(value) => Ok(value),
+                    Value::Integer(value) => Ok(value as f64),
+                    _ => Err(self.invalid(key, "an array of numbers")),
+                })
+                .collect::<Result<Vec<f64>, String>>()
+                .map(Some),
+            Some(_) => Err(self.invalid(key, "an array of numbers")),
+        }
+    }
+
+    /// A table of strings, eg. `{ env = "prod" }`.
+    fn pairs(&self, key: &'static str) -> Result<Vec<(String, String)>, String> {
+        match self.get(key) {
+            None => Ok(vec![]),
+            Some(Value::Table(table)) => table.iter()
+                .map(|(key, value)| match *value {
+                    Value::String(ref value) => Ok((key.clone(), value.clone())),
+                    _ => Err(format!("{}: `{}` must be a string", self.name, key)),
+                })
+                .collect(),
+            Some(_) => Err(self.invalid(key, "a table of strings")),
+        }
+    }
+
+    /// An array of tables, eg. `[{ oid = "1.3.6.1.2.1.1.3.0", name = "uptime" }]`.
+    fn tables(&self, key: &'static str) -> Result<Vec<Section<'a>>, String> {
+        match self.get(key) {
+            None => Ok(vec![]),
+            Some(Value::Array(values)) => values.iter()
+                .enumerate()
+                .map(|(index, value)| match *value {
+                    Value::Table(ref table) => Ok(Section::new(format!("{}.{}[{}]", self.name, key, index), table)),
+                    _ => Err(self.invalid(key, "an array of tables")),
+                })
+                .collect(),
+            Some(_) => Err(self.invalid(key, "an array of tables")),
+        }
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        let used = self.used.borrow();
+        match self.table.keys().find(|key| !used.contains(&key.as_str())) {
+            Some(key) => Err(format!("{}: unknown key `{}`", self.name, key)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    use super::super::metric::{CollectedMetric, MetricId};
+
+    const CONFIG: &str = r#"
+        [db]
+        aggregation_interval = "5s"
+        default_dimensions = { env = "prod" }
+        name_filter = ["!debug.*"]
+        histogram_mode = "sketch"
+
+        [[mapping]]
+        match = "api.*.latency"
+        name = "api_latency"
+        dimensions = { endpoint = "$1" }
+
+        [[scrub]]
+        dimensions = "request_id"
+        action = "remove"
+
+        [[derived]]
+        name = "error_rate"
+        expression = "errors / requests"
+
+        [[listener]]
+        type = "statsd_udp"
+        address = "127.0.0.1:8125"
+
+        [[exporter]]
+        type = "graphite"
+        address = "127.0.0.1:2003"
+        include = ["service.*"]
+        max_batches = 10
+    "#;
+
+    #[test]
+    fn it_builds_db_options() {
+        let options = Config::parse(CONFIG).unwrap().db_options().unwrap();
+        assert_eq!(options.aggregation_interval, Some(Duration::from_secs(5)));
+        assert_eq!(options.default_dimensions, vec![(Atom::from("env"), Atom::from("prod"))]);
+        assert_eq!(options.histogram_mode, Some(HistogramMode::Sketch(0.01)));
+        assert_eq!(options.derived.len(), 1);
+
+        let db = Db::new(options);
+        let subscription = db.aggregation_subscribe();
+        db.collector().push(vec![
+            CollectedMetric::Gauge(UNIX_EPOCH, MetricId::from("api.checkout.latency").with_dimension("request_id", "1"), 1.0),
+            CollectedMetric::Gauge(UNIX_EPOCH, MetricId::from("debug.queue"), 1.0),
+        ]);
+        db.aggregate(None);
+        let expected = MetricId::from("api_latency").with_dimension("endpoint", "checkout").with_dimension("env", "prod");
+        assert_eq!(*subscription.recv().unwrap(), vec![AggregatedMetric::Gauge(UNIX_EPOCH, expected, 1.0)]);
+    }
+
+    #[test]
+    fn it_rejects_invalid_configs() {
+        assert_eq!(Config::parse("[db]\nshards = \"two\"").unwrap_err(), "db: `shards` must be a non-negative integer");
+        assert_eq!(Config::parse("[db]\nintervall = 1").unwrap_err(), "db: unknown key `intervall`");
+        assert_eq!(Config::parse("[[listener]]\ntype = \"carrier_pigeon\"").unwrap_err(), "listener[0]: `type` must be one of statsd_udp, statsd_tcp, cgroup, exec, or snmp");
+        assert_eq!(Config::parse("[[exporter]]\ntype = \"graphite\"").unwrap_err(), "exporter[0]: `address` is required");
+        assert!(Config::parse("[[derived]]\nname = \"x\"\nexpression = \"a +\"").is_err());
+        assert!(Config::parse("[dbb]").is_err());
+        assert_eq!(Config::parse("[health]\nmax_backlog = 5").unwrap_err(), "health: `address` is required");
+        assert_eq!(Config::parse("[cluster]\npeers = [\"a:8125\"]\nlocal = \"b:8125\"").unwrap_err(), "cluster: `local` must be one of `peers`");
+        assert_eq!(Config::parse("[[sampling]]\nnames = \"*\"\nrate = 0.0").unwrap_err(), "sampling[0]: `rate` must be greater than 0 and at most 1");
+        assert_eq!(parse_duration("1.5m"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("10"), None);
+    }
+
+    #[test]
+    fn it_parses_snmp_listeners() {
+        let listener = |oids: &str| Config::parse(&format!(concat!(
+            "[[listener]]\ntype = \"snmp\"\ntargets = [\"127.0.0.1:161\"]\ncommunity = \"private\"\n",
+            "interval = \"30s\"\ntimeout = \"10ms\"\noids = [{}]",
+        ), oids));
+        let config = listener(concat!(
+            "{ oid = \"1.3.6.1.2.1.1.3.0\", name = \"uptime\" }, ",
+            "{ oid = \"1.3.6.1.2.1.2.2.1.10\", name = \"if_in_octets\", kind = \"counter\", walk = true }",
+        )).unwrap();
+        match listener_spec(&config.listeners().unwrap()[0]).unwrap() {
+            Listener::Snmp(targets, community, options, interval) => {
+                assert_eq!(targets, vec!["127.0.0.1:161"]);
+                assert_eq!(community, "private");
+                assert_eq!(interval, Duration::from_secs(30));
+                assert_eq!(options.timeout, Some(Duration::from_millis(10)));
+                assert_eq!(options.oids[0].kind, SnmpKind::Gauge);
+                assert_eq!((options.oids[1].name.as_str(), options.oids[1].kind, options.oids[1].walk), ("if_in_octets", SnmpKind::Counter, true));
+            },
+            _ => panic!("not an SNMP listener"),
+        }
+
+        let db = Db::new(DbOptions::default());
+        let workers = config.start(&db).unwrap();
+        assert_eq!(workers.listeners(), 1);
+        workers.stop(&db);
+
+        assert_eq!(listener("{ oid = \"1.x\", name = \"uptime\" }").unwrap_err(), "listener[0].oids[0]: Invalid OID: 1.x");
+        assert_eq!(listener("{ oid = \"1.3\", name = \"uptime\", kind = \"rate\" }").unwrap_err(), "listener[0].oids[0]: `kind` must be gauge or counter");
+        assert_eq!(listener("").unwrap_err(), "listener[0]: `oids` must be given");
+    }
+
+    #[test]
+    fn it_reloads_changed_exporters() {
+        let directory = std::env::temp_dir().join(format!("metriqs-reload-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let config = |file: &str, interval: &str, rest: &str| {
+            let path = directory.join(file);
+            let _ = fs::remove_file(&path);
+            Config::parse(&format!("[db]\naggregation_interval = \"{}\"\n[[exporter]]\ntype = \"file\"\npath = {:?}\n{}", interval, path, rest)).unwrap()
+        };
+
+        let db = Db::new(DbOptions::default());
+        let mut workers = config("a.jsonl", "10s", "").start(&db).unwrap();
+        let enrichment = "[enrichment]\nsources = [\"kubernetes\"]";
+        assert_eq!(workers.reload(config("a.jsonl", "10s", enrichment), &db), Ok(vec!["enrichment".to_string()]));
+        assert_eq!(workers.reload(config("b.jsonl", "5s", enrichment), &db), Ok(vec!["db.aggregation_interval".to_string()]));
+        assert_eq!(db.stats().subscribers, 1);
+        let unresolvable = Config::parse("[[exporter]]\ntype = \"graphite\"\naddress = \"metriqs.invalid:2003\"").unwrap();
+        assert!(workers.reload(unresolvable, &db).is_err());
+        assert_eq!(db.stats().subscribers, 1);
+
+        db.collect(vec![CollectedMetric::Gauge(UNIX_EPOCH, MetricId::from("queue"), 1.0)]);
+        db.aggregate(None);
+        let written = (0..200)
+            .map(|_| {
+                thread::sleep(Duration::from_millis(10));
+                fs::read_to_string(directory.join("b.jsonl")).unwrap_or_default()
+            })
+            .find(|written| !written.is_empty());
+        assert!(written.is_some_and(|written| written.contains("\"queue\"")));
+        assert!(!directory.join("a.jsonl").exists());
+        workers.stop(&db);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn it_binds_listeners_before_reloading() {
+        let in_use = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let free = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config = |address: &str, receive_buffer: usize| {
+            let listener = format!("[[listener]]\ntype = \"statsd_udp\"\naddress = \"{}\"\nreceive_buffer = {}", address, receive_buffer);
+            Config::parse(&listener).unwrap()
+        };
+
+        let db = Db::new(DbOptions::default());
+        let mut workers = config(&free.to_string(), 1 << 16).start(&db).unwrap();
+        // A changed listener takes over the socket of the one it replaces.
+        assert!(workers.reload(config(&free.to_string(), 1 << 17), &db).is_ok());
+        let err = workers.reload(config(&in_use.local_addr().unwrap().to_string(), 1 << 17), &db).unwrap_err();
+        assert!(err.starts_with("listener[0]: "), "{}", err);
+        assert_eq!(workers.listeners(), 1);
+        workers.stop(&db);
+    }
+}
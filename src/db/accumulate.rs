@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime};
+
+use super::super::metric::{CollectedMetric, MetricId};
+use super::aggregate::{push_count, push_histogram, sum_counts, AggregatedMetric, GaugeAggregation, Group, HistogramMode, HistogramRecorder, RollupOptions};
+use super::sketch::DdSketch;
+
+/// Running rollup of one series' samples so far this interval.
+enum Accumulator {
+    Count {
+        time: SystemTime,
+        sum: i64,
+        saturated: bool,
+    },
+    Gauge {
+        time: SystemTime,
+        /// The sample with the latest time.
+        last: f64,
+        min: f64,
+        max: f64,
+        sum: f64,
+        count: u64,
+    },
+    Histogram(SystemTime, HistogramRecorder),
+    Sketch(SystemTime, DdSketch),
+}
+
+/// Per-series accumulators that counts, gauges, and histograms are folded
+/// into as they're collected, so memory scales with the number of series
+/// rather than with the number of samples.
+pub struct Accumulators {
+    series: HashMap<Group, Accumulator>,
+}
+
+impl Accumulators {
+    pub fn new() -> Accumulators {
+        Accumulators { series: HashMap::new() }
+    }
+
+    /// Fold in what can be folded and return the rest: sets, summaries,
+    /// and monotonic counts still need every sample.
+    pub fn fold(&mut self, metrics: Vec<CollectedMetric>, options: &RollupOptions) -> Vec<CollectedMetric> {
+        let mut rest = vec![];
+        for metric in metrics {
+            match metric {
+                CollectedMetric::Count(time, id, value) => {
+                    let accumulator = self.series.entry(Group::Count(id))
+                        .or_insert(Accumulator::Count { time, sum: 0, saturated: false });
+                    if let Accumulator::Count { time: ref mut latest, ref mut sum, ref mut saturated } = *accumulator {
+                        *latest = (*latest).max(time);
+                        let (next, overflowed) = sum_counts([*sum, value]);
+                        *sum = next;
+                        *saturated |= overflowed;
+                    }
+                },
+                CollectedMetric::Gauge(time, id, value) => {
+                    let accumulator = self.series.entry(Group::Gauge(id)).or_insert(Accumulator::Gauge {
+                        time,
+                        last: value,
+                        min: value,
+                        max: value,
+                        sum: 0.0,
+                        count: 0,
+                    });
+                    if let Accumulator::Gauge { time: ref mut latest, ref mut last, ref mut min, ref mut max, ref mut sum, ref mut count } = *accumulator {
+                        // Ties go to whichever sample was collected later.
+                        if time >= *latest {
+                            *latest = time;
+                            *last = value;
+                        }
+                        *min = min.min(value);
+                        *max = max.max(value);
+                        *sum += value;
+                        *count += 1;
+                    }
+                },
+                CollectedMetric::Histogram(time, id, value) => self.fold_histogram(time, id, value, 1.0, options),
+                CollectedMetric::SampledHistogram(time, id, value, weight) => self.fold_histogram(time, id, value, weight, options),
+                metric => rest.push(metric),
+            }
+        }
+        rest
+    }
+
+    /// Fold a histogram sample standing for `weight` samples.
+    fn fold_histogram(&mut self, time: SystemTime, id: MetricId, value: f64, weight: f64, options: &RollupOptions) {
+        let accumulator = self.series.entry(Group::Histogram(id)).or_insert_with(|| {
+            match options.histogram_mode {
+                HistogramMode::Summary => Accumulator::Histogram(time, HistogramRecorder::new(options.histogram_precision, &options.buckets)),
+                HistogramMode::Sketch(relative_accuracy) => Accumulator::Sketch(time, DdSketch::new(relative_accuracy)),
+            }
+        });
+        match *accumulator {
+            Accumulator::Histogram(ref mut latest, ref mut recorder) => {
+                *latest = (*latest).max(time);
+                if !recorder.record_weighted(value, weight) {
+                    options.negative_samples.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+            Accumulator::Sketch(ref mut latest, ref mut sketch) => {
+                *latest = (*latest).max(time);
+                sketch.insert_n(value, weight.round().max(1.0) as u64)
+            },
+            _ => (),
+        }
+    }
+
+    /// Number of series being accumulated.
+    pub fn len(&self) -> usize {
+        self.series.len()
+    }
+
+    /// Rough estimate of the memory the accumulators use, not counting
+    /// what histograms' recorders and sketches allocate.
+    pub fn estimated_bytes(&self) -> usize {
+        self.series.len() * (mem::size_of::<Group>() + mem::size_of::<Accumulator>())
+    }
+
+    /// Roll up and reset every accumulator. Produces the same metrics as
+    /// `aggregate::aggregate` and `aggregate::aggregate_counts` would for the
+    /// samples that were folded in.
+    pub fn flush(&mut self, elapsed: Duration, options: &RollupOptions) -> Vec<AggregatedMetric> {
+        let seconds = elapsed.as_secs_f64();
+        let mut aggregated = vec![];
+        for (group, accumulator) in self.series.drain() {
+            match (group, accumulator) {
+                (Group::Count(id), Accumulator::Count { time, sum, saturated }) => {
+                    push_count(&mut aggregated, time, id, (sum, saturated), seconds, options)
+                },
+                (Group::Gauge(id), Accumulator::Gauge { time, last, min, max, sum, count }) => {
+                    let value = match options.gauge_aggregation(&id) {
+                        GaugeAggregation::Last => last,
+                        GaugeAggregation::Min => min,
+                        GaugeAggregation::Max => max,
+                        GaugeAggregation::Mean => sum / count as f64,
+                        GaugeAggregation::Sum => sum,
+                    };
+                    aggregated.push(AggregatedMetric::Gauge(time, id, value))
+                },
+                (Group::Histogram(id), Accumulator::Histogram(time, recorder)) => {
+                    if !recorder.is_empty() {
+                        push_histogram(&mut aggregated, time, id, &recorder, options)
+                    }
+                },
+                (Group::Histogram(id), Accumulator::Sketch(time, sketch)) => {
+                    aggregated.push(AggregatedMetric::Sketch(time, id, sketch))
+                },
+                _ => unreachable!("Accumulators always match their group"),
+            }
+        }
+        aggregated
+    }
+}
+
+impl Default for Accumulators {
+    fn default() -> Accumulators {
+        Accumulators::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    use super::super::aggregate::{aggregate, aggregate_counts, group};
+    use super::super::super::metric::MetricId;
+
+    #[test]
+    fn it_matches_buffered_aggregation() {
+        let t = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let metrics = || vec![
+            CollectedMetric::Count(t(1), MetricId::from("requests"), 2),
+            CollectedMetric::Count(t(2), MetricId::from("requests"), 3),
+            CollectedMetric::Gauge(t(2), MetricId::from("queue"), 5.0),
+            CollectedMetric::Gauge(t(1), MetricId::from("queue"), 7.0),
+            CollectedMetric::Histogram(t(1), MetricId::from("latency"), 10.0),
+            CollectedMetric::Histogram(t(3), MetricId::from("latency"), 20.0),
+        ];
+        let options = RollupOptions { gauge: GaugeAggregation::Last, ..RollupOptions::default() };
+        let elapsed = Duration::from_secs(10);
+
+        let mut accumulators = Accumulators::new();
+        let rest = accumulators.fold(metrics(), &options);
+        assert!(rest.is_empty());
+        assert_eq!(accumulators.len(), 3);
+
+        let mut streamed = accumulators.flush(elapsed, &options);
+        let mut buffered = aggregate(group(metrics()), elapsed, &options);
+        buffered.extend(aggregate_counts(&metrics(), elapsed, &options));
+        let name = |metric: &AggregatedMetric| metric.id().name().to_string();
+        streamed.sort_by_key(name);
+        buffered.sort_by_key(name);
+        assert_eq!(streamed, buffered);
+        assert_eq!(accumulators.len(), 0);
+    }
+}
@@ -1,109 +1,954 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::iter::Iterator;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
-use string_cache::DefaultAtom as Atom;
+use hdrhistogram::Histogram as HdrHistogram;
 
-use super::super::metric::{CollectedMetric, Id};
+use super::super::metric::{CollectedMetric, MetricId, Summary};
+use super::super::util::Glob;
+use super::hyperloglog::HyperLogLog;
+use super::sketch::DdSketch;
+use super::storage::{AggregatedKey, Timeseries};
 
-#[derive(Eq, Hash, PartialEq)]
+#[derive(Clone, Eq, Hash, PartialEq)]
 pub enum Group {
-    Count(Id),
-    Gauge(Id),
-    Histogram(Id),
+    Count(MetricId),
+    Gauge(MetricId),
+    Histogram(MetricId),
 }
 
-type GroupedMetrics = HashMap<Group, Vec<(SystemTime, i32)>>;
+/// A sample's time and value, and how many samples it stands for: one,
+/// except for `SampledHistogram`s.
+pub type Sample = (SystemTime, f64, f64);
+
+type GroupedMetrics = HashMap<Group, Vec<Sample>>;
+
+/// Last seen total of every monotonic counter, and how many calls to
+/// `increases` it's gone without a sample.
+pub type MonotonicTotals = HashMap<MetricId, (f64, u32)>;
+
+/// Convert cumulative `MonotonicCount`s into `Count`s of how much they've
+/// increased since the previous sample of the same series. A total lower
+/// than the previous one means the source restarted, in which case the
+/// whole total is the increase. The first sample of a series only
+/// establishes a baseline, as does the first after it went more than
+/// `forget_after` calls without a sample and was forgotten.
+pub fn increases(metrics: Vec<CollectedMetric>, totals: &mut MonotonicTotals, forget_after: u32) -> Vec<CollectedMetric> {
+    let (mut monotonic, mut converted): (Vec<CollectedMetric>, Vec<CollectedMetric>) = metrics.into_iter()
+        .partition(|metric| matches!(*metric, CollectedMetric::MonotonicCount(..)));
+
+    // Samples may have arrived out of order from different connections.
+    monotonic.sort_by_key(|metric| metric.time());
+
+    for metric in monotonic {
+        if let CollectedMetric::MonotonicCount(time, id, total) = metric {
+            if let Some((previous, _)) = totals.insert(id.clone(), (total, 0)) {
+                let increase = if total >= previous { total - previous } else { total };
+                converted.push(CollectedMetric::Count(time, id, increase.round() as i64))
+            }
+        }
+    }
+    // Counting this call, so those sampled by it are at one.
+    totals.retain(|_, &mut (_, ref mut calls)| {
+        *calls = calls.saturating_add(1);
+        *calls - 1 <= forget_after
+    });
+    converted
+}
+
+/// Current value of every gauge, and how many aggregations it's gone
+/// without being updated.
+pub type GaugeValues = HashMap<MetricId, (f64, u32)>;
+
+/// Update gauges' current values in arrival order, the last write winning,
+/// and turn `GaugeDelta`s into `Gauge`s of the values they result in. A
+/// delta to a gauge without a value yet applies to zero.
+pub fn apply_gauges(metrics: Vec<CollectedMetric>, values: &mut GaugeValues) -> Vec<CollectedMetric> {
+    metrics.into_iter()
+        .map(|metric| match metric {
+            CollectedMetric::Gauge(time, id, value) => {
+                values.insert(id.clone(), (value, 0));
+                CollectedMetric::Gauge(time, id, value)
+            },
+            CollectedMetric::GaugeDelta(time, id, delta) => {
+                let value = values.get(&id).map_or(0.0, |current| current.0) + delta;
+                values.insert(id.clone(), (value, 0));
+                CollectedMetric::Gauge(time, id, value)
+            },
+            metric => metric,
+        })
+        .collect()
+}
+
+/// Take the current values of the gauges that haven't been updated since
+/// the previous call, forgetting those that went more than `forget_after`
+/// calls without being updated.
+pub fn idle_gauges(values: &mut GaugeValues, forget_after: u32) -> Vec<(MetricId, f64)> {
+    let mut idle = vec![];
+    values.retain(|id, &mut (value, ref mut idle_for)| {
+        if *idle_for > 0 {
+            if *idle_for > forget_after {
+                return false
+            }
+            idle.push((id.clone(), value))
+        }
+        *idle_for += 1;
+        true
+    });
+    idle
+}
+
+/// What happens to metrics of series beyond the cardinality limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CardinalityOverflow {
+    Drop,
+    /// Roll them all up into one series named `__overflow__` per kind of
+    /// metric, with only a `kind` dimension (eg. `kind=histogram`), so that
+    /// eg. counts and sets' unique counts aren't summed together.
+    Collapse,
+}
+
+/// Admit metrics of at most `max` unique series, counting with `admitted`
+/// which is shared by every shard of the interval. Returns the admitted (or
+/// collapsed) metrics and how many series were over the limit.
+pub fn limit_cardinality(metrics: Vec<CollectedMetric>, admitted: &AtomicUsize, max: usize, overflow: CardinalityOverflow) -> (Vec<CollectedMetric>, usize) {
+    let mut seen: HashMap<MetricId, bool> = HashMap::new();
+    let mut over = 0;
+    let mut limited = Vec::with_capacity(metrics.len());
+    for mut metric in metrics {
+        let allowed = match seen.get(metric.id()) {
+            Some(&allowed) => allowed,
+            None => {
+                let allowed = admitted.fetch_add(1, Ordering::Relaxed) < max;
+                if !allowed {
+                    over += 1
+                }
+                seen.insert(metric.id().to_owned(), allowed);
+                allowed
+            },
+        };
+        if allowed {
+            limited.push(metric)
+        } else if overflow == CardinalityOverflow::Collapse {
+            let kind = match metric {
+                CollectedMetric::Count(..) | CollectedMetric::MonotonicCount(..) => "count",
+                CollectedMetric::Gauge(..) | CollectedMetric::GaugeDelta(..) => "gauge",
+                CollectedMetric::Histogram(..) | CollectedMetric::SampledHistogram(..) => "histogram",
+                CollectedMetric::Set(..) => "set",
+                CollectedMetric::Summary(..) => "summary",
+            };
+            *metric.id_mut() = MetricId::from("__overflow__").with_dimension("kind", kind);
+            limited.push(metric)
+        }
+    }
+    (limited, over)
+}
 
 /// Group metrics by their identifier.
 pub fn group<T: AsRef<Vec<CollectedMetric>>>(metrics: T) -> GroupedMetrics {
     let metrics = metrics.as_ref();
     let mut grouped = GroupedMetrics::new();
-    for metric in metrics.into_iter() {
-        let (group, value) = match metric {
-            &CollectedMetric::Count(time, ref id, value)     => (Group::Count(id.to_owned()), (time, value)),
-            &CollectedMetric::Gauge(time, ref id, value)     => (Group::Gauge(id.to_owned()), (time, value)),
-            &CollectedMetric::Histogram(time, ref id, value) => (Group::Histogram(id.to_owned()), (time, value)),
+    for metric in metrics.iter() {
+        let (group, value) = match *metric {
+            // These are summed exactly by `aggregate_counts`.
+            CollectedMetric::Count(..)                      => continue,
+            // These have to be converted by `increases` first.
+            CollectedMetric::MonotonicCount(..)             => continue,
+            // These are turned into gauges when they're collected.
+            CollectedMetric::GaugeDelta(..)                 => continue,
+            // These are grouped by `group_sets` and `merge_summaries`.
+            CollectedMetric::Set(..) |
+            CollectedMetric::Summary(..)                    => continue,
+            CollectedMetric::Gauge(time, ref id, value)     => (Group::Gauge(id.to_owned()), (time, value, 1.0)),
+            CollectedMetric::Histogram(time, ref id, value) => (Group::Histogram(id.to_owned()), (time, value, 1.0)),
+            CollectedMetric::SampledHistogram(time, ref id, value, weight) => (Group::Histogram(id.to_owned()), (time, value, weight)),
         };
-        let values = grouped.entry(group).or_insert_with(|| vec![]);
+        let values = grouped.entry(group).or_default();
         values.push(value)
     }
     grouped
 }
 
-#[derive(Clone)]
+/// How the unique members of a set are counted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SetMode {
+    /// Keep every distinct member for the interval.
+    Exact,
+    /// Estimate with a HyperLogLog of the given precision (4 through 16) so
+    /// memory stays bounded for high-cardinality sets.
+    HyperLogLog(u8),
+}
+
+/// A set's members so far: every distinct one, or registers estimating how
+/// many there were.
+pub enum Members {
+    Exact(HashSet<String>),
+    Estimated(HyperLogLog),
+}
+
+impl Members {
+    fn new(mode: SetMode) -> Members {
+        match mode {
+            SetMode::Exact => Members::Exact(HashSet::new()),
+            SetMode::HyperLogLog(precision) => Members::Estimated(HyperLogLog::new(precision)),
+        }
+    }
+
+    fn insert(&mut self, member: &str) {
+        match *self {
+            Members::Exact(ref mut members) => {
+                if !members.contains(member) {
+                    members.insert(member.to_owned());
+                }
+            },
+            Members::Estimated(ref mut hll) => hll.insert(member),
+        }
+    }
+
+    fn unique(&self) -> f64 {
+        match *self {
+            Members::Exact(ref members) => members.len() as f64,
+            Members::Estimated(ref hll) => hll.estimate().round(),
+        }
+    }
+}
+
+type GroupedSets = HashMap<MetricId, (SystemTime, Members)>;
+
+/// Group set members by their identifier along with the latest time any
+/// member was seen, counting them per `mode` as they're grouped.
+pub fn group_sets<T: AsRef<Vec<CollectedMetric>>>(metrics: T, mode: SetMode) -> GroupedSets {
+    let mut grouped = GroupedSets::new();
+    for metric in metrics.as_ref().iter() {
+        if let CollectedMetric::Set(time, ref id, ref member) = *metric {
+            let entry = grouped.entry(id.to_owned()).or_insert_with(|| (time, Members::new(mode)));
+            entry.0 = entry.0.max(time);
+            entry.1.insert(member)
+        }
+    }
+    grouped
+}
+
+/// Roll each set up into a gauge of how many unique members it had.
+pub fn aggregate_sets(grouped: GroupedSets) -> Vec<AggregatedMetric> {
+    grouped.into_iter()
+        .map(|(id, (time, members))| AggregatedMetric::Gauge(time, id, members.unique()))
+        .collect()
+}
+
+/// Merge all the summaries of each series into one.
+pub fn merge_summaries<T: AsRef<Vec<CollectedMetric>>>(metrics: T) -> Vec<AggregatedMetric> {
+    let mut merged: HashMap<MetricId, (SystemTime, Summary)> = HashMap::new();
+    for metric in metrics.as_ref().iter() {
+        if let CollectedMetric::Summary(time, ref id, ref summary) = *metric {
+            match merged.get_mut(id) {
+                Some(entry) => {
+                    entry.0 = entry.0.max(time);
+                    entry.1.merge(summary)
+                },
+                None => {
+                    merged.insert(id.to_owned(), (time, summary.to_owned()));
+                },
+            }
+        }
+    }
+    merged.into_iter()
+        .map(|(id, (time, summary))| AggregatedMetric::Summary(time, id, summary))
+        .collect()
+}
+
+/// Merge aggregates of the same kind and series, eg. a histogram's
+/// `.count` and a count that was sent as `foo.count` explicitly, so that
+/// each series has one point per window. Counts are summed (saturating),
+/// summaries and sketches merged, and gauges rolled up per `options`.
+/// The merged point is timestamped with the latest of the duplicates'.
+pub fn merge_duplicates(metrics: Vec<AggregatedMetric>, options: &RollupOptions) -> Vec<AggregatedMetric> {
+    use self::AggregatedMetric::*;
+
+    let mut merged: Vec<AggregatedMetric> = Vec::with_capacity(metrics.len());
+    let mut indexes: HashMap<(u8, MetricId), usize> = HashMap::with_capacity(metrics.len());
+    // Duplicated gauges' samples, rolled up once all of them are known.
+    let mut gauges: HashMap<usize, Vec<Timeseries>> = HashMap::new();
+    for metric in metrics {
+        let kind = match metric {
+            Count(..) => 0,
+            Gauge(..) => 1,
+            Summary(..) => 2,
+            Sketch(..) => 3,
+        };
+        let index = match indexes.get(&(kind, metric.id().clone())) {
+            Some(&index) => index,
+            None => {
+                indexes.insert((kind, metric.id().clone()), merged.len());
+                merged.push(metric);
+                continue
+            },
+        };
+        match (&mut merged[index], metric) {
+            (&mut Count(ref mut time, _, ref mut count), Count(other_time, _, other)) => {
+                *time = (*time).max(other_time);
+                let (sum, saturated) = sum_counts([*count, other]);
+                if saturated {
+                    options.saturated_counts.fetch_add(1, Ordering::Relaxed);
+                }
+                *count = sum
+            },
+            (&mut Gauge(time, _, value), Gauge(other_time, _, other)) => {
+                gauges.entry(index).or_insert_with(|| vec![(time, value)]).push((other_time, other))
+            },
+            (&mut Summary(ref mut time, _, ref mut summary), Summary(other_time, _, ref other)) => {
+                *time = (*time).max(other_time);
+                summary.merge(other)
+            },
+            (&mut Sketch(ref mut time, _, ref mut sketch), Sketch(other_time, _, ref other)) => {
+                *time = (*time).max(other_time);
+                sketch.merge(other)
+            },
+            _ => unreachable!("Duplicates are of the same kind"),
+        }
+    }
+    for (index, mut timeseries) in gauges {
+        // Duplicates arrive in no particular order, so sort them to break
+        // ties between samples at the same time the same way every time.
+        timeseries.sort_by(|x, y| x.0.cmp(&y.0).then(x.1.total_cmp(&y.1)));
+        if let Gauge(ref mut time, ref id, ref mut value) = merged[index] {
+            *time = timeseries[timeseries.len() - 1].0;
+            *value = options.gauge_aggregation(id).apply(timeseries)
+        }
+    }
+    merged
+}
+
+/// How the samples of a gauge over an interval are rolled up into one value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GaugeAggregation {
+    /// The most recent sample.
+    Last,
+    Min,
+    Max,
+    Mean,
+    Sum,
+}
+
+impl GaugeAggregation {
+    /// `timeseries` must not be empty. Ties for `Last` go to whichever
+    /// sample was collected later.
+    pub fn apply<I: IntoIterator<Item = Timeseries>>(&self, timeseries: I) -> f64 {
+        let timeseries = timeseries.into_iter();
+        match *self {
+            GaugeAggregation::Last => timeseries.max_by_key(|t| t.0).map(|t| t.1).unwrap(),
+            GaugeAggregation::Min => timeseries.fold(f64::NAN, |min, t| min.min(t.1)),
+            GaugeAggregation::Max => timeseries.fold(f64::NAN, |max, t| max.max(t.1)),
+            GaugeAggregation::Mean => {
+                let (sum, count) = timeseries.fold((0.0, 0), |(sum, count), t| (sum + t.1, count + 1));
+                sum / count as f64
+            },
+            GaugeAggregation::Sum => timeseries.map(|t| t.1).sum(),
+        }
+    }
+}
+
+/// Settings for how `aggregate` rolls up each group.
+#[derive(Clone, Debug)]
+pub struct RollupOptions {
+    pub gauge: GaugeAggregation,
+    /// Per-metric overrides of `gauge`; the first pattern that matches the
+    /// metric's name wins.
+    pub gauge_overrides: Vec<(Glob, GaugeAggregation)>,
+    /// Percentiles (between 0 and 100) reported for histograms, each as a
+    /// gauge suffixed with eg. `.95percentile` or `.99_9percentile`.
+    pub percentiles: Vec<f64>,
+    /// Per-metric overrides of `percentiles`; the first pattern that
+    /// matches the metric's name wins.
+    pub percentile_overrides: Vec<(Glob, Vec<f64>)>,
+    pub histogram_precision: HistogramPrecision,
+    pub histogram_mode: HistogramMode,
+    /// Upper bounds, in ascending order, of the cumulative buckets reported
+    /// for histograms rolled up as summaries: a `.bucket` count with an
+    /// `le` dimension for each, and one for `+Inf`, plus a `.sum` gauge,
+    /// which make up a Prometheus-style histogram with the `.count`. None
+    /// by default.
+    pub buckets: Vec<f64>,
+    /// Most threads one rollup of grouped metrics is spread over; each
+    /// gets at least `MIN_GROUPS_PER_THREAD` groups.
+    pub threads: usize,
+    /// Counts histogram samples rolled up as summaries that were rejected
+    /// for being negative or NaN (see `HistogramRecorder::record`). Clones
+    /// share it.
+    pub negative_samples: Arc<AtomicUsize>,
+    /// Counts series whose counts summed beyond i64's bounds and saturated.
+    /// Clones share it.
+    pub saturated_counts: Arc<AtomicUsize>,
+}
+
+/// Below this many groups per thread spawning threads costs more than it
+/// saves.
+const MIN_GROUPS_PER_THREAD: usize = 10_000;
+
+impl Default for RollupOptions {
+    fn default() -> RollupOptions {
+        RollupOptions {
+            gauge: GaugeAggregation::Max,
+            gauge_overrides: vec![],
+            percentiles: vec![95.0, 99.0],
+            percentile_overrides: vec![],
+            histogram_precision: HistogramPrecision::default(),
+            histogram_mode: HistogramMode::Summary,
+            buckets: vec![],
+            threads: 1,
+            negative_samples: Arc::default(),
+            saturated_counts: Arc::default(),
+        }
+    }
+}
+
+/// What histograms are rolled up into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HistogramMode {
+    /// Gauges of the min, max, median, average, and percentiles and a count
+    /// of samples.
+    Summary,
+    /// A `DdSketch` with the given relative accuracy (eg. 0.01 for 1%).
+    /// Unlike percentiles, sketches can be merged across shards and agents
+    /// and sent to backends that accept distributions.
+    Sketch(f64),
+}
+
+/// Histogram median and percentiles are computed with an HdrHistogram,
+/// which uses bounded memory no matter how many samples it records.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HistogramPrecision {
+    /// Number of significant decimal digits values are recorded with,
+    /// between 0 and 5; 3 means values are accurate to within 0.1%.
+    pub significant_digits: u8,
+    /// Largest value that can be recorded; larger samples are recorded as
+    /// this value. Defaults to an hour in milliseconds.
+    pub max_value: f64,
+}
+
+impl Default for HistogramPrecision {
+    fn default() -> HistogramPrecision {
+        HistogramPrecision {
+            significant_digits: 3,
+            max_value: 3_600_000.0,
+        }
+    }
+}
+
+/// HdrHistograms record integers; scaling by this keeps three decimal
+/// places of fractional samples such as sub-millisecond timings.
+const HISTOGRAM_SCALE: f64 = 1000.0;
+
+impl RollupOptions {
+    pub fn gauge_aggregation(&self, id: &MetricId) -> GaugeAggregation {
+        self.gauge_overrides.iter()
+            .find(|(pattern, _)| pattern.matches(id.name()))
+            .map(|&(_, aggregation)| aggregation)
+            .unwrap_or(self.gauge)
+    }
+
+    fn percentiles(&self, id: &MetricId) -> &[f64] {
+        self.percentile_overrides.iter()
+            .find(|(pattern, _)| pattern.matches(id.name()))
+            .map(|(_, percentiles)| &percentiles[..])
+            .unwrap_or(&self.percentiles)
+    }
+}
+
+/// Suffix for a percentile's gauge; decimal points become underscores so
+/// that eg. 99.9 doesn't introduce another level into dotted names.
+fn percentile_suffix(percentile: f64) -> String {
+    format!(".{}percentile", percentile.to_string().replace('.', "_"))
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum AggregatedMetric {
-    Count(SystemTime, Id, i32),
-    Gauge(SystemTime, Id, i32),
+    Count(SystemTime, MetricId, i64),
+    Gauge(SystemTime, MetricId, f64),
+    Summary(SystemTime, MetricId, Summary),
+    Sketch(SystemTime, MetricId, DdSketch),
+}
+
+impl AggregatedMetric {
+    pub fn id(&self) -> &MetricId {
+        match *self {
+            AggregatedMetric::Count(_, ref id, _) => id,
+            AggregatedMetric::Gauge(_, ref id, _) => id,
+            AggregatedMetric::Summary(_, ref id, _) => id,
+            AggregatedMetric::Sketch(_, ref id, _) => id,
+        }
+    }
+
+    pub fn id_mut(&mut self) -> &mut MetricId {
+        match *self {
+            AggregatedMetric::Count(_, ref mut id, _) => id,
+            AggregatedMetric::Gauge(_, ref mut id, _) => id,
+            AggregatedMetric::Summary(_, ref mut id, _) => id,
+            AggregatedMetric::Sketch(_, ref mut id, _) => id,
+        }
+    }
+
+    /// Flatten into the count and gauge series it's stored and sent as.
+    /// Summaries are a `.count` count, a `.sum` gauge, and a gauge per
+    /// quantile with a `quantile` dimension. Sketches are a `.count` count
+    /// and `.sum`, `.min`, `.max`, and `.median` gauges.
+    pub fn entries(&self) -> Vec<(AggregatedKey, Timeseries)> {
+        use self::AggregatedMetric::*;
+
+        match *self {
+            Count(time, ref id, value) => vec![(AggregatedKey::Count(id.to_owned()), (time, value as f64))],
+            Gauge(time, ref id, value) => vec![(AggregatedKey::Gauge(id.to_owned()), (time, value))],
+            Summary(time, ref id, ref summary) => {
+                let mut entries = vec![
+                    (AggregatedKey::Count(id.with_suffix(".count")), (time, summary.count as f64)),
+                    (AggregatedKey::Gauge(id.with_suffix(".sum")), (time, summary.sum)),
+                ];
+                for &(quantile, value) in &summary.quantiles {
+                    let quantile_id = id.with_dimension("quantile", quantile.to_string());
+                    entries.push((AggregatedKey::Gauge(quantile_id), (time, value)));
+                }
+                entries
+            },
+            Sketch(time, ref id, ref sketch) => {
+                let mut entries = vec![
+                    (AggregatedKey::Count(id.with_suffix(".count")), (time, sketch.count() as f64)),
+                    (AggregatedKey::Gauge(id.with_suffix(".sum")), (time, sketch.sum())),
+                ];
+                let gauges = [(".min", sketch.min()), (".max", sketch.max()), (".median", sketch.quantile(0.5))];
+                for &(suffix, value) in &gauges {
+                    if let Some(value) = value {
+                        entries.push((AggregatedKey::Gauge(id.with_suffix(suffix)), (time, value)));
+                    }
+                }
+                entries
+            },
+        }
+    }
+}
+
+/// Roll up each group. `elapsed` is how long the aggregation window was and
+/// is used to derive a per-second `.rate` gauge for every count. Large
+/// rollups are spread over up to `options.threads` threads.
+pub fn aggregate(grouped: GroupedMetrics, elapsed: Duration, options: &RollupOptions) -> Vec<AggregatedMetric> {
+    let seconds = elapsed.as_secs_f64();
+    let threads = options.threads.min(grouped.len() / MIN_GROUPS_PER_THREAD).max(1);
+    if threads == 1 {
+        return rollup_groups(grouped, seconds, options)
+    }
+
+    // Groups are independent, so each thread's results are disjoint and
+    // merging them is concatenation.
+    let mut chunks: Vec<Vec<(Group, Vec<Sample>)>> = (0..threads).map(|_| vec![]).collect();
+    for (index, group) in grouped.into_iter().enumerate() {
+        chunks[index % threads].push(group)
+    }
+    thread::scope(|scope| {
+        let handles = chunks.into_iter()
+            .map(|chunk| scope.spawn(move || rollup_groups(chunk, seconds, options)))
+            .collect::<Vec<_>>();
+        handles.into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
 }
 
-pub fn aggregate(grouped: GroupedMetrics) -> Vec<AggregatedMetric> {
+fn rollup_groups<I>(grouped: I, seconds: f64, options: &RollupOptions) -> Vec<AggregatedMetric>
+    where I: IntoIterator<Item = (Group, Vec<Sample>)>
+{
     let mut aggregated = Vec::<AggregatedMetric>::new();
-    for (group, timeseries) in grouped.into_iter() {
+    for (group, timeseries) in grouped {
         use self::AggregatedMetric::*;
 
         let time = match timeseries.iter().max_by(|x, y| x.0.cmp(&y.0)) {
             Some(t) => t.0,
             None => continue,
         };
-        let values = timeseries.iter().map(|t| t.1).collect::<Vec<i32>>();
 
         match group {
-            Group::Count(id) => {
-                let count = values.iter().fold(0, |memo, value| memo + value);
-                aggregated.push(Count(time, id, count))
-            },
+            // Only grouped by hand, since `group` leaves counts to
+            // `aggregate_counts`.
+            Group::Count(id) => push_count(&mut aggregated, time, id, sum_counts(timeseries.iter().map(|t| t.1 as i64)), seconds, options),
             Group::Gauge(id) => {
-                let max = values.iter().max_by(|x, y| x.cmp(&y)).unwrap_or(&0);
-                aggregated.push(Gauge(time, id, *max))
+                let value = options.gauge_aggregation(&id).apply(timeseries.iter().map(|&(time, value, _)| (time, value)));
+                aggregated.push(Gauge(time, id, value))
             },
             Group::Histogram(id) => {
-                let histogram = Histogram::from(&values);
-
-                aggregated.push(Gauge(time, suffix_id(&id, ".min"), histogram.min));
-                aggregated.push(Gauge(time, suffix_id(&id, ".max"), histogram.max));
-                aggregated.push(Gauge(time, suffix_id(&id, ".median"), histogram.median));
-                aggregated.push(Gauge(time, suffix_id(&id, ".avg"), histogram.average));
-                aggregated.push(Gauge(time, suffix_id(&id, ".95percentile"), histogram.percentile95));
-                aggregated.push(Gauge(time, suffix_id(&id, ".99percentile"), histogram.percentile99));
+                if let HistogramMode::Sketch(relative_accuracy) = options.histogram_mode {
+                    let mut sketch = DdSketch::new(relative_accuracy);
+                    for &(_, value, weight) in &timeseries {
+                        sketch.insert_n(value, weight.round().max(1.0) as u64)
+                    }
+                    aggregated.push(Sketch(time, id, sketch));
+                    continue
+                }
 
-                aggregated.push(Count(time, suffix_id(&id, ".count"), values.len() as i32));
+                let mut recorder = HistogramRecorder::new(options.histogram_precision, &options.buckets);
+                for &(_, value, weight) in &timeseries {
+                    if !recorder.record_weighted(value, weight) {
+                        options.negative_samples.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                if !recorder.is_empty() {
+                    push_histogram(&mut aggregated, time, id, &recorder, options);
+                }
             },
         }
     }
     aggregated
 }
 
-/// Add a suffix to the end of the name of a metric.
-fn suffix_id<S: AsRef<str>>(id: &Id, suffix: S) -> Id {
-    let &(ref name_atom, ref dimensions) = id;
-    let name: &str = &name_atom;
+/// Sum each series' counts and push them with their `.rate` gauges, like
+/// `aggregate` does for every other kind of group.
+pub fn aggregate_counts(metrics: &[CollectedMetric], elapsed: Duration, options: &RollupOptions) -> Vec<AggregatedMetric> {
+    let mut sums: HashMap<&MetricId, (SystemTime, Vec<i64>)> = HashMap::new();
+    for metric in metrics {
+        if let CollectedMetric::Count(time, ref id, value) = *metric {
+            let sum = sums.entry(id).or_insert_with(|| (time, vec![]));
+            sum.0 = sum.0.max(time);
+            sum.1.push(value)
+        }
+    }
+    let seconds = elapsed.as_secs_f64();
+    let mut aggregated = vec![];
+    for (id, (time, values)) in sums {
+        push_count(&mut aggregated, time, id.clone(), sum_counts(values), seconds, options)
+    }
+    aggregated
+}
 
-    (Atom::from(format!("{}{}", name, suffix.as_ref())), dimensions.to_owned())
+/// Sum counts exactly, saturating at i64's bounds rather than wrapping.
+/// Returns the sum and whether it saturated.
+pub fn sum_counts<I: IntoIterator<Item = i64>>(counts: I) -> (i64, bool) {
+    let mut sum = 0i64;
+    let mut saturated = false;
+    for count in counts {
+        sum = match sum.checked_add(count) {
+            Some(sum) => sum,
+            None => {
+                saturated = true;
+                if count > 0 { i64::MAX } else { i64::MIN }
+            },
+        };
+    }
+    (sum, saturated)
 }
 
-struct Histogram {
-    min: i32,
-    max: i32,
-    median: i32,
-    average: i32,
-    percentile95: i32,
-    percentile99: i32,
+/// Push a count summed by `sum_counts` and its `.rate` gauge, counting it
+/// in `options.saturated_counts` if it saturated.
+pub fn push_count(aggregated: &mut Vec<AggregatedMetric>, time: SystemTime, id: MetricId, (count, saturated): (i64, bool), seconds: f64, options: &RollupOptions) {
+    if saturated {
+        options.saturated_counts.fetch_add(1, Ordering::Relaxed);
+    }
+    if seconds > 0.0 {
+        aggregated.push(AggregatedMetric::Gauge(time, id.with_suffix(".rate"), count as f64 / seconds))
+    }
+    aggregated.push(AggregatedMetric::Count(time, id, count))
 }
 
-impl<'a> From<&'a Vec<i32>> for Histogram {
-    fn from(values: &'a Vec<i32>) -> Histogram {
-        let mut sorted = values.clone();
-        sorted.sort_by(|a, b| a.cmp(b));
+/// Push the gauges and count a histogram is summarized as. `recorder` must
+/// not be empty.
+pub fn push_histogram(aggregated: &mut Vec<AggregatedMetric>, time: SystemTime, id: MetricId, recorder: &HistogramRecorder, options: &RollupOptions) {
+    use self::AggregatedMetric::*;
 
-        Histogram {
-            min:          *sorted.first().unwrap(),
-            max:          *sorted.last().unwrap(),
-            median:       sorted[sorted.len() / 2], // TODO: Improve how we calculate the median
-            average:      (sorted.iter().fold(0.0, |sum, val| { sum + (*val as f64) }) / (sorted.len() as f64)) as i32,
-            percentile95: sorted[(sorted.len() as f64 * 0.95) as usize],
-            percentile99: sorted[(sorted.len() as f64 * 0.99) as usize],
+    aggregated.push(Gauge(time, id.with_suffix(".min"), recorder.min));
+    aggregated.push(Gauge(time, id.with_suffix(".max"), recorder.max));
+    aggregated.push(Gauge(time, id.with_suffix(".median"), recorder.percentile(50.0)));
+    aggregated.push(Gauge(time, id.with_suffix(".avg"), recorder.sum / recorder.count));
+    for &percentile in options.percentiles(&id) {
+        aggregated.push(Gauge(time, id.with_suffix(percentile_suffix(percentile)), recorder.percentile(percentile)));
+    }
+
+    if !recorder.bounds.is_empty() {
+        let bucket = id.with_suffix(".bucket");
+        let mut cumulative = 0.0;
+        for (bound, &count) in recorder.bounds.iter().zip(&recorder.buckets) {
+            cumulative += count;
+            aggregated.push(Count(time, bucket.with_dimension("le", bound.to_string()), cumulative.round() as i64));
         }
+        aggregated.push(Count(time, bucket.with_dimension("le", "+Inf"), recorder.count.round() as i64));
+        aggregated.push(Gauge(time, id.with_suffix(".sum"), recorder.sum));
     }
+    aggregated.push(Count(time, id.with_suffix(".count"), recorder.count.round() as i64));
+}
+
+/// Records histogram samples into an HdrHistogram. Min, max, and average
+/// are tracked exactly. Counts are fractional since sampled samples stand
+/// for `1 / rate` of them; they're rounded when emitted.
+pub struct HistogramRecorder {
+    hdr: HdrHistogram<u64>,
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: f64,
+    /// Upper bounds of the cumulative buckets, and how many samples fell
+    /// into each bound but not the one before it.
+    bounds: Vec<f64>,
+    buckets: Vec<f64>,
 }
 
+impl HistogramRecorder {
+    /// Samples are also counted into cumulative `buckets` (upper bounds in
+    /// ascending order) if there are any.
+    pub fn new(precision: HistogramPrecision, buckets: &[f64]) -> HistogramRecorder {
+        let high = ((precision.max_value * HISTOGRAM_SCALE) as u64).max(2);
+        HistogramRecorder {
+            hdr: HdrHistogram::<u64>::new_with_bounds(1, high, precision.significant_digits.min(5))
+                .expect("Bounds and significant digits are valid"),
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0.0,
+            count: 0.0,
+            bounds: buckets.to_vec(),
+            buckets: vec![0.0; buckets.len()],
+        }
+    }
+
+    /// Record `value` as `weight` samples, eg. 10 for one taken at a rate
+    /// of 0.1, unless it's negative, which an HdrHistogram can't hold;
+    /// returns false for those, and NaNs, which aren't recorded. The
+    /// HdrHistogram's percentiles count it a whole number of times.
+    pub fn record_weighted(&mut self, value: f64, weight: f64) -> bool {
+        if value.is_nan() || value < 0.0 {
+            return false
+        }
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value * weight;
+        self.count += weight;
+        let bucket = self.bounds.partition_point(|&bound| bound < value);
+        if let Some(count) = self.buckets.get_mut(bucket) {
+            *count += weight
+        }
+        let scaled = (value * HISTOGRAM_SCALE).round() as u64;
+        self.hdr.saturating_record_n(scaled, weight.round().max(1.0) as u64);
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min > self.max
+    }
+
+    fn percentile(&self, percentile: f64) -> f64 {
+        // Recorded values are rounded up to the top of their bucket, so
+        // clamp to the exact range that was observed.
+        (self.hdr.value_at_percentile(percentile) as f64 / HISTOGRAM_SCALE).max(self.min).min(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn id(name: &str) -> MetricId {
+        MetricId::from(name)
+    }
+
+    #[test]
+    fn it_computes_increases_of_monotonic_counts() {
+        let t = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut totals = MonotonicTotals::new();
+
+        // The first sample is a baseline; out-of-order samples are sorted.
+        let converted = increases(vec![
+            CollectedMetric::MonotonicCount(t(2), id("foo"), 15.0),
+            CollectedMetric::MonotonicCount(t(1), id("foo"), 10.0),
+            CollectedMetric::Count(t(1), id("bar"), 1),
+        ], &mut totals, 1);
+        assert_eq!(converted, vec![
+            CollectedMetric::Count(t(1), id("bar"), 1),
+            CollectedMetric::Count(t(2), id("foo"), 5),
+        ]);
+
+        // A lower total is a reset.
+        let converted = increases(vec![
+            CollectedMetric::MonotonicCount(t(3), id("foo"), 3.0),
+        ], &mut totals, 1);
+        assert_eq!(converted, vec![CollectedMetric::Count(t(3), id("foo"), 3)]);
+
+        // One that went idle for longer than `forget_after` is forgotten,
+        // and starts over with a new baseline.
+        increases(vec![], &mut totals, 1);
+        assert_eq!(totals.len(), 1);
+        increases(vec![], &mut totals, 1);
+        assert!(totals.is_empty());
+        let converted = increases(vec![
+            CollectedMetric::MonotonicCount(t(6), id("foo"), 8.0),
+        ], &mut totals, 1);
+        assert_eq!(converted, vec![]);
+    }
+
+    #[test]
+    fn it_limits_cardinality() {
+        let t = UNIX_EPOCH + Duration::from_secs(1);
+        let metrics = || vec![
+            CollectedMetric::Gauge(t, id("a"), 1.0),
+            CollectedMetric::Gauge(t, id("b"), 2.0),
+            CollectedMetric::Gauge(t, id("a"), 3.0),
+            CollectedMetric::Count(t, id("c"), 4),
+        ];
+
+        let (limited, over) = limit_cardinality(metrics(), &AtomicUsize::new(0), 1, CardinalityOverflow::Drop);
+        assert_eq!(limited, vec![
+            CollectedMetric::Gauge(t, id("a"), 1.0),
+            CollectedMetric::Gauge(t, id("a"), 3.0),
+        ]);
+        assert_eq!(over, 2);
+
+        // Collapsed per kind.
+        let overflow = |kind| id("__overflow__").with_dimension("kind", kind);
+        let (limited, over) = limit_cardinality(metrics(), &AtomicUsize::new(1), 2, CardinalityOverflow::Collapse);
+        assert_eq!(limited, vec![
+            CollectedMetric::Gauge(t, id("a"), 1.0),
+            CollectedMetric::Gauge(t, overflow("gauge"), 2.0),
+            CollectedMetric::Gauge(t, id("a"), 3.0),
+            CollectedMetric::Count(t, overflow("count"), 4),
+        ]);
+        assert_eq!(over, 2);
+    }
+
+    #[test]
+    fn it_merges_summaries() {
+        let t = UNIX_EPOCH + Duration::from_secs(1);
+        let summary = |count, sum, quantiles| Summary { count, sum, quantiles };
+
+        let merged = merge_summaries(vec![
+            CollectedMetric::Summary(t, id("foo"), summary(1, 10.0, vec![(0.5, 10.0), (0.99, 10.0)])),
+            CollectedMetric::Summary(t, id("foo"), summary(3, 6.0, vec![(0.5, 2.0)])),
+        ]);
+        assert_eq!(merged, vec![
+            AggregatedMetric::Summary(t, id("foo"), summary(4, 16.0, vec![(0.5, 4.0)])),
+        ]);
+    }
+
+    #[test]
+    fn it_merges_duplicate_aggregates() {
+        let t = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let options = RollupOptions { gauge: GaugeAggregation::Sum, ..RollupOptions::default() };
+        let merged = merge_duplicates(vec![
+            AggregatedMetric::Count(t(1), id("foo.count"), 2),
+            AggregatedMetric::Gauge(t(1), id("foo.count"), 1.0),
+            AggregatedMetric::Count(t(3), id("foo.count"), 3),
+            AggregatedMetric::Gauge(t(2), id("foo.count"), 4.0),
+            AggregatedMetric::Gauge(t(1), id("bar"), 1.0),
+        ], &options);
+        assert_eq!(merged, vec![
+            AggregatedMetric::Count(t(3), id("foo.count"), 5),
+            AggregatedMetric::Gauge(t(2), id("foo.count"), 5.0),
+            AggregatedMetric::Gauge(t(1), id("bar"), 1.0),
+        ]);
+    }
+
+    #[test]
+    fn it_reports_configured_percentiles() {
+        let t = UNIX_EPOCH + Duration::from_secs(1);
+        let timeseries = (1..=1000).map(|v| (t, v as f64, 1.0)).collect();
+        let mut grouped = GroupedMetrics::new();
+        grouped.insert(Group::Histogram(id("foo")), timeseries);
+
+        let options = RollupOptions {
+            percentile_overrides: vec![(Glob::new("f*"), vec![50.0, 99.9, 100.0])],
+            ..RollupOptions::default()
+        };
+        let aggregated = aggregate(grouped, Duration::from_secs(10), &options);
+        let gauge = |name: &str| aggregated.iter()
+            .filter_map(|metric| match *metric {
+                AggregatedMetric::Gauge(_, ref id, value) if &**id.name() == name => Some(value),
+                _ => None,
+            })
+            .next();
+        // HdrHistogram buckets are accurate to 3 significant digits by default.
+        for &(name, value) in &[("foo.50percentile", 500.0), ("foo.99_9percentile", 999.0), ("foo.100percentile", 1000.0)] {
+            let actual = gauge(name).unwrap();
+            assert!((actual - value).abs() / value <= 0.002, "{} = {}", name, actual);
+        }
+        assert_eq!(gauge("foo.min"), Some(1.0));
+        assert_eq!(gauge("foo.avg"), Some(500.5));
+        assert!(!aggregated.iter().any(|metric| match *metric {
+            AggregatedMetric::Gauge(_, ref id, _) => &**id.name() == "foo.95percentile",
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn it_rolls_up_on_several_threads() {
+        let t = UNIX_EPOCH + Duration::from_secs(1);
+        let grouped = (0..MIN_GROUPS_PER_THREAD * 2)
+            .map(|index| (Group::Count(id(&index.to_string())), vec![(t, 1.0, 1.0), (t, index as f64, 1.0)]))
+            .collect::<GroupedMetrics>();
+
+        let options = RollupOptions { threads: 4, ..RollupOptions::default() };
+        let mut aggregated = aggregate(grouped.clone(), Duration::from_secs(10), &options);
+        let mut expected = aggregate(grouped, Duration::from_secs(10), &RollupOptions::default());
+        let key = |metric: &AggregatedMetric| metric.id().clone();
+        aggregated.sort_by_key(key);
+        expected.sort_by_key(key);
+        assert_eq!(aggregated.len(), MIN_GROUPS_PER_THREAD * 4);
+        assert_eq!(aggregated, expected);
+    }
+
+    #[test]
+    fn it_counts_samples_into_cumulative_buckets() {
+        let t = UNIX_EPOCH + Duration::from_secs(1);
+        let mut grouped = GroupedMetrics::new();
+        grouped.insert(Group::Histogram(id("foo")), vec![(t, 0.05, 1.0), (t, 0.1, 1.0), (t, 0.5, 1.0), (t, 5.0, 1.0)]);
+
+        let options = RollupOptions { buckets: vec![0.1, 1.0], ..RollupOptions::default() };
+        let aggregated = aggregate(grouped, Duration::from_secs(10), &options);
+        let bucket = |le: &str| id("foo.bucket").with_dimension("le", le);
+        // Bounds are inclusive.
+        for &(le, count) in &[("0.1", 2), ("1", 3), ("+Inf", 4)] {
+            assert!(aggregated.contains(&AggregatedMetric::Count(t, bucket(le), count)), "le={}", le);
+        }
+        assert!(aggregated.contains(&AggregatedMetric::Gauge(t, id("foo.sum"), 5.65)));
+    }
+
+    #[test]
+    fn it_rejects_negative_histogram_samples() {
+        let t = UNIX_EPOCH + Duration::from_secs(1);
+        let mut grouped = GroupedMetrics::new();
+        grouped.insert(Group::Histogram(id("foo")), vec![(t, -3.0, 1.0), (t, 2.0, 1.0), (t, 4.0, 1.0)]);
+        grouped.insert(Group::Histogram(id("bar")), vec![(t, -1.0, 1.0)]);
+
+        let options = RollupOptions::default();
+        let aggregated = aggregate(grouped, Duration::from_secs(10), &options);
+        assert!(aggregated.contains(&AggregatedMetric::Gauge(t, id("foo.min"), 2.0)));
+        assert!(aggregated.contains(&AggregatedMetric::Count(t, id("foo.count"), 2)));
+        assert!(aggregated.iter().all(|metric| !metric.id().name().starts_with("bar")));
+        assert_eq!(options.negative_samples.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn it_weighs_sampled_histogram_samples() {
+        let t = UNIX_EPOCH + Duration::from_secs(1);
+        let mut grouped = GroupedMetrics::new();
+        grouped.insert(Group::Histogram(id("foo")), vec![(t, 2.0, 10.0), (t, 4.0, 1.0)]);
+
+        let aggregated = aggregate(grouped, Duration::from_secs(10), &RollupOptions::default());
+        assert!(aggregated.contains(&AggregatedMetric::Count(t, id("foo.count"), 11)));
+        assert!(aggregated.contains(&AggregatedMetric::Gauge(t, id("foo.avg"), 24.0 / 11.0)));
+        assert!(aggregated.contains(&AggregatedMetric::Gauge(t, id("foo.median"), 2.0)));
+    }
+
+    #[test]
+    fn it_rolls_histograms_up_into_sketches() {
+        let t = UNIX_EPOCH + Duration::from_secs(1);
+        let mut grouped = GroupedMetrics::new();
+        grouped.insert(Group::Histogram(id("foo")), vec![(t, 1.0, 1.0), (t, 2.0, 1.0)]);
+
+        let options = RollupOptions {
+            histogram_mode: HistogramMode::Sketch(0.01),
+            ..RollupOptions::default()
+        };
+        let mut sketch = DdSketch::new(0.01);
+        sketch.insert(1.0);
+        sketch.insert(2.0);
+        assert_eq!(aggregate(grouped, Duration::from_secs(10), &options), vec![
+            AggregatedMetric::Sketch(t, id("foo"), sketch),
+        ]);
+    }
+}
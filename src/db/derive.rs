@@ -0,0 +1,222 @@
+//! Series computed from each aggregation, eg. `error_rate` as
+//! `errors / requests`, so that every backend gets ratios computed over the
+//! same window rather than each doing its own mismatched math.
+//!
+//! Expressions are arithmetic (`+ - * /` and parentheses) over numbers and
+//! names of aggregated series as they're flattened for storage, eg.
+//! `requests`, `requests.rate`, or `latency.count`. A derived gauge is
+//! published for every set of dimensions any of its series has, using for
+//! each series the point with exactly those dimensions or, failing that,
+//! the one without dimensions. Sets for which a series has neither, or
+//! where the result isn't finite (eg. dividing by zero), are skipped.
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::SystemTime;
+
+use super::super::metric::{Dimension, MetricId};
+use super::aggregate::AggregatedMetric;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expression {
+    Number(f64),
+    Series(String),
+    Binary(Box<Expression>, char, Box<Expression>),
+}
+
+impl Expression {
+    fn series<'a>(&'a self, names: &mut BTreeSet<&'a str>) {
+        match *self {
+            Expression::Number(_) => (),
+            Expression::Series(ref name) => { names.insert(name); },
+            Expression::Binary(ref left, _, ref right) => {
+                left.series(names);
+                right.series(names);
+            },
+        }
+    }
+
+    fn evaluate(&self, value: &dyn Fn(&str) -> Option<f64>) -> Option<f64> {
+        match *self {
+            Expression::Number(number) => Some(number),
+            Expression::Series(ref name) => value(name),
+            Expression::Binary(ref left, operator, ref right) => {
+                let (left, right) = (left.evaluate(value)?, right.evaluate(value)?);
+                Some(match operator {
+                    '+' => left + right,
+                    '-' => left - right,
+                    '*' => left * right,
+                    _ => left / right,
+                })
+            },
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&mut self) -> Option<char> {
+        self.input[self.position..].chars().find(|c| !c.is_whitespace())
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let rest = &self.input[self.position..];
+        let (index, c) = rest.char_indices().find(|(_, c)| !c.is_whitespace())?;
+        self.position += index + c.len_utf8();
+        Some(c)
+    }
+
+    // expression = term (("+" | "-") term)*
+    fn expression(&mut self) -> Result<Expression, String> {
+        let mut left = self.term()?;
+        while let Some(operator) = self.peek().filter(|c| *c == '+' || *c == '-') {
+            self.next();
+            left = Expression::Binary(Box::new(left), operator, Box::new(self.term()?));
+        }
+        Ok(left)
+    }
+
+    // term = factor (("*" | "/") factor)*
+    fn term(&mut self) -> Result<Expression, String> {
+        let mut left = self.factor()?;
+        while let Some(operator) = self.peek().filter(|c| *c == '*' || *c == '/') {
+            self.next();
+            left = Expression::Binary(Box::new(left), operator, Box::new(self.factor()?));
+        }
+        Ok(left)
+    }
+
+    // factor = number | series | "(" expression ")" | "-" factor
+    fn factor(&mut self) -> Result<Expression, String> {
+        match self.peek() {
+            Some('(') => {
+                self.next();
+                let inner = self.expression()?;
+                match self.next() {
+                    Some(')') => Ok(inner),
+                    _ => Err(format!("expected `)` at {}", self.position)),
+                }
+            },
+            Some('-') => {
+                self.next();
+                Ok(Expression::Binary(Box::new(Expression::Number(0.0)), '-', Box::new(self.factor()?)))
+            },
+            Some(c) if c.is_ascii_digit() || c.is_alphabetic() || c == '_' || c == '.' => {
+                let rest = self.input[self.position..].trim_start();
+                self.position = self.input.len() - rest.len();
+                let length = rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.' || c == ':')).unwrap_or(rest.len());
+                let token = &rest[..length];
+                self.position += length;
+                if c.is_ascii_digit() || c == '.' {
+                    token.parse().map(Expression::Number).map_err(|_| format!("invalid number `{}`", token))
+                } else {
+                    Ok(Expression::Series(token.to_string()))
+                }
+            },
+            Some(c) => Err(format!("unexpected `{}` at {}", c, self.position)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+/// A gauge computed from other series in each aggregation.
+#[derive(Clone, Debug)]
+pub struct DerivedMetric {
+    name: String,
+    expression: Expression,
+}
+
+impl DerivedMetric {
+    pub fn new(name: &str, expression: &str) -> Result<DerivedMetric, String> {
+        let mut parser = Parser { input: expression, position: 0 };
+        let parsed = parser.expression()?;
+        if let Some(c) = parser.peek() {
+            return Err(format!("unexpected `{}` at {}", c, parser.position))
+        }
+        Ok(DerivedMetric {
+            name: name.to_string(),
+            expression: parsed,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Compute every derived gauge from an aggregation, timestamped `time`.
+pub fn derive(derived: &[DerivedMetric], aggregated: &[AggregatedMetric], time: SystemTime) -> Vec<AggregatedMetric> {
+    if derived.is_empty() {
+        return vec![]
+    }
+
+    let mut referenced = BTreeSet::new();
+    for metric in derived {
+        metric.expression.series(&mut referenced);
+    }
+    let mut values: HashMap<&str, HashMap<Vec<Dimension>, f64>> = HashMap::new();
+    for metric in aggregated {
+        for (key, (_, value)) in metric.entries() {
+            let id = key.id();
+            if let Some(name) = referenced.get(&**id.name()) {
+                values.entry(name).or_default().insert(id.dimensions().to_vec(), value);
+            }
+        }
+    }
+
+    let mut results = vec![];
+    for metric in derived {
+        let mut series = BTreeSet::new();
+        metric.expression.series(&mut series);
+        let dimension_sets = series.iter()
+            .filter_map(|name| values.get(name))
+            .flat_map(|points| points.keys())
+            .collect::<BTreeSet<&Vec<Dimension>>>();
+        for dimensions in dimension_sets {
+            let value = |name: &str| {
+                let points = values.get(name)?;
+                points.get(dimensions).or_else(|| points.get(&vec![])).cloned()
+            };
+            if let Some(value) = metric.expression.evaluate(&value).filter(|value| value.is_finite()) {
+                results.push(AggregatedMetric::Gauge(time, MetricId::new(metric.name.as_str(), dimensions.clone()), value));
+            }
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn it_parses_expressions() {
+        let parsed = DerivedMetric::new("x", "(a.count - 1) * -2 / b").unwrap().expression;
+        let a = Expression::Binary(Box::new(Expression::Series("a.count".to_string())), '-', Box::new(Expression::Number(1.0)));
+        let negative = Expression::Binary(Box::new(Expression::Number(0.0)), '-', Box::new(Expression::Number(2.0)));
+        let product = Expression::Binary(Box::new(a), '*', Box::new(negative));
+        assert_eq!(parsed, Expression::Binary(Box::new(product), '/', Box::new(Expression::Series("b".to_string()))));
+        assert!(DerivedMetric::new("x", "a +").is_err());
+        assert!(DerivedMetric::new("x", "(a").is_err());
+        assert!(DerivedMetric::new("x", "a b").is_err());
+    }
+
+    #[test]
+    fn it_derives_per_dimension_set() {
+        let derived = vec![DerivedMetric::new("error_rate", "errors / requests").unwrap()];
+        let host = |host| MetricId::from("requests").with_dimension("host", host);
+        let aggregated = vec![
+            AggregatedMetric::Count(UNIX_EPOCH, host("a"), 10),
+            AggregatedMetric::Count(UNIX_EPOCH, host("b"), 0),
+            AggregatedMetric::Count(UNIX_EPOCH, host("a").with_name("errors"), 2),
+            AggregatedMetric::Count(UNIX_EPOCH, host("b").with_name("errors"), 0),
+        ];
+        let expected = vec![AggregatedMetric::Gauge(UNIX_EPOCH, host("a").with_name("error_rate"), 0.2)];
+        assert_eq!(derive(&derived, &aggregated, UNIX_EPOCH), expected);
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::Timeseries;
+
+/// Points older than `after` are compacted into one point per `interval`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Resolution {
+    pub after: Duration,
+    pub interval: Duration,
+}
+
+/// How many aggregated points each compacted point of one series stands
+/// for, by its time, so that compacting it again into a coarser resolution
+/// can weigh it by them. A point that isn't in it stands for one.
+pub type Weights = HashMap<SystemTime, u64>;
+
+/// Compact the points of one series that are older than `cutoff` so there's
+/// at most one per `interval`-aligned bucket. `combine` rolls up the values
+/// of a bucket given how many points each stands for, and the compacted
+/// point keeps the latest time of its points and stands for all of theirs.
+/// Points are put in time order first since corrections for late samples
+/// may have been stored out of order.
+pub fn downsample<F>(values: &mut Vec<Timeseries>, weights: &mut Weights, cutoff: SystemTime, interval: Duration, combine: F)
+    where F: Fn(&[Timeseries], &[u64]) -> f64
+{
+    values.sort_by_key(|&(time, _)| time);
+
+    let interval = interval.as_nanos().max(1);
+    let bucket = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() / interval
+    };
+
+    let old = values.iter().take_while(|&&(time, _)| time < cutoff).count();
+    let mut compacted: Vec<Timeseries> = Vec::with_capacity(values.len());
+    let mut start = 0;
+    while start < old {
+        let current = bucket(values[start].0);
+        let end = start + values[start..old].iter()
+            .take_while(|&&(time, _)| bucket(time) == current)
+            .count();
+        let points = &values[start..end];
+        let counts = points.iter().map(|&(time, _)| weights.remove(&time).unwrap_or(1)).collect::<Vec<_>>();
+        let time = points[points.len() - 1].0;
+        compacted.push((time, combine(points, &counts)));
+        let count = counts.iter().sum();
+        if count > 1 {
+            weights.insert(time, count);
+        }
+        start = end;
+    }
+    compacted.extend_from_slice(&values[old..]);
+    *values = compacted;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn it_compacts_points_before_the_cutoff() {
+        let mut values = vec![(at(0), 1.0), (at(10), 2.0), (at(50), 3.0), (at(60), 4.0), (at(70), 5.0), (at(130), 6.0)];
+        let mut weights = Weights::new();
+        let sum = |points: &[Timeseries], _: &[u64]| points.iter().map(|t| t.1).sum();
+        downsample(&mut values, &mut weights, at(120), Duration::from_secs(60), sum);
+        assert_eq!(values, vec![(at(50), 6.0), (at(70), 9.0), (at(130), 6.0)]);
+
+        // Compacting again is a no-op.
+        downsample(&mut values, &mut weights, at(120), Duration::from_secs(60), sum);
+        assert_eq!(values, vec![(at(50), 6.0), (at(70), 9.0), (at(130), 6.0)]);
+    }
+
+    #[test]
+    fn it_weighs_compacted_points_by_what_they_stand_for() {
+        let mut values = vec![(at(0), 1.0), (at(10), 2.0), (at(20), 3.0), (at(70), 10.0)];
+        let mut weights = Weights::new();
+        let mean = |points: &[Timeseries], counts: &[u64]| {
+            let total = points.iter().zip(counts).map(|(t, &count)| t.1 * count as f64).sum::<f64>();
+            total / counts.iter().sum::<u64>() as f64
+        };
+        downsample(&mut values, &mut weights, at(60), Duration::from_secs(60), mean);
+        assert_eq!(values, vec![(at(20), 2.0), (at(70), 10.0)]);
+        assert_eq!(weights.get(&at(20)), Some(&3));
+
+        // The mean of all four points rather than of the two.
+        downsample(&mut values, &mut weights, at(120), Duration::from_secs(120), mean);
+        assert_eq!(values, vec![(at(70), 4.0)]);
+        assert_eq!(weights.get(&at(70)), Some(&4));
+        assert_eq!(weights.len(), 1);
+    }
+}
@@ -0,0 +1,138 @@
+//! Rollups supplied by embedders for metrics the built-in aggregation
+//! doesn't suit, eg. a weighted average or a domain-specific score, so
+//! they don't have to fork `db::aggregate`.
+//!
+//! Each hook is bound to a name pattern. Every interval, the samples of
+//! each series whose name it matches (the first hook to match wins) are
+//! folded into a new `Aggregator` for that series instead of being rolled
+//! up as usual, and whatever it finishes with is published and stored
+//! alongside the other aggregated metrics. Samples arrive after monotonic
+//! counts were turned into deltas and the cardinality limit was applied,
+//! in the order they were collected.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use super::super::metric::{CollectedMetric, MetricId};
+use super::super::util::Glob;
+use super::aggregate::AggregatedMetric;
+
+/// Rolls up one series' samples for one interval.
+pub trait Aggregator: Send {
+    fn fold(&mut self, sample: &CollectedMetric);
+
+    /// Called once every sample of the interval has been folded.
+    fn finish(&mut self) -> Vec<AggregatedMetric>;
+}
+
+/// Makes an aggregator for the series with an identifier.
+type NewAggregator = dyn Fn(&MetricId) -> Box<dyn Aggregator> + Send + Sync;
+
+/// An `Aggregator` for every series whose name matches `pattern`, made
+/// with `new`.
+#[derive(Clone)]
+pub struct AggregationHook {
+    pattern: Glob,
+    new: Arc<NewAggregator>,
+}
+
+impl AggregationHook {
+    pub fn new<F>(pattern: &str, new: F) -> AggregationHook
+        where F: Fn(&MetricId) -> Box<dyn Aggregator> + Send + Sync + 'static
+    {
+        AggregationHook { pattern: Glob::new(pattern), new: Arc::new(new) }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.pattern.matches(name)
+    }
+}
+
+impl fmt::Debug for AggregationHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AggregationHook").field("pattern", &self.pattern).finish()
+    }
+}
+
+/// Take the samples `hooks` claim out of `metrics`, returning the rest and
+/// what the hooks' aggregators finished with.
+pub fn apply(hooks: &[AggregationHook], metrics: Vec<CollectedMetric>) -> (Vec<CollectedMetric>, Vec<AggregatedMetric>) {
+    if hooks.is_empty() {
+        return (metrics, vec![])
+    }
+
+    let mut rest = Vec::with_capacity(metrics.len());
+    // In the order series were first seen, so that output is stable.
+    let mut aggregators: Vec<Box<dyn Aggregator>> = vec![];
+    let mut series: HashMap<MetricId, usize> = HashMap::new();
+    for metric in metrics {
+        let hook = match hooks.iter().find(|hook| hook.matches(metric.id().name())) {
+            Some(hook) => hook,
+            None => {
+                rest.push(metric);
+                continue
+            },
+        };
+        let index = *series.entry(metric.id().clone()).or_insert_with(|| {
+            aggregators.push((hook.new)(metric.id()));
+            aggregators.len() - 1
+        });
+        aggregators[index].fold(&metric);
+    }
+    let aggregated = aggregators.iter_mut().flat_map(|aggregator| aggregator.finish()).collect();
+    (rest, aggregated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::super::{Db, DbOptions};
+
+    /// Averages gauges, publishing them as a `.mean` gauge.
+    struct Mean {
+        id: MetricId,
+        time: SystemTime,
+        sum: f64,
+        count: usize,
+    }
+
+    impl Aggregator for Mean {
+        fn fold(&mut self, sample: &CollectedMetric) {
+            if let CollectedMetric::Gauge(time, _, value) = *sample {
+                self.time = self.time.max(time);
+                self.sum += value;
+                self.count += 1;
+            }
+        }
+
+        fn finish(&mut self) -> Vec<AggregatedMetric> {
+            let id = MetricId::from(format!("{}.mean", self.id.name()));
+            vec![AggregatedMetric::Gauge(self.time, id, self.sum / self.count as f64)]
+        }
+    }
+
+    #[test]
+    fn it_rolls_up_matching_series_with_their_hook() {
+        let hook = AggregationHook::new("latency.*", |id| {
+            Box::new(Mean { id: id.clone(), time: UNIX_EPOCH, sum: 0.0, count: 0 }) as Box<dyn Aggregator>
+        });
+        let db = Db::new(DbOptions { aggregation_hooks: vec![hook], ..DbOptions::default() });
+        let (_, subscription) = db.subscribe(None);
+        let time = UNIX_EPOCH + Duration::from_secs(10);
+        db.collect(vec![
+            CollectedMetric::Gauge(time, MetricId::from("latency.api"), 10.0),
+            CollectedMetric::Gauge(time, MetricId::from("latency.api"), 40.0),
+            CollectedMetric::Gauge(time, MetricId::from("queue"), 2.0),
+        ]);
+        db.aggregate(None);
+
+        let aggregated = subscription.recv().unwrap();
+        assert!(aggregated.contains(&AggregatedMetric::Gauge(time, MetricId::from("latency.api.mean"), 25.0)));
+        assert!(aggregated.iter().all(|metric| metric.id().name() != "latency.api"));
+        assert!(aggregated.iter().any(|metric| metric.id().name() == "queue"));
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// HyperLogLog cardinality estimator. Uses `2^precision` one-byte registers
+/// regardless of how many distinct values are inserted; the standard error
+/// is about `1.04 / sqrt(2^precision)`.
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// `precision` is clamped to the range 4 through 16.
+    pub fn new(precision: u8) -> HyperLogLog {
+        let precision = precision.clamp(4, 16);
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    pub fn insert<T: Hash + ?Sized>(&mut self, value: &T) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let index = (hash >> (64 - self.precision)) as usize;
+        // Position of the first set bit in what's left of the hash; the
+        // sentinel bit bounds it for hashes whose remaining bits are zero.
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+
+        if rank > self.registers[index] {
+            self.registers[index] = rank
+        }
+    }
+
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        // Linear counting is more accurate for small cardinalities.
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_estimates_cardinality() {
+        for &count in &[0usize, 10, 1000, 100_000] {
+            let mut hll = HyperLogLog::new(14);
+            for i in 0..count {
+                hll.insert(&i);
+                // Duplicates don't change the estimate.
+                hll.insert(&i);
+            }
+            let error = (hll.estimate() - count as f64).abs() / (count.max(1) as f64);
+            assert!(error < 0.03, "estimate {} for {}", hll.estimate(), count);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use string_cache::DefaultAtom as Atom;
+
+use super::super::util::{read_lock, write_lock};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Unit {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    Bytes,
+    Percent,
+    /// Anything else, eg. `requests` or an OTLP/UCUM unit string we don't
+    /// have a variant for.
+    Other(String),
+}
+
+impl Unit {
+    /// Parse the unit strings commonly used by receivers (UCUM as used by
+    /// OTLP as well as Prometheus base-unit suffixes).
+    pub fn parse<S: AsRef<str>>(unit: S) -> Unit {
+        match unit.as_ref() {
+            "ns" | "nanoseconds" => Unit::Nanoseconds,
+            "us" | "microseconds" => Unit::Microseconds,
+            "ms" | "milliseconds" => Unit::Milliseconds,
+            "s" | "seconds" => Unit::Seconds,
+            "By" | "bytes" => Unit::Bytes,
+            "%" | "percent" => Unit::Percent,
+            other => Unit::Other(other.to_owned()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match *self {
+            Unit::Nanoseconds => "ns",
+            Unit::Microseconds => "us",
+            Unit::Milliseconds => "ms",
+            Unit::Seconds => "s",
+            Unit::Bytes => "bytes",
+            Unit::Percent => "percent",
+            Unit::Other(ref other) => other,
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+/// Descriptive information about a metric that doesn't travel with every
+/// sample.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub unit: Option<Unit>,
+    /// Human-readable description, eg. a Prometheus `# HELP` line.
+    pub help: Option<String>,
+}
+
+/// Metadata keyed by metric name. Cheap to clone; clones share the same
+/// underlying registry so that receivers can record metadata which
+/// exporters then read.
+#[derive(Clone, Default)]
+pub struct MetadataRegistry {
+    entries: Arc<RwLock<HashMap<Atom, Metadata>>>,
+}
+
+impl MetadataRegistry {
+    pub fn new() -> MetadataRegistry {
+        MetadataRegistry::default()
+    }
+
+    /// Record metadata for a metric. Fields that are `None` don't overwrite
+    /// what's already known, so receivers that only know the unit (or only
+    /// the help) can each contribute.
+    pub fn describe(&self, name: Atom, metadata: Metadata) {
+        let mut entries = write_lock(&self.entries);
+        let entry = entries.entry(name).or_default();
+        if metadata.unit.is_some() {
+            entry.unit = metadata.unit
+        }
+        if metadata.help.is_some() {
+            entry.help = metadata.help
+        }
+    }
+
+    pub fn get(&self, name: &Atom) -> Option<Metadata> {
+        read_lock(&self.entries).get(name).cloned()
+    }
+}
+
+impl fmt::Debug for MetadataRegistry {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MetadataRegistry")
+            .field("len", &read_lock(&self.entries).len())
+            .finish()
+    }
+}
@@ -1,136 +1,1358 @@
 //! In-memory metrics database used to store and aggregate metrics.
 
 use std::cell::Cell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use super::recv::Collector;
-use super::metric::{CollectedMetric, Id};
+use string_cache::DefaultAtom as Atom;
 
+use super::recv::{Cluster, ClusterOptions, Collector, CountValidator, Enricher, EnrichmentOptions, Mirror, MirrorOptions, NameFilter, NameMapping, NegativeCounts, Pipeline, RelabelRule, Sampler, SamplingRule, ScrubRule};
+use super::metric::{CollectedMetric, Dimension, MetricId};
+
+mod accumulate;
 mod aggregate;
+mod derive;
+mod downsample;
+mod hook;
+mod hyperloglog;
+mod metadata;
+mod queue;
+mod range;
+mod sketch;
+mod snapshot;
+mod stale;
+mod storage;
+mod subscription;
+
+use self::aggregate::{MonotonicTotals, RollupOptions};
+use self::stale::Staleness;
+use self::subscription::{Buffered, Delivery};
+use self::storage::AggregatedMetrics;
+use super::util::{host, lock, read_lock, write_lock, Glob};
 
-use self::aggregate::AggregatedMetric;
+pub use self::downsample::Resolution;
+pub use self::aggregate::{AggregatedMetric, CardinalityOverflow, GaugeAggregation, HistogramMode, HistogramPrecision, SetMode};
+pub use self::derive::DerivedMetric;
+pub use self::hook::{AggregationHook, Aggregator};
+pub use self::sketch::DdSketch;
+pub use self::metadata::{Metadata, MetadataRegistry, Unit};
+pub use self::queue::CollectionQueue;
+pub use self::range::{Fill, Range, Step};
+pub use self::subscription::{Dispatch, Overflow, Subscriber, SubscriptionBuffer, SubscriptionFilter, SubscriptionToken};
+pub use self::storage::{AggregatedKey, MemoryStorage, Storage, Timeseries};
+#[cfg(feature = "sled")]
+pub use self::storage::SledStorage;
 
-type Timeseries = (SystemTime, i32);
+/// Subscribers with a filter only receive the metrics it matches.
+type AggregationSubscriber = (SubscriptionToken, Option<SubscriptionFilter>, Delivery);
 
+#[derive(Default)]
 pub struct DbOptions {
     pub aggregation_interval: Option<Duration>,
+    /// End `sync_aggregate`'s windows on wall-clock multiples of the
+    /// interval (eg. :00, :10, :20 for 10 seconds) rather than an interval
+    /// after whenever the previous one ran.
+    pub align_aggregation: bool,
+    /// How `sync_aggregate`'s windows are labelled; defaults to
+    /// `WindowClock::Wall`.
+    pub window_clock: Option<WindowClock>,
+    /// How unique set members are counted; defaults to `SetMode::Exact`.
+    pub set_mode: Option<SetMode>,
+    /// How gauges are rolled up; defaults to `GaugeAggregation::Max`.
+    pub gauge_aggregation: Option<GaugeAggregation>,
+    /// Per-metric overrides of `gauge_aggregation` keyed by name pattern;
+    /// the first matching pattern wins.
+    pub gauge_aggregation_overrides: Vec<(Glob, GaugeAggregation)>,
+    /// Percentiles (between 0 and 100) reported for histograms; defaults to
+    /// 95 and 99.
+    pub percentiles: Option<Vec<f64>>,
+    /// Per-metric overrides of `percentiles` keyed by name pattern; the
+    /// first matching pattern wins.
+    pub percentile_overrides: Vec<(Glob, Vec<f64>)>,
+    /// Precision of the HdrHistograms used to compute histograms' median
+    /// and percentiles; defaults to 3 significant digits up to 3,600,000.
+    pub histogram_precision: Option<HistogramPrecision>,
+    /// Whether histograms are rolled up into percentile gauges or into
+    /// mergeable sketches; defaults to `HistogramMode::Summary`. Summaries
+    /// can't include negative samples, so those are rejected, and a
+    /// `metriqs.histograms.negative` count of them is published for
+    /// aggregations that had any.
+    pub histogram_mode: Option<HistogramMode>,
+    /// Upper bounds of cumulative buckets reported for histograms as well
+    /// as their percentiles (see `RollupOptions::buckets`), eg. for
+    /// Prometheus histograms. None by default.
+    pub histogram_buckets: Option<Vec<f64>>,
+    /// Fold counts, gauges, and histograms into per-series accumulators as
+    /// they're collected rather than buffering every sample until the next
+    /// aggregation, so memory scales with series instead of ingest rate.
+    /// Folded samples are rolled up by whichever aggregation comes next,
+    /// regardless of its window, and aren't subject to `max_series`.
+    /// Defaults to false.
+    pub streaming: Option<bool>,
+    /// Dimensions (eg. host, environment, service) that collectors add to
+    /// every metric that doesn't already have a dimension with that key.
+    pub default_dimensions: Vec<Dimension>,
+    /// What collectors do with counts collected with negative values,
+    /// before any other stage; defaults to `NegativeCounts::Accept`. When
+    /// counts are clamped or dropped, a `metriqs.counts.clamped` or
+    /// `metriqs.counts.dropped` count of how many were is published every
+    /// aggregation.
+    pub negative_counts: Option<NegativeCounts>,
+    /// Peers to forward the metrics they own to, so that each series is
+    /// aggregated by one agent of a cluster (see `recv::cluster`). Routing
+    /// happens before every other stage collectors apply. A
+    /// `metriqs.cluster.dropped` count of batches that couldn't be
+    /// forwarded is published every aggregation.
+    pub cluster: Option<ClusterOptions>,
+    /// A standby agent sent a copy of every metric collected, before any
+    /// other stage (see `recv::forward`). A `metriqs.mirror.dropped` count
+    /// of batches that couldn't be sent is published every aggregation.
+    pub mirror: Option<MirrorOptions>,
+    /// Run as a mirror's standby: aggregate and store what's collected
+    /// but publish nothing to subscribers, so that exporters stay quiet,
+    /// until `promote` is called.
+    pub standby: bool,
+    /// Look up dimensions from the cloud or Kubernetes that collectors add
+    /// to every metric (see `recv::enrich`). They're looked up by `enrich`
+    /// and refreshed by `sync_enrich`.
+    pub enrichment: Option<EnrichmentOptions>,
+    /// statsd_exporter-style mappings of dotted names onto names and
+    /// dimensions that collectors apply after merging in the default
+    /// dimensions; the first that matches wins.
+    pub mappings: Vec<NameMapping>,
+    /// Relabeling rules collectors apply, in order, after mapping names.
+    pub relabel: Vec<RelabelRule>,
+    /// Rules collectors apply after relabeling to remove or coarsen
+    /// high-cardinality dimensions.
+    pub scrub: Vec<ScrubRule>,
+    /// Which metrics collectors keep by name (after relabeling), eg.
+    /// `["web.*", "!debug.*"]`; the rest are dropped before they're queued
+    /// for aggregation. Everything is kept by default.
+    pub name_filter: Option<NameFilter>,
+    /// Rules collectors apply last to sample or cap firehose metrics; the
+    /// first whose pattern matches a metric's name applies.
+    pub sampling: Vec<SamplingRule>,
+    /// How long aggregated points are stored before `evict` drops them;
+    /// defaults to an hour.
+    pub retention: Option<Duration>,
+    /// Most points stored per series; older points are dropped first.
+    /// Unlimited by default.
+    pub max_points_per_series: Option<usize>,
+    /// Coarser resolutions that older points are compacted into by
+    /// `downsample`, eg. 1 minute after 10 minutes and 10 minutes after an
+    /// hour. Counts are summed and gauges rolled up with their gauge
+    /// aggregation, a `Mean` weighing each point by how many it compacted.
+    pub downsampling: Vec<Resolution>,
+    /// Where `sync_snapshot` periodically writes the stored series.
+    pub snapshot_path: Option<PathBuf>,
+    /// How often `sync_snapshot` writes; defaults to a minute.
+    pub snapshot_interval: Option<Duration>,
+    /// Where aggregated series are stored; defaults to `MemoryStorage`.
+    /// Its errors are logged and counted as `metriqs.storage.errors` rather
+    /// than failing aggregations or reads.
+    pub storage: Option<Box<dyn Storage>>,
+    /// Whether aggregated series are stored at all; defaults to true. Pure
+    /// relays that only publish to subscribers can turn this off, in which
+    /// case `query`, `stats`, and snapshots see no series.
+    pub retain_aggregates: Option<bool>,
+    /// Number of shards collected metrics are partitioned into by the hash
+    /// of their identifier. Each shard is rolled up on its own thread;
+    /// defaults to 1, which rolls up on the aggregating thread.
+    pub shards: Option<usize>,
+    /// Most drained batches kept for collectors to reuse (see
+    /// `Collector::buffer`) rather than allocating new ones. When set,
+    /// `metriqs.pool.*` metrics with `pool=metrics` are collected every
+    /// aggregation that buffers were taken since. No batches are kept by
+    /// default.
+    pub pooled_buffers: Option<usize>,
+    /// Most threads each shard's rollup is spread over once it has enough
+    /// series to be worth it; defaults to 1.
+    pub rollup_threads: Option<usize>,
+    /// Most unique series collected per interval. Metrics of series beyond
+    /// it are handled per `cardinality_overflow`, and a
+    /// `metriqs.series.dropped` count of how many series were over the
+    /// limit is published every interval. Unlimited by default.
+    pub max_series: Option<usize>,
+    /// Defaults to `CardinalityOverflow::Drop`.
+    pub cardinality_overflow: Option<CardinalityOverflow>,
+    /// Report every gauge's current value each aggregation, including
+    /// gauges that weren't collected during the interval, as StatsD does.
+    /// Defaults to false, reporting gauges only for intervals they were
+    /// collected in.
+    pub report_idle_gauges: Option<bool>,
+    /// Aggregations a gauge's current value is kept after it was last
+    /// collected, for deltas to apply to and `report_idle_gauges` to report,
+    /// so that gauges of hosts that went away don't pile up. Defaults to 60;
+    /// a gauge that goes stale first is forgotten then.
+    pub forget_gauges_after: Option<u32>,
+    /// Aggregations a monotonic counter's last total is kept after it was
+    /// last collected, so that those of hosts that went away don't pile up.
+    /// One collected again after it's forgotten starts from a new baseline,
+    /// as after a restart of the agent. Defaults to 60.
+    pub forget_totals_after: Option<u32>,
+    /// Aggregations a count or gauge can go without being aggregated
+    /// before it's stale, eg. after the hosts a deploy replaced went away.
+    /// A stale gauge's current value is forgotten, so `report_idle_gauges`
+    /// stops reporting it. Series are never stale by default.
+    pub stale_after: Option<u32>,
+    /// Publish every series once more as zero when it goes stale, so that
+    /// exporters see it end rather than stop; defaults to false.
+    pub stale_markers: Option<bool>,
+    /// Bounds the aggregations waiting for each channel subscription, ie.
+    /// those made with `subscribe` and `aggregation_subscribe`. When set, a
+    /// `metriqs.subscriptions.dropped` count is published every
+    /// aggregation, rather than only those after a subscriber missed one.
+    /// Unbounded by default.
+    pub subscription_buffer: Option<SubscriptionBuffer>,
+    /// How far before a window's start samples may be timestamped and
+    /// still be rolled up. Late samples are rolled up into corrections for
+    /// the interval-aligned buckets they belong to rather than into the
+    /// current window; older ones are dropped and counted by a
+    /// `metriqs.samples.late` count. When unset, late samples are rolled up
+    /// with the current window.
+    pub lateness: Option<Duration>,
+    /// A dimension that late samples' corrections are published with, set
+    /// to `true`, so that subscribers and exporters can tell them from the
+    /// window's own aggregates, eg. `late`. Corrections are untagged by
+    /// default, so that they're points of the very series they correct.
+    pub correction_dimension: Option<String>,
+    /// Gauges computed from each aggregation and published with it.
+    pub derived: Vec<DerivedMetric>,
+    /// Rollups for the series whose names they match, instead of the
+    /// built-in ones (see `AggregationHook`). Streaming Dbs fold samples
+    /// as they're collected, so hooks never see them.
+    pub aggregation_hooks: Vec<AggregationHook>,
+    /// Name of a count of 1 that `sync_aggregate` collects every
+    /// aggregation, with `host` and `version` dimensions, eg.
+    /// `metriqs.heartbeat`, so that alerting downstream on it going missing
+    /// catches an agent that died or stopped aggregating. It gets the
+    /// default and looked up dimensions but is queued after the pipeline,
+    /// so that the pipeline can't rename, sample, or drop it, and a cluster
+    /// doesn't forward it to the peer that owns it. Unset by default.
+    pub heartbeat: Option<String>,
+}
+
+/// Size of what the Db is holding, as reported by `Db::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DbStats {
+    /// Number of stored series.
+    pub series: usize,
+    /// Number of points across the stored series.
+    pub points: usize,
+    /// Metrics collected but not yet aggregated.
+    pub backlog: usize,
+    /// Series with samples folded into accumulators but not yet aggregated
+    /// when streaming.
+    pub accumulating: usize,
+    pub subscribers: usize,
+    /// Rough estimate of the memory used by the backlog, the accumulators,
+    /// and, for in-memory storage, the stored series; it doesn't account for
+    /// interned names or allocator overhead.
+    pub estimated_bytes: usize,
+    /// Metrics collected since the Db was created.
+    pub collected: usize,
+    /// Aggregations published since the Db was created.
+    pub aggregations: usize,
+}
+
+/// The latest point of every stored series as of one moment, from
+/// `Db::snapshot`. It's taken while aggregations are held off, so it never
+/// mixes series from one aggregation with series from the next.
+#[derive(Clone, Debug)]
+pub struct DbSnapshot {
+    pub taken: SystemTime,
+    /// Sorted by identifier.
+    series: Vec<(AggregatedKey, Timeseries)>,
+    index: HashMap<AggregatedKey, usize>,
+}
+
+impl DbSnapshot {
+    /// Every series and its latest point, sorted by identifier.
+    pub fn series(&self) -> &[(AggregatedKey, Timeseries)] {
+        &self.series
+    }
+
+    pub fn get(&self, key: &AggregatedKey) -> Option<Timeseries> {
+        self.index.get(key).map(|&index| self.series[index].1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.series.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty()
+    }
+}
+
+/// The span of time an aggregation rolls up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Window {
+    pub start: SystemTime,
+    /// Exclusive.
+    pub end: SystemTime,
+}
+
+/// `sync_aggregate` schedules aggregations on the monotonic clock, so that
+/// a stepped system clock doesn't make it sleep too long or wake up early.
+/// This is how it maps back to the wall-clock times its windows are
+/// labelled with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WindowClock {
+    /// The system clock when each window ends. A stepped clock lengthens
+    /// or shortens the window it's stepped in; one stepped back can't make
+    /// windows overlap, it leaves them empty until it catches up.
+    Wall,
+    /// The system clock when the Db was created, plus the monotonic time
+    /// since, so that slewing the system clock doesn't skew windows.
+    /// Samples are still timestamped with the system clock, so once the
+    /// two drift more than `MAX_WINDOW_SKEW` apart (eg. the clock was
+    /// stepped) the labels are re-anchored to the system clock, and that
+    /// window is lengthened or shortened as with `Wall`.
+    Monotonic,
+}
+
+/// How far `WindowClock::Monotonic` labels can drift from the system clock
+/// before they're re-anchored to it.
+const MAX_WINDOW_SKEW: Duration = Duration::from_secs(1);
+
+/// The wall-clock time per a `WindowClock`.
+struct WindowLabels {
+    clock: WindowClock,
+    /// The system and monotonic clocks when `Monotonic` labels were last
+    /// anchored.
+    anchor: Mutex<(SystemTime, Instant)>,
 }
 
-impl Default for DbOptions {
-    fn default() -> DbOptions {
-        DbOptions {
-            aggregation_interval: None,
+impl WindowLabels {
+    fn new(clock: WindowClock) -> WindowLabels {
+        WindowLabels { clock, anchor: Mutex::new((SystemTime::now(), Instant::now())) }
+    }
+
+    fn started(&self) -> SystemTime {
+        lock(&self.anchor).0
+    }
+
+    fn now(&self) -> SystemTime {
+        match self.clock {
+            WindowClock::Wall => SystemTime::now(),
+            WindowClock::Monotonic => self.label(SystemTime::now(), Instant::now()),
         }
     }
+
+    /// The `Monotonic` label for when the system clock read `wall` and the
+    /// monotonic clock `instant`.
+    fn label(&self, wall: SystemTime, instant: Instant) -> SystemTime {
+        let mut anchor = lock(&self.anchor);
+        let label = anchor.0 + instant.saturating_duration_since(anchor.1);
+        let skew = label.duration_since(wall).unwrap_or_else(|err| err.duration());
+        if skew <= MAX_WINDOW_SKEW {
+            return label
+        }
+        warn!("Window labels are {:?} off the system clock; re-anchoring them to it", skew);
+        *anchor = (wall, instant);
+        wall
+    }
 }
 
 pub struct Db {
-    collection_sender: Mutex<Sender<Vec<CollectedMetric>>>,
-    collection_receiver: Mutex<Receiver<Vec<CollectedMetric>>>,
     /// Collected metrics awaiting aggregation.
-    collected_metrics: Mutex<Cell<Vec<CollectedMetric>>>,
+    collected_metrics: Arc<CollectionQueue>,
     aggregation_interval: Duration,
-    aggregation_subscribers: Mutex<Cell<Vec<Sender<Arc<Vec<AggregatedMetric>>>>>>,
-    aggregated_metrics: Option<Mutex<Cell<HashMap<AggregatedKey, Vec<Timeseries>>>>>,
+    align_aggregation: bool,
+    window_labels: WindowLabels,
+    set_mode: SetMode,
+    rollup: RollupOptions,
+    aggregation_subscribers: Mutex<Cell<Vec<AggregationSubscriber>>>,
+    next_subscription: AtomicU64,
+    storage: Option<Mutex<Box<dyn Storage>>>,
+    /// Previous totals of monotonic counters, per shard, so that their
+    /// increases can be computed across intervals.
+    monotonic_totals: Vec<Mutex<MonotonicTotals>>,
+    forget_totals_after: u32,
+    metadata: MetadataRegistry,
+    default_dimensions: Arc<Vec<Dimension>>,
+    pipeline: Arc<RwLock<Arc<Pipeline>>>,
+    /// Counts the pipeline clamped or dropped since the previous
+    /// aggregation, kept across reconfigurations.
+    rejected_counts: Arc<AtomicUsize>,
+    /// Kept across reconfigurations so that forwarders of peers that stay
+    /// are reused.
+    cluster: Mutex<Option<Arc<Cluster>>>,
+    /// Kept across reconfigurations that don't change it, so that what's
+    /// queued for the standby isn't lost.
+    mirror: Mutex<Option<Arc<Mirror>>>,
+    standby: AtomicBool,
+    enricher: Option<Enricher>,
+    retention: Duration,
+    max_points_per_series: Option<usize>,
+    downsampling: Vec<Resolution>,
+    /// How many points each stored point compacted by `downsample` stands
+    /// for. Lost on restart, after which they count as one point each.
+    downsampled: Mutex<HashMap<AggregatedKey, downsample::Weights>>,
+    snapshot_path: Option<PathBuf>,
+    snapshot_interval: Duration,
+    max_series: Option<usize>,
+    cardinality_overflow: CardinalityOverflow,
+    report_idle_gauges: bool,
+    /// Per shard, when `stale_after` is set.
+    staleness: Option<Vec<Mutex<Staleness>>>,
+    stale_markers: bool,
+    pooled_buffers: bool,
+    subscription_buffer: Option<SubscriptionBuffer>,
+    /// Aggregations that bounded subscriptions dropped or coalesced since
+    /// the previous aggregation.
+    dropped_batches: AtomicUsize,
+    /// Storage errors since the previous aggregation.
+    storage_errors: AtomicUsize,
+    lateness: Option<Duration>,
+    correction_dimension: Option<Atom>,
+    derived: RwLock<Vec<DerivedMetric>>,
+    aggregation_hooks: Vec<AggregationHook>,
+    heartbeat: Option<MetricId>,
+    /// Number of series admitted so far this interval.
+    admitted_series: AtomicUsize,
+    /// End of the last window `sync_aggregate` rolled up, initially when
+    /// the Db was created. Held while aggregating so that `shutdown`'s
+    /// final flush doesn't overlap an in-flight one.
+    aggregated_until: Mutex<SystemTime>,
+    /// When `aggregate` last published, initially when the Db was created.
+    last_aggregation: Mutex<SystemTime>,
+    aggregations: AtomicUsize,
+    stopped: Mutex<bool>,
+    /// Wakes the `sync_*` loops when `stopped` is set.
+    stop_signal: Condvar,
 }
 
 impl Db {
-    pub fn new(options: DbOptions) -> Db {
+    pub fn new(mut options: DbOptions) -> Db {
+        let rejected_counts = Arc::new(AtomicUsize::new(0));
+        let cluster = options.cluster.take().map(|options| Arc::new(Cluster::new(options)));
+        let mirror = options.mirror.take().map(|options| Arc::new(Mirror::new(options)));
+        let pipeline = pipeline(&mut options, &rejected_counts, cluster.clone(), mirror.clone());
+        let default_dimensions = Arc::new(mem::take(&mut options.default_dimensions));
+        let enricher = options.enrichment.take().map(|enrichment| Enricher::new(enrichment, default_dimensions.clone()));
         let aggregation_interval = options.aggregation_interval.unwrap_or_else(|| Duration::new(10, 0));
 
-        let (send, recv) = channel();
+        let mut buckets = options.histogram_buckets.take().unwrap_or_default();
+        buckets.retain(|bound| bound.is_finite());
+        buckets.sort_by(f64::total_cmp);
+        buckets.dedup();
+        let rollup = RollupOptions {
+            gauge: options.gauge_aggregation.unwrap_or(GaugeAggregation::Max),
+            gauge_overrides: options.gauge_aggregation_overrides,
+            percentiles: options.percentiles.unwrap_or_else(|| vec![95.0, 99.0]),
+            percentile_overrides: options.percentile_overrides,
+            histogram_precision: options.histogram_precision.unwrap_or_default(),
+            histogram_mode: options.histogram_mode.unwrap_or(HistogramMode::Summary),
+            buckets,
+            threads: options.rollup_threads.unwrap_or(1),
+            negative_samples: Arc::default(),
+            saturated_counts: Arc::default(),
+        };
+        let shards = options.shards.unwrap_or(1);
+        let collected_metrics = if options.streaming.unwrap_or(false) {
+            CollectionQueue::streaming(shards, rollup.clone())
+        } else {
+            CollectionQueue::new(shards)
+        };
+        let collected_metrics = collected_metrics
+            .pooled(options.pooled_buffers.unwrap_or(0))
+            .forgetting_gauges_after(options.forget_gauges_after.unwrap_or(60));
+        let collected_metrics = Arc::new(collected_metrics);
+        let window_labels = WindowLabels::new(options.window_clock.unwrap_or(WindowClock::Wall));
+        let started = window_labels.started();
 
         Db {
-            collection_sender: Mutex::new(send),
-            collection_receiver: Mutex::new(recv),
-            collected_metrics: Mutex::new(Cell::new(vec![])),
+            monotonic_totals: (0..collected_metrics.shards()).map(|_| Mutex::new(MonotonicTotals::new())).collect(),
+            forget_totals_after: options.forget_totals_after.unwrap_or(60),
+            collected_metrics,
             aggregation_interval,
+            align_aggregation: options.align_aggregation,
+            window_labels,
+            set_mode: options.set_mode.unwrap_or(SetMode::Exact),
+            rollup,
             aggregation_subscribers: Mutex::new(Cell::new(vec![])),
-            aggregated_metrics: Some(Mutex::new(Cell::new(HashMap::new()))),
+            next_subscription: AtomicU64::new(0),
+            storage: if options.retain_aggregates.unwrap_or(true) {
+                Some(Mutex::new(options.storage.unwrap_or_else(|| Box::new(MemoryStorage::new()))))
+            } else {
+                None
+            },
+            metadata: MetadataRegistry::new(),
+            default_dimensions,
+            pipeline: Arc::new(RwLock::new(Arc::new(pipeline))),
+            rejected_counts,
+            cluster: Mutex::new(cluster),
+            mirror: Mutex::new(mirror),
+            standby: AtomicBool::new(options.standby),
+            enricher,
+            retention: options.retention.unwrap_or_else(|| Duration::from_secs(60 * 60)),
+            max_points_per_series: options.max_points_per_series,
+            downsampling: {
+                let mut downsampling = options.downsampling;
+                downsampling.sort_by_key(|resolution| resolution.after);
+                downsampling
+            },
+            downsampled: Mutex::new(HashMap::new()),
+            snapshot_path: options.snapshot_path,
+            snapshot_interval: options.snapshot_interval.unwrap_or_else(|| Duration::from_secs(60)),
+            max_series: options.max_series,
+            cardinality_overflow: options.cardinality_overflow.unwrap_or(CardinalityOverflow::Drop),
+            report_idle_gauges: options.report_idle_gauges.unwrap_or(false),
+            staleness: options.stale_after.map(|after| (0..shards.max(1)).map(|_| Mutex::new(Staleness::new(after))).collect()),
+            stale_markers: options.stale_markers.unwrap_or(false),
+            pooled_buffers: options.pooled_buffers.is_some_and(|max| max > 0),
+            subscription_buffer: options.subscription_buffer,
+            dropped_batches: AtomicUsize::new(0),
+            storage_errors: AtomicUsize::new(0),
+            admitted_series: AtomicUsize::new(0),
+            lateness: options.lateness,
+            correction_dimension: options.correction_dimension.map(Atom::from),
+            derived: RwLock::new(options.derived),
+            aggregation_hooks: options.aggregation_hooks,
+            heartbeat: options.heartbeat.map(|name| {
+                let id = match host::hostname() {
+                    Some(host) => MetricId::from(name).with_dimension("host", host.as_str()),
+                    None => MetricId::from(name),
+                };
+                id.with_dimension("version", env!("CARGO_PKG_VERSION"))
+            }),
+            aggregated_until: Mutex::new(started),
+            last_aggregation: Mutex::new(SystemTime::now()),
+            aggregations: AtomicUsize::new(0),
+            stopped: Mutex::new(false),
+            stop_signal: Condvar::new(),
         }
     }
 
     pub fn collector(&self) -> Collector {
-        let sender = {
-            self.collection_sender.lock().unwrap().clone()
+        let collector = Collector::new(self.collected_metrics.clone(), self.metadata.clone(), self.default_dimensions.clone(), self.pipeline.clone());
+        match self.enricher {
+            Some(ref enricher) => collector.enriched(enricher.enrichment().clone()),
+            None => collector,
+        }
+    }
+
+    /// Replace the stages collectors apply (`mirror`, `cluster`,
+    /// `negative_counts`, `mappings`, `relabel`, `scrub`, `name_filter`, and
+    /// `sampling`) and the `derived` metrics with those of `options`, eg. when a
+    /// configuration is reloaded. Collectors already handed out apply the
+    /// new stages to their next push, samplers start over, and the cluster
+    /// is rebalanced onto its new members. Every other option only applies
+    /// to a new Db and is ignored.
+    pub fn reconfigure(&self, mut options: DbOptions) {
+        let cluster = {
+            let mut cluster = lock(&self.cluster);
+            *cluster = match (cluster.take(), options.cluster.take()) {
+                (Some(cluster), Some(options)) => {
+                    cluster.rebalance(options);
+                    Some(cluster)
+                },
+                (None, Some(options)) => Some(Arc::new(Cluster::new(options))),
+                (_, None) => None,
+            };
+            cluster.clone()
         };
-        Collector::new(sender)
+        let mirror = {
+            let mut mirror = lock(&self.mirror);
+            let unchanged = match (&*mirror, &options.mirror) {
+                (Some(existing), Some(options)) => existing.options() == options,
+                (None, None) => true,
+                _ => false,
+            };
+            if !unchanged {
+                *mirror = options.mirror.take().map(|options| Arc::new(Mirror::new(options)));
+            }
+            mirror.clone()
+        };
+        *write_lock(&self.pipeline) = Arc::new(pipeline(&mut options, &self.rejected_counts, cluster, mirror));
+        *write_lock(&self.derived) = options.derived;
     }
 
-    /// Blocking loop to receive metrics from `Collector`s. This acquires a
-    /// permanent lock on `self.collection_receiver`.
-    pub fn sync_recv(&self) {
-        let receiver = self.collection_receiver.lock().unwrap();
-        for metrics in receiver.iter() {
-            self.collect(metrics)
-        }
+    /// Units and descriptions recorded by receivers, keyed by metric name.
+    pub fn metadata(&self) -> &MetadataRegistry {
+        &self.metadata
     }
 
-    /// Blocking loop to aggregate collected metrics.
+    /// Blocking loop to aggregate collected metrics until `shutdown`. Each
+    /// aggregation's window starts where the previous one ended; the first
+    /// starts when the Db was created. With `align_aggregation` every
+    /// window ends on a wall-clock multiple of the interval, so the first
+    /// may be short. Aggregations are scheduled on the monotonic clock and
+    /// labelled per `window_clock`; windows never end before they start.
     pub fn sync_aggregate(&self) {
+        let mut deadline = Instant::now();
         loop {
-            self.aggregate();
+            let end = if self.align_aggregation {
+                let now = self.window_labels.now();
+                let end = next_boundary(now, self.aggregation_interval);
+                if !self.sleep_until(Instant::now() + end.duration_since(now).unwrap_or_default()) {
+                    return
+                }
+                end
+            } else {
+                self.window_labels.now()
+            };
+            {
+                let mut start = self.aggregated_until();
+                let end = end.max(*start);
+                if let Some(ref heartbeat) = self.heartbeat {
+                    let defaults = match self.enricher {
+                        Some(ref enricher) => enricher.enrichment().dimensions(),
+                        None => self.default_dimensions.clone(),
+                    };
+                    self.collect(vec![CollectedMetric::Count(*start, heartbeat.with_defaults(&defaults), 1)]);
+                }
+                self.aggregate(Some(Window { start: *start, end }));
+                *start = end;
+            }
 
-            thread::sleep(self.aggregation_interval);
+            if !self.align_aggregation {
+                // Keep to the schedule, unless aggregating took so long
+                // that there's no catching up.
+                deadline = (deadline + self.aggregation_interval).max(Instant::now());
+                if !self.sleep_until(deadline) {
+                    return
+                }
+            }
         }
     }
 
+    /// An aggregation that panicked leaves the lock poisoned, but the end
+    /// of the previous window is still where the next one starts, so that
+    /// a restarted `sync_aggregate` picks up where it left off.
+    fn aggregated_until(&self) -> MutexGuard<'_, SystemTime> {
+        lock(&self.aggregated_until)
+    }
+
+    /// Sleep for `duration` or until `shutdown`, returning false in the
+    /// latter case.
+    fn sleep(&self, duration: Duration) -> bool {
+        let stopped = lock(&self.stopped);
+        let (stopped, _) = self.stop_signal.wait_timeout_while(stopped, duration, |stopped| !*stopped).unwrap();
+        !*stopped
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> bool {
+        self.sleep(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Stop the `sync_*` loops and run one last aggregation of everything
+    /// collected since the previous one, so that stopping the agent doesn't
+    /// lose up to an interval of metrics. Subscribers receive it like any
+    /// other aggregation. Waits for an in-flight aggregation to finish
+    /// first; the loops return once they wake up.
+    pub fn shutdown(&self) {
+        {
+            let mut stopped = lock(&self.stopped);
+            if *stopped {
+                return
+            }
+            *stopped = true;
+        }
+        self.stop_signal.notify_all();
+        self.flush();
+    }
+
+    /// Aggregate everything collected since the previous aggregation right
+    /// away and publish it to subscribers, regardless of the interval. The
+    /// next window of `sync_aggregate` starts where this one ended.
+    pub fn flush(&self) {
+        let mut start = self.aggregated_until();
+        let end = self.window_labels.now().max(*start);
+        self.aggregate(Some(Window { start: *start, end }));
+        *start = end;
+    }
+
+    /// Take over from the primary as a standby (see `DbOptions::standby`):
+    /// aggregations from now on are published to subscribers.
+    pub fn promote(&self) {
+        if self.standby.swap(false, Ordering::Relaxed) {
+            info!("Promoted from standby; publishing aggregations")
+        }
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::Relaxed)
+    }
+
+    /// Series, point, backlog, accumulator, and subscriber counts, and how
+    /// much has been collected and aggregated. Series and points are zero if
+    /// the storage fails to count them.
+    pub fn stats(&self) -> DbStats {
+        let (series, points) = match self.storage {
+            Some(ref mutex) => lock(mutex).counts().unwrap_or_else(|err| {
+                self.storage_failed("counting", &err);
+                (0, 0)
+            }),
+            None => (0, 0),
+        };
+        let backlog = self.collected_metrics.len();
+        let accumulating = self.collected_metrics.accumulating();
+        let subscribers = {
+            let mut cell = lock(&self.aggregation_subscribers);
+            cell.get_mut().len()
+        };
+        let estimated_bytes =
+            series * (mem::size_of::<AggregatedKey>() + mem::size_of::<Vec<Timeseries>>()) +
+            points * mem::size_of::<Timeseries>() +
+            backlog * mem::size_of::<CollectedMetric>() +
+            self.collected_metrics.accumulated_bytes();
+
+        let collected = self.collected_metrics.pushed();
+        let aggregations = self.aggregations.load(Ordering::Relaxed);
+
+        DbStats { series, points, backlog, accumulating, subscribers, estimated_bytes, collected, aggregations }
+    }
+
+    pub fn aggregation_interval(&self) -> Duration {
+        self.aggregation_interval
+    }
+
+    /// When an aggregation was last published, eg. to tell whether
+    /// `sync_aggregate` is still running.
+    pub fn last_aggregation(&self) -> SystemTime {
+        *lock(&self.last_aggregation)
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        *lock(&self.stopped)
+    }
+
     pub fn collect(&self, metrics: Vec<CollectedMetric>) {
-        let mut cell = self.collected_metrics.lock().unwrap();
-        cell.get_mut().extend(metrics);
+        self.collected_metrics.push(metrics)
     }
 
-    // TODO: Add a window option to the call.
-    pub fn aggregate(&self) {
-        // Get all the collected metrics; replaces it with an empty `Vec`
-        // before releasing the lock so that other threads can continue
-        // adding metrics.
+    /// Roll up collected metrics and publish the results to subscribers.
+    ///
+    /// With a `window`, metrics timestamped at or after its end are
+    /// retained for a later aggregation; this matters when a backlog of
+    /// collected metrics spans several intervals. Metrics timestamped
+    /// before its start arrived late and are rolled up with this window,
+    /// or handled per `lateness` if it's configured. Without one,
+    /// everything collected so far is rolled up.
+    ///
+    /// Counts' `.rate` gauges are per second of the window, or of the
+    /// aggregation interval when there's no window.
+    pub fn aggregate(&self, window: Option<Window>) {
+        let elapsed = match window {
+            Some(window) => window.end.duration_since(window.start).unwrap_or_default(),
+            None => self.aggregation_interval,
+        };
+
+        if self.pooled_buffers {
+            let time = window.map(|window| window.start).unwrap_or_else(SystemTime::now);
+            let metrics = self.collected_metrics.buffers().metrics("metrics", time);
+            if !metrics.is_empty() {
+                self.collect(metrics)
+            }
+        }
+
+        // Series never span shards, but a histogram's suffixed series can
+        // collide with one sent explicitly that's on another shard, so
+        // duplicates are merged once every shard has been rolled up.
+        self.admitted_series.store(0, Ordering::Relaxed);
+        let shards = self.collected_metrics.shards();
+        let results = if shards == 1 {
+            vec![self.aggregate_shard(0, window, elapsed)]
+        } else {
+            // Scoped so that the shards can borrow the Db, with the first
+            // rolled up on this thread rather than on one more of them.
+            thread::scope(|scope| {
+                let handles = (1..shards)
+                    .map(|shard| scope.spawn(move || self.aggregate_shard(shard, window, elapsed)))
+                    .collect::<Vec<_>>();
+                let first = self.aggregate_shard(0, window, elapsed);
+                Some(first).into_iter()
+                    .chain(handles.into_iter().map(|handle| handle.join().unwrap()))
+                    .collect::<Vec<ShardRollup>>()
+            })
+        };
+
+        let mut aggregated = vec![];
+        let mut late: HashMap<SystemTime, Vec<AggregatedMetric>> = HashMap::new();
+        let (mut over_limit, mut too_late) = (0, 0);
+        for result in results {
+            aggregated.extend(result.aggregated);
+            for (bucket, metrics) in result.late {
+                late.entry(bucket).or_default().extend(metrics)
+            }
+            over_limit += result.over_limit;
+            too_late += result.too_late;
+        }
+        let mut aggregated = aggregate::merge_duplicates(aggregated, &self.rollup);
+        for metrics in late.into_values() {
+            let mut corrections = aggregate::merge_duplicates(metrics, &self.rollup);
+            if let Some(ref key) = self.correction_dimension {
+                for correction in &mut corrections {
+                    let id = correction.id().with_dimension(key.clone(), "true");
+                    *correction.id_mut() = id;
+                }
+            }
+            aggregated.extend(corrections);
+        }
+        let time = window.map(|window| window.end).unwrap_or_else(SystemTime::now);
+        if self.max_series.is_some() {
+            aggregated.push(AggregatedMetric::Count(time, MetricId::from("metriqs.series.dropped"), over_limit as i64));
+        }
+        if self.lateness.is_some() && window.is_some() {
+            aggregated.push(AggregatedMetric::Count(time, MetricId::from("metriqs.samples.late"), too_late as i64));
+        }
+        // Sums beyond i64 saturate rather than wrap; say so, since the
+        // reported count is then short.
+        let saturated = self.rollup.saturated_counts.swap(0, Ordering::Relaxed);
+        if saturated > 0 {
+            aggregated.push(AggregatedMetric::Count(time, MetricId::from("metriqs.counts.saturated"), saturated as i64));
+        }
+        let pipeline = read_lock(&self.pipeline).clone();
+        pipeline.sampler.prune(time);
+        let counts = pipeline.counts.clone();
+        let rejected = counts.take_rejected();
+        match counts.policy() {
+            NegativeCounts::Accept => {},
+            NegativeCounts::Clamp => aggregated.push(AggregatedMetric::Count(time, MetricId::from("metriqs.counts.clamped"), rejected as i64)),
+            NegativeCounts::Drop => aggregated.push(AggregatedMetric::Count(time, MetricId::from("metriqs.counts.dropped"), rejected as i64)),
+        }
+        if let Some(ref cluster) = *lock(&self.cluster) {
+            aggregated.push(AggregatedMetric::Count(time, MetricId::from("metriqs.cluster.dropped"), cluster.take_dropped() as i64));
+        }
+        if let Some(ref mirror) = *lock(&self.mirror) {
+            aggregated.push(AggregatedMetric::Count(time, MetricId::from("metriqs.mirror.dropped"), mirror.take_dropped() as i64));
+        }
+        let negative_samples = self.rollup.negative_samples.swap(0, Ordering::Relaxed);
+        if negative_samples > 0 {
+            aggregated.push(AggregatedMetric::Count(time, MetricId::from("metriqs.histograms.negative"), negative_samples as i64));
+        }
+        let dropped_batches = self.dropped_batches.swap(0, Ordering::Relaxed);
+        if self.subscription_buffer.is_some() || dropped_batches > 0 {
+            aggregated.push(AggregatedMetric::Count(time, MetricId::from("metriqs.subscriptions.dropped"), dropped_batches as i64));
+        }
+        // Errors storing this aggregation are published with the next.
+        let storage_errors = self.storage_errors.swap(0, Ordering::Relaxed);
+        if storage_errors > 0 {
+            aggregated.push(AggregatedMetric::Count(time, MetricId::from("metriqs.storage.errors"), storage_errors as i64));
+        }
+        let derived = derive::derive(&read_lock(&self.derived), &aggregated, time);
+        aggregated.extend(derived);
+
+        if let Some(ref mutex) = self.storage {
+            let mut storage = lock(mutex);
+            for metric in &aggregated {
+                for (key, timeseries) in metric.entries() {
+                    // A failing store shouldn't keep metrics from reaching
+                    // subscribers.
+                    if let Err(err) = storage.push(key, timeseries, self.max_points_per_series) {
+                        self.storage_failed("pushing to", &err)
+                    }
+                }
+            }
+        }
+
+        if self.is_standby() {
+            *lock(&self.last_aggregation) = SystemTime::now();
+            self.aggregations.fetch_add(1, Ordering::Relaxed);
+            return
+        }
+
+        let mut cell = lock(&self.aggregation_subscribers);
+        let subscribers = cell.get_mut();
+        let ptr = Arc::new(aggregated);
+        // A failed send means the receiver was dropped, so stop publishing
+        // to it. Inline subscribers are called once the lock is released so
+        // that they can subscribe and unsubscribe.
+        let mut inline = vec![];
+        subscribers.retain(|(_, filter, delivery)| {
+            let metrics = match *filter {
+                Some(ref filter) => {
+                    Arc::new(ptr.iter().filter(|metric| filter.matches(metric.id())).cloned().collect())
+                },
+                None => ptr.clone(),
+            };
+            match *delivery {
+                Delivery::Channel(ref send) => send.send(metrics).is_ok(),
+                Delivery::Buffered(ref buffered) => buffered.publish(metrics, &self.dropped_batches),
+                Delivery::Thread(ref send) => subscription::try_dispatch(send, metrics, &self.dropped_batches),
+                Delivery::Inline(ref subscriber) => {
+                    inline.push((subscriber.clone(), metrics));
+                    true
+                },
+            }
+        });
+        drop(cell);
+        for (subscriber, metrics) in inline {
+            subscriber.on_flush(&metrics)
+        }
+        *lock(&self.last_aggregation) = SystemTime::now();
+        self.aggregations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Roll up everything collected on one shard.
+    fn aggregate_shard(&self, shard: usize, window: Option<Window>, elapsed: Duration) -> ShardRollup {
+        // Drain the batches collected so far. Collectors keep pushing
+        // concurrently; anything pushed after this drain is rolled up by
+        // the next aggregation.
+        let (collected_metrics, idle_gauges) = self.collected_metrics.drain(shard);
+
+        let collected_metrics = match window {
+            Some(window) => {
+                let (inside, retained): (Vec<CollectedMetric>, Vec<CollectedMetric>) = collected_metrics.into_iter()
+                    .partition(|metric| metric.time() < window.end);
+                if !retained.is_empty() {
+                    self.collect(retained)
+                }
+                inside
+            },
+            None => collected_metrics,
+        };
+
+        // Turn cumulative counters into the deltas they represent.
         let collected_metrics = {
-            let cell = self.collected_metrics.lock().unwrap();
-            cell.replace(Vec::new())
+            let mut totals = lock(&self.monotonic_totals[shard]);
+            aggregate::increases(collected_metrics, &mut totals, self.forget_totals_after)
         };
 
+        let (collected_metrics, over_limit) = match self.max_series {
+            Some(max) => aggregate::limit_cardinality(collected_metrics, &self.admitted_series, max, self.cardinality_overflow),
+            None => (collected_metrics, 0),
+        };
+
+        // Split off late samples so they don't pollute this window.
+        let (collected_metrics, late) = match (window, self.lateness) {
+            (Some(window), Some(_)) => collected_metrics.into_iter()
+                .partition(|metric| metric.time() >= window.start),
+            _ => (collected_metrics, vec![]),
+        };
+
+        let (collected_metrics, hooked) = hook::apply(&self.aggregation_hooks, collected_metrics);
+        let mut aggregated = self.rollup(&collected_metrics, elapsed);
+        aggregated.extend(hooked);
+        aggregated.extend(self.collected_metrics.flush_accumulators(shard, elapsed));
+        let time = window.map(|window| window.end).unwrap_or_else(SystemTime::now);
+        let (mut rolled_up_late, mut too_late) = (HashMap::new(), 0);
+        if let (Some(window), Some(lateness)) = (window, self.lateness) {
+            let cutoff = window.start.checked_sub(lateness).unwrap_or(UNIX_EPOCH);
+            let mut buckets: HashMap<SystemTime, Vec<CollectedMetric>> = HashMap::new();
+            for metric in late {
+                if metric.time() < cutoff {
+                    too_late += 1;
+                    continue
+                }
+                let bucket = previous_boundary(metric.time(), self.aggregation_interval);
+                buckets.entry(bucket).or_default().push(metric)
+            }
+            for (bucket, metrics) in buckets {
+                let (metrics, hooked) = hook::apply(&self.aggregation_hooks, metrics);
+                let mut rolled_up = self.rollup(&metrics, self.aggregation_interval);
+                rolled_up.extend(hooked);
+                rolled_up_late.insert(bucket, rolled_up);
+            }
+        }
+
+        // Late corrections count as seeing their series too, so that one
+        // only sampled late isn't marked stale.
+        let stale = match self.staleness {
+            Some(ref staleness) => lock(&staleness[shard]).observe(aggregated.iter().chain(rolled_up_late.values().flatten())),
+            None => vec![],
+        };
+        let stale_gauges = stale.iter()
+            .filter_map(|key| match *key {
+                AggregatedKey::Gauge(ref id) => Some(id.clone()),
+                AggregatedKey::Count(_) => None,
+            })
+            .collect::<HashSet<MetricId>>();
+        if !stale_gauges.is_empty() {
+            self.collected_metrics.forget_gauges(shard, &stale_gauges);
+        }
+        if self.report_idle_gauges {
+            aggregated.extend(idle_gauges.into_iter()
+                .filter(|(id, _)| !stale_gauges.contains(id))
+                .map(|(id, value)| AggregatedMetric::Gauge(time, id, value)));
+        }
+        if self.stale_markers {
+            aggregated.extend(stale.into_iter().map(|key| match key {
+                AggregatedKey::Count(id) => AggregatedMetric::Count(time, id, 0),
+                AggregatedKey::Gauge(id) => AggregatedMetric::Gauge(time, id, 0.0),
+            }));
+        }
+
+        ShardRollup { aggregated, late: rolled_up_late, over_limit, too_late }
+    }
+
+    /// Group and roll up metrics that all belong to the same window.
+    fn rollup(&self, collected_metrics: &Vec<CollectedMetric>, elapsed: Duration) -> Vec<AggregatedMetric> {
         // Convert raw metrics into groups keyed by the identifier and
         // with raw timeseries as the values.
         let grouped = aggregate::group(collected_metrics);
+        let grouped_sets = aggregate::group_sets(collected_metrics, self.set_mode);
 
         // Roll up each metric.
-        let aggregated = aggregate::aggregate(grouped);
+        let mut aggregated = aggregate::aggregate(grouped, elapsed, &self.rollup);
+        aggregated.extend(aggregate::aggregate_counts(collected_metrics, elapsed, &self.rollup));
+        aggregated.extend(aggregate::aggregate_sets(grouped_sets));
+        aggregated.extend(aggregate::merge_summaries(collected_metrics));
+        aggregated
+    }
 
-        if let Some(ref mutex) = self.aggregated_metrics {
-            let mut cell = mutex.lock().unwrap();
-            let aggregated_metrics = cell.get_mut();
-            for metric in &aggregated {
-                let (key, timeseries) = metric.into();
-                let values = aggregated_metrics.entry(key).or_insert_with(|| vec![]);
-                values.push(timeseries)
+    /// Blocking loop that downsamples and then evicts expired points every
+    /// `interval`. Failures are retried on the next interval.
+    pub fn sync_evict(&self, interval: Duration) {
+        while self.sleep(interval) {
+            let now = SystemTime::now();
+            let _ = self.downsample(now);
+            let _ = self.evict(now);
+        }
+    }
+
+    /// Look up the dimensions of `enrichment` now, blocking until each
+    /// source has answered or timed out, eg. before starting listeners.
+    pub fn enrich(&self) {
+        if let Some(ref enricher) = self.enricher {
+            enricher.refresh()
+        }
+    }
+
+    /// Look up the dimensions of `enrichment` again every refresh interval
+    /// in a loop, blocking the calling thread until `shutdown`.
+    pub fn sync_enrich(&self) {
+        let enricher = match self.enricher {
+            Some(ref enricher) => enricher,
+            None => return,
+        };
+        while self.sleep(enricher.refresh_interval()) {
+            enricher.refresh()
+        }
+    }
+
+    /// Rewrite every stored series with `update`.
+    fn update_series<F>(&self, mut update: F) -> io::Result<()>
+        where F: FnMut(&AggregatedKey, &mut Vec<Timeseries>)
+    {
+        if let Some(ref mutex) = self.storage {
+            let mut storage = lock(mutex);
+            for key in storage.keys()? {
+                let mut points = match storage.get(&key)? {
+                    Some(points) => points,
+                    None => continue,
+                };
+                update(&key, &mut points);
+                storage.set(key, points)?;
             }
         }
+        Ok(())
+    }
 
-        let mut cell = self.aggregation_subscribers.lock().unwrap();
-        let subscribers = cell.get_mut();
-        let ptr = Arc::new(aggregated);
-        for subscriber in subscribers {
-            let _ = subscriber.send(ptr.clone());
+    /// Compact stored points into the configured coarser resolutions as of
+    /// `now`. Resolutions are applied finest first so each one compacts the
+    /// output of the previous.
+    pub fn downsample(&self, now: SystemTime) -> io::Result<()> {
+        if self.downsampling.is_empty() {
+            return Ok(())
         }
+        let mut downsampled = lock(&self.downsampled);
+        let result = self.update_series(|key, values| {
+            let weights = downsampled.entry(key.clone()).or_default();
+            for resolution in &self.downsampling {
+                let cutoff = match now.checked_sub(resolution.after) {
+                    Some(cutoff) => cutoff,
+                    None => continue,
+                };
+                match *key {
+                    AggregatedKey::Count(_) => {
+                        downsample::downsample(values, weights, cutoff, resolution.interval, |points, _| {
+                            points.iter().map(|t| t.1).sum()
+                        })
+                    },
+                    AggregatedKey::Gauge(ref id) => {
+                        let aggregation = self.rollup.gauge_aggregation(id);
+                        downsample::downsample(values, weights, cutoff, resolution.interval, |points, counts| {
+                            // Rather than a mean of means.
+                            if aggregation == GaugeAggregation::Mean {
+                                let sum = points.iter().zip(counts).map(|(t, &count)| t.1 * count as f64).sum::<f64>();
+                                return sum / counts.iter().sum::<u64>() as f64
+                            }
+                            aggregation.apply(points.iter().cloned())
+                        })
+                    },
+                }
+            }
+        });
+        downsampled.retain(|_, weights| !weights.is_empty());
+        result
+    }
+
+    /// Drop stored points older than the retention as of `now`, then any
+    /// series left without points.
+    pub fn evict(&self, now: SystemTime) -> io::Result<()> {
+        let cutoff = match now.checked_sub(self.retention) {
+            Some(cutoff) => cutoff,
+            None => return Ok(()),
+        };
+        lock(&self.downsampled).retain(|_, weights| {
+            weights.retain(|&time, _| time >= cutoff);
+            !weights.is_empty()
+        });
+        self.update_series(|_, values| values.retain(|&(time, _)| time >= cutoff))
+    }
+
+    /// Drop every stored series, the previous totals of monotonic
+    /// counters, and gauges' current values, as if nothing had been
+    /// aggregated yet. What's collected but not yet aggregated is kept.
+    pub fn reset(&self) -> io::Result<()> {
+        for totals in &self.monotonic_totals {
+            lock(totals).clear();
+        }
+        self.collected_metrics.clear_gauges();
+        lock(&self.downsampled).clear();
+        self.update_series(|_, values| values.clear())
+    }
+
+    /// Write the stored series to `path`. The snapshot is written to a
+    /// temporary file first and then renamed so that a crash mid-write
+    /// doesn't clobber the previous snapshot.
+    pub fn snapshot_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let mutex = match self.storage {
+            Some(ref mutex) => mutex,
+            None => return Ok(()),
+        };
+
+        let mut series = AggregatedMetrics::new();
+        {
+            let storage = lock(mutex);
+            for key in storage.keys()? {
+                if let Some(points) = storage.get(&key)? {
+                    series.insert(key, points);
+                }
+            }
+        }
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        snapshot::write(&series, BufWriter::new(File::create(&temporary)?))?;
+        fs::rename(&temporary, path)
+    }
+
+    /// Load series from a snapshot written by `snapshot_to`. Restored
+    /// points are added in front of any already stored for the same
+    /// series.
+    pub fn restore_from<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let restored = snapshot::read(BufReader::new(File::open(path)?))?;
+        if let Some(ref mutex) = self.storage {
+            let mut storage = lock(mutex);
+            for (key, mut values) in restored {
+                if let Some(existing) = storage.get(&key)? {
+                    values.extend(existing);
+                }
+                storage.set(key, values)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Blocking loop that writes a snapshot to `snapshot_path` every
+    /// `snapshot_interval`. Returns immediately if there's no path. Failed
+    /// snapshots are retried on the next interval.
+    pub fn sync_snapshot(&self) {
+        let path = match self.snapshot_path {
+            Some(ref path) => path,
+            None => return,
+        };
+        while self.sleep(self.snapshot_interval) {
+            let _ = self.snapshot_to(path);
+        }
+    }
+
+    /// Count a storage error, logging the first of each aggregation
+    /// interval so that a failing store doesn't flood the log.
+    fn storage_failed(&self, doing: &str, err: &io::Error) {
+        if self.storage_errors.fetch_add(1, Ordering::Relaxed) == 0 {
+            warn!("Error {} storage: {}", doing, err);
+        }
+    }
+
+    fn stored<T>(&self, result: io::Result<T>, doing: &str) -> Option<T> {
+        result.map_err(|err| self.storage_failed(doing, &err)).ok()
+    }
+
+    /// Read back stored series whose name matches `name_pattern` and that
+    /// have every one of `dimension_filters`, with their points inside
+    /// `time_range`. Series without any points in range are left out.
+    /// Results are sorted by identifier.
+    pub fn query(&self, name_pattern: &Glob, dimension_filters: &[Dimension], time_range: Window) -> Vec<(MetricId, Vec<Timeseries>)> {
+        let mutex = match self.storage {
+            Some(ref mutex) => mutex,
+            None => return vec![],
+        };
+        let storage = lock(mutex);
+
+        let mut results = self.stored(storage.keys(), "listing").unwrap_or_default().into_iter()
+            .filter_map(|key| {
+                let id = key.id();
+                if !name_pattern.matches(id.name()) {
+                    return None
+                }
+                if !dimension_filters.iter().all(|(k, v)| id.dimension(k) == Some(v)) {
+                    return None
+                }
+                let values = self.stored(storage.get(&key), "reading")??;
+                let points = values.iter()
+                    .filter(|&&(time, _)| time >= time_range.start && time < time_range.end)
+                    .cloned()
+                    .collect::<Vec<Timeseries>>();
+                if points.is_empty() {
+                    None
+                } else {
+                    Some((id.to_owned(), points))
+                }
+            })
+            .collect::<Vec<(MetricId, Vec<Timeseries>)>>();
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+        results
+    }
+
+    /// The stored points of `id` from `from` up to (but not including) `to`,
+    /// or a point per `step` starting at `from` (see `Step`). Only the
+    /// points in range are read, so this is cheaper than `query` for one
+    /// series. Counts are read if there are any in range, otherwise gauges.
+    pub fn iter_range(&self, id: &MetricId, from: SystemTime, to: SystemTime, step: Option<Step>) -> Range {
+        let mut points = vec![];
+        let mut sum = true;
+        if let Some(ref mutex) = self.storage {
+            let storage = lock(mutex);
+            points = self.stored(storage.range(&AggregatedKey::Count(id.clone()), from, to), "reading").unwrap_or_default();
+            if points.is_empty() {
+                points = self.stored(storage.range(&AggregatedKey::Gauge(id.clone()), from, to), "reading").unwrap_or_default();
+                sum = false;
+            }
+        }
+        Range::new(points, from, to, step, sum)
+    }
+
+    /// The latest point of every stored series, sorted by identifier. Series
+    /// the storage fails to read are left out, and the failures counted by
+    /// `metriqs.storage.errors`.
+    pub fn latest(&self) -> Vec<(AggregatedKey, Timeseries)> {
+        let mutex = match self.storage {
+            Some(ref mutex) => mutex,
+            None => return vec![],
+        };
+        let storage = lock(mutex);
+
+        let mut results = self.stored(storage.keys(), "listing").unwrap_or_default().into_iter()
+            .filter_map(|key| {
+                let point = self.stored(storage.last(&key), "reading")??;
+                Some((key, point))
+            })
+            .collect::<Vec<(AggregatedKey, Timeseries)>>();
+        results.sort_by(|a, b| a.0.id().cmp(b.0.id()));
+        results
+    }
+
+    /// What `latest` returns, as a view that can be shared, eg. between
+    /// concurrent scrapes, and looked up by key. Aggregations wait while
+    /// it's taken, so it's consistent with one of them.
+    pub fn snapshot(&self) -> Arc<DbSnapshot> {
+        let series = self.latest();
+        let index = series.iter().enumerate()
+            .map(|(index, (key, _))| (key.clone(), index))
+            .collect();
+        Arc::new(DbSnapshot { taken: SystemTime::now(), series, index })
+    }
+
+    /// Write what `query` returns as CSV with a `timestamp,name,dimensions,value`
+    /// header. Timestamps are milliseconds since the epoch and dimensions
+    /// are `key=value` pairs separated by `;`.
+    pub fn export_csv<W: Write>(&self, mut writer: W, name_pattern: &Glob, dimension_filters: &[Dimension], time_range: Window) -> io::Result<()> {
+        writeln!(writer, "timestamp,name,dimensions,value")?;
+        for (id, points) in self.query(name_pattern, dimension_filters, time_range) {
+            let dimensions = id.dimensions().iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<String>>()
+                .join(";");
+            for (time, value) in points {
+                let millis = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+                writeln!(writer, "{},{},{},{}", millis, csv_field(id.name()), csv_field(&dimensions), value)?;
+            }
+        }
+        writer.flush()
     }
 
     pub fn aggregation_subscribe(&self) -> Receiver<Arc<Vec<AggregatedMetric>>> {
-        let (send, recv) = channel();
+        self.subscribe(None).1
+    }
+
+    /// Like `aggregation_subscribe` but only receives the metrics `filter`
+    /// matches. Every aggregation is still delivered, even if none match.
+    pub fn aggregation_subscribe_filtered(&self, filter: SubscriptionFilter) -> Receiver<Arc<Vec<AggregatedMetric>>> {
+        self.subscribe(Some(filter)).1
+    }
 
-        let mut cell = self.aggregation_subscribers.lock().unwrap();
+    /// Subscribe with an optional filter, also returning a token that can
+    /// be passed to `unsubscribe`. Dropping the receiver unsubscribes too,
+    /// as of the next aggregation.
+    pub fn subscribe(&self, filter: Option<SubscriptionFilter>) -> (SubscriptionToken, Receiver<Arc<Vec<AggregatedMetric>>>) {
+        match self.subscription_buffer {
+            Some(buffer) => self.subscribe_buffered(filter, buffer),
+            None => {
+                let (send, recv) = channel();
+                (self.add_subscriber(filter, Delivery::Channel(send)), recv)
+            },
+        }
+    }
+
+    /// Like `subscribe` but with its own bound on the aggregations waiting
+    /// for it, regardless of `subscription_buffer`.
+    pub fn subscribe_buffered(&self, filter: Option<SubscriptionFilter>, buffer: SubscriptionBuffer) -> (SubscriptionToken, Receiver<Arc<Vec<AggregatedMetric>>>) {
+        let (buffered, recv) = Buffered::new(buffer);
+        (self.add_subscriber(filter, Delivery::Buffered(buffered)), recv)
+    }
+
+    /// Call `subscriber` with every aggregation, or with what `filter`
+    /// matches of it, on the thread `dispatch` says, until the returned
+    /// token is passed to `unsubscribe`.
+    pub fn register_subscriber(&self, subscriber: Arc<dyn Subscriber>, filter: Option<SubscriptionFilter>, dispatch: Dispatch) -> SubscriptionToken {
+        self.add_subscriber(filter, Delivery::new(subscriber, dispatch))
+    }
+
+    fn add_subscriber(&self, filter: Option<SubscriptionFilter>, delivery: Delivery) -> SubscriptionToken {
+        let token = SubscriptionToken(self.next_subscription.fetch_add(1, Ordering::Relaxed));
+        let mut cell = lock(&self.aggregation_subscribers);
         let subscribers = cell.get_mut();
-        subscribers.push(send);
+        subscribers.push((token, filter, delivery));
+        token
+    }
 
-        recv
+    /// Stop publishing to a subscription. Returns whether it was still
+    /// subscribed.
+    pub fn unsubscribe(&self, token: SubscriptionToken) -> bool {
+        let mut cell = lock(&self.aggregation_subscribers);
+        let subscribers = cell.get_mut();
+        let before = subscribers.len();
+        subscribers.retain(|subscriber| subscriber.0 != token);
+        subscribers.len() < before
     }
 }
 
+/// What one shard rolled up, as well as how many of its series were over
+/// the cardinality limit and how many of its samples were too late. Neither
+/// the window's nor each late bucket's rollup has had duplicates merged.
+struct ShardRollup {
+    aggregated: Vec<AggregatedMetric>,
+    late: HashMap<SystemTime, Vec<AggregatedMetric>>,
+    over_limit: usize,
+    too_late: usize,
+}
+
 impl fmt::Debug for Db {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("Db")
@@ -138,23 +1360,723 @@ impl fmt::Debug for Db {
     }
 }
 
-/// Our timeseries "database" of aggregated metrics is keyed by the metric's
-/// identifier (name and dimensions).
-#[derive(Eq, Hash, PartialEq)]
-enum AggregatedKey {
-    Count(Id),
-    Gauge(Id),
+/// Take the collectors' stages out of `options`.
+fn pipeline(options: &mut DbOptions, rejected_counts: &Arc<AtomicUsize>, cluster: Option<Arc<Cluster>>, mirror: Option<Arc<Mirror>>) -> Pipeline {
+    Pipeline {
+        mirror,
+        cluster,
+        counts: CountValidator::new(options.negative_counts.unwrap_or_default(), rejected_counts.clone()),
+        mappings: mem::take(&mut options.mappings),
+        relabel: mem::take(&mut options.relabel),
+        scrub: mem::take(&mut options.scrub),
+        name_filter: options.name_filter.take().unwrap_or_default(),
+        sampler: Sampler::new(mem::take(&mut options.sampling)),
+    }
+}
+
+/// The last multiple of `interval` since the epoch at or before `time`.
+fn previous_boundary(time: SystemTime, interval: Duration) -> SystemTime {
+    let interval = interval.as_nanos().max(1);
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let boundary = since_epoch / interval * interval;
+    UNIX_EPOCH + Duration::new((boundary / 1_000_000_000) as u64, (boundary % 1_000_000_000) as u32)
 }
 
-impl<'a> Into<(AggregatedKey, (SystemTime, i32))> for &'a AggregatedMetric {
-    /// Convert an aggregated metric into a key and value for storage in the
-    /// database's key-value store.
-    fn into(self) -> (AggregatedKey, (SystemTime, i32)) {
-        use self::AggregatedMetric::*;
+/// The first multiple of `interval` since the epoch that's after `time`.
+fn next_boundary(time: SystemTime, interval: Duration) -> SystemTime {
+    let interval = interval.as_nanos().max(1);
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let boundary = (since_epoch / interval + 1) * interval;
+    UNIX_EPOCH + Duration::new((boundary / 1_000_000_000) as u64, (boundary % 1_000_000_000) as u32)
+}
+
+/// Quote a field if it has a comma, quote, or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn it_retains_metrics_after_the_window() {
+        let db = Db::new(DbOptions::default());
+        let subscription = db.aggregation_subscribe();
+
+        db.collect(vec![
+            CollectedMetric::Count(at(5), MetricId::from("foo"), 1),
+            CollectedMetric::Count(at(15), MetricId::from("foo"), 2),
+        ]);
+
+        db.aggregate(Some(Window { start: at(0), end: at(10) }));
+        assert_eq!(*subscription.recv().unwrap(), vec![
+            AggregatedMetric::Gauge(at(5), MetricId::from("foo.rate"), 0.1),
+            AggregatedMetric::Count(at(5), MetricId::from("foo"), 1),
+        ]);
+
+        db.aggregate(Some(Window { start: at(10), end: at(20) }));
+        assert_eq!(*subscription.recv().unwrap(), vec![
+            AggregatedMetric::Gauge(at(15), MetricId::from("foo.rate"), 0.2),
+            AggregatedMetric::Count(at(15), MetricId::from("foo"), 2),
+        ]);
+    }
+
+    #[test]
+    fn it_rolls_late_samples_into_corrections() {
+        let db = Db::new(DbOptions { lateness: Some(Duration::from_secs(30)), ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+
+        db.collect(vec![
+            CollectedMetric::Count(at(1), MetricId::from("foo"), 1),
+            CollectedMetric::Count(at(25), MetricId::from("foo"), 2),
+            CollectedMetric::Count(at(41), MetricId::from("foo"), 3),
+        ]);
+        db.aggregate(Some(Window { start: at(40), end: at(50) }));
+        let aggregated = subscription.recv().unwrap();
+        assert!(aggregated.contains(&AggregatedMetric::Count(at(41), MetricId::from("foo"), 3)));
+        assert!(aggregated.contains(&AggregatedMetric::Count(at(25), MetricId::from("foo"), 2)));
+        assert!(aggregated.contains(&AggregatedMetric::Count(at(50), MetricId::from("metriqs.samples.late"), 1)));
+    }
+
+    #[test]
+    fn it_tags_corrections() {
+        let db = Db::new(DbOptions {
+            lateness: Some(Duration::from_secs(30)),
+            correction_dimension: Some("late".to_string()),
+            ..DbOptions::default()
+        });
+        let subscription = db.aggregation_subscribe();
+
+        db.collect(vec![
+            CollectedMetric::Count(at(25), MetricId::from("foo"), 2),
+            CollectedMetric::Count(at(41), MetricId::from("foo"), 3),
+        ]);
+        db.aggregate(Some(Window { start: at(40), end: at(50) }));
+        let aggregated = subscription.recv().unwrap();
+        assert!(aggregated.contains(&AggregatedMetric::Count(at(41), MetricId::from("foo"), 3)));
+        assert!(aggregated.contains(&AggregatedMetric::Count(at(25), MetricId::from("foo").with_dimension("late", "true"), 2)));
+    }
+
+    #[test]
+    fn it_publishes_nothing_until_promoted() {
+        let db = Db::new(DbOptions { standby: true, ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+
+        db.collect(vec![CollectedMetric::Gauge(at(5), MetricId::from("foo"), 1.0)]);
+        db.aggregate(None);
+        assert!(subscription.try_recv().is_err());
+        assert_eq!(db.latest().len(), 1);
+
+        db.promote();
+        db.collect(vec![CollectedMetric::Gauge(at(15), MetricId::from("foo"), 2.0)]);
+        db.aggregate(None);
+        assert_eq!(*subscription.recv().unwrap(), vec![AggregatedMetric::Gauge(at(15), MetricId::from("foo"), 2.0)]);
+    }
+
+    #[test]
+    fn it_filters_subscriptions() {
+        let db = Db::new(DbOptions::default());
+        let filter = SubscriptionFilter { names: vec![Glob::new("foo*")], ..SubscriptionFilter::default() };
+        let subscription = db.aggregation_subscribe_filtered(filter);
+
+        db.collect(vec![
+            CollectedMetric::Gauge(at(5), MetricId::from("foo"), 1.0),
+            CollectedMetric::Gauge(at(5), MetricId::from("bar"), 2.0),
+        ]);
+        db.aggregate(None);
+        assert_eq!(*subscription.recv().unwrap(), vec![AggregatedMetric::Gauge(at(5), MetricId::from("foo"), 1.0)]);
+    }
+
+    #[test]
+    fn it_calls_registered_subscribers() {
+        struct Batches(Mutex<std::sync::mpsc::Sender<usize>>);
+
+        impl Subscriber for Batches {
+            fn on_flush(&self, batch: &Arc<Vec<AggregatedMetric>>) {
+                lock(&self.0).send(batch.len()).unwrap()
+            }
+        }
+
+        let db = Db::new(DbOptions::default());
+        let (inline, inline_batches) = channel();
+        let (threaded, threaded_batches) = channel();
+        let token = db.register_subscriber(Arc::new(Batches(Mutex::new(inline))), None, Dispatch::Inline);
+        db.register_subscriber(Arc::new(Batches(Mutex::new(threaded))), None, Dispatch::Thread(1));
+
+        db.collect(vec![CollectedMetric::Gauge(at(5), MetricId::from("foo"), 1.0)]);
+        db.aggregate(None);
+        // Inline subscribers have been called by the time it returns.
+        assert_eq!(inline_batches.try_recv(), Ok(1));
+        assert_eq!(threaded_batches.recv(), Ok(1));
+
+        assert!(db.unsubscribe(token));
+        db.aggregate(None);
+        assert_eq!(threaded_batches.recv(), Ok(0));
+        assert!(inline_batches.try_recv().is_err());
+    }
+
+    #[test]
+    fn it_counts_batches_thread_subscribers_drop() {
+        struct Stuck(Mutex<std::sync::mpsc::Sender<()>>, Mutex<Receiver<()>>);
+
+        impl Subscriber for Stuck {
+            fn on_flush(&self, _batch: &Arc<Vec<AggregatedMetric>>) {
+                let _ = lock(&self.0).send(());
+                let _ = lock(&self.1).recv();
+            }
+        }
+
+        let db = Db::new(DbOptions::default());
+        let subscription = db.aggregation_subscribe();
+        let (called, calls) = channel();
+        let (release, released) = channel();
+        db.register_subscriber(Arc::new(Stuck(Mutex::new(called), Mutex::new(released))), None, Dispatch::Thread(1));
+
+        db.aggregate(None);
+        calls.recv().unwrap();
+        // One waits while it's stuck, the rest are dropped.
+        db.aggregate(None);
+        db.aggregate(None);
+        db.aggregate(None);
+        drop(release);
+        db.aggregate(None);
+        // Drops are published with the next aggregation.
+        let dropped = (0..5)
+            .flat_map(|_| subscription.recv().unwrap().iter().cloned().collect::<Vec<_>>())
+            .map(|metric| match metric {
+                AggregatedMetric::Count(_, ref id, count) if id.name() == "metriqs.subscriptions.dropped" => count,
+                _ => 0,
+            })
+            .sum::<i64>();
+        assert_eq!(dropped, 2);
+    }
+
+    #[test]
+    fn it_prunes_dropped_and_unsubscribed_subscribers() {
+        let db = Db::new(DbOptions::default());
+        drop(db.aggregation_subscribe());
+        let (token, _subscription) = db.subscribe(None);
+        let subscribers = |db: &Db| {
+            let mut cell = lock(&db.aggregation_subscribers);
+            cell.get_mut().len()
+        };
+        assert_eq!(subscribers(&db), 2);
+
+        db.aggregate(None);
+        assert_eq!(subscribers(&db), 1);
+        assert!(db.unsubscribe(token));
+        assert!(!db.unsubscribe(token));
+        assert_eq!(subscribers(&db), 0);
+    }
+
+    #[test]
+    fn it_flushes_and_stops_loops_on_shutdown() {
+        let db = Arc::new(Db::new(DbOptions { aggregation_interval: Some(Duration::from_secs(3600)), ..DbOptions::default() }));
+        let subscription = db.aggregation_subscribe();
+        let aggregator = {
+            let db = db.clone();
+            thread::spawn(move || db.sync_aggregate())
+        };
+        // The loop's first aggregation happens right away.
+        subscription.recv().unwrap();
+
+        db.collect(vec![CollectedMetric::Gauge(SystemTime::now(), MetricId::from("foo"), 1.0)]);
+        db.shutdown();
+        aggregator.join().unwrap();
+        assert!(db.is_shut_down());
+        assert_eq!(subscription.recv().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn it_collects_a_heartbeat_every_aggregation() {
+        let options = DbOptions {
+            aggregation_interval: Some(Duration::from_millis(10)),
+            heartbeat: Some("metriqs.heartbeat".to_string()),
+            // Which it isn't subject to.
+            name_filter: Some(NameFilter::new(&["!metriqs.*"])),
+            default_dimensions: vec![("env".into(), "prod".into())],
+            ..DbOptions::default()
+        };
+        let db = Arc::new(Db::new(options));
+        let subscription = db.aggregation_subscribe();
+        let aggregator = {
+            let db = db.clone();
+            thread::spawn(move || db.sync_aggregate())
+        };
+        for _ in 0..2 {
+            let aggregated = subscription.recv().unwrap();
+            assert!(aggregated.iter().any(|metric| match *metric {
+                AggregatedMetric::Count(_, ref id, 1) => id.name() == "metriqs.heartbeat" && id.dimension("version").map(|version| &**version) == Some(env!("CARGO_PKG_VERSION")) && id.dimension("env").is_some(),
+                _ => false,
+            }));
+        }
+        db.shutdown();
+        aggregator.join().unwrap();
+    }
+
+    #[test]
+    fn it_flushes_on_demand() {
+        let db = Db::new(DbOptions::default());
+        let subscription = db.aggregation_subscribe();
+        let now = SystemTime::now();
+        db.collect(vec![CollectedMetric::Gauge(now, MetricId::from("foo"), 1.0)]);
+        db.flush();
+        assert_eq!(*subscription.recv().unwrap(), vec![AggregatedMetric::Gauge(now, MetricId::from("foo"), 1.0)]);
+
+        db.flush();
+        assert_eq!(*subscription.recv().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn it_applies_gauge_deltas_and_reports_idle_gauges() {
+        let db = Db::new(DbOptions {
+            gauge_aggregation: Some(GaugeAggregation::Last),
+            report_idle_gauges: Some(true),
+            ..DbOptions::default()
+        });
+        let subscription = db.aggregation_subscribe();
+        db.collect(vec![
+            CollectedMetric::GaugeDelta(at(5), MetricId::from("depth"), 2.0),
+            CollectedMetric::Gauge(at(5), MetricId::from("depth"), 10.0),
+            CollectedMetric::GaugeDelta(at(6), MetricId::from("depth"), -3.0),
+        ]);
+        db.aggregate(Some(Window { start: at(0), end: at(10) }));
+        assert_eq!(*subscription.recv().unwrap(), vec![AggregatedMetric::Gauge(at(6), MetricId::from("depth"), 7.0)]);
+
+        db.aggregate(Some(Window { start: at(10), end: at(20) }));
+        assert_eq!(*subscription.recv().unwrap(), vec![AggregatedMetric::Gauge(at(20), MetricId::from("depth"), 7.0)]);
+    }
+
+    #[test]
+    fn it_forgets_gauges_that_went_idle() {
+        let db = Db::new(DbOptions {
+            report_idle_gauges: Some(true),
+            forget_gauges_after: Some(1),
+            ..DbOptions::default()
+        });
+        let subscription = db.aggregation_subscribe();
+        db.collect(vec![CollectedMetric::Gauge(at(5), MetricId::from("depth"), 10.0)]);
+        db.aggregate(Some(Window { start: at(0), end: at(10) }));
+        assert_eq!(*subscription.recv().unwrap(), vec![AggregatedMetric::Gauge(at(5), MetricId::from("depth"), 10.0)]);
+        db.aggregate(Some(Window { start: at(10), end: at(20) }));
+        assert_eq!(*subscription.recv().unwrap(), vec![AggregatedMetric::Gauge(at(20), MetricId::from("depth"), 10.0)]);
+        db.aggregate(Some(Window { start: at(20), end: at(30) }));
+        assert_eq!(*subscription.recv().unwrap(), vec![]);
+
+        // A delta to it then applies to zero.
+        db.collect(vec![CollectedMetric::GaugeDelta(at(35), MetricId::from("depth"), 2.0)]);
+        db.aggregate(Some(Window { start: at(30), end: at(40) }));
+        assert_eq!(*subscription.recv().unwrap(), vec![AggregatedMetric::Gauge(at(35), MetricId::from("depth"), 2.0)]);
+    }
+
+    #[test]
+    fn it_saturates_counts_that_overflow() {
+        let db = Db::new(DbOptions::default());
+        let subscription = db.aggregation_subscribe();
+        db.collect(vec![
+            CollectedMetric::Count(at(5), MetricId::from("requests"), i64::MAX),
+            CollectedMetric::Count(at(6), MetricId::from("requests"), i64::MAX),
+        ]);
+        db.aggregate(Some(Window { start: at(0), end: at(10) }));
+        let aggregated = subscription.recv().unwrap();
+        assert!(aggregated.contains(&AggregatedMetric::Count(at(6), MetricId::from("requests"), i64::MAX)));
+        assert!(aggregated.contains(&AggregatedMetric::Count(at(10), MetricId::from("metriqs.counts.saturated"), 1)));
+    }
+
+    #[test]
+    fn it_sums_counts_exactly() {
+        let db = Db::new(DbOptions::default());
+        let subscription = db.aggregation_subscribe();
+        db.collect(vec![
+            CollectedMetric::Count(at(5), MetricId::from("requests"), i64::MAX - 1),
+            CollectedMetric::Count(at(6), MetricId::from("requests"), 1),
+        ]);
+        db.aggregate(Some(Window { start: at(0), end: at(10) }));
+        let aggregated = subscription.recv().unwrap();
+        assert!(aggregated.contains(&AggregatedMetric::Count(at(6), MetricId::from("requests"), i64::MAX)));
+        // Which isn't saturated for happening to be i64::MAX.
+        assert!(!aggregated.iter().any(|metric| metric.id().name() == "metriqs.counts.saturated"));
+    }
+
+    #[test]
+    fn it_drops_negative_counts() {
+        let db = Db::new(DbOptions { negative_counts: Some(NegativeCounts::Drop), ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+        db.collector().push(vec![
+            CollectedMetric::Count(at(1), MetricId::from("requests"), 2),
+            CollectedMetric::Count(at(2), MetricId::from("requests"), -5),
+        ]);
+        db.aggregate(Some(Window { start: at(0), end: at(10) }));
+        let aggregated = subscription.recv().unwrap();
+        assert!(aggregated.contains(&AggregatedMetric::Count(at(1), MetricId::from("requests"), 2)));
+        assert!(aggregated.contains(&AggregatedMetric::Count(at(10), MetricId::from("metriqs.counts.dropped"), 1)));
+    }
+
+    #[test]
+    fn it_merges_series_that_collide_with_histograms() {
+        let db = Db::new(DbOptions::default());
+        let subscription = db.aggregation_subscribe();
+        db.collect(vec![
+            CollectedMetric::Histogram(at(5), MetricId::from("foo"), 1.0),
+            CollectedMetric::Histogram(at(5), MetricId::from("foo"), 2.0),
+            CollectedMetric::Count(at(6), MetricId::from("foo.count"), 3),
+        ]);
+        db.aggregate(Some(Window { start: at(0), end: at(10) }));
+        let counts = subscription.recv().unwrap().iter()
+            .filter(|metric| match **metric {
+                AggregatedMetric::Count(_, ref id, _) => *id == MetricId::from("foo.count"),
+                _ => false,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![AggregatedMetric::Count(at(6), MetricId::from("foo.count"), 5)]);
+    }
+
+    #[test]
+    fn it_merges_collisions_across_shards() {
+        let db = Db::new(DbOptions { shards: Some(4), ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+        // Enough that some histogram and its `.count` are on different
+        // shards.
+        let names = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        for name in &names {
+            db.collect(vec![
+                CollectedMetric::Histogram(at(5), MetricId::from(*name), 1.0),
+                CollectedMetric::Count(at(6), MetricId::from(format!("{}.count", name)), 3),
+            ]);
+        }
+        db.aggregate(Some(Window { start: at(0), end: at(10) }));
+        let aggregated = subscription.recv().unwrap();
+        for name in &names {
+            let id = MetricId::from(format!("{}.count", name));
+            let counts = aggregated.iter()
+                .filter(|metric| matches!(**metric, AggregatedMetric::Count(..)) && *metric.id() == id)
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(counts, vec![AggregatedMetric::Count(at(6), id, 4)]);
+        }
+    }
+
+    #[test]
+    fn it_reuses_drained_batches() {
+        let db = Db::new(DbOptions { pooled_buffers: Some(4), ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+        let collector = db.collector();
+        for value in 0..2 {
+            let mut metrics = collector.buffer();
+            metrics.push(CollectedMetric::Gauge(at(5), MetricId::from("foo"), value as f64));
+            collector.push(metrics);
+        }
+        db.aggregate(None);
+        subscription.recv().unwrap();
+        assert!(collector.buffer().capacity() > 0);
+
+        db.aggregate(None);
+        let hits = MetricId::from("metriqs.pool.hits").with_dimension("pool", "metrics");
+        assert!(subscription.recv().unwrap().iter().any(|metric| match *metric {
+            AggregatedMetric::Count(_, ref id, 1) => *id == hits,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn it_can_skip_storing_aggregates() {
+        let db = Db::new(DbOptions { retain_aggregates: Some(false), ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+        db.collect(vec![CollectedMetric::Gauge(at(5), MetricId::from("foo"), 1.0)]);
+        db.aggregate(None);
+        assert_eq!(subscription.recv().unwrap().len(), 1);
+        assert_eq!(db.stats().series, 0);
+        assert_eq!(db.query(&Glob::new("*"), &[], Window { start: at(0), end: at(10) }), vec![]);
+    }
+
+    #[test]
+    fn it_counts_storage_errors() {
+        struct Failing;
+        impl Storage for Failing {
+            fn push(&mut self, _: AggregatedKey, _: Timeseries, _: Option<usize>) -> io::Result<()> {
+                Err(io::Error::other("disk full"))
+            }
+            fn keys(&self) -> io::Result<Vec<AggregatedKey>> {
+                Err(io::Error::other("disk full"))
+            }
+            fn get(&self, _: &AggregatedKey) -> io::Result<Option<Vec<Timeseries>>> {
+                Err(io::Error::other("disk full"))
+            }
+            fn set(&mut self, _: AggregatedKey, _: Vec<Timeseries>) -> io::Result<()> {
+                Err(io::Error::other("disk full"))
+            }
+        }
+
+        let db = Db::new(DbOptions { storage: Some(Box::new(Failing)), ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+        db.collect(vec![CollectedMetric::Gauge(at(5), MetricId::from("foo"), 1.0)]);
+        db.aggregate(None);
+        assert_eq!(subscription.recv().unwrap().len(), 1);
+        assert_eq!(db.query(&Glob::new("*"), &[], Window { start: at(0), end: at(10) }), vec![]);
+        assert_eq!(db.latest(), vec![]);
+
+        // The failed push, query, and latest are published with the next
+        // aggregation.
+        db.aggregate(Some(Window { start: at(10), end: at(20) }));
+        let errors = subscription.recv().unwrap().iter()
+            .find(|metric| metric.id().name() == "metriqs.storage.errors")
+            .cloned();
+        assert_eq!(errors, Some(AggregatedMetric::Count(at(20), MetricId::from("metriqs.storage.errors"), 3)));
+    }
+
+    #[test]
+    fn it_streams_into_accumulators() {
+        let db = Db::new(DbOptions { streaming: Some(true), shards: Some(2), ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+        for value in 1..=3 {
+            db.collect(vec![
+                CollectedMetric::Gauge(at(5), MetricId::from("foo"), value as f64),
+                CollectedMetric::Set(at(5), MetricId::from("bar"), value.to_string()),
+            ]);
+        }
+        // Only the set members are buffered.
+        assert_eq!(db.stats().backlog, 3);
+        assert_eq!(db.stats().accumulating, 1);
+        let buffered = 3 * mem::size_of::<CollectedMetric>();
+        assert!(db.stats().estimated_bytes > buffered);
+
+        db.aggregate(None);
+        let aggregated = subscription.recv().unwrap();
+        assert!(aggregated.contains(&AggregatedMetric::Gauge(at(5), MetricId::from("foo"), 3.0)));
+        assert!(aggregated.contains(&AggregatedMetric::Gauge(at(5), MetricId::from("bar"), 3.0)));
+    }
+
+    #[test]
+    fn it_fans_out_to_several_pipelines() {
+        let fine = Db::new(DbOptions { aggregation_interval: Some(Duration::from_secs(10)), ..DbOptions::default() });
+        let coarse = Db::new(DbOptions { aggregation_interval: Some(Duration::from_secs(60)), ..DbOptions::default() });
+        let (fine_subscription, coarse_subscription) = (fine.aggregation_subscribe(), coarse.aggregation_subscribe());
+
+        let collector = fine.collector().also(coarse.collector());
+        collector.push(vec![CollectedMetric::Count(at(5), MetricId::from("foo"), 6)]);
+        fine.aggregate(None);
+        coarse.aggregate(None);
+
+        assert!(fine_subscription.recv().unwrap().contains(&AggregatedMetric::Gauge(at(5), MetricId::from("foo.rate"), 0.6)));
+        assert!(coarse_subscription.recv().unwrap().contains(&AggregatedMetric::Gauge(at(5), MetricId::from("foo.rate"), 0.1)));
+    }
+
+    #[test]
+    fn it_filters_collected_names() {
+        let db = Db::new(DbOptions { name_filter: Some(NameFilter::new(&["web.*", "!web.debug.*"])), ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+        db.collector().push(vec![
+            CollectedMetric::Gauge(at(5), MetricId::from("web.latency"), 1.0),
+            CollectedMetric::Gauge(at(5), MetricId::from("web.debug.queue"), 1.0),
+            CollectedMetric::Gauge(at(5), MetricId::from("db.latency"), 1.0),
+        ]);
+        db.aggregate(None);
+        assert_eq!(*subscription.recv().unwrap(), vec![AggregatedMetric::Gauge(at(5), MetricId::from("web.latency"), 1.0)]);
+    }
+
+    #[test]
+    fn it_reconfigures_existing_collectors() {
+        let db = Db::new(DbOptions { name_filter: Some(NameFilter::new(&["web.*"])), ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+        let collector = db.collector();
+        db.reconfigure(DbOptions { name_filter: Some(NameFilter::new(&["db.*"])), ..DbOptions::default() });
+        collector.push(vec![
+            CollectedMetric::Gauge(at(5), MetricId::from("web.latency"), 1.0),
+            CollectedMetric::Gauge(at(5), MetricId::from("db.latency"), 1.0),
+        ]);
+        db.aggregate(None);
+        assert_eq!(*subscription.recv().unwrap(), vec![AggregatedMetric::Gauge(at(5), MetricId::from("db.latency"), 1.0)]);
+    }
+
+    #[test]
+    fn it_publishes_derived_metrics() {
+        let derived = vec![DerivedMetric::new("error_rate", "errors / requests").unwrap()];
+        let db = Db::new(DbOptions { derived, ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+        db.collect(vec![
+            CollectedMetric::Count(at(5), MetricId::from("requests"), 4),
+            CollectedMetric::Count(at(5), MetricId::from("errors"), 1),
+        ]);
+        db.aggregate(Some(Window { start: at(0), end: at(10) }));
+        assert!(subscription.recv().unwrap().contains(&AggregatedMetric::Gauge(at(10), MetricId::from("error_rate"), 0.25)));
+    }
 
-        match self {
-            &Count(time, ref id, value) => (AggregatedKey::Count(id.to_owned()), (time, value)),
-            &Gauge(time, ref id, value) => (AggregatedKey::Gauge(id.to_owned()), (time, value)),
+    #[test]
+    fn it_reports_stats() {
+        let db = Db::new(DbOptions::default());
+        let _subscription = db.aggregation_subscribe();
+        db.collect(vec![
+            CollectedMetric::Gauge(at(5), MetricId::from("foo"), 1.0),
+            CollectedMetric::Gauge(at(5), MetricId::from("bar"), 1.0),
+        ]);
+        assert_eq!(db.stats().backlog, 2);
+
+        db.aggregate(None);
+        db.collect(vec![CollectedMetric::Gauge(at(15), MetricId::from("foo"), 1.0)]);
+        db.aggregate(None);
+        let stats = db.stats();
+        assert_eq!((stats.series, stats.points, stats.backlog, stats.subscribers), (2, 3, 0, 1));
+        assert!(stats.estimated_bytes > 0);
+    }
+
+    #[test]
+    fn it_aligns_to_interval_boundaries() {
+        let interval = Duration::from_secs(10);
+        assert_eq!(next_boundary(at(3), interval), at(10));
+        assert_eq!(next_boundary(at(10), interval), at(20));
+        assert_eq!(next_boundary(at(19) + Duration::from_millis(999), interval), at(20));
+    }
+
+    #[test]
+    fn it_labels_windows_from_the_monotonic_clock() {
+        let instant = Instant::now();
+        let labels = WindowLabels {
+            clock: WindowClock::Monotonic,
+            anchor: Mutex::new((at(100), instant)),
+        };
+        // Slewed by half a second.
+        let slewed = Duration::from_millis(500);
+        assert_eq!(labels.label(at(105) + slewed, instant + Duration::from_secs(5)), at(105));
+        // Stepped forward: re-anchored, and labelled from there on.
+        assert_eq!(labels.label(at(200), instant + Duration::from_secs(6)), at(200));
+        assert_eq!(labels.label(at(201) + slewed, instant + Duration::from_secs(7)), at(201));
+        assert!(WindowLabels { clock: WindowClock::Wall, ..labels }.now() > at(202));
+    }
+
+    #[test]
+    fn it_aggregates_across_shards() {
+        let db = Db::new(DbOptions { shards: Some(4), ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+
+        let id = |i: usize| MetricId::from(format!("foo{}", i));
+        for _ in 0..2 {
+            db.collect((0..20).map(|i| CollectedMetric::Gauge(at(5), id(i), i as f64)).collect());
         }
+        db.aggregate(None);
+
+        let aggregated = subscription.recv().unwrap();
+        assert_eq!(aggregated.len(), 20);
+        for i in 0..20 {
+            assert!(aggregated.contains(&AggregatedMetric::Gauge(at(5), id(i), i as f64)));
+        }
+    }
+
+    #[test]
+    fn it_evicts_expired_and_excess_points() {
+        let db = Db::new(DbOptions {
+            retention: Some(Duration::from_secs(30)),
+            max_points_per_series: Some(2),
+            ..DbOptions::default()
+        });
+        for secs in &[10, 20, 30] {
+            db.collect(vec![CollectedMetric::Gauge(at(*secs), MetricId::from("foo"), *secs as f64)]);
+            db.aggregate(None);
+        }
+        let stored = |db: &Db| {
+            let storage = lock(db.storage.as_ref().unwrap());
+            storage.get(&AggregatedKey::Gauge(MetricId::from("foo"))).unwrap()
+        };
+        assert_eq!(stored(&db), Some(vec![(at(20), 20.0), (at(30), 30.0)]));
+
+        db.evict(at(55)).unwrap();
+        assert_eq!(stored(&db), Some(vec![(at(30), 30.0)]));
+        db.evict(at(70)).unwrap();
+        assert_eq!(stored(&db), None);
+    }
+
+    #[test]
+    fn it_queries_stored_series() {
+        let db = Db::new(DbOptions::default());
+        let host = |host: &str| MetricId::from("cpu").with_dimension("host", host);
+        db.collect(vec![
+            CollectedMetric::Gauge(at(5), host("a"), 1.0),
+            CollectedMetric::Gauge(at(5), host("b"), 2.0),
+            CollectedMetric::Gauge(at(5), MetricId::from("mem"), 3.0),
+        ]);
+        db.aggregate(None);
+        db.collect(vec![CollectedMetric::Gauge(at(15), host("a"), 4.0)]);
+        db.aggregate(None);
+
+        let range = Window { start: at(0), end: at(100) };
+        assert_eq!(db.query(&Glob::new("cpu"), &[], range), vec![
+            (host("a"), vec![(at(5), 1.0), (at(15), 4.0)]),
+            (host("b"), vec![(at(5), 2.0)]),
+        ]);
+
+        let filters = [("host".into(), "a".into())];
+        assert_eq!(db.query(&Glob::new("*"), &filters, Window { start: at(10), end: at(20) }), vec![
+            (host("a"), vec![(at(15), 4.0)]),
+        ]);
+    }
+
+    #[test]
+    fn it_snapshots_latest_points() {
+        let db = Db::new(DbOptions::default());
+        db.collect(vec![CollectedMetric::Gauge(at(5), MetricId::from("cpu"), 1.0)]);
+        db.aggregate(None);
+        let before = db.snapshot();
+        db.collect(vec![
+            CollectedMetric::Gauge(at(15), MetricId::from("cpu"), 2.0),
+            CollectedMetric::Gauge(at(15), MetricId::from("mem"), 3.0),
+        ]);
+        db.aggregate(None);
+
+        // Earlier snapshots don't change.
+        assert_eq!(before.len(), 1);
+        assert_eq!(before.get(&AggregatedKey::Gauge(MetricId::from("cpu"))), Some((at(5), 1.0)));
+        let after = db.snapshot();
+        assert_eq!(after.series(), &[
+            (AggregatedKey::Gauge(MetricId::from("cpu")), (at(15), 2.0)),
+            (AggregatedKey::Gauge(MetricId::from("mem")), (at(15), 3.0)),
+        ]);
+    }
+
+    #[test]
+    fn it_exports_csv() {
+        let db = Db::new(DbOptions::default());
+        db.collect(vec![
+            CollectedMetric::Gauge(at(5), MetricId::from("cpu").with_dimension("host", "a,b"), 0.5),
+            CollectedMetric::Count(at(5), MetricId::from("requests"), 3),
+        ]);
+        db.aggregate(None);
+
+        let mut csv = vec![];
+        db.export_csv(&mut csv, &Glob::new("*"), &[], Window { start: at(0), end: at(100) }).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), concat!(
+            "timestamp,name,dimensions,value\n",
+            "5000,cpu,\"host=a,b\",0.5\n",
+            "5000,requests,,3\n",
+            "5000,requests.rate,,0.3\n",
+        ));
+    }
+
+    #[test]
+    fn it_restores_snapshots() {
+        let path = std::env::temp_dir().join(format!("metriqs-snapshot-{}", std::process::id()));
+        let db = Db::new(DbOptions::default());
+        db.collect(vec![CollectedMetric::Gauge(at(5), MetricId::from("foo"), 1.0)]);
+        db.aggregate(None);
+        db.snapshot_to(&path).unwrap();
+
+        let restored = Db::new(DbOptions::default());
+        restored.collect(vec![CollectedMetric::Gauge(at(15), MetricId::from("foo"), 2.0)]);
+        restored.aggregate(None);
+        restored.restore_from(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let range = Window { start: at(0), end: at(100) };
+        assert_eq!(restored.query(&Glob::new("foo"), &[], range), vec![
+            (MetricId::from("foo"), vec![(at(5), 1.0), (at(15), 2.0)]),
+        ]);
     }
 }
@@ -0,0 +1,195 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crossbeam_queue::SegQueue;
+
+use super::super::metric::{CollectedMetric, MetricId};
+use super::super::util::lock;
+use super::super::util::pool::BufferPool;
+use super::accumulate::Accumulators;
+use super::aggregate::{self, AggregatedMetric, GaugeValues, RollupOptions};
+
+/// Collected metrics awaiting aggregation, partitioned into shards by the
+/// hash of their identifier so that every sample of a series lands in the
+/// same shard. Collectors push onto it without taking any locks, unless
+/// it's streaming or they push gauges.
+pub struct CollectionQueue {
+    shards: Vec<SegQueue<Vec<CollectedMetric>>>,
+    /// Number of metrics queued across all shards.
+    len: AtomicUsize,
+    /// Number of metrics ever pushed, before any were accumulated.
+    pushed: AtomicUsize,
+    /// When streaming, each shard's accumulators and how they're rolled up.
+    accumulators: Option<(RollupOptions, Vec<Mutex<Accumulators>>)>,
+    /// Each shard's gauges' current values, updated as they're pushed.
+    gauges: Vec<Mutex<GaugeValues>>,
+    /// Drains a gauge can go without being pushed before it's forgotten.
+    forget_gauges_after: u32,
+    /// Drained batches, for collectors to push again.
+    buffers: BufferPool<CollectedMetric>,
+}
+
+impl CollectionQueue {
+    /// At least one shard is always created.
+    pub fn new(shards: usize) -> CollectionQueue {
+        let shards = shards.max(1);
+        CollectionQueue {
+            shards: (0..shards).map(|_| SegQueue::new()).collect(),
+            len: AtomicUsize::new(0),
+            pushed: AtomicUsize::new(0),
+            accumulators: None,
+            gauges: (0..shards).map(|_| Mutex::new(GaugeValues::new())).collect(),
+            forget_gauges_after: u32::MAX,
+            buffers: BufferPool::new(0),
+        }
+    }
+
+    /// Fold counts, gauges, and histograms into per-series accumulators as
+    /// they're pushed instead of queueing them. Pushing then briefly locks
+    /// the accumulators of the shards it touches.
+    pub fn streaming(shards: usize, options: RollupOptions) -> CollectionQueue {
+        let mut queue = CollectionQueue::new(shards);
+        let accumulators = queue.shards.iter().map(|_| Mutex::new(Accumulators::new())).collect();
+        queue.accumulators = Some((options, accumulators));
+        queue
+    }
+
+    /// Keep up to `max` drained batches for `buffer` to hand out again.
+    pub fn pooled(mut self, max: usize) -> CollectionQueue {
+        self.buffers = BufferPool::new(max);
+        self
+    }
+
+    /// An empty buffer to collect metrics into before pushing them, reused
+    /// from a drained batch if one's spare.
+    pub fn buffer(&self) -> Vec<CollectedMetric> {
+        self.buffers.take()
+    }
+
+    pub fn buffers(&self) -> &BufferPool<CollectedMetric> {
+        &self.buffers
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Number of metrics pushed but not yet drained.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of metrics pushed since it was created.
+    pub fn pushed(&self) -> usize {
+        self.pushed.load(Ordering::Relaxed)
+    }
+
+    pub fn push(&self, metrics: Vec<CollectedMetric>) {
+        self.pushed.fetch_add(metrics.len(), Ordering::Relaxed);
+        if self.shards.len() == 1 {
+            self.push_shard(0, metrics);
+            return
+        }
+
+        let mut partitioned: Vec<Vec<CollectedMetric>> = self.shards.iter().map(|_| vec![]).collect();
+        for metric in metrics {
+            partitioned[metric.id().shard(self.shards.len())].push(metric)
+        }
+        for (shard, metrics) in partitioned.into_iter().enumerate() {
+            if !metrics.is_empty() {
+                self.push_shard(shard, metrics)
+            }
+        }
+    }
+
+    fn push_shard(&self, shard: usize, metrics: Vec<CollectedMetric>) {
+        // Held until the metrics are queued so that `drain` never sees a
+        // gauge updated without its sample.
+        let mut gauges = if metrics.iter().any(|metric| matches!(*metric, CollectedMetric::Gauge(..) | CollectedMetric::GaugeDelta(..))) {
+            Some(lock(&self.gauges[shard]))
+        } else {
+            None
+        };
+        let metrics = match gauges {
+            Some(ref mut gauges) => aggregate::apply_gauges(metrics, gauges),
+            None => metrics,
+        };
+        let metrics = match self.accumulators {
+            Some((ref options, ref accumulators)) => lock(&accumulators[shard]).fold(metrics, options),
+            None => metrics,
+        };
+        if !metrics.is_empty() {
+            self.len.fetch_add(metrics.len(), Ordering::Relaxed);
+            self.shards[shard].push(metrics)
+        }
+    }
+
+    /// Number of series being accumulated across all shards.
+    pub fn accumulating(&self) -> usize {
+        match self.accumulators {
+            Some((_, ref accumulators)) => accumulators.iter().map(|shard| lock(shard).len()).sum(),
+            None => 0,
+        }
+    }
+
+    /// Rough estimate of the memory used by every shard's accumulators.
+    pub fn accumulated_bytes(&self) -> usize {
+        match self.accumulators {
+            Some((_, ref accumulators)) => accumulators.iter().map(|shard| lock(shard).estimated_bytes()).sum(),
+            None => 0,
+        }
+    }
+
+    /// Roll up and reset a shard's accumulators; nothing if it isn't
+    /// streaming.
+    pub fn flush_accumulators(&self, shard: usize, elapsed: Duration) -> Vec<AggregatedMetric> {
+        match self.accumulators {
+            Some((ref options, ref accumulators)) => lock(&accumulators[shard]).flush(elapsed, options),
+            None => vec![],
+        }
+    }
+
+    /// Forget gauges' current values once they've gone `after` drains
+    /// without being pushed; they're otherwise kept forever.
+    pub fn forgetting_gauges_after(mut self, after: u32) -> CollectionQueue {
+        self.forget_gauges_after = after;
+        self
+    }
+
+    /// Take everything pushed onto a shard so far, and the current values
+    /// of its gauges that weren't pushed since the previous drain but aren't
+    /// forgotten yet. Anything
+    /// pushed concurrently may be left for the next drain.
+    pub fn drain(&self, shard: usize) -> (Vec<CollectedMetric>, Vec<(MetricId, f64)>) {
+        let mut gauges = lock(&self.gauges[shard]);
+        let mut metrics = vec![];
+        while let Some(mut batch) = self.shards[shard].pop() {
+            self.len.fetch_sub(batch.len(), Ordering::Relaxed);
+            if metrics.is_empty() {
+                metrics = batch
+            } else {
+                metrics.append(&mut batch);
+                self.buffers.give(batch)
+            }
+        }
+        (metrics, aggregate::idle_gauges(&mut gauges, self.forget_gauges_after))
+    }
+
+    /// Forget the current values of a shard's `ids`.
+    pub fn forget_gauges(&self, shard: usize, ids: &HashSet<MetricId>) {
+        lock(&self.gauges[shard]).retain(|id, _| !ids.contains(id));
+    }
+
+    /// Forget every gauge's current value.
+    pub fn clear_gauges(&self) {
+        for gauges in &self.gauges {
+            lock(gauges).clear();
+        }
+    }
+}
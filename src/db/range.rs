@@ -0,0 +1,142 @@
+//! Reading a stretch of a stored series, optionally a point per step, for
+//! readers that want less than `Db::query` clones.
+
+use std::time::{Duration, SystemTime};
+use std::vec;
+
+use super::storage::Timeseries;
+
+/// What `Step` yields for steps without any points.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fill {
+    /// Nothing, leaving a gap.
+    Skip,
+    /// The previous step's value, if there was one.
+    Previous,
+    Value(f64),
+}
+
+/// Aligns a range to a point per `every`, starting at the range's start.
+/// A step's value is the sum of its points for counts and the last of them
+/// for gauges.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Step {
+    pub every: Duration,
+    pub fill: Fill,
+}
+
+impl Step {
+    /// Steps without points are skipped.
+    pub fn new(every: Duration) -> Step {
+        Step { every, fill: Fill::Skip }
+    }
+
+    pub fn fill(mut self, fill: Fill) -> Step {
+        self.fill = fill;
+        self
+    }
+}
+
+/// The points of a series within a range, from `Db::iter_range`.
+pub struct Range {
+    points: vec::IntoIter<Timeseries>,
+    /// The next point, once it's been taken from `points` but belongs to a
+    /// later step.
+    peeked: Option<Timeseries>,
+    step: Option<Step>,
+    sum: bool,
+    /// Start of the next step.
+    next: SystemTime,
+    end: SystemTime,
+    previous: Option<f64>,
+}
+
+impl Range {
+    /// `points` are in time order and within `start..end`; `sum` is whether
+    /// they're counts.
+    pub(super) fn new(points: Vec<Timeseries>, start: SystemTime, end: SystemTime, step: Option<Step>, sum: bool) -> Range {
+        Range {
+            points: points.into_iter(),
+            peeked: None,
+            // Zero-length steps would never advance.
+            step: step.filter(|step| !step.every.is_zero()),
+            sum,
+            next: start,
+            end,
+            previous: None,
+        }
+    }
+
+    fn next_point(&mut self) -> Option<Timeseries> {
+        self.peeked.take().or_else(|| self.points.next())
+    }
+}
+
+impl Iterator for Range {
+    type Item = Timeseries;
+
+    fn next(&mut self) -> Option<Timeseries> {
+        let step = match self.step {
+            Some(step) => step,
+            None => return self.points.next(),
+        };
+        while self.next < self.end {
+            let start = self.next;
+            let end = (start + step.every).min(self.end);
+            self.next = end;
+
+            let mut value = None;
+            while let Some((time, point)) = self.next_point() {
+                if time >= end {
+                    self.peeked = Some((time, point));
+                    break
+                }
+                value = Some(match value {
+                    Some(value) if self.sum => value + point,
+                    _ => point,
+                });
+            }
+            let value = match (value, step.fill) {
+                (Some(value), _) => value,
+                (None, Fill::Skip) => continue,
+                (None, Fill::Previous) => match self.previous {
+                    Some(previous) => previous,
+                    None => continue,
+                },
+                (None, Fill::Value(value)) => value,
+            };
+            self.previous = Some(value);
+            return Some((start, value))
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn it_steps_through_points() {
+        let points = vec![(at(10), 1.0), (at(15), 2.0), (at(40), 3.0)];
+
+        let all = Range::new(points.clone(), at(0), at(60), None, true).collect::<Vec<_>>();
+        assert_eq!(all, points);
+
+        let step = Step::new(Duration::from_secs(10));
+        let summed = Range::new(points.clone(), at(10), at(60), Some(step), true).collect::<Vec<_>>();
+        assert_eq!(summed, vec![(at(10), 3.0), (at(40), 3.0)]);
+
+        let filled = Range::new(points.clone(), at(0), at(50), Some(step.fill(Fill::Previous)), false).collect::<Vec<_>>();
+        assert_eq!(filled, vec![(at(10), 2.0), (at(20), 2.0), (at(30), 2.0), (at(40), 3.0)]);
+
+        let zeroed = Range::new(points, at(0), at(30), Some(step.fill(Fill::Value(0.0))), true).collect::<Vec<_>>();
+        assert_eq!(zeroed, vec![(at(0), 0.0), (at(10), 3.0), (at(20), 0.0)]);
+    }
+}
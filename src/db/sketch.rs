@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+
+/// Values closer to zero than this are counted in the zero bucket.
+const MIN_INDEXABLE: f64 = 1e-9;
+
+/// Bins beyond this many (per sign) are collapsed into the lowest one so
+/// memory stays bounded for extremely wide distributions.
+const MAX_BINS: usize = 2048;
+
+/// DDSketch quantile sketch. Every quantile it reports is within
+/// `relative_accuracy` of the exact value, and sketches with the same
+/// accuracy can be merged losslessly, so per-shard or per-agent sketches
+/// roll up into exactly the sketch of all their samples.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DdSketch {
+    relative_accuracy: f64,
+    gamma: f64,
+    ln_gamma: f64,
+    /// Bin counts of positive values keyed by `ceil(log_gamma(value))`.
+    positive: BTreeMap<i32, u64>,
+    /// Same as `positive` but for the magnitude of negative values.
+    negative: BTreeMap<i32, u64>,
+    zero_count: u64,
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl DdSketch {
+    /// `relative_accuracy` is clamped to between 0.0001 and 0.5; 0.01 (1%)
+    /// is a good default.
+    pub fn new(relative_accuracy: f64) -> DdSketch {
+        let relative_accuracy = relative_accuracy.clamp(0.0001, 0.5);
+        let gamma = (1.0 + relative_accuracy) / (1.0 - relative_accuracy);
+        DdSketch {
+            relative_accuracy,
+            gamma,
+            ln_gamma: gamma.ln(),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero_count: 0,
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Rebuild a sketch from what its accessors report, eg. after it's
+    /// been serialized. `min` and `max` are ignored if there are no
+    /// samples.
+    pub fn from_parts(relative_accuracy: f64, positive: BTreeMap<i32, u64>, negative: BTreeMap<i32, u64>, zero_count: u64, sum: f64, min: f64, max: f64) -> DdSketch {
+        let mut sketch = DdSketch::new(relative_accuracy);
+        sketch.count = positive.values().chain(negative.values()).sum::<u64>() + zero_count;
+        sketch.positive = positive;
+        sketch.negative = negative;
+        collapse(&mut sketch.positive);
+        collapse(&mut sketch.negative);
+        sketch.zero_count = zero_count;
+        sketch.sum = sum;
+        if sketch.count > 0 {
+            sketch.min = min;
+            sketch.max = max;
+        }
+        sketch
+    }
+
+    pub fn insert(&mut self, value: f64) {
+        self.insert_n(value, 1)
+    }
+
+    /// Insert `value` as `n` samples.
+    pub fn insert_n(&mut self, value: f64, n: u64) {
+        if !value.is_finite() || n == 0 {
+            return
+        }
+        if value > MIN_INDEXABLE {
+            let key = self.key(value);
+            *self.positive.entry(key).or_insert(0) += n;
+            collapse(&mut self.positive);
+        } else if value < -MIN_INDEXABLE {
+            let key = self.key(-value);
+            *self.negative.entry(key).or_insert(0) += n;
+            collapse(&mut self.negative);
+        } else {
+            self.zero_count += n
+        }
+        self.count += n;
+        self.sum += value * n as f64;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Add all of `other`'s samples to this sketch. Sketches with a
+    /// different accuracy are merged by re-inserting `other`'s bins, which
+    /// loses some of its accuracy.
+    pub fn merge(&mut self, other: &DdSketch) {
+        if other.count == 0 {
+            return
+        }
+        if self.gamma == other.gamma {
+            for (key, count) in &other.positive {
+                *self.positive.entry(*key).or_insert(0) += count;
+            }
+            for (key, count) in &other.negative {
+                *self.negative.entry(*key).or_insert(0) += count;
+            }
+            collapse(&mut self.positive);
+            collapse(&mut self.negative);
+            self.zero_count += other.zero_count;
+            self.count += other.count;
+            self.sum += other.sum;
+            self.min = self.min.min(other.min);
+            self.max = self.max.max(other.max);
+        } else {
+            let (count, sum, min, max) = (self.count, self.sum, self.min, self.max);
+            for (key, n) in &other.positive {
+                self.insert_n(other.value(*key), *n)
+            }
+            for (key, n) in &other.negative {
+                self.insert_n(-other.value(*key), *n)
+            }
+            self.insert_n(0.0, other.zero_count);
+            // Keep the exact statistics rather than the re-inserted ones.
+            self.count = count + other.count;
+            self.sum = sum + other.sum;
+            self.min = min.min(other.min);
+            self.max = max.max(other.max);
+        }
+    }
+
+    /// Value at `quantile` (between 0 and 1), or `None` if the sketch is
+    /// empty.
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None
+        }
+        let rank = quantile.clamp(0.0, 1.0) * (self.count - 1) as f64;
+
+        // Walk the bins from the smallest value to the largest.
+        let mut seen = 0u64;
+        for (key, count) in self.negative.iter().rev() {
+            seen += count;
+            if seen as f64 > rank {
+                return Some(self.clamp(-self.value(*key)))
+            }
+        }
+        seen += self.zero_count;
+        if seen as f64 > rank {
+            return Some(self.clamp(0.0))
+        }
+        for (key, count) in &self.positive {
+            seen += count;
+            if seen as f64 > rank {
+                return Some(self.clamp(self.value(*key)))
+            }
+        }
+        Some(self.max)
+    }
+
+    pub fn relative_accuracy(&self) -> f64 {
+        self.relative_accuracy
+    }
+
+    /// Base of the logarithmic bin mapping; backends that accept sketches
+    /// need it to interpret `positive_bins` and `negative_bins`.
+    pub fn gamma(&self) -> f64 {
+        self.gamma
+    }
+
+    pub fn positive_bins(&self) -> &BTreeMap<i32, u64> {
+        &self.positive
+    }
+
+    pub fn negative_bins(&self) -> &BTreeMap<i32, u64> {
+        &self.negative
+    }
+
+    pub fn zero_count(&self) -> u64 {
+        self.zero_count
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// `None` if the sketch is empty; likewise for `max` and `average`.
+    pub fn min(&self) -> Option<f64> {
+        if self.count > 0 { Some(self.min) } else { None }
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        if self.count > 0 { Some(self.max) } else { None }
+    }
+
+    pub fn average(&self) -> Option<f64> {
+        if self.count > 0 { Some(self.sum / self.count as f64) } else { None }
+    }
+
+    fn key(&self, magnitude: f64) -> i32 {
+        (magnitude.ln() / self.ln_gamma).ceil() as i32
+    }
+
+    /// Representative value of a bin; within `relative_accuracy` of
+    /// everything in it.
+    fn value(&self, key: i32) -> f64 {
+        2.0 * self.gamma.powi(key) / (self.gamma + 1.0)
+    }
+
+    fn clamp(&self, value: f64) -> f64 {
+        value.max(self.min).min(self.max)
+    }
+}
+
+/// Fold the lowest bins together until there are at most `MAX_BINS`.
+fn collapse(bins: &mut BTreeMap<i32, u64>) {
+    while bins.len() > MAX_BINS {
+        let (_, lowest) = bins.pop_first().unwrap();
+        *bins.values_mut().next().unwrap() += lowest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reports_quantiles_within_its_accuracy() {
+        let mut sketch = DdSketch::new(0.01);
+        for value in 1..=10_000 {
+            sketch.insert(value as f64)
+        }
+        for &(quantile, exact) in &[(0.0, 1.0), (0.5, 5000.5), (0.99, 9900.0), (1.0, 10_000.0)] {
+            let value = sketch.quantile(quantile).unwrap();
+            assert!((value - exact).abs() / exact <= 0.01, "{} = {}", quantile, value);
+        }
+        assert_eq!(sketch.count(), 10_000);
+        assert_eq!(sketch.min(), Some(1.0));
+        assert_eq!(DdSketch::new(0.01).quantile(0.5), None);
+    }
+
+    #[test]
+    fn it_merges_losslessly() {
+        let mut all = DdSketch::new(0.01);
+        let mut left = DdSketch::new(0.01);
+        let mut right = DdSketch::new(0.01);
+        for value in -500..500 {
+            let value = value as f64 * 1.5;
+            all.insert(value);
+            if value < 100.0 { left.insert(value) } else { right.insert(value) }
+        }
+        left.merge(&right);
+        assert_eq!(left, all);
+    }
+}
@@ -0,0 +1,129 @@
+//! Snapshots are a line-based text format with one series per line:
+//!
+//! ```text
+//! metriqs-snapshot 1
+//! G<TAB>cpu<TAB>host=a,region=us<TAB>1500000000000000000:0.5;1500000010000000000:0.75
+//! ```
+//!
+//! Fields are tab-separated: `C` or `G` for counts or gauges, the name, the
+//! dimensions, and the points as nanoseconds since the epoch and a value.
+//! Characters with meaning to the format are percent-encoded.
+
+use std::io::{self, BufRead, Write};
+use std::time::{Duration, UNIX_EPOCH};
+
+use string_cache::DefaultAtom as Atom;
+
+use super::super::metric::MetricId;
+use super::super::util::percent;
+use super::storage::{AggregatedKey, AggregatedMetrics, Timeseries};
+
+const HEADER: &str = "metriqs-snapshot 1";
+
+/// Characters with meaning to the format.
+const RESERVED: &str = "\t\n\r,=;:";
+
+pub fn write<W: Write>(metrics: &AggregatedMetrics, mut writer: W) -> io::Result<()> {
+    writeln!(writer, "{}", HEADER)?;
+    for (key, values) in metrics {
+        let (kind, id) = match *key {
+            AggregatedKey::Count(ref id) => ("C", id),
+            AggregatedKey::Gauge(ref id) => ("G", id),
+        };
+        let dimensions = id.dimensions().iter()
+            .map(|(k, v)| format!("{}={}", encode(k), encode(v)))
+            .collect::<Vec<String>>()
+            .join(",");
+        let points = values.iter()
+            .map(|&(time, value)| {
+                let nanos = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+                format!("{}:{}", nanos, value)
+            })
+            .collect::<Vec<String>>()
+            .join(";");
+        writeln!(writer, "{}\t{}\t{}\t{}", kind, encode(id.name()), dimensions, points)?;
+    }
+    writer.flush()
+}
+
+pub fn read<R: BufRead>(reader: R) -> io::Result<AggregatedMetrics> {
+    let mut lines = reader.lines();
+    match lines.next() {
+        Some(Ok(ref header)) if header == HEADER => (),
+        Some(Err(err)) => return Err(err),
+        _ => return Err(invalid("missing snapshot header")),
+    }
+
+    let mut metrics = AggregatedMetrics::new();
+    for line in lines {
+        let line = line?;
+        if line.is_empty() {
+            continue
+        }
+        let (key, values) = parse_line(&line).ok_or_else(|| invalid(format!("invalid snapshot line: {}", line)))?;
+        metrics.entry(key).or_default().extend(values);
+    }
+    Ok(metrics)
+}
+
+fn parse_line(line: &str) -> Option<(AggregatedKey, Vec<Timeseries>)> {
+    let fields = line.split('\t').collect::<Vec<&str>>();
+    if fields.len() != 4 {
+        return None
+    }
+
+    let mut dimensions = vec![];
+    for pair in fields[2].split(',').filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let key = percent::decode(parts.next()?)?;
+        let value = percent::decode(parts.next()?)?;
+        dimensions.push((Atom::from(key), Atom::from(value)));
+    }
+    let id = MetricId::new(percent::decode(fields[1])?, dimensions);
+
+    let mut values = vec![];
+    for point in fields[3].split(';').filter(|point| !point.is_empty()) {
+        let mut parts = point.splitn(2, ':');
+        let nanos: u64 = parts.next()?.parse().ok()?;
+        let value: f64 = parts.next()?.parse().ok()?;
+        values.push((UNIX_EPOCH + Duration::from_nanos(nanos), value));
+    }
+
+    let key = match fields[0] {
+        "C" => AggregatedKey::Count(id),
+        "G" => AggregatedKey::Gauge(id),
+        _ => return None,
+    };
+    Some((key, values))
+}
+
+fn encode(input: &str) -> String {
+    percent::encode(input, RESERVED)
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_snapshots() {
+        let t = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut metrics = AggregatedMetrics::new();
+        metrics.insert(
+            AggregatedKey::Gauge(MetricId::from("cpu:usage").with_dimension("path", "/a,b=c")),
+            vec![(t(1), 0.1), (t(2) + Duration::from_nanos(5), -3.5)],
+        );
+        metrics.insert(AggregatedKey::Count(MetricId::from("requests")), vec![(t(1), 4.0)]);
+
+        let mut buf = vec![];
+        write(&metrics, &mut buf).unwrap();
+        let restored = read(&buf[..]).unwrap();
+        assert!(restored == metrics);
+
+        assert!(read(&b"G\tfoo\t\t1:1\n"[..]).is_err());
+    }
+}
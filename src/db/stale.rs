@@ -0,0 +1,109 @@
+//! Tracks when series were last aggregated, so that ones whose emitters
+//! went away (eg. the hosts of a previous deploy) can be let go of rather
+//! than reported forever.
+
+use std::collections::HashMap;
+
+use super::aggregate::AggregatedMetric;
+use super::storage::AggregatedKey;
+
+/// A shard's counts and gauges, and the aggregation each was last seen in.
+pub struct Staleness {
+    after: u64,
+    aggregations: u64,
+    last_seen: HashMap<AggregatedKey, u64>,
+}
+
+impl Staleness {
+    /// Series are stale once `after` aggregations go by without them.
+    pub fn new(after: u32) -> Staleness {
+        Staleness { after: u64::from(after.max(1)), aggregations: 0, last_seen: HashMap::new() }
+    }
+
+    /// Note the counts and gauges of an aggregation, returning those that
+    /// went stale with it; they're forgotten until they're seen again.
+    pub fn observe<'a, I: IntoIterator<Item = &'a AggregatedMetric>>(&mut self, aggregated: I) -> Vec<AggregatedKey> {
+        self.aggregations += 1;
+        for metric in aggregated {
+            let key = match *metric {
+                AggregatedMetric::Count(_, ref id, _) => AggregatedKey::Count(id.clone()),
+                AggregatedMetric::Gauge(_, ref id, _) => AggregatedKey::Gauge(id.clone()),
+                _ => continue,
+            };
+            self.last_seen.insert(key, self.aggregations);
+        }
+
+        let (aggregations, after) = (self.aggregations, self.after);
+        let mut stale = vec![];
+        self.last_seen.retain(|key, &mut seen| {
+            if aggregations - seen < after {
+                return true
+            }
+            stale.push(key.clone());
+            false
+        });
+        stale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::super::{Db, DbOptions};
+    use super::super::super::metric::{CollectedMetric, MetricId};
+
+    #[test]
+    fn it_marks_series_stale_after_idle_aggregations() {
+        let options = DbOptions { stale_after: Some(2), stale_markers: Some(true), report_idle_gauges: Some(true), ..DbOptions::default() };
+        let db = Db::new(options);
+        let (_, subscription) = db.subscribe(None);
+        let time = UNIX_EPOCH + Duration::from_secs(10);
+        db.collect(vec![
+            CollectedMetric::Gauge(time, MetricId::from("queue"), 3.0),
+            CollectedMetric::Count(time, MetricId::from("requests"), 2),
+        ]);
+        let mut aggregations = vec![];
+        for _ in 0..4 {
+            db.aggregate(None);
+            aggregations.push(subscription.recv().unwrap());
+        }
+        let values = |metrics: &[AggregatedMetric], name: &str| metrics.iter()
+            .filter(|metric| metric.id().name() == name)
+            .map(|metric| match *metric {
+                AggregatedMetric::Count(_, _, value) => value as f64,
+                AggregatedMetric::Gauge(_, _, value) => value,
+                _ => unreachable!(),
+            })
+            .collect::<Vec<f64>>();
+
+        // The idle gauge is reported once, then ends with a zero.
+        assert_eq!(values(&aggregations[0], "queue"), vec![3.0]);
+        assert_eq!(values(&aggregations[1], "queue"), vec![3.0]);
+        assert_eq!(values(&aggregations[2], "queue"), vec![0.0]);
+        assert_eq!(values(&aggregations[3], "queue"), Vec::<f64>::new());
+        assert_eq!(values(&aggregations[1], "requests"), Vec::<f64>::new());
+        assert_eq!(values(&aggregations[2], "requests"), vec![0.0]);
+        assert_eq!(values(&aggregations[3], "requests"), Vec::<f64>::new());
+    }
+
+    #[test]
+    fn it_counts_late_corrections_as_seen() {
+        let options = DbOptions { stale_after: Some(1), stale_markers: Some(true), lateness: Some(Duration::from_secs(30)), ..DbOptions::default() };
+        let db = Db::new(options);
+        let (_, subscription) = db.subscribe(None);
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let window = |start| super::super::Window { start: at(start), end: at(start + 10) };
+
+        db.collect(vec![CollectedMetric::Count(at(41), MetricId::from("requests"), 1)]);
+        db.aggregate(Some(window(40)));
+        subscription.recv().unwrap();
+        db.collect(vec![CollectedMetric::Count(at(45), MetricId::from("requests"), 2)]);
+        db.aggregate(Some(window(50)));
+        let aggregated = subscription.recv().unwrap();
+        assert!(aggregated.contains(&AggregatedMetric::Count(at(45), MetricId::from("requests"), 2)));
+        assert!(!aggregated.contains(&AggregatedMetric::Count(at(60), MetricId::from("requests"), 0)));
+    }
+}
@@ -0,0 +1,391 @@
+//! Where the Db keeps aggregated timeseries. `MemoryStorage` is the
+//! default; `SledStorage` (behind the `sled` feature) keeps history on disk
+//! for deployments that need more of it than fits in memory.
+
+use std::collections::HashMap;
+use std::io;
+use std::time::SystemTime;
+
+use super::super::metric::MetricId;
+
+pub type Timeseries = (SystemTime, f64);
+
+pub type AggregatedMetrics = HashMap<AggregatedKey, Vec<Timeseries>>;
+
+/// Our timeseries "database" of aggregated metrics is keyed by the metric's
+/// identifier (name and dimensions).
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum AggregatedKey {
+    Count(MetricId),
+    Gauge(MetricId),
+}
+
+impl AggregatedKey {
+    pub fn id(&self) -> &MetricId {
+        match *self {
+            AggregatedKey::Count(ref id) |
+            AggregatedKey::Gauge(ref id) => id,
+        }
+    }
+}
+
+/// A store of timeseries, each of which is a list of points in time order.
+/// Late corrections are pushed after the window they correct, so they're
+/// inserted before any later points; ties stay in the order they were
+/// pushed.
+pub trait Storage: Send {
+    /// Append a point to a series, then drop its oldest points so that no
+    /// more than `max_points` remain.
+    fn push(&mut self, key: AggregatedKey, point: Timeseries, max_points: Option<usize>) -> io::Result<()>;
+
+    fn keys(&self) -> io::Result<Vec<AggregatedKey>>;
+
+    fn get(&self, key: &AggregatedKey) -> io::Result<Option<Vec<Timeseries>>>;
+
+    /// The newest point of a series.
+    fn last(&self, key: &AggregatedKey) -> io::Result<Option<Timeseries>> {
+        Ok(self.get(key)?.and_then(|points| points.last().copied()))
+    }
+
+    /// The points of a series from `start` up to (but not including) `end`.
+    fn range(&self, key: &AggregatedKey, start: SystemTime, end: SystemTime) -> io::Result<Vec<Timeseries>> {
+        Ok(self.get(key)?.unwrap_or_default().into_iter()
+            .filter(|&(time, _)| time >= start && time < end)
+            .collect())
+    }
+
+    /// Replace the points of a series; no points removes the series.
+    fn set(&mut self, key: AggregatedKey, points: Vec<Timeseries>) -> io::Result<()>;
+
+    /// Number of series and of points across all of them.
+    fn counts(&self) -> io::Result<(usize, usize)> {
+        let keys = self.keys()?;
+        let mut points = 0;
+        for key in &keys {
+            points += self.get(key)?.map(|points| points.len()).unwrap_or(0);
+        }
+        Ok((keys.len(), points))
+    }
+}
+
+fn truncate(points: &mut Vec<Timeseries>, max_points: Option<usize>) {
+    if let Some(max) = max_points {
+        if points.len() > max {
+            let excess = points.len() - max;
+            points.drain(..excess);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    series: AggregatedMetrics,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn push(&mut self, key: AggregatedKey, point: Timeseries, max_points: Option<usize>) -> io::Result<()> {
+        let points = self.series.entry(key).or_default();
+        let index = points.iter().rposition(|&(time, _)| time <= point.0).map_or(0, |index| index + 1);
+        points.insert(index, point);
+        truncate(points, max_points);
+        Ok(())
+    }
+
+    fn keys(&self) -> io::Result<Vec<AggregatedKey>> {
+        Ok(self.series.keys().cloned().collect())
+    }
+
+    fn get(&self, key: &AggregatedKey) -> io::Result<Option<Vec<Timeseries>>> {
+        Ok(self.series.get(key).cloned())
+    }
+
+    fn last(&self, key: &AggregatedKey) -> io::Result<Option<Timeseries>> {
+        Ok(self.series.get(key).and_then(|points| points.last().copied()))
+    }
+
+    /// Only clones the points in range. Series are in time order (see
+    /// `push`).
+    fn range(&self, key: &AggregatedKey, start: SystemTime, end: SystemTime) -> io::Result<Vec<Timeseries>> {
+        let points = match self.series.get(key) {
+            Some(points) => points,
+            None => return Ok(vec![]),
+        };
+        let from = points.partition_point(|&(time, _)| time < start);
+        let to = from + points[from..].partition_point(|&(time, _)| time < end);
+        Ok(points[from..to].to_vec())
+    }
+
+    fn set(&mut self, key: AggregatedKey, points: Vec<Timeseries>) -> io::Result<()> {
+        if points.is_empty() {
+            self.series.remove(&key);
+        } else {
+            self.series.insert(key, points);
+        }
+        Ok(())
+    }
+
+    fn counts(&self) -> io::Result<(usize, usize)> {
+        Ok((self.series.len(), self.series.values().map(Vec::len).sum()))
+    }
+}
+
+#[cfg(feature = "sled")]
+pub use self::sled_storage::SledStorage;
+
+#[cfg(feature = "sled")]
+mod sled_storage {
+    use std::io;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use sled;
+    use string_cache::DefaultAtom as Atom;
+
+    use super::{AggregatedKey, Storage, Timeseries};
+    use super::super::super::metric::MetricId;
+
+    /// Stores each point as its own sled entry, so that pushing doesn't
+    /// rewrite the whole series and reads are range scans. Series keys are
+    /// a kind byte followed by length-prefixed name and dimensions, and map
+    /// to how many points the series has. A point's key is its series key,
+    /// itself length-prefixed, then big-endian nanoseconds since the epoch
+    /// and a sequence number that keeps ties in push order; its value is
+    /// the value's big-endian bits.
+    pub struct SledStorage {
+        db: sled::Db,
+        series: sled::Tree,
+        points: sled::Tree,
+    }
+
+    impl SledStorage {
+        pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SledStorage> {
+            let db = sled::open(path)?;
+            let series = db.open_tree("series")?;
+            let points = db.open_tree("points")?;
+            Ok(SledStorage { db, series, points })
+        }
+
+        fn length(&self, key: &[u8]) -> io::Result<usize> {
+            match self.series.get(key)? {
+                Some(value) => Ok(decode_u64(&value).ok_or_else(|| invalid("invalid series length"))? as usize),
+                None => Ok(0),
+            }
+        }
+
+        fn insert(&self, prefix: &[u8], point: Timeseries) -> io::Result<()> {
+            let mut key = point_key(prefix, point.0);
+            key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+            self.points.insert(key, &point.1.to_bits().to_be_bytes())?;
+            Ok(())
+        }
+
+        fn scan(&self, prefix: &[u8]) -> io::Result<Vec<Timeseries>> {
+            self.points.scan_prefix(prefix).map(|entry| decode_point(prefix, entry?)).collect()
+        }
+    }
+
+    impl Storage for SledStorage {
+        fn push(&mut self, key: AggregatedKey, point: Timeseries, max_points: Option<usize>) -> io::Result<()> {
+            let key = encode_key(&key);
+            let prefix = prefix(&key);
+            self.insert(&prefix, point)?;
+            let mut length = self.length(&key)? + 1;
+            if let Some(max) = max_points {
+                let excess = length.saturating_sub(max);
+                for entry in self.points.scan_prefix(&prefix).keys().take(excess) {
+                    self.points.remove(entry?)?;
+                }
+                length -= excess;
+            }
+            self.series.insert(key, &(length as u64).to_be_bytes())?;
+            Ok(())
+        }
+
+        fn keys(&self) -> io::Result<Vec<AggregatedKey>> {
+            let mut keys = vec![];
+            for key in self.series.iter().keys() {
+                let key = key?;
+                keys.push(decode_key(&key).ok_or_else(|| invalid("invalid series key"))?);
+            }
+            Ok(keys)
+        }
+
+        fn get(&self, key: &AggregatedKey) -> io::Result<Option<Vec<Timeseries>>> {
+            let points = self.scan(&prefix(&encode_key(key)))?;
+            Ok(if points.is_empty() { None } else { Some(points) })
+        }
+
+        fn last(&self, key: &AggregatedKey) -> io::Result<Option<Timeseries>> {
+            let prefix = prefix(&encode_key(key));
+            self.points.scan_prefix(&prefix).next_back().map(|entry| decode_point(&prefix, entry?)).transpose()
+        }
+
+        fn range(&self, key: &AggregatedKey, start: SystemTime, end: SystemTime) -> io::Result<Vec<Timeseries>> {
+            let prefix = prefix(&encode_key(key));
+            if end <= start {
+                return Ok(vec![])
+            }
+            self.points.range(point_key(&prefix, start)..point_key(&prefix, end))
+                .map(|entry| decode_point(&prefix, entry?))
+                .collect()
+        }
+
+        fn set(&mut self, key: AggregatedKey, points: Vec<Timeseries>) -> io::Result<()> {
+            let key = encode_key(&key);
+            let prefix = prefix(&key);
+            for entry in self.points.scan_prefix(&prefix).keys() {
+                self.points.remove(entry?)?;
+            }
+            if points.is_empty() {
+                self.series.remove(key)?;
+                return Ok(())
+            }
+            for &point in &points {
+                self.insert(&prefix, point)?;
+            }
+            self.series.insert(key, &(points.len() as u64).to_be_bytes())?;
+            Ok(())
+        }
+
+        fn counts(&self) -> io::Result<(usize, usize)> {
+            let (mut series, mut points) = (0, 0);
+            for length in self.series.iter().values() {
+                series += 1;
+                points += decode_u64(&length?).ok_or_else(|| invalid("invalid series length"))? as usize;
+            }
+            Ok((series, points))
+        }
+    }
+
+    fn push_str(buf: &mut Vec<u8>, string: &str) {
+        buf.extend_from_slice(&(string.len() as u32).to_be_bytes());
+        buf.extend_from_slice(string.as_bytes());
+    }
+
+    fn encode_key(key: &AggregatedKey) -> Vec<u8> {
+        let (kind, id) = match *key {
+            AggregatedKey::Count(ref id) => (b'C', id),
+            AggregatedKey::Gauge(ref id) => (b'G', id),
+        };
+        let mut buf = vec![kind];
+        push_str(&mut buf, id.name());
+        for (k, v) in id.dimensions() {
+            push_str(&mut buf, k);
+            push_str(&mut buf, v);
+        }
+        buf
+    }
+
+    fn decode_key(buf: &[u8]) -> Option<AggregatedKey> {
+        let mut strings = vec![];
+        let mut rest = buf.get(1..)?;
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes([*rest.first()?, *rest.get(1)?, *rest.get(2)?, *rest.get(3)?]) as usize;
+            let string = rest.get(4..4 + length)?;
+            strings.push(String::from_utf8(string.to_vec()).ok()?);
+            rest = &rest[4 + length..];
+        }
+
+        let mut strings = strings.into_iter();
+        let name = strings.next()?;
+        let mut dimensions = vec![];
+        while let Some(k) = strings.next() {
+            dimensions.push((Atom::from(k), Atom::from(strings.next()?)));
+        }
+        let id = MetricId::new(name, dimensions);
+        match buf[0] {
+            b'C' => Some(AggregatedKey::Count(id)),
+            b'G' => Some(AggregatedKey::Gauge(id)),
+            _ => None,
+        }
+    }
+
+    /// Length-prefixed so that no series' points are a prefix scan of
+    /// another's, eg. of the same name with more dimensions.
+    fn prefix(key: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(key.len() + 4);
+        buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        buf.extend_from_slice(key);
+        buf
+    }
+
+    /// Without the sequence number, so it sorts before every point at `time`.
+    fn point_key(prefix: &[u8], time: SystemTime) -> Vec<u8> {
+        let nanos = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let mut buf = Vec::with_capacity(prefix.len() + 16);
+        buf.extend_from_slice(prefix);
+        buf.extend_from_slice(&nanos.to_be_bytes());
+        buf
+    }
+
+    fn decode_point(prefix: &[u8], (key, value): (sled::IVec, sled::IVec)) -> io::Result<Timeseries> {
+        let nanos = key.get(prefix.len()..prefix.len() + 8).and_then(decode_u64);
+        match (nanos, decode_u64(&value)) {
+            (Some(nanos), Some(bits)) => Ok((UNIX_EPOCH + Duration::from_nanos(nanos), f64::from_bits(bits))),
+            _ => Err(invalid("invalid series point")),
+        }
+    }
+
+    fn decode_u64(buf: &[u8]) -> Option<u64> {
+        let mut bytes = [0; 8];
+        if buf.len() != 8 {
+            return None
+        }
+        bytes.copy_from_slice(buf);
+        Some(u64::from_be_bytes(bytes))
+    }
+
+    fn invalid(error: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn it_ranges_over_late_corrections() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let key = AggregatedKey::Count(MetricId::from("foo"));
+        let mut storage = MemoryStorage::new();
+        for &(secs, value) in &[(10, 1.0), (20, 2.0), (30, 3.0), (15, 4.0)] {
+            storage.push(key.clone(), (at(secs), value), None).unwrap();
+        }
+        assert_eq!(storage.range(&key, at(12), at(25)).unwrap(), vec![(at(15), 4.0), (at(20), 2.0)]);
+        assert_eq!(storage.last(&key).unwrap(), Some((at(30), 3.0)));
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn it_orders_points_the_same_in_both_backends() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let key = AggregatedKey::Count(MetricId::from("foo"));
+        let other = AggregatedKey::Count(MetricId::from("foo").with_dimension("host", "a"));
+        let path = std::env::temp_dir().join(format!("metriqs-sled-order-{}", std::process::id()));
+        let mut memory = MemoryStorage::new();
+        let mut sled = SledStorage::open(&path).unwrap();
+        for storage in &mut [&mut memory as &mut dyn Storage, &mut sled] {
+            for &(secs, value) in &[(10, 1.0), (20, 2.0), (30, 3.0), (15, 4.0), (20, 5.0)] {
+                storage.push(key.clone(), (at(secs), value), Some(4)).unwrap();
+            }
+            storage.push(other.clone(), (at(5), 6.0), None).unwrap();
+        }
+        for storage in &[&memory as &dyn Storage, &sled] {
+            assert_eq!(storage.get(&key).unwrap().unwrap(), vec![(at(15), 4.0), (at(20), 2.0), (at(20), 5.0), (at(30), 3.0)]);
+            assert_eq!(storage.last(&key).unwrap(), Some((at(30), 3.0)));
+            assert_eq!(storage.range(&key, at(12), at(30)).unwrap(), vec![(at(15), 4.0), (at(20), 2.0), (at(20), 5.0)]);
+            assert_eq!(storage.counts().unwrap(), (2, 5));
+        }
+        drop(sled);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
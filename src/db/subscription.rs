@@ -0,0 +1,285 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use string_cache::DefaultAtom as Atom;
+
+use super::super::metric::MetricId;
+use super::super::util::{lock, Glob};
+use super::aggregate::AggregatedMetric;
+
+/// Identifies a subscription so that it can be cancelled with
+/// `Db::unsubscribe`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct SubscriptionToken(pub(super) u64);
+
+/// Which aggregated metrics a subscriber receives. A metric matches if its
+/// name matches any of `names` (or `names` is empty) and its dimensions
+/// match every one of `dimensions`.
+#[derive(Clone, Debug, Default)]
+pub struct SubscriptionFilter {
+    pub names: Vec<Glob>,
+    /// Dimension keys and patterns their values must match; a metric
+    /// without the key doesn't match.
+    pub dimensions: Vec<(Atom, Glob)>,
+}
+
+impl SubscriptionFilter {
+    pub fn matches(&self, id: &MetricId) -> bool {
+        let name_matches = self.names.is_empty() || self.names.iter().any(|pattern| pattern.matches(id.name()));
+        name_matches && self.dimensions.iter().all(|(key, pattern)| {
+            id.dimension(key).is_some_and(|value| pattern.matches(value))
+        })
+    }
+}
+
+/// Called with every aggregation a Db publishes, as an alternative to
+/// receiving them on a channel; see `Db::register_subscriber`.
+pub trait Subscriber: Send + Sync {
+    fn on_flush(&self, batch: &Arc<Vec<AggregatedMetric>>);
+}
+
+/// Which thread a `Subscriber` is called on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dispatch {
+    /// The aggregating thread, once the aggregation has been published to
+    /// every subscriber. A slow subscriber delays the next aggregation.
+    Inline,
+    /// A thread of the subscriber's own, with at most this many batches
+    /// waiting for it; further batches are dropped until it catches up, and
+    /// counted by a `metriqs.subscriptions.dropped` count.
+    Thread(usize),
+}
+
+/// Bounds how many aggregations can wait for a channel subscriber, so a
+/// stuck one can't grow memory without limit. Batches the subscriber
+/// hasn't received yet are buffered by the Db, plus one being handed over.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SubscriptionBuffer {
+    /// At least one.
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+/// What happens to an aggregation published to a full buffer. Dropped and
+/// coalesced batches are counted by a `metriqs.subscriptions.dropped`
+/// count.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Drop the oldest buffered batch to make room.
+    DropOldest,
+    /// Merge it into the newest buffered batch, so nothing is lost but the
+    /// subscriber receives several aggregations as one.
+    Coalesce,
+    /// Wait up to this long for the subscriber to make room, holding up
+    /// the aggregating thread, and then drop it.
+    Block(Duration),
+}
+
+/// How a subscription's aggregations reach it.
+pub(super) enum Delivery {
+    Channel(Sender<Arc<Vec<AggregatedMetric>>>),
+    Buffered(Buffered),
+    Inline(Arc<dyn Subscriber>),
+    Thread(SyncSender<Arc<Vec<AggregatedMetric>>>),
+}
+
+impl Delivery {
+    /// Start the subscriber's thread if it has one. The thread exits once
+    /// the returned delivery is dropped.
+    pub(super) fn new(subscriber: Arc<dyn Subscriber>, dispatch: Dispatch) -> Delivery {
+        match dispatch {
+            Dispatch::Inline => Delivery::Inline(subscriber),
+            Dispatch::Thread(max_pending) => {
+                let (send, recv) = sync_channel(max_pending);
+                thread::spawn(move || dispatch_to(&*subscriber, recv));
+                Delivery::Thread(send)
+            },
+        }
+    }
+}
+
+fn dispatch_to(subscriber: &dyn Subscriber, recv: Receiver<Arc<Vec<AggregatedMetric>>>) {
+    for batch in recv {
+        subscriber.on_flush(&batch)
+    }
+}
+
+/// Hand `batch` to a thread-dispatched subscriber, counting it in `dropped`
+/// if it can't keep up. Returns whether it's still subscribed, which it
+/// isn't once its thread has gone.
+pub(super) fn try_dispatch(send: &SyncSender<Arc<Vec<AggregatedMetric>>>, batch: Arc<Vec<AggregatedMetric>>, dropped: &AtomicUsize) -> bool {
+    match send.try_send(batch) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            // Only the first drop since the count was last published is
+            // logged, so that a stuck subscriber doesn't flood the log.
+            if dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                warn!("Dropping an aggregation for a subscriber that can't keep up");
+            }
+            true
+        },
+        Err(TrySendError::Disconnected(_)) => false,
+    }
+}
+
+/// A bounded subscription's buffer, which a thread of its own hands over
+/// to the subscriber's channel.
+pub(super) struct Buffered(Arc<Buffer>);
+
+struct Buffer {
+    options: SubscriptionBuffer,
+    state: Mutex<BufferState>,
+    /// Signalled when a batch is buffered or taken, or it's closed.
+    changed: Condvar,
+}
+
+struct BufferState {
+    batches: VecDeque<Arc<Vec<AggregatedMetric>>>,
+    /// Set once either the subscriber or the Db has gone.
+    closed: bool,
+}
+
+impl Buffered {
+    pub(super) fn new(options: SubscriptionBuffer) -> (Buffered, Receiver<Arc<Vec<AggregatedMetric>>>) {
+        let buffer = Arc::new(Buffer {
+            options: SubscriptionBuffer { capacity: options.capacity.max(1), ..options },
+            state: Mutex::new(BufferState { batches: VecDeque::new(), closed: false }),
+            changed: Condvar::new(),
+        });
+        // Handing over on a rendezvous channel keeps batches in the buffer,
+        // where the overflow policy can get at them, until the subscriber
+        // is ready for them.
+        let (send, recv) = sync_channel(0);
+        {
+            let buffer = buffer.clone();
+            thread::spawn(move || buffer.hand_over(&send));
+        }
+        (Buffered(buffer), recv)
+    }
+
+    /// Buffer `batch`, counting any batch dropped or coalesced into
+    /// `dropped`. Returns whether the subscriber is still there.
+    pub(super) fn publish(&self, batch: Arc<Vec<AggregatedMetric>>, dropped: &AtomicUsize) -> bool {
+        let buffer = &self.0;
+        let mut state = lock(&buffer.state);
+        if state.closed {
+            return false
+        }
+        let capacity = buffer.options.capacity;
+        if state.batches.len() >= capacity {
+            match buffer.options.overflow {
+                Overflow::DropOldest => {
+                    state.batches.pop_front();
+                    dropped.fetch_add(1, Ordering::Relaxed);
+                },
+                Overflow::Coalesce => {
+                    if let Some(newest) = state.batches.back_mut() {
+                        *newest = Arc::new(newest.iter().chain(batch.iter()).cloned().collect());
+                    }
+                    dropped.fetch_add(1, Ordering::Relaxed);
+                    return true
+                },
+                Overflow::Block(timeout) => {
+                    state = buffer.changed.wait_timeout_while(state, timeout, |state| {
+                        state.batches.len() >= capacity && !state.closed
+                    }).unwrap().0;
+                    if state.closed {
+                        return false
+                    }
+                    if state.batches.len() >= capacity {
+                        dropped.fetch_add(1, Ordering::Relaxed);
+                        return true
+                    }
+                },
+            }
+        }
+        state.batches.push_back(batch);
+        buffer.changed.notify_all();
+        true
+    }
+}
+
+/// Unsubscribing closes the buffer; what's buffered is still handed over.
+impl Drop for Buffered {
+    fn drop(&mut self) {
+        lock(&self.0.state).closed = true;
+        self.0.changed.notify_all();
+    }
+}
+
+impl Buffer {
+    fn hand_over(&self, send: &SyncSender<Arc<Vec<AggregatedMetric>>>) {
+        loop {
+            let batch = {
+                let mut state = self.changed.wait_while(lock(&self.state), |state| {
+                    state.batches.is_empty() && !state.closed
+                }).unwrap();
+                match state.batches.pop_front() {
+                    Some(batch) => batch,
+                    None => return,
+                }
+            };
+            self.changed.notify_all();
+            if send.send(batch).is_err() {
+                lock(&self.state).closed = true;
+                self.changed.notify_all();
+                return
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn it_applies_overflow_policies() {
+        let batch = |value| Arc::new(vec![AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("foo"), value)]);
+        for &(overflow, ref expected) in &[
+            (Overflow::DropOldest, vec![vec![1], vec![3]]),
+            (Overflow::Coalesce, vec![vec![1], vec![2, 3]]),
+            (Overflow::Block(Duration::from_millis(10)), vec![vec![1], vec![2]]),
+        ] {
+            let dropped = AtomicUsize::new(0);
+            let (buffered, recv) = Buffered::new(SubscriptionBuffer { capacity: 1, overflow });
+            assert!(buffered.publish(batch(1), &dropped));
+            // Wait for the first batch to be taken for handing over.
+            while !lock(&buffered.0.state).batches.is_empty() {
+                thread::yield_now()
+            }
+            assert!(buffered.publish(batch(2), &dropped));
+            assert!(buffered.publish(batch(3), &dropped));
+            assert_eq!(dropped.load(Ordering::Relaxed), 1);
+
+            drop(buffered);
+            let received = recv.iter()
+                .map(|batch| batch.iter().map(|metric| match *metric {
+                    AggregatedMetric::Count(_, _, value) => value,
+                    _ => unreachable!(),
+                }).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            assert_eq!(received, *expected);
+        }
+    }
+
+    #[test]
+    fn it_matches_names_and_dimensions() {
+        let filter = SubscriptionFilter {
+            names: vec![Glob::new("http.*"), Glob::new("grpc.*")],
+            dimensions: vec![("env".into(), Glob::new("prod*"))],
+        };
+        let id = MetricId::from("http.requests");
+        assert!(!filter.matches(&id));
+        assert!(filter.matches(&id.with_dimension("env", "production")));
+        assert!(!filter.matches(&id.with_dimension("env", "staging")));
+        assert!(!filter.matches(&MetricId::from("db.queries").with_dimension("env", "prod")));
+        assert!(SubscriptionFilter::default().matches(&id));
+    }
+}
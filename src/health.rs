@@ -0,0 +1,222 @@
+//! `/healthz` and `/readyz` endpoints for load balancers and Kubernetes
+//! probes.
+//!
+//! `/healthz` is OK while the Db's aggregation loop is running: it isn't
+//! shut down and the last aggregation isn't overdue. `/readyz` also needs
+//! every listener and exporter added to the `Health` to still be running,
+//! eg. a listener whose socket couldn't be bound returns right away, and
+//! each exporter's backlog of undelivered batches to be within
+//! `max_backlog`. Either responds 503 otherwise, and both list the status
+//! of what they checked:
+//!
+//! ```text
+//! aggregation: ok
+//! listener[0]: ok
+//! graphite: backlog of 12 batches (at most 10)
+//! ```
+
+use std::io;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use super::db::Db;
+use super::util::Stop;
+use super::util::http::{self, Response};
+
+/// How late an aggregation may be, beyond two intervals, before the Db
+/// isn't considered live.
+const AGGREGATION_SLACK: Duration = Duration::from_secs(1);
+
+/// The listeners and exporters being checked. Clones share the same ones.
+#[derive(Clone, Debug, Default)]
+pub struct Health {
+    probes: Arc<Mutex<Vec<Arc<Probe>>>>,
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health::default()
+    }
+
+    pub fn add(&self, probe: &Arc<Probe>) {
+        self.probes.lock().unwrap().push(probe.clone());
+    }
+
+    /// Stop checking `probe`, eg. because its listener was stopped on
+    /// purpose.
+    pub fn remove(&self, probe: &Arc<Probe>) {
+        self.probes.lock().unwrap().retain(|other| !Arc::ptr_eq(other, probe));
+    }
+
+    fn probes(&self) -> Vec<Arc<Probe>> {
+        self.probes.lock().unwrap().clone()
+    }
+}
+
+/// The status of one listener or exporter.
+#[derive(Debug)]
+pub struct Probe {
+    name: String,
+    running: AtomicBool,
+    backlog: Arc<AtomicUsize>,
+}
+
+impl Probe {
+    /// A running probe with an empty backlog.
+    pub fn new<S: Into<String>>(name: S) -> Arc<Probe> {
+        Arc::new(Probe {
+            name: name.into(),
+            running: AtomicBool::new(true),
+            backlog: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Undelivered batches, eg. for `DeliveryOptions::backlog` to keep up
+    /// to date.
+    pub fn backlog(&self) -> &Arc<AtomicUsize> {
+        &self.backlog
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Run `work`, after which (even if it panics) the probe is no longer
+    /// running.
+    pub fn run<F>(&self, work: F)
+        where F: FnOnce()
+    {
+        let _exited = Exited(self);
+        work()
+    }
+}
+
+struct Exited<'a>(&'a Probe);
+
+impl<'a> Drop for Exited<'a> {
+    fn drop(&mut self) {
+        self.0.running.store(false, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub struct HealthOptions {
+    /// Exporters with more undelivered batches than this aren't ready;
+    /// defaults to 10.
+    pub max_backlog: Option<usize>,
+}
+
+pub struct HealthServer {
+    db: Arc<Db>,
+    health: Health,
+    max_backlog: usize,
+}
+
+impl HealthServer {
+    pub fn new(db: Arc<Db>, health: Health, options: HealthOptions) -> HealthServer {
+        HealthServer {
+            db,
+            health,
+            max_backlog: options.max_backlog.unwrap_or(10),
+        }
+    }
+
+    /// Serve `/healthz` and `/readyz` on `listener` until `stop` is stopped.
+    pub fn serve(self, listener: &TcpListener, stop: &Stop) -> io::Result<()> {
+        let server = Arc::new(self);
+        http::serve(listener, stop, move |method, target| server.handle(method, target))
+    }
+
+    fn handle(&self, method: &str, target: &str) -> Response {
+        let path = target.split('?').next().unwrap_or(target);
+        match (method, path) {
+            ("GET", "/healthz") => self.live(),
+            ("GET", "/readyz") => self.ready(),
+            (_, "/healthz") | (_, "/readyz") => Response::text(405, "Method Not Allowed\n"),
+            _ => Response::text(404, "Not Found\n"),
+        }
+    }
+
+    pub fn live(&self) -> Response {
+        let aggregation = self.aggregation();
+        respond(vec![("aggregation".to_string(), aggregation)])
+    }
+
+    pub fn ready(&self) -> Response {
+        let mut statuses = vec![("aggregation".to_string(), self.aggregation())];
+        for probe in self.health.probes() {
+            let backlog = probe.backlog.load(Ordering::Relaxed);
+            let status = if !probe.is_running() {
+                Err("not running".to_string())
+            } else if backlog > self.max_backlog {
+                Err(format!("backlog of {} batches (at most {})", backlog, self.max_backlog))
+            } else {
+                Ok(())
+            };
+            statuses.push((probe.name.clone(), status));
+        }
+        respond(statuses)
+    }
+
+    fn aggregation(&self) -> Result<(), String> {
+        if self.db.is_shut_down() {
+            return Err("shut down".to_string())
+        }
+        let since = SystemTime::now().duration_since(self.db.last_aggregation()).unwrap_or_default();
+        if since > self.db.aggregation_interval() * 2 + AGGREGATION_SLACK {
+            return Err(format!("last aggregated {}s ago", since.as_secs()))
+        }
+        Ok(())
+    }
+}
+
+fn respond(statuses: Vec<(String, Result<(), String>)>) -> Response {
+    let mut body = String::new();
+    for (name, status) in &statuses {
+        body.push_str(name);
+        body.push_str(": ");
+        body.push_str(status.as_ref().err().map_or("ok", |err| err.as_str()));
+        body.push('\n');
+    }
+    let ok = statuses.iter().all(|(_, status)| status.is_ok());
+    Response::text(if ok { 200 } else { 503 }, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::db::DbOptions;
+
+    #[test]
+    fn it_is_ready_while_probes_are_running() {
+        let db = Arc::new(Db::new(DbOptions::default()));
+        let health = Health::new();
+        let listener = Probe::new("listener[0]");
+        let exporter = Probe::new("graphite");
+        health.add(&listener);
+        health.add(&exporter);
+        let server = HealthServer::new(db.clone(), health.clone(), HealthOptions { max_backlog: Some(1) });
+        assert_eq!(server.ready(), Response::text(200, "aggregation: ok\nlistener[0]: ok\ngraphite: ok\n"));
+
+        exporter.backlog().store(2, Ordering::Relaxed);
+        listener.run(|| ());
+        assert_eq!(
+            server.ready(),
+            Response::text(503, "aggregation: ok\nlistener[0]: not running\ngraphite: backlog of 2 batches (at most 1)\n"),
+        );
+        assert_eq!(server.live().status, 200);
+
+        health.remove(&listener);
+        exporter.backlog().store(0, Ordering::Relaxed);
+        assert_eq!(server.ready().status, 200);
+        db.shutdown();
+        assert_eq!(server.live(), Response::text(503, "aggregation: shut down\n"));
+    }
+}
@@ -1,14 +1,59 @@
+extern crate crossbeam_queue;
+#[cfg(feature = "flate2")]
+extern crate flate2;
+extern crate hdrhistogram;
+#[cfg(any(feature = "scram", feature = "sigv4"))]
+extern crate hmac;
+#[cfg(feature = "kafka")]
+extern crate kafka;
+#[cfg(unix)]
+extern crate libc;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "metrics")]
+extern crate metrics;
 #[macro_use]
 extern crate nom;
+extern crate regex;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(any(feature = "scram", feature = "sigv4"))]
+extern crate sha2;
+#[cfg(feature = "sled")]
+extern crate sled;
+#[cfg(feature = "snap")]
+extern crate snap;
+#[cfg(feature = "async")]
+extern crate tokio;
 
 extern crate string_cache;
+extern crate toml;
 
+pub mod admin;
+pub mod agent;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[macro_use]
+pub mod client;
+pub mod config;
 pub mod db;
+pub mod health;
 pub mod metric;
+pub mod recorder;
 
 /// How metrics come into the agent.
 pub mod recv;
 
+/// How metrics leave the agent.
+pub mod send;
+
+#[cfg(feature = "serde")]
+mod serialization;
+pub mod supervisor;
+#[cfg(feature = "ui")]
+pub mod ui;
+pub mod util;
+
 #[cfg(test)]
 mod tests {
     #[test]
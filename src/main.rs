@@ -0,0 +1,202 @@
+//! The `metriqs` agent: loads a config file (see `metriqs::config`) and
+//! runs everything it describes, reloading it whenever it changes, until
+//! it gets SIGTERM or SIGINT. It then stops listening, aggregates and
+//! exports what it collected, and exits.
+//!
+//! With `--replay` it instead feeds a StatsD capture (see the `capture`
+//! listener key) through the configured pipeline and exporters, then does
+//! the same. With `--lint` it only runs the StatsD listeners, printing
+//! a report on what they receive (see `Linter`) every minute and when it's
+//! stopped, without aggregating or exporting anything.
+
+#[macro_use]
+extern crate log;
+extern crate metriqs;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use metriqs::agent::Agent;
+use metriqs::config::Config;
+use metriqs::recv::push::statsd::{self, CaptureReader, LintOptions, Linter, ReplayOptions};
+use metriqs::util::{signal, time, Stop};
+
+/// How often the config file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long shutting down may take, within Kubernetes' default grace
+/// period of 30 seconds.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How often `--lint` prints its report.
+const LINT_INTERVAL: Duration = Duration::from_secs(60);
+
+const USAGE: &str = "\
+Usage: metriqs [--config PATH] [--check] [--replay PATH [--replay-speed N]]
+               [--lint] [--log-level LEVEL]
+
+Options:
+    -c, --config PATH       Configuration file; defaults to /etc/metriqs.toml
+        --check             Validate the configuration and exit
+        --replay PATH       Replay a StatsD capture, then shut down
+        --replay-speed N    Replay N times faster than it was captured;
+                            defaults to as fast as possible
+        --lint              Report on what the StatsD listeners receive
+                            instead of aggregating and exporting it
+        --log-level LEVEL   One of error, warn, info (the default), debug,
+                            or trace
+    -h, --help              Print this message
+    -V, --version           Print the version";
+
+/// Writes events to stderr as `<time> <level> <module>: <message>`.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{} {:5} {}: {}", time::iso8601(SystemTime::now()), record.level(), record.target(), record.args())
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+fn fail(message: &str) -> ! {
+    error!("{}", message);
+    process::exit(1)
+}
+
+fn main() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Info);
+
+    let mut config_path = PathBuf::from("/etc/metriqs.toml");
+    let mut check = false;
+    let mut replay = None;
+    let mut replay_options = ReplayOptions::default();
+    let mut lint = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => match args.next() {
+                Some(path) => config_path = PathBuf::from(path),
+                None => fail("--config needs a path"),
+            },
+            "--check" => check = true,
+            "--replay" => match args.next() {
+                Some(path) => replay = Some(PathBuf::from(path)),
+                None => fail("--replay needs a path"),
+            },
+            "--replay-speed" => match args.next().and_then(|speed| speed.parse::<f64>().ok()) {
+                Some(speed) if speed > 0.0 => replay_options.speed = Some(speed),
+                _ => fail("--replay-speed needs a positive number"),
+            },
+            "--lint" => lint = true,
+            "--log-level" => match args.next().and_then(|level| level.parse::<LevelFilter>().ok()) {
+                Some(level) => log::set_max_level(level),
+                None => fail("--log-level needs one of error, warn, info, debug, or trace"),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return
+            },
+            "-V" | "--version" => {
+                println!("metriqs {}", env!("CARGO_PKG_VERSION"));
+                return
+            },
+            _ => fail(&format!("unknown argument `{}`\n\n{}", arg, USAGE)),
+        }
+    }
+
+    let config = Config::load(&config_path).unwrap_or_else(|err| fail(&err));
+    if check {
+        println!("{} is valid", config_path.display());
+        return
+    }
+    if lint {
+        let linter = Linter::new(LintOptions::default());
+        let stop = Stop::new();
+        signal::stop_on_termination(&stop).unwrap_or_else(|err| fail(&format!("couldn't handle signals: {}", err)));
+        let listeners = config.lint(&linter, &stop).unwrap_or_else(|err| fail(&err));
+        info!("Linting with {} listener(s) from {}", listeners.len(), config_path.display());
+        while stop.sleep(LINT_INTERVAL) {
+            println!("{}", linter.report());
+        }
+        for listener in listeners {
+            let _ = listener.join();
+        }
+        println!("{}", linter.report());
+        return
+    }
+
+    let snapshot_path = config.db_options().unwrap_or_else(|err| fail(&err)).snapshot_path;
+    let mut agent = Agent::from_config(config).unwrap_or_else(|err| fail(&err));
+    if let Some(ref path) = snapshot_path {
+        if path.exists() {
+            match agent.db().restore_from(path) {
+                Ok(()) => info!("Restored series from {}", path.display()),
+                Err(err) => warn!("Couldn't restore series from {}: {}", path.display(), err),
+            }
+        }
+    }
+
+    agent.start().unwrap_or_else(|err| fail(&err));
+    if let Some(ref path) = replay {
+        let replayed = CaptureReader::open(path)
+            .and_then(|capture| statsd::replay(capture, &agent.db().collector(), replay_options))
+            .unwrap_or_else(|err| fail(&format!("couldn't replay {}: {}", path.display(), err)));
+        info!("Replayed {} payload(s) from {}", replayed, path.display());
+        if !agent.shutdown_timeout(SHUTDOWN_TIMEOUT) {
+            fail(&format!("Gave up shutting down after {} seconds", SHUTDOWN_TIMEOUT.as_secs()))
+        }
+        return
+    }
+    let stop = agent.stopper().clone();
+    signal::stop_on_termination(&stop).unwrap_or_else(|err| fail(&format!("couldn't handle signals: {}", err)));
+    info!("Started {} from {}", running(&agent), config_path.display());
+
+    // Reload whenever the file changes. A config that doesn't parse or
+    // validate is logged and the running one is kept.
+    let mut modified = modified_time(&config_path);
+    while stop.sleep(WATCH_INTERVAL) {
+        let current = modified_time(&config_path);
+        if current == modified {
+            continue
+        }
+        modified = current;
+
+        match Config::load(&config_path).and_then(|config| agent.reload(config)) {
+            Ok(restart) => {
+                info!("Reloaded {}: {}", config_path.display(), running(&agent));
+                if !restart.is_empty() {
+                    warn!("Restart to apply the changes to {}", restart.join(", "));
+                }
+            },
+            Err(err) => error!("Keeping the running configuration: {}", err),
+        }
+    }
+
+    if !agent.shutdown_timeout(SHUTDOWN_TIMEOUT) {
+        fail(&format!("Gave up shutting down after {} seconds", SHUTDOWN_TIMEOUT.as_secs()))
+    }
+    info!("Shut down");
+}
+
+fn running(agent: &Agent) -> String {
+    let (listeners, exporters) = agent.workers().map_or((0, 0), |workers| (workers.listeners(), workers.exporters()));
+    format!("{} listener(s) and {} exporter(s)", listeners, exporters)
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
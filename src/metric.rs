@@ -1,3 +1,9 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, LazyLock, Mutex, Weak};
 use std::time::{SystemTime};
 
 use string_cache::DefaultAtom as Atom;
@@ -15,14 +21,363 @@ use string_cache::DefaultAtom as Atom;
 //     StatsD: Timer
 //     Datadog: Histogram
 //     Prometheus: Histogram
+//   Set:
+//     StatsD: Set
+//     Datadog: Set
 
 pub type Dimension = (Atom, Atom);
 
-pub type Id = (Atom, Vec<Dimension>);
+/// Identifies a series by its name and dimensions. Dimensions are sorted
+/// by key on construction (so `{a=1,b=2}` and `{b=2,a=1}` are the same
+/// series), keys are unique, and the hash is computed once up front since
+/// identifiers are hashed repeatedly during grouping and storage.
+///
+/// Dimension sets are interned, so cloning an identifier doesn't allocate
+/// and comparing two compares their names' and sets' pointers.
+#[derive(Clone, Debug)]
+pub struct MetricId {
+    name: Atom,
+    dimensions: Dimensions,
+    hash: u64,
+}
+
+impl MetricId {
+    /// When `dimensions` contains the same key more than once the last
+    /// value wins.
+    pub fn new<N: Into<Atom>>(name: N, dimensions: Vec<Dimension>) -> MetricId {
+        let mut unique: Vec<Dimension> = Vec::with_capacity(dimensions.len());
+        for (key, value) in dimensions {
+            match unique.iter_mut().find(|dimension| dimension.0 == key) {
+                Some(existing) => existing.1 = value,
+                None => unique.push((key, value)),
+            }
+        }
+        unique.sort_by(|a, b| a.0.cmp(&b.0));
+
+        MetricId::from_sorted(name.into(), unique)
+    }
+
+    fn from_sorted(name: Atom, dimensions: Vec<Dimension>) -> MetricId {
+        MetricId::with_dimensions(name, Dimensions::intern(dimensions))
+    }
+
+    fn with_dimensions(name: Atom, dimensions: Dimensions) -> MetricId {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        hasher.write_u64(dimensions.0.hash);
+
+        MetricId {
+            name,
+            dimensions,
+            hash: hasher.finish(),
+        }
+    }
+
+    pub fn name(&self) -> &Atom {
+        &self.name
+    }
+
+    /// Sorted by key.
+    pub fn dimensions(&self) -> &[Dimension] {
+        &self.dimensions.0.dimensions
+    }
+
+    pub fn dimension(&self, key: &str) -> Option<&Atom> {
+        self.dimensions().iter()
+            .find(|dimension| &*dimension.0 == key)
+            .map(|dimension| &dimension.1)
+    }
+
+    /// Which of `shards` partitions this identifier belongs to.
+    pub fn shard(&self, shards: usize) -> usize {
+        (self.hash % shards.max(1) as u64) as usize
+    }
+
+    /// Copy with a different name.
+    pub fn with_name<N: Into<Atom>>(&self, name: N) -> MetricId {
+        MetricId::with_dimensions(name.into(), self.dimensions.clone())
+    }
+
+    /// Copy with a suffix appended to the name, eg. `.count`.
+    pub fn with_suffix<S: AsRef<str>>(&self, suffix: S) -> MetricId {
+        self.with_name(format!("{}{}", &*self.name, suffix.as_ref()))
+    }
+
+    /// Copy with a dimension added, replacing any existing value for `key`.
+    pub fn with_dimension<K: Into<Atom>, V: Into<Atom>>(&self, key: K, value: V) -> MetricId {
+        let mut dimensions = self.dimensions().to_vec();
+        dimensions.push((key.into(), value.into()));
+        MetricId::new(self.name.clone(), dimensions)
+    }
+
+    /// Copy with each of `defaults` added unless a dimension with the same
+    /// key is already present.
+    pub fn with_defaults(&self, defaults: &[Dimension]) -> MetricId {
+        let mut dimensions = self.dimensions().to_vec();
+        for default in defaults {
+            if self.dimension(&default.0).is_none() {
+                dimensions.push(default.clone())
+            }
+        }
+        MetricId::new(self.name.clone(), dimensions)
+    }
+
+    /// Copy with the dimension for `key` removed.
+    pub fn without_dimension(&self, key: &str) -> MetricId {
+        let dimensions = self.dimensions().iter()
+            .filter(|dimension| &*dimension.0 != key)
+            .cloned()
+            .collect();
+        MetricId::from_sorted(self.name.clone(), dimensions)
+    }
+}
+
+impl PartialEq for MetricId {
+    fn eq(&self, other: &MetricId) -> bool {
+        self.hash == other.hash && self.name == other.name && Arc::ptr_eq(&self.dimensions.0, &other.dimensions.0)
+    }
+}
+
+impl Eq for MetricId {}
+
+impl Hash for MetricId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash)
+    }
+}
+
+impl PartialOrd for MetricId {
+    fn partial_cmp(&self, other: &MetricId) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MetricId {
+    /// By name and then dimensions, so that sorted identifiers read
+    /// naturally.
+    fn cmp(&self, other: &MetricId) -> Ordering {
+        self.name.cmp(&other.name)
+            .then_with(|| self.dimensions().cmp(other.dimensions()))
+    }
+}
+
+impl<N: Into<Atom>> From<N> for MetricId {
+    fn from(name: N) -> MetricId {
+        MetricId::new(name, vec![])
+    }
+}
+
+/// An interned, sorted set of dimensions. While a set is alive, every
+/// other equal set is the same allocation.
+#[derive(Clone)]
+struct Dimensions(Arc<InternedDimensions>);
 
-#[derive(Debug, PartialEq)]
+struct InternedDimensions {
+    dimensions: Vec<Dimension>,
+    hash: u64,
+}
+
+/// Interned sets are partitioned by hash so that identifiers created on
+/// different threads rarely wait on each other.
+const INTERNER_SHARDS: usize = 16;
+
+/// Sets by hash. Sets are weakly referenced so that they're freed once no
+/// identifier uses them; dead references are swept as the shard grows.
+struct InternerShard {
+    sets: HashMap<u64, Vec<Weak<InternedDimensions>>>,
+    /// Number of hashes after the last sweep.
+    swept: usize,
+}
+
+static INTERNER: LazyLock<Vec<Mutex<InternerShard>>> = LazyLock::new(|| {
+    (0..INTERNER_SHARDS).map(|_| Mutex::new(InternerShard { sets: HashMap::new(), swept: 0 })).collect()
+});
+
+/// Most identifiers don't have any dimensions, so they share one set
+/// without going through the interner.
+static EMPTY: LazyLock<Dimensions> = LazyLock::new(|| {
+    Dimensions(Arc::new(InternedDimensions { dimensions: vec![], hash: 0 }))
+});
+
+impl Dimensions {
+    /// `dimensions` must already be sorted and unique.
+    fn intern(dimensions: Vec<Dimension>) -> Dimensions {
+        if dimensions.is_empty() {
+            return EMPTY.clone()
+        }
+        let mut hasher = DefaultHasher::new();
+        dimensions.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let mut shard = INTERNER[(hash % INTERNER_SHARDS as u64) as usize].lock().unwrap();
+        if shard.sets.len() > (shard.swept * 2).max(1024) {
+            shard.sets.retain(|_, sets| {
+                sets.retain(|set| set.strong_count() > 0);
+                !sets.is_empty()
+            });
+            shard.swept = shard.sets.len();
+        }
+        let sets = shard.sets.entry(hash).or_default();
+        sets.retain(|set| set.strong_count() > 0);
+        if let Some(existing) = sets.iter().filter_map(Weak::upgrade).find(|set| set.dimensions == dimensions) {
+            return Dimensions(existing)
+        }
+        let interned = Arc::new(InternedDimensions { dimensions, hash });
+        sets.push(Arc::downgrade(&interned));
+        Dimensions(interned)
+    }
+}
+
+impl fmt::Debug for Dimensions {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.0.dimensions.fmt(fmt)
+    }
+}
+
+/// An already-summarized distribution, eg. a Prometheus summary or an OTLP
+/// histogram, covering the samples observed during one reporting period.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub count: u64,
+    pub sum: f64,
+    /// Quantile (between 0 and 1) and its value, sorted by quantile.
+    pub quantiles: Vec<(f64, f64)>,
+}
+
+impl Summary {
+    /// Merge another summary of the same series into this one. Counts and
+    /// sums are exact; quantiles can't be merged exactly so each is the
+    /// count-weighted mean of the two, and quantiles that aren't present in
+    /// both are dropped.
+    pub fn merge(&mut self, other: &Summary) {
+        let total = self.count + other.count;
+        let quantiles = self.quantiles.iter()
+            .filter_map(|&(quantile, value)| {
+                other.quantiles.iter()
+                    .find(|&&(other_quantile, _)| other_quantile == quantile)
+                    .map(|&(_, other_value)| {
+                        let merged = if total == 0 {
+                            (value + other_value) / 2.0
+                        } else {
+                            (value * self.count as f64 + other_value * other.count as f64) / total as f64
+                        };
+                        (quantile, merged)
+                    })
+            })
+            .collect();
+
+        self.count = total;
+        self.sum += other.sum;
+        self.quantiles = quantiles;
+    }
+}
+
+/// Counts are kept integral so that summing many of them can't accumulate
+/// floating-point error; gauges and histogram samples are fractional.
+#[derive(Clone, Debug, PartialEq)]
 pub enum CollectedMetric {
-    Count(SystemTime, Id, i32),
-    Gauge(SystemTime, Id, i32),
-    Histogram(SystemTime, Id, i32),
+    /// A delta, eg. a StatsD counter; summed over the interval.
+    Count(SystemTime, MetricId, i64),
+    /// A cumulative total that only goes up until its source restarts, eg. a
+    /// Prometheus counter. Aggregated into the increase over the interval.
+    MonotonicCount(SystemTime, MetricId, f64),
+    Gauge(SystemTime, MetricId, f64),
+    /// A change to a gauge's current value, eg. a StatsD `+3|g`; turned
+    /// into a `Gauge` of the new value when it's collected.
+    GaugeDelta(SystemTime, MetricId, f64),
+    Histogram(SystemTime, MetricId, f64),
+    /// A histogram sample taken at a rate, eg. a StatsD timer sent with
+    /// `|@0.1`, standing for the last field's worth of samples (`1 / rate`)
+    /// of its value.
+    SampledHistogram(SystemTime, MetricId, f64, f64),
+    /// A member of a set; aggregated into the number of unique members seen
+    /// over the interval.
+    Set(SystemTime, MetricId, String),
+    /// Summaries received during an interval are merged with each other
+    /// rather than being recomputed from raw values.
+    Summary(SystemTime, MetricId, Summary),
+}
+
+impl CollectedMetric {
+    /// When the metric was observed: the timestamp from the wire protocol
+    /// if the sender provided one, otherwise when it was received.
+    pub fn time(&self) -> SystemTime {
+        match *self {
+            CollectedMetric::Count(time, _, _) |
+            CollectedMetric::MonotonicCount(time, _, _) |
+            CollectedMetric::Gauge(time, _, _) |
+            CollectedMetric::GaugeDelta(time, _, _) |
+            CollectedMetric::Histogram(time, _, _) |
+            CollectedMetric::SampledHistogram(time, _, _, _) |
+            CollectedMetric::Set(time, _, _) |
+            CollectedMetric::Summary(time, _, _) => time,
+        }
+    }
+
+    pub fn id(&self) -> &MetricId {
+        match *self {
+            CollectedMetric::Count(_, ref id, _) |
+            CollectedMetric::MonotonicCount(_, ref id, _) |
+            CollectedMetric::Gauge(_, ref id, _) |
+            CollectedMetric::GaugeDelta(_, ref id, _) |
+            CollectedMetric::Histogram(_, ref id, _) |
+            CollectedMetric::SampledHistogram(_, ref id, _, _) |
+            CollectedMetric::Set(_, ref id, _) |
+            CollectedMetric::Summary(_, ref id, _) => id,
+        }
+    }
+
+    pub fn id_mut(&mut self) -> &mut MetricId {
+        match *self {
+            CollectedMetric::Count(_, ref mut id, _) |
+            CollectedMetric::MonotonicCount(_, ref mut id, _) |
+            CollectedMetric::Gauge(_, ref mut id, _) |
+            CollectedMetric::GaugeDelta(_, ref mut id, _) |
+            CollectedMetric::Histogram(_, ref mut id, _) |
+            CollectedMetric::SampledHistogram(_, ref mut id, _, _) |
+            CollectedMetric::Set(_, ref mut id, _) |
+            CollectedMetric::Summary(_, ref mut id, _) => id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dimension(key: &str, value: &str) -> Dimension {
+        (Atom::from(key), Atom::from(value))
+    }
+
+    #[test]
+    fn it_interns_dimension_sets() {
+        let a = MetricId::new("foo", vec![(Atom::from("a"), Atom::from("1"))]);
+        let b = MetricId::from("bar").with_dimension("a", "1");
+        assert!(Arc::ptr_eq(&a.dimensions.0, &b.dimensions.0));
+        assert_eq!(a.with_name("bar"), b);
+        assert_ne!(a, MetricId::from("foo").with_dimension("a", "2"));
+        assert!(Arc::ptr_eq(&MetricId::from("foo").dimensions.0, &EMPTY.0));
+    }
+
+    #[test]
+    fn it_sorts_dimensions() {
+        let a = MetricId::new("foo", vec![dimension("a", "1"), dimension("b", "2")]);
+        let b = MetricId::new("foo", vec![dimension("b", "2"), dimension("a", "1")]);
+        assert_eq!(a, b);
+        assert_eq!(b.dimensions(), &[dimension("a", "1"), dimension("b", "2")][..]);
+    }
+
+    #[test]
+    fn it_keeps_the_last_value_for_duplicate_keys() {
+        let id = MetricId::new("foo", vec![dimension("a", "1"), dimension("a", "2")]);
+        assert_eq!(id.dimensions(), &[dimension("a", "2")][..]);
+        assert_eq!(id.with_dimension("a", "3").dimension("a"), Some(&Atom::from("3")));
+    }
+
+    #[test]
+    fn it_builds_derived_ids() {
+        let id = MetricId::new("foo", vec![dimension("a", "1")]);
+        assert_eq!(id.with_suffix(".count"), MetricId::new("foo.count", vec![dimension("a", "1")]));
+        assert_eq!(id.without_dimension("a"), MetricId::from("foo"));
+    }
 }
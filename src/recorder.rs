@@ -0,0 +1,260 @@
+//! A recorder for the data model of the `metrics` crate's `Recorder`, so
+//! that crates instrumented with it can feed a Db: counters, gauges, and
+//! histograms are registered by name and labels, and their handles push
+//! to a `Collector`.
+//!
+//! - Counter increments are counts, and absolute values are cumulative
+//!   totals aggregated into their increase (see `CollectedMetric`).
+//! - Gauges remember their value per series so that increments and
+//!   decrements apply to it, and each change is recorded as a gauge.
+//! - Histogram samples are histograms.
+//!
+//! Descriptions and units are recorded as metadata for the exporters that
+//! carry them.
+//!
+//! With the `metrics` feature it implements `metrics::Recorder`, so that it
+//! can be installed as the facade's recorder:
+//!
+//! ```ignore
+//! metrics::set_global_recorder(MetriqsRecorder::new(db.collector())).unwrap();
+//! metrics::counter!("requests", "endpoint" => "/users").increment(1);
+//! ```
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use string_cache::DefaultAtom as Atom;
+
+use super::db::{Metadata, Unit};
+use super::metric::{CollectedMetric, MetricId};
+use super::recv::Collector;
+
+pub struct MetriqsRecorder {
+    collector: Arc<Collector>,
+    /// Current values, shared by every handle registered for a series.
+    gauges: Mutex<HashMap<MetricId, Arc<Mutex<f64>>>>,
+}
+
+impl MetriqsRecorder {
+    pub fn new(collector: Collector) -> MetriqsRecorder {
+        MetriqsRecorder {
+            collector: Arc::new(collector),
+            gauges: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn describe(&self, name: &str, unit: Option<Unit>, description: &str) {
+        let help = if description.is_empty() { None } else { Some(description.to_string()) };
+        self.collector.describe(Atom::from(name), Metadata { unit, help })
+    }
+
+    pub fn register_counter(&self, name: &str, labels: &[(&str, &str)]) -> CounterHandle {
+        CounterHandle { collector: self.collector.clone(), id: id(name, labels) }
+    }
+
+    pub fn register_gauge(&self, name: &str, labels: &[(&str, &str)]) -> GaugeHandle {
+        let id = id(name, labels);
+        let value = self.gauges.lock().unwrap().entry(id.clone()).or_default().clone();
+        GaugeHandle { collector: self.collector.clone(), id, value }
+    }
+
+    pub fn register_histogram(&self, name: &str, labels: &[(&str, &str)]) -> HistogramHandle {
+        HistogramHandle { collector: self.collector.clone(), id: id(name, labels) }
+    }
+}
+
+fn id(name: &str, labels: &[(&str, &str)]) -> MetricId {
+    MetricId::new(name, labels.iter().map(|&(key, value)| (Atom::from(key), Atom::from(value))).collect())
+}
+
+#[derive(Clone)]
+pub struct CounterHandle {
+    collector: Arc<Collector>,
+    id: MetricId,
+}
+
+impl CounterHandle {
+    /// Counts are signed 64-bit, so larger increments are capped.
+    pub fn increment(&self, value: u64) {
+        let value = value.min(i64::MAX as u64) as i64;
+        self.collector.push(vec![CollectedMetric::Count(SystemTime::now(), self.id.clone(), value)])
+    }
+
+    /// Record the counter's running total.
+    pub fn absolute(&self, total: u64) {
+        self.collector.push(vec![CollectedMetric::MonotonicCount(SystemTime::now(), self.id.clone(), total as f64)])
+    }
+}
+
+#[derive(Clone)]
+pub struct GaugeHandle {
+    collector: Arc<Collector>,
+    id: MetricId,
+    value: Arc<Mutex<f64>>,
+}
+
+impl GaugeHandle {
+    pub fn increment(&self, delta: f64) {
+        self.update(|value| value + delta)
+    }
+
+    pub fn decrement(&self, delta: f64) {
+        self.update(|value| value - delta)
+    }
+
+    pub fn set(&self, value: f64) {
+        self.update(|_| value)
+    }
+
+    fn update<F>(&self, update: F)
+        where F: FnOnce(f64) -> f64
+    {
+        let mut value = self.value.lock().unwrap();
+        *value = update(*value);
+        self.collector.push(vec![CollectedMetric::Gauge(SystemTime::now(), self.id.clone(), *value)])
+    }
+}
+
+#[derive(Clone)]
+pub struct HistogramHandle {
+    collector: Arc<Collector>,
+    id: MetricId,
+}
+
+impl HistogramHandle {
+    pub fn record(&self, value: f64) {
+        self.collector.push(vec![CollectedMetric::Histogram(SystemTime::now(), self.id.clone(), value)])
+    }
+}
+
+#[cfg(feature = "metrics")]
+mod facade {
+    use std::sync::Arc;
+
+    use metrics::{self, Key, KeyName, Metadata, SharedString};
+
+    use super::super::db::Unit;
+    use super::{CounterHandle, GaugeHandle, HistogramHandle, MetriqsRecorder};
+
+    impl MetriqsRecorder {
+        fn describe_key(&self, key: KeyName, unit: Option<metrics::Unit>, description: SharedString) {
+            self.describe(key.as_str(), unit.map(|unit| Unit::parse(unit.as_str())), &description)
+        }
+    }
+
+    fn labels(key: &Key) -> Vec<(&str, &str)> {
+        key.labels().map(|label| (label.key(), label.value())).collect()
+    }
+
+    impl metrics::Recorder for MetriqsRecorder {
+        fn describe_counter(&self, key: KeyName, unit: Option<metrics::Unit>, description: SharedString) {
+            self.describe_key(key, unit, description)
+        }
+
+        fn describe_gauge(&self, key: KeyName, unit: Option<metrics::Unit>, description: SharedString) {
+            self.describe_key(key, unit, description)
+        }
+
+        fn describe_histogram(&self, key: KeyName, unit: Option<metrics::Unit>, description: SharedString) {
+            self.describe_key(key, unit, description)
+        }
+
+        fn register_counter(&self, key: &Key, _: &Metadata) -> metrics::Counter {
+            metrics::Counter::from_arc(Arc::new(MetriqsRecorder::register_counter(self, key.name(), &labels(key))))
+        }
+
+        fn register_gauge(&self, key: &Key, _: &Metadata) -> metrics::Gauge {
+            metrics::Gauge::from_arc(Arc::new(MetriqsRecorder::register_gauge(self, key.name(), &labels(key))))
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata) -> metrics::Histogram {
+            metrics::Histogram::from_arc(Arc::new(MetriqsRecorder::register_histogram(self, key.name(), &labels(key))))
+        }
+    }
+
+    impl metrics::CounterFn for CounterHandle {
+        fn increment(&self, value: u64) {
+            CounterHandle::increment(self, value)
+        }
+
+        fn absolute(&self, total: u64) {
+            CounterHandle::absolute(self, total)
+        }
+    }
+
+    impl metrics::GaugeFn for GaugeHandle {
+        fn increment(&self, delta: f64) {
+            GaugeHandle::increment(self, delta)
+        }
+
+        fn decrement(&self, delta: f64) {
+            GaugeHandle::decrement(self, delta)
+        }
+
+        fn set(&self, value: f64) {
+            GaugeHandle::set(self, value)
+        }
+    }
+
+    impl metrics::HistogramFn for HistogramHandle {
+        fn record(&self, value: f64) {
+            HistogramHandle::record(self, value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::db::{AggregatedMetric, Db, DbOptions, GaugeAggregation};
+
+    #[test]
+    fn it_shares_gauge_values_between_handles() {
+        let db = Db::new(DbOptions { gauge_aggregation: Some(GaugeAggregation::Last), ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+        let recorder = MetriqsRecorder::new(db.collector());
+
+        recorder.register_gauge("connections", &[("pool", "a")]).increment(3.0);
+        recorder.register_gauge("connections", &[("pool", "a")]).decrement(1.0);
+        recorder.register_counter("requests", &[]).increment(2);
+        recorder.describe("connections", None, "Open connections");
+
+        db.aggregate(None);
+        let aggregated = subscription.recv().unwrap();
+        let connections = MetricId::from("connections").with_dimension("pool", "a");
+        assert!(aggregated.iter().any(|metric| match *metric {
+            AggregatedMetric::Gauge(_, ref id, value) => *id == connections && value == 2.0,
+            _ => false,
+        }));
+        assert!(aggregated.iter().any(|metric| match *metric {
+            AggregatedMetric::Count(_, ref id, 2) => *id == MetricId::from("requests"),
+            _ => false,
+        }));
+        assert_eq!(db.metadata().get(&Atom::from("connections")).and_then(|metadata| metadata.help), Some("Open connections".to_string()));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn it_records_from_the_metrics_facade() {
+        let db = Db::new(DbOptions::default());
+        let subscription = db.aggregation_subscribe();
+        // The only test that installs a global recorder.
+        metrics::set_global_recorder(MetriqsRecorder::new(db.collector())).unwrap();
+
+        metrics::counter!("requests", "endpoint" => "/users").increment(2);
+        metrics::histogram!("latency").record(12.0);
+        metrics::describe_counter!("requests", metrics::Unit::Count, "Requests served");
+
+        db.aggregate(None);
+        let aggregated = subscription.recv().unwrap();
+        let requests = MetricId::from("requests").with_dimension("endpoint", "/users");
+        assert!(aggregated.iter().any(|metric| match *metric {
+            AggregatedMetric::Count(_, ref id, 2) => *id == requests,
+            _ => false,
+        }));
+        assert!(aggregated.iter().any(|metric| metric.id().name().starts_with("latency")));
+        assert_eq!(db.metadata().get(&Atom::from("requests")).and_then(|metadata| metadata.help), Some("Requests served".to_string()));
+    }
+}
@@ -0,0 +1,269 @@
+//! Forwarding to a cluster of peer agents, so that every sample of a
+//! series is aggregated by the same agent however many receive it.
+//!
+//! Peers are placed on a consistent-hash ring, each at a number of virtual
+//! points, and each metric belongs to the first peer at or after its
+//! identifier's hash. Metrics this agent owns go on through the pipeline
+//! as if there were no cluster; the rest are forwarded (see `forward`),
+//! before any other stage, to their owner's StatsD listener, which applies
+//! its own pipeline. Forwarded metrics carry the reserved `FORWARDED` tag,
+//! which the receiving peer strips, and are aggregated by whoever receives
+//! them without being forwarded again: peers whose members disagree, eg.
+//! mid-rollout, can split a series between them but can't bounce it back
+//! and forth.
+//!
+//! Changing the members with `Cluster::rebalance` only moves the series
+//! that belong to the joining or leaving peers' points, roughly `1 / peers`
+//! of them, and keeps forwarding to the peers that stayed over their
+//! existing connections.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::super::metric::{CollectedMetric, MetricId};
+use super::super::send::StatsdTransport;
+use super::super::util::{read_lock, write_lock};
+use super::super::util::hash::fnv1a;
+use super::forward::Forwarder;
+
+/// The tag marking metrics forwarded by a peer.
+pub const FORWARDED: &str = "metriqs_forwarded";
+
+#[derive(Clone, Debug, Default)]
+pub struct ClusterOptions {
+    /// Addresses of every member's StatsD listener, including this one's.
+    pub peers: Vec<String>,
+    /// This agent's own address, as it appears in `peers`. Without it in
+    /// `peers` every metric is forwarded.
+    pub local: String,
+    /// How metrics are forwarded; defaults to UDP.
+    pub transport: Option<StatsdTransport>,
+    /// Points each peer is placed at on the ring; more spread series more
+    /// evenly. Defaults to 128.
+    pub virtual_nodes: Option<usize>,
+    /// How many batches can wait to be forwarded to each peer before more
+    /// are dropped; defaults to 1,000.
+    pub max_pending: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct Cluster {
+    ring: RwLock<Ring>,
+    dropped: AtomicUsize,
+    /// What `dropped` was when `take_dropped` was last called.
+    reported: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct Ring {
+    options: ClusterOptions,
+    /// Hash of each virtual point and the peer at it, sorted by hash.
+    points: Vec<(u64, usize)>,
+    /// A forwarder per peer, or None for this agent.
+    peers: Vec<Option<Forwarder>>,
+}
+
+impl Cluster {
+    /// Forwarders connect lazily, so peers don't need to be up yet.
+    pub fn new(options: ClusterOptions) -> Cluster {
+        Cluster {
+            ring: RwLock::new(Ring::new(options, &mut HashMap::new())),
+            dropped: AtomicUsize::new(0),
+            reported: AtomicUsize::new(0),
+        }
+    }
+
+    /// Switch to the members of `options`, eg. when one joins or leaves.
+    /// Unless the transport changed, peers that are still members keep
+    /// their forwarders and whatever's queued for them.
+    pub fn rebalance(&self, options: ClusterOptions) {
+        let mut ring = write_lock(&self.ring);
+        let mut forwarders = HashMap::new();
+        if options.transport == ring.options.transport {
+            let ring = &mut *ring;
+            for (address, peer) in ring.options.peers.iter().zip(ring.peers.drain(..)) {
+                if let Some(forwarder) = peer {
+                    forwarders.insert(address.clone(), forwarder);
+                }
+            }
+        }
+        *ring = Ring::new(options, &mut forwarders);
+    }
+
+    /// Which of `peers` owns `id`, or None if there aren't any.
+    pub fn owner(&self, id: &MetricId) -> Option<String> {
+        let ring = read_lock(&self.ring);
+        ring.owner(id).map(|peer| ring.options.peers[peer].clone())
+    }
+
+    /// Forward the metrics other peers own and return the rest, including
+    /// any a peer forwarded. Monotonic counts and summaries can't be sent as
+    /// StatsD, so they're always kept.
+    pub fn route(&self, metrics: Vec<CollectedMetric>) -> Vec<CollectedMetric> {
+        let ring = read_lock(&self.ring);
+        let mut local = Vec::with_capacity(metrics.len());
+        let mut forwarded: Vec<Vec<CollectedMetric>> = ring.peers.iter().map(|_| vec![]).collect();
+        for mut metric in metrics {
+            if metric.id().dimension(FORWARDED).is_some() {
+                let id = metric.id().without_dimension(FORWARDED);
+                *metric.id_mut() = id;
+                local.push(metric);
+                continue
+            }
+            let peer = match metric {
+                CollectedMetric::MonotonicCount(..) | CollectedMetric::Summary(..) => None,
+                _ => ring.owner(metric.id()).filter(|&peer| ring.peers[peer].is_some()),
+            };
+            match peer {
+                Some(peer) => {
+                    let id = metric.id().with_dimension(FORWARDED, "1");
+                    *metric.id_mut() = id;
+                    forwarded[peer].push(metric)
+                },
+                None => local.push(metric),
+            }
+        }
+        for (peer, metrics) in ring.peers.iter().zip(forwarded) {
+            if let (Some(forwarder), false) = (peer, metrics.is_empty()) {
+                if !forwarder.forward(metrics) && self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    warn!("Dropping metrics for cluster peers that can't keep up")
+                }
+            }
+        }
+        local
+    }
+
+    /// How many batches have been dropped because too many were waiting to
+    /// be forwarded to a peer, or it couldn't be forwarded to at all.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// How many batches have been dropped since this was last called, for
+    /// the Db's `metriqs.cluster.dropped` count.
+    pub fn take_dropped(&self) -> usize {
+        let dropped = self.dropped();
+        dropped - self.reported.swap(dropped, Ordering::Relaxed)
+    }
+}
+
+impl Ring {
+    /// Peers missing from `forwarders` get new ones.
+    fn new(options: ClusterOptions, forwarders: &mut HashMap<String, Forwarder>) -> Ring {
+        let virtual_nodes = options.virtual_nodes.unwrap_or(128).max(1);
+        let mut points = vec![];
+        let mut peers = vec![];
+        for (index, address) in options.peers.iter().enumerate() {
+            for point in 0..virtual_nodes {
+                points.push((mix(fnv1a(format!("{}#{}", address, point).as_bytes())), index));
+            }
+            peers.push(if *address == options.local {
+                None
+            } else {
+                Some(forwarders.remove(address).unwrap_or_else(|| Forwarder::spawn(address, options.transport, options.max_pending.unwrap_or(1000))))
+            });
+        }
+        points.sort_unstable();
+        Ring { options, points, peers }
+    }
+
+    fn owner(&self, id: &MetricId) -> Option<usize> {
+        if self.points.is_empty() {
+            return None
+        }
+        let hash = hash(id);
+        let index = self.points.partition_point(|&(point, _)| point < hash);
+        Some(self.points[index % self.points.len()].1)
+    }
+}
+
+/// Stable across processes and builds (unlike hashing `MetricId`, whose
+/// names are interned), since every peer has to agree on it.
+fn hash(id: &MetricId) -> u64 {
+    let mut bytes = id.name().as_bytes().to_vec();
+    for (key, value) in id.dimensions() {
+        bytes.push(0);
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(value.as_bytes());
+    }
+    mix(fnv1a(&bytes))
+}
+
+/// SplitMix64's finalizer, so that similar inputs (eg. a peer's virtual
+/// points) spread over the whole ring.
+fn mix(mut hash: u64) -> u64 {
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn options(peers: &[&str]) -> ClusterOptions {
+        ClusterOptions {
+            peers: peers.iter().map(|peer| peer.to_string()).collect(),
+            local: peers[0].to_string(),
+            ..ClusterOptions::default()
+        }
+    }
+
+    #[test]
+    fn it_moves_few_series_when_members_change() {
+        let cluster = Cluster::new(options(&["127.0.0.1:9001", "127.0.0.1:9002", "127.0.0.1:9003"]));
+        let ids = (0..1000).map(|index| MetricId::from(format!("series.{}", index))).collect::<Vec<_>>();
+        let before = ids.iter().map(|id| cluster.owner(id).unwrap()).collect::<Vec<_>>();
+        for peer in ["127.0.0.1:9001", "127.0.0.1:9002", "127.0.0.1:9003"].iter() {
+            let owned = before.iter().filter(|owner| owner == peer).count();
+            assert!(owned > 200, "{} owns {}", peer, owned);
+        }
+
+        cluster.rebalance(options(&["127.0.0.1:9001", "127.0.0.1:9002", "127.0.0.1:9003", "127.0.0.1:9004"]));
+        let after = ids.iter().map(|id| cluster.owner(id).unwrap()).collect::<Vec<_>>();
+        // Only series taken over by the new peer move.
+        for (before, after) in before.iter().zip(&after) {
+            assert!(before == after || after == "127.0.0.1:9004");
+        }
+        let moved = after.iter().filter(|owner| *owner == "127.0.0.1:9004").count();
+        assert!(moved > 100 && moved < 400, "{} moved", moved);
+    }
+
+    #[test]
+    fn it_forwards_metrics_other_peers_own() {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let peer_address = peer.local_addr().unwrap().to_string();
+        let cluster = Cluster::new(options(&["127.0.0.1:1", &peer_address]));
+
+        let metrics = (0..20)
+            .map(|index| CollectedMetric::Count(UNIX_EPOCH, MetricId::from(format!("series.{}", index)).with_dimension("host", "a"), 1))
+            .collect::<Vec<_>>();
+        let kept = cluster.route(metrics.clone());
+        let forwarded = metrics.iter().filter(|metric| !kept.contains(metric)).collect::<Vec<_>>();
+        assert!(!kept.is_empty() && !forwarded.is_empty());
+        assert!(kept.iter().all(|metric| cluster.owner(metric.id()).unwrap() == "127.0.0.1:1"));
+
+        let mut buf = [0; 1500];
+        let len = peer.recv(&mut buf).unwrap();
+        let lines = String::from_utf8_lossy(&buf[..len]).lines().map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(lines.len(), forwarded.len());
+        assert!(lines.iter().all(|line| line.ends_with(":1|c|#host:a,metriqs_forwarded:1")));
+    }
+
+    #[test]
+    fn it_keeps_metrics_peers_forwarded() {
+        let cluster = Cluster::new(options(&["127.0.0.1:1", "127.0.0.1:2"]));
+        let metrics = (0..20)
+            .map(|index| CollectedMetric::Count(UNIX_EPOCH, MetricId::from(format!("series.{}", index)).with_dimension(FORWARDED, "1"), 1))
+            .collect::<Vec<_>>();
+        let kept = cluster.route(metrics);
+        assert_eq!(kept.len(), 20);
+        assert!(kept.iter().all(|metric| metric.id().dimensions().is_empty()));
+    }
+}
@@ -1,19 +1,264 @@
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
 
-use super::super::metric::CollectedMetric;
+use string_cache::DefaultAtom as Atom;
+
+use super::super::db::{CollectionQueue, Metadata, MetadataRegistry};
+use super::super::metric::{CollectedMetric, Dimension};
+use super::super::util::{lock, read_lock, write_lock};
+use super::enrich::Enrichment;
+use super::pipeline::Pipeline;
 
 pub struct Collector {
-    sender: Sender<Vec<CollectedMetric>>,
+    queue: Arc<CollectionQueue>,
+    metadata: MetadataRegistry,
+    default_dimensions: Arc<Vec<Dimension>>,
+    /// Dimensions looked up from where the agent runs, added after the
+    /// default ones.
+    enrichment: Option<Enrichment>,
+    /// Shared with the Db so that reconfiguring it applies to collectors
+    /// already handed out.
+    pipeline: Arc<RwLock<Arc<Pipeline>>>,
+    /// Other Dbs that receive a copy of everything pushed.
+    also: Vec<Collector>,
+    /// Channels that receive a copy of everything pushed, before it's
+    /// aggregated.
+    taps: Vec<Sender<Vec<CollectedMetric>>>,
+    /// Where pushes wait to go out together, if it's `buffered`.
+    buffering: Option<Arc<Buffering>>,
+}
+
+#[derive(Default)]
+pub struct BufferingOptions {
+    /// How many metrics can wait before they're pushed; defaults to 1,000.
+    pub max_metrics: Option<usize>,
+    /// How long metrics can wait before they're pushed; defaults to 1
+    /// second.
+    pub max_delay: Option<Duration>,
+}
+
+struct Buffering {
+    pending: Mutex<Vec<CollectedMetric>>,
+    max_metrics: usize,
+    /// The unbuffered collector pending metrics are pushed to. Taps and
+    /// the like added to the buffered collector are added to this one.
+    collector: RwLock<Collector>,
 }
 
 impl Collector {
-    pub fn new(sender: Sender<Vec<CollectedMetric>>) -> Collector {
+    pub fn new(queue: Arc<CollectionQueue>, metadata: MetadataRegistry, default_dimensions: Arc<Vec<Dimension>>, pipeline: Arc<RwLock<Arc<Pipeline>>>) -> Collector {
         Collector {
-            sender: sender,
+            queue,
+            metadata,
+            default_dimensions,
+            enrichment: None,
+            pipeline,
+            also: vec![],
+            taps: vec![],
+            buffering: None,
+        }
+    }
+
+    /// Hold pushed metrics back until `max_metrics` are waiting or
+    /// `max_delay` has passed, then push them at once. This suits recording
+    /// from the application at high call rates (see `client::Metrics`),
+    /// where pushing each metric on its own contends for the Db's queue.
+    /// Whatever's waiting is pushed when the collector is dropped, or
+    /// earlier with `flush`. Enrichment, other Dbs, and taps added to the
+    /// buffered collector apply once metrics are pushed from the buffer.
+    pub fn buffered(self, options: BufferingOptions) -> Collector {
+        let mut buffered = Collector::new(self.queue.clone(), self.metadata.clone(), self.default_dimensions.clone(), self.pipeline.clone());
+        let buffering = Arc::new(Buffering {
+            pending: Mutex::new(vec![]),
+            max_metrics: options.max_metrics.unwrap_or(1000).max(1),
+            collector: RwLock::new(self),
+        });
+        let max_delay = options.max_delay.unwrap_or(Duration::from_secs(1));
+        let timer = Arc::downgrade(&buffering);
+        thread::spawn(move || Buffering::flush_every(&timer, max_delay));
+        buffered.buffering = Some(buffering);
+        buffered
+    }
+
+    /// Add `enrichment`'s dimensions to metrics without them instead of the
+    /// default dimensions, which they start with (see `recv::enrich`).
+    pub fn enriched(mut self, enrichment: Enrichment) -> Collector {
+        self.unbuffered(|collector| collector.enrichment = Some(enrichment));
+        self
+    }
+
+    /// Also push to `other`'s Db. This runs several aggregation pipelines
+    /// off the same receivers, eg. a 10 second Db for one exporter and a
+    /// 60 second one for a cheaper backend, without running two agents.
+    pub fn also(mut self, other: Collector) -> Collector {
+        self.unbuffered(|collector| collector.also.push(other));
+        self
+    }
+
+    /// Also send everything pushed, as it's queued for the Db, to `tap`, eg.
+    /// to forward raw samples upstream. Taps that hang up are skipped.
+    pub fn tap(mut self, tap: Sender<Vec<CollectedMetric>>) -> Collector {
+        self.unbuffered(|collector| collector.taps.push(tap));
+        self
+    }
+
+    /// Change the collector its buffer is pushed to if it's `buffered`,
+    /// otherwise this one.
+    fn unbuffered<F: FnOnce(&mut Collector)>(&mut self, change: F) {
+        match self.buffering {
+            Some(ref buffering) => change(&mut write_lock(&buffering.collector)),
+            None => change(self),
+        }
+    }
+
+    /// An empty buffer for the metrics of a later `push`, which reuses the
+    /// Db's spare buffers if it pools them.
+    pub fn buffer(&self) -> Vec<CollectedMetric> {
+        self.queue.buffer()
+    }
+
+    /// Send metrics to the Db. The Db's default dimensions, and any it
+    /// looked up (see `recv::enrich`), are merged into each metric's
+    /// identifier first, then its pipeline is applied, which may rename or
+    /// drop metrics.
+    pub fn push(&self, mut metrics: Vec<CollectedMetric>) {
+        if let Some(ref buffering) = self.buffering {
+            return buffering.push(metrics)
         }
+        for other in &self.also {
+            other.push(metrics.clone())
+        }
+        // What's looked up already has the default dimensions merged in.
+        let defaults = match self.enrichment {
+            Some(ref enrichment) => enrichment.dimensions(),
+            None => self.default_dimensions.clone(),
+        };
+        if !defaults.is_empty() {
+            for metric in &mut metrics {
+                let id = metric.id().with_defaults(&defaults);
+                *metric.id_mut() = id;
+            }
+        }
+        let pipeline = read_lock(&self.pipeline).clone();
+        let metrics = pipeline.apply(metrics);
+        if metrics.is_empty() {
+            return
+        }
+        for tap in &self.taps {
+            let _ = tap.send(metrics.clone());
+        }
+        self.queue.push(metrics)
     }
 
-    pub fn push(&self, metrics: Vec<CollectedMetric>) {
-        let _ = self.sender.send(metrics);
+    /// Record the unit and/or description of a metric for receivers whose
+    /// protocol carries them (eg. Prometheus `# HELP` and OTLP units).
+    pub fn describe(&self, name: Atom, metadata: Metadata) {
+        if let Some(ref buffering) = self.buffering {
+            return read_lock(&buffering.collector).describe(name, metadata)
+        }
+        for other in &self.also {
+            other.describe(name.clone(), metadata.clone())
+        }
+        self.metadata.describe(name, metadata)
+    }
+
+    /// Push the metrics waiting in a `buffered` collector now.
+    pub fn flush(&self) {
+        if let Some(ref buffering) = self.buffering {
+            buffering.flush()
+        }
+    }
+}
+
+impl Buffering {
+    fn push(&self, metrics: Vec<CollectedMetric>) {
+        let full = {
+            let mut pending = lock(&self.pending);
+            if pending.is_empty() {
+                *pending = metrics;
+            } else {
+                pending.extend(metrics);
+            }
+            if pending.len() < self.max_metrics {
+                return
+            }
+            mem::take(&mut *pending)
+        };
+        read_lock(&self.collector).push(full)
+    }
+
+    fn flush(&self) {
+        let pending = mem::take(&mut *lock(&self.pending));
+        if !pending.is_empty() {
+            read_lock(&self.collector).push(pending)
+        }
+    }
+
+    /// Until the collector is dropped.
+    fn flush_every(buffering: &Weak<Buffering>, delay: Duration) {
+        loop {
+            thread::sleep(delay);
+            match buffering.upgrade() {
+                Some(buffering) => buffering.flush(),
+                None => return,
+            }
+        }
+    }
+}
+
+impl Drop for Buffering {
+    fn drop(&mut self) {
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::time::{Instant, UNIX_EPOCH};
+
+    use super::super::super::db::{Db, DbOptions};
+    use super::super::super::metric::MetricId;
+
+    fn count() -> Vec<CollectedMetric> {
+        vec![CollectedMetric::Count(UNIX_EPOCH, MetricId::from("requests"), 1)]
+    }
+
+    #[test]
+    fn it_pushes_buffered_metrics_together() {
+        let db = Db::new(DbOptions::default());
+        // Long enough that the timer doesn't push anything first.
+        let options = BufferingOptions { max_metrics: Some(3), max_delay: Some(Duration::from_secs(60 * 60)) };
+        let (tap, tapped) = channel();
+        let collector = db.collector().buffered(options).tap(tap);
+
+        collector.push(count());
+        collector.push(count());
+        assert_eq!(db.stats().backlog, 0);
+        collector.push(count());
+        assert_eq!(db.stats().backlog, 3);
+        assert_eq!(tapped.try_iter().map(|metrics| metrics.len()).collect::<Vec<usize>>(), vec![3]);
+
+        collector.push(count());
+        drop(collector);
+        assert_eq!(db.stats().backlog, 4);
+    }
+
+    #[test]
+    fn it_pushes_buffered_metrics_once_theyve_waited() {
+        let db = Db::new(DbOptions::default());
+        let options = BufferingOptions { max_metrics: Some(3), max_delay: Some(Duration::from_millis(20)) };
+        let collector = db.collector().buffered(options);
+        collector.push(count());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while db.stats().backlog < 1 {
+            assert!(Instant::now() < deadline, "the buffer wasn't pushed");
+            thread::sleep(Duration::from_millis(5));
+        }
     }
 }
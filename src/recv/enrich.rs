@@ -0,0 +1,297 @@
+//! Dimensions describing where the agent runs, looked up from the cloud or
+//! Kubernetes rather than configured, since in dynamic infrastructure the
+//! same config runs on instances and pods that come and go. Collectors add
+//! them to every metric after the Db's default dimensions, neither
+//! replacing dimensions metrics already have.
+//!
+//! - EC2 (IMDSv2, falling back to v1 when there's no token, eg. when the
+//!   response to a container is dropped for its hop limit) and GCE
+//!   metadata provide
+//!   `instance_id` and `az`, the availability zone.
+//! - Kubernetes provides `pod`, `namespace`, and `node` from the
+//!   `POD_NAME`, `POD_NAMESPACE`, and `NODE_NAME` environment variables,
+//!   which the downward API sets (eg. `fieldRef: { fieldPath:
+//!   spec.nodeName }`). Without them the pod is the hostname and the
+//!   namespace is the service account's.
+//!
+//! Dimensions are looked up when the agent starts and refreshed every
+//! `refresh_interval`. A source that can't be reached keeps what it last
+//! provided, so that a flaky metadata service doesn't churn series.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use string_cache::DefaultAtom as Atom;
+
+use super::super::metric::Dimension;
+use super::super::util::{http, lock, read_lock, write_lock};
+
+const NAMESPACE_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetadataSource {
+    Ec2,
+    Gce,
+    Kubernetes,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EnrichmentOptions {
+    /// Looked up in order; when two provide a dimension the first wins.
+    pub sources: Vec<MetadataSource>,
+    /// How often to look dimensions up again; defaults to 5 minutes.
+    pub refresh_interval: Option<Duration>,
+    /// How long to wait on a metadata service; defaults to 1 second.
+    pub timeout: Option<Duration>,
+    /// Where EC2's and GCE's metadata services are; defaults to
+    /// 169.254.169.254:80.
+    pub metadata_address: Option<SocketAddr>,
+}
+
+/// The dimensions last looked up, shared by an `Enricher` with the
+/// collectors that add them.
+#[derive(Clone, Debug, Default)]
+pub struct Enrichment {
+    dimensions: Arc<RwLock<Arc<Vec<Dimension>>>>,
+}
+
+impl Enrichment {
+    /// The Db's default dimensions and then those looked up that they
+    /// don't have, merged once per refresh rather than per push.
+    pub fn dimensions(&self) -> Arc<Vec<Dimension>> {
+        read_lock(&self.dimensions).clone()
+    }
+}
+
+/// Looks up dimensions from its sources into an `Enrichment`.
+#[derive(Debug)]
+pub struct Enricher {
+    sources: Vec<MetadataSource>,
+    refresh_interval: Duration,
+    timeout: Duration,
+    metadata_address: SocketAddr,
+    /// What each source last provided.
+    found: Mutex<Vec<Vec<Dimension>>>,
+    /// The Db's, which take precedence.
+    default_dimensions: Arc<Vec<Dimension>>,
+    enrichment: Enrichment,
+}
+
+impl Enricher {
+    pub fn new(options: EnrichmentOptions, default_dimensions: Arc<Vec<Dimension>>) -> Enricher {
+        Enricher {
+            found: Mutex::new(options.sources.iter().map(|_| vec![]).collect()),
+            sources: options.sources,
+            refresh_interval: options.refresh_interval.unwrap_or_else(|| Duration::from_secs(5 * 60)),
+            timeout: options.timeout.unwrap_or_else(|| Duration::from_secs(1)),
+            metadata_address: options.metadata_address.unwrap_or_else(|| SocketAddr::from(([169, 254, 169, 254], 80))),
+            enrichment: Enrichment { dimensions: Arc::new(RwLock::new(default_dimensions.clone())) },
+            default_dimensions,
+        }
+    }
+
+    pub fn enrichment(&self) -> &Enrichment {
+        &self.enrichment
+    }
+
+    pub fn refresh_interval(&self) -> Duration {
+        self.refresh_interval
+    }
+
+    /// Look dimensions up from every source now, blocking until each has
+    /// answered or timed out.
+    pub fn refresh(&self) {
+        let mut found = lock(&self.found);
+        for (source, found) in self.sources.iter().zip(found.iter_mut()) {
+            let looked_up = match *source {
+                MetadataSource::Ec2 => self.ec2(),
+                MetadataSource::Gce => self.gce(),
+                MetadataSource::Kubernetes => Ok(kubernetes(|name| env::var(name).ok(), || fs::read_to_string(NAMESPACE_PATH).ok())),
+            };
+            match looked_up {
+                Ok(dimensions) => *found = dimensions,
+                Err(err) => warn!("Error looking up {:?} metadata: {}", source, err),
+            }
+        }
+
+        let mut dimensions = self.default_dimensions.to_vec();
+        for dimension in found.iter().flatten() {
+            if !dimensions.iter().any(|existing| existing.0 == dimension.0) {
+                dimensions.push(dimension.clone())
+            }
+        }
+        *write_lock(&self.enrichment.dimensions) = Arc::new(dimensions);
+    }
+
+    fn ec2(&self) -> io::Result<Vec<Dimension>> {
+        // IMDSv2 needs a session token; without one it's IMDSv1.
+        let token = match http::request("PUT", &self.metadata_address, "169.254.169.254", "/latest/api/token", &[("X-aws-ec2-metadata-token-ttl-seconds", "60")], self.timeout) {
+            Ok((200, token)) => Some(token.trim().to_string()),
+            Ok(_) => None,
+            Err(err) => {
+                debug!("Falling back to IMDSv1 without a token: {}", err);
+                None
+            },
+        };
+        let mut headers = vec![];
+        if let Some(ref token) = token {
+            headers.push(("X-aws-ec2-metadata-token", token.as_str()));
+        }
+        let get = |path: &str| self.get("169.254.169.254", path, &headers);
+        Ok(vec![
+            dimension("instance_id", get("/latest/meta-data/instance-id")?),
+            dimension("az", get("/latest/meta-data/placement/availability-zone")?),
+        ])
+    }
+
+    fn gce(&self) -> io::Result<Vec<Dimension>> {
+        let headers = [("Metadata-Flavor", "Google")];
+        let get = |path: &str| self.get("metadata.google.internal", path, &headers);
+        // Zones are eg. `projects/123/zones/us-central1-a`.
+        let zone = get("/computeMetadata/v1/instance/zone")?;
+        Ok(vec![
+            dimension("instance_id", get("/computeMetadata/v1/instance/id")?),
+            dimension("az", zone.rsplit('/').next().unwrap_or_default().to_string()),
+        ])
+    }
+
+    fn get(&self, host: &str, path: &str, headers: &[(&str, &str)]) -> io::Result<String> {
+        match http::request("GET", &self.metadata_address, host, path, headers, self.timeout)? {
+            (200, body) => Ok(body.trim().to_string()),
+            (status, _) => Err(io::Error::other(format!("{} responded {}", path, status))),
+        }
+    }
+}
+
+/// The pod's dimensions from the environment variables `var` looks up,
+/// none outside Kubernetes.
+fn kubernetes<V, N>(var: V, service_account_namespace: N) -> Vec<Dimension>
+    where V: Fn(&str) -> Option<String>, N: FnOnce() -> Option<String>
+{
+    let var = |name: &str| var(name).filter(|value| !value.is_empty());
+    let in_kubernetes = var("KUBERNETES_SERVICE_HOST").is_some();
+    let mut dimensions = vec![];
+    if let Some(pod) = var("POD_NAME").or_else(|| var("HOSTNAME").filter(|_| in_kubernetes)) {
+        dimensions.push(dimension("pod", pod));
+    }
+    let namespace = var("POD_NAMESPACE")
+        .or_else(|| if in_kubernetes { service_account_namespace() } else { None })
+        .map(|namespace| namespace.trim().to_string())
+        .filter(|namespace| !namespace.is_empty());
+    if let Some(namespace) = namespace {
+        dimensions.push(dimension("namespace", namespace));
+    }
+    if let Some(node) = var("NODE_NAME") {
+        dimensions.push(dimension("node", node));
+    }
+    dimensions
+}
+
+fn dimension(key: &str, value: String) -> Dimension {
+    (Atom::from(key), Atom::from(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+
+    use super::super::super::db::{Db, DbOptions};
+    use super::super::super::metric::{CollectedMetric, MetricId};
+    use super::super::super::util::Stop;
+    use super::super::super::util::http::Response;
+
+    #[test]
+    fn it_adds_dimensions_from_ec2_metadata() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let stop = Stop::new();
+        let server = {
+            let stop = stop.clone();
+            thread::spawn(move || http::serve(&listener, &stop, |method, target| match (method, target) {
+                ("PUT", "/latest/api/token") => Response::text(200, "token"),
+                ("GET", "/latest/meta-data/instance-id") => Response::text(200, "i-0123\n"),
+                ("GET", "/latest/meta-data/placement/availability-zone") => Response::text(200, "us-east-1a"),
+                _ => Response::text(404, ""),
+            }))
+        };
+
+        let options = DbOptions {
+            default_dimensions: vec![(Atom::from("az"), Atom::from("override"))],
+            enrichment: Some(EnrichmentOptions {
+                sources: vec![MetadataSource::Ec2],
+                metadata_address: Some(address),
+                ..EnrichmentOptions::default()
+            }),
+            ..DbOptions::default()
+        };
+        let db = Db::new(options);
+        db.enrich();
+        stop.stop();
+        server.join().unwrap().unwrap();
+
+        let subscription = db.aggregation_subscribe();
+        db.collector().push(vec![CollectedMetric::Gauge(UNIX_EPOCH, MetricId::from("queue"), 1.0)]);
+        db.aggregate(None);
+        let id = MetricId::from("queue").with_dimension("az", "override").with_dimension("instance_id", "i-0123");
+        assert_eq!(subscription.recv().unwrap()[0].id(), &id);
+    }
+
+    #[test]
+    fn it_falls_back_to_imdsv1_without_a_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // A PUT that goes unanswered, as one from a container beyond the
+        // hop limit does, then the two GETs.
+        let server = thread::spawn(move || {
+            for stream in listener.incoming().take(3) {
+                let mut reader = io::BufReader::new(stream.unwrap());
+                let mut request = String::new();
+                while io::BufRead::read_line(&mut reader, &mut request).unwrap() > 2 {}
+                let body = match request.split_whitespace().take(2).collect::<Vec<&str>>()[..] {
+                    ["GET", "/latest/meta-data/instance-id"] => "i-0123",
+                    ["GET", "/latest/meta-data/placement/availability-zone"] => "us-east-1a",
+                    _ => continue,
+                };
+                assert!(!request.contains("X-aws-ec2-metadata-token:"));
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                io::Write::write_all(reader.get_mut(), response.as_bytes()).unwrap();
+            }
+        });
+
+        let options = EnrichmentOptions { sources: vec![MetadataSource::Ec2], metadata_address: Some(address), ..EnrichmentOptions::default() };
+        let defaults = Arc::new(vec![dimension("service", "api".to_string())]);
+        let enricher = Enricher::new(options, defaults);
+        assert_eq!(*enricher.enrichment().dimensions(), vec![dimension("service", "api".to_string())]);
+        enricher.refresh();
+        server.join().unwrap();
+        assert_eq!(*enricher.enrichment().dimensions(), vec![
+            dimension("service", "api".to_string()),
+            dimension("instance_id", "i-0123".to_string()),
+            dimension("az", "us-east-1a".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn it_finds_the_pod_from_the_environment() {
+        let env = |name: &str| match name {
+            "KUBERNETES_SERVICE_HOST" => Some("10.0.0.1".to_string()),
+            "HOSTNAME" => Some("api-7d9f".to_string()),
+            "NODE_NAME" => Some("node-1".to_string()),
+            _ => None,
+        };
+        assert_eq!(kubernetes(env, || Some("prod\n".to_string())), vec![
+            dimension("pod", "api-7d9f".to_string()),
+            dimension("namespace", "prod".to_string()),
+            dimension("node", "node-1".to_string()),
+        ]);
+        assert_eq!(kubernetes(|_| None, || Some("prod".to_string())), vec![]);
+    }
+}
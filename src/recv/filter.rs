@@ -0,0 +1,53 @@
+use super::super::util::Glob;
+
+/// Which metrics collectors let through to the Db, by name. Patterns
+/// prefixed with `!` deny and the rest allow: a metric is kept if its name
+/// matches no deny pattern and either matches an allow pattern or there
+/// are none. So `["web.*", "!web.debug.*"]` keeps only `web.` metrics
+/// except debugging ones, and `["!debug.*"]` keeps everything else.
+#[derive(Clone, Debug, Default)]
+pub struct NameFilter {
+    allow: Vec<Glob>,
+    deny: Vec<Glob>,
+}
+
+impl NameFilter {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> NameFilter {
+        let mut filter = NameFilter::default();
+        for pattern in patterns {
+            match pattern.as_ref().strip_prefix('!') {
+                Some(pattern) => filter.deny.push(Glob::new(pattern)),
+                None => filter.allow.push(Glob::new(pattern)),
+            }
+        }
+        filter
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn allows(&self, name: &str) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|pattern| pattern.matches(name));
+        allowed && !self.deny.iter().any(|pattern| pattern.matches(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_allows_and_denies_names() {
+        let filter = NameFilter::new(&["web.*", "api.*", "!web.debug.*"]);
+        assert!(filter.allows("web.requests"));
+        assert!(filter.allows("api.latency"));
+        assert!(!filter.allows("web.debug.queue"));
+        assert!(!filter.allows("db.queries"));
+
+        let filter = NameFilter::new(&["!debug.*"]);
+        assert!(filter.allows("web.requests"));
+        assert!(!filter.allows("debug.queue"));
+        assert!(NameFilter::default().allows("anything"));
+    }
+}
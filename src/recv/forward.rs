@@ -0,0 +1,162 @@
+//! Forwarding raw metrics to other agents' StatsD listeners as DogStatsD
+//! lines, for clusters (see `cluster`) and mirrors. Monotonic counts and
+//! summaries have no StatsD equivalent and aren't forwarded.
+//!
+//! A mirror sends a copy of everything collected to a standby agent, so
+//! that it aggregates the same intervals as the primary and can take over
+//! without losing the one in flight. The standby runs with
+//! `DbOptions::standby` so that it doesn't export until it's promoted,
+//! eg. with the admin API's `POST /promote`.
+
+use std::net::ToSocketAddrs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+use super::super::metric::CollectedMetric;
+use super::super::send::{StatsdOptions, StatsdSender, StatsdTransport};
+use super::super::util::{Stop, StopOnDrop};
+
+/// How often an address that didn't resolve is tried again.
+const RESOLVE_RETRY: Duration = Duration::from_secs(5);
+
+/// Sends batches of raw metrics to one agent from a thread of its own,
+/// until it's dropped.
+#[derive(Debug)]
+pub struct Forwarder {
+    send: SyncSender<Vec<CollectedMetric>>,
+    /// Stops resolving the address if it hasn't yet.
+    _stop: StopOnDrop,
+}
+
+impl Forwarder {
+    /// Connects lazily, so the agent doesn't need to be up yet, and keeps
+    /// trying to resolve `address` until it does, eg. while a peer's DNS
+    /// record is being created. At most `max_pending` batches wait to be
+    /// sent.
+    pub fn spawn(address: &str, transport: Option<StatsdTransport>, max_pending: usize) -> Forwarder {
+        let (send, recv) = sync_channel(max_pending);
+        let options = StatsdOptions { transport, ..StatsdOptions::default() };
+        let (address, stop) = (address.to_string(), Stop::new());
+        let resolving = stop.clone();
+        thread::spawn(move || {
+            let mut logged = false;
+            let addr = loop {
+                match address.to_socket_addrs().map(|mut addrs| addrs.next()) {
+                    Ok(Some(addr)) => break addr,
+                    Ok(None) => if !logged {
+                        error!("{} to forward metrics to resolved to no addresses; retrying", address)
+                    },
+                    Err(err) => if !logged {
+                        error!("Error resolving {} to forward metrics to, retrying: {}", address, err)
+                    },
+                }
+                logged = true;
+                if !resolving.sleep(RESOLVE_RETRY) {
+                    return
+                }
+            };
+            match StatsdSender::raw(recv, addr, options) {
+                Ok(mut sender) => sender.send(),
+                Err(err) => error!("Error forwarding metrics to {}: {}", address, err),
+            }
+        });
+        Forwarder { send, _stop: stop.on_drop() }
+    }
+
+    /// Queue `metrics` to be sent, returning false if they were dropped
+    /// because too many batches are waiting, eg. while the address doesn't
+    /// resolve.
+    pub fn forward(&self, metrics: Vec<CollectedMetric>) -> bool {
+        self.send.try_send(metrics).is_ok()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MirrorOptions {
+    /// The standby's StatsD listener.
+    pub address: String,
+    /// Defaults to UDP.
+    pub transport: Option<StatsdTransport>,
+    /// How many batches can wait to be sent before more are dropped;
+    /// defaults to 1,000.
+    pub max_pending: Option<usize>,
+}
+
+/// Copies every batch collected to a standby agent.
+#[derive(Debug)]
+pub struct Mirror {
+    options: MirrorOptions,
+    forwarder: Forwarder,
+    dropped: AtomicUsize,
+    /// `dropped` when `take_dropped` was last called.
+    reported: AtomicUsize,
+}
+
+impl Mirror {
+    pub fn new(options: MirrorOptions) -> Mirror {
+        let forwarder = Forwarder::spawn(&options.address, options.transport, options.max_pending.unwrap_or(1000));
+        Mirror {
+            options,
+            forwarder,
+            dropped: AtomicUsize::new(0),
+            reported: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn options(&self) -> &MirrorOptions {
+        &self.options
+    }
+
+    pub fn mirror(&self, metrics: &[CollectedMetric]) {
+        let forwarded = metrics.iter()
+            .filter(|metric| !matches!(**metric, CollectedMetric::MonotonicCount(..) | CollectedMetric::Summary(..)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !forwarded.is_empty() && !self.forwarder.forward(forwarded) && self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+            warn!("Dropping metrics mirrored to {}; it can't keep up", self.options.address)
+        }
+    }
+
+    /// How many batches have been dropped instead of mirrored.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// How many batches have been dropped since this was last called, for
+    /// the Db's `metriqs.mirror.dropped` count.
+    pub fn take_dropped(&self) -> usize {
+        let dropped = self.dropped();
+        dropped - self.reported.swap(dropped, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::super::super::db::{Db, DbOptions};
+    use super::super::super::metric::MetricId;
+    use super::super::NameFilter;
+
+    #[test]
+    fn it_mirrors_collected_metrics_before_the_pipeline() {
+        let standby = UdpSocket::bind("127.0.0.1:0").unwrap();
+        standby.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mirror = MirrorOptions { address: standby.local_addr().unwrap().to_string(), ..MirrorOptions::default() };
+        let db = Db::new(DbOptions { mirror: Some(mirror), name_filter: Some(NameFilter::new(&["!debug.*"])), ..DbOptions::default() });
+
+        db.collector().push(vec![
+            CollectedMetric::Count(UNIX_EPOCH, MetricId::from("requests").with_dimension("host", "a"), 2),
+            CollectedMetric::Gauge(UNIX_EPOCH, MetricId::from("debug.queue"), 1.0),
+            CollectedMetric::MonotonicCount(UNIX_EPOCH, MetricId::from("bytes"), 10.0),
+        ]);
+        let mut buf = [0; 1500];
+        let len = standby.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &b"requests:2|c|#host:a\ndebug.queue:1|g"[..]);
+    }
+}
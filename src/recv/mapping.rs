@@ -0,0 +1,80 @@
+//! Maps dotted StatsD-style names onto a name and dimensions, the way
+//! statsd_exporter's glob mappings do, eg. `api.prod.checkout.latency` to
+//! `api_latency{env=prod,endpoint=checkout}` with:
+//!
+//! ```text
+//! match: api.*.*.latency
+//! name: api_latency
+//! dimensions: env=$1, endpoint=$2
+//! ```
+//!
+//! A `*` matches any run of characters within one dot-separated component,
+//! and `$1`, `$2`, etc. (or `${1}`) in the name and dimension values are
+//! what the `*`s matched, in order.
+
+use regex::Regex;
+use string_cache::DefaultAtom as Atom;
+
+use super::super::metric::MetricId;
+
+#[derive(Clone, Debug)]
+pub struct NameMapping {
+    pattern: Regex,
+    name: String,
+    dimensions: Vec<(Atom, String)>,
+}
+
+impl NameMapping {
+    pub fn new<K: AsRef<str>, V: AsRef<str>>(pattern: &str, name: &str, dimensions: &[(K, V)]) -> NameMapping {
+        let pattern = pattern.split('*').map(regex::escape).collect::<Vec<String>>().join("([^.]*)");
+        NameMapping {
+            pattern: Regex::new(&format!("^{}$", pattern)).unwrap(),
+            name: name.to_string(),
+            dimensions: dimensions.iter()
+                .map(|(key, value)| (Atom::from(key.as_ref()), value.as_ref().to_string()))
+                .collect(),
+        }
+    }
+
+    /// The mapped identifier if the name matches. Dimensions the metric
+    /// already has are kept unless the mapping sets them.
+    pub fn apply(&self, id: &MetricId) -> Option<MetricId> {
+        let captures = self.pattern.captures(id.name())?;
+        let expand = |template: &str| {
+            let mut expanded = String::new();
+            captures.expand(template, &mut expanded);
+            expanded
+        };
+
+        let mut dimensions = id.dimensions().to_vec();
+        dimensions.extend(self.dimensions.iter().map(|(key, value)| (key.clone(), Atom::from(expand(value)))));
+        Some(MetricId::new(expand(&self.name), dimensions))
+    }
+}
+
+/// Apply the first mapping that matches; names that none match are kept
+/// as they are.
+pub fn map_name(mappings: &[NameMapping], id: MetricId) -> MetricId {
+    mappings.iter().find_map(|mapping| mapping.apply(&id)).unwrap_or(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_maps_dotted_names() {
+        let mappings = vec![
+            NameMapping::new("api.*.*.latency", "api_latency", &[("env", "$1"), ("endpoint", "$2")]),
+            NameMapping::new("jobs.*_queue.depth", "job_queue_depth", &[("queue", "$1")]),
+            NameMapping::new("api.*.*", "api_${2}_total", &[("env", "$1")]),
+        ];
+
+        let id = MetricId::from("api.prod.checkout.latency").with_dimension("host", "a");
+        let expected = MetricId::from("api_latency").with_dimension("env", "prod").with_dimension("endpoint", "checkout").with_dimension("host", "a");
+        assert_eq!(map_name(&mappings, id), expected);
+        assert_eq!(map_name(&mappings, MetricId::from("jobs.mail_queue.depth")), MetricId::from("job_queue_depth").with_dimension("queue", "mail"));
+        assert_eq!(map_name(&mappings, MetricId::from("api.prod.requests")), MetricId::from("api_requests_total").with_dimension("env", "prod"));
+        assert_eq!(map_name(&mappings, MetricId::from("web.requests")), MetricId::from("web.requests"));
+    }
+}
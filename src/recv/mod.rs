@@ -7,6 +7,26 @@
 pub mod push;
 pub mod pull;
 
+pub mod cluster;
 mod collector;
+pub mod enrich;
+mod filter;
+pub mod forward;
+pub mod mapping;
+mod pipeline;
+pub mod relabel;
+pub mod sampling;
+pub mod scrub;
+pub mod validate;
 
-pub use self::collector::Collector;
+pub use self::cluster::{Cluster, ClusterOptions};
+pub use self::collector::{BufferingOptions, Collector};
+pub use self::enrich::{Enricher, Enrichment, EnrichmentOptions, MetadataSource};
+pub use self::filter::NameFilter;
+pub use self::forward::{Forwarder, Mirror, MirrorOptions};
+pub use self::mapping::NameMapping;
+pub use self::pipeline::Pipeline;
+pub use self::relabel::{RelabelAction, RelabelRule};
+pub use self::sampling::{Sampler, SamplingRule};
+pub use self::scrub::{ScrubAction, ScrubRule};
+pub use self::validate::{CountValidator, NegativeCounts};
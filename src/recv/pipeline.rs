@@ -0,0 +1,65 @@
+use std::sync::Arc;
+
+use super::super::metric::CollectedMetric;
+use super::cluster::Cluster;
+use super::forward::Mirror;
+use super::filter::NameFilter;
+use super::mapping::{map_name, NameMapping};
+use super::relabel::{relabel, RelabelRule};
+use super::sampling::Sampler;
+use super::scrub::{scrub, ScrubRule};
+use super::validate::{CountValidator, NegativeCounts};
+
+/// What collectors do to metrics before they're queued for aggregation, in
+/// the order of the fields.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    /// Sent a copy of every metric.
+    pub mirror: Option<Arc<Mirror>>,
+    /// Metrics other peers own are forwarded to them and go no further.
+    pub cluster: Option<Arc<Cluster>>,
+    pub counts: CountValidator,
+    pub mappings: Vec<NameMapping>,
+    pub relabel: Vec<RelabelRule>,
+    pub scrub: Vec<ScrubRule>,
+    pub name_filter: NameFilter,
+    pub sampler: Sampler,
+}
+
+impl Pipeline {
+    pub fn is_empty(&self) -> bool {
+        self.mirror.is_none() && self.cluster.is_none() && self.counts.policy() == NegativeCounts::Accept && self.mappings.is_empty() && self.relabel.is_empty() && self.scrub.is_empty() && self.name_filter.is_empty() && self.sampler.is_empty()
+    }
+
+    pub fn apply(&self, metrics: Vec<CollectedMetric>) -> Vec<CollectedMetric> {
+        if self.is_empty() {
+            return metrics
+        }
+        if let Some(ref mirror) = self.mirror {
+            mirror.mirror(&metrics)
+        }
+        let metrics = match self.cluster {
+            Some(ref cluster) => cluster.route(metrics),
+            None => metrics,
+        };
+
+        metrics.into_iter()
+            .filter_map(|metric| {
+                let mut metric = self.counts.validate(metric)?;
+                if !self.mappings.is_empty() {
+                    *metric.id_mut() = map_name(&self.mappings, metric.id().clone());
+                }
+                if !self.relabel.is_empty() {
+                    *metric.id_mut() = relabel(&self.relabel, metric.id().clone())?;
+                }
+                if !self.scrub.is_empty() {
+                    *metric.id_mut() = scrub(&self.scrub, metric.id().clone());
+                }
+                if !self.name_filter.allows(metric.id().name()) {
+                    return None
+                }
+                self.sampler.sample(metric)
+            })
+            .collect()
+    }
+}
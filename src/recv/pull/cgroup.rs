@@ -0,0 +1,309 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use string_cache::DefaultAtom as Atom;
+
+use super::every;
+use super::super::collector::Collector;
+use super::super::super::metric::{CollectedMetric, Dimension, MetricId};
+use super::super::super::util::Stop;
+
+#[derive(Default)]
+pub struct CgroupOptions {
+    /// Where the cgroup hierarchy is mounted; defaults to `/sys/fs/cgroup`.
+    pub root: Option<PathBuf>,
+    /// Dimensions attached to every metric. When `None` they're discovered
+    /// from the environment with `detect_dimensions`.
+    pub dimensions: Option<Vec<Dimension>>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Version {
+    V1,
+    V2,
+}
+
+/// Reads container-level resource usage out of the cgroup filesystem.
+/// Supports both the unified (v2) and the legacy (v1) hierarchies.
+pub struct CgroupPoller {
+    collector: Collector,
+    root: PathBuf,
+    version: Version,
+    dimensions: Vec<Dimension>,
+}
+
+impl CgroupPoller {
+    pub fn new(collector: Collector, options: CgroupOptions) -> CgroupPoller {
+        let root = options.root.unwrap_or_else(|| PathBuf::from("/sys/fs/cgroup"));
+        // Only the unified hierarchy has a `cgroup.controllers` at its root.
+        let version = if root.join("cgroup.controllers").exists() {
+            Version::V2
+        } else {
+            Version::V1
+        };
+        let dimensions = options.dimensions.unwrap_or_else(detect_dimensions);
+
+        CgroupPoller {
+            collector,
+            root,
+            version,
+            dimensions,
+        }
+    }
+
+    /// Blocking loop that polls the cgroup files every `interval` and pushes
+    /// the metrics into the collector.
+    pub fn run(&mut self, interval: Duration) {
+        self.run_until(interval, &Stop::new())
+    }
+
+    /// Like `run` but returns once `stop` is stopped.
+    pub fn run_until(&mut self, interval: Duration, stop: &Stop) {
+        every(interval, stop, || {
+            let metrics = self.poll();
+            if !metrics.is_empty() {
+                self.collector.push(metrics)
+            }
+        })
+    }
+
+    /// Read the current values. Files that are missing or unreadable (eg.
+    /// because a controller isn't enabled) are skipped.
+    pub fn poll(&self) -> Vec<CollectedMetric> {
+        let now = SystemTime::now();
+        let samples = match self.version {
+            Version::V2 => self.poll_v2(),
+            Version::V1 => self.poll_v1(),
+        };
+
+        samples.into_iter()
+            .map(|(name, mut dimensions, value)| {
+                dimensions.extend(self.dimensions.iter().cloned());
+                let id = MetricId::new(name.as_str(), dimensions);
+                // CPU time and IO totals only ever go up; memory is a level.
+                if name.starts_with("cgroup.memory.") {
+                    CollectedMetric::Gauge(now, id, value as f64)
+                } else {
+                    CollectedMetric::MonotonicCount(now, id, value as f64)
+                }
+            })
+            .collect()
+    }
+
+    fn poll_v2(&self) -> Vec<Sample> {
+        let mut samples = vec![];
+
+        if let Ok(contents) = read(&self.root.join("cpu.stat")) {
+            for (key, value) in parse_flat_keyed(&contents) {
+                samples.push((format!("cgroup.cpu.{}", key), vec![], value));
+            }
+        }
+        if let Ok(contents) = read(&self.root.join("memory.current")) {
+            if let Some(value) = parse_single(&contents) {
+                samples.push(("cgroup.memory.current".to_owned(), vec![], value));
+            }
+        }
+        if let Ok(contents) = read(&self.root.join("memory.max")) {
+            // Unlimited groups report "max" and are skipped.
+            if let Some(value) = parse_single(&contents) {
+                samples.push(("cgroup.memory.max".to_owned(), vec![], value));
+            }
+        }
+        if let Ok(contents) = read(&self.root.join("io.stat")) {
+            for (device, key, value) in parse_io_stat(&contents) {
+                samples.push((format!("cgroup.io.{}", key), vec![device_dimension(device)], value));
+            }
+        }
+
+        samples
+    }
+
+    fn poll_v1(&self) -> Vec<Sample> {
+        let mut samples = vec![];
+
+        if let Ok(contents) = read(&self.root.join("cpuacct/cpuacct.usage")) {
+            if let Some(value) = parse_single(&contents) {
+                // Reported in nanoseconds; normalize to v2's microseconds.
+                samples.push(("cgroup.cpu.usage_usec".to_owned(), vec![], value / 1000));
+            }
+        }
+        if let Ok(contents) = read(&self.root.join("cpu/cpu.stat")) {
+            for (key, value) in parse_flat_keyed(&contents) {
+                samples.push((format!("cgroup.cpu.{}", key), vec![], value));
+            }
+        }
+        if let Ok(contents) = read(&self.root.join("memory/memory.usage_in_bytes")) {
+            if let Some(value) = parse_single(&contents) {
+                samples.push(("cgroup.memory.current".to_owned(), vec![], value));
+            }
+        }
+        if let Ok(contents) = read(&self.root.join("memory/memory.limit_in_bytes")) {
+            if let Some(value) = parse_single(&contents) {
+                samples.push(("cgroup.memory.max".to_owned(), vec![], value));
+            }
+        }
+        if let Ok(contents) = read(&self.root.join("blkio/blkio.throttle.io_service_bytes")) {
+            for (device, key, value) in parse_blkio(&contents) {
+                samples.push((format!("cgroup.io.{}", key), vec![device_dimension(device)], value));
+            }
+        }
+
+        samples
+    }
+} // impl CgroupPoller
+
+/// Name, extra dimensions, value
+type Sample = (String, Vec<Dimension>, u64);
+
+/// Discover pod and container dimensions. Kubernetes deployments are
+/// expected to expose `POD_NAME`, `POD_NAMESPACE`, and `CONTAINER_NAME`
+/// through the downward API; the container ID is read from the process's
+/// own cgroup membership which works under both Docker and Kubernetes.
+pub fn detect_dimensions() -> Vec<Dimension> {
+    let mut dimensions = vec![];
+
+    for &(var, name) in &[("POD_NAME", "pod"), ("POD_NAMESPACE", "namespace"), ("CONTAINER_NAME", "container")] {
+        if let Ok(value) = env::var(var) {
+            dimensions.push((Atom::from(name), Atom::from(value)));
+        }
+    }
+
+    if let Ok(contents) = read(Path::new("/proc/self/cgroup")) {
+        if let Some(id) = parse_container_id(&contents) {
+            dimensions.push((Atom::from("container_id"), Atom::from(id)));
+        }
+    }
+
+    dimensions
+}
+
+fn read(path: &Path) -> io::Result<String> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+fn device_dimension(device: &str) -> Dimension {
+    (Atom::from("device"), Atom::from(device))
+}
+
+fn parse_single(contents: &str) -> Option<u64> {
+    contents.trim().parse().ok()
+}
+
+/// Parses files made of `key value` lines, eg. `cpu.stat`.
+fn parse_flat_keyed(contents: &str) -> Vec<(&str, u64)> {
+    contents.lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next().and_then(|v| v.parse().ok())) {
+                (Some(key), Some(value)) => Some((key, value)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Parses v2 `io.stat` lines of the form `8:0 rbytes=1 wbytes=2 rios=3`.
+fn parse_io_stat(contents: &str) -> Vec<(&str, &str, u64)> {
+    let mut stats = vec![];
+    for line in contents.lines() {
+        let mut parts = line.split_whitespace();
+        let device = match parts.next() {
+            Some(device) => device,
+            None => continue,
+        };
+        for pair in parts {
+            let mut kv = pair.splitn(2, '=');
+            if let (Some(key), Some(Ok(value))) = (kv.next(), kv.next().map(|v| v.parse())) {
+                stats.push((device, key, value))
+            }
+        }
+    }
+    stats
+}
+
+/// Parses v1 `blkio.throttle.io_service_bytes` lines of the form
+/// `8:0 Read 1024`, mapping them onto the v2 names.
+fn parse_blkio(contents: &str) -> Vec<(&str, &str, u64)> {
+    contents.lines()
+        .filter_map(|line| {
+            let parts = line.split_whitespace().collect::<Vec<&str>>();
+            if parts.len() != 3 {
+                return None
+            }
+            let key = match parts[1] {
+                "Read" => "rbytes",
+                "Write" => "wbytes",
+                _ => return None,
+            };
+            parts[2].parse().ok().map(|value| (parts[0], key, value))
+        })
+        .collect()
+}
+
+/// Finds a 64-character hex container ID in `/proc/self/cgroup` paths such
+/// as `0::/kubepods/pod1234/<id>` or `12:memory:/docker/<id>`.
+fn parse_container_id(contents: &str) -> Option<String> {
+    for line in contents.lines() {
+        let path = match line.rsplit(':').next() {
+            Some(path) => path,
+            None => continue,
+        };
+        for segment in path.split('/') {
+            // systemd-managed runtimes wrap the ID, eg. `docker-<id>.scope`.
+            let segment = segment.trim_end_matches(".scope");
+            let candidate = segment.rsplit('-').next().unwrap_or(segment);
+            if candidate.len() == 64 && candidate.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Some(candidate.to_owned())
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_flat_keyed_files() {
+        assert_eq!(
+            parse_flat_keyed("usage_usec 100\nuser_usec 60\nsystem_usec 40\n"),
+            vec![("usage_usec", 100), ("user_usec", 60), ("system_usec", 40)]
+        );
+    }
+
+    #[test]
+    fn it_parses_io_stat() {
+        assert_eq!(
+            parse_io_stat("8:0 rbytes=1024 wbytes=2048\n"),
+            vec![("8:0", "rbytes", 1024), ("8:0", "wbytes", 2048)]
+        );
+    }
+
+    #[test]
+    fn it_parses_blkio() {
+        assert_eq!(
+            parse_blkio("8:0 Read 1024\n8:0 Write 2048\n8:0 Total 3072\nTotal 3072\n"),
+            vec![("8:0", "rbytes", 1024), ("8:0", "wbytes", 2048)]
+        );
+    }
+
+    #[test]
+    fn it_parses_container_ids() {
+        let id = "3f4e1ac2b7d94e5f8a6b0c1d2e3f4a5b6c7d8e9f0a1b2c3d4e5f6a7b8c9d0e1f";
+        assert_eq!(
+            parse_container_id(&format!("12:memory:/docker/{}\n", id)),
+            Some(id.to_owned())
+        );
+        assert_eq!(
+            parse_container_id(&format!("0::/system.slice/docker-{}.scope\n", id)),
+            Some(id.to_owned())
+        );
+        assert_eq!(parse_container_id("0::/\n"), None);
+    }
+}
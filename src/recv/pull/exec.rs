@@ -0,0 +1,284 @@
+use std::io::{self, Read};
+use std::process::{Command, Stdio};
+use std::str;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use string_cache::DefaultAtom as Atom;
+
+use super::every;
+use super::super::collector::Collector;
+use super::super::push::statsd::parse_metrics;
+use super::super::super::metric::{CollectedMetric, Dimension, MetricId};
+use super::super::super::util::Stop;
+
+/// Format of the lines the command writes to stdout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExecFormat {
+    /// StatsD lines, eg. `foo.bar:1|c`.
+    Statsd,
+    /// InfluxDB line protocol, eg. `cpu,host=a usage=0.5 1500000000000000000`.
+    Influx,
+}
+
+pub struct ExecOptions {
+    /// The program to run, directly rather than through a shell.
+    pub command: String,
+    pub args: Vec<String>,
+    /// Defaults to `statsd` in a config.
+    pub format: ExecFormat,
+    /// How long the command may run before it's killed; defaults to 10
+    /// seconds.
+    pub timeout: Option<Duration>,
+}
+
+/// Runs a command and collects the metrics it prints on stdout. This is the
+/// escape hatch for collecting from anything that doesn't have a native
+/// receiver.
+pub struct ExecPoller {
+    collector: Collector,
+    command: String,
+    args: Vec<String>,
+    format: ExecFormat,
+    timeout: Duration,
+}
+
+impl ExecPoller {
+    pub fn new(collector: Collector, options: ExecOptions) -> ExecPoller {
+        ExecPoller {
+            collector,
+            command: options.command,
+            args: options.args,
+            format: options.format,
+            timeout: options.timeout.unwrap_or_else(|| Duration::from_secs(10)),
+        }
+    }
+
+    /// Blocking loop that runs the command every `interval` and pushes the
+    /// parsed metrics into the collector.
+    pub fn run(&mut self, interval: Duration) {
+        self.run_until(interval, &Stop::new())
+    }
+
+    /// Like `run` but returns once `stop` is stopped.
+    pub fn run_until(&mut self, interval: Duration, stop: &Stop) {
+        every(interval, stop, || {
+            match self.poll() {
+                Ok(metrics) => if !metrics.is_empty() {
+                    self.collector.push(metrics)
+                },
+                Err(err) => warn!("Error running {}: {}", self.command, err),
+            }
+        })
+    }
+
+    /// Run the command once and parse its output. Lines that fail to parse
+    /// are skipped so that one bad line doesn't discard the whole run.
+    pub fn poll(&self) -> io::Result<Vec<CollectedMetric>> {
+        let output = self.execute()?;
+        let now = SystemTime::now();
+
+        let metrics = match self.format {
+            ExecFormat::Statsd => {
+                output.lines()
+                    .filter_map(|line| parse_metrics(line.trim_end().as_bytes()).ok())
+                    .flat_map(|metrics| metrics.into_iter().map(|metric| metric.collect(now)))
+                    .collect()
+            },
+            ExecFormat::Influx => {
+                output.lines()
+                    .filter_map(|line| parse_influx_line(line, now))
+                    .flat_map(|metrics| metrics.into_iter())
+                    .collect()
+            },
+        };
+        Ok(metrics)
+    }
+
+    fn execute(&self) -> io::Result<String> {
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        // Drain stdout on another thread so a chatty command can't fill the
+        // pipe and block while we're waiting on it.
+        let mut stdout = child.stdout.take().unwrap();
+        let reader = thread::spawn(move || {
+            let mut buf = vec![];
+            stdout.read_to_end(&mut buf).map(|_| buf)
+        });
+
+        let started = Instant::now();
+        loop {
+            if child.try_wait()?.is_some() {
+                break
+            }
+            if started.elapsed() > self.timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", self.command)))
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let buf = reader.join()
+            .map_err(|_| io::Error::other("failed to read command output"))??;
+        String::from_utf8(buf)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+} // impl ExecPoller
+
+/// Parse one line of InfluxDB line protocol into gauges. Each field becomes
+/// its own metric named `measurement.field`, except for a field named
+/// `value` which uses just the measurement name. String fields are ignored.
+fn parse_influx_line(line: &str, now: SystemTime) -> Option<Vec<CollectedMetric>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None
+    }
+
+    let sections = split_unescaped(line, ' ');
+    if sections.len() < 2 || sections.len() > 3 {
+        return None
+    }
+
+    let mut series = split_unescaped(&sections[0], ',').into_iter();
+    let measurement = unescape(&series.next()?);
+    let mut dimensions = vec![];
+    for tag in series {
+        let pair = split_unescaped(&tag, '=');
+        if pair.len() != 2 {
+            return None
+        }
+        dimensions.push((Atom::from(unescape(&pair[0])), Atom::from(unescape(&pair[1]))));
+    }
+
+    let time = match sections.get(2) {
+        Some(timestamp) => {
+            let nanos: u64 = timestamp.parse().ok()?;
+            UNIX_EPOCH + Duration::from_nanos(nanos)
+        },
+        None => now,
+    };
+
+    let mut metrics = vec![];
+    for field in split_unescaped(&sections[1], ',') {
+        let pair = split_unescaped(&field, '=');
+        if pair.len() != 2 {
+            return None
+        }
+        let key = unescape(&pair[0]);
+        let value = match parse_influx_value(&pair[1]) {
+            Some(value) => value,
+            None => continue,
+        };
+        let name = if key == "value" {
+            measurement.clone()
+        } else {
+            format!("{}.{}", measurement, key)
+        };
+        metrics.push(influx_metric(time, name, dimensions.clone(), value));
+    }
+    Some(metrics)
+}
+
+fn influx_metric(time: SystemTime, name: String, dimensions: Vec<Dimension>, value: f64) -> CollectedMetric {
+    CollectedMetric::Gauge(time, MetricId::new(name, dimensions), value)
+}
+
+fn parse_influx_value(value: &str) -> Option<f64> {
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => Some(1.0),
+        "f" | "F" | "false" | "False" | "FALSE" => Some(0.0),
+        _ if value.starts_with('"') => None,
+        _ if value.ends_with('i') || value.ends_with('u') => value[..value.len() - 1].parse().ok(),
+        _ => value.parse().ok(),
+    }
+}
+
+/// Split on `separator` unless it's escaped with a backslash or inside a
+/// double-quoted string. Escapes are preserved for `unescape`.
+fn split_unescaped(input: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut chars = input.chars();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                current.push(c);
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped)
+                }
+            },
+            '"' => {
+                quoted = !quoted;
+                current.push(c)
+            },
+            _ if c == separator && !quoted => {
+                parts.push(current);
+                current = String::new()
+            },
+            _ => current.push(c),
+        }
+    }
+    parts.push(current);
+    parts
+}
+
+fn unescape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(escaped) = chars.next() {
+                output.push(escaped)
+            }
+        } else {
+            output.push(c)
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gauge(time: SystemTime, name: &str, dimensions: Vec<(&str, &str)>, value: f64) -> CollectedMetric {
+        let dimensions = dimensions.into_iter()
+            .map(|(key, value)| (Atom::from(key), Atom::from(value)))
+            .collect();
+        CollectedMetric::Gauge(time, MetricId::new(name, dimensions), value)
+    }
+
+    #[test]
+    fn it_parses_influx_lines() {
+        let now = SystemTime::now();
+        assert_eq!(
+            parse_influx_line("cpu,host=a,region=us\\ east usage=0.5,idle=90i", now),
+            Some(vec![
+                gauge(now, "cpu.usage", vec![("host", "a"), ("region", "us east")], 0.5),
+                gauge(now, "cpu.idle", vec![("host", "a"), ("region", "us east")], 90.0),
+            ])
+        );
+    }
+
+    #[test]
+    fn it_parses_influx_timestamps_and_value_fields() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        assert_eq!(
+            parse_influx_line("queue value=3,label=\"a b\" 1500000000000000000", SystemTime::now()),
+            Some(vec![gauge(time, "queue", vec![], 3.0)])
+        );
+    }
+
+    #[test]
+    fn it_rejects_malformed_influx_lines() {
+        assert_eq!(parse_influx_line("cpu", SystemTime::now()), None);
+        assert_eq!(parse_influx_line("cpu,host usage=1", SystemTime::now()), None);
+    }
+}
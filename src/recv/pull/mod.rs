@@ -0,0 +1,29 @@
+//! Pull receivers periodically poll a source for its current state and push
+//! the resulting metrics into a `Collector`.
+
+use std::time::{Duration, Instant};
+
+use super::super::util::Stop;
+
+pub mod cgroup;
+pub mod exec;
+pub mod snmp;
+
+pub use self::cgroup::{CgroupOptions, CgroupPoller};
+pub use self::exec::{ExecFormat, ExecOptions, ExecPoller};
+pub use self::snmp::{SnmpDevice, SnmpKind, SnmpOid, SnmpOptions, SnmpPoller};
+
+/// Calls `poll` every `interval` until `stop` is stopped. The time spent
+/// inside `poll` is subtracted from the sleep so that slow sources don't
+/// drift the schedule.
+fn every<F: FnMut()>(interval: Duration, stop: &Stop, mut poll: F) {
+    while !stop.is_stopped() {
+        let started = Instant::now();
+        poll();
+
+        let elapsed = started.elapsed();
+        if elapsed < interval && !stop.sleep(interval - elapsed) {
+            return
+        }
+    }
+}
@@ -0,0 +1,516 @@
+//! SNMPv2c poller. Speaks just enough BER to issue `GetRequest` and
+//! `GetNextRequest` PDUs and decode their responses.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, SystemTime};
+
+use string_cache::DefaultAtom as Atom;
+
+use super::every;
+use super::super::collector::Collector;
+use super::super::super::metric::{CollectedMetric, Dimension, MetricId};
+use super::super::super::util::Stop;
+
+/// A network device to poll.
+#[derive(Clone, Debug)]
+pub struct SnmpDevice {
+    pub address: SocketAddr,
+    pub community: String,
+    /// Attached to every metric from the device as the `device` dimension;
+    /// defaults to the address.
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SnmpKind {
+    /// The value is reported as-is.
+    Gauge,
+    /// The value is a cumulative counter; the increase since the previous
+    /// poll is reported as a count.
+    Counter,
+}
+
+/// An OID to read from every device.
+#[derive(Clone, Debug)]
+pub struct SnmpOid {
+    /// Dotted notation, eg. `1.3.6.1.2.1.2.2.1.10`.
+    pub oid: String,
+    /// Name of the metric the value is reported as.
+    pub name: String,
+    pub kind: SnmpKind,
+    /// Walk the subtree rooted at the OID (eg. a table column) instead of
+    /// getting a single value. The suffix of each OID under the root is
+    /// attached as the `index` dimension.
+    pub walk: bool,
+}
+
+pub struct SnmpOptions {
+    pub devices: Vec<SnmpDevice>,
+    pub oids: Vec<SnmpOid>,
+    /// How long to wait for each response; defaults to 2 seconds.
+    pub timeout: Option<Duration>,
+}
+
+pub struct SnmpPoller {
+    collector: Collector,
+    devices: Vec<SnmpDevice>,
+    oids: Vec<(SnmpOid, Vec<u32>)>,
+    timeout: Duration,
+    request_id: i32,
+    /// Last seen raw value of every counter, keyed by device and full OID.
+    counters: HashMap<(SocketAddr, Vec<u32>), u64>,
+}
+
+impl SnmpPoller {
+    pub fn new(collector: Collector, options: SnmpOptions) -> Result<SnmpPoller, String> {
+        let oids = options.oids.into_iter()
+            .map(|oid| parse_oid(&oid.oid).map(|parsed| (oid, parsed)))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(SnmpPoller {
+            collector,
+            devices: options.devices,
+            oids,
+            timeout: options.timeout.unwrap_or_else(|| Duration::from_secs(2)),
+            request_id: 0,
+            counters: HashMap::new(),
+        })
+    }
+
+    /// Blocking loop that polls every device every `interval` and pushes the
+    /// metrics into the collector.
+    pub fn run(&mut self, interval: Duration) {
+        self.run_until(interval, &Stop::new())
+    }
+
+    /// Like `run` but returns once `stop` is stopped.
+    pub fn run_until(&mut self, interval: Duration, stop: &Stop) {
+        every(interval, stop, || {
+            let metrics = self.poll();
+            if !metrics.is_empty() {
+                self.collector.push(metrics)
+            }
+        })
+    }
+
+    /// Poll every device once. Devices that fail to respond are skipped.
+    pub fn poll(&mut self) -> Vec<CollectedMetric> {
+        let mut metrics = vec![];
+        for device in self.devices.clone() {
+            match self.poll_device(&device) {
+                Ok(device_metrics) => metrics.extend(device_metrics),
+                Err(err) => warn!("Error polling SNMP device {}: {}", device.address, err),
+            }
+        }
+        metrics
+    }
+
+    fn poll_device(&mut self, device: &SnmpDevice) -> io::Result<Vec<CollectedMetric>> {
+        let socket = UdpSocket::bind(if device.address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.connect(device.address)?;
+
+        let device_name = device.name.clone().unwrap_or_else(|| device.address.to_string());
+        let now = SystemTime::now();
+        let mut metrics = vec![];
+
+        for (oid, root) in self.oids.clone() {
+            let values = if oid.walk {
+                self.walk(&socket, &device.community, &root)?
+            } else {
+                match self.request(&socket, &device.community, PDU_GET_REQUEST, &root)? {
+                    (responded, value) if responded == root => vec![(responded, value)],
+                    _ => vec![],
+                }
+            };
+
+            for (full_oid, value) in values {
+                let raw = match value.as_u64() {
+                    Some(raw) => raw,
+                    None => continue,
+                };
+
+                let mut dimensions: Vec<Dimension> = vec![(Atom::from("device"), Atom::from(device_name.as_str()))];
+                if oid.walk {
+                    let index = full_oid[root.len()..].iter()
+                        .map(|n| n.to_string())
+                        .collect::<Vec<String>>()
+                        .join(".");
+                    dimensions.push((Atom::from("index"), Atom::from(index)));
+                }
+                let id = MetricId::new(oid.name.as_str(), dimensions);
+
+                match oid.kind {
+                    SnmpKind::Gauge => {
+                        metrics.push(CollectedMetric::Gauge(now, id, raw as f64))
+                    },
+                    SnmpKind::Counter => {
+                        let key = (device.address, full_oid);
+                        if let Some(previous) = self.counters.insert(key, raw) {
+                            let increase = counter_increase(previous, raw, &value);
+                            metrics.push(CollectedMetric::Count(now, id, increase.min(i64::MAX as u64) as i64))
+                        }
+                    },
+                }
+            }
+        }
+
+        Ok(metrics)
+    }
+
+    fn walk(&mut self, socket: &UdpSocket, community: &str, root: &[u32]) -> io::Result<Vec<(Vec<u32>, Value)>> {
+        let mut values = vec![];
+        let mut current = root.to_vec();
+        loop {
+            let (next, value) = self.request(socket, community, PDU_GET_NEXT_REQUEST, &current)?;
+            // Stop once we've left the subtree or the agent has nothing more.
+            if !next.starts_with(root) || next <= current || value == Value::EndOfMibView {
+                break
+            }
+            current = next.clone();
+            values.push((next, value));
+        }
+        Ok(values)
+    }
+
+    fn request(&mut self, socket: &UdpSocket, community: &str, pdu: u8, oid: &[u32]) -> io::Result<(Vec<u32>, Value)> {
+        self.request_id = self.request_id.wrapping_add(1);
+        let request_id = self.request_id;
+        socket.send(&encode_request(community, pdu, request_id, oid))?;
+
+        let mut buf = [0; 65535];
+        loop {
+            let len = socket.recv(&mut buf)?;
+            match decode_response(&buf[..len]) {
+                Ok(response) => {
+                    // Stale responses to earlier timed-out requests are ignored.
+                    if response.request_id != request_id {
+                        continue
+                    }
+                    if response.error_status != 0 {
+                        return Err(io::Error::other(format!("SNMP error status {}", response.error_status)))
+                    }
+                    return Ok((response.oid, response.value))
+                },
+                Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+            }
+        }
+    }
+} // impl SnmpPoller
+
+/// Increase between two readings of a counter, accounting for 32-bit
+/// counters wrapping around.
+fn counter_increase(previous: u64, current: u64, value: &Value) -> u64 {
+    if current >= previous {
+        current - previous
+    } else if let Value::Counter32(_) = *value {
+        (u32::MAX as u64 - previous) + current + 1
+    } else {
+        // The device most likely restarted.
+        current
+    }
+}
+
+/// Parse a dotted OID, eg. `1.3.6.1.2.1.1.3.0` with an optional leading
+/// dot. Every arc must be a number, and the first two must fit the one
+/// byte `encode_oid` packs them into.
+pub fn parse_oid(oid: &str) -> Result<Vec<u32>, String> {
+    let invalid = || format!("Invalid OID: {}", oid);
+    let parsed = oid.strip_prefix('.').unwrap_or(oid)
+        .split('.')
+        .map(|n| if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) { n.parse::<u32>().ok() } else { None })
+        .collect::<Option<Vec<u32>>>()
+        .ok_or_else(invalid)?;
+    match parsed[..] {
+        [0..=1, 0..=39, ..] | [2, 0..=175, ..] => Ok(parsed),
+        _ => Err(invalid()),
+    }
+}
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_IP_ADDRESS: u8 = 0x40;
+const TAG_COUNTER32: u8 = 0x41;
+const TAG_GAUGE32: u8 = 0x42;
+const TAG_TIMETICKS: u8 = 0x43;
+const TAG_COUNTER64: u8 = 0x46;
+const TAG_NO_SUCH_OBJECT: u8 = 0x80;
+const TAG_NO_SUCH_INSTANCE: u8 = 0x81;
+const TAG_END_OF_MIB_VIEW: u8 = 0x82;
+
+const PDU_GET_REQUEST: u8 = 0xa0;
+const PDU_GET_NEXT_REQUEST: u8 = 0xa1;
+const PDU_RESPONSE: u8 = 0xa2;
+
+const VERSION_2C: i64 = 1;
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Integer(i64),
+    Counter32(u32),
+    Gauge32(u32),
+    TimeTicks(u32),
+    Counter64(u64),
+    /// Strings, addresses, and other values we can't report as numbers.
+    Other,
+    NoSuchObject,
+    EndOfMibView,
+}
+
+impl Value {
+    fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Integer(value) if value >= 0 => Some(value as u64),
+            Value::Counter32(value) | Value::Gauge32(value) | Value::TimeTicks(value) => Some(value as u64),
+            Value::Counter64(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Response {
+    request_id: i32,
+    error_status: i64,
+    oid: Vec<u32>,
+    value: Value,
+}
+
+fn encode_request(community: &str, pdu: u8, request_id: i32, oid: &[u32]) -> Vec<u8> {
+    let varbind = encode_tlv(TAG_SEQUENCE, &[encode_oid(oid), encode_tlv(TAG_NULL, &[])].concat());
+    let varbinds = encode_tlv(TAG_SEQUENCE, &varbind);
+    let pdu = encode_tlv(pdu, &[
+        encode_integer(request_id as i64),
+        encode_integer(0), // error-status
+        encode_integer(0), // error-index
+        varbinds,
+    ].concat());
+
+    encode_tlv(TAG_SEQUENCE, &[
+        encode_integer(VERSION_2C),
+        encode_tlv(TAG_OCTET_STRING, community.as_bytes()),
+        pdu,
+    ].concat())
+}
+
+fn encode_tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8)
+    } else {
+        let bytes = (len as u32).to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (4 - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+fn encode_integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    // Strip redundant leading bytes while keeping the sign bit intact.
+    let mut start = 0;
+    while start < 7 {
+        let (byte, next) = (bytes[start], bytes[start + 1]);
+        if (byte == 0x00 && next & 0x80 == 0) || (byte == 0xff && next & 0x80 != 0) {
+            start += 1
+        } else {
+            break
+        }
+    }
+    encode_tlv(TAG_INTEGER, &bytes[start..])
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut contents = vec![(oid[0] * 40 + oid[1]) as u8];
+    for &n in &oid[2..] {
+        let mut chunk = vec![(n & 0x7f) as u8];
+        let mut rest = n >> 7;
+        while rest > 0 {
+            chunk.push(((rest & 0x7f) as u8) | 0x80);
+            rest >>= 7;
+        }
+        chunk.reverse();
+        contents.extend(chunk);
+    }
+    encode_tlv(TAG_OID, &contents)
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Decoder<'a> {
+        Decoder { buf }
+    }
+
+    fn read_tlv(&mut self) -> Result<(u8, &'a [u8]), String> {
+        if self.buf.len() < 2 {
+            return Err("Truncated TLV".to_owned())
+        }
+        let tag = self.buf[0];
+        let (len, header) = if self.buf[1] & 0x80 == 0 {
+            (self.buf[1] as usize, 2)
+        } else {
+            let count = (self.buf[1] & 0x7f) as usize;
+            if count == 0 || count > 4 || self.buf.len() < 2 + count {
+                return Err("Invalid length".to_owned())
+            }
+            let len = self.buf[2..2 + count].iter().fold(0usize, |len, b| (len << 8) | *b as usize);
+            (len, 2 + count)
+        };
+        if self.buf.len() < header + len {
+            return Err("Truncated contents".to_owned())
+        }
+        let contents = &self.buf[header..header + len];
+        self.buf = &self.buf[header + len..];
+        Ok((tag, contents))
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<&'a [u8], String> {
+        let (tag, contents) = self.read_tlv()?;
+        if tag != expected {
+            return Err(format!("Expected tag {:#x}, got {:#x}", expected, tag))
+        }
+        Ok(contents)
+    }
+
+    fn read_integer(&mut self) -> Result<i64, String> {
+        self.expect(TAG_INTEGER).map(decode_signed)
+    }
+}
+
+fn decode_signed(contents: &[u8]) -> i64 {
+    let initial = if contents.first().is_some_and(|b| b & 0x80 != 0) { -1 } else { 0 };
+    contents.iter().fold(initial, |value, b| (value << 8) | *b as i64)
+}
+
+fn decode_unsigned(contents: &[u8]) -> u64 {
+    contents.iter().fold(0, |value, b| (value << 8) | *b as u64)
+}
+
+fn decode_oid(contents: &[u8]) -> Result<Vec<u32>, String> {
+    let first = *contents.first().ok_or_else(|| "Empty OID".to_owned())? as u32;
+    let mut oid = vec![first / 40, first % 40];
+    let mut n: u32 = 0;
+    for &b in &contents[1..] {
+        n = (n << 7) | (b & 0x7f) as u32;
+        if b & 0x80 == 0 {
+            oid.push(n);
+            n = 0;
+        }
+    }
+    Ok(oid)
+}
+
+fn decode_response(buf: &[u8]) -> Result<Response, String> {
+    let mut message = Decoder::new(Decoder::new(buf).expect(TAG_SEQUENCE)?);
+    let _version = message.read_integer()?;
+    let _community = message.expect(TAG_OCTET_STRING)?;
+
+    let mut pdu = Decoder::new(message.expect(PDU_RESPONSE)?);
+    let request_id = pdu.read_integer()? as i32;
+    let error_status = pdu.read_integer()?;
+    let _error_index = pdu.read_integer()?;
+
+    let mut varbinds = Decoder::new(pdu.expect(TAG_SEQUENCE)?);
+    let mut varbind = Decoder::new(varbinds.expect(TAG_SEQUENCE)?);
+    let oid = decode_oid(varbind.expect(TAG_OID)?)?;
+    let (tag, contents) = varbind.read_tlv()?;
+    let value = match tag {
+        TAG_INTEGER => Value::Integer(decode_signed(contents)),
+        TAG_COUNTER32 => Value::Counter32(decode_unsigned(contents) as u32),
+        TAG_GAUGE32 => Value::Gauge32(decode_unsigned(contents) as u32),
+        TAG_TIMETICKS => Value::TimeTicks(decode_unsigned(contents) as u32),
+        TAG_COUNTER64 => Value::Counter64(decode_unsigned(contents)),
+        TAG_NO_SUCH_OBJECT | TAG_NO_SUCH_INSTANCE => Value::NoSuchObject,
+        TAG_END_OF_MIB_VIEW => Value::EndOfMibView,
+        TAG_OCTET_STRING | TAG_IP_ADDRESS | TAG_OID | TAG_NULL => Value::Other,
+        _ => Value::Other,
+    };
+
+    Ok(Response {
+        request_id,
+        error_status,
+        oid,
+        value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_encodes_get_requests() {
+        // snmpget -v2c -c public <host> 1.3.6.1.2.1.1.3.0
+        assert_eq!(
+            encode_request("public", PDU_GET_REQUEST, 1, &[1, 3, 6, 1, 2, 1, 1, 3, 0]),
+            vec![
+                0x30, 0x26, 0x02, 0x01, 0x01, 0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c',
+                0xa0, 0x19, 0x02, 0x01, 0x01, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00,
+                0x30, 0x0e, 0x30, 0x0c, 0x06, 0x08, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x03, 0x00,
+                0x05, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_oids() {
+        assert_eq!(parse_oid(".1.3.6.1.2.1.1.3.0"), Ok(vec![1, 3, 6, 1, 2, 1, 1, 3, 0]));
+        assert_eq!(parse_oid("2.175"), Ok(vec![2, 175]));
+        for oid in &["", "1", "1..3", "1.3.", "..1.3", "1.3.six", "1.+3", "1.3.-6", "3.1", "1.40", "2.176"] {
+            assert_eq!(parse_oid(oid), Err(format!("Invalid OID: {}", oid)), "{:?}", oid);
+        }
+    }
+
+    #[test]
+    fn it_encodes_multibyte_oid_components() {
+        assert_eq!(encode_oid(&[1, 3, 6, 1, 4, 1, 2021]), vec![0x06, 0x07, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x8f, 0x65]);
+        assert_eq!(decode_oid(&[0x2b, 0x06, 0x01, 0x04, 0x01, 0x8f, 0x65]), Ok(vec![1, 3, 6, 1, 4, 1, 2021]));
+    }
+
+    #[test]
+    fn it_decodes_responses() {
+        let varbind = encode_tlv(TAG_SEQUENCE, &[
+            encode_oid(&[1, 3, 6, 1, 2, 1, 2, 2, 1, 10, 1]),
+            encode_tlv(TAG_COUNTER32, &[0x00, 0xff, 0xff, 0xff, 0xff]),
+        ].concat());
+        let pdu = encode_tlv(PDU_RESPONSE, &[
+            encode_integer(300),
+            encode_integer(0),
+            encode_integer(0),
+            encode_tlv(TAG_SEQUENCE, &varbind),
+        ].concat());
+        let message = encode_tlv(TAG_SEQUENCE, &[
+            encode_integer(VERSION_2C),
+            encode_tlv(TAG_OCTET_STRING, b"public"),
+            pdu,
+        ].concat());
+
+        assert_eq!(
+            decode_response(&message),
+            Ok(Response {
+                request_id: 300,
+                error_status: 0,
+                oid: vec![1, 3, 6, 1, 2, 1, 2, 2, 1, 10, 1],
+                value: Value::Counter32(u32::MAX),
+            })
+        );
+    }
+
+    #[test]
+    fn it_handles_counter_wraparound() {
+        assert_eq!(counter_increase(10, 15, &Value::Counter32(15)), 5);
+        assert_eq!(counter_increase(u32::MAX as u64 - 1, 3, &Value::Counter32(3)), 5);
+        assert_eq!(counter_increase(100, 3, &Value::Counter64(3)), 3);
+    }
+}
@@ -0,0 +1,236 @@
+//! Tokio-based StatsD listeners for when a thread per socket and per
+//! connection doesn't scale, eg. with thousands of TCP clients. They parse
+//! and collect exactly like the blocking listeners.
+//!
+//! Each listener is a future to spawn on a runtime, and must be bound from
+//! within one:
+//!
+//! ```ignore
+//! let listener = StatsdTcpListener::bind(db.collector(), "0.0.0.0:8125")?;
+//! tokio::spawn(listener);
+//! ```
+
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::net::{self, SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::str;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::{self, Instant, Sleep};
+
+use super::record;
+use super::super::super::collector::Collector;
+
+/// Clients have this long to send us data before they're dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Receives StatsD UDP datagrams. Resolves only if the socket fails.
+pub struct StatsdUdpListener {
+    collector: Collector,
+    socket: UdpSocket,
+    buf: Vec<u8>,
+}
+
+impl StatsdUdpListener {
+    pub fn bind<A: ToSocketAddrs>(collector: Collector, addr: A) -> io::Result<StatsdUdpListener> {
+        let socket = net::UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(StatsdUdpListener {
+            collector,
+            socket: UdpSocket::from_std(socket)?,
+            // Big enough to hold an ethernet frame, like the blocking listener.
+            buf: vec![0; 1500],
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl Future for StatsdUdpListener {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let mut buf = ReadBuf::new(&mut this.buf);
+            match this.socket.poll_recv_from(cx, &mut buf) {
+                Poll::Ready(Ok(peer)) => match str::from_utf8(buf.filled()) {
+                    Ok(message) => record(&this.collector, message, SystemTime::now(), peer),
+                    Err(err) => debug!("Dropping StatsD datagram ({} bytes) from {}: {}", buf.filled().len(), peer, err),
+                },
+                Poll::Ready(Err(err)) => {
+                    error!("Error receiving StatsD datagrams: {}", err);
+                    return Poll::Ready(Err(err))
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Accepts StatsD TCP connections, each read as its own task on the
+/// runtime. Resolves only if accepting fails.
+pub struct StatsdTcpListener {
+    collector: Arc<Collector>,
+    listener: TcpListener,
+}
+
+impl StatsdTcpListener {
+    pub fn bind<A: ToSocketAddrs>(collector: Collector, addr: A) -> io::Result<StatsdTcpListener> {
+        let listener = net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(StatsdTcpListener {
+            collector: Arc::new(collector),
+            listener: TcpListener::from_std(listener)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl Future for StatsdTcpListener {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            match self.listener.poll_accept(cx) {
+                Poll::Ready(Ok((stream, peer))) => {
+                    debug!("Accepted StatsD connection from {}", peer);
+                    tokio::spawn(Connection {
+                        collector: self.collector.clone(),
+                        stream,
+                        peer,
+                        line: vec![],
+                        idle: Box::pin(time::sleep(IDLE_TIMEOUT)),
+                    });
+                },
+                Poll::Ready(Err(err)) => {
+                    error!("Error accepting StatsD connections: {}", err);
+                    return Poll::Ready(Err(err))
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Reads newline-separated lines from one client until it hangs up, errors,
+/// or is idle for too long.
+struct Connection {
+    collector: Arc<Collector>,
+    stream: TcpStream,
+    peer: SocketAddr,
+    /// Bytes read since the last newline.
+    line: Vec<u8>,
+    idle: Pin<Box<Sleep>>,
+}
+
+impl Connection {
+    fn record_lines(&mut self) {
+        while let Some(end) = self.line.iter().position(|&byte| byte == b'\n') {
+            let line = self.line.drain(..=end).collect::<Vec<u8>>();
+            self.record(&line);
+        }
+    }
+
+    fn record(&self, line: &[u8]) {
+        match str::from_utf8(line) {
+            Ok(line) => record(&self.collector, line, SystemTime::now(), self.peer),
+            Err(err) => debug!("Dropping StatsD line ({} bytes) from {}: {}", line.len(), self.peer, err),
+        }
+    }
+}
+
+impl Future for Connection {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        loop {
+            let mut chunk = [0; 4096];
+            let mut buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.stream).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) if buf.filled().is_empty() => {
+                    // The client hung up; whatever follows the last newline
+                    // is a line too.
+                    let rest = mem::take(&mut this.line);
+                    if !rest.is_empty() {
+                        this.record(&rest);
+                    }
+                    debug!("StatsD connection from {} closed", this.peer);
+                    return Poll::Ready(())
+                },
+                Poll::Ready(Ok(())) => {
+                    this.line.extend_from_slice(buf.filled());
+                    this.record_lines();
+                    this.idle.as_mut().reset(Instant::now() + IDLE_TIMEOUT);
+                },
+                Poll::Ready(Err(err)) => {
+                    warn!("Error reading StatsD line from {}: {}", this.peer, err);
+                    return Poll::Ready(())
+                },
+                Poll::Pending => {
+                    let idle = this.idle.as_mut().poll(cx);
+                    if idle.is_ready() {
+                        debug!("Closing idle StatsD connection from {}", this.peer);
+                    }
+                    return idle
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::thread;
+
+    use super::super::super::super::super::db::{AggregatedMetric, Db, DbOptions};
+    use super::super::super::super::super::metric::MetricId;
+
+    #[test]
+    fn it_collects_lines_split_across_reads() {
+        let db = Db::new(DbOptions::default());
+        let subscription = db.aggregation_subscribe();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let listener = {
+            let _context = runtime.enter();
+            StatsdTcpListener::bind(db.collector(), "127.0.0.1:0").unwrap()
+        };
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || runtime.block_on(listener));
+
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        stream.write_all(b"requests:1|c\nreq").unwrap();
+        stream.flush().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        stream.write_all(b"uests:2|c").unwrap();
+        drop(stream);
+
+        for _ in 0..200 {
+            if db.stats().backlog == 2 {
+                break
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        db.aggregate(None);
+        let aggregated = subscription.recv().unwrap();
+        assert!(aggregated.iter().any(|metric| match *metric {
+            AggregatedMetric::Count(_, ref id, 3) => *id == MetricId::from("requests"),
+            _ => false,
+        }));
+    }
+}
@@ -0,0 +1,346 @@
+//! Capturing the payloads StatsD listeners receive to a file, and replaying
+//! captures, eg. to reproduce a parsing or aggregation problem seen in
+//! production or to load test with real traffic.
+//!
+//! A capture starts with `CAPTURE_HEADER` and is followed by a record per
+//! payload (a datagram, or a line over TCP): when it was received as
+//! big-endian nanoseconds since the epoch in 8 bytes, its length in 4, and
+//! then its bytes.
+//!
+//! Once a capture reaches its `max_bytes` it's moved aside to the same
+//! path with `.1` appended, replacing the previous one, and a new capture
+//! is started, so at most about twice that is kept.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::Batch;
+use super::super::super::collector::Collector;
+
+pub const CAPTURE_HEADER: &[u8] = b"metriqs capture 1\n";
+
+/// How long captured payloads can wait to be flushed to the file.
+const FLUSH_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Default)]
+pub struct CaptureOptions {
+    /// File to write, appending to it if it's already a capture.
+    pub path: PathBuf,
+    /// How many payloads can wait to be written before more are dropped;
+    /// defaults to 10,000.
+    pub max_pending: Option<usize>,
+    /// Size at which the capture is moved aside and a new one started;
+    /// defaults to 1 GiB.
+    pub max_bytes: Option<u64>,
+}
+
+/// Writes the payloads a StatsD listener receives, with when they were
+/// received, to a capture file. Payloads are written from a separate
+/// thread, so a slow disk drops payloads instead of slowing the listener
+/// down.
+#[derive(Clone)]
+pub struct Capture {
+    send: SyncSender<(SystemTime, Vec<u8>)>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Capture {
+    /// Fails if the file can't be opened, or has something other than a
+    /// capture in it. Nothing in it is replaced.
+    pub fn new(options: CaptureOptions) -> io::Result<Capture> {
+        let file = CaptureFile::open(options.path, options.max_bytes.unwrap_or(1 << 30))?;
+        let (send, recv) = sync_channel(options.max_pending.unwrap_or(10_000));
+        thread::spawn(move || {
+            let mut file = file;
+            if let Err(err) = Capture::run(recv, &mut file) {
+                error!("Error capturing StatsD payloads to {}: {}", file.path.display(), err)
+            }
+        });
+
+        Ok(Capture {
+            send,
+            dropped: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Queue `payload`, received at `received`, to be written.
+    pub fn capture(&self, payload: &[u8], received: SystemTime) {
+        if let Err(TrySendError::Full(_)) = self.send.try_send((received, payload.to_vec())) {
+            if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                warn!("Dropping captured StatsD payloads; the capture file can't keep up")
+            }
+        }
+    }
+
+    /// How many payloads have been dropped because too many were waiting.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Writes until every `Capture` for `recv` has been dropped, flushing
+    /// whenever there's nothing to write.
+    fn run(recv: Receiver<(SystemTime, Vec<u8>)>, file: &mut CaptureFile) -> io::Result<()> {
+        loop {
+            match recv.recv_timeout(FLUSH_DELAY) {
+                Ok((received, payload)) => file.write(received, &payload)?,
+                Err(RecvTimeoutError::Timeout) => file.flush()?,
+                Err(RecvTimeoutError::Disconnected) => return file.flush(),
+            }
+        }
+    }
+}
+
+/// Largest run of records written at once.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+/// A capture being appended to. Only whole records are written, each run of
+/// them with a single write, so that they don't interleave with those of a
+/// capture it's replacing when a configuration is reloaded.
+struct CaptureFile {
+    path: PathBuf,
+    file: File,
+    buffer: Vec<u8>,
+    written: u64,
+    max_bytes: u64,
+}
+
+impl CaptureFile {
+    fn open(path: PathBuf, max_bytes: u64) -> io::Result<CaptureFile> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+        let written = file.metadata()?.len();
+        if written > 0 {
+            let mut header = vec![0; CAPTURE_HEADER.len()];
+            if file.read_exact(&mut header).is_err() || header != CAPTURE_HEADER {
+                return Err(io::Error::new(ErrorKind::InvalidData, "not a StatsD capture"))
+            }
+        }
+        let mut file = CaptureFile { path, file, buffer: vec![], written, max_bytes };
+        if written == 0 {
+            file.start();
+        }
+        Ok(file)
+    }
+
+    fn start(&mut self) {
+        self.buffer.extend_from_slice(CAPTURE_HEADER);
+        self.written = CAPTURE_HEADER.len() as u64;
+    }
+
+    fn write(&mut self, received: SystemTime, payload: &[u8]) -> io::Result<()> {
+        let size = 12 + payload.len() as u64;
+        if self.written + size > self.max_bytes && self.written > CAPTURE_HEADER.len() as u64 {
+            self.flush()?;
+            let mut previous = OsString::from(&self.path);
+            previous.push(".1");
+            fs::rename(&self.path, &previous)?;
+            self.file = OpenOptions::new().append(true).create(true).open(&self.path)?;
+            self.start();
+        }
+        write_record(&mut self.buffer, received, payload)?;
+        self.written += size;
+        if self.buffer.len() >= MAX_BUFFERED {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.file.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+}
+
+fn write_record<W: Write>(writer: &mut W, received: SystemTime, payload: &[u8]) -> io::Result<()> {
+    let nanos = received.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    writer.write_all(&nanos.to_be_bytes())?;
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+#[derive(Default)]
+pub struct ReplayOptions {
+    /// How many times faster than it was captured to replay, eg. 10 to
+    /// replay an hour in 6 minutes. Defaults to as fast as possible.
+    pub speed: Option<f64>,
+    /// Whether metrics are timestamped when they were captured rather than
+    /// when they're replayed; defaults to false. With them a Db aggregates
+    /// what it replays into the original intervals, which only works if it
+    /// accepts samples that late (see `DbOptions::lateness`).
+    pub original_times: Option<bool>,
+}
+
+/// The payloads of a capture file, in the order they were received.
+pub struct CaptureReader<R> {
+    reader: R,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<CaptureReader<BufReader<File>>> {
+        CaptureReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Fails if `reader` doesn't start with `CAPTURE_HEADER`.
+    pub fn new(mut reader: R) -> io::Result<CaptureReader<R>> {
+        let mut header = vec![0; CAPTURE_HEADER.len()];
+        reader.read_exact(&mut header)?;
+        if header != CAPTURE_HEADER {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a StatsD capture"))
+        }
+        Ok(CaptureReader { reader })
+    }
+
+    /// The next payload and when it was received, or None at the end. A
+    /// record cut short, eg. by the capturing agent being killed, is the
+    /// end too.
+    pub fn next_payload(&mut self) -> io::Result<Option<(SystemTime, Vec<u8>)>> {
+        let (mut nanos, mut len) = ([0; 8], [0; 4]);
+        match self.reader.read_exact(&mut nanos).and_then(|_| self.reader.read_exact(&mut len)) {
+            Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let received = UNIX_EPOCH + Duration::from_nanos(u64::from_be_bytes(nanos));
+        let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+        match self.reader.read_exact(&mut payload) {
+            Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            result => result.map(|_| Some((received, payload))),
+        }
+    }
+}
+
+/// Feed every payload of a capture through the StatsD parser into
+/// `collector`, blocking the calling thread until it's done. Returns how
+/// many payloads were replayed.
+pub fn replay<R: Read>(mut capture: CaptureReader<R>, collector: &Collector, options: ReplayOptions) -> io::Result<usize> {
+    let original_times = options.original_times.unwrap_or(false);
+    // Captures don't record senders.
+    let peer = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+    let mut batch = Batch::new(collector);
+    let mut first: Option<(SystemTime, SystemTime)> = None;
+    let mut replayed = 0;
+    while let Some((received, payload)) = capture.next_payload()? {
+        let now = SystemTime::now();
+        let (captured_from, replayed_from) = *first.get_or_insert((received, now));
+        if let Some(speed) = options.speed.filter(|speed| *speed > 0.0) {
+            let due = received.duration_since(captured_from).unwrap_or_default().div_f64(speed);
+            let elapsed = now.duration_since(replayed_from).unwrap_or_default();
+            if due > elapsed {
+                // Whatever's batched shouldn't wait on the sleep.
+                drop(batch);
+                thread::sleep(due - elapsed);
+                batch = Batch::new(collector);
+            }
+        }
+        batch.record(&payload, if original_times { received } else { SystemTime::now() }, peer);
+        batch.push_if_due();
+        replayed += 1;
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::io::Cursor;
+    use std::sync::mpsc::channel;
+    use std::time::Instant;
+
+    use super::super::super::super::super::db::{Db, DbOptions};
+    use super::super::super::super::super::metric::{CollectedMetric, MetricId};
+
+    /// A capture at `path` once it's `len` bytes, giving up after a few
+    /// seconds.
+    fn wait_for(path: &Path, len: usize) -> CaptureReader<Cursor<Vec<u8>>> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let bytes = std::fs::read(path).unwrap();
+            if bytes.len() == len {
+                return CaptureReader::new(Cursor::new(bytes)).unwrap()
+            }
+            assert!(Instant::now() < deadline, "capture is {} bytes, not {}", bytes.len(), len);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn it_moves_full_captures_aside() {
+        let path = env::temp_dir().join(format!("metriqs-capture-rotated-{}", std::process::id()));
+        let max = CAPTURE_HEADER.len() as u64 + 2 * (12 + 7);
+        let capture = Capture::new(CaptureOptions { path: path.clone(), max_bytes: Some(max), ..CaptureOptions::default() }).unwrap();
+        for payload in &[b"foo:1|c", b"foo:2|c", b"foo:3|c"] {
+            capture.capture(*payload, UNIX_EPOCH);
+        }
+        drop(capture);
+
+        let mut reader = wait_for(&path, CAPTURE_HEADER.len() + 12 + 7);
+        assert_eq!(reader.next_payload().unwrap(), Some((UNIX_EPOCH, b"foo:3|c".to_vec())));
+        let mut previous = OsString::from(&path);
+        previous.push(".1");
+        assert_eq!(std::fs::metadata(&previous).unwrap().len(), max);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&previous).unwrap();
+    }
+
+    #[test]
+    fn it_doesnt_overwrite_other_files() {
+        let path = env::temp_dir().join(format!("metriqs-capture-other-{}", std::process::id()));
+        std::fs::write(&path, b"precious").unwrap();
+        assert!(Capture::new(CaptureOptions { path: path.clone(), ..CaptureOptions::default() }).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"precious");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_captures_and_replays_payloads() {
+        let path = env::temp_dir().join(format!("metriqs-capture-{}", std::process::id()));
+        let capture = Capture::new(CaptureOptions { path: path.clone(), ..CaptureOptions::default() }).unwrap();
+        let captured = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        capture.capture(b"foo:1|c\nbar:2|g", captured);
+        capture.capture(b"foo:3|c", captured + Duration::from_millis(20));
+        drop(capture);
+
+        // Written once the writer sees every capture has been dropped.
+        let mut reader = wait_for(&path, CAPTURE_HEADER.len() + 2 * 12 + 15 + 7);
+        assert_eq!(reader.next_payload().unwrap(), Some((captured, b"foo:1|c\nbar:2|g".to_vec())));
+
+        // Capturing again appends.
+        let capture = Capture::new(CaptureOptions { path: path.clone(), ..CaptureOptions::default() }).unwrap();
+        capture.capture(b"baz:1|c", captured);
+        drop(capture);
+        let mut reader = wait_for(&path, CAPTURE_HEADER.len() + 3 * 12 + 15 + 7 + 7);
+        assert_eq!(reader.next_payload().unwrap(), Some((captured, b"foo:1|c\nbar:2|g".to_vec())));
+        std::fs::remove_file(&path).unwrap();
+
+        let db = Db::new(DbOptions::default());
+        let (tap, pushed) = channel();
+        let collector = db.collector().tap(tap);
+        let mut bytes = CAPTURE_HEADER.to_vec();
+        write_record(&mut bytes, captured, b"foo:1|c\nbar:2|g").unwrap();
+        write_record(&mut bytes, captured + Duration::from_millis(20), b"foo:3|c").unwrap();
+        let options = ReplayOptions { speed: Some(1.0), original_times: Some(true) };
+        let started = SystemTime::now();
+        assert_eq!(replay(CaptureReader::new(Cursor::new(bytes)).unwrap(), &collector, options).unwrap(), 2);
+        assert!(started.elapsed().unwrap() >= Duration::from_millis(20));
+
+        let metrics = pushed.try_iter().flatten().collect::<Vec<_>>();
+        assert_eq!(metrics, vec![
+            CollectedMetric::Count(captured, MetricId::from("foo"), 1),
+            CollectedMetric::Gauge(captured, MetricId::from("bar"), 2.0),
+            CollectedMetric::Count(captured + Duration::from_millis(20), MetricId::from("foo"), 3),
+        ]);
+    }
+}
@@ -0,0 +1,256 @@
+//! Auditing what emitters send without aggregating it, eg. before moving
+//! them to metriqs. A listener linting its payloads (see `lint` on the
+//! listeners) checks each line on its own instead of collecting it, and
+//! counts per sender how many didn't parse, used a type StatsD doesn't
+//! have, or came in payloads too big to be sure they weren't truncated.
+//! Across senders it also notes the dimensions (DogStatsD tags) that take
+//! too many values for a metric, which would each be a series. How many
+//! senders and dimensions are tracked is capped, so that linting a firehose
+//! for a long time doesn't grow without bound; what's past the caps is
+//! counted instead.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use super::parse_metrics;
+
+/// Lines kept as an example of what a sender got wrong are cut to this
+/// many bytes.
+const MAX_EXAMPLE: usize = 200;
+
+#[derive(Default)]
+pub struct LintOptions {
+    /// Payloads bigger than this many bytes are oversized; defaults to
+    /// 1,432, what fits in a datagram over Ethernet without fragmenting.
+    pub max_payload: Option<usize>,
+    /// How many values a dimension can take for one metric before it's
+    /// reported as high-cardinality; defaults to 100.
+    pub max_values: Option<usize>,
+    /// How many senders are tracked; defaults to 10,000.
+    pub max_senders: Option<usize>,
+    /// How many dimensions of metrics are tracked; defaults to 10,000.
+    pub max_dimensions: Option<usize>,
+}
+
+/// Lints payloads from any number of listeners into one report.
+#[derive(Clone)]
+pub struct Linter {
+    max_payload: usize,
+    max_values: usize,
+    max_senders: usize,
+    max_dimensions: usize,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    senders: HashMap<IpAddr, SenderLint>,
+    /// Values seen for each metric's dimensions, up to one more than
+    /// `max_values`.
+    values: HashMap<(String, String), HashSet<String>>,
+    untracked_payloads: usize,
+    untracked_dimensions: usize,
+}
+
+/// What was received from one sender. Senders are told apart by address
+/// but not port, since UDP clients often send from a new port each time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SenderLint {
+    pub sender: IpAddr,
+    pub payloads: usize,
+    pub lines: usize,
+    /// Lines that don't parse, besides those with unknown types.
+    pub malformed: usize,
+    pub unknown_types: usize,
+    pub oversized: usize,
+    /// The first line that didn't parse.
+    pub example: Option<String>,
+}
+
+/// A dimension with more than the maximum values for a metric.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CardinalityLint {
+    pub name: String,
+    pub dimension: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LintReport {
+    /// Ordered by address.
+    pub senders: Vec<SenderLint>,
+    /// Ordered by name and then dimension.
+    pub high_cardinality: Vec<CardinalityLint>,
+    /// What counts as high-cardinality.
+    pub max_values: usize,
+    /// Payloads from senders past `max_senders`, which aren't linted.
+    pub untracked_payloads: usize,
+    /// Dimensions of metrics past `max_dimensions`, whose values aren't
+    /// counted.
+    pub untracked_dimensions: usize,
+}
+
+impl Linter {
+    pub fn new(options: LintOptions) -> Linter {
+        Linter {
+            max_payload: options.max_payload.unwrap_or(1432),
+            max_values: options.max_values.unwrap_or(100),
+            max_senders: options.max_senders.unwrap_or(10_000),
+            max_dimensions: options.max_dimensions.unwrap_or(10_000),
+            state: Arc::default(),
+        }
+    }
+
+    /// Lint a datagram or line received from `peer`.
+    pub fn lint(&self, payload: &[u8], peer: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        let State { ref mut senders, ref mut values, ref mut untracked_payloads, ref mut untracked_dimensions } = *state;
+        if senders.len() >= self.max_senders && !senders.contains_key(&peer.ip()) {
+            *untracked_payloads += 1;
+            return
+        }
+        let sender = senders.entry(peer.ip()).or_insert_with(|| SenderLint::new(peer.ip()));
+        sender.payloads += 1;
+        if payload.len() > self.max_payload {
+            sender.oversized += 1;
+        }
+
+        for line in payload.split(|&byte| byte == b'\n').map(<[u8]>::trim_ascii).filter(|line| !line.is_empty()) {
+            sender.lines += 1;
+            match parse_metrics(line) {
+                Ok(metrics) => for metric in metrics {
+                    let metric = metric.collect(UNIX_EPOCH);
+                    let id = metric.id();
+                    for (key, value) in id.dimensions() {
+                        let dimension = (id.name().to_string(), key.to_string());
+                        if values.len() >= self.max_dimensions && !values.contains_key(&dimension) {
+                            *untracked_dimensions += 1;
+                            continue
+                        }
+                        let seen = values.entry(dimension).or_default();
+                        if seen.len() <= self.max_values {
+                            seen.insert(value.to_string());
+                        }
+                    }
+                },
+                Err(_) => {
+                    if has_unknown_type(line) {
+                        sender.unknown_types += 1
+                    } else {
+                        sender.malformed += 1
+                    }
+                    if sender.example.is_none() {
+                        let example = &line[..line.len().min(MAX_EXAMPLE)];
+                        sender.example = Some(String::from_utf8_lossy(example).into_owned());
+                    }
+                },
+            }
+        }
+    }
+
+    /// Everything linted so far.
+    pub fn report(&self) -> LintReport {
+        let state = self.state.lock().unwrap();
+        let mut senders = state.senders.values().cloned().collect::<Vec<_>>();
+        senders.sort_by_key(|sender| sender.sender);
+        let mut high_cardinality = state.values.iter()
+            .filter(|&(_, values)| values.len() > self.max_values)
+            .map(|((name, dimension), _)| CardinalityLint { name: name.clone(), dimension: dimension.clone() })
+            .collect::<Vec<_>>();
+        high_cardinality.sort_by(|a, b| (&a.name, &a.dimension).cmp(&(&b.name, &b.dimension)));
+        LintReport {
+            senders,
+            high_cardinality,
+            max_values: self.max_values,
+            untracked_payloads: state.untracked_payloads,
+            untracked_dimensions: state.untracked_dimensions,
+        }
+    }
+}
+
+impl SenderLint {
+    fn new(sender: IpAddr) -> SenderLint {
+        SenderLint { sender, payloads: 0, lines: 0, malformed: 0, unknown_types: 0, oversized: 0, example: None }
+    }
+}
+
+/// Whether a line that didn't parse is otherwise shaped like StatsD
+/// (`name:value|type...`) but with a type other than `c`, `g`, `ms`, or
+/// `s`, eg. `h` or `d` from other dialects.
+fn has_unknown_type(line: &[u8]) -> bool {
+    let mut fields = line.split(|&byte| byte == b'|');
+    let well_named = fields.next().is_some_and(|head| head.iter().position(|&byte| byte == b':').is_some_and(|at| at > 0 && at + 1 < head.len()));
+    match fields.next() {
+        Some(kind) if well_named => !kind.is_empty() && ![&b"c"[..], b"g", b"ms", b"s"].contains(&kind),
+        _ => false,
+    }
+}
+
+impl fmt::Display for LintReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<40} {:>10} {:>10} {:>10} {:>10} {:>10}", "sender", "payloads", "lines", "malformed", "unknown", "oversized")?;
+        for sender in &self.senders {
+            writeln!(f, "{:<40} {:>10} {:>10} {:>10} {:>10} {:>10}", sender.sender, sender.payloads, sender.lines, sender.malformed, sender.unknown_types, sender.oversized)?;
+            if let Some(ref example) = sender.example {
+                writeln!(f, "    e.g. {:?}", example)?;
+            }
+        }
+        if !self.high_cardinality.is_empty() {
+            writeln!(f, "\nDimensions with more than {} values:", self.max_values)?;
+            for lint in &self.high_cardinality {
+                writeln!(f, "    {} {}", lint.name, lint.dimension)?;
+            }
+        }
+        if self.untracked_payloads > 0 {
+            writeln!(f, "\n{} payloads from further senders weren't linted", self.untracked_payloads)?;
+        }
+        if self.untracked_dimensions > 0 {
+            writeln!(f, "\n{} dimensions of further metrics weren't counted", self.untracked_dimensions)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reports_bad_lines_per_sender() {
+        let linter = Linter::new(LintOptions { max_payload: Some(32), max_values: Some(2), ..LintOptions::default() });
+        let first = "10.0.0.1:5000".parse().unwrap();
+        linter.lint(b"foo:1|c\nfoo:1|h\n\nfoo|c", first);
+        linter.lint(b"bar:1|c|#host:a\nbar:1|c|#host:b\nbar:1|c|#host:c", "10.0.0.1:5001".parse().unwrap());
+        linter.lint(b"baz:1|g|#host:a", "10.0.0.2:5000".parse().unwrap());
+
+        let report = linter.report();
+        assert_eq!(report.senders, vec![
+            SenderLint {
+                sender: first.ip(),
+                payloads: 2,
+                lines: 6,
+                malformed: 1,
+                unknown_types: 1,
+                oversized: 1,
+                example: Some("foo:1|h".to_string()),
+            },
+            SenderLint { payloads: 1, lines: 1, ..SenderLint::new("10.0.0.2".parse().unwrap()) },
+        ]);
+        assert_eq!(report.high_cardinality, vec![CardinalityLint { name: "bar".to_string(), dimension: "host".to_string() }]);
+        assert!(report.to_string().contains("e.g. \"foo:1|h\""));
+    }
+
+    #[test]
+    fn it_caps_what_it_tracks() {
+        let linter = Linter::new(LintOptions { max_senders: Some(1), max_dimensions: Some(1), ..LintOptions::default() });
+        linter.lint(b"foo:1|c|#host:a,pod:b\nbar:1|c|#host:a", "10.0.0.1:5000".parse().unwrap());
+        linter.lint(b"foo:1|c", "10.0.0.2:5000".parse().unwrap());
+
+        let report = linter.report();
+        assert_eq!(report.senders.len(), 1);
+        assert_eq!((report.untracked_payloads, report.untracked_dimensions), (1, 2));
+        assert!(report.to_string().contains("1 payloads from further senders weren't linted"));
+    }
+}
@@ -1,7 +1,151 @@
+use std::mem;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::super::collector::Collector;
+use super::super::super::metric::CollectedMetric;
+
+#[cfg(feature = "async")]
+pub mod asynchronous;
+mod capture;
+mod lint;
 mod parse;
+mod repeat;
 mod tcp;
 mod udp;
 
+pub use self::capture::{replay, Capture, CaptureOptions, CaptureReader, ReplayOptions};
+pub use self::lint::{CardinalityLint, LintOptions, LintReport, Linter, SenderLint};
 pub use self::parse::parse_metrics;
-pub use self::tcp::StatsdTcpListener;
-pub use self::udp::StatsdUdpListener;
+pub use self::repeat::{RepeatOptions, Repeater};
+pub use self::tcp::{StatsdTcpListener, StatsdTcpOptions};
+pub use self::udp::{StatsdUdpListener, StatsdUdpOptions};
+
+/// How many metrics a batch holds before it's pushed.
+const MAX_BATCH: usize = 1000;
+
+/// How long metrics can wait in a batch before it's pushed.
+const BATCH_DELAY: Duration = Duration::from_millis(5);
+
+/// How often listeners report on themselves, eg. the UDP socket's receive
+/// buffer size and drops.
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Parse a datagram or line received from `peer` at `received` and push
+/// its metrics; messages that don't parse are dropped.
+#[cfg(feature = "async")]
+fn record(collector: &Collector, message: &str, received: SystemTime, peer: SocketAddr) {
+    let mut metrics = vec![];
+    parse(&mut metrics, message.as_bytes(), received, peer);
+    if !metrics.is_empty() {
+        collector.push(metrics)
+    }
+}
+
+/// Messages are parsed as bytes, so that datagrams can be parsed straight
+/// from the receive buffer.
+fn parse(into: &mut Vec<CollectedMetric>, message: &[u8], received: SystemTime, peer: SocketAddr) {
+    match parse_metrics(message.trim_ascii_end()) {
+        Ok(metrics) => {
+            trace!("Received {} StatsD metrics ({} bytes) from {}", metrics.len(), message.len(), peer);
+            for metric in metrics {
+                metric.collect_into(into, received)
+            }
+        },
+        Err(err) => debug!("Dropping unparseable StatsD message ({} bytes) from {}: {:?}", message.len(), peer, err),
+    }
+}
+
+/// Coalesces the metrics parsed from many messages into fewer, bigger
+/// pushes to the collector. A batch is pushed once it holds `MAX_BATCH`
+/// metrics or has waited `BATCH_DELAY`, and when it's dropped.
+struct Batch<'a> {
+    collector: &'a Collector,
+    metrics: Vec<CollectedMetric>,
+    /// When the first metric was added.
+    started: Option<Instant>,
+}
+
+impl<'a> Batch<'a> {
+    fn new(collector: &'a Collector) -> Batch<'a> {
+        Batch { collector, metrics: collector.buffer(), started: None }
+    }
+
+    /// Parse a message into the batch, pushing it if it's full.
+    fn record(&mut self, message: &[u8], received: SystemTime, peer: SocketAddr) {
+        parse(&mut self.metrics, message, received, peer);
+        if self.started.is_none() && !self.metrics.is_empty() {
+            self.started = Some(Instant::now())
+        }
+        if self.metrics.len() >= MAX_BATCH {
+            self.push()
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.metrics.is_empty()
+    }
+
+    /// How long to wait for the next message: until the batch is due, or
+    /// `poll` if it's empty.
+    fn timeout(&self, poll: Duration) -> Duration {
+        match self.started {
+            Some(started) => BATCH_DELAY.saturating_sub(started.elapsed()).min(poll),
+            None => poll,
+        }
+    }
+
+    /// Push the batch if it's waited long enough.
+    fn push_if_due(&mut self) {
+        if self.started.is_some_and(|started| started.elapsed() >= BATCH_DELAY) {
+            self.push()
+        }
+    }
+
+    fn push(&mut self) {
+        self.started = None;
+        if !self.metrics.is_empty() {
+            let metrics = mem::replace(&mut self.metrics, self.collector.buffer());
+            self.collector.push(metrics)
+        }
+    }
+}
+
+impl<'a> Drop for Batch<'a> {
+    fn drop(&mut self) {
+        self.push()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use super::super::super::super::db::{Db, DbOptions};
+
+    #[test]
+    fn it_batches_metrics_into_fewer_pushes() {
+        let db = Db::new(DbOptions::default());
+        let (tap, pushed) = channel();
+        let collector = db.collector().tap(tap);
+        let peer = "127.0.0.1:8125".parse().unwrap();
+
+        let mut batch = Batch::new(&collector);
+        batch.record(b"foo:1|c\nbar:2|c", SystemTime::now(), peer);
+        batch.record(b"baz:3|g", SystemTime::now(), peer);
+        batch.push_if_due();
+        assert!(pushed.try_recv().is_err());
+        for _ in 0..MAX_BATCH {
+            batch.record(b"foo:1|c", SystemTime::now(), peer);
+        }
+        assert_eq!(pushed.try_recv().unwrap().len(), MAX_BATCH);
+
+        // The last of the 1,003 are pushed once they're due.
+        thread::sleep(BATCH_DELAY);
+        batch.push_if_due();
+        assert_eq!(pushed.try_recv().unwrap().len(), 3);
+    }
+}
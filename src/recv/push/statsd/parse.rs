@@ -1,41 +1,74 @@
 use std::str::{self, FromStr};
-use std::time::{SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use nom::{digit, is_alphanumeric, IResult};
 use string_cache::DefaultAtom as Atom;
 
-use super::super::super::super::metric::CollectedMetric;
+use super::super::super::super::metric::{CollectedMetric, Dimension, MetricId};
 
 #[derive(Debug, PartialEq)]
 pub struct ParseError {
     description: String,
 }
 
+/// Identifiers have dimensions only when the sender used DogStatsD tags
+/// (`|#key:value,...`), and timestamps are only present when it used the
+/// DogStatsD `|T` extension.
 #[derive(Debug, PartialEq)]
 pub enum StatsdMetric {
-    /// Name, value, sample rate
-    Counter(Atom, f64, Option<f64>),
-    /// Name, value
-    Gauge(Atom, f64),
-    /// Name, value, sample rate
-    Timer(Atom, f64, Option<f64>),
+    /// Identifier, value, sample rate, timestamp
+    Counter(MetricId, f64, Option<f64>, Option<SystemTime>),
+    /// Identifier, value, timestamp
+    Gauge(MetricId, f64, Option<SystemTime>),
+    /// A signed gauge, eg. `+3|g`, which changes its current value rather
+    /// than setting it. Identifier, delta, timestamp
+    GaugeDelta(MetricId, f64, Option<SystemTime>),
+    /// Identifier, value, sample rate, timestamp
+    Timer(MetricId, f64, Option<f64>, Option<SystemTime>),
+    /// Identifier, member, timestamp
+    Set(MetricId, String, Option<SystemTime>),
 }
 
-impl Into<CollectedMetric> for StatsdMetric {
-    fn into(self) -> CollectedMetric {
+impl StatsdMetric {
+    /// Convert into a collected metric. `received` is when the line came off
+    /// the socket and is used unless the line carried its own timestamp.
+    pub fn collect(self, received: SystemTime) -> CollectedMetric {
         use self::StatsdMetric::*;
 
-        let now = SystemTime::now();
+        match self {
+            // Round rather than truncate so that eg. `0.9999` counts as 1.
+            Counter(id, value, _, time) => CollectedMetric::Count(time.unwrap_or(received), id, value.round() as i64),
+            Gauge(id, value, time)      => CollectedMetric::Gauge(time.unwrap_or(received), id, value),
+            GaugeDelta(id, delta, time) => CollectedMetric::GaugeDelta(time.unwrap_or(received), id, delta),
+            Timer(id, value, _, time)   => CollectedMetric::Histogram(time.unwrap_or(received), id, value),
+            Set(id, member, time)       => CollectedMetric::Set(time.unwrap_or(received), id, member),
+        }
+    }
+}
 
+impl StatsdMetric {
+    /// Like `collect`, but a timer sampled at `|@rate` is collected as a
+    /// `SampledHistogram` weighing `1 / rate` so that its histogram's
+    /// `.count` and `.avg` account for the unsampled population. Its
+    /// percentiles are only as good as the sample: weighting the sample
+    /// can't recover outliers that weren't sampled.
+    pub fn collect_into(self, into: &mut Vec<CollectedMetric>, received: SystemTime) {
         match self {
-            Counter(name, value, _) => CollectedMetric::Count(now, (name, vec![]), value as i32),
-            Gauge(name, value)      => CollectedMetric::Gauge(now, (name, vec![]), value as i32),
-            Timer(name, value, _)   => CollectedMetric::Histogram(now, (name, vec![]), value as i32),
+            StatsdMetric::Timer(id, value, Some(rate), time) if rate > 0.0 && rate < 1.0 => {
+                into.push(CollectedMetric::SampledHistogram(time.unwrap_or(received), id, value, 1.0 / rate))
+            },
+            metric => into.push(metric.collect(received)),
         }
     }
 }
 
-pub fn parse_metrics<'a>(i: &'a [u8]) -> Result<Vec<StatsdMetric>, ParseError> {
+impl From<StatsdMetric> for CollectedMetric {
+    fn from(metric: StatsdMetric) -> CollectedMetric {
+        metric.collect(SystemTime::now())
+    }
+}
+
+pub fn parse_metrics(i: &[u8]) -> Result<Vec<StatsdMetric>, ParseError> {
     let result = complete!(i, call!(metrics));
 
     match result {
@@ -46,58 +79,137 @@ pub fn parse_metrics<'a>(i: &'a [u8]) -> Result<Vec<StatsdMetric>, ParseError> {
 }
 
 named!(metrics<Vec<StatsdMetric>>,
-    separated_nonempty_list_complete!(
-        tag!("\n"),
-        alt_complete!(
-            counter |
-            gauge   |
-            timer
-        )
+    map!(
+        separated_nonempty_list_complete!(
+            tag!("\n"),
+            alt_complete!(
+                counter |
+                gauge   |
+                timer   |
+                map!(set, |set| vec![set])
+            )
+        ),
+        |lines: Vec<Vec<StatsdMetric>>| lines.into_iter().flatten().collect()
     )
 );
 
-named!(counter<StatsdMetric>,
+// Counters, gauges, and timers can pack several values into a line, eg.
+// `foo:1:2:3|ms`, which are samples sharing its name, type, rate, and tags.
+named!(counter<Vec<StatsdMetric>>,
     do_parse!(
-                name: metric_name                  >>
-                      tag!(":")                    >>
-               value: double                       >>
-                      tag!("|c")                   >>
-        _sample_rate: opt!(complete!(sample_rate)) >>
+                name: metric_name                                          >>
+                      tag!(":")                                            >>
+              values: separated_nonempty_list_complete!(tag!(":"), double) >>
+                      tag!("|c")                                           >>
+        _sample_rate: opt!(complete!(sample_rate))                         >>
+                tags: opt!(complete!(tags))                                >>
+                time: opt!(complete!(timestamp))                           >>
 
-        (StatsdMetric::Counter(Atom::from(name), value, None))
+        ({
+            let id = id(name, tags);
+            values.into_iter().map(|value| StatsdMetric::Counter(id.clone(), value, None, time)).collect()
+        })
     )
 );
 
-named!(gauge<StatsdMetric>,
+named!(gauge<Vec<StatsdMetric>>,
     do_parse!(
-         name: metric_name >>
-               tag!(":")   >>
-        value: double      >>
-               tag!("|g")  >>
+         name: metric_name                                               >>
+               tag!(":")                                                 >>
+       values: separated_nonempty_list_complete!(tag!(":"), gauge_value) >>
+               tag!("|g")                                                >>
+         tags: opt!(complete!(tags))                                     >>
+         time: opt!(complete!(timestamp))                                >>
 
-        (StatsdMetric::Gauge(Atom::from(name), value))
+        ({
+            let id = id(name, tags);
+            values.into_iter()
+                .map(|(sign, value)| match sign {
+                    Some(b"-") => StatsdMetric::GaugeDelta(id.clone(), -value, time),
+                    Some(_) => StatsdMetric::GaugeDelta(id.clone(), value, time),
+                    None => StatsdMetric::Gauge(id.clone(), value, time),
+                })
+                .collect()
+        })
     )
 );
 
-named!(timer<StatsdMetric>,
+/// A gauge's value, with its sign if it's a delta.
+fn gauge_value(i: &[u8]) -> IResult<&[u8], (Option<&[u8]>, f64)> {
+    tuple!(i,
+        opt!(alt_complete!(tag!("+") | tag!("-"))),
+        double
+    )
+}
+
+named!(timer<Vec<StatsdMetric>>,
     do_parse!(
-         name: metric_name >>
-               tag!(":")   >>
-        value: double      >>
-               tag!("|ms") >>
+               name: metric_name                                          >>
+                     tag!(":")                                            >>
+             values: separated_nonempty_list_complete!(tag!(":"), double) >>
+                     tag!("|ms")                                          >>
+        sample_rate: opt!(complete!(sample_rate))                         >>
+               tags: opt!(complete!(tags))                                >>
+               time: opt!(complete!(timestamp))                           >>
 
-        (StatsdMetric::Timer(Atom::from(name), value, None))
+        ({
+            let id = id(name, tags);
+            values.into_iter().map(|value| StatsdMetric::Timer(id.clone(), value, sample_rate, time)).collect()
+        })
     )
 );
 
-named!(metric_name<&str>,
-    map_res!(
+// Set members can contain colons, so sets only take one.
+named!(set<StatsdMetric>,
+    do_parse!(
+          name: metric_name                >>
+                tag!(":")                  >>
+        member: set_member                 >>
+                tag!("|s")                 >>
+          tags: opt!(complete!(tags))      >>
+          time: opt!(complete!(timestamp)) >>
+
+        (StatsdMetric::Set(id(name, tags), member.to_owned(), time))
+    )
+);
+
+fn set_member(i: &[u8]) -> IResult<&[u8], &str> {
+    map_res!(i,
+        take_while1!(call!(|c| c != b'|' && c != b'\n')),
+        str::from_utf8
+    )
+}
+
+fn metric_name(i: &[u8]) -> IResult<&[u8], &str> {
+    map_res!(i,
         take_while!(call!(|c| {
             is_alphanumeric(c) || c == b'.' || c == b'_'
         })),
         str::from_utf8
     )
-);
+}
+
+/// DogStatsD's `|#key:value,...` tags as dimensions; a tag without a value
+/// is a dimension with an empty one.
+fn tags(i: &[u8]) -> IResult<&[u8], Vec<Dimension>> {
+    map!(i,
+        map_res!(
+            preceded!(tag!("|#"), take_while1!(call!(|c| c != b'|' && c != b'\n'))),
+            str::from_utf8
+        ),
+        |tags: &str| tags.split(',')
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| match tag.split_once(':') {
+                Some((key, value)) => (Atom::from(key), Atom::from(value)),
+                None => (Atom::from(tag), Atom::from("")),
+            })
+            .collect()
+    )
+}
+
+fn id(name: &str, tags: Option<Vec<Dimension>>) -> MetricId {
+    MetricId::new(name, tags.unwrap_or_default())
+}
 
 named!(sample_rate<&[u8], f64>,
     preceded!(
@@ -106,6 +218,20 @@ named!(sample_rate<&[u8], f64>,
     )
 );
 
+// DogStatsD's `|T<unix seconds>` extension.
+named!(timestamp<&[u8], SystemTime>,
+    map!(
+        map_res!(
+            map_res!(
+                preceded!(tag!("|T"), digit),
+                str::from_utf8
+            ),
+            u64::from_str
+        ),
+        |seconds| UNIX_EPOCH + Duration::from_secs(seconds)
+    )
+);
+
 named!(double<&[u8], f64>,
     map_res!(
         map_res!(
@@ -181,7 +307,7 @@ mod tests {
     fn it_parses_counter() {
         assert_eq!(
             counter(&b"foo.bar_baz:23|c"[..]),
-            complete(StatsdMetric::Counter(Atom::from("foo.bar_baz"), 23.0, None))
+            complete(vec![StatsdMetric::Counter(MetricId::from("foo.bar_baz"), 23.0, None, None)])
         );
     }
 
@@ -189,7 +315,15 @@ mod tests {
     fn it_parses_gauge() {
         assert_eq!(
             gauge(&b"foo.bar_baz:12|g"[..]),
-            complete(StatsdMetric::Gauge(Atom::from("foo.bar_baz"), 12.0))
+            complete(vec![StatsdMetric::Gauge(MetricId::from("foo.bar_baz"), 12.0, None)])
+        );
+        assert_eq!(
+            gauge(&b"foo.bar_baz:+3|g"[..]),
+            complete(vec![StatsdMetric::GaugeDelta(MetricId::from("foo.bar_baz"), 3.0, None)])
+        );
+        assert_eq!(
+            gauge(&b"foo.bar_baz:-2.5|g"[..]),
+            complete(vec![StatsdMetric::GaugeDelta(MetricId::from("foo.bar_baz"), -2.5, None)])
         );
     }
 
@@ -197,8 +331,96 @@ mod tests {
     fn it_parses_timer() {
         assert_eq!(
             timer(&b"foo.bar_baz:12|ms"[..]),
-            complete(StatsdMetric::Timer(Atom::from("foo.bar_baz"), 12.0, None))
+            complete(vec![StatsdMetric::Timer(MetricId::from("foo.bar_baz"), 12.0, None, None)])
+        );
+    }
+
+    #[test]
+    fn it_weighs_sampled_timers() {
+        assert_eq!(
+            timer(&b"foo:12|ms|@0.25"[..]),
+            complete(vec![StatsdMetric::Timer(MetricId::from("foo"), 12.0, Some(0.25), None)])
+        );
+        let received = SystemTime::now();
+        let mut collected = vec![];
+        StatsdMetric::Timer(MetricId::from("foo"), 12.0, Some(0.25), None).collect_into(&mut collected, received);
+        StatsdMetric::Timer(MetricId::from("bar"), 1.0, None, None).collect_into(&mut collected, received);
+        assert_eq!(collected, vec![
+            CollectedMetric::SampledHistogram(received, MetricId::from("foo"), 12.0, 4.0),
+            CollectedMetric::Histogram(received, MetricId::from("bar"), 1.0),
+        ]);
+    }
+
+    #[test]
+    fn it_parses_set() {
+        assert_eq!(
+            set(&b"foo.users:user-123|s"[..]),
+            complete(StatsdMetric::Set(MetricId::from("foo.users"), "user-123".to_owned(), None))
+        );
+        assert_eq!(
+            parse_metrics(&b"foo:12|s"[..]),
+            Ok(vec![StatsdMetric::Set(MetricId::from("foo"), "12".to_owned(), None)])
+        );
+    }
+
+    #[test]
+    fn it_parses_tags() {
+        let id = MetricId::from("foo").with_dimension("host", "a").with_dimension("canary", "");
+        assert_eq!(
+            counter(&b"foo:1|c|@0.5|#host:a,canary|T1656581400"[..]),
+            complete(vec![StatsdMetric::Counter(id.clone(), 1.0, None, Some(UNIX_EPOCH + Duration::from_secs(1656581400)))])
+        );
+        assert_eq!(
+            parse_metrics(&b"foo:2|ms|#host:a,canary\nfoo:users|s|#canary,host:a"[..]),
+            Ok(vec![StatsdMetric::Timer(id.clone(), 2.0, None, None), StatsdMetric::Set(id, "users".to_owned(), None)])
+        );
+    }
+
+    #[test]
+    fn it_parses_timestamps() {
+        let time = UNIX_EPOCH + Duration::from_secs(1656581400);
+        assert_eq!(
+            gauge(&b"foo:1|g|T1656581400"[..]),
+            complete(vec![StatsdMetric::Gauge(MetricId::from("foo"), 1.0, Some(time))])
+        );
+        assert_eq!(
+            counter(&b"foo:1|c|@0.5|T1656581400"[..]),
+            complete(vec![StatsdMetric::Counter(MetricId::from("foo"), 1.0, None, Some(time))])
+        );
+    }
+
+    #[test]
+    fn it_prefers_wire_timestamps() {
+        let received = UNIX_EPOCH + Duration::from_secs(2000000000);
+        let sent = UNIX_EPOCH + Duration::from_secs(1656581400);
+        assert_eq!(
+            StatsdMetric::Gauge(MetricId::from("foo"), 1.0, Some(sent)).collect(received),
+            CollectedMetric::Gauge(sent, MetricId::from("foo"), 1.0)
+        );
+        assert_eq!(
+            StatsdMetric::Gauge(MetricId::from("foo"), 1.0, None).collect(received),
+            CollectedMetric::Gauge(received, MetricId::from("foo"), 1.0)
+        );
+    }
+
+    #[test]
+    fn it_parses_multiple_values() {
+        let id = MetricId::from("foo").with_dimension("host", "a");
+        assert_eq!(
+            timer(&b"foo:1:2.5|ms|@0.5|#host:a"[..]),
+            complete(vec![StatsdMetric::Timer(id.clone(), 1.0, Some(0.5), None), StatsdMetric::Timer(id, 2.5, Some(0.5), None)])
+        );
+        assert_eq!(
+            parse_metrics(&b"foo:1:-2|g\nbar:1:1|c\nbaz:a:b|s"[..]),
+            Ok(vec![
+                StatsdMetric::Gauge(MetricId::from("foo"), 1.0, None),
+                StatsdMetric::GaugeDelta(MetricId::from("foo"), -2.0, None),
+                StatsdMetric::Counter(MetricId::from("bar"), 1.0, None, None),
+                StatsdMetric::Counter(MetricId::from("bar"), 1.0, None, None),
+                StatsdMetric::Set(MetricId::from("baz"), "a:b".to_owned(), None),
+            ])
         );
+        assert!(parse_metrics(&b"foo:1:|ms"[..]).is_err());
     }
 
     #[test]
@@ -206,9 +428,9 @@ mod tests {
         assert_eq!(
             parse_metrics(&b"foo:1|g\nbar:2|c|@3\nbaz:4|ms"[..]),
             Ok(vec![
-                StatsdMetric::Gauge(Atom::from("foo"), 1.0),
-                StatsdMetric::Counter(Atom::from("bar"), 2.0, None),
-                StatsdMetric::Timer(Atom::from("baz"), 4.0, None),
+                StatsdMetric::Gauge(MetricId::from("foo"), 1.0, None),
+                StatsdMetric::Counter(MetricId::from("bar"), 2.0, None, None),
+                StatsdMetric::Timer(MetricId::from("baz"), 4.0, None, None),
             ])
         );
     }
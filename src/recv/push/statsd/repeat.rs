@@ -0,0 +1,208 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use super::super::super::super::metric::{CollectedMetric, MetricId};
+use super::super::super::super::send::StatsdTransport;
+use super::super::super::super::send::tcp::ReconnectingStream;
+use super::super::super::super::util::pool::BufferPool;
+
+/// Most spare payload buffers kept.
+const MAX_POOLED: usize = 1024;
+
+#[derive(Default)]
+pub struct RepeatOptions {
+    /// Downstream addresses, each of which is sent every payload.
+    pub addresses: Vec<String>,
+    /// Defaults to UDP, with a datagram per payload. Over TCP payloads are
+    /// sent as lines.
+    pub transport: Option<StatsdTransport>,
+    /// Whether the listener also parses and records what it receives;
+    /// defaults to true. Turned off, it only repeats.
+    pub parse: Option<bool>,
+    /// How many payloads can wait to be sent before more are dropped;
+    /// defaults to 10,000.
+    pub max_pending: Option<usize>,
+}
+
+/// Repeats the payloads a StatsD listener receives verbatim to downstream
+/// addresses, eg. to shadow a new deployment with production traffic.
+/// Payloads are sent from a separate thread, so a slow or unreachable
+/// downstream drops payloads instead of slowing the listener down.
+#[derive(Clone)]
+pub struct Repeater {
+    send: SyncSender<Vec<u8>>,
+    parse: bool,
+    dropped: Arc<AtomicUsize>,
+    /// Buffers payloads are copied into, given back once they're sent.
+    payloads: Arc<BufferPool<u8>>,
+}
+
+impl Repeater {
+    /// Fails if any of the addresses doesn't resolve, or a UDP socket to
+    /// send to it from can't be bound.
+    pub fn new(options: RepeatOptions) -> io::Result<Repeater> {
+        let transport = options.transport.unwrap_or(StatsdTransport::Udp);
+        let mut downstreams = vec![];
+        for address in &options.addresses {
+            let addr = match address.to_socket_addrs()?.next() {
+                Some(addr) => addr,
+                None => continue,
+            };
+            downstreams.push(match transport {
+                StatsdTransport::Udp => {
+                    let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                    let socket = UdpSocket::bind(local).map_err(|err| {
+                        error!("Error binding a socket to repeat StatsD payloads to {}: {}", address, err);
+                        err
+                    })?;
+                    Downstream::Udp(socket, addr)
+                },
+                StatsdTransport::Tcp => Downstream::Tcp(ReconnectingStream::new(addr, Duration::from_secs(5))),
+            });
+        }
+        let max_pending = options.max_pending.unwrap_or(10_000);
+        let (send, recv) = sync_channel(max_pending);
+        let payloads = Arc::new(BufferPool::new(max_pending.min(MAX_POOLED)));
+        {
+            let payloads = payloads.clone();
+            thread::spawn(move || Repeater::run(recv, downstreams, transport, &payloads));
+        }
+
+        Ok(Repeater {
+            send,
+            parse: options.parse.unwrap_or(true),
+            dropped: Arc::new(AtomicUsize::new(0)),
+            payloads,
+        })
+    }
+
+    /// Queue `payload` to be sent downstream.
+    pub fn repeat(&self, payload: &[u8]) {
+        let mut buffer = self.payloads.take();
+        buffer.extend_from_slice(payload);
+        if let Err(TrySendError::Full(buffer)) = self.send.try_send(buffer) {
+            self.payloads.give(buffer);
+            if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                warn!("Dropping repeated StatsD payloads; downstreams can't keep up")
+            }
+        }
+    }
+
+    /// How many payloads have been dropped so far, as a
+    /// `metriqs.statsd.repeat.dropped` count, and how well payload buffers
+    /// are being reused, as `pool=statsd_repeats` metrics (see
+    /// `BufferPool::metrics`).
+    pub fn metrics(&self, now: SystemTime) -> Vec<CollectedMetric> {
+        let mut metrics = self.payloads.metrics("statsd_repeats", now);
+        metrics.push(CollectedMetric::MonotonicCount(now, MetricId::from("metriqs.statsd.repeat.dropped"), self.dropped() as f64));
+        metrics
+    }
+
+    pub fn parses(&self) -> bool {
+        self.parse
+    }
+
+    /// How many payloads have been dropped because too many were waiting.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Sends until every `Repeater` for `recv` has been dropped.
+    fn run(recv: Receiver<Vec<u8>>, mut downstreams: Vec<Downstream>, transport: StatsdTransport, payloads: &BufferPool<u8>) {
+        for mut payload in recv {
+            if transport == StatsdTransport::Tcp && !payload.ends_with(b"\n") {
+                payload.push(b'\n')
+            }
+            for downstream in &mut downstreams {
+                let result = match *downstream {
+                    Downstream::Udp(ref socket, addr) => socket.send_to(&payload, addr).map(|_| ()),
+                    Downstream::Tcp(ref mut stream) => stream.write(&payload),
+                };
+                if let Err(err) = result {
+                    debug!("Error repeating a StatsD payload ({} bytes): {}", payload.len(), err)
+                }
+            }
+            payloads.give(payload)
+        }
+    }
+}
+
+enum Downstream {
+    Udp(UdpSocket, SocketAddr),
+    Tcp(ReconnectingStream),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use super::super::StatsdUdpListener;
+    use super::super::super::super::super::db::{Db, DbOptions};
+    use super::super::super::super::super::util::Stop;
+
+    #[test]
+    fn it_repeats_datagrams_verbatim() {
+        let downstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        downstream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let repeater = Repeater::new(RepeatOptions {
+            addresses: vec![downstream.local_addr().unwrap().to_string()],
+            parse: Some(false),
+            ..RepeatOptions::default()
+        }).unwrap();
+
+        let db = Db::new(DbOptions::default());
+        let subscription = db.aggregation_subscribe();
+        let listener = StatsdUdpListener::new(db.collector()).repeat(repeater);
+        // Find a free port for the listener.
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let stop = Stop::new();
+        let listening = {
+            let stop = stop.clone();
+            thread::spawn(move || listener.listen_until(addr, &stop))
+        };
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buf = [0; 1500];
+        let received = loop {
+            client.send_to(b"foo:1|c\nbar:2|g", addr).unwrap();
+            if let Ok(len) = downstream.recv(&mut buf) {
+                break len
+            }
+        };
+        assert_eq!(&buf[..received], b"foo:1|c\nbar:2|g");
+
+        stop.stop();
+        listening.join().unwrap().unwrap();
+        // Only the listener's own metrics were recorded.
+        db.flush();
+        assert!(subscription.recv().unwrap().iter().all(|metric| metric.id().name().starts_with("metriqs.")));
+    }
+
+    #[test]
+    fn it_reports_dropped_payloads() {
+        let downstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let repeater = Repeater::new(RepeatOptions {
+            addresses: vec![downstream.local_addr().unwrap().to_string()],
+            max_pending: Some(0),
+            ..RepeatOptions::default()
+        }).unwrap();
+        // Nothing can wait with a rendezvous channel, unless the sending
+        // thread happens to be taking it.
+        for _ in 0..100 {
+            repeater.repeat(b"foo:1|c");
+        }
+        assert!(repeater.dropped() > 0);
+        let dropped = MetricId::from("metriqs.statsd.repeat.dropped");
+        assert!(repeater.metrics(SystemTime::now()).iter().any(|metric| match *metric {
+            CollectedMetric::MonotonicCount(_, ref id, count) => *id == dropped && count as usize == repeater.dropped(),
+            _ => false,
+        }));
+    }
+}
@@ -1,89 +1,427 @@
 use std::io::{self, BufRead, BufReader};
+#[cfg(feature = "flate2")]
+use std::io::Read;
 use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
-use std::sync::mpsc::{channel, Sender};
+use std::panic;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use super::parse_metrics;
+#[cfg(feature = "flate2")]
+use flate2::read::GzDecoder;
+
+use super::{Batch, Capture, Linter, Repeater, TELEMETRY_INTERVAL};
 use super::super::super::collector::Collector;
+use super::super::super::super::util::Stop;
+use super::super::super::super::util::pool::BufferPool;
+use super::super::super::super::util::socket;
+
+/// How often a listener waiting for lines checks whether it's been
+/// stopped.
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// Most spare line buffers kept.
+const MAX_POOLED_LINES: usize = 1024;
+
+/// Biggest gzipped frame accepted, in bytes, and the most it can
+/// decompress to; clients sending bigger ones are disconnected.
+#[cfg(feature = "flate2")]
+const MAX_FRAME: usize = 1 << 20;
+#[cfg(feature = "flate2")]
+const MAX_FRAME_CONTENTS: usize = 16 << 20;
+
+#[derive(Default)]
+pub struct StatsdTcpOptions {
+    /// How long clients can go without sending a line before they're
+    /// disconnected; defaults to 30 seconds. Zero never disconnects them,
+    /// so stopping the listener waits for idle clients to hang up.
+    pub read_timeout: Option<Duration>,
+    /// How long a connection can be idle before TCP keepalive probes
+    /// check that the client's still there; defaults to 60 seconds. Zero
+    /// turns keepalive off.
+    pub keepalive: Option<Duration>,
+    /// Read gzipped frames rather than lines: each a 4 byte big-endian
+    /// length and then that many bytes of gzip holding StatsD lines, so
+    /// that emitters over slow links can send a batch compressed. Every
+    /// client of the listener has to send them; defaults to false.
+    #[cfg(feature = "flate2")]
+    pub gzip_frames: Option<bool>,
+}
 
-/// Listens on a TCP socket for StatsD messages.
+/// Listens on a TCP socket for StatsD messages. Line buffers are reused,
+/// and how well is reported as `pool=statsd_tcp_lines` metrics (see
+/// `BufferPool::metrics`).
 pub struct StatsdTcpListener {
     collector: Collector,
     addr: SocketAddr,
+    read_timeout: Option<Duration>,
+    keepalive: Option<Duration>,
+    repeater: Option<Repeater>,
+    capture: Option<Capture>,
+    linter: Option<Linter>,
+    #[cfg(feature = "flate2")]
+    gzip_frames: bool,
 }
 
 impl StatsdTcpListener {
     pub fn new<A: ToSocketAddrs>(collector: Collector, addr: A) -> Result<StatsdTcpListener, io::Error> {
+        StatsdTcpListener::with_options(collector, addr, StatsdTcpOptions::default())
+    }
+
+    pub fn with_options<A: ToSocketAddrs>(collector: Collector, addr: A, options: StatsdTcpOptions) -> Result<StatsdTcpListener, io::Error> {
+        let nonzero = |duration: Duration| if duration.is_zero() { None } else { Some(duration) };
         addr.to_socket_addrs()
             .map(|mut addrs| addrs.next().unwrap())
             .map(|addr| {
                 StatsdTcpListener {
                     collector,
                     addr,
+                    read_timeout: nonzero(options.read_timeout.unwrap_or_else(|| Duration::from_secs(30))),
+                    keepalive: nonzero(options.keepalive.unwrap_or_else(|| Duration::from_secs(60))),
+                    repeater: None,
+                    capture: None,
+                    linter: None,
+                    #[cfg(feature = "flate2")]
+                    gzip_frames: options.gzip_frames.unwrap_or(false),
                 }
             })
     }
 
-    pub fn listen(&mut self) {
-        let (send, recv) = channel();
+    /// Repeat every line received, before parsing it (if it's parsed at
+    /// all).
+    pub fn repeat(mut self, repeater: Repeater) -> StatsdTcpListener {
+        self.repeater = Some(repeater);
+        self
+    }
 
-        let listener = TcpListener::bind(self.addr).unwrap();
-        thread::spawn(move || {
-            StatsdTcpListener::accept_on_listener(listener, send)
-        });
+    /// Capture every line received, before it's repeated or parsed.
+    pub fn capture(mut self, capture: Capture) -> StatsdTcpListener {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Lint every line received instead of parsing it into metrics (see
+    /// `Linter`). The listener still repeats and captures it.
+    pub fn lint(mut self, linter: Linter) -> StatsdTcpListener {
+        self.linter = Some(linter);
+        self
+    }
+
+    pub fn listen(&mut self) -> io::Result<()> {
+        self.listen_until(&Stop::new())
+    }
 
-        for line in recv {
-            match parse_metrics(line.trim_right().as_bytes()) {
-                Ok(metrics) => {
-                    let aggregated_metrics = metrics.into_iter()
-                        .map(|metric| metric.into())
-                        .collect();
+    /// Like `listen` but stops accepting connections once `stop` is
+    /// stopped, and returns after the clients already connected hang up or
+    /// send their next line. Fails if the address can't be bound or
+    /// accepting fails.
+    pub fn listen_until(&mut self, stop: &Stop) -> io::Result<()> {
+        let listener = TcpListener::bind(self.addr)?;
+        self.listen_on(&listener, stop)
+    }
+
+    /// Like `listen_until` but on a socket that's already bound, eg. so
+    /// that an address in use is found before the listener's started.
+    pub fn listen_on(&mut self, listener: &TcpListener, stop: &Stop) -> io::Result<()> {
+        let (send, recv) = channel();
+        let lines = Arc::new(BufferPool::new(MAX_POOLED_LINES));
 
-                    self.collector.push(aggregated_metrics)
+        let listener = listener.try_clone()?;
+        // Accepting also stops if recording panics, so that the socket is
+        // closed and the listener can be restarted.
+        let accepting = Stop::new();
+        let _stopped = accepting.on_drop();
+        let acceptor = {
+            let (accepting, stop) = (accepting.clone(), stop.clone());
+            let client = Client {
+                send,
+                read_timeout: self.read_timeout,
+                keepalive: self.keepalive,
+                repeater: self.repeater.clone(),
+                capture: self.capture.clone(),
+                linter: self.linter.clone(),
+                lines: lines.clone(),
+                #[cfg(feature = "flate2")]
+                gzip_frames: self.gzip_frames,
+            };
+            thread::spawn(move || StatsdTcpListener::accept_on_listener(listener, client, accepting, stop))
+        };
+
+        let mut batch = Batch::new(&self.collector);
+        let mut reported = Instant::now();
+        loop {
+            if reported.elapsed() >= TELEMETRY_INTERVAL {
+                self.report(&lines);
+                reported = Instant::now();
+            }
+            match recv.recv_timeout(batch.timeout(STOP_POLL)) {
+                Ok((line, received, peer)) => {
+                    batch.record(&line, received, peer);
+                    lines.give(line)
+                },
+                Err(RecvTimeoutError::Timeout) => if stop.is_stopped() {
+                    accepting.stop()
                 },
-                Err(_) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
             }
+            batch.push_if_due();
+        }
+        drop(batch);
+        match acceptor.join() {
+            Ok(accepted) => accepted,
+            Err(panic) => panic::resume_unwind(panic),
+        }
+    }
+
+    fn report(&self, lines: &BufferPool<u8>) {
+        let now = SystemTime::now();
+        let mut metrics = lines.metrics("statsd_tcp_lines", now);
+        if let Some(ref repeater) = self.repeater {
+            metrics.extend(repeater.metrics(now));
+        }
+        if !metrics.is_empty() {
+            self.collector.push(metrics)
         }
     }
 
-    fn accept_on_listener(listener: TcpListener, send: Sender<String>) {
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    // Clients have 30 seconds to send us data before we'll drop.
-                    let _ = stream.set_read_timeout(Some(Duration::from_secs(30)));
+    /// Accepts until `accepting` is stopped; clients read until `stop` is.
+    /// Connections aborted before they're accepted are skipped.
+    fn accept_on_listener(listener: TcpListener, client: Client, accepting: Stop, stop: Stop) -> io::Result<()> {
+        loop {
+            match accepting.accept(&listener) {
+                Ok(None) => return Ok(()),
+                Ok(Some(stream)) => {
+                    let peer = match stream.peer_addr() {
+                        Ok(peer) => peer,
+                        Err(_) => continue,
+                    };
+                    debug!("Accepted StatsD connection from {}", peer);
 
-                    let send = send.clone();
+                    let _ = stream.set_read_timeout(client.read_timeout);
+                    if let Some(idle) = client.keepalive {
+                        if let Err(err) = socket::set_keepalive(&stream, idle) {
+                            debug!("Error turning on keepalive for StatsD connection from {}: {}", peer, err)
+                        }
+                    }
+
+                    let client = client.clone();
+                    let stop = stop.clone();
 
                     thread::spawn(move || {
-                        StatsdTcpListener::handle_client(stream, send)
+                        StatsdTcpListener::handle_client(stream, peer, client, stop)
                     });
                 },
-                Err(e) => panic!("Failed to listen on TCP socket: {}", e),
+                Err(ref err) if err.kind() == io::ErrorKind::ConnectionAborted || err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(err),
             }
         }
     }
 
-    fn handle_client(stream: TcpStream, send: Sender<String>) {
+    fn handle_client(stream: TcpStream, peer: SocketAddr, client: Client, stop: Stop) {
         let mut reader = BufReader::new(stream);
+        #[cfg(feature = "flate2")]
+        {
+            if client.gzip_frames {
+                return StatsdTcpListener::handle_frames(&mut reader, peer, &client, &stop)
+            }
+        }
 
-        loop {
-            let mut line = String::new();
+        while !stop.is_stopped() {
+            let mut line = client.lines.take();
 
-            match reader.read_line(&mut line) {
+            match reader.read_until(b'\n', &mut line) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+                    debug!("Closing idle StatsD connection from {}", peer);
+                    break
+                },
                 Err(err) => {
-                    println!("Error reading StatsD line: {:?}", err);
+                    warn!("Error reading StatsD line from {}: {}", peer, err);
                     break
                 },
                 Ok(0) => {
                     // Close if there are no more bytes.
+                    debug!("StatsD connection from {} closed", peer);
                     break
                 },
-                Ok(_) => {
-                    send.send(line).unwrap()
+                Ok(_) => if !client.receive(line, SystemTime::now(), peer) {
+                    break
                 },
             }
         }
     } // fn handle_client
+
+    /// Like `handle_client` for a client sending gzipped frames, each
+    /// received as the lines it holds.
+    #[cfg(feature = "flate2")]
+    fn handle_frames(reader: &mut BufReader<TcpStream>, peer: SocketAddr, client: &Client, stop: &Stop) {
+        while !stop.is_stopped() {
+            let frame = match read_frame(reader) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+                    debug!("Closing idle StatsD connection from {}", peer);
+                    break
+                },
+                Err(err) => {
+                    warn!("Error reading StatsD frame from {}: {}", peer, err);
+                    break
+                },
+                Ok(None) => {
+                    debug!("StatsD connection from {} closed", peer);
+                    break
+                },
+                Ok(Some(frame)) => frame,
+            };
+            let received = SystemTime::now();
+            for line in frame.split(|&byte| byte == b'\n').filter(|line| !line.is_empty()) {
+                let mut buffer = client.lines.take();
+                buffer.extend_from_slice(line);
+                buffer.push(b'\n');
+                if !client.receive(buffer, received, peer) {
+                    return
+                }
+            }
+        }
+    }
 } // struct StatsdTcpListener
+
+/// What each client's connection is handled with.
+#[derive(Clone)]
+struct Client {
+    send: Sender<(Vec<u8>, SystemTime, SocketAddr)>,
+    read_timeout: Option<Duration>,
+    keepalive: Option<Duration>,
+    repeater: Option<Repeater>,
+    capture: Option<Capture>,
+    linter: Option<Linter>,
+    /// Shared with the recording loop, which gives lines back once they're
+    /// parsed.
+    lines: Arc<BufferPool<u8>>,
+    #[cfg(feature = "flate2")]
+    gzip_frames: bool,
+}
+
+impl Client {
+    /// Capture, repeat, and lint a line, then send it to be parsed unless
+    /// it's only repeated or linted. False once the listener stopped
+    /// recording.
+    fn receive(&self, line: Vec<u8>, received: SystemTime, peer: SocketAddr) -> bool {
+        if let Some(ref capture) = self.capture {
+            capture.capture(&line, received)
+        }
+        if let Some(ref repeater) = self.repeater {
+            repeater.repeat(&line);
+            if !repeater.parses() {
+                self.lines.give(line);
+                return true
+            }
+        }
+        if let Some(ref linter) = self.linter {
+            linter.lint(&line, peer);
+            self.lines.give(line);
+            return true
+        }
+        self.send.send((line, received, peer)).is_ok()
+    }
+}
+
+/// The decompressed contents of the next frame, or None if the client hung
+/// up between frames.
+#[cfg(feature = "flate2")]
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} byte frame is over the {} byte limit", len, MAX_FRAME)))
+    }
+    let mut compressed = vec![0; len];
+    reader.read_exact(&mut compressed)?;
+    let mut frame = vec![];
+    GzDecoder::new(&compressed[..]).take(MAX_FRAME_CONTENTS as u64 + 1).read_to_end(&mut frame)?;
+    if frame.len() > MAX_FRAME_CONTENTS {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame decompresses to over {} bytes", MAX_FRAME_CONTENTS)))
+    }
+    Ok(Some(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    use super::super::super::super::super::db::{Db, DbOptions};
+
+    #[test]
+    fn it_disconnects_idle_clients_after_the_read_timeout() {
+        let db = Db::new(DbOptions::default());
+        // Find a free port for the listener.
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let options = StatsdTcpOptions { read_timeout: Some(Duration::from_millis(200)), ..StatsdTcpOptions::default() };
+        let mut listener = StatsdTcpListener::with_options(db.collector(), addr, options).unwrap();
+        let stop = Stop::new();
+        let listening = {
+            let stop = stop.clone();
+            thread::spawn(move || listener.listen_until(&stop))
+        };
+
+        let mut client = loop {
+            if let Ok(client) = TcpStream::connect(addr) {
+                break client
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        // Hung up on rather than timing out.
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+
+        stop.stop();
+        listening.join().unwrap().unwrap();
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn it_reads_lines_from_gzipped_frames() {
+        use std::io::Write;
+        use std::sync::mpsc::channel;
+
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+
+        let db = Db::new(DbOptions::default());
+        let (tap, pushed) = channel();
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let options = StatsdTcpOptions { gzip_frames: Some(true), ..StatsdTcpOptions::default() };
+        let mut listener = StatsdTcpListener::with_options(db.collector().tap(tap), addr, options).unwrap();
+        let stop = Stop::new();
+        let listening = {
+            let stop = stop.clone();
+            thread::spawn(move || listener.listen_until(&stop))
+        };
+
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(b"foo:1|c\nbar:2|g\n").unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut client = loop {
+            if let Ok(client) = TcpStream::connect(addr) {
+                break client
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        client.write_all(&(compressed.len() as u32).to_be_bytes()).unwrap();
+        client.write_all(&compressed).unwrap();
+
+        let mut names = vec![];
+        while names.len() < 2 {
+            names.extend(pushed.recv_timeout(Duration::from_secs(5)).unwrap().iter().map(|metric| metric.id().name().to_string()));
+        }
+        assert_eq!(names, vec!["foo", "bar"]);
+
+        drop(client);
+        stop.stop();
+        listening.join().unwrap().unwrap();
+    }
+}
@@ -1,62 +1,222 @@
+use std::io;
 use std::net::{ToSocketAddrs, UdpSocket};
-use std::str;
-use std::sync::mpsc::channel;
-use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use super::parse_metrics;
+use super::{Batch, Capture, Linter, Repeater, BATCH_DELAY, TELEMETRY_INTERVAL};
 use super::super::super::collector::Collector;
+use super::super::super::super::metric::{CollectedMetric, MetricId};
+use super::super::super::super::util::Stop;
+use super::super::super::super::util::socket;
 
-/// Listens for StatsD UDP datagrams.
+/// How often a listener waiting for datagrams checks whether it's been
+/// stopped.
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+pub struct StatsdUdpOptions {
+    /// Receive buffer size to ask the kernel for, in bytes, so that bursts
+    /// are queued instead of dropped; defaults to the system's. The kernel
+    /// may cap it, eg. at `net.core.rmem_max` on Linux.
+    pub receive_buffer: Option<usize>,
+    /// Type of service (or IPv6 traffic class) byte for the socket.
+    pub tos: Option<u8>,
+}
+
+/// Listens for StatsD UDP datagrams. Besides the metrics it receives it
+/// records the socket's effective receive buffer size as the
+/// `metriqs.statsd.udp.receive_buffer` gauge and, on Linux, the datagrams
+/// the kernel dropped because it was full as the
+/// `metriqs.statsd.udp.kernel_drops` monotonic count, both with an
+/// `address` dimension.
 pub struct StatsdUdpListener {
     collector: Collector,
+    options: StatsdUdpOptions,
+    repeater: Option<Repeater>,
+    capture: Option<Capture>,
+    linter: Option<Linter>,
 }
 
 impl StatsdUdpListener {
     pub fn new(collector: Collector) -> StatsdUdpListener {
+        StatsdUdpListener::with_options(collector, StatsdUdpOptions::default())
+    }
+
+    pub fn with_options(collector: Collector, options: StatsdUdpOptions) -> StatsdUdpListener {
         StatsdUdpListener {
             collector,
+            options,
+            repeater: None,
+            capture: None,
+            linter: None,
         }
     }
 
-    /// Spawns a separate thread that listens for StatsD UDP datagrams,
-    /// received datagrams are sent back to the calling thread (this will
-    /// block) and the parsed metrics are recorded in the store.
-    pub fn listen<A: ToSocketAddrs>(&self, addr: A) {
-        let (send, recv) = channel();
+    /// Repeat every datagram received, before parsing it (if it's parsed
+    /// at all).
+    pub fn repeat(mut self, repeater: Repeater) -> StatsdUdpListener {
+        self.repeater = Some(repeater);
+        self
+    }
 
-        let socket = UdpSocket::bind(addr).unwrap();
+    /// Capture every datagram received, before it's repeated or parsed.
+    pub fn capture(mut self, capture: Capture) -> StatsdUdpListener {
+        self.capture = Some(capture);
+        self
+    }
 
-        thread::spawn(move || {
-            // Big enough to hold an ethernet frame:
-            //   https://github.com/etsy/statsd/blob/master/docs/metric_types.md#multi-metric-packets
-            let mut buf = [0; 1500];
-            loop {
-                let (bytes_read, _) = match socket.recv_from(&mut buf) {
-                    Ok(pair) => pair,
-                    Err(_) => return,
-                };
+    /// Lint every datagram received instead of parsing it into metrics (see
+    /// `Linter`). The listener still repeats and captures it.
+    pub fn lint(mut self, linter: Linter) -> StatsdUdpListener {
+        self.linter = Some(linter);
+        self
+    }
 
-                // Get a string from just the amount of bytes read.
-                let message: &str = match str::from_utf8(&buf[..bytes_read]) {
-                    Ok(s) => s,
-                    Err(_) => return,
-                };
+    /// Listens for StatsD UDP datagrams on the calling thread (this will
+    /// block), parsing each one straight from the receive buffer and
+    /// recording the parsed metrics in the store.
+    pub fn listen<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        self.listen_until(addr, &Stop::new())
+    }
 
-                send.send(message.to_owned()).unwrap();
-            }
-        });
+    /// Like `listen` but returns once `stop` is stopped, closing the
+    /// socket. Fails if the address can't be bound or receiving fails.
+    pub fn listen_until<A: ToSocketAddrs>(&self, addr: A, stop: &Stop) -> io::Result<()> {
+        let socket = UdpSocket::bind(addr)?;
+        self.listen_on(&socket, stop)
+    }
 
-        for line in recv {
-            match parse_metrics(line.trim_right().as_bytes()) {
-                Ok(metrics) => {
-                    let aggregated_metrics = metrics.into_iter()
-                        .map(|metric| metric.into())
-                        .collect();
+    /// Like `listen_until` but on a socket that's already bound, eg. so
+    /// that an address in use is found before the listener's started.
+    pub fn listen_on(&self, socket: &UdpSocket, stop: &Stop) -> io::Result<()> {
+        self.tune(socket);
 
-                    self.collector.push(aggregated_metrics)
+        // Big enough to hold an ethernet frame:
+        //   https://github.com/etsy/statsd/blob/master/docs/metric_types.md#multi-metric-packets
+        let mut buf = [0; 1500];
+        let mut batch = Batch::new(&self.collector);
+        let (mut reported, mut timeout) = (None, None);
+        while !stop.is_stopped() {
+            if reported.is_none_or(|at: Instant| at.elapsed() >= TELEMETRY_INTERVAL) {
+                self.report(socket);
+                reported = Some(Instant::now());
+            }
+            // Only switching between the two timeouts saves a syscall per
+            // datagram, at the cost of a batch sometimes waiting up to twice
+            // its delay.
+            let wait = if batch.is_empty() { STOP_POLL } else { BATCH_DELAY };
+            if timeout != Some(wait) {
+                socket.set_read_timeout(Some(wait))?;
+                timeout = Some(wait);
+            }
+            match socket.recv_from(&mut buf) {
+                Ok((bytes_read, peer)) => {
+                    let received = SystemTime::now();
+                    if let Some(ref capture) = self.capture {
+                        capture.capture(&buf[..bytes_read], received)
+                    }
+                    let parses = match self.repeater {
+                        Some(ref repeater) => {
+                            repeater.repeat(&buf[..bytes_read]);
+                            repeater.parses()
+                        },
+                        None => true,
+                    };
+                    match self.linter {
+                        Some(ref linter) => linter.lint(&buf[..bytes_read], peer),
+                        None if parses => batch.record(&buf[..bytes_read], received, peer),
+                        None => {},
+                    }
                 },
-                Err(_) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {},
+                Err(err) => return Err(err),
             }
+            batch.push_if_due();
         }
+        Ok(())
     } // fn listen
+
+    /// Apply the options to the socket; ones that can't be applied are
+    /// logged rather than failing the listener.
+    fn tune(&self, socket: &UdpSocket) {
+        if let Some(bytes) = self.options.receive_buffer {
+            if let Err(err) = socket::set_receive_buffer(socket, bytes) {
+                warn!("Error setting the StatsD UDP receive buffer to {} bytes: {}", bytes, err)
+            }
+        }
+        if let Some(tos) = self.options.tos {
+            if let Err(err) = socket::set_tos(socket, tos) {
+                warn!("Error setting the StatsD UDP type of service to {}: {}", tos, err)
+            }
+        }
+    }
+
+    fn report(&self, socket: &UdpSocket) {
+        let address = match socket.local_addr() {
+            Ok(address) => address.to_string(),
+            Err(_) => return,
+        };
+        let now = SystemTime::now();
+        let mut metrics = vec![];
+        if let Ok(bytes) = socket::receive_buffer(socket) {
+            let id = MetricId::from("metriqs.statsd.udp.receive_buffer").with_dimension("address", address.as_str());
+            metrics.push(CollectedMetric::Gauge(now, id, bytes as f64));
+        }
+        if let Ok(Some(drops)) = socket::udp_drops(socket) {
+            let id = MetricId::from("metriqs.statsd.udp.kernel_drops").with_dimension("address", address.as_str());
+            metrics.push(CollectedMetric::MonotonicCount(now, id, drops as f64));
+        }
+        if let Some(ref repeater) = self.repeater {
+            metrics.extend(repeater.metrics(now));
+        }
+        if !metrics.is_empty() {
+            self.collector.push(metrics)
+        }
+    }
 } // impl StatsdUdpListener
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use super::super::super::super::super::db::{Db, DbOptions};
+
+    #[test]
+    fn it_parses_datagrams_from_the_receive_buffer() {
+        let db = Db::new(DbOptions::default());
+        let (tap, pushed) = channel();
+        let listener = StatsdUdpListener::new(db.collector().tap(tap));
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let stop = Stop::new();
+        let listening = {
+            let stop = stop.clone();
+            thread::spawn(move || listener.listen_until(addr, &stop))
+        };
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let metrics = loop {
+            client.send_to(b"foo:1|c\nbar:2|g\n", addr).unwrap();
+            let metrics = pushed.recv().unwrap();
+            if metrics.iter().any(|metric| metric.id().name() == "foo") {
+                break metrics
+            }
+        };
+        // Retries can land in the same batch, but each datagram is parsed
+        // whole and in order.
+        let names = metrics.iter().map(|metric| metric.id().name().to_string()).collect::<Vec<_>>();
+        assert!(names.chunks(2).all(|names| names == ["foo", "bar"]));
+
+        stop.stop();
+        listening.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn it_fails_to_listen_on_an_address_in_use() {
+        let db = Db::new(DbOptions::default());
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let err = StatsdUdpListener::new(db.collector()).listen_until(socket.local_addr().unwrap(), &Stop::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    }
+}
@@ -0,0 +1,131 @@
+//! Prometheus-style relabeling of collected metrics, to bring names and
+//! dimensions from different emitters into one scheme. Each rule joins the
+//! values of its source dimensions (`__name__` is the metric's name, and a
+//! missing dimension is empty) with `;` and matches the result against its
+//! regex, which is anchored at both ends.
+
+use regex::{Captures, Regex};
+use string_cache::DefaultAtom as Atom;
+
+use super::super::metric::MetricId;
+
+/// The pseudo-dimension that stands for the metric's name.
+pub const NAME: &str = "__name__";
+
+#[derive(Clone, Debug)]
+pub enum RelabelAction {
+    /// If the regex matches, set `target` (a dimension, or `__name__` to
+    /// rename the metric) to `replacement` with `$1`, `${name}`, etc.
+    /// expanded from the match. An empty result removes the dimension.
+    Replace { target: String, replacement: String },
+    /// Drop metrics that don't match.
+    Keep,
+    /// Drop metrics that match.
+    Drop,
+    /// Remove dimensions whose key matches; sources aren't used.
+    DropDimensions,
+    /// Remove dimensions whose key doesn't match; sources aren't used.
+    KeepDimensions,
+    /// Copy dimensions whose key matches to the key given by `replacement`
+    /// expanded from the match, eg. `__tag_(.+)` and `$1` to strip a
+    /// prefix; sources aren't used. Follow it with `DropDimensions` to
+    /// rename rather than copy.
+    MapDimensions { replacement: String },
+}
+
+#[derive(Clone, Debug)]
+pub struct RelabelRule {
+    sources: Vec<String>,
+    regex: Regex,
+    action: RelabelAction,
+}
+
+impl RelabelRule {
+    pub fn new<S: AsRef<str>>(sources: &[S], regex: &str, action: RelabelAction) -> Result<RelabelRule, regex::Error> {
+        Ok(RelabelRule {
+            sources: sources.iter().map(|source| source.as_ref().to_string()).collect(),
+            regex: Regex::new(&format!("^(?:{})$", regex))?,
+            action,
+        })
+    }
+
+    /// The relabeled identifier, or None if the metric should be dropped.
+    pub fn apply(&self, id: MetricId) -> Option<MetricId> {
+        match self.action {
+            RelabelAction::Replace { ref target, ref replacement } => {
+                let source = self.source(&id);
+                let captures = match self.regex.captures(&source) {
+                    Some(captures) => captures,
+                    None => return Some(id),
+                };
+                let value = expand(&captures, replacement);
+                Some(if target == NAME {
+                    if value.is_empty() { id } else { id.with_name(value) }
+                } else if value.is_empty() {
+                    id.without_dimension(target)
+                } else {
+                    id.with_dimension(target.as_str(), value)
+                })
+            },
+            RelabelAction::Keep => if self.regex.is_match(&self.source(&id)) { Some(id) } else { None },
+            RelabelAction::Drop => if self.regex.is_match(&self.source(&id)) { None } else { Some(id) },
+            RelabelAction::DropDimensions | RelabelAction::KeepDimensions => {
+                let keep_matching = matches!(self.action, RelabelAction::KeepDimensions);
+                let dimensions = id.dimensions().iter()
+                    .filter(|(key, _)| self.regex.is_match(key) == keep_matching)
+                    .cloned()
+                    .collect();
+                Some(MetricId::new(id.name().clone(), dimensions))
+            },
+            RelabelAction::MapDimensions { ref replacement } => {
+                let mut dimensions = id.dimensions().to_vec();
+                for (key, value) in id.dimensions() {
+                    if let Some(captures) = self.regex.captures(key) {
+                        dimensions.push((Atom::from(expand(&captures, replacement)), value.clone()));
+                    }
+                }
+                Some(MetricId::new(id.name().clone(), dimensions))
+            },
+        }
+    }
+
+    fn source(&self, id: &MetricId) -> String {
+        self.sources.iter()
+            .map(|source| if source == NAME { id.name() as &str } else { id.dimension(source).map(|value| value as &str).unwrap_or("") })
+            .collect::<Vec<&str>>()
+            .join(";")
+    }
+}
+
+fn expand(captures: &Captures, replacement: &str) -> String {
+    let mut expanded = String::new();
+    captures.expand(replacement, &mut expanded);
+    expanded
+}
+
+/// Apply `rules` in order, stopping at the first that drops the metric.
+pub fn relabel(rules: &[RelabelRule], id: MetricId) -> Option<MetricId> {
+    rules.iter().try_fold(id, |id, rule| rule.apply(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_relabels() {
+        let rules = vec![
+            RelabelRule::new(&[NAME], "debug\\..*", RelabelAction::Drop).unwrap(),
+            RelabelRule::new(&[NAME, "code"], "http_(\\w+);(\\d)\\d\\d", RelabelAction::Replace { target: "class".to_string(), replacement: "${2}xx".to_string() }).unwrap(),
+            RelabelRule::new(&[NAME], "http_(\\w+)", RelabelAction::Replace { target: NAME.to_string(), replacement: "http.$1".to_string() }).unwrap(),
+            RelabelRule::new::<&str>(&[], "tag_(.+)", RelabelAction::MapDimensions { replacement: "$1".to_string() }).unwrap(),
+            RelabelRule::new::<&str>(&[], "tag_.+|code", RelabelAction::DropDimensions).unwrap(),
+        ];
+
+        let id = MetricId::from("http_requests").with_dimension("code", "503").with_dimension("tag_team", "web");
+        let expected = MetricId::from("http.requests").with_dimension("class", "5xx").with_dimension("team", "web");
+        assert_eq!(relabel(&rules, id), Some(expected));
+        assert_eq!(relabel(&rules, MetricId::from("debug.queue")), None);
+        assert_eq!(relabel(&rules, MetricId::from("other")), Some(MetricId::from("other")));
+    }
+}
@@ -0,0 +1,208 @@
+//! Thins out firehose metrics before they're queued. Rules either keep a
+//! random fraction of a metric's samples or cap how many are kept per
+//! window, while keeping counts approximately right: sampled counts are
+//! scaled up by the inverse of the rate, rounded up or down at random so
+//! totals aren't biased, sampled histogram samples are weighed by it (as
+//! StatsD's `@rate` timers are), and counts over a cap are carried into the first
+//! count of the same series kept in the following window. What's carried
+//! for a series that isn't kept again by then is pruned when the Db
+//! aggregates.
+//! Cumulative (monotonic) counts are never scaled since dropping some of
+//! their samples doesn't change the increase.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::super::metric::{CollectedMetric, MetricId};
+use super::super::util::{lock, Glob};
+
+#[derive(Clone, Debug)]
+pub struct SamplingRule {
+    pub names: Glob,
+    /// Fraction of samples kept, between 0 and 1.
+    pub rate: Option<f64>,
+    /// Most samples kept per window across every series the rule matches.
+    pub max_per_window: Option<usize>,
+    /// Defaults to 10 seconds.
+    pub window: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// For the xorshift generator; seeded on first use.
+    random: u64,
+    /// Start of the current window and samples kept in it, per rule.
+    windows: HashMap<usize, (u64, usize)>,
+    /// Counts dropped by caps, per series, and when (in milliseconds since
+    /// the epoch) the window following the last drop ends.
+    carried: HashMap<MetricId, (u64, i64)>,
+}
+
+impl State {
+    fn next_random(&mut self) -> f64 {
+        if self.random == 0 {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+            self.random = nanos | 1;
+        }
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        (self.random >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Applies the first of its rules that matches each metric's name.
+#[derive(Debug, Default)]
+pub struct Sampler {
+    rules: Vec<SamplingRule>,
+    state: Mutex<State>,
+}
+
+impl Sampler {
+    pub fn new(rules: Vec<SamplingRule>) -> Sampler {
+        Sampler {
+            rules,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The metric to queue, possibly with a compensated count or weight, or
+    /// None if it's dropped.
+    pub fn sample(&self, mut metric: CollectedMetric) -> Option<CollectedMetric> {
+        let (index, rule) = match self.rules.iter().enumerate().find(|(_, rule)| rule.names.matches(metric.id().name())) {
+            Some(found) => found,
+            None => return Some(metric),
+        };
+        let mut state = lock(&self.state);
+
+        if let Some(rate) = rule.rate {
+            if state.next_random() >= rate {
+                return None
+            }
+            if let CollectedMetric::Count(_, _, ref mut value) = metric {
+                // Always rounding to nearest would skew totals whenever
+                // the inverse rate isn't whole (eg. 3.33 at 0.3 is always
+                // 3); rounding up with the fraction's probability doesn't.
+                let scaled = *value as f64 / rate.max(f64::MIN_POSITIVE);
+                let rounded = if state.next_random() < scaled.fract().abs() { scaled.trunc() + scaled.signum() } else { scaled.trunc() };
+                *value = rounded as i64;
+            }
+            metric = match metric {
+                CollectedMetric::Histogram(time, id, value) => CollectedMetric::SampledHistogram(time, id, value, 1.0 / rate),
+                CollectedMetric::SampledHistogram(time, id, value, weight) => CollectedMetric::SampledHistogram(time, id, value, weight / rate),
+                other => other,
+            };
+        }
+
+        if let Some(max) = rule.max_per_window {
+            let window = rule.window.unwrap_or_else(|| Duration::from_secs(10)).as_millis().max(1) as u64;
+            let millis = metric.time().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+            let start = millis - millis % window;
+            let kept = state.windows.entry(index).or_insert((start, 0));
+            if kept.0 != start {
+                *kept = (start, 0);
+            }
+            if kept.1 >= max {
+                if let CollectedMetric::Count(_, ref id, value) = metric {
+                    let carried = state.carried.entry(id.clone()).or_insert((0, 0));
+                    *carried = (start + 2 * window, carried.1.saturating_add(value));
+                }
+                return None
+            }
+            kept.1 += 1;
+        }
+
+        if let CollectedMetric::Count(_, ref id, ref mut value) = metric {
+            if let Some((_, carried)) = state.carried.remove(id) {
+                *value = value.saturating_add(carried);
+            }
+        }
+        Some(metric)
+    }
+
+    /// Forget what was carried for series that weren't kept in the window
+    /// after their last drop, so that series which went idle while capped
+    /// don't accumulate.
+    pub fn prune(&self, now: SystemTime) {
+        let millis = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        lock(&self.state).carried.retain(|_, carried| carried.0 > millis);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::super::db::{AggregatedMetric, Db, DbOptions};
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn it_samples_and_compensates_counts() {
+        let sampler = Sampler::new(vec![SamplingRule { names: Glob::new("firehose.*"), rate: Some(0.25), max_per_window: None, window: None }]);
+        let kept = (0..10_000)
+            .filter_map(|_| sampler.sample(CollectedMetric::Count(at(0), MetricId::from("firehose.events"), 1)))
+            .collect::<Vec<CollectedMetric>>();
+        assert!(kept.len() > 2000 && kept.len() < 3000, "kept {}", kept.len());
+        assert!(kept.iter().all(|metric| *metric == CollectedMetric::Count(at(0), MetricId::from("firehose.events"), 4)));
+
+        let other = CollectedMetric::Count(at(0), MetricId::from("other"), 1);
+        assert_eq!(sampler.sample(other.clone()), Some(other));
+
+        // Inverse rates that aren't whole are rounded either way, keeping
+        // the total close to what was sent.
+        let sampler = Sampler::new(vec![SamplingRule { names: Glob::new("*"), rate: Some(0.3), max_per_window: None, window: None }]);
+        let total: i64 = (0..30_000)
+            .filter_map(|_| sampler.sample(CollectedMetric::Count(at(0), MetricId::from("events"), 1)))
+            .map(|metric| match metric { CollectedMetric::Count(_, _, value) => value, _ => 0 })
+            .sum();
+        assert!(total > 28_500 && total < 31_500, "total {}", total);
+    }
+
+    #[test]
+    fn it_weighs_sampled_histograms() {
+        let rules = vec![SamplingRule { names: Glob::new("latency"), rate: Some(0.25), max_per_window: None, window: None }];
+        let db = Db::new(DbOptions { sampling: rules, ..DbOptions::default() });
+        let subscription = db.aggregation_subscribe();
+        db.collector().push((0..10_000).map(|_| CollectedMetric::Histogram(at(5), MetricId::from("latency"), 1.0)).collect());
+        db.aggregate(None);
+
+        let count = subscription.recv().unwrap().iter()
+            .find_map(|metric| match *metric {
+                AggregatedMetric::Count(_, ref id, value) if id.name() == "latency.count" => Some(value),
+                _ => None,
+            })
+            .unwrap();
+        assert!(count > 9000 && count < 11_000, "count {}", count);
+    }
+
+    #[test]
+    fn it_caps_samples_per_window() {
+        let rule = SamplingRule { names: Glob::new("*"), rate: None, max_per_window: Some(2), window: Some(Duration::from_secs(10)) };
+        let sampler = Sampler::new(vec![rule]);
+        let count = |secs| sampler.sample(CollectedMetric::Count(at(secs), MetricId::from("events"), 1));
+        assert!(count(1).is_some());
+        assert!(count(2).is_some());
+        assert_eq!(count(3), None);
+        assert_eq!(count(4), None);
+        assert_eq!(sampler.sample(CollectedMetric::Gauge(at(5), MetricId::from("depth"), 1.0)), None);
+
+        // What was dropped is carried into the next window.
+        assert_eq!(count(11), Some(CollectedMetric::Count(at(11), MetricId::from("events"), 3)));
+
+        // Until the window after the drop ends.
+        assert!(count(12).is_some());
+        assert_eq!(count(13), None);
+        sampler.prune(at(29));
+        assert_eq!(lock(&sampler.state).carried.len(), 1);
+        sampler.prune(at(30));
+        assert!(lock(&sampler.state).carried.is_empty());
+    }
+}
@@ -0,0 +1,101 @@
+//! Rules that remove or coarsen high-cardinality dimensions (eg. request or
+//! user IDs from careless emitters) before they blow up the number of
+//! series.
+//!
+//! Hashing and bucketing aren't anonymisation: the hash is unkeyed, so
+//! anyone can hash likely values (eg. every numeric ID, or a list of
+//! emails) and match them up. Remove dimensions that must not leave the
+//! host.
+
+use string_cache::DefaultAtom as Atom;
+
+use super::super::metric::MetricId;
+use super::super::util::Glob;
+use super::super::util::hash::fnv1a;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScrubAction {
+    Remove,
+    /// Replace the value with a hash of it, which keeps the series apart
+    /// and the value out of sight, but can be reversed by guessing values
+    /// from a small space.
+    Hash,
+    /// Replace the value with one of this many buckets chosen by its hash,
+    /// eg. `user_id=17` when bucketing into 32.
+    Bucket(u64),
+}
+
+/// Applies `action` to the dimensions of metrics named like `names` that
+/// have keys like `dimensions`.
+#[derive(Clone, Debug)]
+pub struct ScrubRule {
+    pub names: Glob,
+    pub dimensions: Glob,
+    pub action: ScrubAction,
+}
+
+impl ScrubRule {
+    pub fn apply(&self, id: &MetricId) -> Option<MetricId> {
+        if !self.names.matches(id.name()) || !id.dimensions().iter().any(|(key, _)| self.dimensions.matches(key)) {
+            return None
+        }
+
+        let dimensions = id.dimensions().iter()
+            .filter_map(|(key, value)| {
+                if !self.dimensions.matches(key) {
+                    return Some((key.clone(), value.clone()))
+                }
+                match self.action {
+                    ScrubAction::Remove => None,
+                    ScrubAction::Hash => Some((key.clone(), Atom::from(format!("{:016x}", hash(value))))),
+                    ScrubAction::Bucket(buckets) => Some((key.clone(), Atom::from((hash(value) % buckets.max(1)).to_string()))),
+                }
+            })
+            .collect();
+        Some(MetricId::new(id.name().clone(), dimensions))
+    }
+}
+
+/// Apply every rule that matches, in order.
+pub fn scrub(rules: &[ScrubRule], mut id: MetricId) -> MetricId {
+    for rule in rules {
+        if let Some(scrubbed) = rule.apply(&id) {
+            id = scrubbed
+        }
+    }
+    id
+}
+
+/// FNV-1a, so that hashes and buckets don't change between builds.
+fn hash(value: &str) -> u64 {
+    fnv1a(value.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_scrubs_dimensions() {
+        let rules = vec![
+            ScrubRule { names: Glob::new("*"), dimensions: Glob::new("request_id"), action: ScrubAction::Remove },
+            ScrubRule { names: Glob::new("api.*"), dimensions: Glob::new("user_id"), action: ScrubAction::Bucket(32) },
+            ScrubRule { names: Glob::new("api.*"), dimensions: Glob::new("email"), action: ScrubAction::Hash },
+        ];
+        let id = MetricId::from("api.requests")
+            .with_dimension("request_id", "6f1c")
+            .with_dimension("user_id", "42")
+            .with_dimension("email", "a@example.com")
+            .with_dimension("host", "a");
+
+        let scrubbed = scrub(&rules, id);
+        assert_eq!(scrubbed.dimension("request_id"), None);
+        assert_eq!(scrubbed.dimension("host").map(|value| value as &str), Some("a"));
+        let bucket = scrubbed.dimension("user_id").unwrap().parse::<u64>().unwrap();
+        assert!(bucket < 32);
+        assert_eq!(&**scrubbed.dimension("email").unwrap(), format!("{:016x}", hash("a@example.com")));
+
+        let other = MetricId::from("db.queries").with_dimension("user_id", "42");
+        assert_eq!(scrub(&rules, other.clone()), other);
+    }
+}
@@ -0,0 +1,95 @@
+//! Guards against counts that can't be right. StatsD counters only go up,
+//! so a negative increment is an emitter's bug (eg. a subtraction gone
+//! wrong or an overflowed integer) and summed into an interval it produces
+//! nonsense.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::super::metric::CollectedMetric;
+
+/// What happens to counts collected with negative values.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NegativeCounts {
+    /// Sum them like any other count.
+    #[default]
+    Accept,
+    /// Count them as zero.
+    Clamp,
+    /// Drop them, counting how many were.
+    Drop,
+}
+
+/// Applies a `NegativeCounts` policy to collected counts, recording how
+/// many were clamped or dropped.
+#[derive(Clone, Debug, Default)]
+pub struct CountValidator {
+    policy: NegativeCounts,
+    /// Shared so that a Db keeps counting across reconfigurations.
+    rejected: Arc<AtomicUsize>,
+}
+
+impl CountValidator {
+    pub fn new(policy: NegativeCounts, rejected: Arc<AtomicUsize>) -> CountValidator {
+        CountValidator { policy, rejected }
+    }
+
+    pub fn policy(&self) -> NegativeCounts {
+        self.policy
+    }
+
+    /// The metric to queue, possibly clamped, or None if it's dropped.
+    /// Only `Count`s are validated; monotonic counts going down are resets.
+    pub fn validate(&self, mut metric: CollectedMetric) -> Option<CollectedMetric> {
+        if let CollectedMetric::Count(_, _, ref mut value) = metric {
+            if *value < 0 {
+                match self.policy {
+                    NegativeCounts::Accept => {},
+                    NegativeCounts::Clamp => {
+                        self.rejected.fetch_add(1, Ordering::Relaxed);
+                        *value = 0
+                    },
+                    NegativeCounts::Drop => {
+                        self.rejected.fetch_add(1, Ordering::Relaxed);
+                        return None
+                    },
+                }
+            }
+        }
+        Some(metric)
+    }
+
+    /// How many counts were clamped or dropped since the previous call.
+    pub fn take_rejected(&self) -> usize {
+        self.rejected.swap(0, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    use super::super::super::metric::MetricId;
+
+    #[test]
+    fn it_clamps_or_drops_negative_counts() {
+        let count = |value| CollectedMetric::Count(UNIX_EPOCH, MetricId::from("requests"), value);
+        let accept = CountValidator::new(NegativeCounts::Accept, Arc::default());
+        assert_eq!(accept.validate(count(-1)), Some(count(-1)));
+        assert_eq!(accept.take_rejected(), 0);
+
+        let clamp = CountValidator::new(NegativeCounts::Clamp, Arc::default());
+        assert_eq!(clamp.validate(count(-1)), Some(count(0)));
+        assert_eq!(clamp.validate(count(2)), Some(count(2)));
+        assert_eq!(clamp.take_rejected(), 1);
+
+        let drop = CountValidator::new(NegativeCounts::Drop, Arc::default());
+        assert_eq!(drop.validate(count(-1)), None);
+        let gauge = CollectedMetric::Gauge(UNIX_EPOCH, MetricId::from("temperature"), -1.0);
+        assert_eq!(drop.validate(gauge.clone()), Some(gauge));
+        assert_eq!(drop.take_rejected(), 1);
+        assert_eq!(drop.take_rejected(), 0);
+    }
+}
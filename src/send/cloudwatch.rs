@@ -0,0 +1,377 @@
+//! Sends aggregated metrics to Amazon CloudWatch with the `PutMetricData`
+//! query API, batching each aggregation into as few calls as the API's
+//! limits (1000 datums and 40KB per call) allow. Units come from the
+//! metric's metadata; dimensions map directly to CloudWatch dimensions
+//! (which are capped at 30).
+//!
+//! Requests are sent over plain HTTP, so `host` is usually a local proxy
+//! that terminates TLS to CloudWatch. With the `sigv4` feature requests can
+//! be signed with AWS credentials, otherwise the proxy has to sign them
+//! (eg. `aws-sigv4-proxy`).
+
+use std::io;
+use std::net::{SocketAddr};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "sigv4")]
+use sha2::{Digest, Sha256};
+
+use super::super::db::{AggregatedKey, AggregatedMetric, MetadataRegistry, Unit};
+use super::super::metric::MetricId;
+use super::super::util::{http, time};
+#[cfg(feature = "sigv4")]
+use super::super::util::hash::hmac;
+use super::delivery::{self, BufferedSender, Delivery, DeliveryOptions};
+use super::exporter::Exporter;
+
+const MAX_DATUMS: usize = 1000;
+const MAX_BYTES: usize = 40 * 1024;
+const MAX_DIMENSIONS: usize = 30;
+const CONTENT_TYPE: &str = "application/x-www-form-urlencoded; charset=utf-8";
+
+/// Units CloudWatch accepts as-is when a metric's unit is `Unit::Other`.
+const UNITS: [&str; 27] = [
+    "Seconds", "Microseconds", "Milliseconds", "Bytes", "Kilobytes", "Megabytes", "Gigabytes",
+    "Terabytes", "Bits", "Kilobits", "Megabits", "Gigabits", "Terabits", "Percent", "Count",
+    "Bytes/Second", "Kilobytes/Second", "Megabytes/Second", "Gigabytes/Second", "Terabytes/Second",
+    "Bits/Second", "Kilobits/Second", "Megabits/Second", "Gigabits/Second", "Terabits/Second",
+    "Count/Second", "None",
+];
+
+#[cfg(feature = "sigv4")]
+#[derive(Clone)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// For temporary credentials, eg. from an instance profile.
+    pub session_token: Option<String>,
+}
+
+#[derive(Default)]
+pub struct CloudWatchOptions {
+    /// Defaults to `metriqs`.
+    pub namespace: Option<String>,
+    /// Signed into requests; defaults to `us-east-1`.
+    pub region: Option<String>,
+    /// Defaults to 10 seconds.
+    pub timeout: Option<Duration>,
+    /// Sign requests with these; unsigned if unset.
+    #[cfg(feature = "sigv4")]
+    pub credentials: Option<AwsCredentials>,
+    /// How aggregations are buffered while CloudWatch is unreachable.
+    pub delivery: DeliveryOptions,
+}
+
+pub struct CloudWatchSender {
+    subscription: Receiver<Arc<Vec<AggregatedMetric>>>,
+    metadata: MetadataRegistry,
+    addr: SocketAddr,
+    host: String,
+    namespace: String,
+    #[cfg_attr(not(feature = "sigv4"), allow(dead_code))]
+    region: String,
+    timeout: Duration,
+    #[cfg(feature = "sigv4")]
+    credentials: Option<AwsCredentials>,
+    delivery: Delivery,
+}
+
+impl CloudWatchSender {
+    /// `host` is both where to connect and the `Host` header. `metadata`
+    /// is usually the Db's, so units recorded by receivers are used.
+    pub fn new(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, metadata: MetadataRegistry, host: &str, options: CloudWatchOptions) -> Result<CloudWatchSender, io::Error> {
+        let addr = super::resolve(host)?;
+
+        Ok(CloudWatchSender {
+            subscription,
+            metadata,
+            addr,
+            host: host.to_string(),
+            namespace: options.namespace.unwrap_or_else(|| "metriqs".to_string()),
+            region: options.region.unwrap_or_else(|| "us-east-1".to_string()),
+            timeout: options.timeout.unwrap_or_else(|| Duration::from_secs(10)),
+            #[cfg(feature = "sigv4")]
+            credentials: options.credentials,
+            delivery: Delivery::new(options.delivery),
+        })
+    }
+
+    /// Send one aggregation, stopping at the first call that fails. Its
+    /// error is `delivery::partial` if earlier calls succeeded, so that only
+    /// the rest is retried. Calls CloudWatch rejects are skipped, and the
+    /// rejection returned once the others are sent.
+    pub fn write(&self, metrics: &[AggregatedMetric]) -> io::Result<()> {
+        let (mut sent, mut result) = (0, Ok(()));
+        for (body, end) in self.requests(metrics) {
+            let headers = self.headers(&body);
+            let headers = headers.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect::<Vec<(&str, &str)>>();
+            match http::post(&self.addr, &self.host, "/", &headers, body.as_bytes(), self.timeout) {
+                Ok(200..=299) => (),
+                Ok(status) if status != 429 && status < 500 => {
+                    result = Err(delivery::rejected(format!("CloudWatch rejected a request with {}", status)))
+                },
+                failed => {
+                    let err = match failed {
+                        Ok(status) => io::Error::other(format!("CloudWatch responded with {}", status)),
+                        Err(err) => err,
+                    };
+                    return Err(if sent > 0 { delivery::partial(sent, err) } else { err })
+                },
+            }
+            sent = end;
+        }
+        result
+    }
+
+    #[cfg(not(feature = "sigv4"))]
+    fn headers(&self, _: &str) -> Vec<(String, String)> {
+        vec![("Content-Type".to_string(), CONTENT_TYPE.to_string())]
+    }
+
+    #[cfg(feature = "sigv4")]
+    fn headers(&self, body: &str) -> Vec<(String, String)> {
+        let mut headers = vec![("Content-Type".to_string(), CONTENT_TYPE.to_string())];
+        if let Some(ref credentials) = self.credentials {
+            headers.extend(sign(credentials, &self.region, &self.host, body, SystemTime::now()));
+        }
+        headers
+    }
+
+    /// Encode an aggregation as `PutMetricData` form bodies, each within
+    /// the API's limits, and with how many of `metrics` are sent once that
+    /// body is. A metric's datums are kept in one body unless they don't
+    /// fit in any.
+    pub fn requests(&self, metrics: &[AggregatedMetric]) -> Vec<(String, usize)> {
+        let prefix = format!("Action=PutMetricData&Version=2010-08-01&Namespace={}", encode(&self.namespace));
+        let mut requests = vec![];
+        let mut body = prefix.clone();
+        let mut datums = 0;
+        for (index, metric) in metrics.iter().enumerate() {
+            let fields = self.datums(metric);
+            let size = fields.iter().enumerate().map(|(n, datum)| datum_fields(datums + n + 1, datum).len()).sum::<usize>();
+            if datums > 0 && (datums + fields.len() > MAX_DATUMS || body.len() + size > MAX_BYTES) {
+                requests.push((body, index));
+                body = prefix.clone();
+                datums = 0;
+            }
+            for datum in &fields {
+                let mut encoded = datum_fields(datums + 1, datum);
+                if datums == MAX_DATUMS || (datums > 0 && body.len() + encoded.len() > MAX_BYTES) {
+                    requests.push((body, index));
+                    body = prefix.clone();
+                    datums = 0;
+                    encoded = datum_fields(1, datum);
+                }
+                body.push_str(&encoded);
+                datums += 1;
+            }
+        }
+        if datums > 0 {
+            requests.push((body, metrics.len()));
+        }
+        requests
+    }
+
+    /// Each datum's fields, without the `MetricData.member.N.` prefix.
+    fn datums(&self, metric: &AggregatedMetric) -> Vec<Vec<(String, String)>> {
+        if let AggregatedMetric::Sketch(time, ref id, ref sketch) = *metric {
+            let (min, max) = match (sketch.min(), sketch.max()) {
+                (Some(min), Some(max)) => (min, max),
+                _ => return vec![],
+            };
+            let (unit, scale) = self.unit(id.name());
+            let mut fields = common_fields(time, id, unit);
+            fields.push(("StatisticValues.SampleCount".to_string(), sketch.count().to_string()));
+            fields.push(("StatisticValues.Sum".to_string(), (sketch.sum() * scale).to_string()));
+            fields.push(("StatisticValues.Minimum".to_string(), (min * scale).to_string()));
+            fields.push(("StatisticValues.Maximum".to_string(), (max * scale).to_string()));
+            return vec![fields]
+        }
+
+        metric.entries().into_iter()
+            .filter(|&(_, (_, value))| value.is_finite())
+            .map(|(key, (time, value))| {
+                let (unit, value) = match key {
+                    AggregatedKey::Count(_) => ("Count", value),
+                    AggregatedKey::Gauge(_) => {
+                        let (unit, scale) = self.unit(metric.id().name());
+                        (unit, value * scale)
+                    },
+                };
+                let mut fields = common_fields(time, key.id(), unit);
+                fields.push(("Value".to_string(), value.to_string()));
+                fields
+            })
+            .collect()
+    }
+
+    /// CloudWatch unit for a metric and what to scale its values by to be
+    /// in that unit.
+    fn unit(&self, name: &str) -> (&'static str, f64) {
+        let unit = self.metadata.get(&name.into()).and_then(|metadata| metadata.unit);
+        match unit {
+            Some(Unit::Nanoseconds) => ("Microseconds", 0.001),
+            Some(Unit::Microseconds) => ("Microseconds", 1.0),
+            Some(Unit::Milliseconds) => ("Milliseconds", 1.0),
+            Some(Unit::Seconds) => ("Seconds", 1.0),
+            Some(Unit::Bytes) => ("Bytes", 1.0),
+            Some(Unit::Percent) => ("Percent", 1.0),
+            Some(Unit::Other(ref other)) => (UNITS.iter().find(|unit| *unit == other).cloned().unwrap_or("None"), 1.0),
+            None => ("None", 1.0),
+        }
+    }
+}
+
+impl Exporter for CloudWatchSender {
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()> {
+        self.write(batch)
+    }
+}
+
+impl BufferedSender for CloudWatchSender {
+    const BACKEND: &'static str = "CloudWatch";
+
+    fn subscription(&self) -> &Receiver<Arc<Vec<AggregatedMetric>>> {
+        &self.subscription
+    }
+
+    fn delivery(&mut self) -> &mut Delivery {
+        &mut self.delivery
+    }
+}
+
+fn common_fields(time: SystemTime, id: &MetricId, unit: &str) -> Vec<(String, String)> {
+    let mut fields = vec![
+        ("MetricName".to_string(), id.name().to_string()),
+        ("Timestamp".to_string(), time::iso8601(time)),
+        ("Unit".to_string(), unit.to_string()),
+    ];
+    for (index, (key, value)) in id.dimensions().iter().take(MAX_DIMENSIONS).enumerate() {
+        fields.push((format!("Dimensions.member.{}.Name", index + 1), key.to_string()));
+        fields.push((format!("Dimensions.member.{}.Value", index + 1), value.to_string()));
+    }
+    fields
+}
+
+fn datum_fields(member: usize, fields: &[(String, String)]) -> String {
+    fields.iter()
+        .map(|(field, value)| format!("&MetricData.member.{}.{}={}", member, field, encode(value)))
+        .collect()
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters, as both
+/// form bodies and Signature Version 4 expect.
+fn encode(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => output.push(byte as char),
+            byte => output.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    output
+}
+
+/// Signature Version 4 headers for a `PutMetricData` body.
+#[cfg(feature = "sigv4")]
+fn sign(credentials: &AwsCredentials, region: &str, host: &str, body: &str, now: SystemTime) -> Vec<(String, String)> {
+    let (year, month, day, hour, minute, second, _) = time::civil(now);
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let amz_date = format!("{}T{:02}{:02}{:02}Z", date, hour, minute, second);
+
+    let mut headers = vec![
+        ("content-type".to_string(), CONTENT_TYPE.to_string()),
+        ("host".to_string(), host.to_string()),
+        ("x-amz-date".to_string(), amz_date.clone()),
+    ];
+    if let Some(ref token) = credentials.session_token {
+        headers.push(("x-amz-security-token".to_string(), token.clone()));
+    }
+    let canonical_headers = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect::<String>();
+    let signed_headers = headers.iter().map(|(name, _)| name.as_str()).collect::<Vec<&str>>().join(";");
+    let canonical_request = format!("POST\n/\n\n{}\n{}\n{}", canonical_headers, signed_headers, hex(&Sha256::digest(body.as_bytes())));
+
+    let scope = format!("{}/{}/monitoring/aws4_request", date, region);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex(&Sha256::digest(canonical_request.as_bytes())));
+
+    let mut key = hmac(format!("AWS4{}", credentials.secret_access_key).as_bytes(), date.as_bytes());
+    for part in &[region, "monitoring", "aws4_request"] {
+        key = hmac(&key, part.as_bytes());
+    }
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+    let mut signed = vec![
+        ("X-Amz-Date".to_string(), amz_date),
+        ("Authorization".to_string(), format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key_id, scope, signed_headers, signature,
+        )),
+    ];
+    if let Some(ref token) = credentials.session_token {
+        signed.push(("X-Amz-Security-Token".to_string(), token.clone()));
+    }
+    signed
+}
+
+#[cfg(feature = "sigv4")]
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::time::UNIX_EPOCH;
+
+    use super::super::super::db::Metadata;
+
+    #[test]
+    fn it_batches_put_metric_data() {
+        let metadata = MetadataRegistry::new();
+        metadata.describe("latency".into(), Metadata { unit: Some(Unit::Nanoseconds), help: None });
+        let (_, subscription) = channel();
+        let sender = CloudWatchSender::new(subscription, metadata, "127.0.0.1:80", CloudWatchOptions::default()).unwrap();
+
+        let time = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let mut metrics = vec![AggregatedMetric::Gauge(time, MetricId::from("latency").with_dimension("host", "a b"), 2000.0)];
+        metrics.extend((0..1000).map(|n| AggregatedMetric::Count(time, MetricId::from(format!("c{}", n)), 1)));
+
+        let (requests, ends): (Vec<String>, Vec<usize>) = sender.requests(&metrics).into_iter().unzip();
+        assert!(requests[0].starts_with(concat!(
+            "Action=PutMetricData&Version=2010-08-01&Namespace=metriqs",
+            "&MetricData.member.1.MetricName=latency",
+            "&MetricData.member.1.Timestamp=2017-07-14T02%3A40%3A00.000Z",
+            "&MetricData.member.1.Unit=Microseconds",
+            "&MetricData.member.1.Dimensions.member.1.Name=host",
+            "&MetricData.member.1.Dimensions.member.1.Value=a%20b",
+            "&MetricData.member.1.Value=2&",
+        )));
+        assert!(requests.len() > 1);
+        assert!(requests[1].contains("&MetricData.member.1.Unit=Count&"));
+        let datums: usize = requests.iter().map(|body| body.matches(".MetricName=").count()).sum();
+        assert_eq!(datums, 1001);
+        assert!(requests.iter().all(|body| body.len() <= MAX_BYTES));
+        assert_eq!(ends[ends.len() - 1], 1001);
+        assert!(ends.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[cfg(feature = "sigv4")]
+    #[test]
+    fn it_signs_requests() {
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let headers = sign(&credentials, "us-east-1", "monitoring.us-east-1.amazonaws.com", "Action=ListMetrics", UNIX_EPOCH + Duration::from_secs(1_500_000_000));
+        assert_eq!(headers[0], ("X-Amz-Date".to_string(), "20170714T024000Z".to_string()));
+        assert_eq!(headers[1].1, concat!(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20170714/us-east-1/monitoring/aws4_request, ",
+            "SignedHeaders=content-type;host;x-amz-date, Signature=8926797cc467df654a7b6c63d20c9dcc98dd51de7ed9d41a0223edc29e0a1bfd",
+        ));
+    }
+}
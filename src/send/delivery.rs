@@ -0,0 +1,692 @@
+//! Buffering of aggregations a sender couldn't deliver, so that a backend
+//! outage delays metrics instead of losing them. Undelivered batches are
+//! kept in memory up to a cap; beyond it the oldest are spooled to disk if
+//! there's a spool, and dropped otherwise. Every new aggregation retries
+//! everything still pending, oldest first, resuming after the last batch
+//! that was delivered.
+//!
+//! The spool is a text file of batches that's only ever appended to, and
+//! removed once all of them are delivered:
+//!
+//! ```text
+//! metriqs-spool 2
+//! B
+//! C<TAB>1500000000000000000<TAB>requests<TAB>host=a<TAB>3
+//! K<TAB>1500000000000000000<TAB>latency<TAB><TAB>0.01<TAB>0<TAB>12.5<TAB>2.5<TAB>10<TAB>47:1;116:1<TAB>
+//! D<TAB>1
+//! ```
+//!
+//! Each `B` starts a batch and each line after it is one metric: the kind,
+//! nanoseconds since the epoch, name, and dimensions, then the value for
+//! counts and gauges; count, sum, and `quantile:value` pairs for summaries;
+//! and accuracy, zero count, sum, min, max, and positive and negative
+//! `key:count` bins for sketches. A `D` records that that many more of the
+//! oldest batches were delivered, so that they aren't sent again, even by
+//! the next run. Version 1 spools, which have no `D`s, are still read. Only
+//! the oldest undelivered batch is read at a time, from where the last
+//! delivered one ended.
+//!
+//! Batches a sender fails with a `rejected` error, ie. that the backend will
+//! never accept, are dropped rather than retried so that they don't hold up
+//! newer ones. A `partial` error says how much of a batch got through, and
+//! only the rest of it is retried (within a run; a restart resends spooled
+//! batches from their start).
+
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use string_cache::DefaultAtom as Atom;
+
+use super::super::db::{AggregatedMetric, DdSketch};
+use super::super::metric::{MetricId, Summary};
+use super::super::util::percent;
+use super::exporter::Exporter;
+
+const HEADER: &str = "metriqs-spool 2";
+
+/// Headers of spools this can read.
+const READABLE: [&str; 2] = ["metriqs-spool 1", HEADER];
+
+/// Characters with meaning to the spool format.
+const RESERVED: &str = "\t\n\r,=;:";
+
+pub type Batch = Arc<Vec<AggregatedMetric>>;
+
+/// An error for a batch the backend will never accept, eg. one it answered
+/// with a 400, so that `Delivery` drops it instead of retrying it.
+pub fn rejected<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, Rejected(error.into()))
+}
+
+/// Whether `err` was made by `rejected`.
+pub fn is_rejected(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<Rejected>())
+}
+
+fn reject(rejected: &mut usize, batch: &[AggregatedMetric], err: &io::Error) {
+    warn!("Dropping a batch of {} metrics the backend rejected: {}", batch.len(), err);
+    *rejected += 1;
+}
+
+/// An error for a batch of which only the first `sent` metrics were
+/// delivered, so that `Delivery` retries just the rest.
+pub fn partial(sent: usize, error: io::Error) -> io::Error {
+    io::Error::new(error.kind(), Partial { sent, error })
+}
+
+fn sent(err: &io::Error) -> usize {
+    err.get_ref().and_then(|inner| inner.downcast_ref::<Partial>()).map_or(0, |partial| partial.sent)
+}
+
+#[derive(Debug)]
+struct Rejected(Box<dyn Error + Send + Sync>);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for Rejected {}
+
+#[derive(Debug)]
+struct Partial {
+    sent: usize,
+    error: io::Error,
+}
+
+impl fmt::Display for Partial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (after sending {} metrics)", self.error, self.sent)
+    }
+}
+
+impl Error for Partial {}
+
+#[derive(Default)]
+pub struct DeliveryOptions {
+    /// Undelivered batches kept in memory; defaults to 60.
+    pub max_batches: Option<usize>,
+    /// Where batches beyond `max_batches` go instead of being dropped. A
+    /// spool left by a previous run is delivered first.
+    pub spool_path: Option<PathBuf>,
+    /// Once the spool is this many bytes further overflow is dropped;
+    /// defaults to 1 GiB.
+    pub max_spool_bytes: Option<u64>,
+    /// Kept up to date with `Delivery::pending` after every delivery, eg.
+    /// for health checks on another thread.
+    pub backlog: Option<Arc<AtomicUsize>>,
+}
+
+pub struct Delivery {
+    pending: VecDeque<Batch>,
+    max_batches: usize,
+    spool: Option<Spool>,
+    /// Metrics of the oldest pending batch that were already delivered.
+    sent: usize,
+    dropped: usize,
+    rejected: usize,
+    backlog: Option<Arc<AtomicUsize>>,
+}
+
+impl Delivery {
+    pub fn new(options: DeliveryOptions) -> Delivery {
+        let max_spool_bytes = options.max_spool_bytes.unwrap_or(1 << 30);
+        Delivery {
+            pending: VecDeque::new(),
+            max_batches: options.max_batches.unwrap_or(60),
+            spool: options.spool_path.map(|path| Spool::open(path, max_spool_bytes)),
+            sent: 0,
+            dropped: 0,
+            rejected: 0,
+            backlog: options.backlog,
+        }
+    }
+
+    /// Queue `batch` then try to `send` everything pending in order. Stops
+    /// at the first failure, which is returned; what's left is retried on
+    /// the next call. Batches `send` rejects are dropped and skipped.
+    pub fn deliver<F>(&mut self, batch: Batch, send: F) -> io::Result<()>
+        where F: FnMut(&[AggregatedMetric]) -> io::Result<()>
+    {
+        let result = self.deliver_pending(batch, send);
+        if let Some(ref backlog) = self.backlog {
+            backlog.store(self.pending(), Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn deliver_pending<F>(&mut self, batch: Batch, mut send: F) -> io::Result<()>
+        where F: FnMut(&[AggregatedMetric]) -> io::Result<()>
+    {
+        self.pending.push_back(batch);
+        while self.pending.len() > self.max_batches {
+            let oldest = self.pending.pop_front().unwrap();
+            let spooled = match self.spool {
+                Some(ref mut spool) => spool.append(&oldest).unwrap_or_else(|err| {
+                    warn!("Error spooling to {}: {}", spool.path.display(), err);
+                    false
+                }),
+                None => false,
+            };
+            if !spooled {
+                if self.spool.as_ref().is_none_or(|spool| spool.batches == 0) {
+                    self.sent = 0;
+                }
+                warn!("Dropping an undelivered batch of {} metrics", oldest.len());
+                self.dropped += 1;
+            }
+        }
+
+        // The spool always holds older batches than memory does.
+        if let Some(ref mut spool) = self.spool {
+            if spool.batches > 0 {
+                let mut delivered = 0;
+                while spool.batches > 0 {
+                    let (batch, next) = match spool.head() {
+                        Ok(head) => head,
+                        Err(err) => {
+                            // An unreadable spool would block delivery forever.
+                            warn!("Dropping {} batches from unreadable spool {}", spool.batches, spool.path.display());
+                            self.dropped += spool.batches;
+                            self.sent = 0;
+                            spool.clear()?;
+                            return Err(err)
+                        },
+                    };
+                    match send(&batch[self.sent.min(batch.len())..]) {
+                        Err(ref err) if is_rejected(err) => reject(&mut self.rejected, &batch, err),
+                        Err(err) => {
+                            self.sent += sent(&err);
+                            if delivered > 0 {
+                                spool.delivered(delivered)?;
+                            }
+                            return Err(err)
+                        },
+                        Ok(()) => (),
+                    }
+                    self.sent = 0;
+                    spool.advance(next);
+                    delivered += 1;
+                }
+                spool.clear()?;
+            }
+        }
+
+        while let Some(batch) = self.pending.front().cloned() {
+            match send(&batch[self.sent.min(batch.len())..]) {
+                Err(ref err) if is_rejected(err) => reject(&mut self.rejected, &batch, err),
+                Err(err) => {
+                    self.sent += sent(&err);
+                    return Err(err)
+                },
+                Ok(()) => (),
+            }
+            self.sent = 0;
+            self.pending.pop_front();
+        }
+        Ok(())
+    }
+
+
+    /// Batches waiting to be delivered, in memory and spooled.
+    pub fn pending(&self) -> usize {
+        self.pending.len() + self.spool.as_ref().map(|spool| spool.batches).unwrap_or(0)
+    }
+
+    /// Batches dropped because there was no room for them.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Batches dropped because the backend rejected them.
+    pub fn rejected(&self) -> usize {
+        self.rejected
+    }
+}
+
+/// Deliver every aggregation `receive` returns until it returns None, ie.
+/// the Db dropped the subscription, blocking the calling thread. `write`
+/// sends one aggregation; those it fails on are buffered in the sender's
+/// `delivery` and retried with the next one, and the error is logged as
+/// sending to `backend`. The buffer is put back even if `receive` or
+/// `write` panics, so that a supervised sender keeps it when restarted.
+pub fn send<S, D, R, W>(sender: &mut S, backend: &str, delivery: D, mut receive: R, mut write: W)
+    where D: Fn(&mut S) -> &mut Delivery,
+          R: FnMut(&mut S) -> Option<Batch>,
+          W: FnMut(&mut S, &[AggregatedMetric]) -> io::Result<()>
+{
+    // Taken out so that `write` can borrow the sender.
+    let taken = mem::take(delivery(sender));
+    let mut restore = Restore { sender, delivery: taken, put_back: &delivery };
+    let Restore { ref mut sender, delivery: ref mut taken, .. } = restore;
+    while let Some(metrics) = receive(sender) {
+        if let Err(err) = taken.deliver(metrics, |batch| write(sender, batch)) {
+            error!("Error sending to {}: {}", backend, err)
+        }
+    }
+}
+
+/// A sender fed by a Db subscription that buffers what it fails to send in a
+/// `Delivery`, as the built-in ones are.
+pub trait BufferedSender: Exporter {
+    /// Named in errors, eg. `Graphite`.
+    const BACKEND: &'static str;
+
+    fn subscription(&self) -> &Receiver<Batch>;
+
+    fn delivery(&mut self) -> &mut Delivery;
+
+    /// `export` every aggregation until the Db drops the subscription,
+    /// blocking the calling thread (see `send`).
+    fn send(&mut self) where Self: Sized {
+        send(self, Self::BACKEND, Self::delivery, |sender| sender.subscription().recv().ok(), |sender, batch| sender.export(batch))
+    }
+}
+
+/// Puts a sender's `Delivery` back when dropped, including on unwind.
+struct Restore<'a, S: 'a, D: Fn(&mut S) -> &mut Delivery + 'a> {
+    sender: &'a mut S,
+    delivery: Delivery,
+    put_back: &'a D,
+}
+
+impl<'a, S, D: Fn(&mut S) -> &mut Delivery> Drop for Restore<'a, S, D> {
+    fn drop(&mut self) {
+        *(self.put_back)(self.sender) = mem::take(&mut self.delivery);
+    }
+}
+
+impl Default for Delivery {
+    fn default() -> Delivery {
+        Delivery::new(DeliveryOptions::default())
+    }
+}
+
+struct Spool {
+    path: PathBuf,
+    max_bytes: u64,
+    /// Batches not yet delivered.
+    batches: usize,
+    /// Where the oldest of them starts, or 0 if the header hasn't been
+    /// checked yet.
+    offset: u64,
+}
+
+impl Spool {
+    /// Counts the batches of an existing spool and finds the first that
+    /// wasn't delivered; one that can't be read is found out when it's
+    /// sent.
+    fn open(path: PathBuf, max_bytes: u64) -> Spool {
+        let (mut starts, mut skipped) = (vec![], 0usize);
+        let mut offset = 0;
+        if let Ok(file) = File::open(&path) {
+            let mut reader = BufReader::new(file);
+            let mut line = String::new();
+            let mut position = 0;
+            while let Ok(read) = reader.read_line(&mut line) {
+                if read == 0 {
+                    break
+                }
+                let trimmed = line.trim_end_matches('\n');
+                if position == 0 {
+                    if READABLE.contains(&trimmed) {
+                        offset = read as u64
+                    }
+                } else if trimmed == "B" {
+                    starts.push(position)
+                } else if let Some(delivered) = trimmed.strip_prefix("D\t") {
+                    skipped += delivered.parse().unwrap_or(0)
+                }
+                position += read as u64;
+                line.clear();
+            }
+            if offset > 0 {
+                offset = starts.get(skipped).cloned().unwrap_or(position);
+            }
+        }
+        Spool {
+            path,
+            max_bytes,
+            batches: starts.len().saturating_sub(skipped),
+            offset,
+        }
+    }
+
+    /// Whether there was room for the batch.
+    fn append(&mut self, batch: &[AggregatedMetric]) -> io::Result<bool> {
+        let size = fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0);
+        if size >= self.max_bytes {
+            return Ok(false)
+        }
+
+        let mut writer = BufWriter::new(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        if size == 0 {
+            writeln!(writer, "{}", HEADER)?;
+            self.offset = HEADER.len() as u64 + 1;
+        }
+        write_batch(&mut writer, batch)?;
+        writer.flush()?;
+        self.batches += 1;
+        Ok(true)
+    }
+
+    /// The oldest batch not yet delivered, and where the one after it
+    /// starts.
+    fn head(&self) -> io::Result<(Batch, u64)> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        let mut offset = self.offset;
+        if offset == 0 {
+            offset = reader.read_line(&mut line)? as u64;
+            if !READABLE.contains(&line.trim_end_matches('\n')) {
+                return Err(invalid("missing spool header"))
+            }
+        }
+
+        let mut batch: Option<Vec<AggregatedMetric>> = None;
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            let trimmed = line.trim_end_matches('\n');
+            if read == 0 || trimmed == "B" && batch.is_some() {
+                break
+            }
+            offset += read as u64;
+            if trimmed == "B" {
+                batch = Some(vec![]);
+            } else if !trimmed.starts_with("D\t") {
+                let metric = parse_line(trimmed).ok_or_else(|| invalid(format!("invalid spool line: {}", trimmed)))?;
+                batch.as_mut().ok_or_else(|| invalid("spooled metric outside of a batch"))?.push(metric);
+            }
+        }
+        batch.map(|batch| (Arc::new(batch), offset)).ok_or_else(|| invalid("missing spooled batch"))
+    }
+
+    /// Move past the oldest undelivered batch to the next, which starts at
+    /// `offset`.
+    fn advance(&mut self, offset: u64) {
+        self.batches -= 1;
+        self.offset = offset;
+    }
+
+    /// Record that the oldest `count` batches were delivered, for the next
+    /// run.
+    fn delivered(&mut self, count: usize) -> io::Result<()> {
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        writeln!(file, "D\t{}", count)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.batches = 0;
+        self.offset = 0;
+        match fs::remove_file(&self.path) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+fn write_batch<W: Write>(writer: &mut W, batch: &[AggregatedMetric]) -> io::Result<()> {
+    writeln!(writer, "B")?;
+    for metric in batch {
+        let (kind, time, id) = match *metric {
+            AggregatedMetric::Count(time, ref id, _) => ("C", time, id),
+            AggregatedMetric::Gauge(time, ref id, _) => ("G", time, id),
+            AggregatedMetric::Summary(time, ref id, _) => ("S", time, id),
+            AggregatedMetric::Sketch(time, ref id, _) => ("K", time, id),
+        };
+        let nanos = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let dimensions = id.dimensions().iter()
+            .map(|(k, v)| format!("{}={}", encode(k), encode(v)))
+            .collect::<Vec<String>>()
+            .join(",");
+        let fields = match *metric {
+            AggregatedMetric::Count(_, _, value) => value.to_string(),
+            AggregatedMetric::Gauge(_, _, value) => value.to_string(),
+            AggregatedMetric::Summary(_, _, ref summary) => {
+                let quantiles = summary.quantiles.iter()
+                    .map(|&(quantile, value)| format!("{}:{}", quantile, value))
+                    .collect::<Vec<String>>();
+                format!("{}\t{}\t{}", summary.count, summary.sum, quantiles.join(";"))
+            },
+            AggregatedMetric::Sketch(_, _, ref sketch) => format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                sketch.relative_accuracy(),
+                sketch.zero_count(),
+                sketch.sum(),
+                sketch.min().unwrap_or(0.0),
+                sketch.max().unwrap_or(0.0),
+                bins(sketch.positive_bins()),
+                bins(sketch.negative_bins()),
+            ),
+        };
+        writeln!(writer, "{}\t{}\t{}\t{}\t{}", kind, nanos, encode(id.name()), dimensions, fields)?;
+    }
+    Ok(())
+}
+
+fn bins(bins: &BTreeMap<i32, u64>) -> String {
+    bins.iter().map(|(key, count)| format!("{}:{}", key, count)).collect::<Vec<String>>().join(";")
+}
+
+fn parse_line(line: &str) -> Option<AggregatedMetric> {
+    let fields = line.split('\t').collect::<Vec<&str>>();
+    if fields.len() < 5 {
+        return None
+    }
+
+    let time = SystemTime::UNIX_EPOCH + Duration::from_nanos(fields[1].parse().ok()?);
+    let mut dimensions = vec![];
+    for pair in fields[3].split(',').filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let key = percent::decode(parts.next()?)?;
+        let value = percent::decode(parts.next()?)?;
+        dimensions.push((Atom::from(key), Atom::from(value)));
+    }
+    let id = MetricId::new(percent::decode(fields[2])?, dimensions);
+
+    match (fields[0], fields.len()) {
+        ("C", 5) => Some(AggregatedMetric::Count(time, id, fields[4].parse().ok()?)),
+        ("G", 5) => Some(AggregatedMetric::Gauge(time, id, fields[4].parse().ok()?)),
+        ("S", 7) => {
+            let quantiles = pairs(fields[6])?;
+            let summary = Summary { count: fields[4].parse().ok()?, sum: fields[5].parse().ok()?, quantiles };
+            Some(AggregatedMetric::Summary(time, id, summary))
+        },
+        ("K", 11) => {
+            let sketch = DdSketch::from_parts(
+                fields[4].parse().ok()?,
+                pairs(fields[9])?.into_iter().collect(),
+                pairs(fields[10])?.into_iter().collect(),
+                fields[5].parse().ok()?,
+                fields[6].parse().ok()?,
+                fields[7].parse().ok()?,
+                fields[8].parse().ok()?,
+            );
+            Some(AggregatedMetric::Sketch(time, id, sketch))
+        },
+        _ => None,
+    }
+}
+
+/// Parse `a:b;c:d` pairs.
+fn pairs<K: std::str::FromStr, V: std::str::FromStr>(input: &str) -> Option<Vec<(K, V)>> {
+    input.split(';')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, ':');
+            Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+        })
+        .collect()
+}
+
+fn encode(input: &str) -> String {
+    percent::encode(input, RESERVED)
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+
+    #[test]
+    fn it_spools_and_resumes_delivery() {
+        let path = std::env::temp_dir().join(format!("metriqs-spool-{}", std::process::id()));
+        let options = DeliveryOptions { max_batches: Some(1), spool_path: Some(path.clone()), ..DeliveryOptions::default() };
+        let mut delivery = Delivery::new(options);
+
+        let mut sketch = DdSketch::new(0.01);
+        sketch.insert(-2.5);
+        sketch.insert(10.0);
+        let summary = Summary { count: 2, sum: 3.0, quantiles: vec![(0.5, 1.5)] };
+        let batches = vec![
+            Arc::new(vec![AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("a,b").with_dimension("host", "x=y"), 1)]),
+            Arc::new(vec![AggregatedMetric::Sketch(UNIX_EPOCH, MetricId::from("latency"), sketch)]),
+            Arc::new(vec![AggregatedMetric::Summary(UNIX_EPOCH, MetricId::from("summary"), summary)]),
+        ];
+
+        let down = |_: &[AggregatedMetric]| Err(io::Error::other("backend is down"));
+        for batch in &batches {
+            assert!(delivery.deliver(batch.clone(), down).is_err());
+        }
+        assert_eq!(delivery.pending(), 3);
+        assert_eq!(delivery.dropped(), 0);
+
+        let delivered = RefCell::new(vec![]);
+        let up = |batch: &[AggregatedMetric]| {
+            delivered.borrow_mut().push(batch.to_vec());
+            Ok(())
+        };
+        let last = Arc::new(vec![AggregatedMetric::Gauge(UNIX_EPOCH, MetricId::from("last"), 1.0)]);
+        delivery.deliver(last.clone(), up).unwrap();
+
+        let mut expected = batches.iter().map(|batch| batch.to_vec()).collect::<Vec<Vec<AggregatedMetric>>>();
+        expected.push(last.to_vec());
+        assert_eq!(delivered.into_inner(), expected);
+        assert_eq!(delivery.pending(), 0);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn it_resumes_after_the_last_delivered_batch() {
+        let path = std::env::temp_dir().join(format!("metriqs-spool-resume-{}", std::process::id()));
+        let options = || DeliveryOptions { max_batches: Some(1), spool_path: Some(path.clone()), ..DeliveryOptions::default() };
+        let mut delivery = Delivery::new(options());
+        let batch = |name: &str| Arc::new(vec![AggregatedMetric::Count(UNIX_EPOCH, MetricId::from(name), 1)]);
+        let down = |_: &[AggregatedMetric]| Err(io::Error::other("backend is down"));
+        for name in &["a", "b", "c"] {
+            assert!(delivery.deliver(batch(name), down).is_err());
+        }
+        let spooled = fs::read_to_string(&path).unwrap();
+
+        // Only `a` gets through before the backend fails again.
+        let delivered = RefCell::new(vec![]);
+        let flaky = |metrics: &[AggregatedMetric]| {
+            if !delivered.borrow().is_empty() {
+                return Err(io::Error::other("backend is down"))
+            }
+            delivered.borrow_mut().push(metrics[0].id().name().to_string());
+            Ok(())
+        };
+        assert!(delivery.deliver(batch("d"), flaky).is_err());
+        assert_eq!(delivery.pending(), 3);
+        // What was spooled is appended to rather than rewritten.
+        assert_eq!(fs::read_to_string(&path).unwrap()[..spooled.len()], spooled);
+
+        // Including by the next run, which gets what was spooled.
+        drop(delivery);
+        let mut delivery = Delivery::new(options());
+        assert_eq!(delivery.pending(), 2);
+        let delivered = RefCell::new(vec![]);
+        let up = |metrics: &[AggregatedMetric]| {
+            delivered.borrow_mut().push(metrics[0].id().name().to_string());
+            Ok(())
+        };
+        delivery.deliver(batch("e"), up).unwrap();
+        assert_eq!(delivered.into_inner(), vec!["b", "c", "e"]);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn it_drops_rejected_batches() {
+        let path = std::env::temp_dir().join(format!("metriqs-spool-rejected-{}", std::process::id()));
+        let options = DeliveryOptions { max_batches: Some(1), spool_path: Some(path.clone()), ..DeliveryOptions::default() };
+        let mut delivery = Delivery::new(options);
+        let batch = |name: &str| Arc::new(vec![AggregatedMetric::Count(UNIX_EPOCH, MetricId::from(name), 1)]);
+        let down = |_: &[AggregatedMetric]| Err(io::Error::other("backend is down"));
+        for name in &["bad", "a", "worse"] {
+            assert!(delivery.deliver(batch(name), down).is_err());
+        }
+
+        // Rejected ones aren't retried, and don't hold up the rest, whether
+        // they were spooled or in memory.
+        let delivered = RefCell::new(vec![]);
+        let picky = |metrics: &[AggregatedMetric]| {
+            let name = metrics[0].id().name().to_string();
+            if name != "a" && name != "b" {
+                return Err(rejected(format!("can't take {}", name)))
+            }
+            delivered.borrow_mut().push(name);
+            Ok(())
+        };
+        delivery.deliver(batch("b"), picky).unwrap();
+        assert_eq!(delivered.into_inner(), vec!["a", "b"]);
+        assert_eq!(delivery.pending(), 0);
+        assert_eq!(delivery.rejected(), 2);
+        assert!(!path.exists());
+        assert!(!is_rejected(&io::Error::other("backend is down")));
+    }
+
+    #[test]
+    fn it_retries_only_what_a_partial_send_missed() {
+        let mut delivery = Delivery::default();
+        let count = |name: &str| AggregatedMetric::Count(UNIX_EPOCH, MetricId::from(name), 1);
+        let flaky = |_: &[AggregatedMetric]| Err(partial(1, io::Error::other("backend is down")));
+        assert!(delivery.deliver(Arc::new(vec![count("a"), count("b"), count("c")]), flaky).is_err());
+
+        let delivered = RefCell::new(vec![]);
+        let up = |metrics: &[AggregatedMetric]| {
+            delivered.borrow_mut().push(metrics.iter().map(|metric| metric.id().name().to_string()).collect::<Vec<String>>());
+            Ok(())
+        };
+        delivery.deliver(Arc::new(vec![count("d")]), up).unwrap();
+        assert_eq!(delivered.into_inner(), vec![vec!["b", "c"], vec!["d"]]);
+        assert_eq!(delivery.pending(), 0);
+    }
+
+    #[test]
+    fn it_keeps_undelivered_batches_when_a_sender_panics() {
+        struct Sender {
+            delivery: Delivery,
+            batches: Vec<Batch>,
+        }
+
+        let batch = Arc::new(vec![AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("requests"), 1)]);
+        let mut sender = Sender { delivery: Delivery::default(), batches: vec![batch.clone()] };
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            send(&mut sender, "test", |sender| &mut sender.delivery, |sender| match sender.batches.pop() {
+                Some(batch) => Some(batch),
+                None => panic!("bad batch"),
+            }, |_, _| Err(io::Error::other("backend is down")))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(sender.delivery.pending(), 1);
+    }
+}
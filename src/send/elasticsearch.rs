@@ -0,0 +1,294 @@
+//! Indexes aggregated metrics into Elasticsearch or OpenSearch with the
+//! bulk API (`POST /_bulk`), a document per point:
+//!
+//! ```json
+//! {"@timestamp":"2017-07-14T02:40:00.000Z","name":"requests","dimensions":{"host":"a"},"kind":"count","value":3}
+//! ```
+//!
+//! Metrics are flattened the same way they're stored. Documents go to an
+//! index per hour, day, or month of their timestamp, eg.
+//! `metriqs-2017.07.14`, so that old ones can be dropped wholesale. Each
+//! document's `_id` is derived from its series and timestamp, so retrying
+//! a request overwrites whatever of it was already indexed instead of
+//! duplicating it.
+
+use std::io;
+use std::net::{SocketAddr};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::super::db::{AggregatedKey, AggregatedMetric};
+use super::super::util::{http, json};
+use super::super::util::hash::fnv1a;
+use super::super::util::time::{civil, iso8601};
+use super::delivery::{self, BufferedSender, Delivery, DeliveryOptions};
+use super::exporter::Exporter;
+
+/// How much time each index covers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexPeriod {
+    /// eg. `metriqs-2017.07.14.02`
+    Hourly,
+    /// eg. `metriqs-2017.07.14`
+    Daily,
+    /// eg. `metriqs-2017.07`
+    Monthly,
+}
+
+#[derive(Default)]
+pub struct ElasticsearchOptions {
+    /// Indices are named this followed by a dash and the period; defaults
+    /// to `metriqs`.
+    pub index_prefix: Option<String>,
+    /// Defaults to `IndexPeriod::Daily`.
+    pub index_period: Option<IndexPeriod>,
+    /// Extra request headers, eg. `Authorization`.
+    pub headers: Vec<(String, String)>,
+    /// Defaults to 10 seconds.
+    pub timeout: Option<Duration>,
+    /// Defaults to 1,000.
+    pub max_documents_per_request: Option<usize>,
+    /// How many times a request that got a 429 or 5xx, or had documents
+    /// rejected with one, or couldn't be sent at all, is retried. Defaults
+    /// to 3.
+    pub max_retries: Option<u32>,
+    /// Wait before the first retry, doubled for each one after it. Defaults
+    /// to 100 milliseconds.
+    pub retry_backoff: Option<Duration>,
+    /// How aggregations are buffered while the cluster is unreachable.
+    pub delivery: DeliveryOptions,
+}
+
+pub struct ElasticsearchSender {
+    subscription: Receiver<Arc<Vec<AggregatedMetric>>>,
+    addr: SocketAddr,
+    host: String,
+    index_prefix: String,
+    index_period: IndexPeriod,
+    headers: Vec<(String, String)>,
+    timeout: Duration,
+    max_documents_per_request: usize,
+    max_retries: u32,
+    retry_backoff: Duration,
+    delivery: Delivery,
+}
+
+impl ElasticsearchSender {
+    /// `host` is both where to connect (eg. `elasticsearch:9200`) and the
+    /// `Host` header.
+    pub fn new(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, host: &str, options: ElasticsearchOptions) -> Result<ElasticsearchSender, io::Error> {
+        let addr = super::resolve(host)?;
+
+        Ok(ElasticsearchSender {
+            subscription,
+            addr,
+            host: host.to_string(),
+            index_prefix: options.index_prefix.unwrap_or_else(|| "metriqs".to_string()),
+            index_period: options.index_period.unwrap_or(IndexPeriod::Daily),
+            headers: options.headers,
+            timeout: options.timeout.unwrap_or_else(|| Duration::from_secs(10)),
+            max_documents_per_request: options.max_documents_per_request.unwrap_or(1000).max(1),
+            max_retries: options.max_retries.unwrap_or(3),
+            retry_backoff: options.retry_backoff.unwrap_or_else(|| Duration::from_millis(100)),
+            delivery: Delivery::new(options.delivery),
+        })
+    }
+
+    /// Index one aggregation in requests of at most
+    /// `max_documents_per_request` documents.
+    pub fn write(&self, metrics: &[AggregatedMetric]) -> io::Result<()> {
+        let entries = metrics.iter().flat_map(|metric| metric.entries()).collect::<Vec<_>>();
+        for batch in entries.chunks(self.max_documents_per_request) {
+            self.post(&self.encode(batch))?;
+        }
+        Ok(())
+    }
+
+    /// Render a bulk request body: an `index` action and a document per
+    /// point, each a line of JSON.
+    pub fn encode(&self, entries: &[(AggregatedKey, (SystemTime, f64))]) -> String {
+        let mut body = String::new();
+        for &(ref key, (time, value)) in entries {
+            let (kind, id) = match *key {
+                AggregatedKey::Count(ref id) => ("count", id),
+                AggregatedKey::Gauge(ref id) => ("gauge", id),
+            };
+            let dimensions = id.dimensions().iter()
+                .map(|(key, value)| format!("{}:{}", json::string(key), json::string(value)))
+                .collect::<Vec<String>>();
+            body.push_str(&format!(
+                r#"{{"index":{{"_index":{},"_id":"{:016x}"}}}}"#,
+                json::string(&self.index(time)),
+                document_id(key, time),
+            ));
+            body.push('\n');
+            body.push_str(&format!(
+                r#"{{"@timestamp":"{}","name":{},"dimensions":{{{}}},"kind":"{}","value":{}}}"#,
+                iso8601(time),
+                json::string(id.name()),
+                dimensions.join(","),
+                kind,
+                json::number(value),
+            ));
+            body.push('\n');
+        }
+        body
+    }
+
+    /// The index a point at `time` belongs in.
+    fn index(&self, time: SystemTime) -> String {
+        let (year, month, day, hour, ..) = civil(time);
+        match self.index_period {
+            IndexPeriod::Hourly => format!("{}-{:04}.{:02}.{:02}.{:02}", self.index_prefix, year, month, day, hour),
+            IndexPeriod::Daily => format!("{}-{:04}.{:02}.{:02}", self.index_prefix, year, month, day),
+            IndexPeriod::Monthly => format!("{}-{:04}.{:02}", self.index_prefix, year, month),
+        }
+    }
+
+    fn post(&self, body: &str) -> io::Result<()> {
+        let mut headers = vec![("Content-Type", "application/x-ndjson")];
+        headers.extend(self.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            let error = match http::post_reading(&self.addr, &self.host, "/_bulk", &headers, body.as_bytes(), self.timeout) {
+                // A successful bulk request can still have failed to index
+                // some of its documents.
+                Ok((200..=299, response)) => match rejected(&response) {
+                    None => return Ok(()),
+                    Some(true) => io::Error::other("Elasticsearch rejected documents, retrying"),
+                    Some(false) => {
+                        warn!("Elasticsearch rejected documents that can't be indexed");
+                        return Ok(())
+                    },
+                },
+                Ok((status, _)) if status == 429 || status >= 500 => io::Error::other(format!("Elasticsearch responded with {}", status)),
+                Ok((status, _)) => return Err(delivery::rejected(format!("Elasticsearch rejected a bulk request with {}", status))),
+                Err(err) => err,
+            };
+            if attempt >= self.max_retries {
+                return Err(error)
+            }
+            thread::sleep(backoff);
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+impl Exporter for ElasticsearchSender {
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()> {
+        self.write(batch)
+    }
+}
+
+impl BufferedSender for ElasticsearchSender {
+    const BACKEND: &'static str = "Elasticsearch";
+
+    fn subscription(&self) -> &Receiver<Arc<Vec<AggregatedMetric>>> {
+        &self.subscription
+    }
+
+    fn delivery(&mut self) -> &mut Delivery {
+        &mut self.delivery
+    }
+}
+
+/// Whether a bulk response says documents failed, and if so whether any of
+/// them are worth retrying: those rejected for load (429) or by a failing
+/// shard (5xx), rather than for being malformed.
+fn rejected(response: &str) -> Option<bool> {
+    if !response.contains(r#""errors":true"#) {
+        return None
+    }
+    Some(response.contains(r#""status":429"#) || response.contains(r#""status":5"#))
+}
+
+/// The same for every copy of a point, so that re-sending a spooled batch
+/// after a restart or an upgrade overwrites rather than duplicates it.
+fn document_id(key: &AggregatedKey, time: SystemTime) -> u64 {
+    let mut bytes = vec![matches!(*key, AggregatedKey::Count(_)) as u8];
+    // Length prefixed so that eg. `ab`, `c` and `a`, `bc` differ.
+    let mut push = |string: &str| {
+        bytes.extend_from_slice(&(string.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(string.as_bytes());
+    };
+    let id = key.id();
+    push(id.name());
+    for (key, value) in id.dimensions() {
+        push(key);
+        push(value);
+    }
+    bytes.extend_from_slice(&time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_le_bytes());
+    fnv1a(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+
+    use super::super::super::metric::MetricId;
+
+    #[test]
+    fn it_encodes_bulk_requests() {
+        let (_, subscription) = channel();
+        let options = ElasticsearchOptions { index_period: Some(IndexPeriod::Monthly), ..ElasticsearchOptions::default() };
+        let sender = ElasticsearchSender::new(subscription, "127.0.0.1:9200", options).unwrap();
+        let time = UNIX_EPOCH + Duration::from_millis(1_500_000_000_123);
+        let count = AggregatedMetric::Count(time, MetricId::from("requests").with_dimension("host", "a"), 3);
+        let encoded = sender.encode(&count.entries());
+
+        let lines = encoded.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(r#"{"index":{"_index":"metriqs-2017.07","_id":""#));
+        assert_eq!(lines[1], r#"{"@timestamp":"2017-07-14T02:40:00.123Z","name":"requests","dimensions":{"host":"a"},"kind":"count","value":3}"#);
+        // The same point always gets the same id, whichever the build.
+        assert_eq!(sender.encode(&count.entries()), encoded);
+        assert_eq!(document_id(&count.entries()[0].0, time), 4408689699174364322);
+    }
+
+    #[test]
+    fn it_retries_rejected_documents() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut bodies = vec![];
+            for response in &[r#"{"errors":true,"items":[{"index":{"status":429}}]}"#, r#"{"errors":false,"items":[]}"#] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim_end().is_empty() {
+                        break
+                    }
+                    if let Some(value) = header.strip_prefix("Content-Length: ") {
+                        length = value.trim_end().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(body);
+                write!(reader.get_mut(), "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", response.len(), response).unwrap();
+            }
+            bodies
+        });
+
+        let (_, subscription) = channel();
+        let options = ElasticsearchOptions { retry_backoff: Some(Duration::from_millis(1)), ..ElasticsearchOptions::default() };
+        let sender = ElasticsearchSender::new(subscription, &addr, options).unwrap();
+        sender.write(&[AggregatedMetric::Gauge(UNIX_EPOCH, MetricId::from("queue"), 0.5)]).unwrap();
+
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies[0], bodies[1]);
+        assert!(String::from_utf8_lossy(&bodies[1]).contains(r#""_index":"metriqs-1970.01.01""#));
+    }
+}
@@ -0,0 +1,105 @@
+//! The extension point for backends this crate doesn't know about: anything
+//! that can take a batch of aggregated metrics implements `Exporter`, and an
+//! `ExportRunner` feeds it from a Db subscription the way the built-in
+//! senders feed themselves.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+
+use super::super::db::AggregatedMetric;
+use super::delivery::{self, Delivery, DeliveryOptions};
+
+pub trait Exporter {
+    /// Deliver a batch. Returning an error means none of it should be
+    /// considered delivered; the runner will retry it (see `Delivery`).
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()>;
+}
+
+impl<F> Exporter for F where F: FnMut(&[AggregatedMetric]) -> io::Result<()> {
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()> {
+        self(batch)
+    }
+}
+
+#[derive(Default)]
+pub struct ExporterOptions {
+    /// Largest batch passed to `export`; bigger aggregations are split.
+    /// Unlimited by default.
+    pub max_batch_size: Option<usize>,
+    /// How aggregations are buffered while the exporter is failing.
+    pub delivery: DeliveryOptions,
+}
+
+pub struct ExportRunner<E: Exporter> {
+    subscription: Receiver<Arc<Vec<AggregatedMetric>>>,
+    exporter: E,
+    max_batch_size: usize,
+    delivery: Delivery,
+}
+
+impl<E: Exporter> ExportRunner<E> {
+    pub fn new(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, exporter: E, options: ExporterOptions) -> ExportRunner<E> {
+        ExportRunner {
+            subscription,
+            exporter,
+            max_batch_size: options.max_batch_size.unwrap_or(usize::MAX).max(1),
+            delivery: Delivery::new(options.delivery),
+        }
+    }
+
+    /// Export until the Db drops the subscription; blocks the calling
+    /// thread. Aggregations that can't be exported are buffered and retried
+    /// with the next one.
+    pub fn run(&mut self) {
+        delivery::send(self, "exporter", |runner| &mut runner.delivery, |runner| runner.subscription.recv().ok(), |runner, batch| runner.export(batch))
+    }
+
+    /// Export one aggregation in batches of at most `max_batch_size`.
+    pub fn export(&mut self, metrics: &[AggregatedMetric]) -> io::Result<()> {
+        for batch in metrics.chunks(self.max_batch_size) {
+            self.exporter.export(batch)?;
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> E {
+        self.exporter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::time::UNIX_EPOCH;
+
+    use super::super::super::metric::MetricId;
+
+    #[test]
+    fn it_runs_exporters_in_batches() {
+        let (publish, subscription) = channel();
+        let mut batches = vec![];
+        let mut failed = false;
+        let exporter = |batch: &[AggregatedMetric]| {
+            if !failed {
+                failed = true;
+                return Err(io::Error::other("first export fails"))
+            }
+            batches.push(batch.len());
+            Ok(())
+        };
+        let options = ExporterOptions { max_batch_size: Some(2), ..ExporterOptions::default() };
+        let mut runner = ExportRunner::new(subscription, exporter, options);
+
+        let metrics = (0..3).map(|index| AggregatedMetric::Count(UNIX_EPOCH, MetricId::from(format!("m{}", index)), 1)).collect::<Vec<_>>();
+        publish.send(Arc::new(metrics.clone())).unwrap();
+        publish.send(Arc::new(metrics)).unwrap();
+        drop(publish);
+        runner.run();
+        drop(runner);
+
+        assert_eq!(batches, vec![2, 1, 2, 1]);
+    }
+}
@@ -0,0 +1,235 @@
+//! Sends one subscription's aggregations to several exporters, each with
+//! its own selection of metrics and its own dimension rewrites, eg.
+//! everything to Kafka but only `service.*` without the `pod` dimension to
+//! Datadog. Every sink buffers its own undelivered aggregations and, when
+//! run, delivers them on a thread of its own, so one that's down or slow
+//! doesn't hold up the others.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, Receiver, TrySendError};
+use std::thread;
+
+use string_cache::DefaultAtom as Atom;
+
+use super::super::db::{AggregatedMetric, SubscriptionFilter};
+use super::super::util::Glob;
+use super::delivery::{Delivery, DeliveryOptions};
+use super::exporter::Exporter;
+
+/// Applied to the identifiers of the metrics a sink receives, in order.
+#[derive(Clone, Debug)]
+pub enum DimensionRewrite {
+    /// Move the value of the first key to the second.
+    Rename(Atom, Atom),
+    Remove(Atom),
+    /// Add or overwrite a dimension.
+    Set(Atom, Atom),
+}
+
+#[derive(Default)]
+pub struct SinkOptions {
+    /// Which metrics the sink gets; everything by default.
+    pub include: SubscriptionFilter,
+    /// Names of metrics the sink doesn't get even if they're included.
+    pub exclude: Vec<Glob>,
+    pub rewrites: Vec<DimensionRewrite>,
+    pub delivery: DeliveryOptions,
+    /// Aggregations waiting for the sink's thread while it's delivering;
+    /// defaults to 4. Further ones are dropped until it catches up.
+    pub queue: Option<usize>,
+}
+
+struct Sink {
+    name: String,
+    exporter: Box<dyn Exporter + Send>,
+    include: SubscriptionFilter,
+    exclude: Vec<Glob>,
+    rewrites: Vec<DimensionRewrite>,
+    delivery: Delivery,
+    queue: usize,
+}
+
+impl Sink {
+    fn export(&mut self, metrics: &[AggregatedMetric]) -> io::Result<()> {
+        let selected = self.select(metrics);
+        let exporter = &mut self.exporter;
+        self.delivery.deliver(Arc::new(selected), |batch| exporter.export(batch))
+    }
+
+    fn select(&self, metrics: &[AggregatedMetric]) -> Vec<AggregatedMetric> {
+        metrics.iter()
+            .filter(|metric| {
+                let id = metric.id();
+                self.include.matches(id) && !self.exclude.iter().any(|pattern| pattern.matches(id.name()))
+            })
+            .map(|metric| {
+                let mut metric = metric.clone();
+                for rewrite in &self.rewrites {
+                    let id = metric.id_mut();
+                    *id = match *rewrite {
+                        DimensionRewrite::Rename(ref from, ref to) => match id.dimension(from).cloned() {
+                            Some(value) => id.without_dimension(from).with_dimension(to.clone(), value),
+                            None => continue,
+                        },
+                        DimensionRewrite::Remove(ref key) => id.without_dimension(key),
+                        DimensionRewrite::Set(ref key, ref value) => id.with_dimension(key.clone(), value.clone()),
+                    };
+                }
+                metric
+            })
+            .collect()
+    }
+}
+
+pub struct FanOut {
+    subscription: Receiver<Arc<Vec<AggregatedMetric>>>,
+    sinks: Vec<Sink>,
+}
+
+impl FanOut {
+    pub fn new(subscription: Receiver<Arc<Vec<AggregatedMetric>>>) -> FanOut {
+        FanOut {
+            subscription,
+            sinks: vec![],
+        }
+    }
+
+    /// Add a sink; `name` identifies it in error messages.
+    pub fn sink<E: Exporter + Send + 'static>(mut self, name: &str, exporter: E, options: SinkOptions) -> FanOut {
+        self.sinks.push(Sink {
+            name: name.to_string(),
+            exporter: Box::new(exporter),
+            include: options.include,
+            exclude: options.exclude,
+            rewrites: options.rewrites,
+            delivery: Delivery::new(options.delivery),
+            queue: options.queue.unwrap_or(4),
+        });
+        self
+    }
+
+    /// Fan out until the Db drops the subscription, with a thread per sink;
+    /// blocks the calling thread until every sink has delivered what it was
+    /// handed.
+    pub fn run(&mut self) {
+        let (subscription, sinks) = (&self.subscription, &mut self.sinks);
+        thread::scope(|scope| {
+            let queues = sinks.iter_mut()
+                .map(|sink| {
+                    let (send, recv) = sync_channel::<Arc<Vec<AggregatedMetric>>>(sink.queue);
+                    let name = sink.name.clone();
+                    scope.spawn(move || {
+                        for metrics in recv {
+                            if let Err(err) = sink.export(&metrics) {
+                                error!("Error exporting to {}: {}", sink.name, err)
+                            }
+                        }
+                    });
+                    (name, send)
+                })
+                .collect::<Vec<_>>();
+            while let Ok(metrics) = subscription.recv() {
+                for (name, send) in &queues {
+                    if let Err(TrySendError::Full(_)) = send.try_send(metrics.clone()) {
+                        warn!("Dropping an aggregation for {}, which can't keep up", name)
+                    }
+                }
+            }
+        })
+    }
+
+    /// Give every sink its share of one aggregation on the calling thread,
+    /// one after another. Returns the sinks that failed and why; what they
+    /// didn't get is retried on the next call.
+    pub fn export(&mut self, metrics: &[AggregatedMetric]) -> Vec<(String, io::Error)> {
+        let mut errors = vec![];
+        for sink in &mut self.sinks {
+            if let Err(err) = sink.export(metrics) {
+                errors.push((sink.name.clone(), err));
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::super::super::metric::MetricId;
+
+    #[test]
+    fn it_filters_and_rewrites_per_sink() {
+        let everything = Arc::new(Mutex::new(vec![]));
+        let services = Arc::new(Mutex::new(vec![]));
+        let (everything_exporter, services_exporter) = (everything.clone(), services.clone());
+
+        let (_, subscription) = channel();
+        let options = SinkOptions {
+            include: SubscriptionFilter { names: vec![Glob::new("service.*")], ..SubscriptionFilter::default() },
+            exclude: vec![Glob::new("service.debug.*")],
+            rewrites: vec![
+                DimensionRewrite::Remove("pod".into()),
+                DimensionRewrite::Rename("host".into(), "instance".into()),
+                DimensionRewrite::Set("team".into(), "web".into()),
+            ],
+            ..SinkOptions::default()
+        };
+        let mut fanout = FanOut::new(subscription)
+            .sink("kafka", move |batch: &[AggregatedMetric]| {
+                everything_exporter.lock().unwrap().extend_from_slice(batch);
+                Ok(())
+            }, SinkOptions::default())
+            .sink("datadog", move |batch: &[AggregatedMetric]| {
+                services_exporter.lock().unwrap().extend_from_slice(batch);
+                Ok(())
+            }, options);
+
+        let id = MetricId::from("service.requests").with_dimension("pod", "a-1").with_dimension("host", "a");
+        let metrics = vec![
+            AggregatedMetric::Count(UNIX_EPOCH, id, 1),
+            AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("service.debug.queue"), 1),
+            AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("db.queries"), 1),
+        ];
+        assert!(fanout.export(&metrics).is_empty());
+
+        assert_eq!(*everything.lock().unwrap(), metrics);
+        let expected = MetricId::from("service.requests").with_dimension("instance", "a").with_dimension("team", "web");
+        assert_eq!(*services.lock().unwrap(), vec![AggregatedMetric::Count(UNIX_EPOCH, expected, 1)]);
+    }
+
+    #[test]
+    fn it_runs_sinks_on_their_own_threads() {
+        let (release, released) = channel::<()>();
+        let released = Mutex::new(released);
+        let (delivered, fast) = channel();
+        let (publish, subscription) = channel();
+        let mut fanout = FanOut::new(subscription)
+            .sink("stuck", move |_: &[AggregatedMetric]| {
+                released.lock().unwrap().recv().unwrap();
+                Ok(())
+            }, SinkOptions::default())
+            .sink("fast", move |batch: &[AggregatedMetric]| {
+                delivered.send(batch.len()).unwrap();
+                Ok(())
+            }, SinkOptions::default());
+        let runner = thread::spawn(move || fanout.run());
+
+        let batch = Arc::new(vec![AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("requests"), 1)]);
+        publish.send(batch.clone()).unwrap();
+        publish.send(batch).unwrap();
+        // Both reach the fast sink while the stuck one is on the first.
+        assert_eq!(fast.recv_timeout(Duration::from_secs(5)), Ok(1));
+        assert_eq!(fast.recv_timeout(Duration::from_secs(5)), Ok(1));
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        drop(publish);
+        runner.join().unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+//! Appends aggregated metrics to a newline-delimited JSON file (see
+//! `send::json`) for environments where metrics are shipped in batches.
+//! The file is rotated by size and/or age: the current file is renamed with
+//! a millisecond timestamp suffix, eg. `metrics.jsonl.1500000000000`, and
+//! with the `flate2` feature may then be gzipped to `….gz`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "flate2")]
+use flate2::Compression;
+#[cfg(feature = "flate2")]
+use flate2::write::GzEncoder;
+
+use super::super::db::AggregatedMetric;
+use super::exporter::Exporter;
+use super::json;
+
+#[derive(Default)]
+pub struct FileOptions {
+    /// Rotate once the file is at least this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Rotate once the file has been written to for this long.
+    pub max_age: Option<Duration>,
+    /// Gzip rotated files; defaults to false.
+    #[cfg(feature = "flate2")]
+    pub gzip: Option<bool>,
+}
+
+struct Current {
+    file: File,
+    bytes: u64,
+    opened: SystemTime,
+}
+
+pub struct FileSender {
+    subscription: Receiver<Arc<Vec<AggregatedMetric>>>,
+    path: PathBuf,
+    options: FileOptions,
+    current: Option<Current>,
+}
+
+impl FileSender {
+    pub fn new<P: AsRef<Path>>(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, path: P, options: FileOptions) -> FileSender {
+        FileSender {
+            subscription,
+            path: path.as_ref().to_path_buf(),
+            options,
+            current: None,
+        }
+    }
+
+    /// Write until the Db drops the subscription; blocks the calling thread.
+    /// An aggregation that can't be written is dropped.
+    pub fn send(&mut self) {
+        while let Ok(metrics) = self.subscription.recv() {
+            if let Err(err) = self.write(&metrics) {
+                error!("Error writing metrics to {}: {}", self.path.display(), err)
+            }
+        }
+    }
+
+    /// Append one aggregation, rotating first if the file is due.
+    pub fn write(&mut self, metrics: &[AggregatedMetric]) -> io::Result<()> {
+        if metrics.is_empty() {
+            return Ok(())
+        }
+        if self.is_due(SystemTime::now()) {
+            self.rotate()?;
+        }
+        if self.current.is_none() {
+            self.current = Some(self.open()?);
+        }
+
+        let mut lines = String::new();
+        for metric in metrics {
+            lines.push_str(&json::metric(metric));
+            lines.push('\n');
+        }
+        let current = self.current.as_mut().unwrap();
+        current.file.write_all(lines.as_bytes())?;
+        current.file.flush()?;
+        current.bytes += lines.len() as u64;
+        Ok(())
+    }
+
+    fn is_due(&self, now: SystemTime) -> bool {
+        let current = match self.current {
+            Some(ref current) => current,
+            None => return false,
+        };
+        let too_big = self.options.max_bytes.is_some_and(|max| current.bytes >= max);
+        let too_old = self.options.max_age.is_some_and(|max| now.duration_since(current.opened).unwrap_or_default() >= max);
+        too_big || too_old
+    }
+
+    fn open(&self) -> io::Result<Current> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let bytes = file.metadata()?.len();
+        Ok(Current {
+            file,
+            bytes,
+            opened: SystemTime::now(),
+        })
+    }
+
+    /// Close the current file and move it aside; the next write opens a new
+    /// one.
+    pub fn rotate(&mut self) -> io::Result<()> {
+        self.current = None;
+        if !self.path.exists() {
+            return Ok(())
+        }
+
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", millis));
+        let rotated = PathBuf::from(rotated);
+        fs::rename(&self.path, &rotated)?;
+
+        #[cfg(feature = "flate2")]
+        {
+            if self.options.gzip.unwrap_or(false) {
+                gzip(&rotated)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Exporter for FileSender {
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()> {
+        self.write(batch)
+    }
+}
+
+#[cfg(feature = "flate2")]
+fn gzip(path: &Path) -> io::Result<()> {
+    let mut compressed = path.to_path_buf().into_os_string();
+    compressed.push(".gz");
+    let mut encoder = GzEncoder::new(File::create(&compressed)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+
+    use super::super::super::metric::MetricId;
+
+    #[test]
+    fn it_rotates_by_size() {
+        let directory = std::env::temp_dir().join(format!("metriqs-file-sink-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("metrics.jsonl");
+
+        let (_, subscription) = channel();
+        let options = FileOptions { max_bytes: Some(10), ..FileOptions::default() };
+        let mut sender = FileSender::new(subscription, &path, options);
+        let metrics = [AggregatedMetric::Gauge(UNIX_EPOCH, MetricId::from("a"), 1.0)];
+        sender.write(&metrics).unwrap();
+        sender.write(&metrics).unwrap();
+
+        let mut files = fs::read_dir(&directory).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<String>>();
+        files.sort();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0], "metrics.jsonl");
+        assert!(files[1].starts_with("metrics.jsonl."));
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", json::metric(&metrics[0])));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
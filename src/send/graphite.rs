@@ -0,0 +1,161 @@
+//! Writes aggregated metrics to Carbon in the Graphite plaintext protocol:
+//! one `name value timestamp` line per point.
+
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::super::db::AggregatedMetric;
+use super::super::metric::MetricId;
+use super::delivery::{BufferedSender, Delivery, DeliveryOptions};
+use super::exporter::Exporter;
+use super::sanitize::Sanitizer;
+use super::tcp::ReconnectingStream;
+
+#[derive(Default)]
+pub struct GraphiteOptions {
+    /// How names are built from identifiers, eg. `servers.{host}.{name}`.
+    /// `{name}` is the metric's name and any other placeholder is the value
+    /// of that dimension, or `_` when it's missing. Dimensions that aren't
+    /// in the template are appended as Graphite tags (`;key=value`). If
+    /// unset every dimension is a tag.
+    pub template: Option<String>,
+    /// How long to wait when connecting to Carbon; defaults to 5 seconds.
+    pub connect_timeout: Option<Duration>,
+    /// How aggregations are buffered while Carbon is unreachable.
+    pub delivery: DeliveryOptions,
+}
+
+/// Sends each aggregation received from a subscription to a Carbon
+/// endpoint over TCP, reconnecting whenever the connection is lost.
+pub struct GraphiteSender {
+    subscription: Receiver<Arc<Vec<AggregatedMetric>>>,
+    template: Option<String>,
+    stream: ReconnectingStream,
+    delivery: Delivery,
+}
+
+impl GraphiteSender {
+    pub fn new<A: ToSocketAddrs>(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, addr: A, options: GraphiteOptions) -> Result<GraphiteSender, io::Error> {
+        let addr = super::resolve(addr)?;
+
+        Ok(GraphiteSender {
+            subscription,
+            template: options.template,
+            stream: ReconnectingStream::new(addr, options.connect_timeout.unwrap_or_else(|| Duration::from_secs(5))),
+            delivery: Delivery::new(options.delivery),
+        })
+    }
+
+    /// Write one aggregation, connecting first if needed. If the write
+    /// fails on an existing connection it's retried once on a new one.
+    pub fn write(&mut self, metrics: &[AggregatedMetric]) -> io::Result<()> {
+        let lines = self.format(metrics);
+        if lines.is_empty() {
+            return Ok(())
+        }
+
+        self.stream.write(lines.as_bytes())
+    }
+
+    /// Render the plaintext lines for an aggregation.
+    pub fn format(&self, metrics: &[AggregatedMetric]) -> String {
+        let sanitizer = Sanitizer::graphite();
+        let mut lines = String::new();
+        for metric in metrics {
+            for (key, (time, value)) in metric.entries() {
+                let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                let name = self.name(&sanitizer.id(key.id()));
+                lines.push_str(&format!("{} {} {}\n", name, value, seconds));
+            }
+        }
+        lines
+    }
+
+    fn name(&self, id: &MetricId) -> String {
+        let mut name = String::new();
+        let mut templated = vec![];
+        match self.template {
+            Some(ref template) => {
+                let mut rest = template.as_str();
+                while let Some(start) = rest.find('{') {
+                    let end = match rest[start..].find('}') {
+                        Some(end) => start + end,
+                        None => break,
+                    };
+                    name.push_str(&rest[..start]);
+                    let placeholder = &rest[start + 1..end];
+                    if placeholder == "name" {
+                        name.push_str(id.name())
+                    } else {
+                        name.push_str(id.dimension(placeholder).map(|value| &**value).unwrap_or("_"));
+                        templated.push(placeholder);
+                    }
+                    rest = &rest[end + 1..];
+                }
+                name.push_str(rest);
+            },
+            None => name.push_str(id.name()),
+        }
+
+        for (key, value) in id.dimensions() {
+            if !templated.contains(&&**key) {
+                name.push_str(&format!(";{}={}", key, value));
+            }
+        }
+        name
+    }
+}
+
+impl Exporter for GraphiteSender {
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()> {
+        self.write(batch)
+    }
+}
+
+impl BufferedSender for GraphiteSender {
+    const BACKEND: &'static str = "Graphite";
+
+    fn subscription(&self) -> &Receiver<Arc<Vec<AggregatedMetric>>> {
+        &self.subscription
+    }
+
+    fn delivery(&mut self) -> &mut Delivery {
+        &mut self.delivery
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn it_templates_names() {
+        let (_, subscription) = channel();
+        let options = GraphiteOptions { template: Some("servers.{host}.{dc}.{name}".to_string()), ..GraphiteOptions::default() };
+        let sender = GraphiteSender::new(subscription, "127.0.0.1:2003", options).unwrap();
+
+        let id = MetricId::from("cpu usage").with_dimension("host", "a").with_dimension("core", "0");
+        let lines = sender.format(&[AggregatedMetric::Gauge(UNIX_EPOCH + Duration::from_secs(10), id, 0.5)]);
+        assert_eq!(lines, "servers.a._.cpu_usage;core=0 0.5 10\n");
+    }
+
+    #[test]
+    fn it_writes_to_carbon() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (_, subscription) = channel();
+        let mut sender = GraphiteSender::new(subscription, listener.local_addr().unwrap(), GraphiteOptions::default()).unwrap();
+
+        sender.write(&[AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("requests").with_dimension("host", "a"), 3)]).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert_eq!(line, "requests;host=a 3 0\n");
+    }
+}
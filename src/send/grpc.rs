@@ -0,0 +1,286 @@
+//! A gRPC service streaming aggregations to other processes, so that a
+//! custom exporter can be written in any language against
+//! `proto/metriqs/v1/metrics.proto` instead of linking against the crate.
+//! `metriqs.v1.Subscriptions/Subscribe` streams a `MetricBatch` for every
+//! aggregation, with only the metrics its request's patterns match (see
+//! `SubscriptionFilter`).
+//!
+//! It's served over cleartext HTTP/2 (see `util::http2`), so clients need
+//! an insecure channel, and messages can't be compressed.
+//!
+//! At most `STREAM_BUFFER` aggregations wait for a stream; a client that
+//! falls further behind misses the oldest ones, and one that stops reading
+//! altogether is reset (see `http2::Stream::data`).
+
+use std::io;
+use std::net::TcpListener;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use string_cache::DefaultAtom as Atom;
+
+use super::super::db::{AggregatedMetric, Db, Overflow, SubscriptionBuffer, SubscriptionFilter};
+use super::super::util::{Glob, Stop};
+use super::super::util::http2::{self, Request, Stream};
+use super::super::util::protobuf::Reader;
+use super::protobuf;
+
+const SUBSCRIBE: &str = "/metriqs.v1.Subscriptions/Subscribe";
+
+/// How often streams waiting for the next aggregation check whether the
+/// client has gone or the server has been stopped.
+const STREAM_POLL: Duration = Duration::from_millis(100);
+
+/// Aggregations waiting for a stream to be sent.
+const STREAM_BUFFER: usize = 4;
+
+const OK: u32 = 0;
+const INVALID_ARGUMENT: u32 = 3;
+const UNIMPLEMENTED: u32 = 12;
+
+pub struct GrpcServer {
+    db: Arc<Db>,
+}
+
+impl GrpcServer {
+    pub fn new(db: Arc<Db>) -> GrpcServer {
+        GrpcServer { db }
+    }
+
+    /// Serve the API on `listener` until `stop` is stopped, which ends
+    /// every stream.
+    pub fn serve(self, listener: &TcpListener, stop: &Stop) -> io::Result<()> {
+        let (server, streams) = (Arc::new(self), stop.clone());
+        http2::serve(listener, stop, move |request, stream| {
+            if let Err(err) = server.handle(&request, &stream, &streams) {
+                debug!("Error streaming to a gRPC client: {}", err)
+            }
+        })
+    }
+
+    fn handle(&self, request: &Request, stream: &Stream, stop: &Stop) -> io::Result<()> {
+        if !request.header("content-type").is_some_and(|content_type| content_type.starts_with("application/grpc")) {
+            return stream.headers(&[(":status", "415")], true)
+        }
+        if request.method != "POST" || request.path != SUBSCRIBE {
+            return finish(stream, UNIMPLEMENTED, "unknown method", true)
+        }
+        let filter = match message(&request.body).and_then(subscribe_request) {
+            Ok(filter) => filter,
+            Err(err) => return finish(stream, INVALID_ARGUMENT, &err.to_string(), true),
+        };
+
+        let buffer = SubscriptionBuffer { capacity: STREAM_BUFFER, overflow: Overflow::DropOldest };
+        let (token, subscription) = self.db.subscribe_buffered(Some(filter), buffer);
+        let result = self.stream(&subscription, stream, stop);
+        self.db.unsubscribe(token);
+        result
+    }
+
+    fn stream(&self, subscription: &Receiver<Arc<Vec<AggregatedMetric>>>, stream: &Stream, stop: &Stop) -> io::Result<()> {
+        stream.headers(&[(":status", "200"), ("content-type", "application/grpc")], false)?;
+        loop {
+            match subscription.recv_timeout(STREAM_POLL) {
+                Ok(metrics) => stream.data(&framed(&protobuf::batch(&metrics)), false)?,
+                Err(RecvTimeoutError::Timeout) if !stop.is_stopped() && !stream.is_closed() => {},
+                // Drained on shutdown, or cancelled by the client.
+                _ => break,
+            }
+        }
+        finish(stream, OK, "", false)
+    }
+}
+
+/// End the stream with trailers, or with a response that's only trailers
+/// if nothing has been sent yet.
+fn finish(stream: &Stream, status: u32, message: &str, trailers_only: bool) -> io::Result<()> {
+    if stream.is_closed() {
+        return Ok(())
+    }
+    let status = status.to_string();
+    let mut headers = vec![];
+    if trailers_only {
+        headers.extend_from_slice(&[(":status", "200"), ("content-type", "application/grpc")]);
+    }
+    headers.push(("grpc-status", status.as_str()));
+    if !message.is_empty() {
+        headers.push(("grpc-message", message));
+    }
+    stream.headers(&headers, true)
+}
+
+/// Prefix a message with gRPC's framing: whether it's compressed, and its
+/// length.
+fn framed(message: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(5 + message.len());
+    framed.push(0);
+    framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    framed.extend_from_slice(message);
+    framed
+}
+
+/// The one message of a unary request's body.
+fn message(body: &[u8]) -> io::Result<&[u8]> {
+    if body.len() < 5 {
+        return Err(invalid("request is missing its message"))
+    }
+    if body[0] != 0 {
+        return Err(invalid("compressed messages aren't supported"))
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    body.get(5..5 + len).ok_or_else(|| invalid("request message is truncated"))
+}
+
+fn subscribe_request(message: &[u8]) -> io::Result<SubscriptionFilter> {
+    let mut filter = SubscriptionFilter::default();
+    let mut reader = Reader::new(message);
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => filter.names.push(Glob::new(value.string()?)),
+            2 => {
+                let (mut key, mut pattern) = ("", "");
+                let mut reader = Reader::new(value.bytes()?);
+                while let Some((field, value)) = reader.field()? {
+                    match field {
+                        1 => key = value.string()?,
+                        2 => pattern = value.string()?,
+                        _ => {},
+                    }
+                }
+                filter.dimensions.push((Atom::from(key), Glob::new(pattern)));
+            },
+            _ => {},
+        }
+    }
+    Ok(filter)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::SystemTime;
+
+    use super::super::super::db::DbOptions;
+    use super::super::super::metric::{CollectedMetric, MetricId};
+    use super::super::super::util::{hpack, protobuf as wire};
+
+    fn frame(stream: &mut TcpStream, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        stream.write_all(&frame).unwrap();
+    }
+
+    /// The next frame on stream 1 that isn't flow control or settings.
+    fn next_frame(stream: &mut TcpStream) -> (u8, u8, Vec<u8>) {
+        loop {
+            let mut header = [0; 9];
+            stream.read_exact(&mut header).unwrap();
+            let mut payload = vec![0; (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize];
+            stream.read_exact(&mut payload).unwrap();
+            if header[8] == 1 && header[3] != 0x8 {
+                return (header[3], header[4], payload)
+            }
+        }
+    }
+
+    #[test]
+    fn it_streams_filtered_aggregations() {
+        let db = Arc::new(Db::new(DbOptions::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stop = Stop::new();
+        let server = {
+            let (server, stop) = (GrpcServer::new(db.clone()), stop.clone());
+            thread::spawn(move || server.serve(&listener, &stop))
+        };
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").unwrap();
+        frame(&mut client, 0x4, 0, 0, &[]);
+        let mut headers = vec![];
+        for &(name, value) in &[(":method", "POST"), (":scheme", "http"), (":path", SUBSCRIBE), ("content-type", "application/grpc")] {
+            hpack::encode(&mut headers, name, value);
+        }
+        frame(&mut client, 0x1, 0x4, 1, &headers);
+        let mut request = vec![];
+        wire::bytes_field(&mut request, 1, b"api.*");
+        frame(&mut client, 0x0, 0x1, 1, &framed(&request));
+
+        let (kind, _, _) = next_frame(&mut client);
+        assert_eq!(kind, 0x1);
+        let time = SystemTime::now();
+        db.collect(vec![
+            CollectedMetric::Count(time, MetricId::from("api.requests"), 3),
+            CollectedMetric::Count(time, MetricId::from("db.queries"), 1),
+        ]);
+        db.flush();
+        let (kind, _, data) = next_frame(&mut client);
+        assert_eq!(kind, 0x0);
+        let metrics = protobuf::decode_batch(message(&data).unwrap()).unwrap();
+        assert!(metrics.iter().all(|metric| metric.id().name().starts_with("api.")));
+        assert!(metrics.iter().any(|metric| match *metric {
+            AggregatedMetric::Count(_, ref id, 3) => *id == MetricId::from("api.requests"),
+            _ => false,
+        }));
+
+        stop.stop();
+        let (kind, flags, trailers) = next_frame(&mut client);
+        assert_eq!((kind, flags & 0x1), (0x1, 0x1));
+        assert_eq!(hpack::Decoder::new().decode(&trailers).unwrap(), vec![("grpc-status".to_string(), "0".to_string())]);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn it_refuses_streams_over_the_limit() {
+        let db = Arc::new(Db::new(DbOptions::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stop = Stop::new();
+        let server = {
+            let (server, stop) = (GrpcServer::new(db), stop.clone());
+            thread::spawn(move || server.serve(&listener, &stop))
+        };
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").unwrap();
+        frame(&mut client, 0x4, 0, 0, &[]);
+        let mut headers = vec![];
+        for &(name, value) in &[(":method", "POST"), (":scheme", "http"), (":path", SUBSCRIBE), ("content-type", "application/grpc")] {
+            hpack::encode(&mut headers, name, value);
+        }
+        // Requests whose bodies haven't arrived yet count towards the limit.
+        let refused = 2 * http2::MAX_STREAMS as u32 + 1;
+        for id in (1..=refused).step_by(2) {
+            frame(&mut client, 0x1, 0x4, id, &headers);
+        }
+
+        loop {
+            let mut header = [0; 9];
+            client.read_exact(&mut header).unwrap();
+            let mut payload = vec![0; (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize];
+            client.read_exact(&mut payload).unwrap();
+            if header[3] == 0x4 && header[4] == 0 {
+                assert_eq!(payload, [0, 0x3, 0, 0, 0, http2::MAX_STREAMS as u8]);
+            }
+            if header[3] == 0x3 {
+                assert_eq!(u32::from_be_bytes([header[5], header[6], header[7], header[8]]), refused);
+                assert_eq!(payload, 0x7u32.to_be_bytes());
+                break
+            }
+        }
+
+        stop.stop();
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+//! The JSON representation of an aggregated metric shared by the senders
+//! that write JSON, and a sender that writes it as JSON lines, eg.
+//!
+//! ```text
+//! {"time":1500000000000,"name":"api.requests","dimensions":{"host":"a"},"type":"count","value":3}
+//! ```
+//!
+//! `time` is milliseconds since the epoch. Summaries and sketches have
+//! `count`, `sum`, and `quantiles` (keyed by quantile) instead of `value`;
+//! sketches also have `min` and `max`.
+
+use std::io::{self, Stdout, Write};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::UNIX_EPOCH;
+
+use super::super::db::AggregatedMetric;
+use super::super::util::json;
+use super::SKETCH_QUANTILES;
+use super::exporter::Exporter;
+
+pub fn metric(metric: &AggregatedMetric) -> String {
+    let (time, id) = match *metric {
+        AggregatedMetric::Count(time, ref id, _) |
+        AggregatedMetric::Gauge(time, ref id, _) |
+        AggregatedMetric::Summary(time, ref id, _) |
+        AggregatedMetric::Sketch(time, ref id, _) => (time, id),
+    };
+    let dimensions = id.dimensions().iter()
+        .map(|(key, value)| format!("{}:{}", json::string(key), json::string(value)))
+        .collect::<Vec<String>>();
+    let fields = match *metric {
+        AggregatedMetric::Count(_, _, value) => format!(r#""type":"count","value":{}"#, value),
+        AggregatedMetric::Gauge(_, _, value) => format!(r#""type":"gauge","value":{}"#, json::number(value)),
+        AggregatedMetric::Summary(_, _, ref summary) => format!(
+            r#""type":"summary","count":{},"sum":{},"quantiles":{}"#,
+            summary.count,
+            json::number(summary.sum),
+            quantiles(&summary.quantiles),
+        ),
+        AggregatedMetric::Sketch(_, _, ref sketch) => {
+            let values = SKETCH_QUANTILES.iter()
+                .filter_map(|&quantile| sketch.quantile(quantile).map(|value| (quantile, value)))
+                .collect::<Vec<(f64, f64)>>();
+            format!(
+                r#""type":"sketch","count":{},"sum":{},"min":{},"max":{},"quantiles":{}"#,
+                sketch.count(),
+                json::number(sketch.sum()),
+                sketch.min().map(json::number).unwrap_or_else(|| "null".to_string()),
+                sketch.max().map(json::number).unwrap_or_else(|| "null".to_string()),
+                quantiles(&values),
+            )
+        },
+    };
+    let millis = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    format!(
+        r#"{{"time":{},"name":{},"dimensions":{{{}}},{}}}"#,
+        millis,
+        json::string(id.name()),
+        dimensions.join(","),
+        fields,
+    )
+}
+
+/// Writes one JSON object per aggregated metric per line, eg. to pipe the
+/// agent's output into `jq` while debugging a pipeline.
+pub struct JsonLinesSender<W: Write> {
+    subscription: Receiver<Arc<Vec<AggregatedMetric>>>,
+    writer: W,
+}
+
+impl JsonLinesSender<Stdout> {
+    pub fn stdout(subscription: Receiver<Arc<Vec<AggregatedMetric>>>) -> JsonLinesSender<Stdout> {
+        JsonLinesSender::new(subscription, io::stdout())
+    }
+}
+
+impl<W: Write> JsonLinesSender<W> {
+    pub fn new(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, writer: W) -> JsonLinesSender<W> {
+        JsonLinesSender {
+            subscription,
+            writer,
+        }
+    }
+
+    /// Write until the Db drops the subscription; blocks the calling thread.
+    pub fn send(&mut self) -> io::Result<()> {
+        while let Ok(metrics) = self.subscription.recv() {
+            self.write(&metrics)?
+        }
+        Ok(())
+    }
+
+    /// Write one aggregation and flush.
+    pub fn write(&mut self, metrics: &[AggregatedMetric]) -> io::Result<()> {
+        for aggregated in metrics {
+            writeln!(self.writer, "{}", metric(aggregated))?;
+        }
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Exporter for JsonLinesSender<W> {
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()> {
+        self.write(batch)
+    }
+}
+
+fn quantiles(quantiles: &[(f64, f64)]) -> String {
+    let quantiles = quantiles.iter()
+        .map(|&(quantile, value)| format!("{}:{}", json::string(&quantile.to_string()), json::number(value)))
+        .collect::<Vec<String>>();
+    format!("{{{}}}", quantiles.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    use super::super::super::metric::{MetricId, Summary};
+
+    #[test]
+    fn it_encodes_metrics() {
+        let time = UNIX_EPOCH + Duration::from_millis(1500);
+        let id = MetricId::from("api.requests").with_dimension("host", "a");
+        assert_eq!(
+            metric(&AggregatedMetric::Count(time, id.clone(), 3)),
+            r#"{"time":1500,"name":"api.requests","dimensions":{"host":"a"},"type":"count","value":3}"#
+        );
+        let summary = Summary { count: 2, sum: 3.0, quantiles: vec![(0.5, 1.5)] };
+        assert_eq!(
+            metric(&AggregatedMetric::Summary(time, MetricId::from("latency"), summary)),
+            r#"{"time":1500,"name":"latency","dimensions":{},"type":"summary","count":2,"sum":3,"quantiles":{"0.5":1.5}}"#
+        );
+    }
+
+    #[test]
+    fn it_writes_json_lines() {
+        let (send, subscription) = channel();
+        let mut sender = JsonLinesSender::new(subscription, vec![]);
+        send.send(Arc::new(vec![
+            AggregatedMetric::Gauge(UNIX_EPOCH, MetricId::from("a"), 1.0),
+            AggregatedMetric::Gauge(UNIX_EPOCH, MetricId::from("b"), f64::NAN),
+        ])).unwrap();
+        drop(send);
+
+        sender.send().unwrap();
+        assert_eq!(
+            String::from_utf8(sender.into_inner()).unwrap(),
+            concat!(
+                r#"{"time":0,"name":"a","dimensions":{},"type":"gauge","value":1}"#, "\n",
+                r#"{"time":0,"name":"b","dimensions":{},"type":"gauge","value":null}"#, "\n",
+            )
+        );
+    }
+}
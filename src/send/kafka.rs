@@ -0,0 +1,109 @@
+//! Publishes aggregated metrics to a Kafka topic, one message per metric
+//! keyed by its name so every series of a metric lands in the same
+//! partition. Messages are JSON (see `send::json`) or, for consumers that
+//! want a schema, protobuf `AggregatedMetric`s (see `send::protobuf`).
+
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use kafka::producer::{Producer, Record, RequiredAcks};
+
+use super::super::db::AggregatedMetric;
+use super::delivery::{BufferedSender, Delivery, DeliveryOptions};
+use super::exporter::Exporter;
+use super::{json, protobuf};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KafkaFormat {
+    Json,
+    Protobuf,
+}
+
+#[derive(Default)]
+pub struct KafkaOptions {
+    /// Defaults to JSON.
+    pub format: Option<KafkaFormat>,
+    /// Defaults to `metriqs`.
+    pub client_id: Option<String>,
+    /// How long brokers have to acknowledge a flush; defaults to 5 seconds.
+    pub ack_timeout: Option<Duration>,
+    /// How aggregations are buffered while the brokers are unreachable.
+    pub delivery: DeliveryOptions,
+}
+
+pub struct KafkaSender {
+    subscription: Receiver<Arc<Vec<AggregatedMetric>>>,
+    producer: Producer,
+    topic: String,
+    format: KafkaFormat,
+    delivery: Delivery,
+}
+
+impl KafkaSender {
+    /// Connects to `hosts` (eg. `kafka-1:9092`) to fetch the topic's
+    /// metadata.
+    pub fn new(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, hosts: Vec<String>, topic: &str, options: KafkaOptions) -> Result<KafkaSender, io::Error> {
+        let producer = Producer::from_hosts(hosts)
+            .with_client_id(options.client_id.unwrap_or_else(|| "metriqs".to_string()))
+            .with_ack_timeout(options.ack_timeout.unwrap_or_else(|| Duration::from_secs(5)))
+            .with_required_acks(RequiredAcks::One)
+            .create()
+            .map_err(io::Error::other)?;
+
+        Ok(KafkaSender {
+            subscription,
+            producer,
+            topic: topic.to_string(),
+            format: options.format.unwrap_or(KafkaFormat::Json),
+            delivery: Delivery::new(options.delivery),
+        })
+    }
+
+    /// Publish one flush as a single produce request.
+    pub fn write(&mut self, metrics: &[AggregatedMetric]) -> io::Result<()> {
+        if metrics.is_empty() {
+            return Ok(())
+        }
+
+        let topic = &self.topic;
+        let format = self.format;
+        let records = metrics.iter()
+            .map(|metric| {
+                let value = match format {
+                    KafkaFormat::Json => json::metric(metric).into_bytes(),
+                    KafkaFormat::Protobuf => protobuf::metric(metric),
+                };
+                Record::from_key_value(topic, metric.id().name().to_string(), value)
+            })
+            .collect::<Vec<Record<String, Vec<u8>>>>();
+        let confirms = self.producer.send_all(&records).map_err(io::Error::other)?;
+        for confirm in confirms {
+            for partition in confirm.partition_confirms {
+                if let Err(code) = partition.offset {
+                    return Err(io::Error::other(format!("Kafka rejected messages to partition {}: {:?}", partition.partition, code)))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Exporter for KafkaSender {
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()> {
+        self.write(batch)
+    }
+}
+
+impl BufferedSender for KafkaSender {
+    const BACKEND: &'static str = "Kafka";
+
+    fn subscription(&self) -> &Receiver<Arc<Vec<AggregatedMetric>>> {
+        &self.subscription
+    }
+
+    fn delivery(&mut self) -> &mut Delivery {
+        &mut self.delivery
+    }
+}
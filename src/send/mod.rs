@@ -0,0 +1,74 @@
+//! Senders are how aggregated metrics leave the agent for other backends.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use super::metric::MetricId;
+
+pub mod cloudwatch;
+pub mod delivery;
+pub mod elasticsearch;
+pub mod exporter;
+pub mod fanout;
+pub mod file;
+pub mod graphite;
+pub mod grpc;
+pub mod json;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod nats;
+pub mod otlp;
+pub mod postgres;
+pub mod prometheus;
+#[cfg(feature = "snap")]
+pub mod prometheus_remote_write;
+pub mod protobuf;
+pub mod sanitize;
+pub mod statsd;
+pub mod tcp;
+pub mod wavefront;
+
+pub use self::cloudwatch::{CloudWatchOptions, CloudWatchSender};
+#[cfg(feature = "sigv4")]
+pub use self::cloudwatch::AwsCredentials;
+pub use self::delivery::{BufferedSender, Delivery, DeliveryOptions};
+pub use self::elasticsearch::{ElasticsearchOptions, ElasticsearchSender, IndexPeriod};
+pub use self::exporter::{ExportRunner, Exporter, ExporterOptions};
+pub use self::fanout::{DimensionRewrite, FanOut, SinkOptions};
+pub use self::file::{FileOptions, FileSender};
+pub use self::graphite::{GraphiteOptions, GraphiteSender};
+pub use self::grpc::GrpcServer;
+pub use self::json::JsonLinesSender;
+#[cfg(feature = "kafka")]
+pub use self::kafka::{KafkaFormat, KafkaOptions, KafkaSender};
+pub use self::nats::{NatsFormat, NatsOptions, NatsSender};
+pub use self::otlp::{OtlpOptions, OtlpSender};
+pub use self::postgres::{PostgresOptions, PostgresSender};
+pub use self::prometheus::{Exposer, ExposerOptions};
+#[cfg(feature = "snap")]
+pub use self::prometheus_remote_write::{RemoteWriteOptions, RemoteWriteSender};
+pub use self::sanitize::Sanitizer;
+pub use self::statsd::{StatsdOptions, StatsdSender, StatsdTransport};
+pub use self::wavefront::{WavefrontOptions, WavefrontSender};
+
+/// Quantiles reported for sketches by senders that can't send whole ones.
+const SKETCH_QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// A histogram's cumulative bucket count (see
+/// `DbOptions::histogram_buckets`), as the histogram's identifier and the
+/// bucket's upper bound.
+fn bucket(id: &MetricId) -> Option<(MetricId, f64)> {
+    let name = id.name().strip_suffix(".bucket")?;
+    let bound = match &**id.dimension("le")? {
+        "+Inf" => f64::INFINITY,
+        bound => bound.parse().ok()?,
+    };
+    Some((id.with_name(name).without_dimension("le"), bound))
+}
+
+/// The first address `addr` resolves to.
+fn resolve<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to send to"))
+}
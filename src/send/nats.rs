@@ -0,0 +1,296 @@
+//! Publishes aggregated metrics to NATS, batched into a message per
+//! subject per aggregation. Subjects come from a template on each metric's
+//! name, eg. `metrics.{prefix}` publishes `api.requests` and `api.latency`
+//! together to `metrics.api`, so that consumers can subscribe to just the
+//! services they care about. Messages are JSON lines (see `send::json`)
+//! or protobuf `MetricBatch`es (see `send::protobuf`), split to fit the
+//! server's maximum payload.
+//!
+//! Each aggregation ends with a `PING`, and is only considered delivered
+//! once the server's `PONG` says it's processed everything before it.
+//! Connections are cleartext; TLS isn't supported.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use super::super::db::AggregatedMetric;
+use super::super::util::json as json_value;
+use super::super::util::protobuf as wire;
+use super::delivery::{BufferedSender, Delivery, DeliveryOptions};
+use super::exporter::Exporter;
+use super::{json, protobuf};
+
+/// What servers accept unless their `INFO` says otherwise.
+const DEFAULT_MAX_PAYLOAD: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NatsFormat {
+    Json,
+    Protobuf,
+}
+
+#[derive(Default)]
+pub struct NatsOptions {
+    /// Template for the subject each metric is published to, where
+    /// `{prefix}` is its name up to the first `.` and `{name}` all of it.
+    /// Defaults to `metriqs.{prefix}`.
+    pub subject: Option<String>,
+    /// Defaults to JSON.
+    pub format: Option<NatsFormat>,
+    /// Credentials for servers with authentication, either a token or a
+    /// user and password.
+    pub token: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// For connecting and waiting for the server to confirm each
+    /// aggregation; defaults to 5 seconds.
+    pub timeout: Option<Duration>,
+    /// How aggregations are buffered while the server is unreachable.
+    pub delivery: DeliveryOptions,
+}
+
+pub struct NatsSender {
+    subscription: Receiver<Arc<Vec<AggregatedMetric>>>,
+    addr: SocketAddr,
+    subject: String,
+    format: NatsFormat,
+    connect: String,
+    timeout: Duration,
+    /// Reused until it fails, then reopened by the next write.
+    connection: Option<Connection>,
+    delivery: Delivery,
+}
+
+impl NatsSender {
+    /// Connects lazily, so the server doesn't need to be up yet.
+    pub fn new(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, address: &str, options: NatsOptions) -> Result<NatsSender, io::Error> {
+        let addr = super::resolve(address)?;
+
+        let mut connect = vec![
+            r#""verbose":false"#.to_string(),
+            r#""pedantic":false"#.to_string(),
+            r#""name":"metriqs""#.to_string(),
+            r#""lang":"rust""#.to_string(),
+            format!(r#""version":{}"#, json_value::string(env!("CARGO_PKG_VERSION"))),
+        ];
+        let credentials = [("auth_token", options.token), ("user", options.user), ("pass", options.password)];
+        for (key, value) in credentials.iter() {
+            if let Some(ref value) = *value {
+                connect.push(format!("{}:{}", json_value::string(key), json_value::string(value)));
+            }
+        }
+
+        Ok(NatsSender {
+            subscription,
+            addr,
+            subject: options.subject.unwrap_or_else(|| "metriqs.{prefix}".to_string()),
+            format: options.format.unwrap_or(NatsFormat::Json),
+            connect: format!("CONNECT {{{}}}\r\n", connect.join(",")),
+            timeout: options.timeout.unwrap_or_else(|| Duration::from_secs(5)),
+            connection: None,
+            delivery: Delivery::new(options.delivery),
+        })
+    }
+
+    /// Publish one aggregation and wait for the server to confirm it.
+    pub fn write(&mut self, metrics: &[AggregatedMetric]) -> io::Result<()> {
+        if metrics.is_empty() {
+            return Ok(())
+        }
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => Connection::open(&self.addr, &self.connect, self.timeout)?,
+        };
+        let mut commands = vec![];
+        for (subject, payload) in self.messages(metrics, connection.max_payload) {
+            commands.extend_from_slice(format!("PUB {} {}\r\n", subject, payload.len()).as_bytes());
+            commands.extend_from_slice(&payload);
+            commands.extend_from_slice(b"\r\n");
+        }
+        commands.extend_from_slice(b"PING\r\n");
+        connection.stream.get_mut().write_all(&commands)?;
+        connection.confirm()?;
+        self.connection = Some(connection);
+        Ok(())
+    }
+
+    /// Subjects and payloads, each at most `max_payload` bytes unless a
+    /// single metric is bigger, in order of subject.
+    pub fn messages(&self, metrics: &[AggregatedMetric], max_payload: usize) -> Vec<(String, Vec<u8>)> {
+        let mut subjects: BTreeMap<String, Vec<&AggregatedMetric>> = BTreeMap::new();
+        for metric in metrics {
+            subjects.entry(subject(&self.subject, metric.id().name())).or_default().push(metric);
+        }
+
+        let mut messages = vec![];
+        for (subject, metrics) in subjects {
+            let mut payload = vec![];
+            for metric in metrics {
+                let mut encoded = vec![];
+                match self.format {
+                    NatsFormat::Json => {
+                        encoded.extend_from_slice(json::metric(metric).as_bytes());
+                        encoded.push(b'\n');
+                    },
+                    NatsFormat::Protobuf => wire::bytes_field(&mut encoded, 1, &protobuf::metric(metric)),
+                }
+                if !payload.is_empty() && payload.len() + encoded.len() > max_payload {
+                    messages.push((subject.clone(), mem::take(&mut payload)));
+                }
+                payload.extend(encoded);
+            }
+            messages.push((subject, payload));
+        }
+        messages
+    }
+}
+
+impl Exporter for NatsSender {
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()> {
+        self.write(batch)
+    }
+}
+
+impl BufferedSender for NatsSender {
+    const BACKEND: &'static str = "NATS";
+
+    fn subscription(&self) -> &Receiver<Arc<Vec<AggregatedMetric>>> {
+        &self.subscription
+    }
+
+    fn delivery(&mut self) -> &mut Delivery {
+        &mut self.delivery
+    }
+}
+
+/// Fill in `template` for a metric named `name`. Characters NATS gives
+/// meaning to in subjects (whitespace and the `*` and `>` wildcards) are
+/// replaced with underscores.
+fn subject(template: &str, name: &str) -> String {
+    let prefix = name.split('.').next().unwrap_or(name);
+    template.replace("{prefix}", prefix)
+        .replace("{name}", name)
+        .chars()
+        .map(|c| if c.is_whitespace() || c == '*' || c == '>' { '_' } else { c })
+        .collect()
+}
+
+struct Connection {
+    stream: BufReader<TcpStream>,
+    max_payload: usize,
+}
+
+impl Connection {
+    /// Connect and read the server's `INFO`, then introduce ourselves with
+    /// `connect`.
+    fn open(addr: &SocketAddr, connect: &str, timeout: Duration) -> io::Result<Connection> {
+        let stream = TcpStream::connect_timeout(addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let mut stream = BufReader::new(stream);
+
+        let mut info = String::new();
+        stream.read_line(&mut info)?;
+        if !info.starts_with("INFO ") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("expected INFO from the server, got {:?}", info.trim_end())))
+        }
+        let max_payload = info.split(r#""max_payload":"#)
+            .nth(1)
+            .and_then(|rest| rest.split(|c: char| !c.is_ascii_digit()).next())
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(DEFAULT_MAX_PAYLOAD);
+        stream.get_mut().write_all(connect.as_bytes())?;
+        Ok(Connection { stream, max_payload })
+    }
+
+    /// Read until the `PONG` for our `PING`, answering the server's own
+    /// `PING`s and failing on its errors.
+    fn confirm(&mut self) -> io::Result<()> {
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "NATS server closed the connection"))
+            }
+            let line = line.trim_end();
+            if line == "PONG" {
+                return Ok(())
+            } else if line == "PING" {
+                self.stream.get_mut().write_all(b"PONG\r\n")?;
+            } else if let Some(err) = line.strip_prefix("-ERR ") {
+                return Err(io::Error::other(format!("NATS server responded with {}", err)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+
+    use super::super::super::metric::MetricId;
+
+    #[test]
+    fn it_batches_metrics_by_subject() {
+        assert_eq!(subject("metrics.{prefix}", "api.requests"), "metrics.api");
+        assert_eq!(subject("metrics.{name}", "queue depth"), "metrics.queue_depth");
+
+        let (_, subscription) = channel();
+        let sender = NatsSender::new(subscription, "127.0.0.1:4222", NatsOptions::default()).unwrap();
+        let metrics = ["api.requests", "db.queries", "api.errors"].iter()
+            .map(|name| AggregatedMetric::Count(UNIX_EPOCH, MetricId::from(*name), 1))
+            .collect::<Vec<_>>();
+        let messages = sender.messages(&metrics, DEFAULT_MAX_PAYLOAD);
+        let subjects = messages.iter().map(|message| message.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(subjects, vec!["metriqs.api", "metriqs.db"]);
+        assert_eq!(messages[0].1, format!("{}\n{}\n", json::metric(&metrics[0]), json::metric(&metrics[2])).into_bytes());
+
+        // Payloads are split to fit, but never between a metric's bytes.
+        let split = sender.messages(&metrics, 1);
+        assert_eq!(split.len(), 3);
+    }
+
+    #[test]
+    fn it_publishes_and_waits_for_confirmation() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"INFO {\"server_id\":\"test\",\"max_payload\":1048576}\r\n").unwrap();
+            let mut received = vec![];
+            let mut buf = [0; 1024];
+            while !received.ends_with(b"PING\r\n") {
+                let len = stream.read(&mut buf).unwrap();
+                received.extend_from_slice(&buf[..len]);
+            }
+            // The server's own ping has to be answered before the pong.
+            stream.write_all(b"PING\r\nPONG\r\n").unwrap();
+            let mut pong = [0; 6];
+            stream.read_exact(&mut pong).unwrap();
+            (String::from_utf8(received).unwrap(), pong)
+        });
+
+        let (_, subscription) = channel();
+        let options = NatsOptions { token: Some("secret".to_string()), ..NatsOptions::default() };
+        let mut sender = NatsSender::new(subscription, &addr, options).unwrap();
+        let count = AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("api.requests"), 3);
+        sender.write(std::slice::from_ref(&count)).unwrap();
+
+        let (received, pong) = server.join().unwrap();
+        let payload = format!("{}\n", json::metric(&count));
+        assert!(received.starts_with("CONNECT {\"verbose\":false,"));
+        assert!(received.contains(r#""auth_token":"secret""#));
+        assert!(received.ends_with(&format!("PUB metriqs.api {}\r\n{}\r\nPING\r\n", payload.len(), payload)));
+        assert_eq!(&pong, b"PONG\r\n");
+    }
+}
@@ -0,0 +1,368 @@
+//! Exports aggregated metrics to an OpenTelemetry collector over OTLP/HTTP
+//! with the JSON encoding (`POST /v1/metrics`). Counts become delta
+//! monotonic sums, gauges become gauges, summaries become summaries, and
+//! sketches become explicit-bucket histograms with a bucket per bin.
+//!
+//! Histograms with cumulative buckets (see `DbOptions::histogram_buckets`)
+//! become explicit-bucket histograms too, from their `.bucket` counts,
+//! `.count`, `.sum`, `.min`, and `.max`; their other gauges, eg. `.median`
+//! and percentiles, stay gauges. Histograms without buckets are only their
+//! gauges and `.count`, since they have no buckets or sum to send.
+//!
+//! OTLP/gRPC isn't supported; collectors accept OTLP/HTTP on port 4318 by
+//! default.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{SocketAddr};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::super::db::{AggregatedMetric, DdSketch};
+use super::super::metric::MetricId;
+use super::super::util::{http, json};
+use super::delivery::{BufferedSender, Delivery, DeliveryOptions};
+use super::exporter::Exporter;
+use super::bucket;
+
+/// `AGGREGATION_TEMPORALITY_DELTA`: every aggregation covers only its own
+/// interval.
+const DELTA: u8 = 1;
+
+#[derive(Default)]
+pub struct OtlpOptions {
+    /// Defaults to `/v1/metrics`.
+    pub path: Option<String>,
+    /// Attributes of the resource every metric is reported under, eg.
+    /// `service.name` and `host.name`.
+    pub resource_attributes: Vec<(String, String)>,
+    /// Extra request headers, eg. for authentication.
+    pub headers: Vec<(String, String)>,
+    /// Defaults to 10 seconds.
+    pub timeout: Option<Duration>,
+    /// How aggregations are buffered while the collector is unreachable.
+    pub delivery: DeliveryOptions,
+}
+
+/// Posts each aggregation received from a subscription to an OTLP/HTTP
+/// endpoint.
+pub struct OtlpSender {
+    subscription: Receiver<Arc<Vec<AggregatedMetric>>>,
+    addr: SocketAddr,
+    host: String,
+    path: String,
+    resource_attributes: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    timeout: Duration,
+    delivery: Delivery,
+}
+
+impl OtlpSender {
+    /// `host` is both where to connect (eg. `otel-collector:4318`) and the
+    /// `Host` header.
+    pub fn new(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, host: &str, options: OtlpOptions) -> Result<OtlpSender, io::Error> {
+        let addr = super::resolve(host)?;
+
+        Ok(OtlpSender {
+            subscription,
+            addr,
+            host: host.to_string(),
+            path: options.path.unwrap_or_else(|| "/v1/metrics".to_string()),
+            resource_attributes: options.resource_attributes,
+            headers: options.headers,
+            timeout: options.timeout.unwrap_or_else(|| Duration::from_secs(10)),
+            delivery: Delivery::new(options.delivery),
+        })
+    }
+
+    pub fn write(&self, metrics: &[AggregatedMetric]) -> io::Result<()> {
+        if metrics.is_empty() {
+            return Ok(())
+        }
+
+        let body = self.encode(metrics);
+        let mut headers = vec![("Content-Type", "application/json")];
+        headers.extend(self.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+        match http::post(&self.addr, &self.host, &self.path, &headers, body.as_bytes(), self.timeout)? {
+            200..=299 => Ok(()),
+            status => Err(io::Error::other(format!("OTLP collector responded with {}", status))),
+        }
+    }
+
+    /// Render an `ExportMetricsServiceRequest` in the OTLP JSON encoding.
+    pub fn encode(&self, metrics: &[AggregatedMetric]) -> String {
+        let (histograms, rest) = histograms(metrics);
+        let encoded = rest.into_iter().map(encode_metric)
+            .chain(histograms.iter().map(|(id, histogram)| encode_histogram(id, histogram)))
+            .collect::<Vec<String>>();
+        let resource = self.resource_attributes.iter()
+            .map(|(key, value)| attribute(key, value))
+            .collect::<Vec<String>>();
+        format!(
+            r#"{{"resourceMetrics":[{{"resource":{{"attributes":[{}]}},"scopeMetrics":[{{"scope":{{"name":"metriqs"}},"metrics":[{}]}}]}}]}}"#,
+            resource.join(","),
+            encoded.join(","),
+        )
+    }
+}
+
+impl Exporter for OtlpSender {
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()> {
+        self.write(batch)
+    }
+}
+
+impl BufferedSender for OtlpSender {
+    const BACKEND: &'static str = "OTLP collector";
+
+    fn subscription(&self) -> &Receiver<Arc<Vec<AggregatedMetric>>> {
+        &self.subscription
+    }
+
+    fn delivery(&mut self) -> &mut Delivery {
+        &mut self.delivery
+    }
+}
+
+fn encode_metric(metric: &AggregatedMetric) -> String {
+    let (data, id) = match *metric {
+        AggregatedMetric::Count(time, ref id, value) => {
+            let point = format!(r#"{},"asInt":"{}""#, point_fields(time, id), value);
+            (format!(r#""sum":{{"aggregationTemporality":{},"isMonotonic":true,"dataPoints":[{{{}}}]}}"#, DELTA, point), id)
+        },
+        AggregatedMetric::Gauge(time, ref id, value) => {
+            let point = format!(r#"{},"asDouble":{}"#, point_fields(time, id), json::number(value));
+            (format!(r#""gauge":{{"dataPoints":[{{{}}}]}}"#, point), id)
+        },
+        AggregatedMetric::Summary(time, ref id, ref summary) => {
+            let quantiles = summary.quantiles.iter()
+                .map(|&(quantile, value)| format!(r#"{{"quantile":{},"value":{}}}"#, json::number(quantile), json::number(value)))
+                .collect::<Vec<String>>();
+            let point = format!(
+                r#"{},"count":"{}","sum":{},"quantileValues":[{}]"#,
+                point_fields(time, id),
+                summary.count,
+                json::number(summary.sum),
+                quantiles.join(","),
+            );
+            (format!(r#""summary":{{"dataPoints":[{{{}}}]}}"#, point), id)
+        },
+        AggregatedMetric::Sketch(time, ref id, ref sketch) => {
+            let (bounds, counts) = buckets(sketch);
+            let mut point = format!(
+                r#"{},"count":"{}","sum":{},"bucketCounts":[{}],"explicitBounds":[{}]"#,
+                point_fields(time, id),
+                sketch.count(),
+                json::number(sketch.sum()),
+                counts.iter().map(|count| format!("\"{}\"", count)).collect::<Vec<String>>().join(","),
+                bounds.iter().map(|&bound| json::number(bound)).collect::<Vec<String>>().join(","),
+            );
+            if let (Some(min), Some(max)) = (sketch.min(), sketch.max()) {
+                point.push_str(&format!(r#","min":{},"max":{}"#, json::number(min), json::number(max)));
+            }
+            (format!(r#""histogram":{{"aggregationTemporality":{},"dataPoints":[{{{}}}]}}"#, DELTA, point), id)
+        },
+    };
+    format!(r#"{{"name":{},{}}}"#, json::string(id.name()), data)
+}
+
+/// A histogram rolled up with cumulative buckets, regrouped from the
+/// series it was flattened into.
+#[derive(Default)]
+struct Histogram {
+    time: Option<SystemTime>,
+    /// Cumulative counts by upper bound.
+    buckets: Vec<(f64, i64)>,
+    count: i64,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+/// Split the series of histograms with cumulative buckets out of
+/// `metrics`, in the order they first appear, from the rest.
+fn histograms(metrics: &[AggregatedMetric]) -> (Vec<(MetricId, Histogram)>, Vec<&AggregatedMetric>) {
+    let ids = metrics.iter()
+        .filter_map(|metric| match *metric {
+            AggregatedMetric::Count(_, ref id, _) => bucket(id).map(|(histogram, _)| histogram),
+            _ => None,
+        })
+        .collect::<HashSet<MetricId>>();
+    let mut histograms: Vec<(MetricId, Histogram)> = vec![];
+    let mut indices: HashMap<MetricId, usize> = HashMap::new();
+    let mut rest = vec![];
+    for metric in metrics {
+        let id = metric.id();
+        let part = |suffix: &str| id.name().strip_suffix(suffix)
+            .map(|name| id.with_name(name))
+            .filter(|histogram| ids.contains(histogram));
+        let (histogram, time) = match *metric {
+            AggregatedMetric::Count(time, _, _) => match bucket(id).filter(|(histogram, _)| ids.contains(histogram)) {
+                Some((histogram, _)) => (histogram, time),
+                None => match part(".count") {
+                    Some(histogram) => (histogram, time),
+                    None => {
+                        rest.push(metric);
+                        continue
+                    },
+                },
+            },
+            AggregatedMetric::Gauge(time, _, _) => match part(".sum").or_else(|| part(".min")).or_else(|| part(".max")) {
+                Some(histogram) => (histogram, time),
+                None => {
+                    rest.push(metric);
+                    continue
+                },
+            },
+            _ => {
+                rest.push(metric);
+                continue
+            },
+        };
+
+        let index = *indices.entry(histogram.clone()).or_insert_with(|| {
+            histograms.push((histogram, Histogram::default()));
+            histograms.len() - 1
+        });
+        let histogram = &mut histograms[index].1;
+        histogram.time = histogram.time.max(Some(time));
+        match *metric {
+            AggregatedMetric::Count(_, _, value) => match bucket(id) {
+                Some((_, bound)) => histogram.buckets.push((bound, value)),
+                None => histogram.count = value,
+            },
+            AggregatedMetric::Gauge(_, _, value) => match id.name().rsplit('.').next() {
+                Some("sum") => histogram.sum = value,
+                Some("min") => histogram.min = Some(value),
+                _ => histogram.max = Some(value),
+            },
+            _ => {},
+        }
+    }
+    (histograms, rest)
+}
+
+fn encode_histogram(id: &MetricId, histogram: &Histogram) -> String {
+    let mut buckets = histogram.buckets.iter()
+        .filter(|&&(bound, _)| bound.is_finite())
+        .cloned()
+        .collect::<Vec<(f64, i64)>>();
+    buckets.sort_by(|x, y| x.0.total_cmp(&y.0));
+    // OTLP's bucket counts aren't cumulative, and the last is unbounded
+    // above.
+    let mut counts = vec![];
+    let mut below = 0;
+    for &(_, cumulative) in &buckets {
+        counts.push(cumulative - below);
+        below = cumulative;
+    }
+    counts.push(histogram.count - below);
+
+    let mut point = format!(
+        r#"{},"count":"{}","sum":{},"bucketCounts":[{}],"explicitBounds":[{}]"#,
+        point_fields(histogram.time.unwrap_or(UNIX_EPOCH), id),
+        histogram.count,
+        json::number(histogram.sum),
+        counts.iter().map(|count| format!("\"{}\"", count)).collect::<Vec<String>>().join(","),
+        buckets.iter().map(|&(bound, _)| json::number(bound)).collect::<Vec<String>>().join(","),
+    );
+    if let (Some(min), Some(max)) = (histogram.min, histogram.max) {
+        point.push_str(&format!(r#","min":{},"max":{}"#, json::number(min), json::number(max)));
+    }
+    format!(r#"{{"name":{},"histogram":{{"aggregationTemporality":{},"dataPoints":[{{{}}}]}}}}"#, json::string(id.name()), DELTA, point)
+}
+
+fn point_fields(time: SystemTime, id: &MetricId) -> String {
+    let attributes = id.dimensions().iter()
+        .map(|(key, value)| attribute(key, value))
+        .collect::<Vec<String>>();
+    let nanos = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    format!(r#""attributes":[{}],"timeUnixNano":"{}""#, attributes.join(","), nanos)
+}
+
+fn attribute(key: &str, value: &str) -> String {
+    format!(r#"{{"key":{},"value":{{"stringValue":{}}}}}"#, json::string(key), json::string(value))
+}
+
+/// Explicit bounds and counts with a bucket per sketch bin, in ascending
+/// order of value. Positive bin `k` holds values in `(gamma^(k-1),
+/// gamma^k]` and negative ones their negations. There's one more count
+/// than bounds since the last bucket is unbounded above.
+fn buckets(sketch: &DdSketch) -> (Vec<f64>, Vec<u64>) {
+    let gamma = sketch.gamma();
+    let mut buckets = vec![];
+    for (&key, &count) in sketch.negative_bins().iter().rev() {
+        buckets.push((-gamma.powi(key - 1), count));
+    }
+    if sketch.zero_count() > 0 {
+        buckets.push((0.0, sketch.zero_count()));
+    }
+    for (&key, &count) in sketch.positive_bins() {
+        buckets.push((gamma.powi(key), count));
+    }
+
+    let counts = buckets.iter().map(|&(_, count)| count).collect();
+    buckets.pop();
+    (buckets.into_iter().map(|(bound, _)| bound).collect(), counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn it_encodes_export_requests() {
+        let (_, subscription) = channel();
+        let options = OtlpOptions {
+            resource_attributes: vec![("service.name".to_string(), "api".to_string())],
+            ..OtlpOptions::default()
+        };
+        let sender = OtlpSender::new(subscription, "127.0.0.1:4318", options).unwrap();
+
+        let mut sketch = DdSketch::new(0.01);
+        sketch.insert(-1.0);
+        sketch.insert(0.0);
+        sketch.insert(10.0);
+        let time = UNIX_EPOCH + Duration::from_secs(1);
+        let encoded = sender.encode(&[
+            AggregatedMetric::Count(time, MetricId::from("requests").with_dimension("host", "a"), 3),
+            AggregatedMetric::Gauge(time, MetricId::from("queue"), 0.5),
+            AggregatedMetric::Sketch(time, MetricId::from("latency"), sketch),
+        ]);
+
+        assert!(encoded.starts_with(r#"{"resourceMetrics":[{"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"api"}}]}"#));
+        assert!(encoded.contains(r#"{"name":"requests","sum":{"aggregationTemporality":1,"isMonotonic":true,"dataPoints":[{"attributes":[{"key":"host","value":{"stringValue":"a"}}],"timeUnixNano":"1000000000","asInt":"3"}]}}"#));
+        assert!(encoded.contains(r#"{"name":"queue","gauge":{"dataPoints":[{"attributes":[],"timeUnixNano":"1000000000","asDouble":0.5}]}}"#));
+        assert!(encoded.contains(r#""count":"3","sum":9,"bucketCounts":["1","1","1"],"explicitBounds":["#));
+    }
+
+    #[test]
+    fn it_encodes_cumulative_buckets_as_histograms() {
+        let (_, subscription) = channel();
+        let sender = OtlpSender::new(subscription, "127.0.0.1:4318", OtlpOptions::default()).unwrap();
+        let time = UNIX_EPOCH + Duration::from_secs(1);
+        let id = MetricId::from("latency");
+        let bucket = |le: &str, count| AggregatedMetric::Count(time, id.with_suffix(".bucket").with_dimension("le", le), count);
+        let encoded = sender.encode(&[
+            AggregatedMetric::Gauge(time, id.with_suffix(".min"), 0.05),
+            AggregatedMetric::Gauge(time, id.with_suffix(".max"), 5.0),
+            AggregatedMetric::Gauge(time, id.with_suffix(".median"), 0.3),
+            bucket("0.1", 1),
+            bucket("1", 3),
+            bucket("+Inf", 4),
+            AggregatedMetric::Gauge(time, id.with_suffix(".sum"), 6.05),
+            AggregatedMetric::Count(time, id.with_suffix(".count"), 4),
+        ]);
+
+        assert!(encoded.contains(r#"{"name":"latency.median","gauge":"#));
+        assert!(encoded.contains(concat!(
+            r#"{"name":"latency","histogram":{"aggregationTemporality":1,"dataPoints":[{"attributes":[],"timeUnixNano":"1000000000","#,
+            r#""count":"4","sum":6.05,"bucketCounts":["1","2","1"],"explicitBounds":[0.1,1],"min":0.05,"max":5}]}}"#,
+        )));
+        assert!(!encoded.contains("latency.bucket"));
+        assert!(!encoded.contains("latency.min"));
+    }
+}
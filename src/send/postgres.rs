@@ -0,0 +1,696 @@
+//! Copies aggregated metrics into a PostgreSQL table, eg. a TimescaleDB
+//! hypertable, with a `COPY ... FROM STDIN` per aggregation so that each
+//! one is a single statement however many points it has. Metrics are
+//! flattened the same way they're stored, a row per point:
+//!
+//! ```sql
+//! CREATE TABLE metrics (
+//!     time timestamptz NOT NULL,
+//!     name text NOT NULL,
+//!     dimensions jsonb NOT NULL,
+//!     kind text NOT NULL,
+//!     value double precision
+//! );
+//! ```
+//!
+//! With `create_table` that table is created if it doesn't exist, and with
+//! `hypertable` too it's turned into a hypertable partitioned on `time`.
+//!
+//! This speaks just enough of the frontend/backend protocol (version 3)
+//! for that, in cleartext: connections can't use TLS. Passwords are sent
+//! as the server asks, as they are, hashed with MD5, or with SCRAM-SHA-256
+//! (the default since PostgreSQL 14), which needs the `scram` feature; it's
+//! a default one.
+
+#[cfg(feature = "scram")]
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "scram")]
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+#[cfg(feature = "scram")]
+use sha2::{Digest, Sha256};
+
+use super::super::db::{AggregatedKey, AggregatedMetric};
+use super::super::util::json;
+#[cfg(feature = "scram")]
+use super::super::util::hash::hmac;
+use super::super::util::time::iso8601;
+use super::delivery::{BufferedSender, Delivery, DeliveryOptions};
+use super::exporter::Exporter;
+
+/// Version 3.0 of the protocol.
+const PROTOCOL_VERSION: i32 = 196_608;
+
+/// Largest `CopyData` message sent; rows are split across them freely.
+const MAX_COPY_DATA: usize = 64 * 1024;
+
+#[derive(Default)]
+pub struct PostgresOptions {
+    /// Defaults to `postgres`.
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Defaults to the user's name.
+    pub database: Option<String>,
+    /// As it's written in SQL, eg. `metrics` or `monitoring."agent metrics"`;
+    /// defaults to `metrics`.
+    pub table: Option<String>,
+    /// Create the table when connecting if it doesn't exist; defaults to
+    /// false.
+    pub create_table: Option<bool>,
+    /// Also make it a TimescaleDB hypertable, which needs the extension;
+    /// defaults to false.
+    pub hypertable: Option<bool>,
+    /// For connecting and for each read and write; defaults to 10 seconds.
+    pub timeout: Option<Duration>,
+    /// How aggregations are buffered while the database is unreachable.
+    pub delivery: DeliveryOptions,
+}
+
+pub struct PostgresSender {
+    subscription: Receiver<Arc<Vec<AggregatedMetric>>>,
+    addr: SocketAddr,
+    user: String,
+    password: Option<String>,
+    database: String,
+    table: String,
+    create_table: bool,
+    hypertable: bool,
+    timeout: Duration,
+    /// Reused until it fails, then reopened by the next write.
+    connection: Option<Connection>,
+    delivery: Delivery,
+}
+
+impl PostgresSender {
+    /// Connects lazily, so the database doesn't need to be up yet.
+    pub fn new(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, address: &str, options: PostgresOptions) -> Result<PostgresSender, io::Error> {
+        let addr = super::resolve(address)?;
+        let user = options.user.unwrap_or_else(|| "postgres".to_string());
+
+        Ok(PostgresSender {
+            subscription,
+            addr,
+            database: options.database.unwrap_or_else(|| user.clone()),
+            user,
+            password: options.password,
+            table: options.table.unwrap_or_else(|| "metrics".to_string()),
+            create_table: options.create_table.unwrap_or(false),
+            hypertable: options.hypertable.unwrap_or(false),
+            timeout: options.timeout.unwrap_or_else(|| Duration::from_secs(10)),
+            connection: None,
+            delivery: Delivery::new(options.delivery),
+        })
+    }
+
+    /// Copy one aggregation into the table. A failed copy inserts none of
+    /// it, so it can be retried as a whole.
+    pub fn write(&mut self, metrics: &[AggregatedMetric]) -> io::Result<()> {
+        if metrics.is_empty() {
+            return Ok(())
+        }
+        let rows = encode(metrics);
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.connect()?,
+        };
+        let copy = format!("COPY {} (time, name, dimensions, kind, value) FROM STDIN", self.table);
+        let result = connection.copy(&copy, &rows);
+        // An error from the server leaves the connection usable; anything
+        // else might have left it mid-message.
+        if result.as_ref().is_ok() || result.as_ref().is_err_and(|err| err.kind() == io::ErrorKind::Other) {
+            self.connection = Some(connection);
+        }
+        result
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        let mut connection = Connection::open(&self.addr, &self.user, self.password.as_deref(), &self.database, self.timeout)?;
+        if self.create_table {
+            connection.query(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (time timestamptz NOT NULL, name text NOT NULL, dimensions jsonb NOT NULL, kind text NOT NULL, value double precision)",
+                self.table,
+            ))?;
+            if self.hypertable {
+                connection.query(&format!("SELECT create_hypertable('{}', 'time', if_not_exists => TRUE)", self.table.replace('\'', "''")))?;
+            }
+        }
+        Ok(connection)
+    }
+}
+
+impl Exporter for PostgresSender {
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()> {
+        self.write(batch)
+    }
+}
+
+impl BufferedSender for PostgresSender {
+    const BACKEND: &'static str = "PostgreSQL";
+
+    fn subscription(&self) -> &Receiver<Arc<Vec<AggregatedMetric>>> {
+        &self.subscription
+    }
+
+    fn delivery(&mut self) -> &mut Delivery {
+        &mut self.delivery
+    }
+}
+
+/// Rows in `COPY`'s text format: tab-separated, a line each.
+pub fn encode(metrics: &[AggregatedMetric]) -> Vec<u8> {
+    let mut rows = String::new();
+    for metric in metrics {
+        for (key, (time, value)) in metric.entries() {
+            let (kind, id) = match key {
+                AggregatedKey::Count(ref id) => ("count", id),
+                AggregatedKey::Gauge(ref id) => ("gauge", id),
+            };
+            let dimensions = id.dimensions().iter()
+                .map(|(key, value)| format!("{}:{}", json::string(key), json::string(value)))
+                .collect::<Vec<String>>();
+            let fields = [
+                iso8601(time),
+                copy_field(id.name()),
+                copy_field(&format!("{{{}}}", dimensions.join(","))),
+                kind.to_string(),
+                number(value),
+            ];
+            rows.push_str(&fields.join("\t"));
+            rows.push('\n');
+        }
+    }
+    rows.into_bytes()
+}
+
+/// `double precision` takes `NaN` and `Infinity` but not Rust's `inf`.
+fn number(value: f64) -> String {
+    if value.is_infinite() {
+        if value > 0.0 { "Infinity".to_string() } else { "-Infinity".to_string() }
+    } else {
+        value.to_string()
+    }
+}
+
+/// Escape what's meaningful to `COPY`'s text format.
+fn copy_field(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '\\' => output.push_str("\\\\"),
+            '\t' => output.push_str("\\t"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+/// An authenticated session, idle between statements.
+struct Connection {
+    stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn open(addr: &SocketAddr, user: &str, password: Option<&str>, database: &str, timeout: Duration) -> io::Result<Connection> {
+        let stream = TcpStream::connect_timeout(addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let mut connection = Connection { stream: BufReader::new(stream) };
+
+        let mut startup = PROTOCOL_VERSION.to_be_bytes().to_vec();
+        for &(key, value) in &[("user", user), ("database", database), ("application_name", "metriqs")] {
+            cstring(&mut startup, key);
+            cstring(&mut startup, value);
+        }
+        startup.push(0);
+        let mut message = ((startup.len() + 4) as i32).to_be_bytes().to_vec();
+        message.extend_from_slice(&startup);
+        connection.stream.get_mut().write_all(&message)?;
+
+        #[cfg(feature = "scram")]
+        let mut scram = None;
+        loop {
+            let (kind, body) = connection.message()?;
+            match kind {
+                b'R' => {
+                    let code = body.get(..4).map(|code| i32::from_be_bytes([code[0], code[1], code[2], code[3]]));
+                    match code {
+                        Some(0) => {},
+                        Some(3) => {
+                            let password = password.ok_or_else(|| invalid("the server asked for a password, but none is configured"))?;
+                            let mut message = vec![];
+                            cstring(&mut message, password);
+                            connection.send(b'p', &message)?;
+                        },
+                        Some(5) => {
+                            let password = password.ok_or_else(|| invalid("the server asked for a password, but none is configured"))?;
+                            let salt = body.get(4..8).ok_or_else(|| invalid("malformed MD5 salt"))?;
+                            let mut message = vec![];
+                            cstring(&mut message, &md5_password(user, password, salt));
+                            connection.send(b'p', &message)?;
+                        },
+                        #[cfg(not(feature = "scram"))]
+                        Some(10) => return Err(invalid("the server asked for SCRAM-SHA-256, which needs the `scram` feature")),
+                        #[cfg(feature = "scram")]
+                        Some(10) => {
+                            let password = password.ok_or_else(|| invalid("the server asked for a password, but none is configured"))?;
+                            if !body[4..].split(|&byte| byte == 0).any(|mechanism| mechanism == b"SCRAM-SHA-256") {
+                                return Err(invalid("the server doesn't offer SCRAM-SHA-256"))
+                            }
+                            let client = Scram::new("", password);
+                            let first = client.first();
+                            let mut message = vec![];
+                            cstring(&mut message, "SCRAM-SHA-256");
+                            message.extend_from_slice(&(first.len() as i32).to_be_bytes());
+                            message.extend_from_slice(first.as_bytes());
+                            connection.send(b'p', &message)?;
+                            scram = Some(client);
+                        },
+                        #[cfg(feature = "scram")]
+                        Some(11) => {
+                            let client = scram.as_mut().ok_or_else(|| invalid("unexpected SASL challenge"))?;
+                            let response = client.respond(&String::from_utf8_lossy(&body[4..]))?;
+                            connection.send(b'p', response.as_bytes())?;
+                        },
+                        #[cfg(feature = "scram")]
+                        Some(12) => {
+                            let client = scram.as_ref().ok_or_else(|| invalid("unexpected SASL outcome"))?;
+                            client.verify(&String::from_utf8_lossy(&body[4..]))?;
+                        },
+                        _ => return Err(invalid("the server asked for an unsupported authentication method")),
+                    }
+                },
+                b'E' => return Err(server_error(&body)),
+                b'Z' => return Ok(connection),
+                // Parameter statuses, the cancellation key, and notices.
+                _ => {},
+            }
+        }
+    }
+
+    /// Run a statement with the simple query protocol, ignoring any rows.
+    fn query(&mut self, sql: &str) -> io::Result<()> {
+        let mut message = vec![];
+        cstring(&mut message, sql);
+        self.send(b'Q', &message)?;
+        self.finish(None)
+    }
+
+    fn copy(&mut self, sql: &str, rows: &[u8]) -> io::Result<()> {
+        let mut message = vec![];
+        cstring(&mut message, sql);
+        self.send(b'Q', &message)?;
+        loop {
+            let (kind, body) = self.message()?;
+            match kind {
+                b'G' => break,
+                b'E' => return self.finish(Some(server_error(&body))),
+                b'Z' => return Err(invalid("the server didn't start copying")),
+                _ => {},
+            }
+        }
+        for chunk in rows.chunks(MAX_COPY_DATA) {
+            self.send(b'd', chunk)?;
+        }
+        self.send(b'c', &[])?;
+        self.finish(None)
+    }
+
+    /// Read until the server's ready for the next statement, returning the
+    /// first error it reported.
+    fn finish(&mut self, mut error: Option<io::Error>) -> io::Result<()> {
+        loop {
+            let (kind, body) = self.message()?;
+            match kind {
+                b'E' if error.is_none() => error = Some(server_error(&body)),
+                b'Z' => return error.map_or(Ok(()), Err),
+                _ => {},
+            }
+        }
+    }
+
+    fn send(&mut self, kind: u8, body: &[u8]) -> io::Result<()> {
+        let mut message = Vec::with_capacity(5 + body.len());
+        message.push(kind);
+        message.extend_from_slice(&((body.len() + 4) as i32).to_be_bytes());
+        message.extend_from_slice(body);
+        self.stream.get_mut().write_all(&message)
+    }
+
+    fn message(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut header = [0; 5];
+        self.stream.read_exact(&mut header)?;
+        let len = i32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        if len < 4 {
+            return Err(invalid("invalid message length"))
+        }
+        let mut body = vec![0; len as usize - 4];
+        self.stream.read_exact(&mut body)?;
+        Ok((header[0], body))
+    }
+}
+
+fn cstring(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(value.as_bytes());
+    buffer.push(0)
+}
+
+/// An `ErrorResponse` as an error of kind `Other`, with its message and
+/// SQLSTATE code.
+fn server_error(body: &[u8]) -> io::Error {
+    let (mut message, mut code) = (String::new(), String::new());
+    for field in body.split(|&byte| byte == 0).filter(|field| !field.is_empty()) {
+        let value = String::from_utf8_lossy(&field[1..]).into_owned();
+        match field[0] {
+            b'M' => message = value,
+            b'C' => code = value,
+            _ => {},
+        }
+    }
+    io::Error::other(format!("{} (SQLSTATE {})", message, code))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// What `md5` authentication sends: `md5` then the hex of the MD5 of the
+/// hex of the MD5 of the password and user, and the salt.
+fn md5_password(user: &str, password: &str, salt: &[u8]) -> String {
+    let mut salted = hex(&md5(format!("{}{}", password, user).as_bytes())).into_bytes();
+    salted.extend_from_slice(salt);
+    format!("md5{}", hex(&md5(&salted)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Per-round shift amounts and constants of MD5 (RFC 1321).
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+const MD5_CONSTANTS: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee,
+    0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be,
+    0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa,
+    0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed,
+    0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c,
+    0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05,
+    0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039,
+    0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1,
+    0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+/// MD5, which is only fit for what servers still configured for `md5`
+/// authentication expect.
+fn md5(input: &[u8]) -> [u8; 16] {
+    let mut message = input.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0)
+    }
+    message.extend_from_slice(&((input.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for chunk in message.chunks(64) {
+        let words = (0..16)
+            .map(|index| u32::from_le_bytes([chunk[index * 4], chunk[index * 4 + 1], chunk[index * 4 + 2], chunk[index * 4 + 3]]))
+            .collect::<Vec<u32>>();
+        let [mut a, mut b, mut c, mut d] = state;
+        for round in 0..64 {
+            let (f, word) = match round / 16 {
+                0 => ((b & c) | (!b & d), round),
+                1 => ((d & b) | (!d & c), (5 * round + 1) % 16),
+                2 => (b ^ c ^ d, (3 * round + 5) % 16),
+                _ => (c ^ (b | !d), (7 * round) % 16),
+            };
+            let rotated = a.wrapping_add(f).wrapping_add(MD5_CONSTANTS[round]).wrapping_add(words[word])
+                .rotate_left(MD5_SHIFTS[round / 16 * 4 + round % 4]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        for (state, value) in state.iter_mut().zip(&[a, b, c, d]) {
+            *state = state.wrapping_add(*value)
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (bytes, word) in digest.chunks_mut(4).zip(&state) {
+        bytes.copy_from_slice(&word.to_le_bytes())
+    }
+    digest
+}
+
+/// The client side of a SCRAM-SHA-256 exchange (RFC 7677), without channel
+/// binding.
+#[cfg(feature = "scram")]
+struct Scram {
+    /// Sent empty to PostgreSQL, which takes the user from the startup
+    /// message.
+    user: String,
+    password: String,
+    nonce: String,
+    /// The server's nonce, salt, and iteration count, and what was said
+    /// so far, once it's sent them.
+    auth_message: Option<String>,
+    salted_password: Vec<u8>,
+}
+
+#[cfg(feature = "scram")]
+impl Scram {
+    fn new(user: &str, password: &str) -> Scram {
+        Scram { user: user.to_string(), password: password.to_string(), nonce: nonce(), auth_message: None, salted_password: vec![] }
+    }
+
+    fn first_bare(&self) -> String {
+        format!("n={},r={}", self.user.replace('=', "=3D").replace(',', "=2C"), self.nonce)
+    }
+
+    fn first(&self) -> String {
+        format!("n,,{}", self.first_bare())
+    }
+
+    fn respond(&mut self, server_first: &str) -> io::Result<String> {
+        let attribute = |name: char| server_first.split(',')
+            .find(|part| part.starts_with(name) && part[1..].starts_with('='))
+            .map(|part| &part[2..])
+            .ok_or_else(|| invalid("malformed SCRAM challenge"));
+        let nonce = attribute('r')?;
+        if !nonce.starts_with(&self.nonce) {
+            return Err(invalid("the server's SCRAM nonce doesn't extend ours"))
+        }
+        let salt = base64_decode(attribute('s')?).ok_or_else(|| invalid("malformed SCRAM salt"))?;
+        let iterations = attribute('i')?.parse::<u32>().map_err(|_| invalid("malformed SCRAM iteration count"))?;
+
+        self.salted_password = pbkdf2(self.password.as_bytes(), &salt, iterations);
+        let client_key = hmac(&self.salted_password, b"Client Key");
+        let stored_key = Sha256::digest(&client_key);
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!("{},{},{}", self.first_bare(), server_first, without_proof);
+        let signature = hmac(&stored_key, auth_message.as_bytes());
+        let proof = client_key.iter().zip(&signature).map(|(key, signature)| key ^ signature).collect::<Vec<u8>>();
+        self.auth_message = Some(auth_message);
+        Ok(format!("{},p={}", without_proof, base64_encode(&proof)))
+    }
+
+    fn verify(&self, server_final: &str) -> io::Result<()> {
+        let auth_message = self.auth_message.as_ref().ok_or_else(|| invalid("unexpected SASL outcome"))?;
+        let server_key = hmac(&self.salted_password, b"Server Key");
+        let expected = base64_encode(&hmac(&server_key, auth_message.as_bytes()));
+        match server_final.strip_prefix("v=") {
+            Some(signature) if signature == expected => Ok(()),
+            _ => Err(invalid("the server's SCRAM signature doesn't match")),
+        }
+    }
+}
+
+/// PBKDF2-HMAC-SHA-256 with a single block, which is all SCRAM-SHA-256
+/// needs.
+#[cfg(feature = "scram")]
+fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut block = salt.to_vec();
+    block.extend_from_slice(&1u32.to_be_bytes());
+    let mut previous = hmac(password, &block);
+    let mut result = previous.clone();
+    for _ in 1..iterations {
+        previous = hmac(password, &previous);
+        for (result, byte) in result.iter_mut().zip(&previous) {
+            *result ^= byte
+        }
+    }
+    result
+}
+
+/// Hex of a few randomly-seeded hashes; SCRAM nonces need to be
+/// unpredictable, not uniformly random.
+#[cfg(feature = "scram")]
+fn nonce() -> String {
+    (0..3).map(|_| format!("{:016x}", RandomState::new().build_hasher().finish())).collect()
+}
+
+#[cfg(feature = "scram")]
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[cfg(feature = "scram")]
+fn base64_encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [chunk[0], chunk.get(1).cloned().unwrap_or(0), chunk.get(2).cloned().unwrap_or(0)];
+        let group = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for index in 0..4 {
+            if index <= chunk.len() {
+                output.push(BASE64[(group >> (18 - 6 * index) & 0x3f) as usize] as char)
+            } else {
+                output.push('=')
+            }
+        }
+    }
+    output
+}
+
+#[cfg(feature = "scram")]
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let mut output = vec![];
+    let (mut group, mut bits) = (0u32, 0);
+    for byte in input.bytes().filter(|&byte| byte != b'=') {
+        group = group << 6 | BASE64.iter().position(|&c| c == byte)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((group >> bits) as u8);
+        }
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+
+    use super::super::super::metric::MetricId;
+
+    #[test]
+    fn it_encodes_copy_rows() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let rows = encode(&[
+            AggregatedMetric::Count(time, MetricId::from("requests").with_dimension("path", "a\\b"), 3),
+            AggregatedMetric::Gauge(time, MetricId::from("queue"), f64::INFINITY),
+        ]);
+        assert_eq!(String::from_utf8(rows).unwrap(), concat!(
+            "2017-07-14T02:40:00.000Z\trequests\t{\"path\":\"a\\\\\\\\b\"}\tcount\t3\n",
+            "2017-07-14T02:40:00.000Z\tqueue\t{}\tgauge\tInfinity\n",
+        ));
+    }
+
+    /// Read a frontend message, the startup message if `kind` is false.
+    fn read_message(stream: &mut TcpStream, kind: bool) -> (u8, Vec<u8>) {
+        let mut kind_byte = [0];
+        if kind {
+            stream.read_exact(&mut kind_byte).unwrap();
+        }
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut body = vec![0; i32::from_be_bytes(len) as usize - 4];
+        stream.read_exact(&mut body).unwrap();
+        (kind_byte[0], body)
+    }
+
+    fn write_message(stream: &mut TcpStream, kind: u8, body: &[u8]) {
+        let mut message = vec![kind];
+        message.extend_from_slice(&((body.len() + 4) as i32).to_be_bytes());
+        message.extend_from_slice(body);
+        stream.write_all(&message).unwrap();
+    }
+
+    #[test]
+    fn it_copies_aggregations_into_the_table() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (_, startup) = read_message(&mut stream, false);
+            write_message(&mut stream, b'R', &3i32.to_be_bytes());
+            let (_, password) = read_message(&mut stream, true);
+            write_message(&mut stream, b'R', &0i32.to_be_bytes());
+            write_message(&mut stream, b'Z', b"I");
+
+            let (_, query) = read_message(&mut stream, true);
+            write_message(&mut stream, b'G', &[0, 0, 0]);
+            let mut copied = vec![];
+            loop {
+                match read_message(&mut stream, true) {
+                    (b'd', data) => copied.extend(data),
+                    (b'c', _) => break,
+                    (kind, _) => panic!("unexpected message {}", kind as char),
+                }
+            }
+            write_message(&mut stream, b'C', b"COPY 2\0");
+            write_message(&mut stream, b'Z', b"I");
+            (startup, password, query, copied)
+        });
+
+        let (_, subscription) = channel();
+        let options = PostgresOptions {
+            password: Some("secret".to_string()),
+            table: Some("agent_metrics".to_string()),
+            ..PostgresOptions::default()
+        };
+        let mut sender = PostgresSender::new(subscription, &addr, options).unwrap();
+        let metrics = [AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("requests"), 3)];
+        sender.write(&metrics).unwrap();
+        assert!(sender.connection.is_some());
+
+        let (startup, password, query, copied) = server.join().unwrap();
+        assert!(startup.windows(14).any(|window| window == b"user\0postgres\0"));
+        assert_eq!(password, b"secret\0");
+        assert_eq!(query, b"COPY agent_metrics (time, name, dimensions, kind, value) FROM STDIN\0");
+        assert_eq!(copied, encode(&metrics));
+    }
+
+    #[test]
+    fn it_hashes_md5_passwords() {
+        // Test vectors from RFC 1321.
+        assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(&md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(hex(&md5(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890")), "57edf4a22be3c955ac49da2e2107b67a");
+        assert_eq!(md5_password("postgres", "secret", &[1, 2, 3, 4]), "md5bb41a296aab6baccb36ff243a562abff");
+    }
+
+    #[cfg(feature = "scram")]
+    #[test]
+    fn it_answers_scram_challenges() {
+        // PBKDF2-HMAC-SHA-256 test vectors from RFC 7914.
+        assert_eq!(hex(&pbkdf2(b"passwd", b"salt", 1)), "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc");
+        assert_eq!(base64_encode(b"metriqs"), "bWV0cmlxcw==");
+        assert_eq!(base64_decode("bWV0cmlxcw==").unwrap(), b"metriqs");
+
+        // The exchange from RFC 7677.
+        let mut client = Scram::new("user", "pencil");
+        client.nonce = "rOprNGfwEbeRWgbNEkqO".to_string();
+        assert_eq!(client.first(), "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        assert!(client.respond("r=someone-elses,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096").is_err());
+        let response = client.respond("r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096").unwrap();
+        assert_eq!(response, "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=");
+        client.verify("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=").unwrap();
+        assert!(client.verify("v=bogus").is_err());
+    }
+}
@@ -0,0 +1,473 @@
+//! Serves the latest aggregated state over HTTP in the Prometheus text
+//! exposition format so Prometheus can scrape the agent directly, making it
+//! a StatsD to Prometheus bridge.
+//!
+//! Histograms with cumulative buckets (see `DbOptions::histogram_buckets`)
+//! are exposed as Prometheus histograms, their `.bucket` counts, `.sum`,
+//! and `.count` together, so that `histogram_quantile` works on them.
+//!
+//! Families of metrics described with help or a unit (see
+//! `Collector::describe`) get a `# HELP` line; the text format has nowhere
+//! else to put units, so they're appended to the help, eg.
+//! `# HELP latency Request latency (unit: ms)`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+use string_cache::DefaultAtom as Atom;
+
+use super::super::db::{AggregatedMetric, MetadataRegistry};
+use super::super::metric::{MetricId, Summary};
+use super::super::util::{lock, Stop};
+use super::{bucket, SKETCH_QUANTILES};
+use super::sanitize::Sanitizer;
+
+enum Exposed {
+    /// Running total of every count aggregated so far.
+    Counter(f64),
+    Gauge(f64),
+    /// Count and sum are running totals; quantiles are the latest.
+    Summary(Summary),
+    /// Running totals of cumulative buckets by upper bound, sum, and count.
+    Histogram { buckets: Vec<(f64, f64)>, sum: f64, count: f64 },
+}
+
+#[derive(Default)]
+pub struct ExposerOptions {
+    /// Aggregations a series can go without being updated before it's no
+    /// longer exposed, so that series whose emitters went away don't stay
+    /// forever; one that comes back starts over, as after a restart.
+    /// Series are exposed until the agent stops by default.
+    pub stale_after: Option<u32>,
+    /// Where metrics' help and units are looked up by the names they were
+    /// aggregated under, eg. the Db's (`Db::metadata`).
+    pub metadata: Option<MetadataRegistry>,
+}
+
+/// The state exposed to scrapes, updated from each aggregation.
+#[derive(Default)]
+pub struct Exposition {
+    series: HashMap<MetricId, Exposed>,
+    stale_after: Option<u64>,
+    updates: u64,
+    /// The update each series was last in, when series can go stale.
+    updated: HashMap<MetricId, u64>,
+    metadata: Option<MetadataRegistry>,
+    /// The names sanitized names were aggregated under, to look their
+    /// metadata up by.
+    described: HashMap<Atom, Atom>,
+}
+
+impl Exposition {
+    pub fn new() -> Exposition {
+        Exposition::default()
+    }
+
+    pub fn with_options(options: ExposerOptions) -> Exposition {
+        Exposition {
+            stale_after: options.stale_after.map(|after| u64::from(after.max(1))),
+            metadata: options.metadata,
+            ..Exposition::default()
+        }
+    }
+
+    pub fn update(&mut self, metrics: &[AggregatedMetric]) {
+        self.updates += 1;
+        let sanitizer = Sanitizer::prometheus();
+        let histograms = metrics.iter()
+            .filter_map(|metric| match *metric {
+                AggregatedMetric::Count(_, ref id, _) => bucket(id).map(|(histogram, _)| histogram),
+                _ => None,
+            })
+            .collect::<HashSet<MetricId>>();
+        for metric in metrics {
+            if self.update_histogram(metric, &histograms, &sanitizer) {
+                continue
+            }
+            let id = sanitizer.id(metric.id());
+            self.touch(&id);
+            self.describe(&id, metric.id());
+            let summary = match *metric {
+                AggregatedMetric::Count(_, _, value) => {
+                    match self.series.entry(id).or_insert(Exposed::Counter(0.0)) {
+                        &mut Exposed::Counter(ref mut total) => *total += value as f64,
+                        exposed => *exposed = Exposed::Counter(value as f64),
+                    }
+                    continue
+                },
+                AggregatedMetric::Gauge(_, _, value) => {
+                    self.series.insert(id, Exposed::Gauge(value));
+                    continue
+                },
+                AggregatedMetric::Summary(_, _, ref summary) => summary.clone(),
+                AggregatedMetric::Sketch(_, _, ref sketch) => Summary {
+                    count: sketch.count(),
+                    sum: sketch.sum(),
+                    quantiles: SKETCH_QUANTILES.iter()
+                        .filter_map(|&quantile| sketch.quantile(quantile).map(|value| (quantile, value)))
+                        .collect(),
+                },
+            };
+            match self.series.entry(id).or_insert(Exposed::Summary(Summary { count: 0, sum: 0.0, quantiles: vec![] })) {
+                &mut Exposed::Summary(ref mut total) => {
+                    total.count += summary.count;
+                    total.sum += summary.sum;
+                    total.quantiles = summary.quantiles;
+                },
+                exposed => *exposed = Exposed::Summary(summary),
+            }
+        }
+
+        if let Some(after) = self.stale_after {
+            let (updates, updated) = (self.updates, &mut self.updated);
+            updated.retain(|_, &mut at| updates - at < after);
+            self.series.retain(|id, _| updated.contains_key(id));
+            let names = self.series.keys().map(|id| id.name()).collect::<HashSet<&Atom>>();
+            self.described.retain(|name, _| names.contains(name));
+        }
+    }
+
+    /// Note that `id` was updated, if series can go stale.
+    fn touch(&mut self, id: &MetricId) {
+        if self.stale_after.is_some() {
+            self.updated.insert(id.clone(), self.updates);
+        }
+    }
+
+    /// Note the name `sanitized` was aggregated under, if there's metadata
+    /// to look up.
+    fn describe(&mut self, sanitized: &MetricId, original: &MetricId) {
+        if self.metadata.is_some() && !self.described.contains_key(sanitized.name()) {
+            self.described.insert(sanitized.name().clone(), original.name().clone());
+        }
+    }
+
+    /// The `# HELP` text of the family of series named `name`.
+    fn help(&self, name: &Atom) -> Option<String> {
+        let original = self.described.get(name)?;
+        let metadata = self.metadata.as_ref()?.get(original)?;
+        match (metadata.help, metadata.unit) {
+            (Some(help), Some(unit)) => Some(format!("{} (unit: {})", help, unit)),
+            (Some(help), None) => Some(help),
+            (None, Some(unit)) => Some(format!("Unit: {}", unit)),
+            (None, None) => None,
+        }
+    }
+
+    /// Fold `metric` into its histogram if it's part of one of
+    /// `histograms`, returning whether it was.
+    fn update_histogram(&mut self, metric: &AggregatedMetric, histograms: &HashSet<MetricId>, sanitizer: &Sanitizer) -> bool {
+        let part = |id: &MetricId, suffix: &str| {
+            id.name().strip_suffix(suffix)
+                .map(|name| id.with_name(name))
+                .filter(|histogram| histograms.contains(histogram))
+        };
+        let (histogram, bound, value) = match *metric {
+            AggregatedMetric::Count(_, ref id, value) => match bucket(id) {
+                Some((histogram, bound)) => (histogram, Some(bound), value as f64),
+                None => match part(id, ".count") {
+                    Some(histogram) => (histogram, None, value as f64),
+                    None => return false,
+                },
+            },
+            AggregatedMetric::Gauge(_, ref id, value) => match part(id, ".sum") {
+                Some(histogram) => (histogram, Some(f64::NAN), value),
+                None => return false,
+            },
+            _ => return false,
+        };
+
+        let sanitized = sanitizer.id(&histogram);
+        self.touch(&sanitized);
+        self.describe(&sanitized, &histogram);
+        let histogram = sanitized;
+        let exposed = self.series.entry(histogram).or_insert(Exposed::Histogram { buckets: vec![], sum: 0.0, count: 0.0 });
+        if let Exposed::Histogram { ref mut buckets, ref mut sum, ref mut count } = *exposed {
+            match bound {
+                Some(bound) if bound.is_nan() => *sum += value,
+                Some(bound) => match buckets.iter().position(|&(existing, _)| existing == bound) {
+                    Some(index) => buckets[index].1 += value,
+                    None => {
+                        buckets.push((bound, value));
+                        buckets.sort_by(|x, y| x.0.total_cmp(&y.0));
+                    },
+                },
+                None => *count += value,
+            }
+        } else {
+            *exposed = Exposed::Histogram { buckets: vec![], sum: 0.0, count: 0.0 };
+        }
+        true
+    }
+
+    /// Render every series, grouped into families by name and type.
+    pub fn render(&self) -> String {
+        // Families by name and type, with the name their help is under.
+        let mut families: BTreeMap<(String, &str), (&Atom, Vec<String>)> = BTreeMap::new();
+        for (id, exposed) in &self.series {
+            match *exposed {
+                Exposed::Counter(total) => {
+                    let name = format!("{}_total", id.name());
+                    let line = format!("{}{} {}", name, labels(id, None), total);
+                    families.entry((name, "counter")).or_insert_with(|| (id.name(), vec![])).1.push(line)
+                },
+                Exposed::Gauge(value) => {
+                    let line = format!("{}{} {}", id.name(), labels(id, None), value);
+                    families.entry((id.name().to_string(), "gauge")).or_insert_with(|| (id.name(), vec![])).1.push(line)
+                },
+                Exposed::Summary(ref summary) => {
+                    let name = id.name();
+                    let lines = &mut families.entry((name.to_string(), "summary")).or_insert_with(|| (name, vec![])).1;
+                    for &(quantile, value) in &summary.quantiles {
+                        lines.push(format!("{}{} {}", name, labels(id, Some(("quantile", quantile.to_string()))), value))
+                    }
+                    lines.push(format!("{}_sum{} {}", name, labels(id, None), summary.sum));
+                    lines.push(format!("{}_count{} {}", name, labels(id, None), summary.count));
+                },
+                Exposed::Histogram { ref buckets, sum, count } => {
+                    // One entry per series so that sorting keeps its buckets
+                    // in order of bound.
+                    let name = id.name();
+                    let mut lines = buckets.iter()
+                        .map(|&(bound, total)| {
+                            let bound = if bound.is_infinite() { "+Inf".to_string() } else { bound.to_string() };
+                            format!("{}_bucket{} {}", name, labels(id, Some(("le", bound))), total)
+                        })
+                        .collect::<Vec<String>>();
+                    lines.push(format!("{}_sum{} {}", name, labels(id, None), sum));
+                    lines.push(format!("{}_count{} {}", name, labels(id, None), count));
+                    families.entry((name.to_string(), "histogram")).or_insert_with(|| (name, vec![])).1.push(lines.join("\n"))
+                },
+            }
+        }
+
+        let mut output = String::new();
+        for ((name, kind), (described, mut lines)) in families {
+            lines.sort();
+            if let Some(help) = self.help(described) {
+                let _ = writeln!(output, "# HELP {} {}", name, help.replace('\\', "\\\\").replace('\n', "\\n"));
+            }
+            let _ = writeln!(output, "# TYPE {} {}", name, kind);
+            for line in lines {
+                output.push_str(&line);
+                output.push('\n');
+            }
+        }
+        output
+    }
+}
+
+/// The dimensions as labels, with an `extra` label such as a quantile or a
+/// bucket's bound.
+fn labels(id: &MetricId, extra: Option<(&str, String)>) -> String {
+    let mut labels = id.dimensions().iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+        .collect::<Vec<String>>();
+    if let Some((key, value)) = extra {
+        labels.push(format!("{}=\"{}\"", key, value));
+    }
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Exposes aggregated metrics on `/metrics` for Prometheus to scrape.
+pub struct Exposer {
+    exposition: Arc<Mutex<Exposition>>,
+    addr: SocketAddr,
+}
+
+impl Exposer {
+    /// Spawns a thread that folds each aggregation received from the
+    /// subscription into the exposed state.
+    pub fn new<A: ToSocketAddrs>(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, addr: A) -> Result<Exposer, io::Error> {
+        Exposer::with_options(subscription, addr, ExposerOptions::default())
+    }
+
+    pub fn with_options<A: ToSocketAddrs>(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, addr: A, options: ExposerOptions) -> Result<Exposer, io::Error> {
+        let addr = addr.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on"))?;
+
+        let exposition = Arc::new(Mutex::new(Exposition::with_options(options)));
+        let updated = exposition.clone();
+        thread::spawn(move || {
+            for metrics in subscription {
+                lock(&updated).update(&metrics)
+            }
+        });
+
+        Ok(Exposer {
+            exposition,
+            addr,
+        })
+    }
+
+    /// Serve scrapes; blocks the calling thread.
+    pub fn listen(&mut self) -> Result<(), io::Error> {
+        self.listen_until(&Stop::new())
+    }
+
+    /// Like `listen` but returns once `stop` is stopped, closing the
+    /// socket.
+    pub fn listen_until(&mut self, stop: &Stop) -> Result<(), io::Error> {
+        let listener = TcpListener::bind(self.addr)?;
+        while let Some(stream) = stop.accept(&listener)? {
+            let exposition = self.exposition.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr();
+                if let Err(err) = Exposer::handle_client(stream, &exposition) {
+                    match peer {
+                        Ok(peer) => warn!("Error serving Prometheus scrape from {}: {}", peer, err),
+                        Err(_) => warn!("Error serving Prometheus scrape: {}", err),
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_client(stream: TcpStream, exposition: &Mutex<Exposition>) -> Result<(), io::Error> {
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        let mut reader = BufReader::new(stream);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // Skip the headers, nothing in them changes the response.
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+                break
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", exposition.lock().unwrap().render()),
+            (Some("GET"), Some(_)) => ("404 Not Found", "Not Found\n".to_string()),
+            _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_string()),
+        };
+
+        let mut stream = reader.into_inner();
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body,
+        )?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    use super::super::super::db::{DdSketch, Metadata, Unit};
+
+    #[test]
+    fn it_renders_the_text_format() {
+        let id = MetricId::from("api.requests").with_dimension("path", "/a\"b");
+        let mut sketch = DdSketch::new(0.01);
+        sketch.insert(10.0);
+
+        let mut exposition = Exposition::new();
+        exposition.update(&[
+            AggregatedMetric::Count(UNIX_EPOCH, id.clone(), 3),
+            AggregatedMetric::Gauge(UNIX_EPOCH, MetricId::from("queue"), 5.0),
+            AggregatedMetric::Sketch(UNIX_EPOCH, MetricId::from("latency"), sketch),
+        ]);
+        exposition.update(&[
+            AggregatedMetric::Count(UNIX_EPOCH, id, 4),
+            AggregatedMetric::Gauge(UNIX_EPOCH, MetricId::from("queue"), 2.0),
+        ]);
+
+        let rendered = exposition.render();
+        assert!(rendered.contains("# TYPE api_requests_total counter\napi_requests_total{path=\"/a\\\"b\"} 7\n"));
+        assert!(rendered.contains("# TYPE queue gauge\nqueue 2\n"));
+        assert!(rendered.contains("# TYPE latency summary\n"));
+        assert!(rendered.contains("latency_count 1\n"));
+        assert!(rendered.contains("latency{quantile=\"0.5\"} "));
+    }
+
+    #[test]
+    fn it_renders_cumulative_buckets_as_histograms() {
+        let id = MetricId::from("latency");
+        let bucket = |le: &str, count| AggregatedMetric::Count(UNIX_EPOCH, id.with_suffix(".bucket").with_dimension("le", le), count);
+        let aggregated = vec![
+            bucket("0.1", 1),
+            bucket("1", 3),
+            bucket("+Inf", 4),
+            AggregatedMetric::Gauge(UNIX_EPOCH, id.with_suffix(".sum"), 6.05),
+            AggregatedMetric::Count(UNIX_EPOCH, id.with_suffix(".count"), 4),
+        ];
+
+        let mut exposition = Exposition::new();
+        exposition.update(&aggregated);
+        exposition.update(&aggregated);
+        let rendered = exposition.render();
+        assert!(rendered.contains(concat!(
+            "# TYPE latency histogram\n",
+            "latency_bucket{le=\"0.1\"} 2\n",
+            "latency_bucket{le=\"1\"} 6\n",
+            "latency_bucket{le=\"+Inf\"} 8\n",
+            "latency_sum 12.1\n",
+            "latency_count 8\n",
+        )));
+        assert!(!rendered.contains("latency_count_total"));
+    }
+
+    #[test]
+    fn it_renders_help_and_units_from_metadata() {
+        let metadata = MetadataRegistry::new();
+        metadata.describe(Atom::from("api.requests"), Metadata { help: Some("Requests served\nso far".to_string()), ..Metadata::default() });
+        metadata.describe(Atom::from("queue"), Metadata { unit: Some(Unit::Bytes), ..Metadata::default() });
+        metadata.describe(Atom::from("latency"), Metadata { unit: Some(Unit::Milliseconds), help: Some("Request latency".to_string()) });
+        let mut exposition = Exposition::with_options(ExposerOptions { metadata: Some(metadata), ..ExposerOptions::default() });
+        exposition.update(&[
+            AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("api.requests"), 3),
+            AggregatedMetric::Gauge(UNIX_EPOCH, MetricId::from("queue"), 5.0),
+            AggregatedMetric::Summary(UNIX_EPOCH, MetricId::from("latency"), Summary { count: 1, sum: 2.0, quantiles: vec![] }),
+        ]);
+        assert_eq!(exposition.render(), concat!(
+            "# HELP api_requests_total Requests served\\nso far\n",
+            "# TYPE api_requests_total counter\n",
+            "api_requests_total 3\n",
+            "# HELP latency Request latency (unit: ms)\n",
+            "# TYPE latency summary\n",
+            "latency_count 1\n",
+            "latency_sum 2\n",
+            "# HELP queue Unit: bytes\n",
+            "# TYPE queue gauge\n",
+            "queue 5\n",
+        ));
+    }
+
+    #[test]
+    fn it_stops_exposing_stale_series() {
+        let mut exposition = Exposition::with_options(ExposerOptions { stale_after: Some(2), ..ExposerOptions::default() });
+        exposition.update(&[
+            AggregatedMetric::Gauge(UNIX_EPOCH, MetricId::from("queue"), 1.0),
+            AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("requests"), 1),
+        ]);
+        exposition.update(&[AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("requests"), 1)]);
+        assert!(exposition.render().contains("queue 1\n"));
+        exposition.update(&[AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("requests"), 1)]);
+        assert_eq!(exposition.render(), "# TYPE requests_total counter\nrequests_total 3\n");
+    }
+}
@@ -0,0 +1,281 @@
+//! Pushes aggregated samples to a Prometheus remote-write endpoint (eg.
+//! Cortex, Mimir, or Thanos receive) as snappy-compressed protobuf
+//! `WriteRequest`s. Metrics are flattened the same way they're stored;
+//! counts are sent as running totals so `rate()` works on them.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use snap::raw::Encoder;
+
+use super::super::db::{AggregatedKey, AggregatedMetric, Timeseries};
+use super::super::metric::MetricId;
+use super::super::util::{http, protobuf};
+use super::delivery::{self, BufferedSender, Delivery, DeliveryOptions};
+use super::exporter::Exporter;
+use super::sanitize::Sanitizer;
+
+#[derive(Default)]
+pub struct RemoteWriteOptions {
+    /// Defaults to `/api/v1/write`.
+    pub path: Option<String>,
+    /// Extra request headers, eg. `X-Scope-OrgID` or authentication.
+    pub headers: Vec<(String, String)>,
+    /// Defaults to 10 seconds.
+    pub timeout: Option<Duration>,
+    /// Number of requests sent in parallel for each aggregation; series are
+    /// assigned to a shard by the hash of their identifier. Defaults to 1.
+    pub shards: Option<usize>,
+    /// Defaults to 500.
+    pub max_samples_per_request: Option<usize>,
+    /// How many times a request that got a 429 or 5xx, or couldn't be sent
+    /// at all, is retried. Defaults to 3.
+    pub max_retries: Option<u32>,
+    /// Wait before the first retry, doubled for each one after it. Defaults
+    /// to 100 milliseconds.
+    pub retry_backoff: Option<Duration>,
+    /// How aggregations are buffered while the endpoint is unreachable.
+    pub delivery: DeliveryOptions,
+}
+
+/// Where requests go; shared by the shards' threads.
+struct Endpoint {
+    addr: SocketAddr,
+    host: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    timeout: Duration,
+    max_retries: u32,
+    retry_backoff: Duration,
+}
+
+impl Endpoint {
+    fn post(&self, series: &[(MetricId, Timeseries)]) -> io::Result<()> {
+        let body = Encoder::new().compress_vec(&encode(series)).map_err(io::Error::other)?;
+        let mut headers = vec![
+            ("Content-Type", "application/x-protobuf"),
+            ("Content-Encoding", "snappy"),
+            ("X-Prometheus-Remote-Write-Version", "0.1.0"),
+        ];
+        headers.extend(self.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            let error = match http::post(&self.addr, &self.host, &self.path, &headers, &body, self.timeout) {
+                Ok(200..=299) => return Ok(()),
+                Ok(status) if status == 429 || status >= 500 => io::Error::other(format!("remote write responded with {}", status)),
+                Ok(status) => return Err(delivery::rejected(format!("remote write rejected samples with {}", status))),
+                Err(err) => err,
+            };
+            if attempt >= self.max_retries {
+                return Err(error)
+            }
+            thread::sleep(backoff);
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+pub struct RemoteWriteSender {
+    subscription: Receiver<Arc<Vec<AggregatedMetric>>>,
+    endpoint: Endpoint,
+    shards: usize,
+    max_samples_per_request: usize,
+    /// Running total of every count series.
+    totals: HashMap<MetricId, f64>,
+    delivery: Delivery,
+}
+
+impl RemoteWriteSender {
+    /// `host` is both where to connect (eg. `mimir:9009`) and the `Host`
+    /// header.
+    pub fn new(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, host: &str, options: RemoteWriteOptions) -> Result<RemoteWriteSender, io::Error> {
+        let addr = super::resolve(host)?;
+
+        Ok(RemoteWriteSender {
+            subscription,
+            endpoint: Endpoint {
+                addr,
+                host: host.to_string(),
+                path: options.path.unwrap_or_else(|| "/api/v1/write".to_string()),
+                headers: options.headers,
+                timeout: options.timeout.unwrap_or_else(|| Duration::from_secs(10)),
+                max_retries: options.max_retries.unwrap_or(3),
+                retry_backoff: options.retry_backoff.unwrap_or_else(|| Duration::from_millis(100)),
+            },
+            shards: options.shards.unwrap_or(1).max(1),
+            max_samples_per_request: options.max_samples_per_request.unwrap_or(500).max(1),
+            totals: HashMap::new(),
+            delivery: Delivery::new(options.delivery),
+        })
+    }
+
+    /// Send one aggregation, split into shards and batches. Returns the
+    /// first error if any batch couldn't be delivered, in which case the
+    /// whole aggregation can be retried.
+    pub fn write(&mut self, metrics: &[AggregatedMetric]) -> io::Result<()> {
+        let mut shards: Vec<Vec<(MetricId, Timeseries)>> = (0..self.shards).map(|_| vec![]).collect();
+        let sanitizer = Sanitizer::prometheus();
+        let mut totals = HashMap::new();
+        for metric in metrics {
+            for (key, (time, value)) in metric.entries() {
+                let value = match key {
+                    AggregatedKey::Count(ref id) => {
+                        let previous = self.totals.get(id).cloned().unwrap_or(0.0);
+                        let total = totals.entry(id.clone()).or_insert(previous);
+                        *total += value;
+                        *total
+                    },
+                    AggregatedKey::Gauge(_) => value,
+                };
+                let id = sanitizer.id(key.id());
+                shards[id.shard(self.shards)].push((id, (time, value)));
+            }
+        }
+
+        let endpoint = &self.endpoint;
+        let max_samples = self.max_samples_per_request;
+        let results = thread::scope(|scope| {
+            let handles = shards.iter()
+                .filter(|series| !series.is_empty())
+                .map(|series| scope.spawn(move || {
+                    for batch in series.chunks(max_samples) {
+                        endpoint.post(batch)?;
+                    }
+                    Ok(())
+                }))
+                .collect::<Vec<_>>();
+            handles.into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|_| Err(io::Error::other("remote write shard panicked"))))
+                .collect::<Vec<io::Result<()>>>()
+        });
+        results.into_iter().collect::<io::Result<()>>()?;
+
+        // Only once everything was delivered, so that retrying an aggregation
+        // doesn't count it twice.
+        self.totals.extend(totals);
+        Ok(())
+    }
+}
+
+impl Exporter for RemoteWriteSender {
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()> {
+        self.write(batch)
+    }
+}
+
+impl BufferedSender for RemoteWriteSender {
+    const BACKEND: &'static str = "remote write";
+
+    fn subscription(&self) -> &Receiver<Arc<Vec<AggregatedMetric>>> {
+        &self.subscription
+    }
+
+    fn delivery(&mut self) -> &mut Delivery {
+        &mut self.delivery
+    }
+}
+
+/// Encode a `WriteRequest` with one `TimeSeries` of one sample per point.
+/// Labels are the dimensions plus `__name__`, sorted by name.
+pub fn encode(series: &[(MetricId, Timeseries)]) -> Vec<u8> {
+    let mut request = vec![];
+    for &(ref id, (time, value)) in series {
+        let mut labels: Vec<(&str, &str)> = vec![("__name__", id.name())];
+        labels.extend(id.dimensions().iter().map(|(key, value)| (&**key, &**value)));
+        labels.sort();
+
+        let mut timeseries = vec![];
+        for (name, value) in labels {
+            let mut label = vec![];
+            protobuf::bytes_field(&mut label, 1, name.as_bytes());
+            protobuf::bytes_field(&mut label, 2, value.as_bytes());
+            protobuf::bytes_field(&mut timeseries, 1, &label);
+        }
+        let mut sample = vec![];
+        protobuf::double_field(&mut sample, 1, value);
+        let millis = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        protobuf::varint_field(&mut sample, 2, millis);
+        protobuf::bytes_field(&mut timeseries, 2, &sample);
+
+        protobuf::bytes_field(&mut request, 1, &timeseries);
+    }
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+
+    use snap::raw::Decoder;
+
+    #[test]
+    fn it_encodes_write_requests() {
+        let id = MetricId::from("up").with_dimension("job", "a");
+        let encoded = encode(&[(id, (UNIX_EPOCH + Duration::from_millis(5), 1.0))]);
+        let mut expected = vec![0x0a, 39];
+        expected.extend_from_slice(&[0x0a, 14, 0x0a, 8]);
+        expected.extend_from_slice(b"__name__");
+        expected.extend_from_slice(&[0x12, 2]);
+        expected.extend_from_slice(b"up");
+        expected.extend_from_slice(&[0x0a, 8, 0x0a, 3]);
+        expected.extend_from_slice(b"job");
+        expected.extend_from_slice(&[0x12, 1, b'a']);
+        expected.extend_from_slice(&[0x12, 11, 0x09, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f, 0x10, 5]);
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn it_retries_server_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut bodies = vec![];
+            for status in &["503 Service Unavailable", "200 OK"] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim_end().is_empty() {
+                        break
+                    }
+                    if let Some(value) = header.strip_prefix("Content-Length: ") {
+                        length = value.trim_end().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(body);
+                write!(reader.get_mut(), "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            }
+            bodies
+        });
+
+        let (_, subscription) = channel();
+        let options = RemoteWriteOptions { retry_backoff: Some(Duration::from_millis(1)), ..RemoteWriteOptions::default() };
+        let mut sender = RemoteWriteSender::new(subscription, &addr, options).unwrap();
+        let count = AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("requests"), 3);
+        sender.write(std::slice::from_ref(&count)).unwrap();
+
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies[0], bodies[1]);
+        let decoded = Decoder::new().decompress_vec(&bodies[1]).unwrap();
+        assert_eq!(decoded, encode(&[(MetricId::from("requests"), (UNIX_EPOCH, 3.0))]));
+
+        // Counts accumulate across aggregations.
+        assert_eq!(sender.totals[&MetricId::from("requests")], 3.0);
+    }
+}
@@ -0,0 +1,252 @@
+//! The protobuf representation of aggregated metrics, defined by
+//! `proto/metriqs/v1/metrics.proto`, shared by the senders that write
+//! protobuf and by anything reading it back, eg. another agent. Consumers
+//! in other languages can generate their types from the schema.
+//!
+//! `batch` encodes a whole aggregation as a `MetricBatch` and `metric` a
+//! single `AggregatedMetric`; the decoders skip fields they don't know so
+//! that they keep reading batches written against a newer schema.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use string_cache::DefaultAtom as Atom;
+
+use super::super::db::{AggregatedMetric, DdSketch};
+use super::super::metric::{MetricId, Summary};
+use super::super::util::protobuf::{self, Reader};
+
+pub fn batch(metrics: &[AggregatedMetric]) -> Vec<u8> {
+    let mut batch = vec![];
+    for aggregated in metrics {
+        protobuf::bytes_field(&mut batch, 1, &metric(aggregated));
+    }
+    batch
+}
+
+pub fn metric(metric: &AggregatedMetric) -> Vec<u8> {
+    let (time, id) = match *metric {
+        AggregatedMetric::Count(time, ref id, _) |
+        AggregatedMetric::Gauge(time, ref id, _) |
+        AggregatedMetric::Summary(time, ref id, _) |
+        AggregatedMetric::Sketch(time, ref id, _) => (time, id),
+    };
+    let mut buf = vec![];
+    let nanos = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    protobuf::varint_field(&mut buf, 1, nanos);
+    protobuf::bytes_field(&mut buf, 2, &metric_id(id));
+    match *metric {
+        AggregatedMetric::Count(_, _, value) => protobuf::varint_field(&mut buf, 3, value as u64),
+        AggregatedMetric::Gauge(_, _, value) => protobuf::double_field(&mut buf, 4, value),
+        AggregatedMetric::Summary(_, _, ref summary) => protobuf::bytes_field(&mut buf, 5, &self::summary(summary)),
+        AggregatedMetric::Sketch(_, _, ref sketch) => protobuf::bytes_field(&mut buf, 6, &self::sketch(sketch)),
+    }
+    buf
+}
+
+fn metric_id(id: &MetricId) -> Vec<u8> {
+    let mut buf = vec![];
+    protobuf::bytes_field(&mut buf, 1, id.name().as_bytes());
+    for (key, value) in id.dimensions() {
+        let mut dimension = vec![];
+        protobuf::bytes_field(&mut dimension, 1, key.as_bytes());
+        protobuf::bytes_field(&mut dimension, 2, value.as_bytes());
+        protobuf::bytes_field(&mut buf, 2, &dimension);
+    }
+    buf
+}
+
+fn summary(summary: &Summary) -> Vec<u8> {
+    let mut buf = vec![];
+    protobuf::varint_field(&mut buf, 1, summary.count);
+    protobuf::double_field(&mut buf, 2, summary.sum);
+    for &(quantile, value) in &summary.quantiles {
+        let mut pair = vec![];
+        protobuf::double_field(&mut pair, 1, quantile);
+        protobuf::double_field(&mut pair, 2, value);
+        protobuf::bytes_field(&mut buf, 3, &pair);
+    }
+    buf
+}
+
+fn sketch(sketch: &DdSketch) -> Vec<u8> {
+    let mut buf = vec![];
+    protobuf::double_field(&mut buf, 1, sketch.relative_accuracy());
+    for (field, bins) in &[(2, sketch.positive_bins()), (3, sketch.negative_bins())] {
+        for (&key, &count) in bins.iter() {
+            let mut bin = vec![];
+            protobuf::varint_field(&mut bin, 1, i64::from(key) as u64);
+            protobuf::varint_field(&mut bin, 2, count);
+            protobuf::bytes_field(&mut buf, *field, &bin);
+        }
+    }
+    protobuf::varint_field(&mut buf, 4, sketch.zero_count());
+    protobuf::double_field(&mut buf, 5, sketch.sum());
+    if let (Some(min), Some(max)) = (sketch.min(), sketch.max()) {
+        protobuf::double_field(&mut buf, 6, min);
+        protobuf::double_field(&mut buf, 7, max);
+    }
+    buf
+}
+
+pub fn decode_batch(bytes: &[u8]) -> io::Result<Vec<AggregatedMetric>> {
+    let mut reader = Reader::new(bytes);
+    let mut metrics = vec![];
+    while let Some((field, value)) = reader.field()? {
+        if field == 1 {
+            metrics.push(decode_metric(value.bytes()?)?)
+        }
+    }
+    Ok(metrics)
+}
+
+pub fn decode_metric(bytes: &[u8]) -> io::Result<AggregatedMetric> {
+    let mut reader = Reader::new(bytes);
+    let (mut time, mut id, mut metric) = (UNIX_EPOCH, None, None);
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => time = UNIX_EPOCH + Duration::from_nanos(value.varint()?),
+            2 => id = Some(decode_metric_id(value.bytes()?)?),
+            3 => metric = Some(Value::Count(value.varint()? as i64)),
+            4 => metric = Some(Value::Gauge(value.double()?)),
+            5 => metric = Some(Value::Summary(decode_summary(value.bytes()?)?)),
+            6 => metric = Some(Value::Sketch(decode_sketch(value.bytes()?)?)),
+            _ => {},
+        }
+    }
+    let id = id.ok_or_else(|| invalid("metric without an id"))?;
+    match metric {
+        Some(value) => Ok(value.into_metric(time, id)),
+        None => Err(invalid("metric without a value")),
+    }
+}
+
+/// The `value` oneof, which can come before the metric's id.
+enum Value {
+    Count(i64),
+    Gauge(f64),
+    Summary(Summary),
+    Sketch(DdSketch),
+}
+
+impl Value {
+    fn into_metric(self, time: SystemTime, id: MetricId) -> AggregatedMetric {
+        match self {
+            Value::Count(value) => AggregatedMetric::Count(time, id, value),
+            Value::Gauge(value) => AggregatedMetric::Gauge(time, id, value),
+            Value::Summary(summary) => AggregatedMetric::Summary(time, id, summary),
+            Value::Sketch(sketch) => AggregatedMetric::Sketch(time, id, sketch),
+        }
+    }
+}
+
+fn decode_metric_id(bytes: &[u8]) -> io::Result<MetricId> {
+    let mut reader = Reader::new(bytes);
+    let (mut name, mut dimensions) = ("", vec![]);
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => name = value.string()?,
+            2 => {
+                let mut reader = Reader::new(value.bytes()?);
+                let (mut key, mut value) = ("", "");
+                while let Some((field, field_value)) = reader.field()? {
+                    match field {
+                        1 => key = field_value.string()?,
+                        2 => value = field_value.string()?,
+                        _ => {},
+                    }
+                }
+                dimensions.push((Atom::from(key), Atom::from(value)));
+            },
+            _ => {},
+        }
+    }
+    Ok(MetricId::new(name, dimensions))
+}
+
+fn decode_summary(bytes: &[u8]) -> io::Result<Summary> {
+    let mut reader = Reader::new(bytes);
+    let mut summary = Summary { count: 0, sum: 0.0, quantiles: vec![] };
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => summary.count = value.varint()?,
+            2 => summary.sum = value.double()?,
+            3 => {
+                let mut reader = Reader::new(value.bytes()?);
+                let (mut quantile, mut value) = (0.0, 0.0);
+                while let Some((field, field_value)) = reader.field()? {
+                    match field {
+                        1 => quantile = field_value.double()?,
+                        2 => value = field_value.double()?,
+                        _ => {},
+                    }
+                }
+                summary.quantiles.push((quantile, value));
+            },
+            _ => {},
+        }
+    }
+    Ok(summary)
+}
+
+fn decode_sketch(bytes: &[u8]) -> io::Result<DdSketch> {
+    let mut reader = Reader::new(bytes);
+    let (mut relative_accuracy, mut zero_count, mut sum) = (0.0, 0, 0.0);
+    let (mut positive, mut negative) = (BTreeMap::new(), BTreeMap::new());
+    let (mut min, mut max) = (f64::INFINITY, f64::NEG_INFINITY);
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => relative_accuracy = value.double()?,
+            2 | 3 => {
+                let mut reader = Reader::new(value.bytes()?);
+                let (mut key, mut count) = (0, 0);
+                while let Some((field, field_value)) = reader.field()? {
+                    match field {
+                        1 => key = field_value.varint()? as i64 as i32,
+                        2 => count = field_value.varint()?,
+                        _ => {},
+                    }
+                }
+                let bins = if field == 2 { &mut positive } else { &mut negative };
+                *bins.entry(key).or_insert(0) += count;
+            },
+            4 => zero_count = value.varint()?,
+            5 => sum = value.double()?,
+            6 => min = value.double()?,
+            7 => max = value.double()?,
+            _ => {},
+        }
+    }
+    Ok(DdSketch::from_parts(relative_accuracy, positive, negative, zero_count, sum, min, max))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_batches() {
+        let time = UNIX_EPOCH + Duration::from_millis(1500);
+        let id = MetricId::from("api.latency").with_dimension("host", "a");
+        let mut sketch = DdSketch::new(0.01);
+        for value in &[-2.0, 0.0, 0.5, 12.0] {
+            sketch.insert(*value)
+        }
+        let metrics = vec![
+            AggregatedMetric::Count(time, id.clone(), -3),
+            AggregatedMetric::Gauge(time, MetricId::from("queue"), 2.5),
+            AggregatedMetric::Summary(time, id.clone(), Summary { count: 3, sum: 6.0, quantiles: vec![(0.5, 2.0), (0.99, 3.0)] }),
+            AggregatedMetric::Sketch(time, id.clone(), sketch),
+            AggregatedMetric::Sketch(time, id, DdSketch::new(0.02)),
+        ];
+        assert_eq!(decode_batch(&batch(&metrics)).unwrap(), metrics);
+
+        let gauge = metric(&metrics[1]);
+        assert!(decode_metric(&gauge[..gauge.len() - 1]).is_err());
+    }
+}
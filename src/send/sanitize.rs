@@ -0,0 +1,155 @@
+//! Backends differ in which characters are legal in metric names and
+//! dimensions: Prometheus forbids `.`, Graphite forbids spaces, Datadog caps
+//! the length. A `Sanitizer` rewrites identifiers for one backend so that a
+//! single ingest pipeline can feed several of them.
+
+use string_cache::DefaultAtom as Atom;
+
+use super::super::db::AggregatedMetric;
+use super::super::metric::MetricId;
+
+/// Whether a character is legal at the given position.
+pub type CharRule = fn(char, usize) -> bool;
+
+#[derive(Clone, Copy)]
+pub struct Sanitizer {
+    name: CharRule,
+    dimension_key: CharRule,
+    /// Dimension values are left alone when this is `None`.
+    dimension_value: Option<CharRule>,
+    replacement: char,
+    max_length: Option<usize>,
+}
+
+impl Sanitizer {
+    pub fn new(name: CharRule, dimension_key: CharRule, dimension_value: Option<CharRule>, replacement: char, max_length: Option<usize>) -> Sanitizer {
+        Sanitizer {
+            name,
+            dimension_key,
+            dimension_value,
+            replacement,
+            max_length,
+        }
+    }
+
+    /// Names match `[a-zA-Z_:][a-zA-Z0-9_:]*` and label names
+    /// `[a-zA-Z_][a-zA-Z0-9_]*`; label values may be anything.
+    pub fn prometheus() -> Sanitizer {
+        fn name(c: char, index: usize) -> bool {
+            c.is_ascii_alphabetic() || c == '_' || c == ':' || (index > 0 && c.is_ascii_digit())
+        }
+        fn label(c: char, index: usize) -> bool {
+            c.is_ascii_alphabetic() || c == '_' || (index > 0 && c.is_ascii_digit())
+        }
+        Sanitizer::new(name, label, None, '_', None)
+    }
+
+    /// Whitespace and the characters Graphite uses as separators in tagged
+    /// series (`;`, `=`, `~`, `!`) are illegal everywhere.
+    pub fn graphite() -> Sanitizer {
+        fn legal(c: char, _: usize) -> bool {
+            !c.is_whitespace() && !c.is_control() && !";=~!^".contains(c)
+        }
+        Sanitizer::new(legal, legal, Some(legal), '_', None)
+    }
+
+    /// Names may contain alphanumerics and `-_./,~`; point tag keys
+    /// alphanumerics and `-_.`. Tag values are quoted so may be anything.
+    pub fn wavefront() -> Sanitizer {
+        fn name(c: char, _: usize) -> bool {
+            c.is_ascii_alphanumeric() || "-_./,~".contains(c)
+        }
+        fn tag(c: char, _: usize) -> bool {
+            c.is_ascii_alphanumeric() || "-_.".contains(c)
+        }
+        Sanitizer::new(name, tag, None, '_', None)
+    }
+
+    /// Names must start with a letter and contain only alphanumerics,
+    /// underscores, and periods; names and tags are capped at 200
+    /// characters.
+    pub fn datadog() -> Sanitizer {
+        fn name(c: char, index: usize) -> bool {
+            c.is_ascii_alphabetic() || (index > 0 && (c.is_ascii_digit() || c == '_' || c == '.'))
+        }
+        fn tag(c: char, _: usize) -> bool {
+            c.is_alphanumeric() || "_-:./".contains(c)
+        }
+        Sanitizer::new(name, tag, Some(tag), '_', Some(200))
+    }
+
+    pub fn name(&self, name: &str) -> String {
+        self.apply(name, self.name)
+    }
+
+    pub fn dimension_key(&self, key: &str) -> String {
+        self.apply(key, self.dimension_key)
+    }
+
+    pub fn id(&self, id: &MetricId) -> MetricId {
+        let dimensions = id.dimensions().iter()
+            .map(|(key, value)| {
+                let value = match self.dimension_value {
+                    Some(rule) => Atom::from(self.apply(value, rule)),
+                    None => value.clone(),
+                };
+                (Atom::from(self.dimension_key(key)), value)
+            })
+            .collect();
+        MetricId::new(self.name(id.name()), dimensions)
+    }
+
+    pub fn metric(&self, metric: &AggregatedMetric) -> AggregatedMetric {
+        use self::AggregatedMetric::*;
+
+        match *metric {
+            Count(time, ref id, value) => Count(time, self.id(id), value),
+            Gauge(time, ref id, value) => Gauge(time, self.id(id), value),
+            Summary(time, ref id, ref summary) => Summary(time, self.id(id), summary.clone()),
+            Sketch(time, ref id, ref sketch) => Sketch(time, self.id(id), sketch.clone()),
+        }
+    }
+
+    fn apply(&self, input: &str, rule: CharRule) -> String {
+        let mut output = input.chars()
+            .enumerate()
+            .map(|(index, c)| if rule(c, index) { c } else { self.replacement })
+            .collect::<String>();
+        if let Some(max_length) = self.max_length {
+            if let Some((index, _)) = output.char_indices().nth(max_length) {
+                output.truncate(index)
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_sanitizes_for_prometheus() {
+        let sanitizer = Sanitizer::prometheus();
+        assert_eq!(sanitizer.name("api.request-time"), "api_request_time");
+        assert_eq!(sanitizer.name("5xx"), "_xx");
+
+        let id = MetricId::new("a.b", vec![(Atom::from("status.code"), Atom::from("5.0"))]);
+        assert_eq!(
+            sanitizer.id(&id),
+            MetricId::new("a_b", vec![(Atom::from("status_code"), Atom::from("5.0"))])
+        );
+    }
+
+    #[test]
+    fn it_sanitizes_for_graphite() {
+        assert_eq!(Sanitizer::graphite().name("api.request time"), "api.request_time");
+    }
+
+    #[test]
+    fn it_sanitizes_for_datadog() {
+        let sanitizer = Sanitizer::datadog();
+        assert_eq!(sanitizer.name("1api.request-time"), "_api.request_time");
+        assert_eq!(sanitizer.name(&"a".repeat(250)).len(), 200);
+    }
+}
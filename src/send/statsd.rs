@@ -0,0 +1,319 @@
+//! Re-emits metrics as StatsD lines to an upstream StatsD server or Datadog
+//! agent, so that many agents can roll up locally and forward to a central
+//! one. Dimensions are sent as DogStatsD tags (`|#key:value`).
+//!
+//! Aggregated counts are sent as `|c` and gauges as `|g`, but only ones the
+//! upstream can roll up again: it derives counts' `.rate`s itself, and
+//! histograms' percentiles and the like can't be rolled up again, so those
+//! series, sketches, and summaries are skipped. Forwarding raw metrics
+//! (see `StatsdSender::raw`) sends histograms' samples instead. Raw metrics
+//! are sent as they were received, except that monotonic counts and
+//! summaries have no StatsD equivalent and are skipped.
+
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use super::super::db::AggregatedMetric;
+use super::super::metric::{CollectedMetric, MetricId};
+use super::delivery::{self, Delivery, DeliveryOptions};
+use super::exporter::Exporter;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatsdTransport {
+    /// Lines are packed into datagrams of at most `max_packet_size` bytes.
+    Udp,
+    /// Reconnects whenever the connection is lost.
+    Tcp,
+}
+
+#[derive(Default)]
+pub struct StatsdOptions {
+    /// Defaults to UDP.
+    pub transport: Option<StatsdTransport>,
+    /// Whether to send dimensions as tags; defaults to true. StatsD servers
+    /// that don't understand tags need this off, which drops dimensions.
+    pub tags: Option<bool>,
+    /// Defaults to 1432 bytes, which fits in a typical ethernet frame.
+    pub max_packet_size: Option<usize>,
+    /// How aggregations are buffered while the server is unreachable. Raw
+    /// metrics aren't buffered.
+    pub delivery: DeliveryOptions,
+}
+
+enum Source {
+    Aggregated(Receiver<Arc<Vec<AggregatedMetric>>>),
+    Raw(Receiver<Vec<CollectedMetric>>),
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+pub struct StatsdSender {
+    source: Source,
+    addr: SocketAddr,
+    transport: StatsdTransport,
+    tags: bool,
+    max_packet_size: usize,
+    connection: Option<Connection>,
+    delivery: Delivery,
+}
+
+impl StatsdSender {
+    /// Forward each aggregation received from a Db subscription.
+    pub fn new<A: ToSocketAddrs>(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, addr: A, options: StatsdOptions) -> Result<StatsdSender, io::Error> {
+        StatsdSender::with_source(Source::Aggregated(subscription), addr, options)
+    }
+
+    /// Forward raw metrics before they're aggregated, as received from a
+    /// `Collector::tap`.
+    pub fn raw<A: ToSocketAddrs>(tap: Receiver<Vec<CollectedMetric>>, addr: A, options: StatsdOptions) -> Result<StatsdSender, io::Error> {
+        StatsdSender::with_source(Source::Raw(tap), addr, options)
+    }
+
+    fn with_source<A: ToSocketAddrs>(source: Source, addr: A, options: StatsdOptions) -> Result<StatsdSender, io::Error> {
+        let addr = super::resolve(addr)?;
+
+        Ok(StatsdSender {
+            source,
+            addr,
+            transport: options.transport.unwrap_or(StatsdTransport::Udp),
+            tags: options.tags.unwrap_or(true),
+            max_packet_size: options.max_packet_size.unwrap_or(1432),
+            connection: None,
+            delivery: Delivery::new(options.delivery),
+        })
+    }
+
+    /// Forward until the source hangs up; blocks the calling thread (see
+    /// `delivery::send`). Raw metrics that can't be delivered are dropped.
+    pub fn send(&mut self) {
+        delivery::send(self, "StatsD upstream", |sender| &mut sender.delivery, StatsdSender::receive, |sender, batch| {
+            let lines = sender.format(batch);
+            sender.write(&lines)
+        })
+    }
+
+    /// The next aggregation, forwarding raw metrics right away while
+    /// waiting for one.
+    fn receive(&mut self) -> Option<Arc<Vec<AggregatedMetric>>> {
+        loop {
+            let metrics = match self.source {
+                Source::Aggregated(ref subscription) => return subscription.recv().ok(),
+                Source::Raw(ref tap) => tap.recv().ok()?,
+            };
+            let lines = self.format_raw(&metrics);
+            if let Err(err) = self.write(&lines) {
+                error!("Error sending to StatsD upstream: {}", err)
+            }
+        }
+    }
+
+    /// Render aggregated metrics as StatsD lines, skipping ones derived
+    /// from others (see the module docs).
+    pub fn format(&self, metrics: &[AggregatedMetric]) -> Vec<String> {
+        let derived = derived(metrics);
+        let mut lines = vec![];
+        for metric in metrics {
+            match *metric {
+                AggregatedMetric::Count(_, ref id, value) if !derived.contains(id) => lines.push(self.line(id, &value.to_string(), "c")),
+                AggregatedMetric::Gauge(_, ref id, value) if !derived.contains(id) => self.push_gauge(&mut lines, id, value),
+                _ => {},
+            }
+        }
+        lines
+    }
+
+    /// Render raw metrics as StatsD lines.
+    pub fn format_raw(&self, metrics: &[CollectedMetric]) -> Vec<String> {
+        let mut lines = vec![];
+        for metric in metrics {
+            match *metric {
+                CollectedMetric::Count(_, ref id, value) => lines.push(self.line(id, &value.to_string(), "c")),
+                CollectedMetric::Gauge(_, ref id, value) => self.push_gauge(&mut lines, id, value),
+                CollectedMetric::GaugeDelta(_, ref id, delta) => lines.push(self.line(id, &format!("{:+}", delta), "g")),
+                CollectedMetric::Histogram(_, ref id, value) => lines.push(self.line(id, &value.to_string(), "ms")),
+                // The sample rate goes before any tags.
+                CollectedMetric::SampledHistogram(_, ref id, value, weight) => {
+                    lines.push(self.line(id, &value.to_string(), &format!("ms|@{}", 1.0 / weight)))
+                },
+                CollectedMetric::Set(_, ref id, ref member) => lines.push(self.line(id, member, "s")),
+                CollectedMetric::MonotonicCount(..) |
+                CollectedMetric::Summary(..) => (),
+            }
+        }
+        lines
+    }
+
+    /// StatsD reads a signed gauge as a change to the current value, so a
+    /// negative one is sent as a reset to zero followed by the decrement.
+    fn push_gauge(&self, lines: &mut Vec<String>, id: &MetricId, value: f64) {
+        if value < 0.0 {
+            lines.push(self.line(id, "0", "g"));
+        }
+        lines.push(self.line(id, &value.to_string(), "g"))
+    }
+
+    fn line(&self, id: &MetricId, value: &str, kind: &str) -> String {
+        let mut line = format!("{}:{}|{}", id.name(), value, kind);
+        if self.tags && !id.dimensions().is_empty() {
+            let tags = id.dimensions().iter()
+                .map(|(key, value)| format!("{}:{}", key, value))
+                .collect::<Vec<String>>();
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+        line
+    }
+
+    /// Send lines, connecting first if needed.
+    pub fn write(&mut self, lines: &[String]) -> io::Result<()> {
+        if lines.is_empty() {
+            return Ok(())
+        }
+        if self.connection.is_none() {
+            self.connection = Some(self.connect()?);
+        }
+
+        let result = match *self.connection.as_mut().unwrap() {
+            Connection::Udp(ref socket) => {
+                let mut packet = String::new();
+                for line in lines {
+                    if !packet.is_empty() && packet.len() + 1 + line.len() > self.max_packet_size {
+                        socket.send(packet.as_bytes())?;
+                        packet.clear();
+                    }
+                    if !packet.is_empty() {
+                        packet.push('\n');
+                    }
+                    packet.push_str(line);
+                }
+                socket.send(packet.as_bytes()).map(|_| ())
+            },
+            Connection::Tcp(ref mut stream) => {
+                let mut buf = lines.join("\n");
+                buf.push('\n');
+                stream.write_all(buf.as_bytes()).and_then(|_| stream.flush())
+            },
+        };
+        if result.is_err() {
+            self.connection = None
+        }
+        result
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        match self.transport {
+            StatsdTransport::Udp => {
+                let local = if self.addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(self.addr)?;
+                Ok(Connection::Udp(socket))
+            },
+            StatsdTransport::Tcp => {
+                Ok(Connection::Tcp(TcpStream::connect_timeout(&self.addr, Duration::from_secs(5))?))
+            },
+        }
+    }
+}
+
+impl Exporter for StatsdSender {
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()> {
+        let lines = self.format(batch);
+        self.write(&lines)
+    }
+}
+
+/// The series of an aggregation derived from others: counts' `.rate`
+/// gauges and every series of a histogram rolled up into a summary, which
+/// always has a `.median`.
+fn derived(metrics: &[AggregatedMetric]) -> HashSet<MetricId> {
+    let base = |id: &MetricId| id.name().rsplit_once('.').map(|(name, suffix)| (id.with_name(name), suffix.to_string()));
+    let mut counts = HashSet::new();
+    let mut histograms = HashSet::new();
+    for metric in metrics {
+        match *metric {
+            AggregatedMetric::Count(_, ref id, _) => {
+                counts.insert(id.clone());
+            },
+            AggregatedMetric::Gauge(_, ref id, _) => if let Some((histogram, suffix)) = base(id) {
+                if suffix == "median" {
+                    histograms.insert(histogram);
+                }
+            },
+            _ => {},
+        }
+    }
+    metrics.iter()
+        .map(|metric| metric.id())
+        .filter(|id| match base(&id.without_dimension("le")) {
+            Some((base, suffix)) => histograms.contains(&base) || (suffix == "rate" && counts.contains(&base)),
+            None => false,
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn it_forwards_over_udp() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (_, subscription) = channel();
+        let mut sender = StatsdSender::new(subscription, upstream.local_addr().unwrap(), StatsdOptions::default()).unwrap();
+
+        let lines = sender.format(&[
+            AggregatedMetric::Count(UNIX_EPOCH, MetricId::from("requests").with_dimension("host", "a"), 3),
+            AggregatedMetric::Gauge(UNIX_EPOCH, MetricId::from("temperature"), -2.5),
+        ]);
+        sender.write(&lines).unwrap();
+
+        let mut buf = [0; 1500];
+        let length = upstream.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..length], &b"requests:3|c|#host:a\ntemperature:0|g\ntemperature:-2.5|g"[..]);
+    }
+
+    #[test]
+    fn it_skips_derived_series() {
+        let (_, subscription) = channel();
+        let sender = StatsdSender::new(subscription, "127.0.0.1:8125", StatsdOptions::default()).unwrap();
+
+        let (requests, latency) = (MetricId::from("requests"), MetricId::from("latency"));
+        let lines = sender.format(&[
+            AggregatedMetric::Gauge(UNIX_EPOCH, requests.with_suffix(".rate"), 0.3),
+            AggregatedMetric::Count(UNIX_EPOCH, requests, 3),
+            AggregatedMetric::Gauge(UNIX_EPOCH, MetricId::from("bytes.rate"), 2.0),
+            AggregatedMetric::Gauge(UNIX_EPOCH, latency.with_suffix(".max"), 5.0),
+            AggregatedMetric::Gauge(UNIX_EPOCH, latency.with_suffix(".median"), 1.0),
+            AggregatedMetric::Gauge(UNIX_EPOCH, latency.with_suffix(".99percentile"), 4.0),
+            AggregatedMetric::Count(UNIX_EPOCH, latency.with_suffix(".bucket").with_dimension("le", "1"), 2),
+            AggregatedMetric::Count(UNIX_EPOCH, latency.with_suffix(".count"), 4),
+        ]);
+        assert_eq!(lines, vec!["requests:3|c", "bytes.rate:2|g"]);
+    }
+
+    #[test]
+    fn it_formats_raw_metrics() {
+        let (_, tap) = channel();
+        let options = StatsdOptions { tags: Some(false), ..StatsdOptions::default() };
+        let sender = StatsdSender::raw(tap, "127.0.0.1:8125", options).unwrap();
+
+        let id = MetricId::from("api").with_dimension("host", "a");
+        let lines = sender.format_raw(&[
+            CollectedMetric::Histogram(UNIX_EPOCH, id.clone(), 12.5),
+            CollectedMetric::Set(UNIX_EPOCH, id.clone(), "user".to_string()),
+            CollectedMetric::MonotonicCount(UNIX_EPOCH, id, 100.0),
+        ]);
+        assert_eq!(lines, vec!["api:12.5|ms", "api:user|s"]);
+    }
+}
@@ -0,0 +1,45 @@
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// A TCP connection for line protocols that's opened on first use and
+/// reopened whenever it's lost.
+pub struct ReconnectingStream {
+    addr: SocketAddr,
+    connect_timeout: Duration,
+    stream: Option<TcpStream>,
+}
+
+impl ReconnectingStream {
+    pub fn new(addr: SocketAddr, connect_timeout: Duration) -> ReconnectingStream {
+        ReconnectingStream {
+            addr,
+            connect_timeout,
+            stream: None,
+        }
+    }
+
+    /// Write all of `bytes`, connecting first if needed. If the write fails
+    /// on an existing connection it's retried once on a new one.
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let reused = self.stream.is_some();
+        match self.write_once(bytes) {
+            Err(_) if reused => self.write_once(bytes),
+            result => result,
+        }
+    }
+
+    fn write_once(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.stream.is_none() {
+            self.stream = Some(TcpStream::connect_timeout(&self.addr, self.connect_timeout)?);
+        }
+        let result = {
+            let stream = self.stream.as_mut().unwrap();
+            stream.write_all(bytes).and_then(|_| stream.flush())
+        };
+        if result.is_err() {
+            self.stream = None
+        }
+        result
+    }
+}
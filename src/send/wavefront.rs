@@ -0,0 +1,150 @@
+//! Sends aggregated metrics to a Wavefront proxy in its wire format:
+//!
+//! ```text
+//! <name> <value> <timestamp> source=<source> [<key>="<value>" ...]
+//! ```
+//!
+//! One dimension is used as the point's source (`host` by default) and the
+//! rest become point tags.
+
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::super::db::AggregatedMetric;
+use super::delivery::{BufferedSender, Delivery, DeliveryOptions};
+use super::exporter::Exporter;
+use super::sanitize::Sanitizer;
+use super::tcp::ReconnectingStream;
+
+#[derive(Default)]
+pub struct WavefrontOptions {
+    /// Dimension whose value is the source; defaults to `host`.
+    pub source_dimension: Option<String>,
+    /// Source of points without that dimension; defaults to `metriqs`.
+    pub default_source: Option<String>,
+    /// Prepended to every name, eg. `prod.`.
+    pub prefix: Option<String>,
+    /// How long to wait when connecting to the proxy; defaults to 5
+    /// seconds.
+    pub connect_timeout: Option<Duration>,
+    /// How aggregations are buffered while the proxy is unreachable.
+    pub delivery: DeliveryOptions,
+}
+
+/// Sends each aggregation received from a subscription to a Wavefront
+/// proxy (usually on port 2878) over TCP, reconnecting whenever the
+/// connection is lost.
+pub struct WavefrontSender {
+    subscription: Receiver<Arc<Vec<AggregatedMetric>>>,
+    source_dimension: String,
+    default_source: String,
+    prefix: String,
+    stream: ReconnectingStream,
+    delivery: Delivery,
+}
+
+impl WavefrontSender {
+    pub fn new<A: ToSocketAddrs>(subscription: Receiver<Arc<Vec<AggregatedMetric>>>, addr: A, options: WavefrontOptions) -> Result<WavefrontSender, io::Error> {
+        let addr = super::resolve(addr)?;
+
+        Ok(WavefrontSender {
+            subscription,
+            source_dimension: options.source_dimension.unwrap_or_else(|| "host".to_string()),
+            default_source: options.default_source.unwrap_or_else(|| "metriqs".to_string()),
+            prefix: options.prefix.unwrap_or_default(),
+            stream: ReconnectingStream::new(addr, options.connect_timeout.unwrap_or_else(|| Duration::from_secs(5))),
+            delivery: Delivery::new(options.delivery),
+        })
+    }
+
+    /// Write one aggregation, connecting first if needed.
+    pub fn write(&mut self, metrics: &[AggregatedMetric]) -> io::Result<()> {
+        let lines = self.format(metrics);
+        if lines.is_empty() {
+            return Ok(())
+        }
+        self.stream.write(lines.as_bytes())
+    }
+
+    /// Render the wire format lines for an aggregation.
+    pub fn format(&self, metrics: &[AggregatedMetric]) -> String {
+        let sanitizer = Sanitizer::wavefront();
+        let mut lines = String::new();
+        for metric in metrics {
+            for (key, (time, value)) in metric.entries() {
+                if !value.is_finite() {
+                    continue
+                }
+                let id = key.id();
+                let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                let source = id.dimension(&self.source_dimension).map(|source| &**source).unwrap_or(&self.default_source);
+                lines.push_str(&format!(
+                    "{}{} {} {} source={}",
+                    self.prefix,
+                    sanitizer.name(id.name()),
+                    value,
+                    seconds,
+                    quote(source),
+                ));
+                for (key, value) in id.dimensions() {
+                    if **key != *self.source_dimension {
+                        lines.push_str(&format!(" {}={}", sanitizer.dimension_key(key), quote(value)));
+                    }
+                }
+                lines.push('\n');
+            }
+        }
+        lines
+    }
+}
+
+impl Exporter for WavefrontSender {
+    fn export(&mut self, batch: &[AggregatedMetric]) -> io::Result<()> {
+        self.write(batch)
+    }
+}
+
+impl BufferedSender for WavefrontSender {
+    const BACKEND: &'static str = "Wavefront";
+
+    fn subscription(&self) -> &Receiver<Arc<Vec<AggregatedMetric>>> {
+        &self.subscription
+    }
+
+    fn delivery(&mut self) -> &mut Delivery {
+        &mut self.delivery
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', " "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+
+    use super::super::super::metric::MetricId;
+
+    #[test]
+    fn it_formats_points() {
+        let (_, subscription) = channel();
+        let options = WavefrontOptions { prefix: Some("prod.".to_string()), ..WavefrontOptions::default() };
+        let sender = WavefrontSender::new(subscription, "127.0.0.1:2878", options).unwrap();
+
+        let time = UNIX_EPOCH + Duration::from_secs(10);
+        let lines = sender.format(&[
+            AggregatedMetric::Gauge(time, MetricId::from("cpu usage").with_dimension("host", "a").with_dimension("region", "us \"east\"").with_dimension("k8s/zone", "b"), 0.5),
+            AggregatedMetric::Count(time, MetricId::from("requests"), 3),
+        ]);
+        assert_eq!(lines, concat!(
+            "prod.cpu_usage 0.5 10 source=\"a\" k8s_zone=\"b\" region=\"us \\\"east\\\"\"\n",
+            "prod.requests 3 10 source=\"metriqs\"\n",
+        ));
+    }
+}
@@ -0,0 +1,438 @@
+//! `Serialize` and `Deserialize` for metrics, so that they can be written to
+//! and read from any serde format, eg. to forward them between agents or to
+//! spool them to disk:
+//!
+//! - `MetricId` is a struct of its `name` and its `dimensions` as a map.
+//!   Dimensions can be left out when deserializing.
+//! - `Summary` is a struct of its fields, and `DdSketch` a struct of what
+//!   its accessors report. Sketch bins are `[key, count]` pairs since not
+//!   every format allows integer map keys.
+//! - `CollectedMetric` and `AggregatedMetric` are externally tagged like
+//!   derived enums, eg. `{"Count": [time, id, 3]}`, with times as serde
+//!   represents a `SystemTime`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::SystemTime;
+
+use serde::de::{self, DeserializeSeed, EnumAccess, Expected, IgnoredAny, MapAccess, SeqAccess, Unexpected, VariantAccess, Visitor};
+use serde::ser::{SerializeStruct, SerializeTupleVariant};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use string_cache::DefaultAtom as Atom;
+
+use super::db::{AggregatedMetric, DdSketch};
+use super::metric::{CollectedMetric, Dimension, MetricId, Summary};
+
+impl Serialize for MetricId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("MetricId", 2)?;
+        state.serialize_field("name", &**self.name())?;
+        state.serialize_field("dimensions", &Dimensions(self.dimensions()))?;
+        state.end()
+    }
+}
+
+struct Dimensions<'a>(&'a [Dimension]);
+
+impl<'a> Serialize for Dimensions<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(key, value)| (&**key, &**value)))
+    }
+}
+
+impl<'de> Deserialize<'de> for MetricId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MetricId, D::Error> {
+        deserializer.deserialize_struct("MetricId", &["name", "dimensions"], MetricIdVisitor)
+    }
+}
+
+struct MetricIdVisitor;
+
+impl<'de> Visitor<'de> for MetricIdVisitor {
+    type Value = MetricId;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a metric name and dimensions")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<MetricId, A::Error> {
+        let name = element(&mut seq, 0, &self)?;
+        let dimensions = element(&mut seq, 1, &self)?;
+        Ok(metric_id(name, dimensions))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<MetricId, A::Error> {
+        let (mut name, mut dimensions) = (None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "name" => name = Some(map.next_value()?),
+                "dimensions" => dimensions = Some(map.next_value()?),
+                _ => { map.next_value::<IgnoredAny>()?; },
+            }
+        }
+        Ok(metric_id(required(name, "name")?, dimensions.unwrap_or_default()))
+    }
+}
+
+fn metric_id(name: String, dimensions: BTreeMap<String, String>) -> MetricId {
+    MetricId::new(name, dimensions.into_iter().map(|(key, value)| (Atom::from(key), Atom::from(value))).collect())
+}
+
+impl Serialize for Summary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Summary", 3)?;
+        state.serialize_field("count", &self.count)?;
+        state.serialize_field("sum", &self.sum)?;
+        state.serialize_field("quantiles", &self.quantiles)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Summary {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Summary, D::Error> {
+        deserializer.deserialize_struct("Summary", &["count", "sum", "quantiles"], SummaryVisitor)
+    }
+}
+
+struct SummaryVisitor;
+
+impl<'de> Visitor<'de> for SummaryVisitor {
+    type Value = Summary;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a summary")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Summary, A::Error> {
+        Ok(Summary {
+            count: element(&mut seq, 0, &self)?,
+            sum: element(&mut seq, 1, &self)?,
+            quantiles: element(&mut seq, 2, &self)?,
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Summary, A::Error> {
+        let (mut count, mut sum, mut quantiles) = (None, None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "count" => count = Some(map.next_value()?),
+                "sum" => sum = Some(map.next_value()?),
+                "quantiles" => quantiles = Some(map.next_value()?),
+                _ => { map.next_value::<IgnoredAny>()?; },
+            }
+        }
+        Ok(Summary {
+            count: required(count, "count")?,
+            sum: required(sum, "sum")?,
+            quantiles: required(quantiles, "quantiles")?,
+        })
+    }
+}
+
+const SKETCH_FIELDS: &[&str] = &["relative_accuracy", "positive", "negative", "zero_count", "sum", "min", "max"];
+
+impl Serialize for DdSketch {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("DdSketch", SKETCH_FIELDS.len())?;
+        state.serialize_field("relative_accuracy", &self.relative_accuracy())?;
+        state.serialize_field("positive", &Bins(self.positive_bins()))?;
+        state.serialize_field("negative", &Bins(self.negative_bins()))?;
+        state.serialize_field("zero_count", &self.zero_count())?;
+        state.serialize_field("sum", &self.sum())?;
+        state.serialize_field("min", &self.min())?;
+        state.serialize_field("max", &self.max())?;
+        state.end()
+    }
+}
+
+struct Bins<'a>(&'a BTreeMap<i32, u64>);
+
+impl<'a> Serialize for Bins<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for DdSketch {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DdSketch, D::Error> {
+        deserializer.deserialize_struct("DdSketch", SKETCH_FIELDS, SketchVisitor)
+    }
+}
+
+struct SketchVisitor;
+
+impl<'de> Visitor<'de> for SketchVisitor {
+    type Value = DdSketch;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a sketch")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<DdSketch, A::Error> {
+        let relative_accuracy = element(&mut seq, 0, &self)?;
+        let positive: Vec<(i32, u64)> = element(&mut seq, 1, &self)?;
+        let negative: Vec<(i32, u64)> = element(&mut seq, 2, &self)?;
+        let zero_count = element(&mut seq, 3, &self)?;
+        let sum = element(&mut seq, 4, &self)?;
+        let min: Option<f64> = element(&mut seq, 5, &self)?;
+        let max: Option<f64> = element(&mut seq, 6, &self)?;
+        Ok(sketch(relative_accuracy, positive, negative, zero_count, sum, min, max))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<DdSketch, A::Error> {
+        let (mut relative_accuracy, mut positive, mut negative, mut zero_count, mut sum) = (None, None, None, None, None);
+        let (mut min, mut max) = (None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "relative_accuracy" => relative_accuracy = Some(map.next_value()?),
+                "positive" => positive = Some(map.next_value()?),
+                "negative" => negative = Some(map.next_value()?),
+                "zero_count" => zero_count = Some(map.next_value()?),
+                "sum" => sum = Some(map.next_value()?),
+                "min" => min = map.next_value()?,
+                "max" => max = map.next_value()?,
+                _ => { map.next_value::<IgnoredAny>()?; },
+            }
+        }
+        Ok(sketch(
+            required(relative_accuracy, "relative_accuracy")?,
+            required(positive, "positive")?,
+            required(negative, "negative")?,
+            required(zero_count, "zero_count")?,
+            required(sum, "sum")?,
+            min,
+            max,
+        ))
+    }
+}
+
+/// `min` and `max` are `None` for empty sketches, and some formats leave
+/// them out altogether.
+fn sketch(relative_accuracy: f64, positive: Vec<(i32, u64)>, negative: Vec<(i32, u64)>, zero_count: u64, sum: f64, min: Option<f64>, max: Option<f64>) -> DdSketch {
+    DdSketch::from_parts(
+        relative_accuracy,
+        positive.into_iter().collect(),
+        negative.into_iter().collect(),
+        zero_count,
+        sum,
+        min.unwrap_or(f64::INFINITY),
+        max.unwrap_or(f64::NEG_INFINITY),
+    )
+}
+
+const COLLECTED_VARIANTS: &[&str] = &["Count", "MonotonicCount", "Gauge", "Histogram", "Set", "Summary", "GaugeDelta", "SampledHistogram"];
+
+impl Serialize for CollectedMetric {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let variant = Variant { name: "CollectedMetric", variants: COLLECTED_VARIANTS };
+        match *self {
+            CollectedMetric::Count(ref time, ref id, ref value) => variant.serialize(serializer, 0, time, id, value),
+            CollectedMetric::MonotonicCount(ref time, ref id, ref value) => variant.serialize(serializer, 1, time, id, value),
+            CollectedMetric::Gauge(ref time, ref id, ref value) => variant.serialize(serializer, 2, time, id, value),
+            CollectedMetric::Histogram(ref time, ref id, ref value) => variant.serialize(serializer, 3, time, id, value),
+            CollectedMetric::Set(ref time, ref id, ref member) => variant.serialize(serializer, 4, time, id, member),
+            CollectedMetric::Summary(ref time, ref id, ref summary) => variant.serialize(serializer, 5, time, id, summary),
+            CollectedMetric::GaugeDelta(ref time, ref id, ref delta) => variant.serialize(serializer, 6, time, id, delta),
+            CollectedMetric::SampledHistogram(ref time, ref id, value, weight) => variant.serialize(serializer, 7, time, id, &(value, weight)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CollectedMetric {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CollectedMetric, D::Error> {
+        deserializer.deserialize_enum("CollectedMetric", COLLECTED_VARIANTS, CollectedMetricVisitor)
+    }
+}
+
+struct CollectedMetricVisitor;
+
+impl<'de> Visitor<'de> for CollectedMetricVisitor {
+    type Value = CollectedMetric;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a collected metric")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<CollectedMetric, A::Error> {
+        let (index, access) = data.variant_seed(Variant { name: "CollectedMetric", variants: COLLECTED_VARIANTS })?;
+        Ok(match index {
+            0 => { let (time, id, value) = parts(access)?; CollectedMetric::Count(time, id, value) },
+            1 => { let (time, id, value) = parts(access)?; CollectedMetric::MonotonicCount(time, id, value) },
+            2 => { let (time, id, value) = parts(access)?; CollectedMetric::Gauge(time, id, value) },
+            3 => { let (time, id, value) = parts(access)?; CollectedMetric::Histogram(time, id, value) },
+            4 => { let (time, id, member) = parts(access)?; CollectedMetric::Set(time, id, member) },
+            5 => { let (time, id, summary) = parts(access)?; CollectedMetric::Summary(time, id, summary) },
+            6 => { let (time, id, delta) = parts(access)?; CollectedMetric::GaugeDelta(time, id, delta) },
+            _ => { let (time, id, (value, weight)) = parts(access)?; CollectedMetric::SampledHistogram(time, id, value, weight) },
+        })
+    }
+}
+
+const AGGREGATED_VARIANTS: &[&str] = &["Count", "Gauge", "Summary", "Sketch"];
+
+impl Serialize for AggregatedMetric {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let variant = Variant { name: "AggregatedMetric", variants: AGGREGATED_VARIANTS };
+        match *self {
+            AggregatedMetric::Count(ref time, ref id, ref value) => variant.serialize(serializer, 0, time, id, value),
+            AggregatedMetric::Gauge(ref time, ref id, ref value) => variant.serialize(serializer, 1, time, id, value),
+            AggregatedMetric::Summary(ref time, ref id, ref summary) => variant.serialize(serializer, 2, time, id, summary),
+            AggregatedMetric::Sketch(ref time, ref id, ref sketch) => variant.serialize(serializer, 3, time, id, sketch),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AggregatedMetric {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<AggregatedMetric, D::Error> {
+        deserializer.deserialize_enum("AggregatedMetric", AGGREGATED_VARIANTS, AggregatedMetricVisitor)
+    }
+}
+
+struct AggregatedMetricVisitor;
+
+impl<'de> Visitor<'de> for AggregatedMetricVisitor {
+    type Value = AggregatedMetric;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an aggregated metric")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<AggregatedMetric, A::Error> {
+        let (index, access) = data.variant_seed(Variant { name: "AggregatedMetric", variants: AGGREGATED_VARIANTS })?;
+        Ok(match index {
+            0 => { let (time, id, value) = parts(access)?; AggregatedMetric::Count(time, id, value) },
+            1 => { let (time, id, value) = parts(access)?; AggregatedMetric::Gauge(time, id, value) },
+            2 => { let (time, id, summary) = parts(access)?; AggregatedMetric::Summary(time, id, summary) },
+            _ => { let (time, id, sketch) = parts(access)?; AggregatedMetric::Sketch(time, id, sketch) },
+        })
+    }
+}
+
+/// A variant of one of the metric enums, all of which are a time, an
+/// identifier, and a value. Deserializes to the variant's index from either
+/// its name or its index, depending on the format.
+#[derive(Clone, Copy)]
+struct Variant {
+    name: &'static str,
+    variants: &'static [&'static str],
+}
+
+impl Variant {
+    fn serialize<S, V>(self, serializer: S, index: u32, time: &SystemTime, id: &MetricId, value: &V) -> Result<S::Ok, S::Error>
+        where S: Serializer, V: Serialize
+    {
+        let mut state = serializer.serialize_tuple_variant(self.name, index, self.variants[index as usize], 3)?;
+        state.serialize_field(time)?;
+        state.serialize_field(id)?;
+        state.serialize_field(value)?;
+        state.end()
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for Variant {
+    type Value = usize;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        deserializer.deserialize_identifier(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Variant {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a variant of {}", self.name)
+    }
+
+    fn visit_u64<E: de::Error>(self, index: u64) -> Result<usize, E> {
+        if index < self.variants.len() as u64 {
+            Ok(index as usize)
+        } else {
+            Err(E::invalid_value(Unexpected::Unsigned(index), &self))
+        }
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<usize, E> {
+        self.variants.iter()
+            .position(|variant| *variant == name)
+            .ok_or_else(|| E::unknown_variant(name, self.variants))
+    }
+}
+
+fn parts<'de, A, V>(access: A) -> Result<(SystemTime, MetricId, V), A::Error>
+    where A: VariantAccess<'de>, V: Deserialize<'de>
+{
+    access.tuple_variant(3, Parts(PhantomData))
+}
+
+struct Parts<V>(PhantomData<V>);
+
+impl<'de, V: Deserialize<'de>> Visitor<'de> for Parts<V> {
+    type Value = (SystemTime, MetricId, V);
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a time, a metric identifier, and a value")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let time = element(&mut seq, 0, &self)?;
+        let id = element(&mut seq, 1, &self)?;
+        let value = element(&mut seq, 2, &self)?;
+        Ok((time, id, value))
+    }
+}
+
+fn element<'de, A, T>(seq: &mut A, index: usize, expected: &dyn Expected) -> Result<T, A::Error>
+    where A: SeqAccess<'de>, T: Deserialize<'de>
+{
+    seq.next_element()?.ok_or_else(|| de::Error::invalid_length(index, expected))
+}
+
+fn required<T, E: de::Error>(value: Option<T>, field: &'static str) -> Result<T, E> {
+    value.ok_or_else(|| E::missing_field(field))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use toml::Value;
+
+    #[test]
+    fn it_round_trips_metrics() {
+        let time = UNIX_EPOCH + Duration::from_millis(1500);
+        let id = MetricId::from("api.latency").with_dimension("host", "a");
+        let mut sketch = DdSketch::new(0.01);
+        for value in &[-2.0, 0.0, 3.5, 12.0] {
+            sketch.insert(*value)
+        }
+        let summary = Summary { count: 3, sum: 6.0, quantiles: vec![(0.5, 2.0), (0.99, 3.0)] };
+        let collected = vec![
+            CollectedMetric::Count(time, id.clone(), 3),
+            CollectedMetric::Set(time, id.clone(), "user-1".to_string()),
+            CollectedMetric::Summary(time, id.clone(), summary.clone()),
+        ];
+        let aggregated = vec![
+            AggregatedMetric::Gauge(time, id.clone(), 2.5),
+            AggregatedMetric::Sketch(time, id.clone(), sketch),
+            AggregatedMetric::Sketch(time, id.clone(), DdSketch::new(0.01)),
+        ];
+
+        for metric in collected {
+            let value = Value::try_from(&metric).unwrap();
+            assert_eq!(value.try_into::<CollectedMetric>().unwrap(), metric);
+        }
+        for metric in aggregated {
+            let value = Value::try_from(&metric).unwrap();
+            assert_eq!(value.try_into::<AggregatedMetric>().unwrap(), metric);
+        }
+
+        let value = Value::try_from(&id).unwrap();
+        assert_eq!(value.get("dimensions").and_then(|dimensions| dimensions.get("host")), Some(&Value::from("a")));
+        let undimensioned: MetricId = toml::from_str(r#"name = "api.requests""#).unwrap();
+        assert_eq!(undimensioned, MetricId::from("api.requests"));
+    }
+}
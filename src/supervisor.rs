@@ -0,0 +1,86 @@
+//! Restarting workers that panic, eg. on a bug tripped by one odd sample,
+//! instead of leaving a half-working agent. Each panic is logged and
+//! counted as `metriqs.workers.panicked` with a `worker` dimension naming
+//! the worker, so that it shows up in the agent's own metrics.
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use super::metric::{CollectedMetric, MetricId};
+use super::recv::Collector;
+use super::util::Stop;
+
+/// How long after a panic a worker is restarted, so that one that panics
+/// right away doesn't spin.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Clones count panics to the same collector.
+#[derive(Clone)]
+pub struct Supervisor {
+    collector: Arc<Collector>,
+}
+
+impl Supervisor {
+    pub fn new(collector: Collector) -> Supervisor {
+        Supervisor { collector: Arc::new(collector) }
+    }
+
+    /// Run `work` until it returns, restarting it whenever it panics unless
+    /// `stop` has been stopped by then.
+    pub fn run<F>(&self, name: &str, stop: &Stop, mut work: F)
+        where F: FnMut()
+    {
+        loop {
+            let panic = match panic::catch_unwind(AssertUnwindSafe(&mut work)) {
+                Ok(()) => return,
+                Err(panic) => panic,
+            };
+            error!("{} panicked, restarting it: {}", name, panic_message(&*panic));
+            let id = MetricId::from("metriqs.workers.panicked").with_dimension("worker", name);
+            self.collector.push(vec![CollectedMetric::Count(SystemTime::now(), id, 1)]);
+            if !stop.sleep(RESTART_DELAY) {
+                return
+            }
+        }
+    }
+}
+
+/// What a caught panic's payload says, if it's a string.
+pub fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or("unknown cause", |message| message.as_str()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::db::{AggregatedMetric, Db, DbOptions};
+
+    #[test]
+    fn it_restarts_panicking_workers() {
+        let db = Db::new(DbOptions::default());
+        let subscription = db.aggregation_subscribe();
+        let supervisor = Supervisor::new(db.collector());
+
+        let mut runs = 0;
+        supervisor.run("listener[0]", &Stop::new(), || {
+            runs += 1;
+            if runs == 1 {
+                panic!("empty histogram")
+            }
+        });
+        assert_eq!(runs, 2);
+
+        db.aggregate(None);
+        let panicked = MetricId::from("metriqs.workers.panicked").with_dimension("worker", "listener[0]");
+        assert!(subscription.recv().unwrap().iter().any(|metric| match *metric {
+            AggregatedMetric::Count(_, ref id, 1) => *id == panicked,
+            _ => false,
+        }));
+    }
+}
@@ -0,0 +1,145 @@
+//! A page for operators to check at a glance that data is arriving,
+//! served by the admin server at `GET /ui`. It lists stored series with
+//! their latest value and a sparkline of their last hour, rendered on the
+//! server as HTML and SVG so that it needs no JavaScript, and refreshes
+//! itself every aggregation. `?name=` filters series by a name pattern
+//! (see `Glob`), eg. `/ui?name=api.*`.
+
+use std::fmt::Write;
+use std::time::{Duration, SystemTime};
+
+use super::db::{AggregatedKey, Db, Timeseries};
+use super::util::{time, Glob};
+use super::util::percent;
+
+/// Most series listed, so that a big Db doesn't make a page too big to
+/// render; narrower `name` patterns show the rest.
+const MAX_SERIES: usize = 500;
+
+/// How far back sparklines go.
+const SPARKLINE_SPAN: Duration = Duration::from_secs(60 * 60);
+
+const SPARKLINE_WIDTH: f64 = 120.0;
+const SPARKLINE_HEIGHT: f64 = 24.0;
+
+/// The page for a request to `target`, eg. `/ui?name=api.*`.
+pub fn page(db: &Db, target: &str) -> String {
+    let pattern = query_param(target, "name").unwrap_or_else(|| "*".to_string());
+    let glob = Glob::new(&pattern);
+    let now = SystemTime::now();
+    let series = db.latest().into_iter()
+        .filter(|(key, _)| glob.matches(key.id().name()))
+        .collect::<Vec<_>>();
+
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        concat!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">",
+            "<meta http-equiv=\"refresh\" content=\"{}\"><title>metriqs</title>",
+            "<style>body{{font:14px sans-serif}}td,th{{padding:2px 8px;text-align:left}}td.value{{text-align:right}}</style>",
+            "</head><body>\n<form><input name=\"name\" value=\"{}\"> <button>Filter</button></form>\n",
+            "<p>{} series, last aggregated {}.</p>\n",
+            "<table>\n<tr><th>Name</th><th>Dimensions</th><th>Type</th><th>Value</th><th>Time</th><th>Last hour</th></tr>\n",
+        ),
+        db.aggregation_interval().as_secs().max(1),
+        escape(&pattern),
+        series.len(),
+        time::iso8601(db.last_aggregation()),
+    );
+    for (key, (at, value)) in series.iter().take(MAX_SERIES) {
+        let (kind, id) = match *key {
+            AggregatedKey::Count(ref id) => ("count", id),
+            AggregatedKey::Gauge(ref id) => ("gauge", id),
+        };
+        let dimensions = id.dimensions().iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<String>>();
+        let points = db.iter_range(id, now - SPARKLINE_SPAN, now, None).collect::<Vec<_>>();
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td class=\"value\">{}</td><td>{}</td><td>{}</td></tr>",
+            escape(id.name()),
+            escape(&dimensions.join(", ")),
+            kind,
+            value,
+            time::iso8601(*at),
+            sparkline(&points, now),
+        );
+    }
+    html.push_str("</table>\n");
+    if series.len() > MAX_SERIES {
+        let _ = writeln!(html, "<p>Showing the first {}; filter by name to see the rest.</p>", MAX_SERIES);
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+/// An SVG line of `points` over the span up to `now`, scaled to their
+/// range of values.
+fn sparkline(points: &[Timeseries], now: SystemTime) -> String {
+    if points.is_empty() {
+        return String::new()
+    }
+    let start = now - SPARKLINE_SPAN;
+    let (min, max) = points.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &(_, value)| (min.min(value), max.max(value)));
+    let coordinates = points.iter()
+        .map(|&(time, value)| {
+            let x = time.duration_since(start).unwrap_or_default().as_secs_f64() / SPARKLINE_SPAN.as_secs_f64() * SPARKLINE_WIDTH;
+            // A flat line sits in the middle.
+            let y = if max > min { (max - value) / (max - min) * (SPARKLINE_HEIGHT - 2.0) + 1.0 } else { SPARKLINE_HEIGHT / 2.0 };
+            format!("{:.1},{:.1}", x.min(SPARKLINE_WIDTH), y)
+        })
+        .collect::<Vec<String>>();
+    format!(
+        "<svg width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\"><polyline fill=\"none\" stroke=\"steelblue\" points=\"{}\"/></svg>",
+        coordinates.join(" "),
+        w = SPARKLINE_WIDTH,
+        h = SPARKLINE_HEIGHT,
+    )
+}
+
+/// The decoded value of `key` in `target`'s query, where `+` is a space.
+fn query_param(target: &str, key: &str) -> Option<String> {
+    let query = target.split_once('?')?.1;
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|&(name, _)| name == key)
+        .and_then(|(_, value)| percent::decode(&value.replace('+', " ")))
+        .filter(|value| !value.is_empty())
+}
+
+fn escape(input: &str) -> String {
+    input.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::db::DbOptions;
+    use super::super::metric::{CollectedMetric, MetricId};
+
+    #[test]
+    fn it_lists_series_with_sparklines() {
+        let db = Db::new(DbOptions::default());
+        let now = SystemTime::now();
+        for (ago, value) in [(120, 1.0), (60, 3.0)].iter() {
+            db.collect(vec![
+                CollectedMetric::Gauge(now - Duration::from_secs(*ago), MetricId::from("queue").with_dimension("host", "<a>"), *value),
+                CollectedMetric::Gauge(now - Duration::from_secs(*ago), MetricId::from("other"), *value),
+            ]);
+            db.flush();
+        }
+
+        let page = page(&db, "/ui?name=qu%2A");
+        assert!(page.contains("<td>queue</td><td>host=&lt;a&gt;</td><td>gauge</td><td class=\"value\">3</td>"));
+        assert!(!page.contains("<td>other</td>"));
+        assert!(page.contains("<input name=\"name\" value=\"qu*\">"));
+        // Two points, the higher one at the top.
+        assert!(page.contains("points=\"116.0,23.0 118.0,1.0\""));
+    }
+}
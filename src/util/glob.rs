@@ -0,0 +1,69 @@
+use std::fmt;
+
+/// Shell-style pattern for matching metric names: `*` matches any run of
+/// characters (including none) and `?` matches exactly one.
+#[derive(Clone, PartialEq)]
+pub struct Glob {
+    pattern: Vec<char>,
+}
+
+impl Glob {
+    pub fn new<S: AsRef<str>>(pattern: S) -> Glob {
+        Glob {
+            pattern: pattern.as_ref().chars().collect(),
+        }
+    }
+
+    pub fn matches(&self, input: &str) -> bool {
+        let input = input.chars().collect::<Vec<char>>();
+        let (mut p, mut i) = (0, 0);
+        // Where to resume if the most recent `*` needs to match more.
+        let mut backtrack: Option<(usize, usize)> = None;
+
+        while i < input.len() {
+            match self.pattern.get(p) {
+                Some(&'*') => {
+                    backtrack = Some((p, i));
+                    p += 1;
+                },
+                Some(&c) if c == '?' || c == input[i] => {
+                    p += 1;
+                    i += 1;
+                },
+                _ => match backtrack {
+                    Some((star, matched)) => {
+                        p = star + 1;
+                        i = matched + 1;
+                        backtrack = Some((star, matched + 1));
+                    },
+                    None => return false,
+                },
+            }
+        }
+        self.pattern[p..].iter().all(|c| *c == '*')
+    }
+}
+
+impl fmt::Debug for Glob {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Glob({:?})", self.pattern.iter().collect::<String>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_matches_globs() {
+        assert!(Glob::new("web.*").matches("web.requests"));
+        assert!(Glob::new("web.*").matches("web."));
+        assert!(!Glob::new("web.*").matches("api.requests"));
+        assert!(Glob::new("*.count").matches("a.b.count"));
+        assert!(Glob::new("a.*.c*").matches("a.b.count"));
+        assert!(Glob::new("a?c").matches("abc"));
+        assert!(!Glob::new("a?c").matches("ac"));
+        assert!(Glob::new("exact").matches("exact"));
+        assert!(!Glob::new("exact").matches("exactly"));
+    }
+}
@@ -0,0 +1,21 @@
+//! Hashes that have to be stable across processes and builds, unlike
+//! `DefaultHasher`'s, or keyed ones for authentication.
+
+#[cfg(any(feature = "scram", feature = "sigv4"))]
+use hmac::{Hmac, Mac};
+#[cfg(any(feature = "scram", feature = "sigv4"))]
+use sha2::Sha256;
+
+/// 64-bit FNV-1a. Fast and simple, but it spreads similar inputs poorly,
+/// so mix the result further where that matters.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// HMAC-SHA-256 of `data` with `key`.
+#[cfg(any(feature = "scram", feature = "sigv4"))]
+pub fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
@@ -0,0 +1,18 @@
+//! Finding out about the host an agent runs on, eg. for dimensions that
+//! tell agents apart.
+
+/// The host's name, or None if it can't be found out.
+#[cfg(unix)]
+pub fn hostname() -> Option<String> {
+    let mut name = [0u8; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+        return None
+    }
+    let len = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+    String::from_utf8(name[..len].to_vec()).ok().filter(|name| !name.is_empty())
+}
+
+#[cfg(not(unix))]
+pub fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok().filter(|name| !name.is_empty())
+}
@@ -0,0 +1,337 @@
+//! Just enough HPACK (RFC 7541) for the HTTP/2 server: header blocks are
+//! decoded with the static and dynamic tables and Huffman-coded strings,
+//! and headers are encoded as plain literals that aren't indexed, which
+//! every decoder accepts and which leaves the peer's table untouched.
+
+use std::collections::VecDeque;
+use std::io;
+
+/// The table size HTTP/2 starts with, and that the server never raises.
+const MAX_TABLE_SIZE: usize = 4096;
+
+/// Each entry's size counts its name and value plus this much overhead.
+const ENTRY_OVERHEAD: usize = 32;
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Length in bits of the Huffman code of each byte, and of end-of-string
+/// last. The code is canonical, so the codes themselves follow from their
+/// lengths.
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28,
+    28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6,
+    5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10,
+    13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6,
+    15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5,
+    6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28,
+    20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23,
+    24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24,
+    22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23,
+    21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23,
+    26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25,
+    19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27,
+    20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23,
+    26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+    30,
+];
+
+/// Decodes the header blocks of one connection, which share the dynamic
+/// table.
+pub struct Decoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    huffman: Huffman,
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder { table: VecDeque::new(), size: 0, max_size: MAX_TABLE_SIZE, huffman: Huffman::new() }
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    pub fn decode(&mut self, block: &[u8]) -> io::Result<Vec<(String, String)>> {
+        let mut headers = vec![];
+        let mut buf = block;
+        while let Some(&first) = buf.first() {
+            if first & 0x80 != 0 {
+                // Indexed.
+                let index = integer(&mut buf, 7)?;
+                headers.push(self.entry(index)?);
+            } else if first & 0x40 != 0 {
+                // Literal, added to the table.
+                let header = self.literal(&mut buf, 6)?;
+                self.insert(header.clone());
+                headers.push(header);
+            } else if first & 0x20 != 0 {
+                let size = integer(&mut buf, 5)?;
+                if size > MAX_TABLE_SIZE {
+                    return Err(invalid("table size update is too large"))
+                }
+                self.max_size = size;
+                self.evict(0);
+            } else {
+                // Literal, not indexed or never indexed.
+                let header = self.literal(&mut buf, 4)?;
+                headers.push(header);
+            }
+        }
+        Ok(headers)
+    }
+
+    fn literal(&self, buf: &mut &[u8], prefix: u8) -> io::Result<(String, String)> {
+        let index = integer(buf, prefix)?;
+        let name = if index == 0 { self.string(buf)? } else { self.entry(index)?.0 };
+        Ok((name, self.string(buf)?))
+    }
+
+    fn entry(&self, index: usize) -> io::Result<(String, String)> {
+        if index == 0 {
+            return Err(invalid("header index 0"))
+        }
+        if let Some(&(name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok((name.to_string(), value.to_string()))
+        }
+        self.table.get(index - 1 - STATIC_TABLE.len())
+            .cloned()
+            .ok_or_else(|| invalid("header index out of range"))
+    }
+
+    fn insert(&mut self, header: (String, String)) {
+        let size = header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // An entry larger than the whole table empties it and isn't added.
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(header);
+        }
+    }
+
+    /// Evict the oldest entries until there's room for `size` more.
+    fn evict(&mut self, size: usize) {
+        while self.size + size > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+
+    fn string(&self, buf: &mut &[u8]) -> io::Result<String> {
+        let huffman = buf.first().is_some_and(|first| first & 0x80 != 0);
+        let len = integer(buf, 7)?;
+        if len > buf.len() {
+            return Err(invalid("header block is truncated"))
+        }
+        let (bytes, rest) = buf.split_at(len);
+        *buf = rest;
+        let bytes = if huffman { self.huffman.decode(bytes)? } else { bytes.to_vec() };
+        String::from_utf8(bytes).map_err(|_| invalid("header isn't UTF-8"))
+    }
+}
+
+/// Encode a header as a literal without indexing and without Huffman
+/// coding. Names must be lowercase, as HTTP/2 requires.
+pub fn encode(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.push(0);
+    for string in &[name, value] {
+        encode_integer(buf, 7, string.len());
+        buf.extend_from_slice(string.as_bytes());
+    }
+}
+
+/// An integer with a `prefix`-bit prefix, the rest of the first byte
+/// being zero.
+fn encode_integer(buf: &mut Vec<u8>, prefix: u8, mut value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        buf.push(value as u8);
+        return
+    }
+    buf.push(max as u8);
+    value -= max;
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8)
+}
+
+fn integer(buf: &mut &[u8], prefix: u8) -> io::Result<usize> {
+    let max = (1usize << prefix) - 1;
+    let (&first, mut rest) = buf.split_first().ok_or_else(|| invalid("header block is truncated"))?;
+    let mut value = first as usize & max;
+    if value == max {
+        let mut shift = 0;
+        loop {
+            let (&byte, tail) = rest.split_first().ok_or_else(|| invalid("header block is truncated"))?;
+            rest = tail;
+            if shift > 28 {
+                return Err(invalid("integer is too large"))
+            }
+            value += ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte < 0x80 {
+                break
+            }
+        }
+    }
+    *buf = rest;
+    Ok(value)
+}
+
+/// Canonical decoding: codes of each length are consecutive, starting
+/// from the code after the last one of the previous length, doubled.
+struct Huffman {
+    /// Symbols ordered by code.
+    symbols: Vec<u16>,
+    /// For each length, its first code and that code's index in `symbols`.
+    first: [(u32, usize); 31],
+    counts: [usize; 31],
+}
+
+impl Huffman {
+    fn new() -> Huffman {
+        let mut symbols = (0..HUFFMAN_LENGTHS.len() as u16).collect::<Vec<u16>>();
+        symbols.sort_by_key(|&symbol| (HUFFMAN_LENGTHS[symbol as usize], symbol));
+        let mut counts = [0; 31];
+        for &length in HUFFMAN_LENGTHS.iter() {
+            counts[length as usize] += 1;
+        }
+        let (mut first, mut code, mut index) = ([(0, 0); 31], 0u32, 0);
+        for length in 1..31 {
+            first[length] = (code, index);
+            code = (code + counts[length] as u32) << 1;
+            index += counts[length];
+        }
+        Huffman { symbols, first, counts }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoded = vec![];
+        let (mut code, mut length) = (0u32, 0);
+        for &byte in bytes {
+            for bit in (0..8).rev() {
+                code = (code << 1) | u32::from((byte >> bit) & 1);
+                length += 1;
+                let (first, index) = self.first[length];
+                if code >= first && ((code - first) as usize) < self.counts[length] {
+                    match self.symbols[index + (code - first) as usize] {
+                        256 => return Err(invalid("Huffman string contains end-of-string")),
+                        symbol => decoded.push(symbol as u8),
+                    }
+                    code = 0;
+                    length = 0;
+                } else if length == 30 {
+                    return Err(invalid("invalid Huffman code"))
+                }
+            }
+        }
+        // Padding is the most significant bits of end-of-string, all ones.
+        if length > 7 || code != (1 << length) - 1 {
+            return Err(invalid("invalid Huffman padding"))
+        }
+        Ok(decoded)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid HPACK: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_decodes_requests() {
+        // RFC 7541 C.4: requests with Huffman coding sharing a table.
+        let mut decoder = Decoder::new();
+        let first = [0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff];
+        assert_eq!(decoder.decode(&first).unwrap(), vec![
+            (":method".to_string(), "GET".to_string()),
+            (":scheme".to_string(), "http".to_string()),
+            (":path".to_string(), "/".to_string()),
+            (":authority".to_string(), "www.example.com".to_string()),
+        ]);
+        let second = [0x82, 0x86, 0x84, 0xbe, 0x58, 0x86, 0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf];
+        let headers = decoder.decode(&second).unwrap();
+        assert_eq!(headers[3], (":authority".to_string(), "www.example.com".to_string()));
+        assert_eq!(headers[4], ("cache-control".to_string(), "no-cache".to_string()));
+
+        let mut buf = vec![];
+        encode(&mut buf, "grpc-message", &"x".repeat(200));
+        let decoded = Decoder::new().decode(&buf).unwrap();
+        assert_eq!(decoded, vec![("grpc-message".to_string(), "x".repeat(200))]);
+        assert!(Decoder::new().decode(&[0x82, 0xc0]).is_err());
+    }
+}
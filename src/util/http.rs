@@ -0,0 +1,180 @@
+//! A minimal HTTP/1.1 client for senders that post to an HTTP API and for
+//! reading metadata services, and a minimal server for the agent's own
+//! endpoints.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::Stop;
+
+/// Post `body` to `path` on `addr`, one connection per request, and return
+/// the response's status code. `host` is sent as the `Host` header.
+pub fn post(addr: &SocketAddr, host: &str, path: &str, headers: &[(&str, &str)], body: &[u8], timeout: Duration) -> io::Result<u16> {
+    let (status, reader) = exchange("POST", addr, host, path, headers, body, timeout)?;
+    // Drain the rest so the server isn't reset mid-response.
+    let _ = io::copy(&mut reader.take(1 << 20), &mut io::sink());
+    Ok(status)
+}
+
+/// Like `post`, but also return the rest of the response (up to a megabyte
+/// of its headers and body, lossily decoded), for APIs that report
+/// failures in successful responses.
+pub fn post_reading(addr: &SocketAddr, host: &str, path: &str, headers: &[(&str, &str)], body: &[u8], timeout: Duration) -> io::Result<(u16, String)> {
+    let (status, reader) = exchange("POST", addr, host, path, headers, body, timeout)?;
+    Ok((status, read_rest(reader)))
+}
+
+/// Make a `method` request, eg. a `GET` of a metadata service, and return
+/// the response's status code and body (up to a megabyte, lossily
+/// decoded, and without its headers).
+pub fn request(method: &str, addr: &SocketAddr, host: &str, path: &str, headers: &[(&str, &str)], timeout: Duration) -> io::Result<(u16, String)> {
+    let (status, reader) = exchange(method, addr, host, path, headers, &[], timeout)?;
+    let response = read_rest(reader);
+    let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+    Ok((status, body.to_string()))
+}
+
+/// Send a request and read its response's status line, leaving the rest to
+/// be read.
+fn exchange(method: &str, addr: &SocketAddr, host: &str, path: &str, headers: &[(&str, &str)], body: &[u8], timeout: Duration) -> io::Result<(u16, BufReader<TcpStream>)> {
+    let mut stream = TcpStream::connect_timeout(addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n", method, path, host, body.len());
+    for &(name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line.split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("invalid HTTP status line: {:?}", status_line)))?;
+    Ok((status, reader))
+}
+
+fn read_rest(reader: BufReader<TcpStream>) -> String {
+    let mut response = vec![];
+    let _ = reader.take(1 << 20).read_to_end(&mut response);
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+/// What a handler passed to `serve` responds with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text<S: Into<String>>(status: u16, body: S) -> Response {
+        Response { status, content_type: "text/plain", body: body.into() }
+    }
+
+    pub fn json<S: Into<String>>(status: u16, body: S) -> Response {
+        Response { status, content_type: "application/json", body: body.into() }
+    }
+
+    pub fn html<S: Into<String>>(status: u16, body: S) -> Response {
+        Response { status, content_type: "text/html; charset=utf-8", body: body.into() }
+    }
+}
+
+/// Serve requests on `listener` until `stop` is stopped, each connection
+/// on its own thread. `handle` is called with the method and target (the
+/// path and any query) of each request; request bodies are ignored.
+pub fn serve<F>(listener: &TcpListener, stop: &Stop, handle: F) -> io::Result<()>
+    where F: Fn(&str, &str) -> Response + Send + Sync + 'static
+{
+    let handle = Arc::new(handle);
+    while let Some(stream) = stop.accept(listener)? {
+        let handle = handle.clone();
+        thread::spawn(move || {
+            if let Err(err) = respond(stream, &*handle) {
+                debug!("Error serving HTTP request: {}", err)
+            }
+        });
+    }
+    Ok(())
+}
+
+fn respond(stream: TcpStream, handle: &dyn Fn(&str, &str) -> Response) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, nothing in them changes the response.
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => handle(method, target),
+        _ => Response::text(400, "Bad Request\n"),
+    };
+
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len(),
+        response.body,
+    )?;
+    stream.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_serves_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stop = Stop::new();
+        let server = {
+            let stop = stop.clone();
+            thread::spawn(move || serve(&listener, &stop, |method, target| match (method, target) {
+                ("POST", "/flush") => Response::text(200, "flushed\n"),
+                _ => Response::text(404, "Not Found\n"),
+            }))
+        };
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(post(&addr, "localhost", "/flush", &[], b"", timeout).unwrap(), 200);
+        assert_eq!(post(&addr, "localhost", "/reset", &[], b"", timeout).unwrap(), 404);
+        stop.stop();
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,502 @@
+//! A minimal HTTP/2 server for the agent's gRPC API, over cleartext TCP
+//! with prior knowledge ("h2c"), which is how gRPC clients connect without
+//! TLS. Each request is handled on its own thread once its body has been
+//! received, and responds through a `Stream` with headers, data, and
+//! trailers; data waits for the client's flow control to make room for it,
+//! and the stream is reset if it doesn't in time. Priorities, server push,
+//! and upgrading from HTTP/1.1 aren't supported.
+//!
+//! So that one client can't take unbounded threads or memory, connections
+//! advertise and enforce `MAX_STREAMS` concurrent streams, refusing any
+//! more, and reset requests whose body is over `MAX_BODY`. Header blocks
+//! over `MAX_HEADER_BLOCK` close the connection.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::Stop;
+use super::hpack::{self, Decoder};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const INITIAL_WINDOW_SIZE: u16 = 0x4;
+const MAX_FRAME_SIZE: u16 = 0x5;
+
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const REFUSED_STREAM: u32 = 0x7;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+/// What every connection starts with. The server never advertises larger
+/// windows or frames, so it only has to accept frames this large.
+const DEFAULT_WINDOW: i64 = 65535;
+const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+
+/// Streams a connection can have open, being received or responded to.
+pub const MAX_STREAMS: usize = 100;
+
+/// Bytes of a request's body, and of one header block.
+const MAX_BODY: usize = 1 << 20;
+const MAX_HEADER_BLOCK: usize = 64 << 10;
+
+/// How often blocked reads and sends check whether they've been stopped.
+const POLL: Duration = Duration::from_millis(100);
+
+/// How long clients have to read what they're sent, or to make room for it
+/// with flow control, before they're dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long open streams have to finish once the server is stopped before
+/// their connections are closed.
+const STOP_GRACE: Duration = Duration::from_secs(1);
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Every header but the pseudo-headers, eg. `content-type`.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|header| header.0 == name)
+            .map(|header| header.1.as_str())
+    }
+}
+
+/// Where a handler writes its response. Dropping it before the stream has
+/// been ended resets the stream.
+pub struct Stream {
+    id: u32,
+    connection: Arc<Connection>,
+}
+
+impl Stream {
+    /// Send the response's headers, or its trailers after its data. Header
+    /// names must be lowercase.
+    pub fn headers(&self, headers: &[(&str, &str)], end_stream: bool) -> io::Result<()> {
+        self.connection.check(self.id)?;
+        let mut block = vec![];
+        for &(name, value) in headers {
+            hpack::encode(&mut block, name, value);
+        }
+        self.connection.write(HEADERS, END_HEADERS | if end_stream { END_STREAM } else { 0 }, self.id, &block)?;
+        if end_stream {
+            self.connection.close_stream(self.id);
+        }
+        Ok(())
+    }
+
+    /// Send `data`, blocking until the client has made room for all of it.
+    /// Fails if it hasn't within `WRITE_TIMEOUT`.
+    pub fn data(&self, data: &[u8], end_stream: bool) -> io::Result<()> {
+        let mut rest = data;
+        loop {
+            let len = self.connection.reserve(self.id, rest.len())?;
+            let (frame, tail) = rest.split_at(len);
+            rest = tail;
+            let flags = if rest.is_empty() && end_stream { END_STREAM } else { 0 };
+            self.connection.write(DATA, flags, self.id, frame)?;
+            if rest.is_empty() {
+                break
+            }
+        }
+        if end_stream {
+            self.connection.close_stream(self.id);
+        }
+        Ok(())
+    }
+
+    /// Whether the stream has been ended, reset by the client, or lost with
+    /// its connection.
+    pub fn is_closed(&self) -> bool {
+        self.connection.check(self.id).is_err()
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if !self.is_closed() {
+            self.connection.close_stream(self.id);
+            let _ = self.connection.write(RST_STREAM, 0, self.id, &INTERNAL_ERROR.to_be_bytes());
+        }
+    }
+}
+
+/// Serve requests on `listener` until `stop` is stopped, each connection
+/// on its own thread and each request on its own too. Streams still open
+/// once stopped have a moment to finish before their connection is closed.
+pub fn serve<F>(listener: &TcpListener, stop: &Stop, handle: F) -> io::Result<()>
+    where F: Fn(Request, Stream) + Send + Sync + 'static
+{
+    let handle: Handler = Arc::new(handle);
+    while let Some(stream) = stop.accept(listener)? {
+        let (handle, stop) = (handle.clone(), stop.clone());
+        thread::spawn(move || {
+            if let Err(err) = connect(stream, &stop, handle) {
+                debug!("Error serving HTTP/2 connection: {}", err)
+            }
+        });
+    }
+    Ok(())
+}
+
+type Handler = Arc<dyn Fn(Request, Stream) + Send + Sync>;
+
+/// What's shared between a connection's reader and its streams.
+struct Connection {
+    writer: Mutex<TcpStream>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    closed: bool,
+    /// The connection's send window.
+    window: i64,
+    initial_window: i64,
+    max_frame_size: usize,
+    /// Send windows of the streams that are being responded to.
+    streams: HashMap<u32, i64>,
+}
+
+impl State {
+    fn check(&self, id: u32) -> io::Result<()> {
+        if self.closed {
+            Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection is closed"))
+        } else if !self.streams.contains_key(&id) {
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream is closed"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Connection {
+    fn write(&self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(9 + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.writer.lock().unwrap().write_all(&frame)
+    }
+
+    fn check(&self, id: u32) -> io::Result<()> {
+        self.state.lock().unwrap().check(id)
+    }
+
+    /// Wait until up to `len` bytes can be sent on stream `id` and take
+    /// them from the windows, returning how many were taken.
+    fn reserve(&self, id: u32, len: usize) -> io::Result<usize> {
+        let deadline = Instant::now() + WRITE_TIMEOUT;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            state.check(id)?;
+            let available = state.window
+                .min(state.streams[&id])
+                .min(state.max_frame_size as i64)
+                .min(len as i64);
+            if len == 0 || available > 0 {
+                let available = available.max(0);
+                state.window -= available;
+                if let Some(window) = state.streams.get_mut(&id) {
+                    *window -= available;
+                }
+                return Ok(available as usize)
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "client isn't reading"))
+            }
+            state = self.changed.wait_timeout(state, POLL).unwrap().0;
+        }
+    }
+
+    fn open_stream(&self, id: u32) {
+        let mut state = self.state.lock().unwrap();
+        let window = state.initial_window;
+        state.streams.insert(id, window);
+    }
+
+    /// How many streams are being responded to.
+    fn open_streams(&self) -> usize {
+        self.state.lock().unwrap().streams.len()
+    }
+
+    fn close_stream(&self, id: u32) {
+        self.state.lock().unwrap().streams.remove(&id);
+        self.changed.notify_all();
+    }
+
+    fn window_update(&self, id: u32, increment: u32) {
+        let mut state = self.state.lock().unwrap();
+        if id == 0 {
+            state.window += i64::from(increment);
+        } else if let Some(window) = state.streams.get_mut(&id) {
+            *window += i64::from(increment);
+        }
+        self.changed.notify_all();
+    }
+
+    fn settings(&self, payload: &[u8]) -> io::Result<()> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(invalid("SETTINGS frame has a partial setting"))
+        }
+        let mut state = self.state.lock().unwrap();
+        for setting in payload.chunks(6) {
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match u16::from_be_bytes([setting[0], setting[1]]) {
+                INITIAL_WINDOW_SIZE => {
+                    let delta = i64::from(value) - state.initial_window;
+                    state.initial_window = i64::from(value);
+                    for window in state.streams.values_mut() {
+                        *window += delta;
+                    }
+                },
+                MAX_FRAME_SIZE => state.max_frame_size = value as usize,
+                _ => {},
+            }
+        }
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Wait until every stream has been ended or reset, or `timeout`.
+    fn wait_for_streams(&self, timeout: Duration) {
+        let state = self.state.lock().unwrap();
+        let _state = self.changed.wait_timeout_while(state, timeout, |state| !state.streams.is_empty()).unwrap();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+fn connect(mut stream: TcpStream, stop: &Stop, handle: Handler) -> io::Result<()> {
+    stream.set_read_timeout(Some(POLL))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let connection = Arc::new(Connection {
+        writer: Mutex::new(stream.try_clone()?),
+        state: Mutex::new(State {
+            closed: false,
+            window: DEFAULT_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            streams: HashMap::new(),
+        }),
+        changed: Condvar::new(),
+    });
+
+    let result = read(&mut stream, stop, &connection, &handle);
+    if stop.is_stopped() {
+        connection.wait_for_streams(STOP_GRACE);
+        let mut goaway = vec![0; 4];
+        goaway.extend_from_slice(&NO_ERROR.to_be_bytes());
+        let _ = connection.write(GOAWAY, 0, 0, &goaway);
+    }
+    connection.close();
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+/// Read frames until the client goes away, the connection fails, or
+/// `stop` is stopped.
+fn read(stream: &mut TcpStream, stop: &Stop, connection: &Arc<Connection>, handle: &Handler) -> io::Result<()> {
+    let mut preface = [0; 24];
+    if !read_exact(stream, &mut preface, stop)? {
+        return Ok(())
+    }
+    if preface != PREFACE {
+        return Err(invalid("expected the connection preface"))
+    }
+    let mut settings = MAX_CONCURRENT_STREAMS.to_be_bytes().to_vec();
+    settings.extend_from_slice(&(MAX_STREAMS as u32).to_be_bytes());
+    connection.write(SETTINGS, 0, 0, &settings)?;
+
+    let mut decoder = Decoder::new();
+    // Requests whose body is still being received.
+    let mut pending: HashMap<u32, Request> = HashMap::new();
+    // A header block waiting for its CONTINUATION frames: its stream,
+    // whether it ends the stream, and its fragments so far.
+    let mut block: Option<(u32, bool, Vec<u8>)> = None;
+    let mut header = [0; 9];
+    while read_exact(stream, &mut header, stop)? {
+        let len = (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize;
+        let (kind, flags) = (header[3], header[4]);
+        let id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+        if len > DEFAULT_MAX_FRAME_SIZE {
+            return Err(invalid("frame is larger than the maximum frame size"))
+        }
+        let mut payload = vec![0; len];
+        if !read_exact(stream, &mut payload, stop)? {
+            break
+        }
+        if block.is_some() != (kind == CONTINUATION) {
+            return Err(invalid("header block is interrupted"))
+        }
+
+        match kind {
+            DATA => {
+                let data = unpad(&payload, flags)?;
+                if pending.get(&id).is_some_and(|request| request.body.len() + data.len() > MAX_BODY) {
+                    pending.remove(&id);
+                    connection.write(RST_STREAM, 0, id, &ENHANCE_YOUR_CALM.to_be_bytes())?;
+                }
+                if let Some(request) = pending.get_mut(&id) {
+                    request.body.extend_from_slice(data);
+                }
+                // Padding counts towards flow control too.
+                if len > 0 {
+                    let increment = (len as u32).to_be_bytes();
+                    connection.write(WINDOW_UPDATE, 0, 0, &increment)?;
+                    if flags & END_STREAM == 0 {
+                        connection.write(WINDOW_UPDATE, 0, id, &increment)?;
+                    }
+                }
+                if flags & END_STREAM != 0 {
+                    if let Some(request) = pending.remove(&id) {
+                        dispatch(connection, handle, id, request);
+                    }
+                }
+            },
+            HEADERS => {
+                let mut fragment = unpad(&payload, flags)?;
+                if flags & PRIORITY != 0 {
+                    fragment = fragment.get(5..).ok_or_else(|| invalid("HEADERS frame is truncated"))?;
+                }
+                block = Some((id, flags & END_STREAM != 0, fragment.to_vec()));
+            },
+            CONTINUATION => match block {
+                Some((stream, _, ref mut fragments)) if stream == id => {
+                    if fragments.len() + payload.len() > MAX_HEADER_BLOCK {
+                        return Err(invalid("header block is too large"))
+                    }
+                    fragments.extend_from_slice(&payload)
+                },
+                _ => return Err(invalid("CONTINUATION frame for another stream")),
+            },
+            RST_STREAM => {
+                pending.remove(&id);
+                connection.close_stream(id);
+            },
+            SETTINGS if flags & ACK == 0 => {
+                connection.settings(&payload)?;
+                connection.write(SETTINGS, ACK, 0, &[])?;
+            },
+            PUSH_PROMISE => return Err(invalid("clients can't push")),
+            PING if flags & ACK == 0 => connection.write(PING, ACK, 0, &payload)?,
+            GOAWAY => break,
+            WINDOW_UPDATE => {
+                let increment = payload.get(..4)
+                    .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) & 0x7fff_ffff)
+                    .ok_or_else(|| invalid("WINDOW_UPDATE frame is truncated"))?;
+                connection.window_update(id, increment);
+            },
+            // PRIORITY and extensions.
+            _ => {},
+        }
+
+        if (kind == HEADERS || kind == CONTINUATION) && flags & END_HEADERS != 0 {
+            let (id, end_stream, fragments) = block.take().unwrap_or_default();
+            let fields = decoder.decode(&fragments)?;
+            // A second header block on a stream is the request's trailers.
+            let request = match pending.remove(&id) {
+                Some(request) => Some(request),
+                None if pending.len() + connection.open_streams() >= MAX_STREAMS => {
+                    connection.write(RST_STREAM, 0, id, &REFUSED_STREAM.to_be_bytes())?;
+                    continue
+                },
+                None => request(fields),
+            };
+            match request {
+                Some(request) if end_stream => dispatch(connection, handle, id, request),
+                Some(request) => {
+                    pending.insert(id, request);
+                },
+                None => connection.write(RST_STREAM, 0, id, &PROTOCOL_ERROR.to_be_bytes())?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn request(fields: Vec<(String, String)>) -> Option<Request> {
+    let (mut method, mut path, mut headers) = (None, None, vec![]);
+    for (name, value) in fields {
+        match name.as_str() {
+            ":method" => method = Some(value),
+            ":path" => path = Some(value),
+            _ if name.starts_with(':') => {},
+            _ => headers.push((name, value)),
+        }
+    }
+    Some(Request { method: method?, path: path?, headers, body: vec![] })
+}
+
+fn dispatch(connection: &Arc<Connection>, handle: &Handler, id: u32, request: Request) {
+    connection.open_stream(id);
+    let (stream, handle) = (Stream { id, connection: connection.clone() }, handle.clone());
+    thread::spawn(move || handle(request, stream));
+}
+
+fn unpad(payload: &[u8], flags: u8) -> io::Result<&[u8]> {
+    if flags & PADDED == 0 {
+        return Ok(payload)
+    }
+    let padding = *payload.first().ok_or_else(|| invalid("padded frame is empty"))? as usize;
+    payload.len().checked_sub(padding)
+        .filter(|&end| end >= 1)
+        .map(|end| &payload[1..end])
+        .ok_or_else(|| invalid("padding is longer than the frame"))
+}
+
+/// Fill `buf`, returning false if the client closed the connection or
+/// `stop` was stopped first.
+fn read_exact(stream: &mut TcpStream, buf: &mut [u8], stop: &Stop) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match stream.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+                if stop.is_stopped() {
+                    return Ok(false)
+                }
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid HTTP/2: {}", message))
+}
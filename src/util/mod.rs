@@ -0,0 +1,22 @@
+//! Helpers shared between receivers, the database, and senders.
+
+mod glob;
+pub mod hash;
+pub mod host;
+pub mod hpack;
+pub mod http;
+pub mod http2;
+pub mod json;
+pub mod percent;
+mod poison;
+pub mod pool;
+pub mod protobuf;
+#[cfg(unix)]
+pub mod signal;
+pub mod socket;
+mod stop;
+pub mod time;
+
+pub use self::glob::Glob;
+pub use self::poison::{lock, read_lock, write_lock};
+pub use self::stop::{Stop, StopOnDrop};
@@ -0,0 +1,31 @@
+//! Percent-encoding for text formats whose separators may appear in names
+//! and dimension values.
+
+/// Encode `%` and every character in `reserved` as `%XX`. Reserved
+/// characters must be ASCII.
+pub fn encode(input: &str, reserved: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        if c == '%' || reserved.contains(c) {
+            output.push_str(&format!("%{:02X}", c as u32))
+        } else {
+            output.push(c)
+        }
+    }
+    output
+}
+
+/// `None` if an escape isn't two hex digits.
+pub fn decode(input: &str) -> Option<String> {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c == '%' {
+            let hex: String = chars.by_ref().take(2).collect();
+            output.push(u8::from_str_radix(&hex, 16).ok()? as char);
+        } else {
+            output.push(c)
+        }
+    }
+    Some(output)
+}
@@ -0,0 +1,98 @@
+//! Reusing buffers instead of allocating one per packet, line, or batch.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use super::lock;
+use super::super::metric::{CollectedMetric, MetricId};
+
+/// Spare, empty vectors that keep their capacity. Taking one when there
+/// are none spare allocates a new one; giving one back when `max` are
+/// already spare drops it, which bounds the memory held while idle.
+pub struct BufferPool<T> {
+    spare: Mutex<Vec<Vec<T>>>,
+    max: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<T> BufferPool<T> {
+    pub fn new(max: usize) -> BufferPool<T> {
+        BufferPool {
+            spare: Mutex::new(vec![]),
+            max,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn take(&self) -> Vec<T> {
+        match lock(&self.spare).pop() {
+            Some(buffer) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buffer
+            },
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                vec![]
+            },
+        }
+    }
+
+    /// Clear `buffer` and keep it for reuse. Buffers that never allocated
+    /// aren't worth keeping.
+    pub fn give(&self, mut buffer: Vec<T>) {
+        if buffer.capacity() == 0 {
+            return
+        }
+        buffer.clear();
+        let mut spare = lock(&self.spare);
+        if spare.len() < self.max {
+            spare.push(buffer)
+        }
+    }
+
+    /// `metriqs.pool.hits` and `metriqs.pool.misses` counts of the takes
+    /// since the previous call, and a `metriqs.pool.hit_rate` gauge of the
+    /// fraction of them that reused a buffer, with a `pool` dimension;
+    /// nothing if there weren't any.
+    pub fn metrics(&self, pool: &str, now: SystemTime) -> Vec<CollectedMetric> {
+        let hits = self.hits.swap(0, Ordering::Relaxed);
+        let misses = self.misses.swap(0, Ordering::Relaxed);
+        if hits + misses == 0 {
+            return vec![]
+        }
+        let id = |name: &str| MetricId::from(name).with_dimension("pool", pool);
+        vec![
+            CollectedMetric::Count(now, id("metriqs.pool.hits"), hits as i64),
+            CollectedMetric::Count(now, id("metriqs.pool.misses"), misses as i64),
+            CollectedMetric::Gauge(now, id("metriqs.pool.hit_rate"), hits as f64 / (hits + misses) as f64),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reuses_buffers_up_to_its_limit() {
+        let pool = BufferPool::new(1);
+        let mut buffer = pool.take();
+        buffer.extend_from_slice(b"foo:1|c");
+        pool.give(buffer);
+        pool.give(Vec::with_capacity(16));
+
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() >= 7);
+        assert_eq!(pool.take().capacity(), 0);
+
+        let now = SystemTime::now();
+        let metrics = pool.metrics("lines", now);
+        assert!(metrics.contains(&CollectedMetric::Count(now, MetricId::from("metriqs.pool.hits").with_dimension("pool", "lines"), 1)));
+        assert!(metrics.contains(&CollectedMetric::Gauge(now, MetricId::from("metriqs.pool.hit_rate").with_dimension("pool", "lines"), 1.0 / 3.0)));
+        assert_eq!(pool.metrics("lines", now), vec![]);
+    }
+}
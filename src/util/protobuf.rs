@@ -0,0 +1,174 @@
+//! Just enough protobuf for the senders that speak it. Fields are appended
+//! to a buffer in the wire format; messages are nested by encoding them
+//! into their own buffer first. Messages are decoded a field at a time with
+//! a `Reader`, and nested ones with a reader of their own.
+
+use std::io;
+use std::str;
+
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LENGTH_DELIMITED: u64 = 2;
+const FIXED32: u64 = 5;
+
+pub fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8)
+}
+
+fn key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    varint(buf, (field << 3) | wire_type)
+}
+
+/// An `int64`, `uint64`, or `bool` field. Negative `int64`s are encoded as
+/// their two's complement.
+pub fn varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    key(buf, field, VARINT);
+    varint(buf, value)
+}
+
+pub fn double_field(buf: &mut Vec<u8>, field: u64, value: f64) {
+    key(buf, field, FIXED64);
+    buf.extend_from_slice(&value.to_bits().to_le_bytes())
+}
+
+/// A `string`, `bytes`, or embedded message field.
+pub fn bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    key(buf, field, LENGTH_DELIMITED);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes)
+}
+
+/// A field's value as it's encoded; what it means depends on the field's
+/// type in the schema.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> Value<'a> {
+    /// An `int64` (see `varint_field`), `uint64`, or `bool`.
+    pub fn varint(self) -> io::Result<u64> {
+        match self {
+            Value::Varint(value) => Ok(value),
+            _ => Err(invalid("expected a varint")),
+        }
+    }
+
+    pub fn double(self) -> io::Result<f64> {
+        match self {
+            Value::Fixed64(bits) => Ok(f64::from_bits(bits)),
+            _ => Err(invalid("expected a double")),
+        }
+    }
+
+    /// A `bytes` field or embedded message.
+    pub fn bytes(self) -> io::Result<&'a [u8]> {
+        match self {
+            Value::Bytes(bytes) => Ok(bytes),
+            _ => Err(invalid("expected a length-delimited field")),
+        }
+    }
+
+    pub fn string(self) -> io::Result<&'a str> {
+        str::from_utf8(self.bytes()?).map_err(|_| invalid("expected a UTF-8 string"))
+    }
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf }
+    }
+
+    /// The next field's number and value, or `None` once the whole message
+    /// has been read.
+    pub fn field(&mut self) -> io::Result<Option<(u64, Value<'a>)>> {
+        if self.buf.is_empty() {
+            return Ok(None)
+        }
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            VARINT => Value::Varint(self.varint()?),
+            FIXED64 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(self.take(8)?);
+                Value::Fixed64(u64::from_le_bytes(bytes))
+            },
+            LENGTH_DELIMITED => {
+                let len = self.varint()?;
+                Value::Bytes(self.take(len as usize)?)
+            },
+            FIXED32 => {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(self.take(4)?);
+                Value::Fixed32(u32::from_le_bytes(bytes))
+            },
+            wire_type => return Err(invalid(&format!("unsupported wire type {}", wire_type))),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value = 0;
+        for shift in 0..10 {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7f) << (shift * 7);
+            if byte < 0x80 {
+                return Ok(value)
+            }
+        }
+        Err(invalid("varint is too long"))
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.buf.len() {
+            return Err(invalid("message is truncated"))
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid protobuf: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_encodes_fields() {
+        let mut buf = vec![];
+        varint_field(&mut buf, 1, 300);
+        bytes_field(&mut buf, 2, b"hi");
+        double_field(&mut buf, 3, 1.0);
+        assert_eq!(buf, vec![0x08, 0xac, 0x02, 0x12, 2, b'h', b'i', 0x19, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f]);
+    }
+
+    #[test]
+    fn it_reads_fields() {
+        let mut buf = vec![];
+        varint_field(&mut buf, 1, -2i64 as u64);
+        bytes_field(&mut buf, 2, b"hi");
+        double_field(&mut buf, 3, 1.5);
+
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.field().unwrap(), Some((1, Value::Varint(-2i64 as u64))));
+        assert_eq!(reader.field().unwrap().map(|(_, value)| value.string().unwrap()), Some("hi"));
+        assert_eq!(reader.field().unwrap().map(|(_, value)| value.double().unwrap()), Some(1.5));
+        assert_eq!(reader.field().unwrap(), None);
+        assert!(Reader::new(&[0x12, 5, b'h']).field().is_err());
+    }
+}
@@ -0,0 +1,56 @@
+//! Turning SIGTERM and SIGINT into a `Stop`, so that an agent shuts down
+//! gracefully when it's asked to, eg. by Kubernetes during a rollout or by
+//! Ctrl-C. A second signal exits right away in case shutting down hangs.
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use libc::{self, c_int};
+
+use super::Stop;
+
+/// How often the signal flag is checked.
+const SIGNAL_POLL: Duration = Duration::from_millis(100);
+
+/// The last signal received, or zero. Handlers can only safely touch
+/// atomics, so a thread polls this instead.
+static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn handle(signal: c_int) {
+    if RECEIVED.swap(signal as usize, Ordering::SeqCst) != 0 {
+        unsafe { libc::_exit(128 + signal) }
+    }
+}
+
+/// Stop `stop` on the first SIGTERM or SIGINT the process gets. Replaces
+/// any handlers already installed for them.
+pub fn stop_on_termination(stop: &Stop) -> io::Result<()> {
+    for &signal in &[libc::SIGTERM, libc::SIGINT] {
+        let handler = handle as extern "C" fn(c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error())
+        }
+    }
+
+    let stop = stop.clone();
+    thread::spawn(move || {
+        while stop.sleep(SIGNAL_POLL) {
+            if let Some(name) = name(RECEIVED.load(Ordering::SeqCst) as c_int) {
+                info!("Received {}, shutting down", name);
+                stop.stop();
+            }
+        }
+    });
+    Ok(())
+}
+
+fn name(signal: c_int) -> Option<&'static str> {
+    match signal {
+        0 => None,
+        libc::SIGTERM => Some("SIGTERM"),
+        libc::SIGINT => Some("SIGINT"),
+        _ => Some("a signal"),
+    }
+}
@@ -0,0 +1,145 @@
+//! Socket options and statistics std doesn't expose.
+
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::io;
+#[cfg(unix)]
+use std::mem;
+use std::net::{TcpStream, UdpSocket};
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::MetadataExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+#[cfg(unix)]
+use libc::{self, c_int};
+
+/// Turn on TCP keepalive for `stream`, probing once it's been idle for
+/// `idle` (rounded to seconds, at least one), so that peers which went
+/// away without closing the connection are noticed.
+#[cfg(unix)]
+pub fn set_keepalive(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    const IDLE: c_int = libc::TCP_KEEPALIVE;
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    const IDLE: c_int = libc::TCP_KEEPIDLE;
+
+    let fd = stream.as_raw_fd();
+    set(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    set(fd, libc::IPPROTO_TCP, IDLE, idle.as_secs().clamp(1, c_int::MAX as u64) as c_int)
+}
+
+/// Keepalive can't be configured off Unix; connections are left as they
+/// are.
+#[cfg(not(unix))]
+pub fn set_keepalive(_stream: &TcpStream, _idle: Duration) -> io::Result<()> {
+    Ok(())
+}
+
+/// Ask for a receive buffer of `bytes`, so that bursts are queued rather
+/// than dropped by the kernel. The kernel may cap (or, on Linux, double)
+/// it; `receive_buffer` says what it settled on.
+#[cfg(unix)]
+pub fn set_receive_buffer(socket: &UdpSocket, bytes: usize) -> io::Result<()> {
+    set(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVBUF, bytes.min(c_int::MAX as usize) as c_int)
+}
+
+#[cfg(not(unix))]
+pub fn set_receive_buffer(_socket: &UdpSocket, _bytes: usize) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(unix)]
+pub fn receive_buffer(socket: &UdpSocket) -> io::Result<usize> {
+    get(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVBUF).map(|bytes| bytes as usize)
+}
+
+#[cfg(not(unix))]
+pub fn receive_buffer(_socket: &UdpSocket) -> io::Result<usize> {
+    Err(unsupported())
+}
+
+/// Set the type of service (IPv4) or traffic class (IPv6) of what's sent
+/// from `socket`.
+#[cfg(unix)]
+pub fn set_tos(socket: &UdpSocket, tos: u8) -> io::Result<()> {
+    if socket.local_addr()?.is_ipv4() {
+        set(socket.as_raw_fd(), libc::IPPROTO_IP, libc::IP_TOS, tos as c_int)
+    } else {
+        set(socket.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos as c_int)
+    }
+}
+
+#[cfg(not(unix))]
+pub fn set_tos(_socket: &UdpSocket, _tos: u8) -> io::Result<()> {
+    Err(unsupported())
+}
+
+/// How many datagrams the kernel has dropped for `socket` because its
+/// receive buffer was full, if the platform says; only Linux does, in
+/// `/proc/net/udp`.
+#[cfg(target_os = "linux")]
+pub fn udp_drops(socket: &UdpSocket) -> io::Result<Option<u64>> {
+    let inode = fs::metadata(format!("/proc/self/fd/{}", socket.as_raw_fd()))?.ino().to_string();
+    let table = if socket.local_addr()?.is_ipv4() { "/proc/net/udp" } else { "/proc/net/udp6" };
+    // The inode is the tenth column and the drops the last.
+    Ok(fs::read_to_string(table)?.lines().skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|columns| columns.get(9) == Some(&inode.as_str()))
+        .and_then(|columns| columns.last().and_then(|drops| drops.parse().ok())))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn udp_drops(_socket: &UdpSocket) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "not supported on this platform")
+}
+
+#[cfg(unix)]
+fn get(fd: c_int, level: c_int, name: c_int) -> io::Result<c_int> {
+    let mut value: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as libc::socklen_t;
+    let result = unsafe { libc::getsockopt(fd, level, name, &mut value as *mut c_int as *mut libc::c_void, &mut len) };
+    if result == 0 { Ok(value) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(unix)]
+fn set(fd: c_int, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(fd, level, name, &value as *const c_int as *const libc::c_void, mem::size_of::<c_int>() as libc::socklen_t)
+    };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    #[test]
+    fn it_sets_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        set_keepalive(&stream, Duration::from_secs(42)).unwrap();
+        assert_eq!(get(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_KEEPALIVE).unwrap(), 1);
+        #[cfg(target_os = "linux")]
+        assert_eq!(get(stream.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_KEEPIDLE).unwrap(), 42);
+    }
+
+    #[test]
+    fn it_tunes_udp_sockets() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        set_receive_buffer(&socket, 4096).unwrap();
+        assert!(receive_buffer(&socket).unwrap() >= 4096);
+        set_tos(&socket, 0x10).unwrap();
+        assert_eq!(get(socket.as_raw_fd(), libc::IPPROTO_IP, libc::IP_TOS).unwrap(), 0x10);
+        #[cfg(target_os = "linux")]
+        assert_eq!(udp_drops(&socket).unwrap(), Some(0));
+    }
+}
@@ -0,0 +1,85 @@
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// How often blocking accepts check whether they've been stopped.
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+/// Shared between whoever starts a blocking loop (a listener, poller, or
+/// exporter) and the loop itself, so that the loop can be asked to return,
+/// eg. when a reloaded configuration no longer includes it. Clones share
+/// the same state.
+#[derive(Clone, Debug, Default)]
+pub struct Stop {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Stop {
+    pub fn new() -> Stop {
+        Stop::default()
+    }
+
+    /// Ask the loops to return; they notice within a poll interval.
+    pub fn stop(&self) {
+        let (ref stopped, ref signal) = *self.state;
+        *stopped.lock().unwrap() = true;
+        signal.notify_all();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    /// Sleep for `duration` or until stopped, returning false in the latter
+    /// case.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let (ref stopped, ref signal) = *self.state;
+        let (stopped, _) = signal.wait_timeout_while(stopped.lock().unwrap(), duration, |stopped| !*stopped).unwrap();
+        !*stopped
+    }
+
+    /// Block until stopped.
+    pub fn wait(&self) {
+        let (ref stopped, ref signal) = *self.state;
+        let _stopped = signal.wait_while(stopped.lock().unwrap(), |stopped| !*stopped).unwrap();
+    }
+
+    /// Stop once the returned guard is dropped, including while unwinding
+    /// from a panic.
+    pub fn on_drop(&self) -> StopOnDrop {
+        StopOnDrop { stop: self.clone() }
+    }
+
+    /// Accept the next connection on `listener`, or None once stopped. The
+    /// listener is switched to non-blocking so that it can be polled, and
+    /// accepted streams are switched back to blocking.
+    pub fn accept(&self, listener: &TcpListener) -> io::Result<Option<TcpStream>> {
+        listener.set_nonblocking(true)?;
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    return Ok(Some(stream))
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    if !self.sleep(ACCEPT_POLL) {
+                        return Ok(None)
+                    }
+                },
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct StopOnDrop {
+    stop: Stop,
+}
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        self.stop.stop()
+    }
+}
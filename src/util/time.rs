@@ -0,0 +1,47 @@
+//! Calendar formatting of timestamps, always in UTC.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Year, month, day, hour, minute, second, and millisecond.
+pub type Civil = (i64, u32, u32, u32, u32, u32, u32);
+
+/// Break a timestamp down into its UTC calendar date and time of day.
+/// Times before the epoch are clamped to it.
+pub fn civil(time: SystemTime) -> Civil {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() as i64;
+    let (days, of_day) = (seconds / 86_400, seconds % 86_400);
+
+    // Howard Hinnant's days-to-civil algorithm.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day, (of_day / 3600) as u32, (of_day % 3600 / 60) as u32, (of_day % 60) as u32, since_epoch.subsec_millis())
+}
+
+/// eg. `2017-07-14T02:40:00.000Z`
+pub fn iso8601(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, millis) = civil(time);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, hour, minute, second, millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn it_formats_iso8601() {
+        assert_eq!(iso8601(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(iso8601(UNIX_EPOCH + Duration::from_millis(1_500_000_000_123)), "2017-07-14T02:40:00.123Z");
+        assert_eq!(iso8601(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00.000Z");
+    }
+}